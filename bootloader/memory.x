@@ -0,0 +1,32 @@
+MEMORY
+{
+  /* nRF52832_xxAA, MBR and SoftDevice S132 7.3.0 take the first 152K. Keep in sync
+   * with firmware/memory-bootloader.x */
+  ACTIVE : ORIGIN = 152K, LENGTH = 148K
+  /* One page more than ACTIVE, swapping needs it */
+  DFU : ORIGIN = 300K, LENGTH = 152K
+  FLASH : ORIGIN = 452K, LENGTH = 24K
+  BOOTLOADER_STATE : ORIGIN = 476K, LENGTH = 4K
+  /* Firmware data (factory, history, black box, settings) is at the very end, we don't care */
+  /* Softdevice RAM, it's not enabled yet. Keeps us off the application's retained RAM */
+  RAM : ORIGIN = 0x20000000, LENGTH = 0x3328
+  uicr_bootloader_start_address (r) : ORIGIN = 0x10001014, LENGTH = 0x4
+}
+
+__bootloader_active_start = ORIGIN(ACTIVE);
+__bootloader_active_end = ORIGIN(ACTIVE) + LENGTH(ACTIVE);
+
+__bootloader_dfu_start = ORIGIN(DFU);
+__bootloader_dfu_end = ORIGIN(DFU) + LENGTH(DFU);
+
+__bootloader_state_start = ORIGIN(BOOTLOADER_STATE);
+__bootloader_state_end = ORIGIN(BOOTLOADER_STATE) + LENGTH(BOOTLOADER_STATE);
+
+/* MBR jumps to us rather than to the softdevice once this is set */
+SECTIONS
+{
+  .uicr_bootloader_start_address :
+  {
+    LONG(ORIGIN(FLASH))
+  } > uicr_bootloader_start_address
+}
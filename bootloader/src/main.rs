@@ -0,0 +1,133 @@
+#![no_std]
+#![no_main]
+
+// A/B bootloader
+//
+// MBR hands over to us through UICR, and we start the application through the
+// softdevice. New images are written to DFU by the application, which then marks them
+// for update. On the next boot they are swapped into ACTIVE and started on trial:
+// unless the application marks them as booted, the reset after that swaps the previous
+// image back. What happened is passed on through GPREGRET, see copter_core::boot.
+//
+// We don't set up a watchdog of our own, the application picks its timeout and it
+// can't be changed once running. Once it has started keeps running through the reset
+// though, and a swap takes a few seconds, so it's fed on every erase and write.
+
+use core::{cell::RefCell, ptr};
+
+use copter_core::boot::ImageState;
+use cortex_m::peripheral::SCB;
+use cortex_m_rt::entry;
+use embassy_boot::{AlignedBuffer, BootLoader, BootLoaderConfig, State};
+use embassy_nrf::{
+    nvmc::{Nvmc, PAGE_SIZE},
+    pac,
+};
+use embassy_sync::blocking_mutex::Mutex;
+use embedded_storage::nor_flash::{ErrorType, NorFlash, ReadNorFlash};
+use nrf_softdevice_mbr as mbr;
+
+// Right past the MBR. It starts the application on its own
+const SOFTDEVICE_START: u32 = 0x1000;
+const NRF_SUCCESS: u32 = 0;
+
+// Only the reload, the application has configured it. It uses the first register only
+fn feed_watchdog() {
+    if pac::WDT.runstatus().read().runstatuswdt() {
+        pac::WDT
+            .rr(0)
+            .write(|w| w.set_rr(pac::wdt::vals::Rr::RELOAD));
+    }
+}
+
+// Flash that feeds the watchdog as it goes, a page erase takes ~85 ms
+struct FedFlash<F>(F);
+
+impl<F: ErrorType> ErrorType for FedFlash<F> {
+    type Error = F::Error;
+}
+
+impl<F: ReadNorFlash> ReadNorFlash for FedFlash<F> {
+    const READ_SIZE: usize = F::READ_SIZE;
+
+    fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), Self::Error> {
+        self.0.read(offset, bytes)
+    }
+
+    fn capacity(&self) -> usize {
+        self.0.capacity()
+    }
+}
+
+impl<F: NorFlash> NorFlash for FedFlash<F> {
+    const WRITE_SIZE: usize = F::WRITE_SIZE;
+    const ERASE_SIZE: usize = F::ERASE_SIZE;
+
+    // A page at a time, a whole partition in one go would outlast the timeout
+    fn erase(&mut self, from: u32, to: u32) -> Result<(), Self::Error> {
+        for page in (from..to).step_by(F::ERASE_SIZE) {
+            feed_watchdog();
+            self.0.erase(page, page + F::ERASE_SIZE as u32)?;
+        }
+
+        Ok(())
+    }
+
+    fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), Self::Error> {
+        feed_watchdog();
+        self.0.write(offset, bytes)
+    }
+}
+
+unsafe fn start_softdevice() -> ! {
+    // Interrupts go through the MBR, which has to forward them to the softdevice from
+    // now on, rather than to us
+    let mut cmd = mbr::sd_mbr_command_t {
+        command: mbr::NRF_MBR_COMMANDS_SD_MBR_COMMAND_IRQ_FORWARD_ADDRESS_SET,
+        params: mbr::sd_mbr_command_t__bindgen_ty_1 {
+            irq_forward_address_set: mbr::sd_mbr_command_irq_forward_address_set_t {
+                address: SOFTDEVICE_START,
+            },
+        },
+    };
+
+    if mbr::sd_mbr_command(&mut cmd) != NRF_SUCCESS {
+        SCB::sys_reset();
+    }
+
+    let msp = ptr::read_volatile(SOFTDEVICE_START as *const u32);
+    let reset_vector = ptr::read_volatile((SOFTDEVICE_START + 4) as *const u32);
+
+    cortex_m::asm::bootstrap(msp as *const u32, reset_vector as *const u32)
+}
+
+#[entry]
+fn main() -> ! {
+    let p = embassy_nrf::init(Default::default());
+
+    let flash = Mutex::new(RefCell::new(FedFlash(Nvmc::new(p.NVMC))));
+    let config = BootLoaderConfig::from_linkerfile_blocking(&flash, &flash, &flash);
+
+    let mut buffer = AlignedBuffer([0; PAGE_SIZE]);
+    let mut bootloader = BootLoader::new(config);
+
+    // Whatever is in ACTIVE is still the best bet if anything goes wrong
+    let image = match bootloader.prepare_boot(buffer.as_mut()) {
+        Ok(State::Swap) => ImageState::Trial,
+        Ok(State::Revert) => ImageState::RolledBack,
+        _ => ImageState::Confirmed,
+    };
+
+    if let Some(gpregret) = image.to_gpregret() {
+        pac::POWER
+            .gpregret()
+            .write(|w| w.set_gpregret(gpregret as u8));
+    }
+
+    unsafe { start_softdevice() }
+}
+
+#[panic_handler]
+fn panic(_info: &core::panic::PanicInfo) -> ! {
+    SCB::sys_reset();
+}
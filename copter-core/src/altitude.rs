@@ -0,0 +1,376 @@
+// Altitude
+//
+// Barometer knows the altitude, but it's noisy and lags behind. The IMU knows the
+// vertical acceleration, which is smooth but drifts off once integrated twice. The
+// estimator blends the two, the same way the attitude filter does with the gyro, and
+// tracks the climb rate along the way. Everything is relative to the ground at
+// arming, the absolute altitude is of no use here.
+//
+// Altitude hold closes the loop around it: altitude error asks for a climb rate, climb
+// rate error moves the throttle from where it was when the hold was engaged. Close to
+// the ground it flies on the rangefinder instead (see copter_core::range), which is
+// also what lets a forced landing come down gently rather than blind.
+
+use crate::shaping::STICK_FULL;
+
+pub const GRAVITY: f32 = 9.81;
+
+#[derive(Clone, Copy, Default, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Vertical {
+    // m above the ground at arming, m/s up
+    pub altitude: f32,
+    pub climb: f32,
+}
+
+// Only ever called close to 1, a few terms are plenty there
+fn ln(x: f32) -> f32 {
+    let y = (x - 1.0) / (x + 1.0);
+    let y2 = y * y;
+
+    2.0 * y * (1.0 + y2 * (1.0 / 3.0 + y2 * (1.0 / 5.0 + y2 / 7.0)))
+}
+
+// Hypsometric equation at 15°C, both in the same units
+pub fn pressure_altitude(pressure: f32, reference: f32) -> f32 {
+    // R * T / (M * g), m
+    const SCALE_HEIGHT: f32 = 8434.0;
+
+    SCALE_HEIGHT * ln(reference / pressure)
+}
+
+pub struct Estimator {
+    vertical: Option<Vertical>,
+}
+
+impl Estimator {
+    // Critically damped at ~1 rad/s. Slower trusts the accelerometer more
+    const ALTITUDE_GAIN: f32 = 2.0;
+    const CLIMB_GAIN: f32 = 1.0;
+
+    pub const fn new() -> Self {
+        Self { vertical: None }
+    }
+
+    // Barometric altitude in m. Acceleration is along the world up with the gravity
+    // taken out, m/s², zero if there's no IMU to tell
+    pub fn update(&mut self, baro: f32, accel: f32, dt: f32) -> Vertical {
+        let Some(v) = &mut self.vertical else {
+            let v = Vertical {
+                altitude: baro,
+                climb: 0.0,
+            };
+
+            self.vertical = Some(v);
+            return v;
+        };
+
+        let error = baro - v.altitude;
+
+        v.altitude += (v.climb + Self::ALTITUDE_GAIN * error) * dt;
+        v.climb += (accel + Self::CLIMB_GAIN * error) * dt;
+
+        *v
+    }
+}
+
+impl Default for Estimator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Target {
+    altitude: f32,
+    // Throttle stick when engaged, and the throttle it took to get there
+    stick: i32,
+    hover: i32,
+    // Altitude as of the last tick
+    last: f32,
+}
+
+pub struct AltitudeHold {
+    target: Option<Target>,
+    integral: f32,
+}
+
+impl AltitudeHold {
+    // m/s asked for per m off
+    const ALTITUDE_GAIN: f32 = 1.0;
+    const MAX_CLIMB: f32 = 1.0;
+    // Throttle per m/s off
+    const CLIMB_P: f32 = 60.0;
+    const CLIMB_I: f32 = 30.0;
+    const INTEGRAL_LIMIT: f32 = 100.0;
+    // Target doesn't run away while the airframe can't keep up
+    const MAX_LEAD: f32 = 2.0;
+    // Nobody holds a stick perfectly still
+    const STICK_DEADBAND: i32 = 32;
+    // Landing comes down at that much m/s per m of height, within the limits. Slow
+    // for the last bit
+    const LANDING_GAIN: f32 = 0.5;
+    const MIN_DESCENT: f32 = 0.15;
+    const MAX_DESCENT: f32 = 0.5;
+
+    pub const fn new() -> Self {
+        Self {
+            target: None,
+            integral: 0.0,
+        }
+    }
+
+    pub fn engaged(&self) -> bool {
+        self.target.is_some()
+    }
+
+    // Holds wherever it is, with the throttle it's flying on
+    pub fn engage(&mut self, throttle: i32, altitude: f32) {
+        self.target = Some(Target {
+            altitude,
+            stick: throttle,
+            hover: throttle,
+            last: altitude,
+        });
+        self.integral = 0.0;
+    }
+
+    pub fn disengage(&mut self) {
+        self.target = None;
+    }
+
+    // Altitude is measured against something else now, the target moves along so the
+    // airframe doesn't
+    pub fn rebase(&mut self, altitude: f32) {
+        if let Some(target) = &mut self.target {
+            target.altitude += altitude - target.last;
+            target.last = altitude;
+        }
+    }
+
+    // Throttle stick as shaped, gives the throttle to fly on. Moving the stick away
+    // from where it was when engaged asks for a climb or a descent
+    pub fn throttle(&mut self, stick: i32, vertical: Vertical, dt: f32) -> i32 {
+        let Some(target) = &self.target else {
+            return stick;
+        };
+
+        let offset = stick - target.stick;
+        let command = match offset.abs() > Self::STICK_DEADBAND {
+            true => {
+                let offset = offset - Self::STICK_DEADBAND * offset.signum();
+                (offset as f32 / (STICK_FULL / 2) as f32 * Self::MAX_CLIMB)
+                    .clamp(-Self::MAX_CLIMB, Self::MAX_CLIMB)
+            }
+            false => 0.0,
+        };
+
+        self.fly(command, vertical, dt).unwrap_or(stick)
+    }
+
+    // Comes down at a rate that eases off close to the ground, so the altitude has to
+    // be the height above it. None unless engaged
+    pub fn descend(&mut self, vertical: Vertical, dt: f32) -> Option<i32> {
+        let rate =
+            (vertical.altitude * Self::LANDING_GAIN).clamp(Self::MIN_DESCENT, Self::MAX_DESCENT);
+        self.fly(-rate, vertical, dt)
+    }
+
+    // Climb rate asked for, m/s
+    fn fly(&mut self, command: f32, vertical: Vertical, dt: f32) -> Option<i32> {
+        let target = self.target.as_mut()?;
+
+        target.last = vertical.altitude;
+        target.altitude = (target.altitude + command * dt).clamp(
+            vertical.altitude - Self::MAX_LEAD,
+            vertical.altitude + Self::MAX_LEAD,
+        );
+
+        let climb = (Self::ALTITUDE_GAIN * (target.altitude - vertical.altitude) + command)
+            .clamp(-Self::MAX_CLIMB, Self::MAX_CLIMB);
+        let error = climb - vertical.climb;
+
+        self.integral = (self.integral + Self::CLIMB_I * error * dt)
+            .clamp(-Self::INTEGRAL_LIMIT, Self::INTEGRAL_LIMIT);
+
+        let throttle = target.hover as f32 + Self::CLIMB_P * error + self.integral;
+        Some((throttle as i32).clamp(0, STICK_FULL))
+    }
+}
+
+impl Default for AltitudeHold {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DT: f32 = 0.005;
+
+    // Point mass, lift goes with the throttle
+    struct Plant {
+        vertical: Vertical,
+        hover: f32,
+    }
+
+    impl Plant {
+        fn new(hover: f32) -> Self {
+            Self {
+                vertical: Vertical::default(),
+                hover,
+            }
+        }
+
+        fn step(&mut self, throttle: i32) {
+            let accel = (throttle as f32 / self.hover - 1.0) * GRAVITY;
+
+            self.vertical.climb += accel * DT;
+            self.vertical.altitude += self.vertical.climb * DT;
+        }
+    }
+
+    fn fly(hold: &mut AltitudeHold, plant: &mut Plant, stick: i32, seconds: f32) {
+        for _ in 0..(seconds / DT) as usize {
+            let throttle = hold.throttle(stick, plant.vertical, DT);
+            plant.step(throttle);
+        }
+    }
+
+    #[test]
+    fn pressure_altitude_matches_the_tables() {
+        assert_eq!(pressure_altitude(101325.0, 101325.0), 0.0);
+
+        // ~8.3 m per hPa near the sea level, and the exact formula further up
+        assert!((pressure_altitude(101225.0, 101325.0) - 8.32).abs() < 0.05);
+        let exact = 8434.0 * (101325.0f32 / 95000.0).ln();
+        assert!((pressure_altitude(95000.0, 101325.0) - exact).abs() < 0.01);
+    }
+
+    #[test]
+    fn estimator_follows_a_climb_without_an_imu() {
+        let mut estimator = Estimator::new();
+        let mut v = Vertical::default();
+
+        // 0.5 m/s up for 10s
+        for i in 0..2000 {
+            v = estimator.update(i as f32 * DT * 0.5, 0.0, DT);
+        }
+
+        assert!((v.climb - 0.5).abs() < 0.05, "climb {}", v.climb);
+        assert!((v.altitude - 5.0).abs() < 0.1, "altitude {}", v.altitude);
+    }
+
+    #[test]
+    fn estimator_smooths_the_barometer_noise() {
+        let mut estimator = Estimator::new();
+        let mut worst: f32 = 0.0;
+
+        // Sitting at 1m, with the barometer jumping ±0.5m every other sample
+        for i in 0..2000 {
+            let noise = if i % 2 == 0 { 0.5 } else { -0.5 };
+            let v = estimator.update(1.0 + noise, 0.0, DT);
+
+            if i > 1000 {
+                worst = worst.max((v.altitude - 1.0).abs());
+            }
+        }
+
+        assert!(worst < 0.05, "off by {}", worst);
+    }
+
+    #[test]
+    fn hold_keeps_the_altitude_against_a_wrong_hover_guess() {
+        let mut hold = AltitudeHold::new();
+        let mut plant = Plant::new(300.0);
+
+        // Engaged on a throttle that's a bit too low, the integral has to make up for it
+        hold.engage(280, 0.0);
+        fly(&mut hold, &mut plant, 280, 10.0);
+
+        assert!(plant.vertical.altitude.abs() < 0.1, "{:?}", plant.vertical);
+        assert!(plant.vertical.climb.abs() < 0.05, "{:?}", plant.vertical);
+    }
+
+    #[test]
+    fn stick_asks_for_a_climb() {
+        let mut hold = AltitudeHold::new();
+        let mut plant = Plant::new(300.0);
+
+        hold.engage(300, 0.0);
+        // Half of the range past the deadband is half of the max climb
+        fly(&mut hold, &mut plant, 300 + 32 + 128, 3.0);
+        assert!(
+            (plant.vertical.climb - 0.5).abs() < 0.05,
+            "{:?}",
+            plant.vertical
+        );
+
+        // Stick back where it was holds wherever it got to
+        fly(&mut hold, &mut plant, 300, 5.0);
+        let altitude = plant.vertical.altitude;
+        fly(&mut hold, &mut plant, 300, 2.0);
+
+        assert!(altitude > 1.0);
+        assert!((plant.vertical.altitude - altitude).abs() < 0.1);
+    }
+
+    #[test]
+    fn descent_slows_down_close_to_the_ground() {
+        let mut hold = AltitudeHold::new();
+        let mut plant = Plant::new(300.0);
+        plant.vertical.altitude = 2.0;
+
+        assert_eq!(hold.descend(plant.vertical, DT), None);
+
+        hold.engage(300, 2.0);
+        let mut fastest: f32 = 0.0;
+
+        while plant.vertical.altitude > 0.05 {
+            let throttle = hold.descend(plant.vertical, DT).unwrap();
+            plant.step(throttle);
+            fastest = fastest.min(plant.vertical.climb);
+        }
+
+        assert!(fastest > -0.8, "{}", fastest);
+        assert!(plant.vertical.climb > -0.2, "{:?}", plant.vertical);
+    }
+
+    #[test]
+    fn rebase_keeps_the_airframe_where_it_is() {
+        let mut hold = AltitudeHold::new();
+        let mut plant = Plant::new(300.0);
+
+        hold.engage(300, 0.0);
+        fly(&mut hold, &mut plant, 300, 2.0);
+
+        // Same airframe, measured from 5m lower down. Without the rebase the hold would
+        // go for the ground
+        hold.rebase(plant.vertical.altitude + 5.0);
+        let mut offset = Plant::new(300.0);
+        offset.vertical = Vertical {
+            altitude: plant.vertical.altitude + 5.0,
+            ..plant.vertical
+        };
+
+        fly(&mut hold, &mut offset, 300, 5.0);
+        assert!(
+            (offset.vertical.altitude - 5.0).abs() < 0.1,
+            "{:?}",
+            offset.vertical
+        );
+    }
+
+    #[test]
+    fn disengaged_hold_passes_the_stick_through() {
+        let mut hold = AltitudeHold::new();
+
+        assert_eq!(hold.throttle(123, Vertical::default(), DT), 123);
+
+        hold.engage(300, 0.0);
+        hold.disengage();
+        assert_eq!(hold.throttle(123, Vertical::default(), DT), 123);
+    }
+}
@@ -0,0 +1,444 @@
+// Attitude estimation
+//
+// Mahony filter: the gyro is integrated into a quaternion, and the accelerometer pulls
+// it back towards where gravity is with a PI correction, which takes the gyro drift out
+// of roll and pitch. Without a magnetometer, heading has nothing to be corrected
+// against, so it drifts along with the yaw gyro - good enough to hold it for a while,
+// not to navigate by. With one, the field pulls the heading towards the magnetic north
+// the same way.
+//
+// Body frame is forward, left, up. Nothing in std is available on the target, so the
+// few functions needed are approximated below, well within what the sensors can tell.
+
+use core::f32::consts::{FRAC_PI_2, PI};
+
+#[derive(Clone, Copy, Default, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Attitude {
+    // Degrees. Right side down and nose up are positive
+    pub roll: f32,
+    pub pitch: f32,
+    // Degrees from wherever the nose was at the start, or from the magnetic north with a
+    // magnetometer. Counterclockwise, 0..360
+    pub heading: f32,
+}
+
+// Quake style, two Newton steps are plenty
+fn inv_sqrt(x: f32) -> f32 {
+    let mut y = f32::from_bits(0x5f37_59df - (x.to_bits() >> 1));
+    y *= 1.5 - 0.5 * x * y * y;
+    y *= 1.5 - 0.5 * x * y * y;
+    y
+}
+
+// Minimax polynomial, |x| <= 1. Error is ~1e-5 rad
+fn atan(x: f32) -> f32 {
+    let x2 = x * x;
+    x * (0.999_977_26
+        + x2 * (-0.332_623_47
+            + x2 * (0.193_543_46 + x2 * (-0.116_432_87 + x2 * (0.052_653_32 - x2 * 0.011_721_2)))))
+}
+
+fn atan2(y: f32, x: f32) -> f32 {
+    if x == 0.0 && y == 0.0 {
+        return 0.0;
+    }
+
+    if x.abs() >= y.abs() {
+        let a = atan(y / x);
+
+        match x < 0.0 {
+            true if y < 0.0 => a - PI,
+            true => a + PI,
+            false => a,
+        }
+    } else {
+        let a = atan(x / y);
+
+        match y < 0.0 {
+            true => -FRAC_PI_2 - a,
+            false => FRAC_PI_2 - a,
+        }
+    }
+}
+
+fn asin(x: f32) -> f32 {
+    let x = x.clamp(-1.0, 1.0);
+    let c = 1.0 - x * x;
+
+    match c > 0.0 {
+        true => atan2(x, c * inv_sqrt(c)),
+        false => FRAC_PI_2.copysign(x),
+    }
+}
+
+pub struct Mahony {
+    // w, x, y, z
+    q: [f32; 4],
+    integral: [f32; 3],
+    kp: f32,
+    ki: f32,
+}
+
+impl Mahony {
+    // Accel readings off by more than that (g) from 1g are not gravity, mostly. Rotors
+    // shake the frame a lot
+    const ACCEL_TRUST: f32 = 0.3;
+    // Same for the calibrated field, which is 1 as well. Motor currents are right there
+    const MAG_TRUST: f32 = 0.3;
+
+    pub const fn new(kp: f32, ki: f32) -> Self {
+        Self {
+            q: [1.0, 0.0, 0.0, 0.0],
+            integral: [0.0; 3],
+            kp,
+            ki,
+        }
+    }
+
+    // Starts over from what the accelerometer says. Heading is the new zero, or comes
+    // from the magnetometer if there's one. Saves the filter from having to converge
+    // from level when it's not
+    pub fn align(&mut self, accel: [f32; 3], mag: Option<[f32; 3]>) {
+        let [ax, ay, az] = accel;
+        let roll = atan2(ay, az);
+        let pitch = atan2(-ax, (ay * ay + az * az) * inv_sqrt(ay * ay + az * az));
+
+        let (sr, cr) = half_sin_cos(roll);
+        let (sp, cp) = half_sin_cos(pitch);
+
+        self.q = [cr * cp, sr * cp, cr * sp, -sr * sp];
+        self.integral = [0.0; 3];
+
+        let Some([mx, my, mz]) = mag else {
+            return;
+        };
+
+        // Horizontal part of the field, as seen with the tilt taken out
+        let [q0, q1, q2, q3] = self.q;
+        let hx = mx * (1.0 - 2.0 * (q2 * q2 + q3 * q3))
+            + my * 2.0 * (q1 * q2 - q0 * q3)
+            + mz * 2.0 * (q1 * q3 + q0 * q2);
+        let hy = mx * 2.0 * (q1 * q2 + q0 * q3)
+            + my * (1.0 - 2.0 * (q1 * q1 + q3 * q3))
+            + mz * 2.0 * (q2 * q3 - q0 * q1);
+
+        // Turned by the heading around the world up
+        let (sy, cy) = half_sin_cos(-atan2(hy, hx));
+        self.q = [
+            cy * q0 - sy * q3,
+            cy * q1 - sy * q2,
+            cy * q2 + sy * q1,
+            cy * q3 + sy * q0,
+        ];
+    }
+
+    // Gyro in dps, accel in g, dt in seconds
+    pub fn update(&mut self, gyro: [f32; 3], accel: [f32; 3], dt: f32) {
+        self.step(gyro, accel, None, dt);
+    }
+
+    // Same, with a calibrated magnetometer reading (see copter_core::compass)
+    pub fn update_with_mag(&mut self, gyro: [f32; 3], accel: [f32; 3], mag: [f32; 3], dt: f32) {
+        self.step(gyro, accel, Some(mag), dt);
+    }
+
+    fn step(&mut self, gyro: [f32; 3], accel: [f32; 3], mag: Option<[f32; 3]>, dt: f32) {
+        let [mut gx, mut gy, mut gz] = gyro.map(|g| g.to_radians());
+        let [q0, q1, q2, q3] = self.q;
+
+        // How far off it is, halved, summed up over whatever there is to go on
+        let mut e = [0.0; 3];
+        let mut corrected = false;
+
+        if let Some([ax, ay, az]) = normalized(accel, Self::ACCEL_TRUST) {
+            // Where gravity should be, halved
+            let vx = q1 * q3 - q0 * q2;
+            let vy = q0 * q1 + q2 * q3;
+            let vz = q0 * q0 - 0.5 + q3 * q3;
+
+            e[0] += ay * vz - az * vy;
+            e[1] += az * vx - ax * vz;
+            e[2] += ax * vy - ay * vx;
+            corrected = true;
+        }
+
+        if let Some([mx, my, mz]) = mag.and_then(|m| normalized(m, Self::MAG_TRUST)) {
+            // Field in the world frame. Only its horizontal and vertical parts are
+            // known, which way it points horizontally is the north by definition
+            let hx = 2.0
+                * (mx * (0.5 - q2 * q2 - q3 * q3)
+                    + my * (q1 * q2 - q0 * q3)
+                    + mz * (q1 * q3 + q0 * q2));
+            let hy = 2.0
+                * (mx * (q1 * q2 + q0 * q3)
+                    + my * (0.5 - q1 * q1 - q3 * q3)
+                    + mz * (q2 * q3 - q0 * q1));
+            let bz = 2.0
+                * (mx * (q1 * q3 - q0 * q2)
+                    + my * (q2 * q3 + q0 * q1)
+                    + mz * (0.5 - q1 * q1 - q2 * q2));
+            let h2 = hx * hx + hy * hy;
+            let bx = h2 * inv_sqrt(h2);
+
+            // Where the field should be then, halved
+            let wx = bx * (0.5 - q2 * q2 - q3 * q3) + bz * (q1 * q3 - q0 * q2);
+            let wy = bx * (q1 * q2 - q0 * q3) + bz * (q0 * q1 + q2 * q3);
+            let wz = bx * (q0 * q2 + q1 * q3) + bz * (0.5 - q1 * q1 - q2 * q2);
+
+            e[0] += my * wz - mz * wy;
+            e[1] += mz * wx - mx * wz;
+            e[2] += mx * wy - my * wx;
+            corrected = true;
+        }
+
+        if corrected {
+            for (i, e) in e.iter().enumerate() {
+                self.integral[i] += 2.0 * self.ki * e * dt;
+            }
+
+            gx += self.integral[0] + 2.0 * self.kp * e[0];
+            gy += self.integral[1] + 2.0 * self.kp * e[1];
+            gz += self.integral[2] + 2.0 * self.kp * e[2];
+        }
+
+        let (hx, hy, hz) = (gx * 0.5 * dt, gy * 0.5 * dt, gz * 0.5 * dt);
+
+        let q = [
+            q0 - q1 * hx - q2 * hy - q3 * hz,
+            q1 + q0 * hx + q2 * hz - q3 * hy,
+            q2 + q0 * hy - q1 * hz + q3 * hx,
+            q3 + q0 * hz + q1 * hy - q2 * hx,
+        ];
+
+        let r = inv_sqrt(q.iter().map(|v| v * v).sum());
+        self.q = q.map(|v| v * r);
+    }
+
+    pub fn attitude(&self) -> Attitude {
+        let [q0, q1, q2, q3] = self.q;
+
+        let roll = atan2(2.0 * (q0 * q1 + q2 * q3), 1.0 - 2.0 * (q1 * q1 + q2 * q2));
+        let pitch = asin(2.0 * (q0 * q2 - q3 * q1));
+        let yaw = atan2(2.0 * (q0 * q3 + q1 * q2), 1.0 - 2.0 * (q2 * q2 + q3 * q3)).to_degrees();
+
+        Attitude {
+            // Body frame has Y to the left, so a positive X rotation puts the right side
+            // down. Positive Y rotation puts the nose down though
+            roll: roll.to_degrees(),
+            pitch: -pitch.to_degrees(),
+            heading: match yaw < 0.0 {
+                true => yaw + 360.0,
+                false => yaw,
+            },
+        }
+    }
+
+    // World up, in the body frame
+    fn up(&self) -> [f32; 3] {
+        let [q0, q1, q2, q3] = self.q;
+
+        [
+            2.0 * (q1 * q3 - q0 * q2),
+            2.0 * (q0 * q1 + q2 * q3),
+            q0 * q0 - q1 * q1 - q2 * q2 + q3 * q3,
+        ]
+    }
+
+    // Acceleration along the world up with the gravity taken out, g. Accel is in the
+    // body frame, as it comes
+    pub fn vertical_accel(&self, accel: [f32; 3]) -> f32 {
+        self.up().iter().zip(accel).map(|(u, a)| u * a).sum::<f32>() - 1.0
+    }
+
+    // Cosine of the angle between the body and the world up, 1 when level. Anything
+    // looking straight down sees that much further than the height
+    pub fn tilt(&self) -> f32 {
+        self.up()[2]
+    }
+}
+
+impl Default for Mahony {
+    // Fairly soft, the accelerometer is shaken a lot in flight
+    fn default() -> Self {
+        Self::new(0.5, 0.01)
+    }
+}
+
+// Scaled to 1, unless it's too far off from 1 to be trusted
+fn normalized(v: [f32; 3], trust: f32) -> Option<[f32; 3]> {
+    let norm = v.iter().map(|x| x * x).sum::<f32>();
+
+    match (norm - 1.0).abs() < 2.0 * trust {
+        true => {
+            let r = inv_sqrt(norm);
+            Some(v.map(|x| x * r))
+        }
+        false => None,
+    }
+}
+
+fn half_sin_cos(angle: f32) -> (f32, f32) {
+    // Only ever called with angles within ±180°. Taylor is fine at half of that with a
+    // few terms
+    let x = angle * 0.5;
+    let x2 = x * x;
+    let sin = x * (1.0 - x2 / 6.0 * (1.0 - x2 / 20.0 * (1.0 - x2 / 42.0 * (1.0 - x2 / 72.0))));
+    let cos = 1.0 - x2 / 2.0 * (1.0 - x2 / 12.0 * (1.0 - x2 / 30.0 * (1.0 - x2 / 56.0)));
+    (sin, cos)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DT: f32 = 0.005;
+
+    fn close(a: f32, b: f32, tolerance: f32) -> bool {
+        (a - b).abs() < tolerance
+    }
+
+    #[test]
+    fn math_is_close_enough() {
+        for i in -100..=100 {
+            let x = i as f32 / 10.0;
+
+            assert!(close(
+                inv_sqrt(x.abs() + 0.01),
+                1.0 / (x.abs() + 0.01).sqrt(),
+                1e-4
+            ));
+            assert!(close(atan2(x, 1.0), x.atan2(1.0), 1e-4));
+            assert!(close(atan2(1.0, x), 1f32.atan2(x), 1e-4));
+            assert!(close(atan2(-1.0, x), (-1f32).atan2(x), 1e-4));
+            assert!(close(asin(x / 10.0), (x / 10.0).asin(), 1e-3));
+
+            let (s, c) = half_sin_cos(x / 10.0 * PI);
+            assert!(close(s, (x / 20.0 * PI).sin(), 1e-3));
+            assert!(close(c, (x / 20.0 * PI).cos(), 1e-3));
+        }
+    }
+
+    #[test]
+    fn level_and_still_stays_put() {
+        let mut filter = Mahony::default();
+
+        for _ in 0..1000 {
+            filter.update([0.0; 3], [0.0, 0.0, 1.0], DT);
+        }
+
+        assert_eq!(filter.attitude(), Attitude::default());
+    }
+
+    #[test]
+    fn align_matches_the_accelerometer() {
+        let mut filter = Mahony::default();
+        let (roll, pitch) = (20f32.to_radians(), -10f32.to_radians());
+
+        // Gravity as seen from a body rolled right and pitched down
+        let accel = [
+            pitch.sin(),
+            roll.sin() * pitch.cos(),
+            roll.cos() * pitch.cos(),
+        ];
+        filter.align(accel, None);
+
+        let a = filter.attitude();
+        assert!(close(a.roll, 20.0, 0.1), "roll {}", a.roll);
+        assert!(close(a.pitch, -10.0, 0.1), "pitch {}", a.pitch);
+        assert!(close(a.heading, 0.0, 0.1) || close(a.heading, 360.0, 0.1));
+        assert!(close(filter.tilt(), roll.cos() * pitch.cos(), 0.001));
+    }
+
+    #[test]
+    fn vertical_accel_is_along_the_world_up() {
+        let mut filter = Mahony::default();
+        let roll = 30f32.to_radians();
+
+        // Rolled, and pushed up at 0.5g on top of the gravity
+        let gravity = [0.0, roll.sin(), roll.cos()];
+        filter.align(gravity, None);
+
+        let accel = gravity.map(|g| g * 1.5);
+        assert!(close(filter.vertical_accel(accel), 0.5, 0.01));
+        assert!(close(filter.vertical_accel(gravity), 0.0, 0.01));
+    }
+
+    #[test]
+    fn heading_follows_the_yaw_gyro() {
+        let mut filter = Mahony::default();
+
+        // 90 dps counterclockwise for a second
+        for _ in 0..200 {
+            filter.update([0.0, 0.0, 90.0], [0.0, 0.0, 1.0], DT);
+        }
+
+        let a = filter.attitude();
+        assert!(close(a.heading, 90.0, 0.5), "heading {}", a.heading);
+        assert!(close(a.roll, 0.0, 0.1) && close(a.pitch, 0.0, 0.1));
+    }
+
+    #[test]
+    fn gyro_drift_is_pulled_back_on_roll() {
+        let mut filter = Mahony::default();
+
+        // Biased gyro says it's rolling, the accelerometer says it's level. Gyro alone
+        // would be 40° off by the end, the integral takes the rest out over time
+        for _ in 0..20 * 200 {
+            filter.update([2.0, 0.0, 0.0], [0.0, 0.0, 1.0], DT);
+        }
+
+        let a = filter.attitude();
+        assert!(close(a.roll, 0.0, 3.0), "roll {}", a.roll);
+    }
+
+    // Field pointing north and down, the way it does up here
+    fn field(heading: f32) -> [f32; 3] {
+        let (h, dip) = (heading.to_radians(), 60f32.to_radians());
+        [h.cos() * dip.cos(), -h.sin() * dip.cos(), -dip.sin()]
+    }
+
+    #[test]
+    fn align_takes_the_heading_from_the_magnetometer() {
+        let mut filter = Mahony::default();
+
+        // Nose 90° counterclockwise from the north, so the north is to the right
+        filter.align([0.0, 0.0, 1.0], Some(field(90.0)));
+
+        let a = filter.attitude();
+        assert!(close(a.heading, 90.0, 0.5), "heading {}", a.heading);
+        assert!(close(a.roll, 0.0, 0.1) && close(a.pitch, 0.0, 0.1));
+    }
+
+    #[test]
+    fn magnetometer_takes_the_drift_out_of_the_heading() {
+        let mut filter = Mahony::default();
+        filter.align([0.0, 0.0, 1.0], Some(field(0.0)));
+
+        // Gyro alone would be 30° off by the end. Field doesn't pull as hard as
+        // gravity does, it's mostly pointing down
+        for _ in 0..60 * 200 {
+            filter.update_with_mag([0.0, 0.0, 0.5], [0.0, 0.0, 1.0], field(0.0), DT);
+        }
+
+        let a = filter.attitude();
+        let off = a.heading.min(360.0 - a.heading);
+        assert!(off < 6.0, "heading {}", a.heading);
+        assert!(
+            close(a.roll, 0.0, 1.5) && close(a.pitch, 0.0, 1.5),
+            "{:?}",
+            a
+        );
+    }
+
+    #[test]
+    fn shaking_is_not_taken_for_gravity() {
+        let mut filter = Mahony::default();
+
+        for _ in 0..200 {
+            filter.update([0.0; 3], [2.0, 0.0, 1.0], DT);
+        }
+
+        assert_eq!(filter.attitude(), Attitude::default());
+    }
+}
@@ -0,0 +1,59 @@
+// Bootloader handshake
+//
+// Bootloader leaves a note in GPREGRET on its way to the application, saying whether
+// the image it's starting was just swapped in and is on trial, or whether the previous
+// one was brought back because the trial didn't work out. Anything else is left alone.
+
+pub const GPREGRET_TRIAL: u32 = 0xb0;
+pub const GPREGRET_ROLLED_BACK: u32 = 0xb1;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum ImageState {
+    // Has been running for long enough before, or there's no bootloader at all
+    Confirmed,
+    // Has to be marked as booted, otherwise it's swapped back on the next reset
+    Trial,
+    // Previous image didn't make it through the trial, so we're back to this one
+    RolledBack,
+}
+
+impl ImageState {
+    pub fn to_gpregret(self) -> Option<u32> {
+        match self {
+            Self::Confirmed => None,
+            Self::Trial => Some(GPREGRET_TRIAL),
+            Self::RolledBack => Some(GPREGRET_ROLLED_BACK),
+        }
+    }
+
+    pub fn from_gpregret(gpregret: u32) -> Self {
+        match gpregret {
+            GPREGRET_TRIAL => Self::Trial,
+            GPREGRET_ROLLED_BACK => Self::RolledBack,
+            _ => Self::Confirmed,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn survives_the_handshake() {
+        for state in [ImageState::Trial, ImageState::RolledBack] {
+            assert_eq!(
+                ImageState::from_gpregret(state.to_gpregret().unwrap()),
+                state
+            );
+        }
+    }
+
+    #[test]
+    fn anything_else_is_confirmed() {
+        assert_eq!(ImageState::Confirmed.to_gpregret(), None);
+        assert_eq!(ImageState::from_gpregret(0), ImageState::Confirmed);
+        assert_eq!(ImageState::from_gpregret(0x01), ImageState::Confirmed);
+    }
+}
@@ -0,0 +1,152 @@
+// Compass calibration
+//
+// Magnetometer readings come shifted by whatever magnetized bits of the airframe add
+// on (hard iron, a constant offset), and squashed by whatever iron is around (soft
+// iron, the sphere the readings should lie on comes out as an ellipsoid). Turning the
+// airframe around through every orientation traces it out: the middle of it is the
+// offset, and its extent along each axis is the scale. A full soft iron correction
+// takes an ellipsoid fit, the axis-aligned part is most of it on a board this small.
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Calibration {
+    // Raw units, whatever the chip has
+    pub offset: [f32; 3],
+    pub radius: [f32; 3],
+}
+
+impl Calibration {
+    // Raw reading to the unit sphere, which is what the attitude filter wants
+    pub fn apply(&self, raw: [f32; 3]) -> [f32; 3] {
+        core::array::from_fn(|i| (raw[i] - self.offset[i]) / self.radius[i])
+    }
+}
+
+pub struct Calibrator {
+    min: [f32; 3],
+    max: [f32; 3],
+    samples: u32,
+}
+
+impl Calibrator {
+    const MIN_SAMPLES: u32 = 100;
+    // Axes of the earth field can't differ by more than that, whatever is around. More
+    // means some axis was never turned through
+    const MAX_ASPECT: f32 = 1.5;
+
+    pub const fn new() -> Self {
+        Self {
+            min: [f32::MAX; 3],
+            max: [f32::MIN; 3],
+            samples: 0,
+        }
+    }
+
+    pub fn add(&mut self, raw: [f32; 3]) {
+        for (i, v) in raw.iter().enumerate() {
+            self.min[i] = self.min[i].min(*v);
+            self.max[i] = self.max[i].max(*v);
+        }
+
+        self.samples += 1;
+    }
+
+    // None unless it was turned around enough
+    pub fn finish(&self) -> Option<Calibration> {
+        if self.samples < Self::MIN_SAMPLES {
+            return None;
+        }
+
+        let radius: [f32; 3] = core::array::from_fn(|i| (self.max[i] - self.min[i]) / 2.0);
+        let smallest = radius.iter().copied().fold(f32::MAX, f32::min);
+        let largest = radius.iter().copied().fold(0.0, f32::max);
+
+        if smallest <= 0.0 || largest > smallest * Self::MAX_ASPECT {
+            return None;
+        }
+
+        Some(Calibration {
+            offset: core::array::from_fn(|i| (self.max[i] + self.min[i]) / 2.0),
+            radius,
+        })
+    }
+}
+
+impl Default for Calibrator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const OFFSET: [f32; 3] = [120.0, -45.0, 300.0];
+    const RADIUS: [f32; 3] = [1000.0, 900.0, 1100.0];
+
+    // What the chip would read with the field along a given direction
+    fn reading(direction: [f32; 3]) -> [f32; 3] {
+        core::array::from_fn(|i| OFFSET[i] + direction[i] * RADIUS[i])
+    }
+
+    // Directions all over the sphere, or only around the vertical axis
+    fn directions(tumbled: bool) -> Vec<[f32; 3]> {
+        let mut v = Vec::new();
+
+        for i in 0..36 {
+            let yaw = (i as f32 * 10.0).to_radians();
+
+            for j in 0..=12 {
+                let dip = match tumbled {
+                    true => (j as f32 * 15.0 - 90.0).to_radians(),
+                    false => 60f32.to_radians(),
+                };
+
+                v.push([yaw.cos() * dip.cos(), yaw.sin() * dip.cos(), dip.sin()]);
+            }
+        }
+
+        v
+    }
+
+    #[test]
+    fn offset_and_scale_are_found() {
+        let mut calibrator = Calibrator::new();
+
+        for d in directions(true) {
+            calibrator.add(reading(d));
+        }
+
+        let calibration = calibrator.finish().unwrap();
+
+        for d in directions(true) {
+            let m = calibration.apply(reading(d));
+
+            for i in 0..3 {
+                assert!((m[i] - d[i]).abs() < 1e-3, "{:?} vs {:?}", m, d);
+            }
+        }
+    }
+
+    #[test]
+    fn turning_it_flat_is_not_enough() {
+        let mut calibrator = Calibrator::new();
+
+        for d in directions(false) {
+            calibrator.add(reading(d));
+        }
+
+        assert_eq!(calibrator.finish(), None);
+    }
+
+    #[test]
+    fn a_few_samples_are_not_enough() {
+        let mut calibrator = Calibrator::new();
+
+        for d in directions(true).iter().step_by(10) {
+            calibrator.add(reading(*d));
+        }
+
+        assert_eq!(calibrator.finish(), None);
+    }
+}
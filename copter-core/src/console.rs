@@ -0,0 +1,331 @@
+// Debug console
+//
+// One set of commands, whichever way the line came in: the Nordic UART service and
+// the RTT down channel both end up in dispatch(). Subsystems keep tables of their own
+// commands, the firmware only lists the tables. Nothing is allocated, a line is split
+// in place and the reply goes into whatever the caller hands over.
+
+use core::fmt::{self, Write};
+use core::str::{FromStr, SplitAsciiWhitespace};
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Error {
+    UnknownCommand,
+    MissingArgument,
+    BadArgument,
+    TooManyArguments,
+    LineTooLong,
+    // Reply didn't fit, whatever did is still there
+    Output,
+    // Command made sense, but it's not happening right now
+    Refused,
+}
+
+impl Error {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::UnknownCommand => "unknown command, try help",
+            Self::MissingArgument => "missing argument",
+            Self::BadArgument => "bad argument",
+            Self::TooManyArguments => "too many arguments",
+            Self::LineTooLong => "line too long",
+            Self::Output => "reply truncated",
+            Self::Refused => "refused",
+        }
+    }
+}
+
+// So that the handlers can go with writeln!(...)?
+impl From<fmt::Error> for Error {
+    fn from(_: fmt::Error) -> Self {
+        Self::Output
+    }
+}
+
+pub struct Args<'a> {
+    tokens: SplitAsciiWhitespace<'a>,
+}
+
+impl<'a> Args<'a> {
+    pub fn new(line: &'a str) -> Self {
+        Self {
+            tokens: line.split_ascii_whitespace(),
+        }
+    }
+
+    pub fn next_str(&mut self) -> Result<&'a str, Error> {
+        self.tokens.next().ok_or(Error::MissingArgument)
+    }
+
+    pub fn parse<T: FromStr>(&mut self) -> Result<T, Error> {
+        self.next_str()?.parse().map_err(|_| Error::BadArgument)
+    }
+
+    pub fn optional<T: FromStr>(&mut self) -> Result<Option<T>, Error> {
+        match self.tokens.next() {
+            Some(token) => token.parse().map(Some).map_err(|_| Error::BadArgument),
+            None => Ok(None),
+        }
+    }
+
+    // Handlers call that once they've taken everything they wanted
+    pub fn finish(mut self) -> Result<(), Error> {
+        match self.tokens.next() {
+            Some(_) => Err(Error::TooManyArguments),
+            None => Ok(()),
+        }
+    }
+}
+
+pub type Handler<C> = fn(&C, Args, &mut dyn Write) -> Result<(), Error>;
+
+pub struct Command<C> {
+    pub name: &'static str,
+    // Arguments and what it does, printed by help
+    pub help: &'static str,
+    pub run: Handler<C>,
+}
+
+fn help<C>(tables: &[&[Command<C>]], out: &mut dyn Write) -> Result<(), Error> {
+    for command in tables.iter().flat_map(|table| table.iter()) {
+        writeln!(out, "{} {}", command.name, command.help)?;
+    }
+
+    Ok(())
+}
+
+// Empty line is fine, it's someone pressing enter
+pub fn dispatch<C>(
+    tables: &[&[Command<C>]],
+    context: &C,
+    line: &str,
+    out: &mut dyn Write,
+) -> Result<(), Error> {
+    let mut args = Args::new(line);
+
+    let Ok(name) = args.next_str() else {
+        return Ok(());
+    };
+
+    if name == "help" {
+        args.finish()?;
+        return help(tables, out);
+    }
+
+    let command = tables
+        .iter()
+        .flat_map(|table| table.iter())
+        .find(|command| command.name == name)
+        .ok_or(Error::UnknownCommand)?;
+
+    (command.run)(context, args, out)
+}
+
+// Collects bytes into lines. Either of CR and LF ends one, so it doesn't matter which
+// of them the terminal sends, and the other one is just an empty line
+pub struct LineBuffer<const N: usize> {
+    buf: [u8; N],
+    len: usize,
+    overflow: bool,
+    complete: bool,
+}
+
+impl<const N: usize> LineBuffer<N> {
+    pub const fn new() -> Self {
+        Self {
+            buf: [0; N],
+            len: 0,
+            overflow: false,
+            complete: false,
+        }
+    }
+
+    // Gives the line back once it's complete. One that didn't fit is thrown away as a
+    // whole, running half of a command is worse than not running it
+    pub fn push(&mut self, byte: u8) -> Option<Result<&str, Error>> {
+        if self.complete {
+            self.len = 0;
+            self.overflow = false;
+            self.complete = false;
+        }
+
+        match byte {
+            b'\r' | b'\n' => {
+                self.complete = true;
+
+                Some(match self.overflow {
+                    true => Err(Error::LineTooLong),
+                    false => {
+                        core::str::from_utf8(&self.buf[..self.len]).map_err(|_| Error::BadArgument)
+                    }
+                })
+            }
+            // Backspace and DEL, depending on the terminal
+            0x08 | 0x7f => {
+                self.len = self.len.saturating_sub(1);
+                None
+            }
+            _ if self.len == N => {
+                self.overflow = true;
+                None
+            }
+            _ => {
+                self.buf[self.len] = byte;
+                self.len += 1;
+                None
+            }
+        }
+    }
+}
+
+impl<const N: usize> Default for LineBuffer<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Fixed size reply. Whatever doesn't fit is dropped, and the writer gets to know
+pub struct Reply<const N: usize> {
+    buf: [u8; N],
+    len: usize,
+}
+
+impl<const N: usize> Reply<N> {
+    pub const fn new() -> Self {
+        Self {
+            buf: [0; N],
+            len: 0,
+        }
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.buf[..self.len]
+    }
+}
+
+impl<const N: usize> Default for Reply<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize> Write for Reply<N> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let room = N - self.len;
+
+        // Cut on a char boundary, so that the reply is still valid UTF-8
+        let take = match s.len() <= room {
+            true => s.len(),
+            false => (0..=room)
+                .rev()
+                .find(|&i| s.is_char_boundary(i))
+                .unwrap_or(0),
+        };
+
+        self.buf[self.len..self.len + take].copy_from_slice(&s.as_bytes()[..take]);
+        self.len += take;
+
+        match take == s.len() {
+            true => Ok(()),
+            false => Err(fmt::Error),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Context;
+
+    fn add(_: &Context, mut args: Args, out: &mut dyn Write) -> Result<(), Error> {
+        let a: u32 = args.parse()?;
+        let b: u32 = args.optional()?.unwrap_or(1);
+        args.finish()?;
+
+        write!(out, "{}", a + b)?;
+        Ok(())
+    }
+
+    fn ping(_: &Context, args: Args, out: &mut dyn Write) -> Result<(), Error> {
+        args.finish()?;
+        write!(out, "pong")?;
+        Ok(())
+    }
+
+    const MATH: &[Command<Context>] = &[Command {
+        name: "add",
+        help: "<a> [b] - adds them up",
+        run: add,
+    }];
+
+    const MISC: &[Command<Context>] = &[Command {
+        name: "ping",
+        help: "- pong",
+        run: ping,
+    }];
+
+    const TABLES: &[&[Command<Context>]] = &[MATH, MISC];
+
+    fn run(line: &str) -> (Result<(), Error>, String) {
+        let mut out = Reply::<64>::new();
+        let r = dispatch(TABLES, &Context, line, &mut out);
+
+        (r, String::from_utf8(out.as_bytes().to_vec()).unwrap())
+    }
+
+    #[test]
+    fn commands_from_every_table_are_found() {
+        assert_eq!(run("add 2 3"), (Ok(()), "5".into()));
+        assert_eq!(run("  ping "), (Ok(()), "pong".into()));
+        assert_eq!(run("add 2"), (Ok(()), "3".into()));
+    }
+
+    #[test]
+    fn bad_input_is_reported() {
+        assert_eq!(run("launch").0, Err(Error::UnknownCommand));
+        assert_eq!(run("add").0, Err(Error::MissingArgument));
+        assert_eq!(run("add x").0, Err(Error::BadArgument));
+        assert_eq!(run("add 1 2 3").0, Err(Error::TooManyArguments));
+        assert_eq!(run(""), (Ok(()), "".into()));
+    }
+
+    #[test]
+    fn help_lists_everything() {
+        assert_eq!(
+            run("help"),
+            (Ok(()), "add <a> [b] - adds them up\nping - pong\n".into())
+        );
+    }
+
+    #[test]
+    fn lines_are_split_and_overflow_is_dropped() {
+        let mut lines = LineBuffer::<4>::new();
+        let mut got = Vec::new();
+
+        for &b in b"ab\x7fc\r\nabcdef\nok\n" {
+            if let Some(line) = lines.push(b) {
+                got.push(line.map(String::from));
+            }
+        }
+
+        assert_eq!(
+            got,
+            [
+                Ok("ac".into()),
+                Ok("".into()),
+                Err(Error::LineTooLong),
+                Ok("ok".into())
+            ]
+        );
+    }
+
+    #[test]
+    fn reply_is_truncated_on_a_char_boundary() {
+        let mut out = Reply::<3>::new();
+
+        assert!(write!(out, "ab°").is_err());
+        assert_eq!(out.as_bytes(), b"ab");
+    }
+}
@@ -0,0 +1,638 @@
+// Control pipeline
+//
+// Sticks and the gyro in, rotor and tail outputs out: altitude hold or guided landing,
+// throttle failsafe, stick shaping, drift hold, the yaw PID and the mixer, in that order. The
+// firmware runs it in Controller::tick() and the simulator in Flight::tick(), so both
+// fly exactly the same thing.
+//
+// Tests below replay traces recorded in the simulator (testdata/*.csv, sticks and gyro
+// at the control loop rate) and compare every tick against the golden outputs next to
+// them. A change that is meant to alter the outputs gets the goldens rewritten with
+// `UPDATE_GOLDEN=1 cargo test`, and the diff shows what it did.
+
+use crate::{
+    altitude::{AltitudeHold, Vertical},
+    failsafe::ThrottleLimiter,
+    flow::{DriftHold, Flow},
+    mixer,
+    pid::Pid,
+    policy::SocStage,
+    shaping,
+};
+
+// Rotors are barely turning below that, there's nothing for the PID to hold yet
+pub const MIN_CONTROL_THROTTLE: i32 = 10;
+// Sticks closer to the center than that are left alone, in shaped units
+const DRIFT_HOLD_DEADBAND: i32 = 16;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Gains {
+    pub p: f32,
+    pub i: f32,
+    pub d: f32,
+}
+
+impl Default for Gains {
+    fn default() -> Self {
+        Self {
+            p: 0.5,
+            i: 0.2,
+            d: 0.2,
+        }
+    }
+}
+
+// Pilot's taste, see PilotProfile in the firmware
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Shaping {
+    pub expo: u8,
+    pub yaw_rate: u8,
+    pub pitch_rate: u8,
+    pub yaw_trim: i8,
+    pub pitch_trim: i8,
+}
+
+impl Default for Shaping {
+    fn default() -> Self {
+        Self {
+            expo: 0,
+            yaw_rate: 100,
+            pitch_rate: 100,
+            yaw_trim: 0,
+            pitch_trim: 0,
+        }
+    }
+}
+
+// Raw 16-bit sticks, same as what comes from the controller
+#[derive(Clone, Copy, Default, PartialEq, Debug)]
+pub struct Sticks {
+    pub throttle: i32,
+    pub yaw: i32,
+    pub pitch: i32,
+}
+
+// Whatever there is to go by besides the gyro. None when it's not fitted, or can't be
+// trusted at the moment
+#[derive(Clone, Copy, Default, PartialEq, Debug)]
+pub struct Estimates {
+    // With a barometer, see copter_core::altitude
+    pub vertical: Option<Vertical>,
+    // With a rangefinder, see copter_core::range
+    pub range: Option<Vertical>,
+    // With a flow sensor, see copter_core::flow
+    pub flow: Option<Flow>,
+}
+
+// What a tick came up with, in duty cycle units
+#[derive(Clone, Copy, Default, PartialEq, Debug)]
+pub struct Step {
+    pub throttle: i32,
+    pub yaw: i32,
+    // Zero while the throttle is too low for the PID to run
+    pub control: i32,
+    pub rotor1: i32,
+    pub rotor2: i32,
+    pub elevator: i32,
+    // Throttle came from the altitude hold rather than the stick
+    pub holding: bool,
+    // Drift hold had a say in the yaw or the elevator
+    pub drifting: bool,
+}
+
+impl Step {
+    pub fn duties(&self, max_duty: u16) -> mixer::Duties {
+        mixer::duties(self.rotor1, self.rotor2, self.elevator, max_duty)
+    }
+}
+
+pub struct ControlCore {
+    pid: Pid,
+    limiter: ThrottleLimiter,
+    control_limit: u16,
+    hold: AltitudeHold,
+    // Pilot asked for it, it's only engaged with an altitude estimate to go on
+    hold_requested: bool,
+    // Hold is on the rangefinder height, rather than the barometer
+    hold_on_range: bool,
+    // Guided landing is done, motors stay off
+    landed: bool,
+    drift: DriftHold,
+    dt: f32,
+}
+
+impl ControlCore {
+    pub fn new(max_duty: u16, loop_hz: u32) -> Self {
+        let mut core = Self {
+            pid: Pid::new(0.0, max_duty),
+            limiter: ThrottleLimiter::new(max_duty, loop_hz),
+            // Half of the range is enough to stop any spin, and leaves some for the lift
+            control_limit: max_duty / 2,
+            hold: AltitudeHold::new(),
+            hold_requested: false,
+            hold_on_range: false,
+            landed: false,
+            drift: DriftHold::new(),
+            dt: 1.0 / loop_hz as f32,
+        };
+
+        core.set_gains(Gains::default());
+        core
+    }
+
+    pub fn set_gains(&mut self, gains: Gains) {
+        let limit = self.control_limit;
+        self.pid
+            .p(gains.p, limit)
+            .i(gains.i, limit)
+            .d(gains.d, limit);
+    }
+
+    pub fn undervoltage(&mut self) {
+        self.limiter.undervoltage();
+    }
+
+    pub fn locked_out(&self) -> bool {
+        self.limiter.locked_out()
+    }
+
+    pub fn throttle_limit(&self) -> f32 {
+        self.limiter.limit()
+    }
+
+    pub fn toggle_altitude_hold(&mut self) {
+        self.hold_requested = !self.hold_requested;
+    }
+
+    pub fn altitude_hold_requested(&self) -> bool {
+        self.hold_requested
+    }
+
+    // Same hold, measured against something else now
+    fn switch_source(&mut self, on_range: bool, altitude: f32) {
+        if on_range != self.hold_on_range {
+            self.hold.rebase(altitude);
+            self.hold_on_range = on_range;
+        }
+    }
+
+    // Holds wherever it is once engaged, on the rangefinder while it can see the ground.
+    // Throttle stick at zero lets go, that's the pilot landing. So does losing the
+    // estimate, the stick is all that's left then
+    fn hold_throttle(
+        &mut self,
+        stick: i32,
+        vertical: Option<Vertical>,
+        range: Option<Vertical>,
+    ) -> i32 {
+        if stick == 0 {
+            self.hold_requested = false;
+        }
+
+        let source = match (range, vertical) {
+            (Some(v), _) => Some((v, true)),
+            (None, Some(v)) => Some((v, false)),
+            (None, None) => None,
+        };
+
+        match source {
+            Some((v, on_range)) if self.hold_requested => {
+                match self.hold.engaged() {
+                    true => self.switch_source(on_range, v.altitude),
+                    false => {
+                        self.hold.engage(stick, v.altitude);
+                        self.hold_on_range = on_range;
+                    }
+                }
+
+                self.hold.throttle(stick, v, self.dt)
+            }
+            _ => {
+                self.hold.disengage();
+                stick
+            }
+        }
+    }
+
+    // Forced landing with the ground in sight comes down on the rangefinder, slowing
+    // down for the last bit, and cuts the motors on touchdown. Throttle stick at zero
+    // still cuts them right away, same as with the hold
+    fn guided_landing(&mut self, stick: i32, height: Vertical) -> i32 {
+        // Ground reads a bit off zero, see the firmware
+        const TOUCHDOWN: f32 = 0.05;
+
+        if height.altitude < TOUCHDOWN || stick == 0 {
+            self.landed = true;
+        }
+
+        if self.landed {
+            self.hold.disengage();
+            return 0;
+        }
+
+        match self.hold.engaged() {
+            true => self.switch_source(true, height.altitude),
+            false => {
+                // From wherever the throttle is, the stick may be anywhere
+                let throttle = stick.min(self.limiter.limit() as i32);
+                self.hold.engage(throttle, height.altitude);
+                self.hold_on_range = true;
+            }
+        }
+
+        self.hold.descend(height, self.dt).unwrap_or(0)
+    }
+
+    // Only over the floor in rangefinder reach, with the altitude hold on. Pilot's sticks
+    // win, each one of them as soon as it's off the center
+    fn drift_hold(&mut self, range: Option<Vertical>, flow: Option<Flow>) -> Option<(i32, i32)> {
+        match (range, flow) {
+            (Some(height), Some(flow)) if self.hold.engaged() && self.hold_on_range => {
+                Some(self.drift.update(flow.velocity(height.altitude), self.dt))
+            }
+            _ => {
+                self.drift.reset();
+                None
+            }
+        }
+    }
+
+    // Gyro is in dps. None while the motors are cut
+    pub fn tick(
+        &mut self,
+        sticks: &Sticks,
+        shaping: &Shaping,
+        stage: SocStage,
+        gyro: f32,
+        estimates: &Estimates,
+    ) -> Option<Step> {
+        let Estimates {
+            vertical,
+            range,
+            flow,
+        } = *estimates;
+        let stick = shaping::throttle(sticks.throttle);
+
+        if stage < SocStage::ForceLanding {
+            self.landed = false;
+        }
+
+        // Failsafe still has the last word. Without the ground in sight, the landing
+        // goes open loop
+        let throttle = match range {
+            Some(height) if stage >= SocStage::ForceLanding => {
+                let throttle = self.guided_landing(stick, height);
+                self.limiter.guided(throttle)?
+            }
+            _ => {
+                let throttle = self.hold_throttle(stick, vertical, range);
+                self.limiter.apply(stage, throttle)?
+            }
+        };
+
+        let shape = |raw, trim, rate| shaping::shape(shaping::stick(raw), shaping.expo, rate, trim);
+        let mut yaw = shape(sticks.yaw, shaping.yaw_trim, shaping.yaw_rate);
+        let mut elevator = shape(sticks.pitch, shaping.pitch_trim, shaping.pitch_rate);
+
+        let drift = self.drift_hold(range, flow);
+        let centered = |raw| shaping::stick(raw).abs() < DRIFT_HOLD_DEADBAND;
+
+        if let Some((drift_yaw, drift_elevator)) = drift {
+            if centered(sticks.yaw) {
+                yaw += drift_yaw;
+            }
+
+            if centered(sticks.pitch) {
+                elevator += drift_elevator;
+            }
+        }
+
+        let control = if throttle > MIN_CONTROL_THROTTLE {
+            self.pid.setpoint = -yaw as f32;
+            self.pid.next_control_output(gyro).output as i32
+        } else {
+            0
+        };
+
+        let (rotor1, rotor2) = mixer::mix(throttle, control);
+
+        Some(Step {
+            throttle,
+            yaw,
+            control,
+            rotor1,
+            rotor2,
+            elevator,
+            holding: self.hold.engaged(),
+            drifting: drift.is_some(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::altitude::GRAVITY;
+    use std::{env, fmt::Write, fs, path::PathBuf};
+
+    const MAX_DUTY: u16 = 512;
+    const LOOP_HZ: u32 = 200;
+
+    // Something happening at a given tick, on top of what the trace has
+    enum Event {
+        Stage(SocStage),
+        Undervoltage,
+    }
+
+    fn testdata(name: &str) -> PathBuf {
+        PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+            .join("testdata")
+            .join(name)
+    }
+
+    // One tick per line: throttle, yaw, pitch (raw sticks), gyro (dps)
+    fn load_trace(name: &str) -> Vec<(Sticks, f32)> {
+        let text = fs::read_to_string(testdata(&format!("{}.csv", name))).unwrap();
+
+        text.lines()
+            .filter(|line| !line.starts_with('#'))
+            .map(|line| {
+                let v: Vec<&str> = line.split(',').collect();
+                let sticks = Sticks {
+                    throttle: v[0].parse().unwrap(),
+                    yaw: v[1].parse().unwrap(),
+                    pitch: v[2].parse().unwrap(),
+                };
+
+                (sticks, v[3].parse().unwrap())
+            })
+            .collect()
+    }
+
+    // Every scenario has a golden of its own, a trace can go into more than one
+    fn replay(trace: &str, golden: &str, events: &[(usize, Event)]) -> Vec<Option<Step>> {
+        let mut core = ControlCore::new(MAX_DUTY, LOOP_HZ);
+        let mut stage = SocStage::Normal;
+
+        let steps: Vec<_> = load_trace(trace)
+            .iter()
+            .enumerate()
+            .map(|(tick, (sticks, gyro))| {
+                for (_, event) in events.iter().filter(|(at, _)| *at == tick) {
+                    match event {
+                        Event::Stage(s) => stage = *s,
+                        Event::Undervoltage => core.undervoltage(),
+                    }
+                }
+
+                core.tick(
+                    sticks,
+                    &Shaping::default(),
+                    stage,
+                    *gyro,
+                    &Estimates::default(),
+                )
+            })
+            .collect();
+
+        check_golden(golden, &steps);
+        steps
+    }
+
+    // Golden file has a line per tick: throttle, yaw, control, rotor1, rotor2, elevator,
+    // or "cut" while the motors are off
+    fn check_golden(name: &str, steps: &[Option<Step>]) {
+        let mut actual = String::new();
+
+        for step in steps {
+            match step {
+                Some(s) => writeln!(
+                    actual,
+                    "{},{},{},{},{},{}",
+                    s.throttle, s.yaw, s.control, s.rotor1, s.rotor2, s.elevator
+                ),
+                None => writeln!(actual, "cut"),
+            }
+            .unwrap();
+        }
+
+        let path = testdata(&format!("{}.golden", name));
+
+        if env::var_os("UPDATE_GOLDEN").is_some() {
+            fs::write(&path, &actual).unwrap();
+            return;
+        }
+
+        let golden = fs::read_to_string(&path).unwrap();
+
+        for (tick, (a, g)) in actual.lines().zip(golden.lines()).enumerate() {
+            assert_eq!(
+                a, g,
+                "{} differs from the golden output at tick {}",
+                name, tick
+            );
+        }
+
+        assert_eq!(actual.lines().count(), golden.lines().count());
+    }
+
+    fn ticks(ms: u32) -> usize {
+        (ms * LOOP_HZ / 1000) as usize
+    }
+
+    #[test]
+    fn yaw_step_matches_the_golden() {
+        let steps = replay("yaw_step", "yaw_step", &[]);
+
+        // Stick goes hard over at 2s. Setpoint is the other way around from the stick,
+        // so is the push, and it eases off once the spin gets going
+        let control = |ms| steps[ticks(ms)].unwrap().control;
+        assert!(control(1990).abs() < 10);
+        assert!(control(2010) < -(MAX_DUTY as i32) / 2);
+        assert!(control(3900) > control(2010));
+    }
+
+    #[test]
+    fn hover_holds_without_the_pilot() {
+        let steps = replay("hover", "hover", &[]);
+
+        // Lower rotor is the stronger one, trimming it out takes a steady difference.
+        // Anything more than a nudge means the loop is fighting the gyro noise
+        let last = steps.last().unwrap().unwrap();
+        assert!(last.rotor1 > last.rotor2);
+        assert!(steps.iter().all(|s| s.unwrap().control.abs() < 10));
+    }
+
+    #[test]
+    fn undervoltage_cuts_and_recovers_in_time() {
+        let steps = replay(
+            "hover",
+            "hover_undervoltage",
+            &[(ticks(2000), Event::Undervoltage)],
+        );
+
+        let cut = steps.iter().filter(|s| s.is_none()).count();
+        let first_back = ticks(2000) + cut;
+
+        // ~200ms off, then back to where the pilot is within another ~500ms
+        assert_eq!(cut, ticks(200));
+        assert!(steps[..ticks(2000)].iter().all(Option::is_some));
+        assert!(steps[first_back].unwrap().throttle < steps[ticks(1990)].unwrap().throttle);
+        assert_eq!(
+            steps[first_back + ticks(500)].unwrap().throttle,
+            steps[ticks(1990)].unwrap().throttle
+        );
+    }
+
+    #[test]
+    fn forced_landing_reaches_zero_in_time() {
+        let steps = replay(
+            "hover",
+            "hover_forced_landing",
+            &[(ticks(1000), Event::Stage(SocStage::ForceLanding))],
+        );
+
+        let landed = ticks(1000)
+            + steps[ticks(1000)..]
+                .iter()
+                .position(|s| s.unwrap().throttle == 0)
+                .unwrap();
+
+        // Hover is well below the full throttle, so it's down in less than ~5s
+        assert!(landed > ticks(1000));
+        assert!(landed <= ticks(1000 + 5000));
+        assert!(steps[landed..].iter().all(|s| s.unwrap().throttle == 0));
+    }
+
+    #[test]
+    fn altitude_hold_needs_an_estimate_and_lets_go_on_landing() {
+        fn holding(core: &mut ControlCore, throttle: i32, vertical: Option<Vertical>) -> bool {
+            let sticks = Sticks {
+                throttle,
+                ..Default::default()
+            };
+
+            core.tick(
+                &sticks,
+                &Shaping::default(),
+                SocStage::Normal,
+                0.0,
+                &Estimates {
+                    vertical,
+                    ..Default::default()
+                },
+            )
+            .unwrap()
+            .holding
+        }
+
+        let mut core = ControlCore::new(MAX_DUTY, LOOP_HZ);
+        let estimate = Some(Vertical::default());
+
+        core.toggle_altitude_hold();
+        assert!(!holding(&mut core, 20000, None));
+        assert!(holding(&mut core, 20000, estimate));
+
+        // Throttle down is the pilot landing, it stays off after that
+        assert!(!holding(&mut core, 0, estimate));
+        assert!(!holding(&mut core, 20000, estimate));
+    }
+
+    #[test]
+    fn drift_hold_needs_the_hold_and_leaves_the_sticks_to_the_pilot() {
+        // Drifting forward at 0.5m
+        let estimates = Estimates {
+            range: Some(Vertical {
+                altitude: 0.5,
+                climb: 0.0,
+            }),
+            flow: Some(Flow {
+                forward: -0.6,
+                left: 0.0,
+            }),
+            ..Default::default()
+        };
+
+        let tick = |core: &mut ControlCore, pitch| {
+            let sticks = Sticks {
+                throttle: 20000,
+                pitch,
+                ..Default::default()
+            };
+
+            core.tick(
+                &sticks,
+                &Shaping::default(),
+                SocStage::Normal,
+                0.0,
+                &estimates,
+            )
+            .unwrap()
+        };
+
+        let mut core = ControlCore::new(MAX_DUTY, LOOP_HZ);
+
+        let step = tick(&mut core, 0);
+        assert!(!step.drifting);
+        assert_eq!(step.elevator, 0);
+
+        // Brakes with the hold on
+        core.toggle_altitude_hold();
+        let step = (0..ticks(500)).map(|_| tick(&mut core, 0)).last().unwrap();
+        assert!(step.drifting);
+        assert!(step.elevator < 0);
+
+        // Pilot pushing forward gets what they asked for
+        let step = tick(&mut core, 10000);
+        assert_eq!(step.elevator, shaping::stick(10000));
+    }
+
+    #[test]
+    fn forced_landing_comes_down_gently_on_the_rangefinder() {
+        const HOVER: f32 = 300.0;
+
+        let mut core = ControlCore::new(MAX_DUTY, LOOP_HZ);
+        let dt = 1.0 / LOOP_HZ as f32;
+        let sticks = Sticks {
+            throttle: (HOVER as i32) << 6,
+            ..Default::default()
+        };
+
+        // Hovering at 1.5m when the battery gives out
+        let mut height = Vertical {
+            altitude: 1.5,
+            climb: 0.0,
+        };
+        let mut touchdown = None;
+
+        for tick in 0..ticks(20000) {
+            let step = core
+                .tick(
+                    &sticks,
+                    &Shaping::default(),
+                    SocStage::ForceLanding,
+                    0.0,
+                    &Estimates {
+                        range: Some(height),
+                        ..Default::default()
+                    },
+                )
+                .unwrap();
+
+            if step.throttle == 0 {
+                touchdown.get_or_insert((tick, height.climb));
+                continue;
+            }
+
+            assert!(touchdown.is_none(), "motors came back on");
+
+            height.climb += (step.throttle as f32 / HOVER - 1.0) * GRAVITY * dt;
+            height.altitude += height.climb * dt;
+        }
+
+        // Way slower than the blind ramp, but it gets there, and doesn't hit hard
+        let (tick, climb) = touchdown.unwrap();
+        assert!(tick > ticks(4000), "down at {}", tick);
+        assert!(climb > -0.3, "hit at {} m/s", climb);
+    }
+}
@@ -0,0 +1,130 @@
+// Motor current
+//
+// With sense resistors fitted, each rotor's current is sampled along with the gyro, at
+// the control loop rate. That's way faster than the gauge, and it tells the rotors
+// apart, where the gauge only knows the whole battery averaged over a second.
+//
+// Overcurrent is anything past the limit for more than a couple of ticks: a short, or a
+// rotor jammed at full throttle. A stall is a rotor drawing way more than its duty
+// cycle would ask for, which is what a motor that can't turn does: it's all resistance
+// then, so the current goes with the duty rather than with the speed. A spinning one
+// stays well below that line everywhere, the limit is meant to sit at about twice what
+// it draws at full throttle.
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum MotorFault {
+    // Rotor index, 0 is the lower one
+    Overcurrent(usize),
+    Stall(usize),
+}
+
+pub struct CurrentMonitor {
+    // A
+    limit: f32,
+    overcurrent_ticks: u32,
+    stall_ticks: u32,
+    over: [u32; 2],
+    stalled: [u32; 2],
+}
+
+impl CurrentMonitor {
+    // Rotors barely turn below that, and a stall there is harmless
+    const MIN_STALL_DUTY: f32 = 0.1;
+
+    pub fn new(limit: f32, loop_hz: u32) -> Self {
+        Self {
+            limit,
+            // ~10ms, a bit more than the inrush of a rotor spinning up
+            overcurrent_ticks: (loop_hz / 100).max(1),
+            // ~300ms, long enough for the rotor to get going after a throttle punch
+            stall_ticks: loop_hz * 3 / 10,
+            over: [0; 2],
+            stalled: [0; 2],
+        }
+    }
+
+    // A, and the duty cycles as fractions of the full scale. The first fault found,
+    // if any. Counts start over once it's back in line
+    pub fn update(&mut self, currents: [f32; 2], duties: [f32; 2]) -> Option<MotorFault> {
+        let mut fault = None;
+
+        for i in 0..2 {
+            self.over[i] = match currents[i] > self.limit {
+                true => self.over[i] + 1,
+                false => 0,
+            };
+
+            let stalling = duties[i] > Self::MIN_STALL_DUTY && currents[i] > duties[i] * self.limit;
+            self.stalled[i] = match stalling {
+                true => self.stalled[i] + 1,
+                false => 0,
+            };
+
+            if fault.is_some() {
+                continue;
+            }
+
+            if self.over[i] > self.overcurrent_ticks {
+                fault = Some(MotorFault::Overcurrent(i));
+            } else if self.stalled[i] > self.stall_ticks {
+                fault = Some(MotorFault::Stall(i));
+            }
+        }
+
+        fault
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HZ: u32 = 200;
+    const LIMIT: f32 = 3.0;
+
+    // That many ticks of the same thing, gives the first fault
+    fn run(
+        monitor: &mut CurrentMonitor,
+        currents: [f32; 2],
+        duties: [f32; 2],
+        n: u32,
+    ) -> Option<MotorFault> {
+        (0..n).find_map(|_| monitor.update(currents, duties))
+    }
+
+    #[test]
+    fn spinning_rotors_are_fine() {
+        let mut monitor = CurrentMonitor::new(LIMIT, HZ);
+
+        // Running current goes with the square of the duty, or so
+        for d in [0.0, 0.1, 0.3, 0.5, 0.8, 1.0] {
+            let i = LIMIT / 2.0 * d * d;
+            assert_eq!(run(&mut monitor, [i, i], [d, d], HZ), None);
+        }
+    }
+
+    #[test]
+    fn overcurrent_trips_quickly_but_not_on_spikes() {
+        let mut monitor = CurrentMonitor::new(LIMIT, HZ);
+
+        assert_eq!(run(&mut monitor, [0.5, 4.0], [0.5, 0.5], 2), None);
+        assert_eq!(run(&mut monitor, [0.5, 0.5], [0.5, 0.5], 1), None);
+        assert_eq!(
+            run(&mut monitor, [0.5, 4.0], [0.5, 0.5], 3),
+            Some(MotorFault::Overcurrent(1))
+        );
+    }
+
+    #[test]
+    fn stalled_rotor_is_found() {
+        let mut monitor = CurrentMonitor::new(LIMIT, HZ);
+
+        // Jammed at 30% draws ~3x what it would at full speed, still under the limit
+        assert_eq!(run(&mut monitor, [1.2, 0.15], [0.3, 0.3], HZ / 5), None);
+        assert_eq!(
+            run(&mut monitor, [1.2, 0.15], [0.3, 0.3], HZ / 5),
+            Some(MotorFault::Stall(0))
+        );
+    }
+}
@@ -0,0 +1,481 @@
+// Firmware update over BLE
+//
+// What the updater (tools/ota) and the firmware agree on. The image goes to the DFU
+// partition in chunks tagged with where they go, strictly in order: a chunk that doesn't
+// start where the previous one ended is dropped, and the status says where to pick up
+// from. Once it's all there it's checked against the CRC it was started with, and only
+// then can it be activated - marked for the bootloader to swap in on the next boot.
+//
+// Anyone in radio range can connect, so the link proves nothing about who sent the image.
+// The image does instead: it's signed with the update key (tools/ota --keygen makes one),
+// the signature goes right after it, and the firmware only takes images that check out
+// against the public half it was built with.
+
+use ed25519_compact::{PublicKey, Signature, VerifyingState};
+
+// Where the image runs from, and how much room there is (see firmware/memory-bootloader.x)
+pub const ACTIVE_START: u32 = 152 * 1024;
+pub const IMAGE_MAX_LEN: u32 = 148 * 1024;
+// Flash is written a word at a time
+pub const IMAGE_ALIGN: u32 = 4;
+
+pub const KEY_LEN: usize = 32;
+pub const SIGNATURE_LEN: u32 = 64;
+
+const RAM_START: u32 = 0x2000_0000;
+const RAM_END: u32 = RAM_START + 64 * 1024;
+
+// Default ATT MTU leaves 20 bytes for a write, the offset takes 4 of them
+pub const CHUNK_LEN: usize = 16;
+pub const CHUNK_WRITE_LEN: usize = 4 + CHUNK_LEN;
+
+pub const COMMAND_LEN: usize = 9;
+pub const STATUS_LEN: usize = 10;
+
+const COMMAND_START: u8 = 1;
+const COMMAND_ACTIVATE: u8 = 2;
+const COMMAND_ABORT: u8 = 3;
+
+fn u32_at(b: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes([b[at], b[at + 1], b[at + 2], b[at + 3]])
+}
+
+// CRC-32 as in zlib, a bit at a time. Slow, but it's only run once per update
+#[derive(Clone, Copy)]
+pub struct Crc32(u32);
+
+impl Crc32 {
+    pub fn new() -> Self {
+        Self(!0)
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        for &b in data {
+            self.0 ^= b as u32;
+
+            for _ in 0..8 {
+                let mask = (self.0 & 1).wrapping_neg();
+                self.0 = (self.0 >> 1) ^ (0xedb8_8320 & mask);
+            }
+        }
+    }
+
+    pub fn finish(self) -> u32 {
+        !self.0
+    }
+}
+
+impl Default for Crc32 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = Crc32::new();
+    crc.update(data);
+    crc.finish()
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum ImageError {
+    Length,
+    // Initial stack pointer isn't in RAM
+    StackPointer,
+    // Reset handler isn't in the image, or isn't thumb code
+    ResetVector,
+}
+
+// First two words of the vector table, enough to tell an image built for the bootloader
+// layout from anything else
+pub fn check_vectors(len: u32, stack_pointer: u32, reset_vector: u32) -> Result<(), ImageError> {
+    if !(RAM_START..=RAM_END).contains(&stack_pointer) {
+        return Err(ImageError::StackPointer);
+    }
+
+    let code = ACTIVE_START..ACTIVE_START + len;
+    if !code.contains(&(reset_vector & !1)) || reset_vector & 1 == 0 {
+        return Err(ImageError::ResetVector);
+    }
+
+    Ok(())
+}
+
+// Leaves room for the signature, it goes to the partition right after the image
+pub fn check_len(len: u32) -> Result<(), ImageError> {
+    match (8..=IMAGE_MAX_LEN - SIGNATURE_LEN).contains(&len) && len.is_multiple_of(IMAGE_ALIGN) {
+        true => Ok(()),
+        false => Err(ImageError::Length),
+    }
+}
+
+pub fn check_image(image: &[u8]) -> Result<(), ImageError> {
+    let len = u32::try_from(image.len()).map_err(|_| ImageError::Length)?;
+    check_len(len)?;
+    check_vectors(len, u32_at(image, 0), u32_at(image, 4))
+}
+
+// What's sent is the image and its signature, this is the image part of it
+pub fn image_len(signed_len: u32) -> Result<u32, ImageError> {
+    let len = signed_len
+        .checked_sub(SIGNATURE_LEN)
+        .ok_or(ImageError::Length)?;
+
+    check_len(len).map(|_| len)
+}
+
+// 64 hex digits, as the keys are passed around. Const so that a bad one built into the
+// firmware doesn't get past the build
+pub const fn parse_key(hex: &str) -> Option<[u8; KEY_LEN]> {
+    const fn digit(c: u8) -> Option<u8> {
+        match c {
+            b'0'..=b'9' => Some(c - b'0'),
+            b'a'..=b'f' => Some(c - b'a' + 10),
+            b'A'..=b'F' => Some(c - b'A' + 10),
+            _ => None,
+        }
+    }
+
+    let hex = hex.as_bytes();
+    if hex.len() != KEY_LEN * 2 {
+        return None;
+    }
+
+    let mut key = [0; KEY_LEN];
+    let mut i = 0;
+
+    while i < KEY_LEN {
+        let (Some(hi), Some(lo)) = (digit(hex[i * 2]), digit(hex[i * 2 + 1])) else {
+            return None;
+        };
+
+        key[i] = hi << 4 | lo;
+        i += 1;
+    }
+
+    Some(key)
+}
+
+// Image is fed in pieces as it's read back, it doesn't fit in RAM
+pub struct SignatureCheck(VerifyingState);
+
+impl SignatureCheck {
+    // None if the signature can't be one, no point in reading the image then
+    pub fn new(key: &[u8; KEY_LEN], signature: &[u8; SIGNATURE_LEN as usize]) -> Option<Self> {
+        PublicKey::new(*key)
+            .verify_incremental(&Signature::new(*signature))
+            .ok()
+            .map(Self)
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        self.0.absorb(data);
+    }
+
+    pub fn finish(self) -> bool {
+        self.0.verify().is_ok()
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Command {
+    // Erases the partition and gets ready for that much
+    Start { len: u32, crc: u32 },
+    // Only once the image is all there and checked. Board reboots into it right away
+    Activate,
+    // Back to idle, whatever is in the partition stays there but is never used
+    Abort,
+}
+
+impl Command {
+    pub fn to_bytes(self) -> [u8; COMMAND_LEN] {
+        let mut b = [0; COMMAND_LEN];
+
+        match self {
+            Self::Start { len, crc } => {
+                b[0] = COMMAND_START;
+                b[1..5].copy_from_slice(&len.to_le_bytes());
+                b[5..9].copy_from_slice(&crc.to_le_bytes());
+            }
+            Self::Activate => b[0] = COMMAND_ACTIVATE,
+            Self::Abort => b[0] = COMMAND_ABORT,
+        }
+
+        b
+    }
+
+    pub fn parse(b: &[u8; COMMAND_LEN]) -> Option<Self> {
+        match b[0] {
+            COMMAND_START => Some(Self::Start {
+                len: u32_at(b, 1),
+                crc: u32_at(b, 5),
+            }),
+            COMMAND_ACTIVATE => Some(Self::Activate),
+            COMMAND_ABORT => Some(Self::Abort),
+            _ => None,
+        }
+    }
+}
+
+pub struct Chunk<'a> {
+    pub offset: u32,
+    pub data: &'a [u8],
+}
+
+impl<'a> Chunk<'a> {
+    // Returns how much of the buffer is used
+    pub fn write_to(&self, b: &mut [u8; CHUNK_WRITE_LEN]) -> usize {
+        b[..4].copy_from_slice(&self.offset.to_le_bytes());
+        b[4..4 + self.data.len()].copy_from_slice(self.data);
+        4 + self.data.len()
+    }
+
+    pub fn parse(b: &'a [u8]) -> Option<Self> {
+        (b.len() > 4 && b.len() <= CHUNK_WRITE_LEN).then(|| Self {
+            offset: u32_at(b, 0),
+            data: &b[4..],
+        })
+    }
+}
+
+#[repr(u8)]
+#[derive(Clone, Copy, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Phase {
+    Idle = 0,
+    Erasing = 1,
+    Receiving = 2,
+    // All there and the CRC matches
+    Ready = 3,
+    // Marked for the bootloader, reboot is on its way
+    Activated = 4,
+    Failed = 5,
+}
+
+impl Phase {
+    pub fn from_u8(v: u8) -> Option<Self> {
+        Some(match v {
+            0 => Self::Idle,
+            1 => Self::Erasing,
+            2 => Self::Receiving,
+            3 => Self::Ready,
+            4 => Self::Activated,
+            5 => Self::Failed,
+            _ => return None,
+        })
+    }
+}
+
+// Why the last command or transfer failed
+#[repr(u8)]
+#[derive(Clone, Copy, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Failure {
+    None = 0,
+    // Nothing is touched while the rotors might spin
+    Armed = 1,
+    Image = 2,
+    Crc = 3,
+    Flash = 4,
+    // Command that doesn't make sense right now, e.g. activating before it's ready
+    Sequence = 5,
+    // Built without the bootloader or the update key, there's nowhere to put the image
+    // or no way to tell where it came from
+    Unsupported = 6,
+    // Not signed with the update key
+    Signature = 7,
+}
+
+impl Failure {
+    pub fn from_u8(v: u8) -> Option<Self> {
+        Some(match v {
+            0 => Self::None,
+            1 => Self::Armed,
+            2 => Self::Image,
+            3 => Self::Crc,
+            4 => Self::Flash,
+            5 => Self::Sequence,
+            6 => Self::Unsupported,
+            7 => Self::Signature,
+            _ => return None,
+        })
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Status {
+    pub phase: Phase,
+    pub failure: Failure,
+    // In order from the start, the next chunk is expected right there
+    pub received: u32,
+    // Signature included
+    pub len: u32,
+}
+
+impl Status {
+    pub const IDLE: Self = Self {
+        phase: Phase::Idle,
+        failure: Failure::None,
+        received: 0,
+        len: 0,
+    };
+
+    pub fn to_bytes(self) -> [u8; STATUS_LEN] {
+        let mut b = [0; STATUS_LEN];
+        b[0] = self.phase as u8;
+        b[1] = self.failure as u8;
+        b[2..6].copy_from_slice(&self.received.to_le_bytes());
+        b[6..10].copy_from_slice(&self.len.to_le_bytes());
+        b
+    }
+
+    pub fn parse(b: &[u8]) -> Option<Self> {
+        if b.len() != STATUS_LEN {
+            return None;
+        }
+
+        Some(Self {
+            phase: Phase::from_u8(b[0])?,
+            failure: Failure::from_u8(b[1])?,
+            received: u32_at(b, 2),
+            len: u32_at(b, 6),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Vector table of an image that's len long
+    fn image(len: usize) -> Vec<u8> {
+        let mut image = vec![0xff; len];
+        image[..4].copy_from_slice(&0x2001_0000_u32.to_le_bytes());
+        image[4..8].copy_from_slice(&(ACTIVE_START + 0x101).to_le_bytes());
+        image
+    }
+
+    #[test]
+    fn crc_matches_zlib() {
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+        assert_eq!(crc32(&[]), 0);
+
+        // Same in pieces
+        let mut crc = Crc32::new();
+        crc.update(b"1234");
+        crc.update(b"56789");
+        assert_eq!(crc.finish(), 0xcbf4_3926);
+    }
+
+    #[test]
+    fn images_are_checked() {
+        assert_eq!(check_image(&image(1024)), Ok(()));
+
+        assert_eq!(check_image(&image(1022)), Err(ImageError::Length));
+        assert_eq!(
+            check_image(&image(IMAGE_MAX_LEN as usize + 4)),
+            Err(ImageError::Length)
+        );
+
+        // No room for the signature after it
+        assert_eq!(
+            check_image(&image(IMAGE_MAX_LEN as usize)),
+            Err(ImageError::Length)
+        );
+        assert_eq!(image_len(1024 + SIGNATURE_LEN), Ok(1024));
+        assert_eq!(image_len(SIGNATURE_LEN - 4), Err(ImageError::Length));
+
+        // Built for the layout without the bootloader, the reset handler is past the end
+        let mut other = image(256);
+        other[4..8].copy_from_slice(&(ACTIVE_START + 0x400 + 1).to_le_bytes());
+        assert_eq!(check_image(&other), Err(ImageError::ResetVector));
+
+        // Not thumb
+        let mut arm = image(1024);
+        arm[4] &= !1;
+        assert_eq!(check_image(&arm), Err(ImageError::ResetVector));
+
+        let mut text = image(1024);
+        text[..4].copy_from_slice(b"hell");
+        assert_eq!(check_image(&text), Err(ImageError::StackPointer));
+    }
+
+    #[test]
+    fn only_signed_images_pass() {
+        use ed25519_compact::{KeyPair, Seed};
+
+        let keys = KeyPair::from_seed(Seed::new([7; 32]));
+        let image = image(1024);
+        let signature = *keys.sk.sign(&image, None);
+
+        let check = |key: &[u8; KEY_LEN], image: &[u8]| {
+            let mut check = SignatureCheck::new(key, &signature).unwrap();
+            for chunk in image.chunks(256) {
+                check.update(chunk);
+            }
+            check.finish()
+        };
+
+        assert!(check(&keys.pk, &image));
+
+        let mut tampered = image.clone();
+        tampered[100] ^= 1;
+        assert!(!check(&keys.pk, &tampered));
+
+        let other = KeyPair::from_seed(Seed::new([8; 32]));
+        assert!(!check(&other.pk, &image));
+    }
+
+    #[test]
+    fn keys_are_parsed() {
+        let hex = "00112233445566778899aabbccddeeff00112233445566778899AABBCCDDEEFF";
+        let key = parse_key(hex).unwrap();
+        assert_eq!((key[0], key[5], key[31]), (0x00, 0x55, 0xff));
+
+        assert_eq!(parse_key(&hex[2..]), None);
+        assert_eq!(parse_key(&hex.replace('a', "g")), None);
+    }
+
+    #[test]
+    fn commands_survive_the_link() {
+        for command in [
+            Command::Start {
+                len: 0x1_2340,
+                crc: 0xdead_beef,
+            },
+            Command::Activate,
+            Command::Abort,
+        ] {
+            assert_eq!(Command::parse(&command.to_bytes()), Some(command));
+        }
+
+        assert_eq!(Command::parse(&[0; COMMAND_LEN]), None);
+    }
+
+    #[test]
+    fn chunks_and_status_survive_the_link() {
+        let mut b = [0; CHUNK_WRITE_LEN];
+        let len = Chunk {
+            offset: 0x40,
+            data: &[1, 2, 3],
+        }
+        .write_to(&mut b);
+
+        let chunk = Chunk::parse(&b[..len]).unwrap();
+        assert_eq!((chunk.offset, chunk.data), (0x40, &[1, 2, 3][..]));
+        assert!(Chunk::parse(&b[..4]).is_none());
+
+        let status = Status {
+            phase: Phase::Failed,
+            failure: Failure::Armed,
+            received: 4096,
+            len: 8192,
+        };
+
+        assert_eq!(Status::parse(&status.to_bytes()), Some(status));
+        assert_eq!(Status::parse(&[9; STATUS_LEN]), None);
+    }
+}
@@ -0,0 +1,230 @@
+// Per-unit data, written once at the bench
+//
+// What tools/provision puts in a flash page of its own and the firmware reads at boot,
+// so a batch of boards can run the very same image. Nothing on the board ever writes
+// the page, and settings resets leave it alone. A blank or damaged page just means the
+// built-in defaults.
+
+use crate::dfu::crc32;
+
+// Same as in firmware/memory.x and memory-bootloader.x
+pub const PAGE: u32 = 256 * 1024 - 24 * 1024;
+pub const PAGE_BOOTLOADER: u32 = 512 * 1024 - 24 * 1024;
+
+pub const RECORD_LEN: usize = 48;
+// Scan response has room for more, but it's shown in lists
+pub const NAME_MAX: usize = 20;
+
+// Change this whenever the layout changes, boards go back to the defaults until they're
+// provisioned again
+const MAGIC: u32 = 0x5107_fac1;
+const CRC_AT: usize = RECORD_LEN - 4;
+
+const HAS_BATTERY: u8 = 1 << 0;
+const HAS_ADDRESS: u8 = 1 << 1;
+
+fn u16_at(b: &[u8], at: usize) -> u16 {
+    u16::from_le_bytes([b[at], b[at + 1]])
+}
+
+fn u32_at(b: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes([b[at], b[at + 1], b[at + 2], b[at + 3]])
+}
+
+// Same fields as the firmware's BatteryProfile, the cell the board ships with
+#[derive(Clone, Copy, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Battery {
+    // mAh, mWh, mV
+    pub capacity: u16,
+    pub energy: u16,
+    pub terminate_voltage: u16,
+    pub taper_rate: u16,
+    // %
+    pub soc_delta: u8,
+    pub chemistry: u8,
+}
+
+impl Battery {
+    fn write_to(&self, b: &mut [u8]) {
+        b[0..2].copy_from_slice(&self.capacity.to_le_bytes());
+        b[2..4].copy_from_slice(&self.energy.to_le_bytes());
+        b[4..6].copy_from_slice(&self.terminate_voltage.to_le_bytes());
+        b[6..8].copy_from_slice(&self.taper_rate.to_le_bytes());
+        b[8] = self.soc_delta;
+        b[9] = self.chemistry;
+    }
+
+    fn parse(b: &[u8]) -> Self {
+        Self {
+            capacity: u16_at(b, 0),
+            energy: u16_at(b, 2),
+            terminate_voltage: u16_at(b, 4),
+            taper_rate: u16_at(b, 6),
+            soc_delta: b[8],
+            chemistry: b[9],
+        }
+    }
+}
+
+// Static random address, little-endian the way the softdevice has it. Two top bits
+// set, and the rest can't be all zeros or all ones
+pub fn valid_address(address: &[u8; 6]) -> bool {
+    let mut rest = *address;
+    rest[5] &= 0x3f;
+
+    address[5] & 0xc0 == 0xc0 && rest != [0; 6] && rest != [0xff, 0xff, 0xff, 0xff, 0xff, 0x3f]
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Error {
+    Name,
+    Address,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug, Default)]
+pub struct Factory {
+    // Zero for not known
+    pub board_revision: u8,
+    pub battery: Option<Battery>,
+    pub address: Option<[u8; 6]>,
+    name: [u8; NAME_MAX],
+    name_len: u8,
+}
+
+impl Factory {
+    pub fn new(
+        board_revision: u8,
+        battery: Option<Battery>,
+        address: Option<[u8; 6]>,
+        name: Option<&str>,
+    ) -> Result<Self, Error> {
+        if address.is_some_and(|a| !valid_address(&a)) {
+            return Err(Error::Address);
+        }
+
+        let name = name.unwrap_or_default().as_bytes();
+        if name.len() > NAME_MAX {
+            return Err(Error::Name);
+        }
+
+        let mut factory = Self {
+            board_revision,
+            battery,
+            address,
+            name: [0; NAME_MAX],
+            name_len: name.len() as u8,
+        };
+
+        factory.name[..name.len()].copy_from_slice(name);
+        Ok(factory)
+    }
+
+    // Advertised one, None for the built-in
+    pub fn name(&self) -> Option<&str> {
+        let name = core::str::from_utf8(&self.name[..self.name_len as usize]).ok()?;
+        (!name.is_empty()).then_some(name)
+    }
+
+    pub fn to_bytes(&self) -> [u8; RECORD_LEN] {
+        let mut b = [0; RECORD_LEN];
+        let mut flags = 0;
+
+        b[0..4].copy_from_slice(&MAGIC.to_le_bytes());
+        b[4] = self.board_revision;
+
+        if let Some(battery) = &self.battery {
+            battery.write_to(&mut b[8..18]);
+            flags |= HAS_BATTERY;
+        }
+
+        if let Some(address) = &self.address {
+            b[18..24].copy_from_slice(address);
+            flags |= HAS_ADDRESS;
+        }
+
+        b[5] = flags;
+        b[6] = self.name_len;
+        b[24..24 + NAME_MAX].copy_from_slice(&self.name);
+
+        let crc = crc32(&b[..CRC_AT]);
+        b[CRC_AT..].copy_from_slice(&crc.to_le_bytes());
+        b
+    }
+
+    // None for a blank page, or one written by another layout
+    pub fn parse(b: &[u8]) -> Option<Self> {
+        if b.len() < RECORD_LEN || u32_at(b, 0) != MAGIC || u32_at(b, CRC_AT) != crc32(&b[..CRC_AT])
+        {
+            return None;
+        }
+
+        let flags = b[5];
+        let address: [u8; 6] = b[18..24].try_into().ok()?;
+        let name = b.get(24..24 + b[6] as usize)?;
+
+        Self::new(
+            b[4],
+            (flags & HAS_BATTERY != 0).then(|| Battery::parse(&b[8..18])),
+            (flags & HAS_ADDRESS != 0).then_some(address),
+            Some(core::str::from_utf8(name).ok()?),
+        )
+        .ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn records_survive_the_flash() {
+        let factory = Factory::new(
+            2,
+            Some(Battery {
+                capacity: 150,
+                energy: 555,
+                terminate_voltage: 3000,
+                taper_rate: 100,
+                soc_delta: 1,
+                chemistry: 0,
+            }),
+            Some([0x03, 0x00, 0x00, 0xee, 0xff, 0xc0]),
+            Some("S107 #3"),
+        )
+        .unwrap();
+
+        let b = factory.to_bytes();
+        let parsed = Factory::parse(&b).unwrap();
+
+        assert_eq!(parsed, factory);
+        assert_eq!(parsed.name(), Some("S107 #3"));
+
+        // Erased flash, and a flipped bit
+        assert_eq!(Factory::parse(&[0xff; RECORD_LEN]), None);
+        let mut damaged = b;
+        damaged[10] ^= 1;
+        assert_eq!(Factory::parse(&damaged), None);
+
+        let bare = Factory::parse(&Factory::default().to_bytes()).unwrap();
+        assert_eq!(
+            (bare.battery, bare.address, bare.name()),
+            (None, None, None)
+        );
+    }
+
+    #[test]
+    fn bad_values_are_turned_away() {
+        let name = "Syma S107 with a way too long name";
+        assert_eq!(Factory::new(1, None, None, Some(name)), Err(Error::Name));
+
+        // Public-looking, and the reserved all-ones
+        for address in [[1, 2, 3, 4, 5, 0x06], [0xff; 6], [0, 0, 0, 0, 0, 0xc0]] {
+            assert_eq!(
+                Factory::new(1, None, Some(address), None),
+                Err(Error::Address)
+            );
+        }
+    }
+}
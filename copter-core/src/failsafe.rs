@@ -0,0 +1,170 @@
+// Throttle failsafe
+//
+// Low battery caps the throttle or brings it down to zero, and a supply rail dip cuts
+// the motors for a moment and then brings the throttle back gradually. Everything is
+// counted in control loop ticks, so the caller decides how fast time goes.
+
+use crate::policy::SocStage;
+
+pub struct ThrottleLimiter {
+    max: f32,
+    low_battery: f32,
+    landing_step: f32,
+    recovery_step: f32,
+    lockout_len: u32,
+    // Current cap, in duty cycle units
+    limit: f32,
+    // Ticks until the motors are allowed to spin again
+    lockout: u32,
+}
+
+impl ThrottleLimiter {
+    pub fn new(max_duty: u16, loop_hz: u32) -> Self {
+        let max = max_duty as f32;
+
+        Self {
+            max,
+            // Cap while in SocStage::LimitThrottle
+            low_battery: max * 0.6,
+            // Forced landing brings the throttle from the hover to zero in ~5s
+            landing_step: max / (5 * loop_hz) as f32,
+            // After an undervoltage, the throttle is brought back within ~0.5s...
+            recovery_step: max / (loop_hz / 2) as f32,
+            // ...once the motors were off for ~200ms
+            lockout_len: loop_hz / 5,
+            limit: max,
+            lockout: 0,
+        }
+    }
+
+    pub fn limit(&self) -> f32 {
+        self.limit
+    }
+
+    pub fn locked_out(&self) -> bool {
+        self.lockout > 0
+    }
+
+    // Battery can't hold the load, motors have to be cut right away
+    pub fn undervoltage(&mut self) {
+        self.lockout = self.lockout_len;
+        self.limit = 0.0;
+    }
+
+    fn lockout_tick(&mut self) -> bool {
+        if self.lockout > 0 {
+            self.lockout -= 1;
+            return true;
+        }
+
+        false
+    }
+
+    // Called once per tick with the throttle the pilot asks for. None while the motors
+    // are locked out, they are expected to be off then
+    pub fn apply(&mut self, stage: SocStage, throttle: i32) -> Option<i32> {
+        if self.lockout_tick() {
+            return None;
+        }
+
+        let ramp_up_to = |limit: f32| (self.limit + self.recovery_step).min(limit);
+
+        self.limit = match stage {
+            SocStage::Normal | SocStage::Warn => ramp_up_to(self.max),
+            SocStage::LimitThrottle => ramp_up_to(self.low_battery),
+            _ => (self.limit.min(throttle as f32) - self.landing_step).max(0.0),
+        };
+
+        Some(throttle.min(self.limit as i32))
+    }
+
+    // Something else is bringing the throttle down for a forced landing, and knows
+    // better (see ControlCore). Cap follows along, so the ramp picks up from there if it
+    // has to take over
+    pub fn guided(&mut self, throttle: i32) -> Option<i32> {
+        if self.lockout_tick() {
+            return None;
+        }
+
+        self.limit = throttle as f32;
+        Some(throttle)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MAX: u16 = 512;
+    const HZ: u32 = 200;
+
+    #[test]
+    fn passes_through_normally() {
+        let mut limiter = ThrottleLimiter::new(MAX, HZ);
+
+        assert_eq!(limiter.apply(SocStage::Normal, 300), Some(300));
+        assert_eq!(limiter.apply(SocStage::Warn, 512), Some(512));
+    }
+
+    #[test]
+    fn low_battery_caps_the_throttle() {
+        let mut limiter = ThrottleLimiter::new(MAX, HZ);
+
+        // Only ramps up, coming down to the cap is immediate
+        assert_eq!(limiter.apply(SocStage::LimitThrottle, 512), Some(307));
+        assert_eq!(limiter.apply(SocStage::LimitThrottle, 100), Some(100));
+    }
+
+    #[test]
+    fn forced_landing_reaches_zero_in_time() {
+        let mut limiter = ThrottleLimiter::new(MAX, HZ);
+        let mut throttle = Some(MAX as i32);
+
+        for _ in 0..5 * HZ {
+            throttle = limiter.apply(SocStage::ForceLanding, MAX as i32);
+        }
+
+        assert_eq!(throttle, Some(0));
+    }
+
+    #[test]
+    fn forced_landing_starts_from_where_the_pilot_is() {
+        let mut limiter = ThrottleLimiter::new(MAX, HZ);
+
+        let throttle = limiter.apply(SocStage::ForceLanding, 200).unwrap();
+        assert!(throttle < 200 && throttle > 190);
+    }
+
+    #[test]
+    fn ramp_picks_up_where_the_guided_landing_left() {
+        let mut limiter = ThrottleLimiter::new(MAX, HZ);
+
+        assert_eq!(limiter.guided(250), Some(250));
+
+        let throttle = limiter.apply(SocStage::ForceLanding, 400).unwrap();
+        assert!(throttle < 250 && throttle > 240);
+    }
+
+    #[test]
+    fn undervoltage_locks_out_and_recovers() {
+        let mut limiter = ThrottleLimiter::new(MAX, HZ);
+
+        limiter.undervoltage();
+
+        for _ in 0..HZ / 5 {
+            assert!(limiter.locked_out());
+            assert_eq!(limiter.apply(SocStage::Normal, 400), None);
+        }
+
+        assert!(!limiter.locked_out());
+
+        let first = limiter.apply(SocStage::Normal, 400).unwrap();
+        assert!(first > 0 && first < 400);
+
+        for _ in 0..HZ / 2 {
+            limiter.apply(SocStage::Normal, 400);
+        }
+
+        assert_eq!(limiter.apply(SocStage::Normal, 400), Some(400));
+    }
+}
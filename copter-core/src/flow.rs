@@ -0,0 +1,142 @@
+// Optical flow and drift hold
+//
+// Flow sensor looks straight down and reports how far the picture of the floor moved.
+// That's an angle, so it takes the height to make a speed out of it, and the airframe
+// pitching or rolling moves the picture just the same as flying does, so the gyro's
+// share comes out first. Good close to the ground only: higher up, the same error in
+// the angle is a bigger one in the speed, and the floor gets too dark to see.
+//
+// Drift hold damps whatever speed is left over the floor while the altitude hold is on
+// and the pilot leaves the sticks alone. Elevator brakes the forward and back drift.
+// There's nothing on this airframe to push it sideways, so a sideways drift turns the
+// nose into it, slowly, until the elevator can take care of it.
+
+use crate::shaping::STICK_FULL;
+
+#[derive(Clone, Copy, Default, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Flow {
+    // rad/s the picture moved, forward and left, with the airframe's own rotation
+    // taken out
+    pub forward: f32,
+    pub left: f32,
+}
+
+impl Flow {
+    // Picture as the sensor sees it, and the gyro about the forward and left axes, both
+    // in rad/s. Nose down makes the floor look like it's going back, right side down like
+    // it's going left
+    pub fn new(picture: [f32; 2], rates: [f32; 2]) -> Self {
+        Self {
+            forward: picture[0] + rates[1],
+            left: picture[1] - rates[0],
+        }
+    }
+
+    // m/s forward and left over the floor, height is in m
+    pub fn velocity(&self, height: f32) -> [f32; 2] {
+        [-self.forward * height, -self.left * height]
+    }
+}
+
+pub struct DriftHold {
+    // m/s, smoothed, the sensor is noisy tick to tick
+    velocity: [f32; 2],
+}
+
+impl DriftHold {
+    // Stick units per m/s, a third of the elevator stops a brisk walk
+    const ELEVATOR_GAIN: f32 = 400.0;
+    const MAX_ELEVATOR: f32 = (STICK_FULL / 3) as f32;
+    // Turning into the drift is a slow affair, or it's just spinning around
+    const YAW_GAIN: f32 = 150.0;
+    const MAX_YAW: f32 = (STICK_FULL / 6) as f32;
+    // s
+    const SMOOTHING: f32 = 0.1;
+
+    pub const fn new() -> Self {
+        Self { velocity: [0.0; 2] }
+    }
+
+    // Starts from scratch the next time
+    pub fn reset(&mut self) {
+        self.velocity = [0.0; 2];
+    }
+
+    // Yaw and elevator to add, same units as the shaped sticks
+    pub fn update(&mut self, velocity: [f32; 2], dt: f32) -> (i32, i32) {
+        let k = dt / (Self::SMOOTHING + dt);
+
+        for (v, new) in self.velocity.iter_mut().zip(velocity) {
+            *v += (new - *v) * k;
+        }
+
+        let [forward, left] = self.velocity;
+
+        // Positive yaw is to the right, same as the stick
+        let yaw = (-left * Self::YAW_GAIN).clamp(-Self::MAX_YAW, Self::MAX_YAW);
+        let elevator =
+            (-forward * Self::ELEVATOR_GAIN).clamp(-Self::MAX_ELEVATOR, Self::MAX_ELEVATOR);
+
+        (yaw as i32, elevator as i32)
+    }
+}
+
+impl Default for DriftHold {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DT: f32 = 0.005;
+
+    #[test]
+    fn rotation_alone_is_not_flying() {
+        // Pitching nose down at 0.5 rad/s over a still floor
+        let flow = Flow::new([-0.5, 0.0], [0.0, 0.5]);
+        assert_eq!(flow.velocity(1.0), [0.0, 0.0]);
+
+        // Rolling right
+        let flow = Flow::new([0.0, 0.3], [0.3, 0.0]);
+        assert_eq!(flow.velocity(1.0), [0.0, 0.0]);
+
+        // Floor going back under at 0.5 rad/s from 0.4m up is 0.2 m/s forward
+        let flow = Flow::new([-0.5, 0.0], [0.0, 0.0]);
+        assert!((flow.velocity(0.4)[0] - 0.2).abs() < 1e-6);
+    }
+
+    #[test]
+    fn drift_is_braked() {
+        let mut hold = DriftHold::new();
+        let mut velocity = [0.3, 0.0];
+
+        // Elevator accelerates the airframe, about 1 m/s² at full stick
+        for _ in 0..800 {
+            let (yaw, elevator) = hold.update(velocity, DT);
+
+            assert_eq!(yaw, 0);
+            velocity[0] += elevator as f32 / STICK_FULL as f32 * DT;
+        }
+
+        assert!(velocity[0].abs() < 0.05, "still at {} m/s", velocity[0]);
+    }
+
+    #[test]
+    fn sideways_drift_turns_the_nose_into_it() {
+        let mut hold = DriftHold::new();
+
+        // Going left, turns left
+        let (yaw, elevator) = (0..100).fold((0, 0), |_, _| hold.update([0.0, 0.3], DT));
+        assert!(yaw < 0);
+        assert_eq!(elevator, 0);
+
+        // Doesn't get carried away however fast it goes
+        let (yaw, elevator) = (0..100).fold((0, 0), |_, _| hold.update([-5.0, 5.0], DT));
+        assert_eq!(yaw, -STICK_FULL / 6);
+        assert_eq!(elevator, STICK_FULL / 3);
+    }
+}
@@ -0,0 +1,28 @@
+// Hardware-independent part of the firmware
+//
+// Nothing in here knows about embassy or the softdevice, so it builds for the host
+// as well, and control changes can be tested there before they get anywhere near an
+// airframe. Run `cargo test` from this directory.
+
+#![cfg_attr(not(test), no_std)]
+
+pub mod altitude;
+pub mod attitude;
+pub mod boot;
+pub mod compass;
+pub mod console;
+pub mod control;
+pub mod current;
+pub mod dfu;
+pub mod factory;
+pub mod failsafe;
+pub mod flow;
+pub mod lights;
+pub mod mixer;
+pub mod pid;
+pub mod policy;
+pub mod range;
+pub mod sag;
+pub mod shaping;
+pub mod syma;
+pub mod xbox;
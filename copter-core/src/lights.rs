@@ -0,0 +1,95 @@
+// Auxiliary light patterns
+//
+// Whatever the pilot glued onto the canopy: navigation lights, a searchlight LED. Each
+// one goes through a pattern at its own brightness, and the firmware only asks for the
+// level every now and then (see aux.rs over there). Patterns are picked to look like
+// the real thing from across the room, not to be exact about it.
+
+#[repr(u8)]
+#[derive(Clone, Copy, PartialEq, Default, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Pattern {
+    #[default]
+    Off = 0,
+    Solid = 1,
+    // Half a second on, half off
+    Blink = 2,
+    // Double flash every ~1s, the anti-collision strobe
+    Strobe = 3,
+    // Fades in and out, the rotating beacon
+    Beacon = 4,
+}
+
+impl Pattern {
+    pub fn from_u8(v: u8) -> Option<Self> {
+        match v {
+            0 => Some(Self::Off),
+            1 => Some(Self::Solid),
+            2 => Some(Self::Blink),
+            3 => Some(Self::Strobe),
+            4 => Some(Self::Beacon),
+            _ => None,
+        }
+    }
+
+    // %, brightness is in % as well, ms is anything that goes up with the time
+    pub fn level(self, brightness: u8, ms: u32) -> u8 {
+        let brightness = brightness.min(100) as u32;
+
+        let level = match self {
+            Self::Off => 0,
+            Self::Solid => brightness,
+            Self::Blink => match ms % 1000 < 500 {
+                true => brightness,
+                false => 0,
+            },
+            Self::Strobe => match ms % 1200 {
+                0..50 | 150..200 => brightness,
+                _ => 0,
+            },
+            Self::Beacon => {
+                let t = ms % 2000;
+                let ramp = match t < 1000 {
+                    true => t,
+                    false => 2000 - t,
+                };
+
+                // Squared, so it lingers on the dark side like the real one
+                brightness * ramp * ramp / 1_000_000
+            }
+        };
+
+        level as u8
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lit(pattern: Pattern, period: u32) -> u32 {
+        (0..period).filter(|&ms| pattern.level(100, ms) > 0).count() as u32
+    }
+
+    #[test]
+    fn patterns_look_right() {
+        assert_eq!(lit(Pattern::Off, 1000), 0);
+        assert_eq!(lit(Pattern::Solid, 1000), 1000);
+        assert_eq!(lit(Pattern::Blink, 1000), 500);
+        // Mostly dark, that's what makes it a strobe
+        assert_eq!(lit(Pattern::Strobe, 1200), 100);
+
+        // Beacon goes all the way up and back, at whatever brightness it's given
+        assert_eq!(Pattern::Beacon.level(100, 0), 0);
+        assert_eq!(Pattern::Beacon.level(100, 1000), 100);
+        assert_eq!(Pattern::Beacon.level(40, 1000), 40);
+        assert!(Pattern::Beacon.level(100, 500) < 50);
+    }
+
+    #[test]
+    fn brightness_is_capped() {
+        assert_eq!(Pattern::Solid.level(250, 0), 100);
+        assert_eq!(Pattern::Solid.level(30, 0), 30);
+        assert_eq!(Pattern::from_u8(5), None);
+    }
+}
@@ -0,0 +1,77 @@
+// Mixer
+//
+// Main rotors are coaxial and spin in the opposite directions, so yaw comes from the
+// difference between them. Tail rotor sits on a half-driven H-bridge: one leg gets the
+// PWM and the other one is a plain pin, which sets the direction. PWM is inverted,
+// so with the plain leg high the duty has to be turned around.
+
+#[derive(Clone, Copy, PartialEq, Default, Debug)]
+pub struct Duties {
+    pub rotor1: u16,
+    pub rotor2: u16,
+    pub tail: u16,
+    // Level of the other leg of the tail bridge
+    pub tail_n: bool,
+}
+
+pub fn mix(throttle: i32, yaw_control: i32) -> (i32, i32) {
+    (throttle + yaw_control, throttle - yaw_control)
+}
+
+pub fn duties(rotor1: i32, rotor2: i32, tail: i32, max_duty: u16) -> Duties {
+    let clamp = |x: i32| x.clamp(0, max_duty as i32) as u16;
+
+    let (tail, tail_n) = match tail > 0 {
+        true => (max_duty as i32 - tail, true),
+        false => (-tail, false),
+    };
+
+    Duties {
+        rotor1: clamp(rotor1),
+        rotor2: clamp(rotor2),
+        tail: clamp(tail),
+        tail_n,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MAX: u16 = 512;
+
+    #[test]
+    fn yaw_splits_the_rotors() {
+        assert_eq!(mix(200, 0), (200, 200));
+        assert_eq!(mix(200, 50), (250, 150));
+        assert_eq!(mix(200, -50), (150, 250));
+    }
+
+    #[test]
+    fn rotors_are_clamped() {
+        let d = duties(600, -20, 0, MAX);
+
+        assert_eq!((d.rotor1, d.rotor2), (MAX, 0));
+    }
+
+    #[test]
+    fn tail_direction() {
+        assert_eq!(
+            duties(0, 0, 0, MAX),
+            Duties {
+                tail: 0,
+                tail_n: false,
+                ..Default::default()
+            }
+        );
+
+        let forward = duties(0, 0, 100, MAX);
+        assert_eq!((forward.tail, forward.tail_n), (MAX - 100, true));
+
+        let reverse = duties(0, 0, -100, MAX);
+        assert_eq!((reverse.tail, reverse.tail_n), (100, false));
+
+        let full = duties(0, 0, 1000, MAX);
+        assert_eq!((full.tail, full.tail_n), (0, true));
+    }
+}
@@ -0,0 +1,137 @@
+// Yaw rate PID
+//
+// Same math as the pid crate the firmware started with, brought in here so the host
+// side flies exactly what the airframe does. Each term is limited on its own, and so is
+// the sum. Derivative is taken on the measurement, so setpoint steps don't kick it.
+
+#[derive(Clone, Copy, PartialEq, Default, Debug)]
+pub struct ControlOutput {
+    pub p: f32,
+    pub i: f32,
+    pub d: f32,
+    pub output: f32,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Pid {
+    pub setpoint: f32,
+    output_limit: f32,
+    kp: f32,
+    ki: f32,
+    kd: f32,
+    p_limit: f32,
+    i_limit: f32,
+    d_limit: f32,
+    integral: f32,
+    previous: Option<f32>,
+}
+
+fn limit(x: f32, limit: f32) -> f32 {
+    x.min(limit).max(-limit)
+}
+
+impl Pid {
+    pub fn new(setpoint: f32, output_limit: impl Into<f32>) -> Self {
+        Self {
+            setpoint,
+            output_limit: output_limit.into(),
+            kp: 0.0,
+            ki: 0.0,
+            kd: 0.0,
+            p_limit: 0.0,
+            i_limit: 0.0,
+            d_limit: 0.0,
+            integral: 0.0,
+            previous: None,
+        }
+    }
+
+    pub fn p(&mut self, gain: f32, limit: impl Into<f32>) -> &mut Self {
+        self.kp = gain;
+        self.p_limit = limit.into();
+        self
+    }
+
+    pub fn i(&mut self, gain: f32, limit: impl Into<f32>) -> &mut Self {
+        self.ki = gain;
+        self.i_limit = limit.into();
+        self
+    }
+
+    pub fn d(&mut self, gain: f32, limit: impl Into<f32>) -> &mut Self {
+        self.kd = gain;
+        self.d_limit = limit.into();
+        self
+    }
+
+    pub fn next_control_output(&mut self, measurement: f32) -> ControlOutput {
+        let error = self.setpoint - measurement;
+
+        let p = limit(error * self.kp, self.p_limit);
+
+        self.integral = limit(self.integral + error * self.ki, self.i_limit);
+
+        let change = self.previous.map_or(0.0, |previous| measurement - previous);
+        let d = limit(-change * self.kd, self.d_limit);
+
+        self.previous = Some(measurement);
+
+        ControlOutput {
+            p,
+            i: self.integral,
+            d,
+            output: limit(p + self.integral + d, self.output_limit),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pid() -> Pid {
+        let mut pid = Pid::new(0.0, 100.0);
+        pid.p(1.0, 100.0).i(0.5, 100.0).d(2.0, 100.0);
+        pid
+    }
+
+    #[test]
+    fn terms_add_up() {
+        let mut pid = pid();
+
+        let first = pid.next_control_output(-10.0);
+        assert_eq!((first.p, first.i, first.d), (10.0, 5.0, 0.0));
+        assert_eq!(first.output, 15.0);
+
+        // Measurement moved up by 4, derivative pushes back
+        let second = pid.next_control_output(-6.0);
+        assert_eq!((second.p, second.i, second.d), (6.0, 8.0, -8.0));
+        assert_eq!(second.output, 6.0);
+    }
+
+    #[test]
+    fn every_term_is_limited() {
+        let mut pid = Pid::new(0.0, 50.0);
+        pid.p(1.0, 20.0).i(1.0, 10.0).d(0.0, 0.0);
+
+        for _ in 0..10 {
+            pid.next_control_output(-100.0);
+        }
+
+        let out = pid.next_control_output(-100.0);
+        assert_eq!((out.p, out.i, out.output), (20.0, 10.0, 30.0));
+
+        pid.p(10.0, 1000.0);
+        assert_eq!(pid.next_control_output(-100.0).output, 50.0);
+    }
+
+    #[test]
+    fn setpoint_steps_dont_kick_the_derivative() {
+        let mut pid = pid();
+
+        pid.next_control_output(0.0);
+        pid.setpoint = 100.0;
+
+        assert_eq!(pid.next_control_output(0.0).d, 0.0);
+    }
+}
@@ -0,0 +1,177 @@
+// Low battery policy
+//
+// The closer we get to empty, the more restrictive we become. Stages are ordered
+// from the most relaxed one to the most restrictive, so they can be compared.
+
+#[derive(Clone, Copy, PartialEq, PartialOrd, Default, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum SocStage {
+    #[default]
+    Normal,
+    // Let the pilot know, nothing else changes
+    Warn,
+    // Not enough juice to climb, cap the throttle
+    LimitThrottle,
+    // Slowly bring the throttle down to zero
+    ForceLanding,
+    // Whatever is on the ground, stays on the ground
+    InhibitArming,
+    // Cell is about to be damaged, power off everything we can
+    Shutdown,
+}
+
+// SoC levels (%) at which each stage kicks in
+#[derive(Clone, Copy, Debug)]
+pub struct Thresholds {
+    pub warn: u8,
+    pub limit_throttle: u8,
+    pub force_landing: u8,
+    pub inhibit_arming: u8,
+    pub shutdown: u8,
+}
+
+impl Thresholds {
+    // Anything higher would power off with a good part of the pack left
+    pub const MAX_SHUTDOWN: u8 = 10;
+
+    // Stages only ever get stricter on the way down, a threshold out of order
+    // would skip the ones before it
+    pub fn valid(&self) -> bool {
+        self.warn <= 100
+            && self.warn >= self.limit_throttle
+            && self.limit_throttle >= self.force_landing
+            && self.force_landing >= self.inhibit_arming
+            && self.inhibit_arming >= self.shutdown
+            && self.shutdown <= Self::MAX_SHUTDOWN
+    }
+}
+
+#[derive(Default)]
+pub struct SocPolicy {
+    stage: SocStage,
+}
+
+impl SocPolicy {
+    // Gauge readings are jumping a bit under load, so require some margin before
+    // going back to the more relaxed stage
+    pub const HYSTERESIS: u8 = 2;
+
+    pub const fn new() -> Self {
+        Self {
+            stage: SocStage::Normal,
+        }
+    }
+
+    fn stage_for(soc: u8, t: &Thresholds) -> SocStage {
+        match soc {
+            x if x <= t.shutdown => SocStage::Shutdown,
+            x if x <= t.inhibit_arming => SocStage::InhibitArming,
+            x if x <= t.force_landing => SocStage::ForceLanding,
+            x if x <= t.limit_throttle => SocStage::LimitThrottle,
+            x if x <= t.warn => SocStage::Warn,
+            _ => SocStage::Normal,
+        }
+    }
+
+    pub fn update(&mut self, soc: u8, charging: bool, t: &Thresholds) -> SocStage {
+        let target = Self::stage_for(soc, t);
+
+        if target > self.stage {
+            self.stage = target;
+        } else if target < self.stage {
+            self.stage = Self::stage_for(soc.saturating_sub(Self::HYSTERESIS), t);
+        }
+
+        // Turning off while on the charger only hides the SoC from the user,
+        // charging goes on anyway
+        if charging && self.stage == SocStage::Shutdown {
+            self.stage = SocStage::InhibitArming;
+        }
+
+        self.stage
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const THRESHOLDS: Thresholds = Thresholds {
+        warn: 20,
+        limit_throttle: 15,
+        force_landing: 10,
+        inhibit_arming: 5,
+        shutdown: 2,
+    };
+
+    #[test]
+    fn stages_follow_the_soc_down() {
+        let mut policy = SocPolicy::new();
+
+        let stages = [100, 20, 15, 10, 5, 2].map(|soc| policy.update(soc, false, &THRESHOLDS));
+
+        assert_eq!(
+            stages,
+            [
+                SocStage::Normal,
+                SocStage::Warn,
+                SocStage::LimitThrottle,
+                SocStage::ForceLanding,
+                SocStage::InhibitArming,
+                SocStage::Shutdown,
+            ]
+        );
+    }
+
+    #[test]
+    fn going_back_takes_the_hysteresis() {
+        let mut policy = SocPolicy::new();
+
+        assert_eq!(
+            policy.update(15, false, &THRESHOLDS),
+            SocStage::LimitThrottle
+        );
+        assert_eq!(
+            policy.update(16, false, &THRESHOLDS),
+            SocStage::LimitThrottle
+        );
+        assert_eq!(
+            policy.update(17, false, &THRESHOLDS),
+            SocStage::LimitThrottle
+        );
+        assert_eq!(policy.update(18, false, &THRESHOLDS), SocStage::Warn);
+    }
+
+    #[test]
+    fn thresholds_out_of_order_are_rejected() {
+        assert!(THRESHOLDS.valid());
+
+        let swapped = Thresholds {
+            force_landing: 16,
+            ..THRESHOLDS
+        };
+        let over_full = Thresholds {
+            warn: 101,
+            ..THRESHOLDS
+        };
+        let early_shutdown = Thresholds {
+            warn: 50,
+            limit_throttle: 40,
+            force_landing: 30,
+            inhibit_arming: 20,
+            shutdown: 15,
+        };
+
+        assert!(!swapped.valid());
+        assert!(!over_full.valid());
+        assert!(!early_shutdown.valid());
+    }
+
+    #[test]
+    fn never_shuts_down_on_the_charger() {
+        let mut policy = SocPolicy::new();
+
+        assert_eq!(policy.update(1, true, &THRESHOLDS), SocStage::InhibitArming);
+        assert_eq!(policy.update(1, false, &THRESHOLDS), SocStage::Shutdown);
+    }
+}
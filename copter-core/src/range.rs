@@ -0,0 +1,121 @@
+// Rangefinder health
+//
+// Time-of-flight sensors give the height above whatever is right under, which is what
+// flying close to the ground wants and what the barometer can't tell. They also give up
+// without much of a fuss: past their range, over black carpet or out in the sun, or
+// tilted so far the beam misses the spot under the airframe. Readings are only passed
+// on when the sensor says they're good, they're within range, and they agree with the
+// ones before them. After anything else, it takes a few good ones in a row to be
+// trusted again. Whatever flies on it goes open loop in the meantime.
+
+pub struct RangeCheck {
+    // m, past that the sensor is guessing
+    max: f32,
+    last: Option<f32>,
+    good: u32,
+}
+
+impl RangeCheck {
+    // ~30°, the beam is still mostly on the ground under the airframe
+    const MIN_TILT: f32 = 0.85;
+    // m/s, nothing this airframe does comes close. Faster means it's looking at
+    // something else now
+    const MAX_RATE: f32 = 3.0;
+    const TRUST_AFTER: u32 = 5;
+
+    pub const fn new(max: f32) -> Self {
+        Self {
+            max,
+            last: None,
+            good: 0,
+        }
+    }
+
+    // Range in m, None if the sensor says it's no good. Tilt is the cosine, see
+    // Mahony::tilt(). Gives the height, if it can be trusted
+    pub fn check(&mut self, range: Option<f32>, tilt: f32, dt: f32) -> Option<f32> {
+        let height = range
+            .filter(|r| *r >= 0.0 && *r <= self.max && tilt >= Self::MIN_TILT)
+            .map(|r| r * tilt);
+
+        let Some(height) = height else {
+            self.last = None;
+            self.good = 0;
+            return None;
+        };
+
+        let consistent = match self.last {
+            Some(last) => (height - last).abs() <= Self::MAX_RATE * dt,
+            None => true,
+        };
+
+        self.last = Some(height);
+        self.good = match consistent {
+            true => self.good.saturating_add(1),
+            false => 0,
+        };
+
+        (self.good >= Self::TRUST_AFTER).then_some(height)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DT: f32 = 0.05;
+
+    fn feed(check: &mut RangeCheck, range: Option<f32>, tilt: f32, n: usize) -> Option<f32> {
+        let mut height = None;
+
+        for _ in 0..n {
+            height = check.check(range, tilt, DT);
+        }
+
+        height
+    }
+
+    #[test]
+    fn takes_a_few_good_readings_to_trust() {
+        let mut check = RangeCheck::new(2.0);
+
+        assert_eq!(feed(&mut check, Some(0.5), 1.0, 4), None);
+        assert_eq!(feed(&mut check, Some(0.5), 1.0, 1), Some(0.5));
+
+        // One bad reading starts it over
+        assert_eq!(feed(&mut check, None, 1.0, 1), None);
+        assert_eq!(feed(&mut check, Some(0.5), 1.0, 4), None);
+        assert_eq!(feed(&mut check, Some(0.5), 1.0, 1), Some(0.5));
+    }
+
+    #[test]
+    fn out_of_range_and_tilted_are_not_trusted() {
+        let mut check = RangeCheck::new(2.0);
+
+        assert_eq!(feed(&mut check, Some(2.5), 1.0, 10), None);
+        assert_eq!(feed(&mut check, Some(1.0), 0.7, 10), None);
+
+        // A bit of tilt is fine, the height is what's under
+        let height = feed(&mut check, Some(1.0), 0.9, 10).unwrap();
+        assert!((height - 0.9).abs() < 1e-6);
+    }
+
+    #[test]
+    fn jumps_are_not_trusted_until_they_settle() {
+        let mut check = RangeCheck::new(2.0);
+
+        feed(&mut check, Some(1.0), 1.0, 10);
+
+        // Flew over a table
+        assert_eq!(feed(&mut check, Some(0.3), 1.0, 1), None);
+        assert_eq!(feed(&mut check, Some(0.3), 1.0, 4), None);
+        assert_eq!(feed(&mut check, Some(0.3), 1.0, 1), Some(0.3));
+
+        // Moving at a sane rate is fine all along
+        let mut height = 0.3;
+        for _ in 0..20 {
+            height += 0.5 * DT;
+            assert!(check.check(Some(height), 1.0, DT).is_some());
+        }
+    }
+}
@@ -0,0 +1,173 @@
+// Battery rail sag
+//
+// Punching the throttle drags the rail down for a moment, by the rotor current times
+// everything between the cells and the motors: cell resistance, the wiring, the
+// connector. Stock harness barely shows, a modded one with thin leads or a worn plug
+// does, and that's what this is for. The rail is sampled way faster than the control
+// loop runs, so the bottom of the dip isn't missed.
+//
+// Only throttle steps count. Baseline is the rail averaged over the quiet time before
+// one, then the lowest sample of the window after it is the dip. Steps that come while
+// a window is open belong to it.
+
+use crate::shaping::STICK_FULL;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Sag {
+    // mV
+    pub baseline: u16,
+    pub min: u16,
+    // ms from the step to the bottom
+    pub time_to_min: u16,
+    // How far the throttle went up, stick units
+    pub step: i32,
+}
+
+impl Sag {
+    pub fn droop(&self) -> u16 {
+        self.baseline.saturating_sub(self.min)
+    }
+}
+
+struct Window {
+    baseline: f32,
+    min: f32,
+    time_to_min: u32,
+    from: i32,
+    to: i32,
+    elapsed: u32,
+}
+
+pub struct SagMeter {
+    sample_hz: u32,
+    // mV, smoothed, only follows the rail outside of the windows
+    baseline: Option<f32>,
+    // Throttle as of a little while ago, smoothed the same way
+    throttle: f32,
+    window: Option<Window>,
+}
+
+impl SagMeter {
+    // Anything smaller is the pilot holding the altitude
+    const MIN_STEP: i32 = STICK_FULL / 8;
+    // s, the dip is over well before that
+    const WINDOW: f32 = 0.3;
+    // s, long enough to ride out the PWM ripple
+    const SMOOTHING: f32 = 0.1;
+
+    pub const fn new(sample_hz: u32) -> Self {
+        Self {
+            sample_hz,
+            baseline: None,
+            throttle: 0.0,
+            window: None,
+        }
+    }
+
+    fn ms(&self, samples: u32) -> u16 {
+        (samples * 1000 / self.sample_hz) as u16
+    }
+
+    // Every rail sample, in mV, along with the throttle it was taken at. Sag is there
+    // once the window after a step is over
+    pub fn update(&mut self, voltage: f32, throttle: i32) -> Option<Sag> {
+        let k = 1.0 / (Self::SMOOTHING * self.sample_hz as f32 + 1.0);
+        let baseline = *self.baseline.get_or_insert(voltage);
+        let before = (self.throttle + 0.5) as i32;
+
+        self.throttle += (throttle as f32 - self.throttle) * k;
+
+        let Some(window) = &mut self.window else {
+            self.baseline = Some(baseline + (voltage - baseline) * k);
+
+            if throttle - before >= Self::MIN_STEP {
+                self.window = Some(Window {
+                    baseline,
+                    min: voltage,
+                    time_to_min: 0,
+                    from: before,
+                    to: throttle,
+                    elapsed: 0,
+                });
+            }
+
+            return None;
+        };
+
+        window.elapsed += 1;
+        window.to = window.to.max(throttle);
+
+        if voltage < window.min {
+            window.min = voltage;
+            window.time_to_min = window.elapsed;
+        }
+
+        if window.elapsed < (Self::WINDOW * self.sample_hz as f32) as u32 {
+            return None;
+        }
+
+        let window = self.window.take()?;
+
+        // Rail is wherever it settled at the new throttle
+        self.baseline = Some(voltage);
+
+        Some(Sag {
+            baseline: window.baseline as u16,
+            min: window.min as u16,
+            time_to_min: self.ms(window.time_to_min),
+            step: window.to - window.from,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HZ: u32 = 1000;
+
+    // Rail at rest, minus the drop across the harness while the rotors spin up
+    fn rail(throttle: i32, t: u32) -> f32 {
+        let spinup = (t as f32 / 40.0).min(1.0);
+        let dip = 300.0 * (1.0 - spinup);
+
+        3900.0 - throttle as f32 * 0.5 - dip
+    }
+
+    #[test]
+    fn steady_throttle_is_no_sag() {
+        let mut meter = SagMeter::new(HZ);
+
+        // Slow climb of the throttle, the way the altitude hold would do it
+        for t in 0..5 * HZ {
+            let throttle = (t / 40) as i32;
+            assert_eq!(meter.update(rail(throttle, 1000), throttle), None);
+        }
+    }
+
+    #[test]
+    fn throttle_punch_is_measured() {
+        let mut meter = SagMeter::new(HZ);
+
+        // Taking off is a step too, the hover comes first
+        for _ in 0..HZ {
+            meter.update(rail(100, 1000), 100);
+        }
+
+        let sag = (0..HZ)
+            .find_map(|t| meter.update(rail(400, t), 400))
+            .unwrap();
+
+        assert_eq!(sag.baseline, 3850);
+        assert_eq!(sag.step, 300);
+        assert!(sag.time_to_min < 5, "bottom at {}ms", sag.time_to_min);
+        // 300mV worth of spinning up, 150 more for holding it
+        assert!((440..460).contains(&sag.droop()), "{}mV", sag.droop());
+
+        // And nothing more until the next one
+        for t in 0..HZ {
+            assert_eq!(meter.update(rail(400, 1000 + t), 400), None);
+        }
+    }
+}
@@ -0,0 +1,75 @@
+// Input shaping
+//
+// Raw sticks are 16-bit, centered at zero. The control loop works with +-512 at full
+// deflection, which is what the pilot profile trims and rates are in as well.
+
+pub const STICK_FULL: i32 = 512;
+
+pub fn stick(raw: i32) -> i32 {
+    raw >> 6
+}
+
+// Throttle stick only goes one way, the lower half is all zero
+pub fn throttle(raw: i32) -> i32 {
+    stick(raw).max(0)
+}
+
+// Expo blends in a cubic curve, which is flat around the center and catches up at full
+// deflection. Rate is in %, full deflection gets that much of the full range
+pub fn shape(stick: i32, expo: u8, rate: u8, trim: i8) -> i32 {
+    const FULL: f32 = STICK_FULL as f32;
+
+    let x = stick as f32 / FULL;
+    let expo = expo.min(100) as f32 / 100.0;
+    let curved = x * (1.0 - expo) + x * x * x * expo;
+
+    (curved * FULL * rate as f32 / 100.0) as i32 + trim as i32
+}
+
+// Only the press itself counts, not holding the button
+pub fn pressed(previous: u32, current: u32, button: u32) -> bool {
+    current & button != 0 && previous & button == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn linear_without_expo() {
+        for x in [-512, -100, 0, 100, 512] {
+            assert_eq!(shape(x, 0, 100, 0), x);
+        }
+    }
+
+    #[test]
+    fn expo_keeps_the_ends_and_softens_the_center() {
+        assert_eq!(shape(512, 100, 100, 0), 512);
+        assert_eq!(shape(-512, 100, 100, 0), -512);
+        assert!(shape(128, 50, 100, 0) < 128);
+        assert!(shape(-128, 50, 100, 0) > -128);
+    }
+
+    #[test]
+    fn rate_and_trim() {
+        assert_eq!(shape(512, 0, 50, 0), 256);
+        assert_eq!(shape(0, 0, 100, -10), -10);
+        assert_eq!(shape(0, 100, 0, 7), 7);
+    }
+
+    #[test]
+    fn throttle_has_no_reverse() {
+        assert_eq!(throttle(-32768), 0);
+        assert_eq!(throttle(32767), 511);
+    }
+
+    #[test]
+    fn press_is_an_edge() {
+        const BUTTON: u32 = 1 << 11;
+
+        assert!(pressed(0, BUTTON, BUTTON));
+        assert!(!pressed(BUTTON, BUTTON, BUTTON));
+        assert!(!pressed(BUTTON, 0, BUTTON));
+        assert!(!pressed(0, 1, BUTTON));
+    }
+}
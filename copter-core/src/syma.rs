@@ -0,0 +1,348 @@
+// Stock Syma remote
+//
+// The S107 transmitter sends its 3 channels over IR, 38 kHz on and off. A frame is a
+// 2ms mark and a 2ms space, then 32 bits, each a ~300us mark followed by a ~300us space
+// for a zero or a ~700us one for a one, MSB first, and a closing mark. Bytes are yaw,
+// pitch, throttle and yaw trim, 0..127 each, with the channel switch in the top bit of
+// the throttle. Channel A repeats every ~120ms, B every ~180ms.
+//
+// There's no checksum, so frames have to fit the timing exactly. A missed edge shifts
+// everything after it, which ends up either too short or running into the silence
+// after the closing mark, and either way it's thrown away.
+
+// Raw sticks, same as the Xbox ones: 16-bit, centered at zero
+const STICK_SCALE: i32 = 512;
+const CENTER: i32 = 63;
+// Remote's throttle doesn't quite get to zero on some
+const THROTTLE_DEADBAND: u8 = 4;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Channel {
+    A,
+    B,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Frame {
+    // 0..127, centered at 63 but the throttle. Full left and full back are 127
+    pub yaw: u8,
+    pub pitch: u8,
+    pub throttle: u8,
+    pub trim: u8,
+    pub channel: Channel,
+}
+
+impl Frame {
+    fn decode(bits: u32) -> Option<Self> {
+        let [yaw, pitch, throttle, trim] = bits.to_be_bytes();
+
+        // Only the throttle has anything up there
+        if (yaw | pitch | trim) & 0x80 != 0 {
+            return None;
+        }
+
+        Some(Self {
+            yaw,
+            pitch,
+            throttle: throttle & 0x7f,
+            trim,
+            channel: match throttle & 0x80 {
+                0 => Channel::A,
+                _ => Channel::B,
+            },
+        })
+    }
+
+    // Raw sticks, see shaping.rs. Throttle is on the upper half only, as the lower half
+    // is all zero anyway
+    pub fn throttle_stick(&self) -> i32 {
+        let throttle = self.throttle.saturating_sub(THROTTLE_DEADBAND) as i32;
+        throttle * i16::MAX as i32 / (127 - THROTTLE_DEADBAND as i32)
+    }
+
+    // Trim is on the yaw only, the rest of the airframe has nothing to trim
+    pub fn yaw_stick(&self) -> i32 {
+        ((CENTER - self.yaw as i32) + (CENTER - self.trim as i32) / 4) * STICK_SCALE
+    }
+
+    pub fn pitch_stick(&self) -> i32 {
+        (CENTER - self.pitch as i32) * STICK_SCALE
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum State {
+    Idle,
+    Header,
+    // Bits so far
+    Mark(u32, u8),
+    Space(u32, u8),
+    // Waiting for the closing mark, then for it to go quiet
+    Closing(u32),
+    Done(u32),
+}
+
+pub struct Decoder {
+    state: State,
+}
+
+impl Decoder {
+    // us, with plenty of room, receivers stretch marks and shrink spaces a bit
+    const HEADER: (u32, u32) = (1600, 2500);
+    const MARK: (u32, u32) = (150, 600);
+    const ZERO: (u32, u32) = (150, 500);
+    const ONE: (u32, u32) = (500, 1000);
+    // Nothing in a frame lasts that long, see silence()
+    pub const SILENCE_US: u32 = 4000;
+
+    pub const fn new() -> Self {
+        Self { state: State::Idle }
+    }
+
+    fn within(us: u32, (min, max): (u32, u32)) -> bool {
+        us >= min && us < max
+    }
+
+    // Time since the previous edge, either way
+    pub fn edge(&mut self, us: u32) {
+        let header = Self::within(us, Self::HEADER);
+        let mark = Self::within(us, Self::MARK);
+
+        self.state = match self.state {
+            State::Header if header => State::Mark(0, 0),
+            State::Mark(bits, n) if mark => State::Space(bits, n),
+            State::Space(bits, n) => {
+                let bit = match us {
+                    _ if Self::within(us, Self::ZERO) => 0,
+                    _ if Self::within(us, Self::ONE) => 1,
+                    _ => return self.restart(header),
+                };
+
+                match n + 1 {
+                    32 => State::Closing(bits << 1 | bit),
+                    n => State::Mark(bits << 1 | bit, n),
+                }
+            }
+            State::Closing(bits) if mark => State::Done(bits),
+            _ => return self.restart(header),
+        };
+    }
+
+    fn restart(&mut self, header: bool) {
+        self.state = match header {
+            true => State::Header,
+            false => State::Idle,
+        };
+    }
+
+    // Somewhere in a frame, silence() is due once it's quiet
+    pub fn busy(&self) -> bool {
+        self.state != State::Idle
+    }
+
+    // No edges for SILENCE_US. A frame is only over once it's quiet after it
+    pub fn silence(&mut self) -> Option<Frame> {
+        let state = core::mem::replace(&mut self.state, State::Idle);
+
+        match state {
+            State::Done(bits) => Frame::decode(bits),
+            _ => None,
+        }
+    }
+}
+
+impl Default for Decoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Event {
+    // Remote has just taken over
+    Engaged,
+    Input(Frame),
+}
+
+// Who's flying. The remote only takes over with the throttle down for a while, so
+// picking it up or losing the other controller mid-flight doesn't jump the throttle.
+// Sticks to the first channel it hears, a neighbour on the other one can't butt in
+pub struct Remote {
+    channel: Option<Channel>,
+    low: u32,
+    engaged: bool,
+}
+
+impl Remote {
+    // ~1s on either channel
+    const ENGAGE_FRAMES: u32 = 8;
+
+    pub const fn new() -> Self {
+        Self {
+            channel: None,
+            low: 0,
+            engaged: false,
+        }
+    }
+
+    pub fn engaged(&self) -> bool {
+        self.engaged
+    }
+
+    pub fn update(&mut self, frame: Frame) -> Option<Event> {
+        if *self.channel.get_or_insert(frame.channel) != frame.channel {
+            return None;
+        }
+
+        if self.engaged {
+            return Some(Event::Input(frame));
+        }
+
+        self.low = match frame.throttle_stick() == 0 {
+            true => self.low + 1,
+            false => 0,
+        };
+
+        self.engaged = self.low >= Self::ENGAGE_FRAMES;
+        self.engaged.then_some(Event::Engaged)
+    }
+
+    // Remote went quiet, or something else is flying. Takes the throttle down again
+    // to come back, on whichever channel is heard first
+    pub fn lost(&mut self) {
+        *self = Self::new();
+    }
+}
+
+impl Default for Remote {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Edge to edge, as the transmitter sends it
+    fn encode(bytes: [u8; 4]) -> impl Iterator<Item = u32> {
+        let bits = u32::from_be_bytes(bytes);
+        let data = (0..32).rev().flat_map(move |i| match bits >> i & 1 {
+            0 => [320, 280],
+            _ => [320, 680],
+        });
+
+        [2000, 2000].into_iter().chain(data).chain([320])
+    }
+
+    fn decode(decoder: &mut Decoder, edges: impl Iterator<Item = u32>) -> Option<Frame> {
+        edges.for_each(|us| decoder.edge(us));
+        decoder.silence()
+    }
+
+    #[test]
+    fn decodes_a_frame() {
+        let mut decoder = Decoder::new();
+
+        let frame = decode(&mut decoder, encode([63, 20, 0x80 | 100, 66])).unwrap();
+        assert_eq!(
+            frame,
+            Frame {
+                yaw: 63,
+                pitch: 20,
+                throttle: 100,
+                trim: 66,
+                channel: Channel::B,
+            }
+        );
+
+        // Pitched forward, and the trim doesn't do anything that small
+        assert_eq!(frame.yaw_stick(), 0);
+        assert!(frame.pitch_stick() > 0);
+
+        // Throttle is all there, however low the remote goes
+        let full = Frame {
+            throttle: 127,
+            ..frame
+        };
+        let low = Frame {
+            throttle: 3,
+            ..frame
+        };
+        assert_eq!(full.throttle_stick(), i16::MAX as i32);
+        assert_eq!(low.throttle_stick(), 0);
+    }
+
+    #[test]
+    fn missed_edges_throw_the_frame_away() {
+        let mut decoder = Decoder::new();
+
+        for missed in [1, 2, 3] {
+            let edges: Vec<u32> = encode([63, 63, 50, 63]).collect();
+            // Space and the mark after it look just like a one
+            let mut merged = edges[..11].to_vec();
+
+            merged.push(edges[11..11 + missed + 1].iter().sum());
+            merged.extend_from_slice(&edges[11 + missed + 1..]);
+
+            assert_eq!(decode(&mut decoder, merged.into_iter()), None);
+        }
+
+        // Not stuck either
+        assert!(decode(&mut decoder, encode([63, 63, 50, 63])).is_some());
+    }
+
+    #[test]
+    fn only_the_throttle_has_the_top_bit() {
+        let mut decoder = Decoder::new();
+
+        assert_eq!(decode(&mut decoder, encode([0x80 | 63, 63, 0, 63])), None);
+    }
+
+    #[test]
+    fn picks_up_mid_frame() {
+        let mut decoder = Decoder::new();
+        let edges = encode([63, 63, 50, 63]).skip(20).chain([30_000]);
+
+        assert!(decode(&mut decoder, edges.chain(encode([63, 63, 50, 63]))).is_some());
+    }
+
+    fn frame(throttle: u8, channel: Channel) -> Frame {
+        Frame {
+            yaw: 63,
+            pitch: 63,
+            throttle,
+            trim: 63,
+            channel,
+        }
+    }
+
+    #[test]
+    fn remote_takes_over_with_the_throttle_down() {
+        let mut remote = Remote::new();
+
+        // Throttle up doesn't count, it has to be down all along
+        for _ in 0..20 {
+            assert_eq!(remote.update(frame(80, Channel::A)), None);
+        }
+
+        for _ in 0..Remote::ENGAGE_FRAMES - 1 {
+            assert_eq!(remote.update(frame(0, Channel::A)), None);
+        }
+
+        assert_eq!(remote.update(frame(0, Channel::A)), Some(Event::Engaged));
+        assert_eq!(
+            remote.update(frame(80, Channel::A)),
+            Some(Event::Input(frame(80, Channel::A)))
+        );
+
+        // Other channel is someone else
+        assert_eq!(remote.update(frame(0, Channel::B)), None);
+
+        remote.lost();
+        assert!(!remote.engaged());
+        assert_eq!(remote.update(frame(80, Channel::B)), None);
+    }
+}
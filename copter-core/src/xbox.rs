@@ -0,0 +1,184 @@
+// Xbox One controller advertisements and HID reports
+
+pub const STICKS_RANGE: i32 = 65535;
+
+// Sticks are centered at zero, positive is right and up
+#[derive(Clone, Copy, PartialEq, Default, Debug)]
+pub struct HidReport {
+    pub j1: (i32, i32),
+    pub j2: (i32, i32),
+    pub t1: u16,
+    pub t2: u16,
+    pub buttons: u32,
+}
+
+// Checks whether advetrisement packet is coming from XBox controller
+// This is a pretty crude check overall.
+pub fn is_xbox_controller(packet: &[u8]) -> bool {
+    const TYPE_MANUFACTURER_SPECIFIC_DATA: u8 = 0xFF;
+    const TYPE_PARTIAL_16BIT_UUIDS: u8 = 0x02;
+    const TYPE_COMPLETE_16BIT_UUIDS: u8 = 0x03;
+
+    let mut i = 0;
+
+    let mut next_entry = || {
+        let mut remaining = packet.len() - i;
+
+        // we need at least len + type
+        if remaining < 2 {
+            i += remaining;
+            None
+        } else {
+            let data_len = packet[i] as usize;
+            i += 1;
+            remaining -= 1;
+
+            if data_len == 0 || data_len > remaining {
+                i += remaining;
+                return None;
+            }
+
+            let data = &packet[i..i + data_len];
+            i += data_len;
+
+            Some((data[0], &data[1..]))
+        }
+    };
+
+    let mut is_microsoft = false;
+    let mut is_hid = false;
+
+    while let Some((t, data)) = next_entry() {
+        match t {
+            TYPE_MANUFACTURER_SPECIFIC_DATA if data.len() >= 2 && data[0..2] == [0x06, 0x00] => {
+                is_microsoft = true;
+            }
+
+            TYPE_PARTIAL_16BIT_UUIDS | TYPE_COMPLETE_16BIT_UUIDS => {
+                for uuid in data.chunks(2) {
+                    if uuid == [0x12, 0x18] {
+                        is_hid = true;
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    is_microsoft && is_hid
+}
+
+pub fn decode_hid_report(p: &[u8; 16]) -> HidReport {
+    let read_u16 = |at: usize| u16::from_le_bytes([p[at], p[at + 1]]);
+    let button_mask = u32::from_le_bytes([p[13], p[14], p[15], 0]);
+
+    let x1 = read_u16(0);
+    let y1 = read_u16(2);
+    let x2 = read_u16(4);
+    let y2 = read_u16(6);
+
+    let t1 = read_u16(8);
+    let t2 = read_u16(10);
+
+    let map_stick = |x| (x as i32) - STICKS_RANGE / 2;
+
+    HidReport {
+        j1: (map_stick(x1), -map_stick(y1)),
+        j2: (map_stick(x2), -map_stick(y2)),
+        t1,
+        t2,
+        buttons: button_mask,
+    }
+}
+
+// Other way around, for whatever relays a pad that isn't one (see tools/relay)
+pub fn encode_hid_report(r: &HidReport) -> [u8; 16] {
+    let unmap_stick = |v: i32| (v + STICKS_RANGE / 2).clamp(0, STICKS_RANGE) as u16;
+
+    let mut p = [0; 16];
+
+    p[0..2].copy_from_slice(&unmap_stick(r.j1.0).to_le_bytes());
+    p[2..4].copy_from_slice(&unmap_stick(-r.j1.1).to_le_bytes());
+    p[4..6].copy_from_slice(&unmap_stick(r.j2.0).to_le_bytes());
+    p[6..8].copy_from_slice(&unmap_stick(-r.j2.1).to_le_bytes());
+    p[8..10].copy_from_slice(&r.t1.to_le_bytes());
+    p[10..12].copy_from_slice(&r.t2.to_le_bytes());
+    p[13..16].copy_from_slice(&r.buttons.to_le_bytes()[..3]);
+
+    p
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn recognizes_a_controller() {
+        let packet = [
+            0x02, 0x01, 0x06, // flags
+            0x03, 0x03, 0x12, 0x18, // HID service
+            0x05, 0xff, 0x06, 0x00, 0x03, 0x00, // Microsoft
+        ];
+
+        assert!(is_xbox_controller(&packet));
+    }
+
+    #[test]
+    fn needs_both_microsoft_and_hid() {
+        assert!(!is_xbox_controller(&[0x03, 0x03, 0x12, 0x18]));
+        assert!(!is_xbox_controller(&[0x05, 0xff, 0x06, 0x00, 0x03, 0x00]));
+    }
+
+    #[test]
+    fn survives_malformed_packets() {
+        assert!(!is_xbox_controller(&[]));
+        assert!(!is_xbox_controller(&[0x00]));
+        assert!(!is_xbox_controller(&[0x10, 0x03, 0x12]));
+        assert!(!is_xbox_controller(&[0x03, 0x03, 0x12, 0x18, 0x05]));
+    }
+
+    #[test]
+    fn decodes_sticks_and_buttons() {
+        let mut report = [0; 16];
+
+        // Left stick all the way up, right one all the way left
+        report[0..2].copy_from_slice(&32767u16.to_le_bytes());
+        report[2..4].copy_from_slice(&0u16.to_le_bytes());
+        report[4..6].copy_from_slice(&0u16.to_le_bytes());
+        report[6..8].copy_from_slice(&32767u16.to_le_bytes());
+        report[8..10].copy_from_slice(&1023u16.to_le_bytes());
+        report[13..16].copy_from_slice(&[0x01, 0x08, 0x00]);
+
+        let decoded = decode_hid_report(&report);
+
+        assert_eq!(decoded.j1, (0, 32767));
+        assert_eq!(decoded.j2, (-32767, 0));
+        assert_eq!(decoded.t1, 1023);
+        assert_eq!(decoded.t2, 0);
+        assert_eq!(decoded.buttons, 1 << 0 | 1 << 11);
+    }
+
+    #[test]
+    fn encodes_what_it_decodes() {
+        let report = HidReport {
+            j1: (-32767, 32767),
+            j2: (1000, -2000),
+            t1: 512,
+            t2: 1023,
+            buttons: 1 << 3 | 1 << 16,
+        };
+
+        assert_eq!(decode_hid_report(&encode_hid_report(&report)), report);
+
+        // Off the scale is pinned to the end of it
+        let report = HidReport {
+            j1: (0, 40000),
+            ..report
+        };
+
+        assert_eq!(
+            decode_hid_report(&encode_hid_report(&report)).j1,
+            (0, 32767)
+        );
+    }
+}
@@ -0,0 +1,35 @@
+MEMORY
+{
+  /* nRF52832_xxAA with SoftDevice S132 7.3.0 and the bootloader. Keep in sync with
+   * bootloader/memory.x */
+  FLASH : ORIGIN = 0x00000000 + 152K, LENGTH = 148K
+  /* New images are written there, see boot.rs */
+  DFU : ORIGIN = 300K, LENGTH = 152K
+  BOOTLOADER_STATE : ORIGIN = 476K, LENGTH = 4K
+  /* Per-unit data written at the bench, see factory.rs */
+  FACTORY : ORIGIN = 512K - 24K, LENGTH = 4K
+  /* Latest pre-crash history dump, see history.rs */
+  HISTORY : ORIGIN = 512K - 20K, LENGTH = 4K
+  /* Warnings and errors that outlive a reboot, see blackbox.rs */
+  BLACKBOX : ORIGIN = 512K - 16K, LENGTH = 8K
+  /* Last two flash pages keep persistent settings, see settings.rs */
+  SETTINGS : ORIGIN = 512K - 8K, LENGTH = 8K
+  RAM : ORIGIN = 0x20000000 + 0x3328, LENGTH = 64K - 0x3328
+}
+
+__settings_start = ORIGIN(SETTINGS);
+__settings_end = ORIGIN(SETTINGS) + LENGTH(SETTINGS);
+
+__blackbox_start = ORIGIN(BLACKBOX);
+__blackbox_end = ORIGIN(BLACKBOX) + LENGTH(BLACKBOX);
+
+__history_start = ORIGIN(HISTORY);
+__history_end = ORIGIN(HISTORY) + LENGTH(HISTORY);
+
+__factory_start = ORIGIN(FACTORY);
+
+__bootloader_dfu_start = ORIGIN(DFU);
+__bootloader_dfu_end = ORIGIN(DFU) + LENGTH(DFU);
+
+__bootloader_state_start = ORIGIN(BOOTLOADER_STATE);
+__bootloader_state_end = ORIGIN(BOOTLOADER_STATE) + LENGTH(BOOTLOADER_STATE);
@@ -1,6 +1,25 @@
 MEMORY
 {
   /* These values correspond to the NRF52832 with SoftDevices S132 7.3.0 */
-  FLASH : ORIGIN = 0x00000000 + 152K, LENGTH = 256K - 152K
+  FLASH : ORIGIN = 0x00000000 + 152K, LENGTH = 256K - 152K - 24K
+  /* Per-unit data written at the bench, see factory.rs */
+  FACTORY : ORIGIN = 256K - 24K, LENGTH = 4K
+  /* Latest pre-crash history dump, see history.rs */
+  HISTORY : ORIGIN = 256K - 20K, LENGTH = 4K
+  /* Warnings and errors that outlive a reboot, see blackbox.rs */
+  BLACKBOX : ORIGIN = 256K - 16K, LENGTH = 8K
+  /* Last two flash pages keep persistent settings, see settings.rs */
+  SETTINGS : ORIGIN = 256K - 8K, LENGTH = 8K
   RAM : ORIGIN = 0x20000000 + 0x3328, LENGTH = 32K - 0x3328
 }
+
+__settings_start = ORIGIN(SETTINGS);
+__settings_end = ORIGIN(SETTINGS) + LENGTH(SETTINGS);
+
+__blackbox_start = ORIGIN(BLACKBOX);
+__blackbox_end = ORIGIN(BLACKBOX) + LENGTH(BLACKBOX);
+
+__history_start = ORIGIN(HISTORY);
+__history_end = ORIGIN(HISTORY) + LENGTH(HISTORY);
+
+__factory_start = ORIGIN(FACTORY);
@@ -0,0 +1,152 @@
+// Recoverable assertions
+//
+// For things that are never supposed to happen, but don't have to take everything
+// down when they do. Unlike unwrap!, a failed one is recorded before anything else:
+// the report is kept in RAM that survives a reset, same as a panic (see postmortem.rs),
+// the black box gets an Incident::Assertion, and a connected client is notified. Only
+// then the severity decides whether to carry on, stay on the ground, or reset.
+
+use crate::blackbox::Incident;
+use crate::eventlog::Event;
+use crate::postmortem;
+use crate::state::SystemState;
+use crate::types::Faults;
+
+#[derive(Clone, Copy, PartialEq, defmt::Format)]
+pub enum Severity {
+    // Caller copes, it's only worth knowing about
+    Continue = 0,
+    // Not fit to fly anymore, arming is inhibited until the next power-on
+    Degrade = 1,
+    // Nothing sensible left to do. Not while armed though, see SystemState::escalate()
+    Reset = 2,
+}
+
+// Goes into the report, so it can be told which one failed without the defmt table
+#[repr(u16)]
+#[derive(Clone, Copy, PartialEq, defmt::Format)]
+pub enum Assertion {
+    // Attribute table doesn't fit, we go on with the controller only
+    GattServer = 1,
+    // See Mode::transition_allowed()
+    ModeTransition = 2,
+    // SAADC stopped sampling while armed, see control.rs
+    GyroSampler = 3,
+}
+
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+pub struct AssertionReport {
+    // Zero if nothing has failed
+    pub line: u32,
+    pub assertion: u16,
+    pub severity: u8,
+    pub _reserved: u8,
+    // Tail of the module path, the crate name is the same for all of them anyway
+    pub module: [u8; 24],
+}
+
+impl AssertionReport {
+    pub const EMPTY: Self = Self {
+        line: 0,
+        assertion: 0,
+        severity: 0,
+        _reserved: 0,
+        module: [0; 24],
+    };
+
+    fn new(assertion: Assertion, severity: Severity, module: &str, line: u32) -> Self {
+        let path = module.as_bytes();
+        let tail = &path[path.len().saturating_sub(24)..];
+
+        let mut report = Self {
+            line,
+            assertion: assertion as u16,
+            severity: severity as u8,
+            ..Self::EMPTY
+        };
+
+        report.module[..tail.len()].copy_from_slice(tail);
+        report
+    }
+}
+
+// Not meant to be called directly, see soft_assert! and soft_unwrap!. Says whether
+// it's worth printing
+pub fn failed(
+    state: &SystemState,
+    assertion: Assertion,
+    severity: Severity,
+    module: &str,
+    line: u32,
+) -> bool {
+    let report = AssertionReport::new(assertion, severity, module, line);
+
+    // In case whatever comes next doesn't let us get any further
+    postmortem::capture_assertion(report);
+    state.assertion.sender().send(report);
+
+    let print = state.raise_incident(Event::Error, Incident::Assertion);
+
+    match severity {
+        Severity::Continue => {}
+        Severity::Degrade => state.set_faults(Faults::ASSERTION, true),
+        // Stays on the ground if the reset has to wait
+        Severity::Reset => {
+            state.set_faults(Faults::ASSERTION, true);
+            state.escalate(Incident::Assertion);
+        }
+    }
+
+    print
+}
+
+// Evaluates to the condition, so the caller can get out of the way if it's false.
+// Message is optional, the assertion name is printed otherwise
+macro_rules! soft_assert {
+    ($state:expr, $severity:ident, $assertion:expr, $cond:expr) => {
+        $crate::assertion::soft_assert!($state, $severity, $assertion, $cond, "{} failed", $assertion)
+    };
+    ($state:expr, $severity:ident, $assertion:expr, $cond:expr, $($arg:tt)+) => {{
+        let ok: bool = $cond;
+
+        if !ok
+            && $crate::assertion::failed(
+                $state,
+                $assertion,
+                $crate::assertion::Severity::$severity,
+                module_path!(),
+                line!(),
+            )
+        {
+            defmt::error!($($arg)+);
+        }
+
+        ok
+    }};
+}
+
+// Same as unwrap!, except that it's None instead of a panic
+macro_rules! soft_unwrap {
+    ($state:expr, $severity:ident, $assertion:expr, $result:expr) => {
+        match $result {
+            Ok(value) => Some(value),
+            Err(e) => {
+                if $crate::assertion::failed(
+                    $state,
+                    $assertion,
+                    $crate::assertion::Severity::$severity,
+                    module_path!(),
+                    line!(),
+                ) {
+                    defmt::error!("{} failed - {}", $assertion, e);
+                }
+
+                None
+            }
+        }
+    };
+}
+
+pub(crate) use soft_assert;
+pub(crate) use soft_unwrap;
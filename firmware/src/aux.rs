@@ -0,0 +1,260 @@
+// Auxiliary outputs
+//
+// Builds with the aux-outputs feature drive a couple of lights, whatever got glued onto
+// the canopy: navigation lights, a searchlight LED. Active high. On the S107 board that's
+// the inputs of the aux H-bridge, with the lights between AUXA / AUXB and the battery
+// plus: a high input pulls the other side's output low, and both high is brake, which
+// pulls both low. So the two stay independent, unlike with lights to ground. Patterns
+// and brightness are in copter_core::lights, what each output does is kept in the
+// settings, and the lights as a whole are switched with the pilot profile's aux button
+// or over BLE.
+//
+// All three PWM peripherals are taken, so the dimming is done with a timer instead: it
+// counts the period, and over PPI the start of it sets each pin and its own compare
+// clears it again, through GPIOTE. Nothing for the CPU to do but to move the compares.
+
+use defmt::{info, unwrap};
+use embassy_futures::select::{select, select4, Either4};
+use embassy_nrf::{
+    gpio::{Level, Output, OutputDrive},
+    gpiote::{self, OutputChannel, OutputChannelPolarity},
+    ppi::{AnyConfigurableChannel, Ppi},
+    timer, Peri,
+};
+use embassy_time::{Duration, Instant, Timer};
+
+use copter_core::{lights::Pattern, shaping};
+
+use crate::{
+    board::AuxResources,
+    state::SystemState,
+    taskstats::{self, Task},
+    types::JoystickData,
+};
+
+pub const AUX_OUTPUTS: usize = 2;
+
+// 1 MHz ticks, so the lights run at 1 kHz. No flicker on camera either
+const PERIOD_TICKS: u32 = 1000;
+// Often enough for the patterns to look smooth
+const STEP: Duration = Duration::from_millis(20);
+
+#[repr(C, packed)]
+#[derive(Clone, Copy, Default)]
+pub struct AuxOutput {
+    // copter_core::lights::Pattern
+    pub pattern: u8,
+    // %
+    pub brightness: u8,
+}
+
+impl AuxOutput {
+    fn pattern(&self) -> Pattern {
+        Pattern::from_u8(self.pattern).unwrap_or_default()
+    }
+}
+
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+pub struct AuxOutputs {
+    pub outputs: [AuxOutput; AUX_OUTPUTS],
+}
+
+impl AuxOutputs {
+    // Coming from the outside, unknown patterns are turned away rather than stored
+    pub fn valid(&self) -> bool {
+        let outputs = self.outputs;
+        outputs
+            .iter()
+            .all(|o| Pattern::from_u8(o.pattern).is_some())
+    }
+}
+
+// Navigation light and the anti-collision strobe, that's what most people fit
+impl Default for AuxOutputs {
+    fn default() -> Self {
+        Self {
+            outputs: [
+                AuxOutput {
+                    pattern: Pattern::Solid as u8,
+                    brightness: 100,
+                },
+                AuxOutput {
+                    pattern: Pattern::Strobe as u8,
+                    brightness: 100,
+                },
+            ],
+        }
+    }
+}
+
+struct Light<'a> {
+    pin: OutputChannel<'a>,
+    // Start of the period sets the pin, the compare clears it
+    start: Ppi<'a, AnyConfigurableChannel, 1, 1>,
+    _end: Ppi<'a, AnyConfigurableChannel, 1, 1>,
+    compare: usize,
+}
+
+impl<'a> Light<'a> {
+    fn new(
+        timer: &timer::Timer<'a>,
+        compare: usize,
+        pin: Output<'a>,
+        gpiote: Peri<'a, impl gpiote::Channel>,
+        start: Peri<'a, AnyConfigurableChannel>,
+        end: Peri<'a, AnyConfigurableChannel>,
+    ) -> Self {
+        let pin = OutputChannel::new(gpiote, pin, OutputChannelPolarity::Toggle);
+        let start = Ppi::new_one_to_one(start, timer.cc(0).event_compare(), pin.task_set());
+        let mut end = Ppi::new_one_to_one(end, timer.cc(compare).event_compare(), pin.task_clr());
+
+        end.enable();
+
+        Self {
+            pin,
+            start,
+            _end: end,
+            compare,
+        }
+    }
+
+    // %
+    fn show(&mut self, timer: &timer::Timer<'a>, level: u8) {
+        if level == 0 {
+            self.start.disable();
+            self.pin.clear();
+            return;
+        }
+
+        // Past the end of the period it's never cleared, that's full on
+        let ticks = match level {
+            100.. => PERIOD_TICKS + 1,
+            level => PERIOD_TICKS * level as u32 / 100,
+        };
+
+        timer.cc(self.compare).write(ticks);
+        self.start.enable();
+    }
+}
+
+#[embassy_executor::task]
+pub async fn run(state: &'static SystemState, r: AuxResources) {
+    taskstats::accounted(Task::Aux, task(state, r)).await
+}
+
+// Aux button toggles the lights, pilot profile may have none
+fn lights_toggled(state: &SystemState, previous: &JoystickData, current: &JoystickData) -> bool {
+    let button = state
+        .pilot_profile
+        .try_get()
+        .unwrap_or_default()
+        .aux_button();
+
+    !button.is_empty() && shaping::pressed(previous.buttons.bits, current.buttons.bits, button.bits)
+}
+
+async fn task(state: &'static SystemState, r: AuxResources) {
+    if !cfg!(feature = "aux-outputs") {
+        return;
+    }
+
+    let mut timer = timer::Timer::new(r.timer);
+    timer.set_frequency(timer::Frequency::F1MHz);
+    timer.cc(0).write(PERIOD_TICKS);
+    timer.cc(0).short_compare_clear();
+
+    let mut lights = [
+        Light::new(
+            &timer,
+            1,
+            Output::new(r.aux1, Level::Low, OutputDrive::Standard),
+            r.gpiote1,
+            r.ppi1_start.into(),
+            r.ppi1_end.into(),
+        ),
+        Light::new(
+            &timer,
+            2,
+            Output::new(r.aux2, Level::Low, OutputDrive::Standard),
+            r.gpiote2,
+            r.ppi2_start.into(),
+            r.ppi2_end.into(),
+        ),
+    ];
+
+    info!("aux outputs running...");
+
+    let mut outputs_receiver = unwrap!(state.aux_outputs.receiver());
+    let mut lights_receiver = unwrap!(state.aux_lights.receiver());
+    let mut controller_sample_receiver = unwrap!(state.controller_sample.receiver());
+    let mut shutdown_receiver = unwrap!(state.shutdown.receiver());
+    let lights_sender = state.aux_lights.sender();
+
+    let mut previous = JoystickData::default();
+
+    loop {
+        let outputs = outputs_receiver.try_get().unwrap_or_default().outputs;
+        let on = lights_receiver.try_get().unwrap_or_default();
+        let ms = Instant::now().as_millis() as u32;
+
+        let levels = outputs.map(|o| match on {
+            true => o.pattern().level(o.brightness, ms),
+            false => 0,
+        });
+
+        for (light, level) in lights.iter_mut().zip(levels) {
+            light.show(&timer, level);
+        }
+
+        // Counter keeps the HF clock up, no reason for that with everything dark
+        match levels.iter().any(|level| *level > 0) {
+            true => timer.start(),
+            false => timer.stop(),
+        }
+
+        // Steady ones don't need any looking after
+        let animated = on
+            && outputs
+                .iter()
+                .any(|o| !matches!(o.pattern(), Pattern::Off | Pattern::Solid));
+
+        let step = async {
+            match animated {
+                true => Timer::after(STEP).await,
+                false => core::future::pending().await,
+            }
+        };
+
+        let r = select4(
+            step,
+            controller_sample_receiver.changed(),
+            select(outputs_receiver.changed(), lights_receiver.changed()),
+            shutdown_receiver.changed(),
+        )
+        .await;
+
+        match r {
+            Either4::Second(sample) => {
+                if lights_toggled(state, &previous, &sample) {
+                    info!("aux lights {}", if on { "off" } else { "on" });
+                    lights_sender.send(!on);
+                }
+
+                previous = sample;
+            }
+
+            // Dark on the way out, the pins are let go of after that
+            Either4::Fourth(_) => {
+                for light in &mut lights {
+                    light.show(&timer, 0);
+                }
+
+                timer.stop();
+                return;
+            }
+
+            _ => {}
+        }
+    }
+}
@@ -0,0 +1,377 @@
+// Barometer
+//
+// Some builds carry a barometer on the gauge's I2C bus, which is what altitude hold
+// flies on (see copter_core::altitude). Bosch BMP280 (and BME280, which is the same
+// thing as far as pressure goes) and Goertek SPL06 are known, they sit at the same
+// addresses and are told apart by their ID registers. Nothing on the bus means there's
+// no altitude hold, the pilot still gets to ask for it but the stick stays in charge.
+//
+// Only sampled while armed, same as the IMU. Altitude is relative to the pressure at
+// arming, the vertical acceleration comes from the IMU when there's a fresh sample.
+
+use defmt::{info, unwrap, warn};
+use embassy_nrf::twim;
+use embassy_time::{Duration, Instant, Timer};
+use embedded_hal_async::i2c::I2c;
+
+use copter_core::altitude::{self, Estimator, Vertical, GRAVITY};
+
+use crate::{
+    state::SystemState,
+    taskstats::{self, Task},
+    types::AltitudeReport,
+    utils, SharedI2cBus,
+};
+
+// Both chips are set up to go faster than that, so every read is a new one
+const SAMPLE_RATE_HZ: u64 = 20;
+// Couple of samples, the hold lets go after that
+const STALE_AFTER: Duration = Duration::from_millis(150);
+// Every that many samples go to the clients, ~10 Hz
+const ALTITUDE_REPORT_DIVIDER: u32 = 2;
+// Ground pressure is averaged over that many samples after arming
+const REFERENCE_SAMPLES: usize = 8;
+
+#[derive(Clone, Copy)]
+pub struct AltitudeSample {
+    pub vertical: Vertical,
+    pub at: Instant,
+}
+
+impl AltitudeSample {
+    pub fn fresh(&self) -> bool {
+        self.at.elapsed() < STALE_AFTER
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, defmt::Format)]
+enum Chip {
+    Bmp280,
+    Spl06,
+}
+
+impl Chip {
+    // Depends on how SDO is strapped, same for both
+    const ADDRESSES: [u8; 2] = [0x76, 0x77];
+
+    fn id_register(self) -> u8 {
+        match self {
+            Self::Bmp280 => 0xd0,
+            Self::Spl06 => 0x0d,
+        }
+    }
+
+    fn knows(self, id: u8) -> bool {
+        match self {
+            // BMP280 samples and production parts, BME280
+            Self::Bmp280 => matches!(id, 0x56 | 0x57 | 0x58 | 0x60),
+            Self::Spl06 => id == 0x10,
+        }
+    }
+
+    // Register, value. Pressure oversampled 8x, temperature is only needed for the
+    // compensation, so it's sampled once
+    fn setup(self) -> &'static [(u8, u8)] {
+        match self {
+            Self::Bmp280 => &[
+                // No standby, IIR filter at 4. Only sticks while sleeping, so goes first
+                (0xf5, 0x08),
+                // Temperature 1x, pressure 8x, normal mode. ~45 Hz
+                (0xf4, 0x33),
+            ],
+            Self::Spl06 => &[
+                // Pressure 32 Hz, 8x
+                (0x06, 0x53),
+                // Temperature 32 Hz, 1x, from the MEMS sensor the coefficients are for
+                (0x07, 0xd0),
+                // No result shift, that's only for oversampling past 8x
+                (0x09, 0x00),
+                // Continuous pressure and temperature
+                (0x08, 0x07),
+            ],
+        }
+    }
+
+    fn coefficient_registers(self) -> (u8, usize) {
+        match self {
+            Self::Bmp280 => (0x88, 24),
+            Self::Spl06 => (0x10, 18),
+        }
+    }
+
+    // First of the 6 data bytes, pressure then temperature on both
+    fn data_register(self) -> u8 {
+        match self {
+            Self::Bmp280 => 0xf7,
+            Self::Spl06 => 0x00,
+        }
+    }
+}
+
+// Sign extends the lower bits
+fn signed(v: u32, bits: u32) -> i32 {
+    ((v << (32 - bits)) as i32) >> (32 - bits)
+}
+
+// Trimmed at the factory, see the datasheets for what they mean
+enum Coefficients {
+    Bmp280 {
+        t: [f32; 3],
+        p: [f32; 9],
+    },
+    // c0 and c1 are for the temperature, which is of no use here
+    Spl06 {
+        c00: f32,
+        c10: f32,
+        c01: f32,
+        c11: f32,
+        c20: f32,
+        c21: f32,
+        c30: f32,
+    },
+}
+
+impl Coefficients {
+    // Scale factors for the oversampling rates in Chip::setup()
+    const SPL06_KP: f32 = 7864320.0;
+    const SPL06_KT: f32 = 524288.0;
+
+    fn decode(chip: Chip, buf: &[u8]) -> Self {
+        match chip {
+            // T1 and P1 are unsigned, the rest are not. All little endian
+            Chip::Bmp280 => {
+                let u = |i: usize| u16::from_le_bytes([buf[i], buf[i + 1]]) as f32;
+                let s = |i: usize| i16::from_le_bytes([buf[i], buf[i + 1]]) as f32;
+
+                Self::Bmp280 {
+                    t: [u(0), s(2), s(4)],
+                    p: [u(6), s(8), s(10), s(12), s(14), s(16), s(18), s(20), s(22)],
+                }
+            }
+            // Packed into 20 bits, big endian
+            Chip::Spl06 => {
+                let b = |i: usize| buf[i] as u32;
+                let s = |i: usize| i16::from_be_bytes([buf[i], buf[i + 1]]) as f32;
+
+                Self::Spl06 {
+                    c00: signed(b(3) << 12 | b(4) << 4 | b(5) >> 4, 20) as f32,
+                    c10: signed((b(5) & 0xf) << 16 | b(6) << 8 | b(7), 20) as f32,
+                    c01: s(8),
+                    c11: s(10),
+                    c20: s(12),
+                    c21: s(14),
+                    c30: s(16),
+                }
+            }
+        }
+    }
+
+    // Raw readings to Pa
+    fn pressure(&self, buf: &[u8; 6]) -> f32 {
+        match self {
+            // 20 bits, left aligned. Datasheet's floating point version
+            Self::Bmp280 { t, p } => {
+                let raw = |i: usize| {
+                    (buf[i] as u32) << 12 | (buf[i + 1] as u32) << 4 | (buf[i + 2] as u32) >> 4
+                };
+                let (adc_p, adc_t) = (raw(0) as f32, raw(3) as f32);
+
+                let v1 = (adc_t / 16384.0 - t[0] / 1024.0) * t[1];
+                let v2 = adc_t / 131072.0 - t[0] / 8192.0;
+                let t_fine = v1 + v2 * v2 * t[2];
+
+                let v1 = t_fine / 2.0 - 64000.0;
+                let v2 = v1 * v1 * p[5] / 32768.0 + v1 * p[4] * 2.0;
+                let v2 = v2 / 4.0 + p[3] * 65536.0;
+                let v1 = (p[2] * v1 * v1 / 524288.0 + p[1] * v1) / 524288.0;
+                let v1 = (1.0 + v1 / 32768.0) * p[0];
+
+                if v1 == 0.0 {
+                    return 0.0;
+                }
+
+                let pressure = (1048576.0 - adc_p - v2 / 4096.0) * 6250.0 / v1;
+                let v1 = p[8] * pressure * pressure / 2147483648.0;
+                let v2 = pressure * p[7] / 32768.0;
+
+                pressure + (v1 + v2 + p[6]) / 16.0
+            }
+            // 24 bits, signed
+            Self::Spl06 {
+                c00,
+                c10,
+                c01,
+                c11,
+                c20,
+                c21,
+                c30,
+            } => {
+                let raw = |i: usize| {
+                    signed(
+                        (buf[i] as u32) << 16 | (buf[i + 1] as u32) << 8 | buf[i + 2] as u32,
+                        24,
+                    ) as f32
+                };
+
+                let p = raw(0) / Self::SPL06_KP;
+                let t = raw(3) / Self::SPL06_KT;
+
+                c00 + p * (c10 + p * (c20 + p * c30)) + t * c01 + t * p * (c11 + p * c21)
+            }
+        }
+    }
+}
+
+struct Baro<'a> {
+    i2c: &'a SharedI2cBus,
+    chip: Chip,
+    address: u8,
+}
+
+impl<'a> Baro<'a> {
+    async fn probe(i2c: &'a SharedI2cBus) -> Option<Self> {
+        for chip in [Chip::Bmp280, Chip::Spl06] {
+            for address in Chip::ADDRESSES {
+                let mut id = [0];
+                let r = i2c
+                    .lock()
+                    .await
+                    .write_read(address, &[chip.id_register()], &mut id)
+                    .await;
+
+                if r.is_ok() && chip.knows(id[0]) {
+                    return Some(Self { i2c, chip, address });
+                }
+            }
+        }
+
+        None
+    }
+
+    async fn read_registers(&self, register: u8, buf: &mut [u8]) -> Result<(), twim::Error> {
+        self.i2c
+            .lock()
+            .await
+            .write_read(self.address, &[register], buf)
+            .await
+    }
+
+    async fn configure(&self) -> Result<Coefficients, twim::Error> {
+        // SPL06 takes a moment to load them after power up
+        if self.chip == Chip::Spl06 {
+            for _ in 0..10 {
+                let mut status = [0];
+                self.read_registers(0x08, &mut status).await?;
+
+                if status[0] & 0x80 != 0 {
+                    break;
+                }
+
+                Timer::after_millis(10).await;
+            }
+        }
+
+        let (register, len) = self.chip.coefficient_registers();
+        let mut buf = [0; 24];
+        self.read_registers(register, &mut buf[..len]).await?;
+
+        for &(register, value) in self.chip.setup() {
+            self.i2c
+                .lock()
+                .await
+                .write(self.address, &[register, value])
+                .await?;
+        }
+
+        // First conversion
+        Timer::after_millis(50).await;
+        Ok(Coefficients::decode(self.chip, &buf[..len]))
+    }
+
+    async fn read(&self, coefficients: &Coefficients) -> Result<f32, twim::Error> {
+        let mut buf = [0; 6];
+
+        self.read_registers(self.chip.data_register(), &mut buf)
+            .await?;
+
+        Ok(coefficients.pressure(&buf))
+    }
+}
+
+#[embassy_executor::task]
+pub async fn run(state: &'static SystemState, i2c: &'static SharedI2cBus) {
+    taskstats::accounted(Task::Baro, task(state, i2c)).await
+}
+
+async fn task(state: &'static SystemState, i2c: &'static SharedI2cBus) {
+    let Some(baro) = Baro::probe(i2c).await else {
+        info!("no barometer on the bus, no altitude hold");
+        return;
+    };
+
+    info!("found {} at {=u8:#x}", baro.chip, baro.address);
+
+    let coefficients = match baro.configure().await {
+        Ok(c) => c,
+        Err(e) => {
+            warn!("unable to configure the barometer - {}", e);
+            return;
+        }
+    };
+
+    let altitude_sender = state.altitude.sender();
+    let report_sender = state.altitude_report.sender();
+    let mut armed_receiver = unwrap!(state.armed.receiver());
+
+    utils::sample_while_armed(&mut armed_receiver, SAMPLE_RATE_HZ, async |sampler| {
+        let mut estimator = Estimator::new();
+        let mut reference = 0.0;
+        let mut last: Option<Instant> = None;
+
+        // Still on the ground, that's where zero is
+        for _ in 0..REFERENCE_SAMPLES {
+            match sampler.next("barometer", baro.read(&coefficients)).await {
+                Some(pressure) => reference += pressure / REFERENCE_SAMPLES as f32,
+                // Starts over, for as long as we're armed
+                None => return,
+            }
+        }
+
+        info!("ground pressure is {} Pa", reference as u32);
+
+        loop {
+            // Control loop lets go of the hold once the samples are stale
+            let Some(pressure) = sampler.next("barometer", baro.read(&coefficients)).await else {
+                continue;
+            };
+
+            let now = Instant::now();
+            let dt = match last {
+                Some(at) => (now - at).as_micros() as f32 / 1_000_000.0,
+                None => 0.0,
+            };
+            last = Some(now);
+
+            let accel = match state.imu.try_get().filter(|s| s.fresh()) {
+                Some(sample) => sample.vertical_accel * GRAVITY,
+                None => 0.0,
+            };
+
+            let sample = AltitudeSample {
+                vertical: estimator.update(
+                    altitude::pressure_altitude(pressure, reference),
+                    accel,
+                    dt,
+                ),
+                at: now,
+            };
+
+            altitude_sender.send(sample);
+
+            if sampler.every(ALTITUDE_REPORT_DIVIDER) {
+                report_sender.send(sample.vertical.into());
+            }
+        }
+    })
+    .await;
+}
@@ -0,0 +1,210 @@
+// Black box
+//
+// Warnings and errors that matter are raised with incident!, which prints them as usual
+// and also appends them to a small ring in flash. Failures that happen away from an RTT
+// probe can then be read back over BLE. Only the incident code is kept, the message
+// itself lives in the defmt string table and has to be looked up by the code.
+
+use core::mem::size_of;
+
+use embedded_storage_async::nor_flash::{NorFlash, ReadNorFlash};
+use nrf_softdevice::{Flash, FlashError};
+
+use crate::eventlog::LoggedEvent;
+
+extern "C" {
+    // Provided by memory.x
+    static __blackbox_start: u32;
+    static __blackbox_end: u32;
+}
+
+// Goes into the event log as the value of Event::Warning / Event::Error
+#[repr(u16)]
+#[derive(Clone, Copy, PartialEq, defmt::Format)]
+pub enum Incident {
+    // Previous run has panicked, see postmortem.rs
+    Panic = 1,
+    GaugeCommunication = 2,
+    SettingsRead = 3,
+    SettingsStore = 4,
+    Advertise = 6,
+    NotificationDispatcher = 7,
+    ControllerGatt = 8,
+    ControllerSearch = 9,
+    // Previous run has hard faulted, see postmortem.rs
+    HardFault = 11,
+    // Previous run was reset by the watchdog, see watchdog.rs
+    Watchdog = 12,
+    // Firmware image on trial didn't make it, the previous one is back. See boot.rs
+    ImageRolledBack = 13,
+    // Whatever came right before kept failing, so we've rebooted. See faultmanager.rs
+    Unrecoverable = 14,
+    // Soft one, see assertion.rs. The report tells which
+    Assertion = 15,
+    // Rotor overcurrent or stall, we've disarmed. See copter_core::current
+    MotorCurrent = 16,
+}
+
+// Same as defmt::warn! / defmt::error!, except that the incident outlives the reboot.
+// One that keeps coming back is only printed once in a while, see faultmanager.rs
+macro_rules! incident {
+    (warn, $state:expr, $incident:expr, $($arg:tt)+) => {{
+        if $state.raise_incident($crate::eventlog::Event::Warning, $incident) {
+            defmt::warn!($($arg)+);
+        }
+    }};
+    (error, $state:expr, $incident:expr, $($arg:tt)+) => {{
+        if $state.raise_incident($crate::eventlog::Event::Error, $incident) {
+            defmt::error!($($arg)+);
+        }
+    }};
+}
+
+pub(crate) use incident;
+
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+pub struct BlackboxEntry {
+    // Grows by one with every entry, BLANK for erased flash
+    sequence: u32,
+    // Device time only resumes from the last settings store after a power cycle,
+    // so this is what keeps entries in order. See BootCounters
+    pub boot: u32,
+    pub event: LoggedEvent,
+}
+
+impl BlackboxEntry {
+    const EMPTY: Self = Self {
+        sequence: BLANK,
+        boot: 0,
+        event: LoggedEvent::EMPTY,
+    };
+}
+
+pub const BLACKBOX_LOG_LEN: usize = 16;
+
+// What clients get to see, the latest entries with the oldest first
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+pub struct BlackboxLog {
+    pub entries: [BlackboxEntry; BLACKBOX_LOG_LEN],
+}
+
+// Same as with the settings, a page is only erased once the ring wraps around to it
+const PAGE_SIZE: u32 = 4096;
+const ENTRY_SIZE: u32 = size_of::<BlackboxEntry>() as u32;
+
+const _: () = assert!(PAGE_SIZE % ENTRY_SIZE == 0);
+
+// Erased flash reads as 0xff
+const BLANK: u32 = u32::MAX;
+
+fn blackbox_region() -> (u32, u32) {
+    unsafe {
+        (
+            &__blackbox_start as *const u32 as u32,
+            &__blackbox_end as *const u32 as u32,
+        )
+    }
+}
+
+fn entry_count() -> u32 {
+    let (start, end) = blackbox_region();
+    (end - start) / ENTRY_SIZE
+}
+
+fn entry_address(index: u32) -> u32 {
+    let (start, _) = blackbox_region();
+    start + index * ENTRY_SIZE
+}
+
+// The flash itself belongs to the settings task, which is the one driving this
+pub struct Blackbox {
+    // Where the next entry goes, and its sequence number
+    next: u32,
+    sequence: u32,
+}
+
+impl Blackbox {
+    async fn read_entry(flash: &mut Flash, index: u32) -> Result<BlackboxEntry, FlashError> {
+        let mut entry = BlackboxEntry::EMPTY;
+
+        let bytes = unsafe {
+            core::slice::from_raw_parts_mut(
+                &mut entry as *mut BlackboxEntry as *mut u8,
+                size_of::<BlackboxEntry>(),
+            )
+        };
+
+        flash.read(entry_address(index), bytes).await?;
+        Ok(entry)
+    }
+
+    pub async fn load(flash: &mut Flash) -> Result<Self, FlashError> {
+        let mut blackbox = Self {
+            next: 0,
+            sequence: 0,
+        };
+
+        for index in 0..entry_count() {
+            let entry = Self::read_entry(flash, index).await?;
+
+            if entry.sequence != BLANK && entry.sequence >= blackbox.sequence {
+                blackbox.next = (index + 1) % entry_count();
+                blackbox.sequence = entry.sequence + 1;
+            }
+        }
+
+        Ok(blackbox)
+    }
+
+    pub async fn append(
+        &mut self,
+        flash: &mut Flash,
+        boot: u32,
+        event: LoggedEvent,
+    ) -> Result<(), FlashError> {
+        let index = self.next;
+        let address = entry_address(index);
+
+        if address % PAGE_SIZE == 0 {
+            flash.erase(address, address + PAGE_SIZE).await?;
+        }
+
+        let entry = BlackboxEntry {
+            sequence: self.sequence,
+            boot,
+            event,
+        };
+
+        let bytes = unsafe {
+            core::slice::from_raw_parts(
+                &entry as *const BlackboxEntry as *const u8,
+                size_of::<BlackboxEntry>(),
+            )
+        };
+
+        // Even if the write fails halfway, the slot is taken
+        self.next = (index + 1) % entry_count();
+        self.sequence += 1;
+
+        flash.write(address, bytes).await
+    }
+
+    // Entries are handed out as they are. Erased ones read back as all ones, clients
+    // skip them by the sequence
+    pub async fn recent(&self, flash: &mut Flash) -> Result<BlackboxLog, FlashError> {
+        let mut log = BlackboxLog {
+            entries: [BlackboxEntry::EMPTY; BLACKBOX_LOG_LEN],
+        };
+
+        let count = entry_count();
+
+        for (i, slot) in log.entries.iter_mut().enumerate() {
+            let index = (self.next + count - (BLACKBOX_LOG_LEN - i) as u32 % count) % count;
+            *slot = Self::read_entry(flash, index).await?;
+        }
+
+        Ok(log)
+    }
+}
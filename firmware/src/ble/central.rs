@@ -1,26 +1,79 @@
-use defmt::{debug, error, info, warn};
+use core::cell::Cell;
+
+use defmt::{error, info, unwrap, warn};
 use embassy_futures::select::{select, Either};
-use embassy_time::{Duration, Timer};
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_time::{Duration, Instant, Timer};
 use nrf_softdevice::{
     ble::{
         self, central, gatt_client, security::SecurityHandler, Address, AddressType, EncryptError,
-        EncryptionInfo,
+        EncryptionInfo, MasterId,
     },
     Softdevice,
 };
 use scopeguard::guard;
 
-use crate::state::SystemState;
+use crate::blackbox::{incident, Incident};
+use crate::eventlog::Event;
+use crate::logfilter::log;
+use crate::params::Param;
+use crate::state::{ActivityLevel, Request, StateMutex, SystemState};
+use crate::types::{ControllerAddress, ControllerBond, ControllerProfile};
+use crate::watchdog::Supervised;
 use crate::xbox::XboxHidServiceClient;
 use crate::xbox::{self, XboxHidServiceClientEvent};
 
 use super::errors::BleError;
+use super::privacy;
+
+pub struct Bonder {
+    state: &'static SystemState,
+    // Of the controller bonded just now, if it has handed one over
+    identity: Mutex<StateMutex, Cell<Option<ControllerAddress>>>,
+    // Keys we go with on the next connection, none while pairing
+    bond: Mutex<StateMutex, Cell<ControllerBond>>,
+}
+
+impl Bonder {
+    pub fn new(state: &'static SystemState) -> Self {
+        Bonder {
+            state,
+            identity: Mutex::new(Cell::new(None)),
+            bond: Mutex::new(Cell::new(ControllerBond::default())),
+        }
+    }
 
-pub struct Bonder {}
+    fn take_identity(&self) -> Option<ControllerAddress> {
+        self.identity.lock(|identity| identity.take())
+    }
+
+    // Keys of some other controller would only get the link dropped
+    fn use_bond(&self, controller: &ControllerAddress) {
+        let bond = self
+            .state
+            .controller_bond
+            .try_get()
+            .filter(|bond| bond.for_controller(controller))
+            .unwrap_or_default();
+
+        self.bond.lock(|b| b.set(bond));
+    }
 
-impl Default for Bonder {
-    fn default() -> Self {
-        Bonder {}
+    fn keys(&self) -> Option<(MasterId, EncryptionInfo)> {
+        let bond = self.bond.lock(|b| b.get());
+
+        (bond.valid != 0).then(|| {
+            let master_id = MasterId {
+                ediv: bond.ediv,
+                rand: bond.rand,
+            };
+            let key = EncryptionInfo {
+                ltk: bond.ltk,
+                flags: bond.flags,
+            };
+
+            (master_id, key)
+        })
     }
 }
 
@@ -31,21 +84,75 @@ impl SecurityHandler for Bonder {
 
     fn on_bonded(
         &self,
-        _conn: &ble::Connection,
-        _master_id: ble::MasterId,
-        _key: EncryptionInfo,
-        _peer_id: ble::IdentityKey,
+        conn: &ble::Connection,
+        master_id: MasterId,
+        key: EncryptionInfo,
+        peer_id: ble::IdentityKey,
     ) {
-        info!("on_bonded is called!")
+        info!("bonded with the controller");
+
+        // Controllers with a fixed address don't bother
+        let irk = peer_id.irk.as_raw().irk;
+        let controller = match irk != [0; 16] {
+            true => {
+                let identity = ControllerAddress {
+                    irk,
+                    ..peer_id.addr.into()
+                };
+
+                self.identity.lock(|i| i.set(Some(identity)));
+                identity
+            }
+            false => conn.peer_address().into(),
+        };
+
+        let bond = ControllerBond {
+            valid: 1,
+            kind: controller.kind,
+            bytes: controller.bytes,
+            ediv: master_id.ediv,
+            rand: master_id.rand,
+            flags: key.flags,
+            _reserved: [0; 1],
+            ltk: key.ltk,
+        };
+
+        // Good for the rest of this boot as well, settings may take a while
+        self.bond.lock(|b| b.set(bond));
+        self.state
+            .requests
+            .sender()
+            .send(Request::ControllerBondUpdate(bond));
+    }
+
+    // We're the one asking for encryption, with the keys the controller gave us
+    fn get_peripheral_key(&self, _conn: &ble::Connection) -> Option<(MasterId, EncryptionInfo)> {
+        self.keys()
+    }
+
+    // Same keys if it's the controller who asks, as long as it's the same master ID
+    fn get_key(&self, _conn: &ble::Connection, master_id: MasterId) -> Option<EncryptionInfo> {
+        self.keys()
+            .filter(|(id, _)| *id == master_id)
+            .map(|(_, key)| key)
     }
 }
 
-// Scan for Xbox controllers
-async fn scan(sd: &Softdevice) -> Option<Address> {
-    let config = central::ScanConfig {
-        interval: 3200, // *0.625 us
-        window: 160,    // *0.625us
-        ..central::ScanConfig::default()
+// Scan for Xbox controllers, anything goes
+async fn scan(sd: &Softdevice, activity: ActivityLevel) -> Option<Address> {
+    let config = match activity {
+        ActivityLevel::Full => central::ScanConfig {
+            interval: 3200, // *0.625 us
+            window: 160,    // *0.625us
+            ..central::ScanConfig::default()
+        },
+
+        // Radio is one of the major heat sources while charging
+        ActivityLevel::Reduced => central::ScanConfig {
+            interval: 6400, // *0.625 us
+            window: 80,     // *0.625us
+            ..central::ScanConfig::default()
+        },
     };
 
     let timeout = Duration::from_secs(10);
@@ -54,12 +161,16 @@ async fn scan(sd: &Softdevice) -> Option<Address> {
         let ret = central::scan(sd, &config, |params| unsafe {
             let payload = core::slice::from_raw_parts(params.data.p_data, params.data.len as usize);
 
-            if xbox::is_xbox_controller(payload) {
-                let addr = Address::new(AddressType::Public, params.peer_addr.addr);
-                info!("found controller {:?}", addr);
-                Some(addr)
-            } else {
-                None
+            let kind =
+                AddressType::try_from(params.peer_addr.addr_type()).unwrap_or(AddressType::Public);
+            let addr = Address::new(kind, params.peer_addr.addr);
+
+            match xbox::is_xbox_controller(payload) {
+                true => {
+                    info!("found controller {:?}", addr);
+                    Some(addr)
+                }
+                false => None,
             }
         })
         .await;
@@ -87,14 +198,40 @@ async fn scan(sd: &Softdevice) -> Option<Address> {
     }
 }
 
+impl From<Address> for ControllerAddress {
+    fn from(address: Address) -> Self {
+        Self {
+            valid: 1,
+            kind: address.address_type() as u8,
+            bytes: address.bytes(),
+            irk: [0; 16],
+        }
+    }
+}
+
+impl ControllerAddress {
+    fn address(&self) -> Option<Address> {
+        if self.valid == 0 {
+            return None;
+        }
+
+        let kind = AddressType::try_from(self.kind).ok()?;
+        Some(Address::new(kind, self.bytes))
+    }
+}
+
 async fn connect(
     sd: &Softdevice,
     addr: Address,
     bonder: &'static Bonder,
 ) -> Result<ble::Connection, BleError> {
+    // Controller may be switched off, so give up once in a while and look around
+    const CONNECT_TIMEOUT: u16 = 1000; // * 10ms
+
     let whitelist = &[&addr];
     let mut config = central::ConnectConfig::default();
     config.scan_config.whitelist = Some(whitelist);
+    config.scan_config.timeout = CONNECT_TIMEOUT;
 
     info!("connecting to device.. {}", addr);
 
@@ -124,11 +261,11 @@ async fn run_gatt(conn: ble::Connection, stats: &'static SystemState) -> Result<
     let controller_sample_sender = stats.controller_sample.sender();
     let client: XboxHidServiceClient = gatt_client::discover(&conn).await?;
 
-    debug!("services discovered!");
+    log!(Ble, debug, "services discovered!");
 
     client.hid_report_cccd_write(true).await?;
 
-    debug!("notifications enabled!");
+    log!(Ble, debug, "notifications enabled!");
 
     // XXX: would be cool to read and dynamically parse report map
     // let report_map = client.hid_report_map_read().await?;
@@ -137,6 +274,7 @@ async fn run_gatt(conn: ble::Connection, stats: &'static SystemState) -> Result<
     // All ready, we're connected
     gatt_client::run(&conn, &client, |event| match event {
         XboxHidServiceClientEvent::HidReportNotification(val) => {
+            log!(Hid, trace, "hid report: {:02x}", val);
             let jd = xbox::decode_hid_report(&val);
             controller_sample_sender.send(jd);
         }
@@ -151,17 +289,107 @@ pub async fn central_loop(
     state: &'static SystemState,
     bonder: &'static Bonder,
 ) {
+    const REDUCED_ACTIVITY_SCAN_PAUSE: Duration = Duration::from_secs(30);
+    // Pause, scan and connection attempt all together, with some to spare
+    const CHECK_IN_WITHIN: Duration = Duration::from_secs(90);
+
     let controller_connected_sender = state.controller_connected.sender();
+    let pairing_mode_sender = state.pairing_mode.sender();
+    let mut activity_receiver = unwrap!(state.activity.receiver());
+    let mut requests_receiver = unwrap!(state.requests.receiver());
+
+    // Once a controller is found, we stick to it, so a neighbour's pad can't take over.
+    // Pairing mode lets a new one in. It's remembered across reboots as well
+    let mut known_controller_receiver = unwrap!(state.known_controller.receiver());
+    let mut known_controller = known_controller_receiver.get().await;
+    let mut pairing_until: Option<Instant> = None;
+
+    let mut scan_connect = async || -> Result<(), BleError> {
+        state.supervisor.check_in(Supervised::Ble, CHECK_IN_WITHIN);
+
+        let activity = activity_receiver.try_get().unwrap_or(ActivityLevel::Full);
+        let pairing = known_controller.address().is_none()
+            || pairing_until.is_some_and(|t| t > Instant::now());
+
+        pairing_mode_sender.send(pairing);
+
+        if activity == ActivityLevel::Reduced {
+            Timer::after(REDUCED_ACTIVITY_SCAN_PAUSE).await;
+        }
+
+        // No need to look around for the one we know, connecting goes straight to it
+        let find = async || match pairing {
+            true => scan(sd, activity).await,
+            false => known_controller.address(),
+        };
+
+        let s = select(find(), requests_receiver.changed()).await;
+
+        let address = match s {
+            Either::First(address) => address,
+            Either::Second(Request::StartPairing) => {
+                info!("entering pairing mode");
+                let params = state.params.try_get().unwrap_or_default();
+                let timeout = params.get(Param::PairingTimeout) as u64;
+
+                pairing_until = Some(Instant::now() + Duration::from_secs(timeout));
+                None
+            }
+            Either::Second(_) => None,
+        };
+
+        if let Some(address) = address {
+            // Pairing starts over, a controller that has forgotten us needs it as well
+            let controller = match pairing {
+                true => ControllerAddress::default(),
+                false => known_controller,
+            };
+
+            privacy::set_controller_identity(&controller);
+            bonder.use_bond(&controller);
 
-    let scan_connect = async || -> Result<(), BleError> {
-        if let Some(address) = scan(sd).await {
             let conn = connect(sd, address, bonder).await?;
 
+            // Private addresses change, so the identity one is what's remembered
+            let controller = bonder.take_identity().unwrap_or(address.into());
+            let (irk, known_irk) = (controller.irk, known_controller.irk);
+
+            if !known_controller.same_controller(&controller) || irk != known_irk {
+                info!("remembering the new controller");
+                state
+                    .requests
+                    .sender()
+                    .send(Request::ControllerUpdate(controller));
+            }
+
+            known_controller = controller;
+            pairing_until = None;
+            pairing_mode_sender.send(false);
+
+            let profiles = state.pilot_profiles.try_get().unwrap_or_default();
+            let profile = ControllerProfile::lookup(&profiles, &controller);
+            state.pilot_profile.sender().send(profile);
+
             controller_connected_sender.send(true);
-            let _g = guard((), |_| controller_connected_sender.send(false));
+            state.log_event(Event::ControllerConnected, 0);
+
+            let _g = guard((), |_| {
+                controller_connected_sender.send(false);
+                state.log_event(Event::ControllerDisconnected, 0);
+            });
+
+            // Controller may stay silent for as long as the sticks are, the link
+            // supervision timeout takes care of the connection
+            state.supervisor.release(Supervised::Ble);
 
             match run_gatt(conn, state).await {
-                Err(e) => error!("run gatt exited with error - {}", e),
+                Err(e) => incident!(
+                    error,
+                    state,
+                    Incident::ControllerGatt,
+                    "run gatt exited with error - {}",
+                    e
+                ),
                 _ => {}
             }
         }
@@ -171,7 +399,13 @@ pub async fn central_loop(
 
     loop {
         if let Err(e) = scan_connect().await {
-            error!("search loop error - {}", e)
+            incident!(
+                error,
+                state,
+                Incident::ControllerSearch,
+                "search loop error - {}",
+                e
+            )
         }
     }
 }
@@ -1,26 +1,134 @@
+use core::fmt::Write;
+
+#[cfg(not(feature = "peripheral-only"))]
 use central::{central_loop, Bonder};
-use defmt::unwrap;
+use copter_core::console::{Args, Error};
 use embassy_futures::join::join3;
-use nrf_softdevice::Softdevice;
+use nrf_softdevice::{raw, SocEvent, Softdevice};
+#[cfg(not(feature = "central-only"))]
 use peripheral::{peripheral_loop, GattServer};
+#[cfg(not(feature = "peripheral-only"))]
 use static_cell::StaticCell;
 
-use crate::state::SystemState;
+#[cfg(not(feature = "central-only"))]
+use crate::assertion::{soft_unwrap, Assertion};
+use crate::console::Command;
+use crate::state::{Request, SystemState};
+use crate::taskstats::{self, Task};
+use crate::types::ShutdownAcks;
 
+#[cfg(not(feature = "peripheral-only"))]
 mod central;
 mod errors;
+#[cfg(not(feature = "central-only"))]
 mod peripheral;
+mod privacy;
+
+// Bench builds may leave one of the roles out. Peripheral-only never looks for the
+// controller, central-only has no GATT server, and the softdevice sets aside RAM for
+// the roles that are left only
+#[cfg(all(feature = "peripheral-only", feature = "central-only"))]
+compile_error!("peripheral-only and central-only leave nothing to do, pick one");
+
+const CENTRAL: bool = cfg!(not(feature = "peripheral-only"));
+const PERIPHERAL: bool = cfg!(not(feature = "central-only"));
+
+pub const CONN_COUNT: u8 = CENTRAL as u8 + PERIPHERAL as u8;
+
+pub fn softdevice_roles() -> raw::ble_gap_cfg_role_count_t {
+    raw::ble_gap_cfg_role_count_t {
+        // Softdevice wants one even with nothing to advertise
+        adv_set_count: 1,
+        periph_role_count: PERIPHERAL as u8,
+        central_role_count: CENTRAL as u8,
+        central_sec_count: CENTRAL as u8,
+        _bitfield_1: raw::ble_gap_cfg_role_count_t::new_bitfield_1(0),
+    }
+}
+
+fn pair_command(state: &SystemState, args: Args, _: &mut dyn Write) -> Result<(), Error> {
+    args.finish()?;
+    state.requests.sender().send(Request::StartPairing);
+    Ok(())
+}
+
+fn controller_command(state: &SystemState, args: Args, out: &mut dyn Write) -> Result<(), Error> {
+    args.finish()?;
+
+    let connected = state.controller_connected.try_get().unwrap_or_default();
+
+    match state.known_controller.try_get().filter(|c| c.valid != 0) {
+        // Little endian, same as the softdevice keeps it
+        Some(controller) => {
+            for (i, byte) in controller.bytes.iter().rev().enumerate() {
+                let sep = if i == 0 { "" } else { ":" };
+                write!(out, "{}{:02x}", sep, byte)?;
+            }
+
+            let bonded = state
+                .controller_bond
+                .try_get()
+                .is_some_and(|bond| bond.for_controller(&controller));
+
+            writeln!(out, ", connected {}, bonded {}", connected, bonded)?;
+        }
+        None => writeln!(out, "none")?,
+    }
+
+    Ok(())
+}
+
+pub const COMMANDS: &[Command] = &[
+    Command {
+        name: "pair",
+        help: "- look for a new controller for a while",
+        run: pair_command,
+    },
+    Command {
+        name: "controller",
+        help: "- the one we pair with",
+        run: controller_command,
+    },
+];
 
 #[embassy_executor::task]
 pub async fn run(sd: &'static mut Softdevice, state: &'static SystemState) {
-    static BONDER: StaticCell<Bonder> = StaticCell::new();
-    let bonder = BONDER.init(Bonder::default());
-    let server = unwrap!(GattServer::new(sd));
+    taskstats::accounted(Task::Ble, task(sd, state)).await
+}
+
+async fn task(sd: &'static mut Softdevice, state: &'static SystemState) {
+    #[cfg(not(feature = "central-only"))]
+    let server = soft_unwrap!(state, Continue, Assertion::GattServer, GattServer::new(sd));
+
+    privacy::enable(sd, state).await;
+
+    let central = async {
+        #[cfg(not(feature = "peripheral-only"))]
+        {
+            static BONDER: StaticCell<Bonder> = StaticCell::new();
+            central_loop(sd, state, BONDER.init(Bonder::new(state))).await
+        }
+    };
+
+    let peripheral = async {
+        #[cfg(not(feature = "central-only"))]
+        if let Some(server) = &server {
+            return peripheral_loop(sd, state, server).await;
+        }
+
+        // Still flies, just nobody to talk to
+        state.ack_shutdown(ShutdownAcks::PEERS);
+        core::future::pending().await
+    };
 
     join3(
-        central_loop(sd, state, bonder),
-        peripheral_loop(sd, state, &server),
-        sd.run(),
+        central,
+        peripheral,
+        sd.run_with_callback(|e| {
+            if let SocEvent::PowerFailureWarning = e {
+                state.undervoltage.signal(());
+            }
+        }),
     )
     .await;
 }
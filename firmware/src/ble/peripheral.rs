@@ -1,14 +1,46 @@
-use defmt::{debug, error, unwrap, warn};
-use embassy_futures::select::{select, select3, Either, Either3};
+use core::fmt::Write;
+
+use copter_core::dfu::{Chunk, Command, Status, CHUNK_WRITE_LEN, COMMAND_LEN, STATUS_LEN};
+use copter_core::policy::Thresholds;
+use defmt::{unwrap, warn};
+use embassy_futures::select::{select, select3, select4, Either, Either3, Either4};
+use embassy_sync::blocking_mutex::raw::NoopRawMutex;
+use embassy_sync::signal::Signal;
 use embassy_time::Timer;
 use nrf_softdevice::ble::advertisement_builder::{
     Flag, LegacyAdvertisementBuilder, LegacyAdvertisementPayload, ServiceList,
 };
-use nrf_softdevice::ble::{gatt_server, peripheral, Connection, Primitive};
-use nrf_softdevice::Softdevice;
+use nrf_softdevice::ble::gatt_server::NotifyValueError;
+use nrf_softdevice::ble::{
+    gatt_server, peripheral, security::SecurityHandler, Connection, Primitive,
+};
+use nrf_softdevice::{RawError, Softdevice};
 
+use crate::assertion::AssertionReport;
+use crate::aux::AuxOutputs;
+use crate::blackbox::{incident, BlackboxLog, Incident};
+use crate::charger::{ChargeMode, CHARGE_MODE_NONE};
+use crate::console::{self, Reply, LINE_LEN};
+use crate::dfu::{self, ChunkWrite, DfuRequest};
+use crate::eventlog::EventLog;
+use crate::factory;
+use crate::faultmanager::IncidentCounts;
+use crate::history::{HistoryPage, HISTORY_PAGES};
+use crate::indications::{FlightLight, IndicationStyle, IndicationTheme, ThemeEntry};
+use crate::logfilter::{self, log, LogLevels};
+use crate::params::{ParamTable, ParamUpdate, ParamValues, PARAM_TABLE};
+use crate::postmortem::{FaultReport, PanicReport};
+use crate::relay::{self, RelayAccess};
+use crate::selftest::SelfTestMode;
 use crate::state::{Request, SystemState};
-use crate::types::{ChargerState, PeriodicUpdate, PidParams};
+use crate::taskstats::TaskStats;
+use crate::types::{
+    AltitudeReport, AttitudeReport, BatteryProfile, BootCounters, BootInfo, ChargerState,
+    FlightPowerSummary, GaugeLearnedData, LearningReport, Odometer, PeerAttrs, PeriodicUpdate,
+    PidParams, PilotProfile, RailSagReport, RateLoopReport, SettingsGroups, ShutdownAcks,
+    ShutdownReason, SocThresholds,
+};
+use crate::xbox;
 
 use super::errors::BleError;
 
@@ -18,14 +50,91 @@ pub struct BatteryService {
     battery_level: u8,
 }
 
+// Standard device information one. The updater (tools/ota) reads the version back from
+// there to tell whether the new image is the one running
+#[nrf_softdevice::gatt_service(uuid = "180a")]
+pub struct DeviceInformationService {
+    #[characteristic(uuid = "2a26", read)]
+    firmware_revision: FirmwareRevision,
+
+    // As provisioned, see factory.rs
+    #[characteristic(uuid = "2a27", read)]
+    hardware_revision: HardwareRevision,
+}
+
+const FIRMWARE_REVISION_LEN: usize = 32;
+
+type FirmwareRevision = heapless::Vec<u8, FIRMWARE_REVISION_LEN>;
+
+// git describe of the build, cut short if it has to
+fn firmware_revision() -> FirmwareRevision {
+    let version = crate::VERSION.as_bytes();
+    let len = version.len().min(FIRMWARE_REVISION_LEN);
+
+    unwrap!(FirmwareRevision::from_slice(&version[..len]))
+}
+
+type HardwareRevision = heapless::Vec<u8, 8>;
+
+fn hardware_revision() -> HardwareRevision {
+    let mut revision = heapless::String::<8>::new();
+
+    // Fits either way
+    let _ = match factory::get().filter(|unit| unit.board_revision != 0) {
+        Some(unit) => write!(revision, "{}", unit.board_revision),
+        None => write!(revision, "unknown"),
+    };
+
+    revision.into_bytes()
+}
+
 unsafe impl Primitive for PeriodicUpdate {}
 unsafe impl Primitive for ChargerState {}
 unsafe impl Primitive for PidParams {}
+unsafe impl Primitive for BatteryProfile {}
+unsafe impl Primitive for SocThresholds {}
+unsafe impl Primitive for BootInfo {}
+unsafe impl Primitive for FlightPowerSummary {}
+unsafe impl Primitive for IndicationTheme {}
+unsafe impl Primitive for ThemeEntryUpdate {}
+unsafe impl Primitive for EventLog {}
+unsafe impl Primitive for PanicReport {}
+unsafe impl Primitive for FaultReport {}
+unsafe impl Primitive for Odometer {}
+unsafe impl Primitive for BootCounters {}
+unsafe impl Primitive for BlackboxLog {}
+unsafe impl Primitive for ParamUpdate {}
+unsafe impl Primitive for ParamValues {}
+unsafe impl Primitive for ParamTable {}
+unsafe impl Primitive for PilotProfile {}
+unsafe impl Primitive for HistoryPage {}
+unsafe impl Primitive for TaskStats {}
+unsafe impl Primitive for AssertionReport {}
+unsafe impl Primitive for LogLevels {}
+unsafe impl Primitive for IncidentCounts {}
+unsafe impl Primitive for AttitudeReport {}
+unsafe impl Primitive for AltitudeReport {}
+unsafe impl Primitive for RailSagReport {}
+unsafe impl Primitive for RateLoopReport {}
+unsafe impl Primitive for AuxOutputs {}
+unsafe impl Primitive for LearningReport {}
+unsafe impl Primitive for GaugeLearnedData {}
+
+// Theme is too large for a single write, so it's edited one entry at a time
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+pub struct ThemeEntryUpdate {
+    style: u8,
+    entry: ThemeEntry,
+}
 
 // Help clients find us by using that uuid
 const POWER_SERVICE_UUID_BYTES: [u8; 16] =
     0x38924a07_23d7_43fe_af5d_9c887a089cf1_u128.to_le_bytes();
 
+// Unless the board was provisioned with a name of its own. Tools look for this one
+const DEVICE_NAME: &str = "Syma S107";
+
 // bas is too limited to share everything we have
 #[nrf_softdevice::gatt_service(uuid = "38924a07-23d7-43fe-af5d-9c887a089cf1")]
 pub struct PowerService {
@@ -35,8 +144,35 @@ pub struct PowerService {
     #[characteristic(uuid = "38924a07-23d7-43fe-af5d-9c887a289cf1", notify)]
     periodic_update: PeriodicUpdate,
 
+    // Only while armed, at 50Hz, for tuning (see tools/tune)
     #[characteristic(uuid = "38924a07-23d7-43fe-af5d-9c887a389cf1", notify)]
-    gyro: i16,
+    rate_loop: RateLoopReport,
+
+    #[characteristic(uuid = "38924a07-23d7-43fe-af5d-9c887a489cf1", read, notify)]
+    learning_phase: u8,
+
+    #[characteristic(uuid = "38924a07-23d7-43fe-af5d-9c887a589cf1", read, notify)]
+    flight_summary: FlightPowerSummary,
+
+    // ShutdownReason, zero until the shutdown starts
+    #[characteristic(uuid = "38924a07-23d7-43fe-af5d-9c887a689cf1", read, notify)]
+    shutdown: u8,
+
+    // Only while armed, and only with an IMU fitted
+    #[characteristic(uuid = "38924a07-23d7-43fe-af5d-9c887a789cf1", read, notify)]
+    attitude: AttitudeReport,
+
+    // Only while armed, and only with a barometer fitted
+    #[characteristic(uuid = "38924a07-23d7-43fe-af5d-9c887a889cf1", read, notify)]
+    altitude: AltitudeReport,
+
+    // Only while armed, once per throttle punch
+    #[characteristic(uuid = "38924a07-23d7-43fe-af5d-9c887a989cf1", read, notify)]
+    rail_sag: RailSagReport,
+
+    // Only while the gauge learning cycle runs, see learning.rs
+    #[characteristic(uuid = "38924a07-23d7-43fe-af5d-9c887aa89cf1", read, notify)]
+    learning_report: LearningReport,
 }
 
 #[nrf_softdevice::gatt_service(uuid = "38924a07-23d7-43fe-af5d-9c887b089cf1")]
@@ -49,17 +185,326 @@ pub struct RequestsService {
 
     #[characteristic(uuid = "38924a07-23d7-43fe-af5d-9c887b389cf1", write)]
     fuelgauge_reset: bool,
+
+    #[characteristic(uuid = "38924a07-23d7-43fe-af5d-9c887b489cf1", write)]
+    start_learning: bool,
+
+    #[characteristic(uuid = "38924a07-23d7-43fe-af5d-9c887b589cf1", write)]
+    shutdown: bool,
+
+    #[characteristic(uuid = "38924a07-23d7-43fe-af5d-9c887b689cf1", write)]
+    start_pairing: bool,
+
+    // Airframe has to stay still for a couple of seconds, watch the LED
+    #[characteristic(uuid = "38924a07-23d7-43fe-af5d-9c887b789cf1", write)]
+    calibrate_gyro: bool,
+
+    // Turn the airframe around every way for 30s, watch the LED
+    #[characteristic(uuid = "38924a07-23d7-43fe-af5d-9c887b889cf1", write)]
+    calibrate_compass: bool,
+
+    // Xbox HID report from a PC relaying its gamepad, see relay.rs. Encrypted link
+    // gets the OS to pair, the switch on the board is what lets it fly
+    #[characteristic(
+        uuid = "38924a07-23d7-43fe-af5d-9c887b989cf1",
+        write,
+        write_without_response,
+        security = "justworks"
+    )]
+    direct_control: [u8; 16],
 }
 
+// Persistent settings, values are read back from flash at boot
+#[nrf_softdevice::gatt_service(uuid = "38924a07-23d7-43fe-af5d-9c887c089cf1")]
+pub struct ConfigService {
+    #[characteristic(uuid = "38924a07-23d7-43fe-af5d-9c887c189cf1", read, write)]
+    battery_profile: BatteryProfile,
+
+    // CHARGE_MODE_NONE where there's nothing to switch, see charger.rs
+    #[characteristic(uuid = "38924a07-23d7-43fe-af5d-9c887c289cf1", read, write)]
+    charge_mode: u8,
+
+    #[characteristic(uuid = "38924a07-23d7-43fe-af5d-9c887c389cf1", read, write)]
+    soc_thresholds: SocThresholds,
+
+    // Entries go in IndicationStyle order
+    #[characteristic(uuid = "38924a07-23d7-43fe-af5d-9c887c489cf1", read)]
+    indication_theme: IndicationTheme,
+
+    #[characteristic(uuid = "38924a07-23d7-43fe-af5d-9c887c589cf1", write)]
+    indication_theme_entry: ThemeEntryUpdate,
+
+    // FlightLight, how the LED behaves while armed
+    #[characteristic(uuid = "38924a07-23d7-43fe-af5d-9c887c689cf1", read, write)]
+    flight_light: u8,
+
+    // SelfTestMode, applied on the next power-on
+    #[characteristic(uuid = "38924a07-23d7-43fe-af5d-9c887c789cf1", read, write)]
+    self_test_mode: u8,
+
+    #[characteristic(uuid = "38924a07-23d7-43fe-af5d-9c887c889cf1", read)]
+    odometer: Odometer,
+
+    // Parameter registry, see params.rs. Values are written one at a time by id
+    #[characteristic(uuid = "38924a07-23d7-43fe-af5d-9c887c989cf1", write)]
+    param_update: ParamUpdate,
+
+    #[characteristic(uuid = "38924a07-23d7-43fe-af5d-9c887ca89cf1", read, notify)]
+    params: ParamValues,
+
+    // What each of the values is, never changes
+    #[characteristic(uuid = "38924a07-23d7-43fe-af5d-9c887cb89cf1", read)]
+    param_table: ParamTable,
+
+    // Of the controller we know, written ones are kept for it
+    #[characteristic(uuid = "38924a07-23d7-43fe-af5d-9c887cc89cf1", read, write)]
+    pilot_profile: PilotProfile,
+
+    // SettingsGroups to put back to defaults, the rest is left alone
+    #[characteristic(uuid = "38924a07-23d7-43fe-af5d-9c887cd89cf1", write)]
+    restore_defaults: u32,
+
+    // Per logfilter::Module, applied right away and forgotten on reboot
+    #[characteristic(uuid = "38924a07-23d7-43fe-af5d-9c887ce89cf1", read, write)]
+    log_levels: LogLevels,
+
+    // What the latest learning cycle came up with, the gauge is configured with it.
+    // Written ones are taken the next time the gauge is configured
+    #[characteristic(uuid = "38924a07-23d7-43fe-af5d-9c887cf89cf1", read, write)]
+    gauge_learned: GaugeLearnedData,
+}
+
+// Post-mortem data and other things that help to figure out what went wrong
+#[nrf_softdevice::gatt_service(uuid = "38924a07-23d7-43fe-af5d-9c887d089cf1")]
+pub struct DiagnosticsService {
+    #[characteristic(uuid = "38924a07-23d7-43fe-af5d-9c887d189cf1", read)]
+    boot_info: BootInfo,
+
+    // What has been going on since boot, see eventlog.rs
+    #[characteristic(uuid = "38924a07-23d7-43fe-af5d-9c887d289cf1", read)]
+    event_log: EventLog,
+
+    // Left by the previous run if it panicked, zero line otherwise
+    #[characteristic(uuid = "38924a07-23d7-43fe-af5d-9c887d389cf1", read)]
+    panic_report: PanicReport,
+
+    #[characteristic(uuid = "38924a07-23d7-43fe-af5d-9c887d489cf1", read)]
+    boot_counters: BootCounters,
+
+    // Latest warnings and errors, across reboots. See blackbox.rs
+    #[characteristic(uuid = "38924a07-23d7-43fe-af5d-9c887d589cf1", read)]
+    blackbox: BlackboxLog,
+
+    // Latest hard fault, zero pc if there was none so far
+    #[characteristic(uuid = "38924a07-23d7-43fe-af5d-9c887d689cf1", read)]
+    fault_report: FaultReport,
+
+    // Latest pre-crash history dump, one page at a time. See history.rs
+    #[characteristic(uuid = "38924a07-23d7-43fe-af5d-9c887d789cf1", read)]
+    history: HistoryPage,
+
+    // Picks the page to read above
+    #[characteristic(uuid = "38924a07-23d7-43fe-af5d-9c887d889cf1", write)]
+    history_page: u8,
+
+    // Refreshed every few seconds, see taskstats.rs
+    #[characteristic(uuid = "38924a07-23d7-43fe-af5d-9c887d989cf1", read)]
+    task_stats: TaskStats,
+
+    // Every incident raised since boot, reported or not. See faultmanager.rs
+    #[characteristic(uuid = "38924a07-23d7-43fe-af5d-9c887da89cf1", read)]
+    incident_counts: IncidentCounts,
+
+    // Latest failed soft assertion, zero line if there was none. See assertion.rs
+    #[characteristic(uuid = "38924a07-23d7-43fe-af5d-9c887db89cf1", read, notify)]
+    assertion: AssertionReport,
+
+    // Sent right back with the RSSI we see filled in, for range tests (see tools/range)
+    #[characteristic(
+        uuid = "38924a07-23d7-43fe-af5d-9c887dc89cf1",
+        write,
+        write_without_response,
+        notify
+    )]
+    echo: EchoPacket,
+}
+
+// Canopy lights on the aux outputs, see aux.rs. Builds without them take the writes all
+// the same, there's just nothing to see
+#[nrf_softdevice::gatt_service(uuid = "38924a07-23d7-43fe-af5d-9c887e089cf1")]
+pub struct LightsService {
+    // Pattern and brightness of each, kept in the settings
+    #[characteristic(uuid = "38924a07-23d7-43fe-af5d-9c887e189cf1", read, write)]
+    aux_outputs: AuxOutputs,
+
+    // All of them on or off, same as the aux button. Every power-on starts with them on
+    #[characteristic(uuid = "38924a07-23d7-43fe-af5d-9c887e289cf1", read, write, notify)]
+    aux_lights: bool,
+}
+
+// Firmware update, see dfu.rs. Wire format is in copter_core::dfu
+#[nrf_softdevice::gatt_service(uuid = "38924a07-23d7-43fe-af5d-9c887f089cf1")]
+pub struct DfuService {
+    // Anyone can write these, only images signed with the update key are ever activated
+    #[characteristic(uuid = "38924a07-23d7-43fe-af5d-9c887f189cf1", write)]
+    control: [u8; COMMAND_LEN],
+
+    // Offset first, then the image bytes. Fast path, nothing comes back for these
+    #[characteristic(
+        uuid = "38924a07-23d7-43fe-af5d-9c887f289cf1",
+        write,
+        write_without_response
+    )]
+    data: heapless::Vec<u8, CHUNK_WRITE_LEN>,
+
+    #[characteristic(uuid = "38924a07-23d7-43fe-af5d-9c887f389cf1", read, notify)]
+    status: [u8; STATUS_LEN],
+}
+
+// Nordic UART service, so that the console (see console.rs) works from any of the
+// terminal apps out there
+#[nrf_softdevice::gatt_service(uuid = "6e400001-b5a3-f393-e0a9-e50e24dcca9e")]
+pub struct NusService {
+    #[characteristic(
+        uuid = "6e400002-b5a3-f393-e0a9-e50e24dcca9e",
+        write,
+        write_without_response
+    )]
+    rx: NusLine,
+
+    #[characteristic(uuid = "6e400003-b5a3-f393-e0a9-e50e24dcca9e", notify)]
+    tx: NusChunk,
+}
+
+// Default ATT MTU, nothing bigger is ever negotiated
+const NUS_CHUNK_LEN: usize = 20;
+
+type NusLine = heapless::Vec<u8, LINE_LEN>;
+type NusChunk = heapless::Vec<u8, NUS_CHUNK_LEN>;
+
+// Sequence number from the host first, then the RSSI, the rest comes back as it was
+type EchoPacket = [u8; NUS_CHUNK_LEN];
+
+const ECHO_RSSI_AT: usize = 4;
+// Until the first reading is in, same as the softdevice says for "not available"
+const RSSI_UNKNOWN: i8 = 127;
+
 #[nrf_softdevice::gatt_server]
 pub struct GattServer {
     bas: BatteryService,
+    dis: DeviceInformationService,
     power: PowerService,
     requests: RequestsService,
+    config: ConfigService,
+    diagnostics: DiagnosticsService,
+    lights: LightsService,
+    dfu: DfuService,
+    nus: NusService,
+}
+
+// CCCDs live in the softdevice and are gone once the peer disconnects. Restoring them
+// lets a returning client get its notifications right away. Peers are told apart by
+// their address, since we don't bond with them
+fn restore_peer_attrs(conn: &Connection, state: &SystemState) -> PeerAttrs {
+    let address = conn.peer_address();
+    let mut attrs = PeerAttrs {
+        valid: 1,
+        kind: address.address_type() as u8,
+        address: address.bytes(),
+        ..PeerAttrs::EMPTY
+    };
+
+    let known = state
+        .peer_attrs
+        .try_get()
+        .and_then(|table| table.into_iter().find(|entry| entry.same_peer(&attrs)));
+
+    let restored = match known {
+        Some(known) => {
+            attrs = known;
+            gatt_server::set_sys_attrs(conn, Some(&known.data[..known.len as usize]))
+        }
+        None => gatt_server::set_sys_attrs(conn, None),
+    };
+
+    if let Err(e) = restored {
+        warn!("unable to restore peer attributes - {}", e);
+    }
+
+    attrs
+}
+
+// Called after every write, CCCD ones included. Settings only hear about it when
+// something has actually changed
+fn save_peer_attrs(conn: &Connection, state: &SystemState, attrs: &mut PeerAttrs) {
+    let mut data = [0; PeerAttrs::DATA_LEN];
+
+    let Ok(len) = gatt_server::get_sys_attrs(conn, &mut data) else {
+        return;
+    };
+
+    if len == attrs.len as usize && data[..len] == attrs.data[..len] {
+        return;
+    }
+
+    attrs.len = len as u8;
+    attrs.data = data;
+
+    state
+        .requests
+        .sender()
+        .send(Request::PeerAttrsUpdate(*attrs));
+}
+
+async fn send_reply(
+    server: &GattServer,
+    conn: &Connection,
+    reply: &Reply,
+) -> Result<(), NotifyValueError> {
+    for chunk in reply.as_bytes().chunks(NUS_CHUNK_LEN) {
+        let chunk = unwrap!(NusChunk::from_slice(chunk));
+
+        // Only a couple fit in the queue at a time
+        loop {
+            match server.nus.tx_notify(conn, &chunk) {
+                Err(NotifyValueError::Raw(RawError::Resources)) => Timer::after_millis(10).await,
+                r => break r?,
+            }
+        }
+    }
+
+    Ok(())
+}
+
+// Apps send a whole line per write, with or without the line ending
+async fn run_console(
+    server: &GattServer,
+    conn: &Connection,
+    state: &SystemState,
+    lines: &Signal<NoopRawMutex, NusLine>,
+) {
+    loop {
+        let line = lines.wait().await;
+
+        let Ok(line) = core::str::from_utf8(&line) else {
+            continue;
+        };
+
+        let reply = console::execute(state, line.trim());
+
+        if let Err(e) = send_reply(server, conn, &reply).await {
+            warn!("unable to send the console reply - {}", e);
+        }
+    }
 }
 
 async fn run_gatt(server: &GattServer, conn: &Connection, state: &SystemState) {
     let host_request_sender = state.requests.sender();
+    let mut peer_attrs = restore_peer_attrs(conn, state);
+    let console_lines = Signal::new();
+
+    // Softdevice keeps it up to date from then on, the echo picks it up
+    conn.start_rssi();
 
     let handle_bas = |e| match e {
         _ => {}
@@ -67,9 +512,39 @@ async fn run_gatt(server: &GattServer, conn: &Connection, state: &SystemState) {
 
     let handle_requests = |e| {
         let request = match e {
+            // Not a request anyone could ignore
+            RequestsServiceEvent::ShutdownWrite(true) => {
+                state.request_shutdown(ShutdownReason::Host);
+                return;
+            }
+
+            RequestsServiceEvent::CalibrateGyroWrite(true) => {
+                state.calibrate_gyro.signal(());
+                return;
+            }
+
+            RequestsServiceEvent::CalibrateCompassWrite(true) => {
+                state.calibrate_compass.signal(());
+                return;
+            }
+
+            // Only once let in with the switch, see relay.rs
+            RequestsServiceEvent::DirectControlWrite(report) => {
+                match state.relay_access.try_get() {
+                    Some(RelayAccess::Approved) => {
+                        state.relay_frame.signal(xbox::decode_hid_report(&report))
+                    }
+                    Some(RelayAccess::Pending) => {}
+                    _ => relay::request_access(state),
+                }
+                return;
+            }
+
             RequestsServiceEvent::RebootWrite(true) => Request::Reboot,
             RequestsServiceEvent::PidUpdateWrite(pid) => Request::PidUpdate(pid),
             RequestsServiceEvent::FuelgaugeResetWrite(true) => Request::FuelgaugeReset,
+            RequestsServiceEvent::StartLearningWrite(true) => Request::StartLearning,
+            RequestsServiceEvent::StartPairingWrite(true) => Request::StartPairing,
 
             _ => return,
         };
@@ -81,12 +556,150 @@ async fn run_gatt(server: &GattServer, conn: &Connection, state: &SystemState) {
         _ => {}
     };
 
-    gatt_server::run(conn, server, |e| match e {
-        GattServerEvent::Bas(e) => handle_bas(e),
-        GattServerEvent::Requests(e) => handle_requests(e),
-        GattServerEvent::Power(e) => handle_power(e),
-    })
-    .await;
+    let handle_diagnostics = |e| match e {
+        DiagnosticsServiceEvent::HistoryPageWrite(page) => {
+            if (page as usize) < HISTORY_PAGES {
+                host_request_sender.send(Request::HistoryPageRead(page));
+            }
+        }
+        // Straight from the handler, no queue between that could add to the latency.
+        // Full notification queue loses it, that's part of what's measured
+        DiagnosticsServiceEvent::EchoWrite(mut packet) => {
+            packet[ECHO_RSSI_AT] = conn.rssi().unwrap_or(RSSI_UNKNOWN) as u8;
+
+            if let Err(e) = server.diagnostics.echo_notify(conn, &packet) {
+                log!(Ble, debug, "echo dropped - {}", e);
+            }
+        }
+        DiagnosticsServiceEvent::AssertionCccdWrite { .. }
+        | DiagnosticsServiceEvent::EchoCccdWrite { .. } => {}
+    };
+
+    let handle_config = |e| {
+        let request = match e {
+            ConfigServiceEvent::BatteryProfileWrite(profile) => {
+                if profile.valid() {
+                    Request::BatteryProfileUpdate(profile)
+                } else {
+                    // Goes back to what's actually in use
+                    let current = state.battery_profile.try_get().unwrap_or_default();
+                    if let Err(e) = server.config.battery_profile_set(&current) {
+                        warn!("unable to reset the battery profile - {}", e);
+                    }
+                    return;
+                }
+            }
+            // Nothing to switch, so it goes back to saying so
+            ConfigServiceEvent::ChargeModeWrite(_) if !cfg!(feature = "charger-control") => {
+                if let Err(e) = server.config.charge_mode_set(&CHARGE_MODE_NONE) {
+                    warn!("unable to reset the charge mode - {}", e);
+                }
+                return;
+            }
+            ConfigServiceEvent::ChargeModeWrite(mode) => match ChargeMode::from_u8(mode) {
+                Some(mode) => Request::ChargeModeUpdate(mode),
+                None => return,
+            },
+            ConfigServiceEvent::SocThresholdsWrite(thresholds) => {
+                if Thresholds::from(thresholds).valid() {
+                    Request::SocThresholdsUpdate(thresholds)
+                } else {
+                    // Goes back to what's actually in use
+                    let current = state.soc_thresholds.try_get().unwrap_or_default();
+                    if let Err(e) = server.config.soc_thresholds_set(&current) {
+                        warn!("unable to reset the SoC thresholds - {}", e);
+                    }
+                    return;
+                }
+            }
+            ConfigServiceEvent::IndicationThemeEntryWrite(update) => {
+                match IndicationStyle::from_u8(update.style) {
+                    Some(style) => Request::IndicationThemeUpdate(style, update.entry),
+                    None => return,
+                }
+            }
+            ConfigServiceEvent::FlightLightWrite(light) => match FlightLight::from_u8(light) {
+                Some(light) => Request::FlightLightUpdate(light),
+                None => return,
+            },
+            ConfigServiceEvent::SelfTestModeWrite(mode) => match SelfTestMode::from_u8(mode) {
+                Some(mode) => Request::SelfTestModeUpdate(mode),
+                None => return,
+            },
+            // Checked against the registry by the settings
+            ConfigServiceEvent::ParamUpdateWrite(update) => Request::ParamUpdate(update),
+            ConfigServiceEvent::ParamsCccdWrite { .. } => return,
+            ConfigServiceEvent::PilotProfileWrite(profile) => Request::PilotProfileUpdate(profile),
+            // Nothing to store, so no need to bother the settings
+            ConfigServiceEvent::LogLevelsWrite(levels) => {
+                logfilter::set_levels(levels);
+                return;
+            }
+            // Zero QMAX would have the gauge report an empty cell forever
+            ConfigServiceEvent::GaugeLearnedWrite(learned) => match learned.qmax {
+                0 => return,
+                _ => Request::GaugeLearnedUpdate(learned),
+            },
+            ConfigServiceEvent::RestoreDefaultsWrite(groups) => {
+                match SettingsGroups::from_bits(groups).filter(|g| !g.is_empty()) {
+                    Some(groups) => Request::RestoreDefaults(groups),
+                    None => return,
+                }
+            }
+        };
+
+        host_request_sender.send(request);
+    };
+
+    let handle_lights = |e| match e {
+        LightsServiceEvent::AuxOutputsWrite(outputs) => {
+            if outputs.valid() {
+                host_request_sender.send(Request::AuxOutputsUpdate(outputs));
+            }
+        }
+        // Nothing to store, it's a switch
+        LightsServiceEvent::AuxLightsWrite(on) => state.aux_lights.sender().send(on),
+        LightsServiceEvent::AuxLightsCccdWrite { .. } => {}
+    };
+
+    // Queued for the settings task, garbage is dropped right here
+    let handle_dfu = |e| match e {
+        DfuServiceEvent::ControlWrite(b) => {
+            if let Some(command) = Command::parse(&b) {
+                dfu::request(state, DfuRequest::Command(command));
+            }
+        }
+        DfuServiceEvent::DataWrite(b) => {
+            if let Some(chunk) = Chunk::parse(&b) {
+                dfu::request(state, DfuRequest::Chunk(ChunkWrite::new(&chunk)));
+            }
+        }
+        DfuServiceEvent::StatusCccdWrite { .. } => {}
+    };
+
+    // Console is slow to reply at times, no reason to hold up the rest for that
+    let handle_nus = |e| match e {
+        NusServiceEvent::RxWrite(line) => console_lines.signal(line),
+        NusServiceEvent::TxCccdWrite { .. } => {}
+    };
+
+    let gatt = gatt_server::run(conn, server, |e| {
+        match e {
+            GattServerEvent::Bas(e) => handle_bas(e),
+            GattServerEvent::Dis(_) => {}
+            GattServerEvent::Requests(e) => handle_requests(e),
+            GattServerEvent::Power(e) => handle_power(e),
+            GattServerEvent::Config(e) => handle_config(e),
+            GattServerEvent::Diagnostics(e) => handle_diagnostics(e),
+            GattServerEvent::Lights(e) => handle_lights(e),
+            GattServerEvent::Dfu(e) => handle_dfu(e),
+            GattServerEvent::Nus(e) => handle_nus(e),
+        }
+
+        save_peer_attrs(conn, state, &mut peer_attrs);
+    });
+
+    select(gatt, run_console(server, conn, state, &console_lines)).await;
 }
 
 async fn run_notifications(
@@ -97,27 +710,350 @@ async fn run_notifications(
     let mut soc_receiver = unwrap!(state.soc.receiver());
     let mut charger_state_receiver = unwrap!(state.charger_state.receiver());
     let mut periodic_update_receiver = unwrap!(state.periodic_update.receiver());
+    let mut battery_profile_receiver = unwrap!(state.battery_profile.receiver());
+    let mut charge_mode_receiver = unwrap!(state.charge_mode.receiver());
+    let mut soc_thresholds_receiver = unwrap!(state.soc_thresholds.receiver());
+    let mut learning_phase_receiver = unwrap!(state.learning_phase.receiver());
+    let mut flight_summary_receiver = unwrap!(state.flight_summary.receiver());
+    let mut shutdown_receiver = unwrap!(state.shutdown.receiver());
+    let mut indication_theme_receiver = unwrap!(state.indication_theme.receiver());
+    let flight_light_receiver = unwrap!(state.flight_light.receiver());
+    let self_test_mode_receiver = unwrap!(state.self_test_mode.receiver());
+    let mut odometer_receiver = unwrap!(state.odometer.receiver());
+    let mut boot_counters_receiver = unwrap!(state.boot_counters.receiver());
+    let mut blackbox_receiver = unwrap!(state.blackbox.receiver());
+    let mut params_receiver = unwrap!(state.params.receiver());
+    let mut pilot_profile_receiver = unwrap!(state.pilot_profile.receiver());
+    let mut history_page_receiver = unwrap!(state.history_page.receiver());
+    let mut task_stats_receiver = unwrap!(state.task_stats.receiver());
+    let mut assertion_receiver = unwrap!(state.assertion.receiver());
+    let mut attitude_receiver = unwrap!(state.attitude.receiver());
+    let mut altitude_receiver = unwrap!(state.altitude_report.receiver());
+    let mut rail_sag_receiver = unwrap!(state.rail_sag.receiver());
+    let mut rate_loop_receiver = unwrap!(state.rate_loop.receiver());
+    let mut learning_report_receiver = unwrap!(state.learning_report.receiver());
+    let mut gauge_learned_receiver = unwrap!(state.gauge_learned.receiver());
+    let mut aux_outputs_receiver = unwrap!(state.aux_outputs.receiver());
+    let mut aux_lights_receiver = unwrap!(state.aux_lights.receiver());
+    let mut dfu_status_receiver = unwrap!(state.dfu_status.receiver());
+
+    server.dis.firmware_revision_set(&firmware_revision())?;
+    server.dis.hardware_revision_set(&hardware_revision())?;
+    server.config.param_table_set(&PARAM_TABLE)?;
+    server.config.log_levels_set(&logfilter::levels())?;
+
+    server.diagnostics.boot_info_set(&state.boot_info)?;
+    server.diagnostics.event_log_set(&state.event_log())?;
+    server
+        .diagnostics
+        .incident_counts_set(&state.fault_manager.counts())?;
+    server
+        .diagnostics
+        .panic_report_set(&state.panic_report.unwrap_or(PanicReport::EMPTY))?;
+    server.diagnostics.assertion_set(
+        &assertion_receiver
+            .try_get()
+            .unwrap_or(AssertionReport::EMPTY),
+    )?;
+
+    let snapshot = state.snapshot();
 
-    if let Some(soc) = soc_receiver.try_get() {
+    if let Some(soc) = snapshot.soc {
         server.bas.battery_level_set(&soc)?;
     }
 
-    if let Some(charger_state) = charger_state_receiver.try_get() {
+    if let Some(charger_state) = snapshot.charger_state {
         server.power.charger_state_set(&charger_state)?;
     }
 
+    if let Some(update) = snapshot.periodic_update {
+        server.power.periodic_update_set(&update)?;
+    }
+
+    if let Some(profile) = battery_profile_receiver.try_get() {
+        server.config.battery_profile_set(&profile)?;
+    }
+
+    let charge_mode = match cfg!(feature = "charger-control") {
+        true => charge_mode_receiver.try_get().map(|mode| mode as u8),
+        false => Some(CHARGE_MODE_NONE),
+    };
+
+    if let Some(mode) = charge_mode {
+        server.config.charge_mode_set(&mode)?;
+    }
+
+    if let Some(thresholds) = soc_thresholds_receiver.try_get() {
+        server.config.soc_thresholds_set(&thresholds)?;
+    }
+
+    if let Some(phase) = learning_phase_receiver.try_get() {
+        server.power.learning_phase_set(&(phase as u8))?;
+    }
+
+    if let Some(theme) = indication_theme_receiver.try_get() {
+        server.config.indication_theme_set(&theme)?;
+    }
+
+    if let Some(light) = flight_light_receiver.try_get() {
+        server.config.flight_light_set(&(light as u8))?;
+    }
+
+    if let Some(mode) = self_test_mode_receiver.try_get() {
+        server.config.self_test_mode_set(&(mode as u8))?;
+    }
+
+    if let Some(odometer) = odometer_receiver.try_get() {
+        server.config.odometer_set(&odometer)?;
+    }
+
+    if let Some(counters) = boot_counters_receiver.try_get() {
+        server.diagnostics.boot_counters_set(&counters)?;
+    }
+
+    if let Some(report) = state.last_fault.try_get() {
+        server.diagnostics.fault_report_set(&report)?;
+    }
+
+    if let Some(log) = blackbox_receiver.try_get() {
+        server.diagnostics.blackbox_set(&log)?;
+    }
+
+    if let Some(page) = history_page_receiver.try_get() {
+        server.diagnostics.history_set(&page)?;
+    }
+
+    if let Some(params) = params_receiver.try_get() {
+        server.config.params_set(&params)?;
+    }
+
+    if let Some(profile) = pilot_profile_receiver.try_get() {
+        server.config.pilot_profile_set(&profile)?;
+    }
+
+    if let Some(learned) = gauge_learned_receiver.try_get() {
+        server.config.gauge_learned_set(&learned)?;
+    }
+
+    if let Some(report) = learning_report_receiver.try_get() {
+        server.power.learning_report_set(&report)?;
+    }
+
+    if let Some(summary) = flight_summary_receiver.try_get() {
+        server.power.flight_summary_set(&summary)?;
+    }
+
+    if let Some(outputs) = aux_outputs_receiver.try_get() {
+        server.lights.aux_outputs_set(&outputs)?;
+    }
+
+    if let Some(on) = aux_lights_receiver.try_get() {
+        server.lights.aux_lights_set(&on)?;
+    }
+
+    let dfu_status = dfu_status_receiver.try_get().unwrap_or(Status::IDLE);
+    server.dfu.status_set(&dfu_status.to_bytes())?;
+
     loop {
-        let r = select3(
-            soc_receiver.changed(),
-            charger_state_receiver.changed(),
-            periodic_update_receiver.changed(),
+        let r = select4(
+            select4(
+                soc_receiver.changed(),
+                charger_state_receiver.changed(),
+                periodic_update_receiver.changed(),
+                learning_phase_receiver.changed(),
+            ),
+            select4(
+                flight_summary_receiver.changed(),
+                shutdown_receiver.changed(),
+                indication_theme_receiver.changed(),
+                state.events_changed.wait(),
+            ),
+            select4(
+                odometer_receiver.changed(),
+                boot_counters_receiver.changed(),
+                blackbox_receiver.changed(),
+                params_receiver.changed(),
+            ),
+            select4(
+                pilot_profile_receiver.changed(),
+                select3(
+                    history_page_receiver.changed(),
+                    aux_outputs_receiver.changed(),
+                    aux_lights_receiver.changed(),
+                ),
+                select4(
+                    task_stats_receiver.changed(),
+                    attitude_receiver.changed(),
+                    altitude_receiver.changed(),
+                    select3(
+                        rail_sag_receiver.changed(),
+                        rate_loop_receiver.changed(),
+                        learning_report_receiver.changed(),
+                    ),
+                ),
+                select3(
+                    assertion_receiver.changed(),
+                    gauge_learned_receiver.changed(),
+                    dfu_status_receiver.changed(),
+                ),
+            ),
         )
         .await;
 
         let err = match r {
-            Either3::First(x) => server.bas.battery_level_notify(conn, &x),
-            Either3::Second(x) => server.power.charger_state_notify(conn, &x),
-            Either3::Third(x) => server.power.periodic_update_notify(conn, &x),
+            Either4::First(Either4::First(x)) => server.bas.battery_level_notify(conn, &x),
+            Either4::First(Either4::Second(x)) => server.power.charger_state_notify(conn, &x),
+            Either4::First(Either4::Third(x)) => server.power.periodic_update_notify(conn, &x),
+            Either4::First(Either4::Fourth(x)) => {
+                server.power.learning_phase_notify(conn, &(x as u8))
+            }
+            Either4::Second(Either4::First(x)) => server.power.flight_summary_notify(conn, &x),
+
+            // Edited one entry at a time, so keep the whole thing up to date for reads
+            Either4::Second(Either4::Third(x)) => {
+                if let Err(e) = server.config.indication_theme_set(&x) {
+                    warn!("unable to update the indication theme - {}", e);
+                }
+
+                continue;
+            }
+
+            // Read-only, nobody is waiting for notifications
+            Either4::Second(Either4::Fourth(_)) => {
+                if let Err(e) = server.diagnostics.event_log_set(&state.event_log()) {
+                    warn!("unable to update the event log - {}", e);
+                }
+
+                let counts = state.fault_manager.counts();
+                if let Err(e) = server.diagnostics.incident_counts_set(&counts) {
+                    warn!("unable to update the incident counts - {}", e);
+                }
+
+                continue;
+            }
+
+            Either4::Third(Either4::First(x)) => {
+                if let Err(e) = server.config.odometer_set(&x) {
+                    warn!("unable to update the odometer - {}", e);
+                }
+
+                continue;
+            }
+
+            // Both are only sent once, right after the settings are loaded
+            Either4::Third(Either4::Second(x)) => {
+                if let Err(e) = server.diagnostics.boot_counters_set(&x) {
+                    warn!("unable to update the boot counters - {}", e);
+                }
+
+                let report = state.last_fault.try_get().unwrap_or(FaultReport::EMPTY);
+                if let Err(e) = server.diagnostics.fault_report_set(&report) {
+                    warn!("unable to update the fault report - {}", e);
+                }
+
+                continue;
+            }
+
+            Either4::Third(Either4::Third(x)) => {
+                if let Err(e) = server.diagnostics.blackbox_set(&x) {
+                    warn!("unable to update the black box - {}", e);
+                }
+
+                continue;
+            }
+
+            Either4::Third(Either4::Fourth(x)) => server.config.params_notify(conn, &x),
+
+            Either4::Fourth(Either4::First(x)) => {
+                if let Err(e) = server.config.pilot_profile_set(&x) {
+                    warn!("unable to update the pilot profile - {}", e);
+                }
+
+                continue;
+            }
+
+            // Client reads it back once it's there
+            Either4::Fourth(Either4::Second(Either3::First(x))) => {
+                if let Err(e) = server.diagnostics.history_set(&x) {
+                    warn!("unable to update the history - {}", e);
+                }
+
+                continue;
+            }
+
+            Either4::Fourth(Either4::Second(Either3::Second(x))) => {
+                if let Err(e) = server.lights.aux_outputs_set(&x) {
+                    warn!("unable to update the aux outputs - {}", e);
+                }
+
+                continue;
+            }
+
+            // Button flips it as well, so the app has to hear about it
+            Either4::Fourth(Either4::Second(Either3::Third(x))) => {
+                server.lights.aux_lights_notify(conn, &x)
+            }
+
+            Either4::Fourth(Either4::Third(Either4::First(x))) => {
+                if let Err(e) = server.diagnostics.task_stats_set(&x) {
+                    warn!("unable to update the task stats - {}", e);
+                }
+
+                continue;
+            }
+
+            Either4::Fourth(Either4::Third(Either4::Second(x))) => {
+                server.power.attitude_notify(conn, &x)
+            }
+
+            Either4::Fourth(Either4::Third(Either4::Third(x))) => {
+                server.power.altitude_notify(conn, &x)
+            }
+
+            Either4::Fourth(Either4::Third(Either4::Fourth(Either3::First(x)))) => {
+                server.power.rail_sag_notify(conn, &x)
+            }
+
+            Either4::Fourth(Either4::Third(Either4::Fourth(Either3::Second(x)))) => {
+                server.power.rate_loop_notify(conn, &x)
+            }
+
+            Either4::Fourth(Either4::Third(Either4::Fourth(Either3::Third(x)))) => {
+                server.power.learning_report_notify(conn, &x)
+            }
+
+            Either4::Fourth(Either4::Fourth(Either3::First(x))) => {
+                server.diagnostics.assertion_notify(conn, &x)
+            }
+
+            Either4::Fourth(Either4::Fourth(Either3::Second(x))) => {
+                if let Err(e) = server.config.gauge_learned_set(&x) {
+                    warn!("unable to update the learned gauge data - {}", e);
+                }
+
+                continue;
+            }
+
+            // Updater may just as well poll it, so a notification nobody asked for is fine
+            Either4::Fourth(Either4::Fourth(Either3::Third(x))) => {
+                let b = x.to_bytes();
+
+                if let Err(e) = server.dfu.status_set(&b) {
+                    warn!("unable to update the firmware update status - {}", e);
+                }
+
+                let _ = server.dfu.status_notify(conn, &b);
+                continue;
+            }
+
+            // Peer is about to lose us anyway, so that's the last thing we send
+            Either4::Second(Either4::Second(reason)) => {
+                if let Err(e) = server.power.shutdown_notify(conn, &(reason as u8)) {
+                    warn!("unable to notify about the shutdown - {}", e);
+                }
+
+                return Ok(());
+            }
         };
 
         if let Err(x) = err {
@@ -126,14 +1062,24 @@ async fn run_notifications(
     }
 }
 
+// Just Works and no bond, it's only there so that the relay can have its link
+// encrypted. Peers are still told apart by their address, see above
+struct Pairing;
+
+impl SecurityHandler for Pairing {}
+
+static PAIRING: Pairing = Pairing;
+
 pub async fn peripheral_loop(sd: &Softdevice, ps: &'static SystemState, server: &GattServer) {
     static ADV_DATA: LegacyAdvertisementPayload = LegacyAdvertisementBuilder::new()
         .flags(&[Flag::GeneralDiscovery, Flag::LE_Only])
         .services_128(ServiceList::Incomplete, &[POWER_SERVICE_UUID_BYTES])
         .build();
 
-    static SCAN_DATA: LegacyAdvertisementPayload = LegacyAdvertisementBuilder::new()
-        .full_name("Syma S107")
+    // Provisioned boards may go by a name of their own, so they can be told apart
+    let unit = factory::get();
+    let scan_data = LegacyAdvertisementBuilder::new()
+        .full_name(unit.as_ref().and_then(|u| u.name()).unwrap_or(DEVICE_NAME))
         .build();
 
     let config = peripheral::Config {
@@ -143,11 +1089,27 @@ pub async fn peripheral_loop(sd: &Softdevice, ps: &'static SystemState, server:
 
     let adv = peripheral::ConnectableAdvertisement::ScannableUndirected {
         adv_data: &ADV_DATA,
-        scan_data: &SCAN_DATA,
+        scan_data: &scan_data,
     };
 
+    let mut shutdown_receiver = unwrap!(ps.shutdown.receiver());
+
     loop {
-        match peripheral::advertise_connectable(sd, adv, &config).await {
+        // Whoever was connected has been notified by now, and nobody new is welcome
+        let conn = match select(
+            shutdown_receiver.changed(),
+            peripheral::advertise_pairable(sd, adv, &config, &PAIRING),
+        )
+        .await
+        {
+            Either::First(_) => {
+                ps.ack_shutdown(ShutdownAcks::PEERS);
+                return;
+            }
+            Either::Second(conn) => conn,
+        };
+
+        match conn {
             Ok(conn) => {
                 let r = select(
                     run_gatt(&server, &conn, ps),
@@ -155,19 +1117,33 @@ pub async fn peripheral_loop(sd: &Softdevice, ps: &'static SystemState, server:
                 )
                 .await;
 
+                relay::revoke_access(ps);
+
                 match r {
-                    Either::First(_) => debug!("gatt finished"),
+                    Either::First(_) => log!(Ble, debug, "gatt finished"),
                     Either::Second(r) => {
-                        debug!("notification dispatcher finished");
+                        log!(Ble, debug, "notification dispatcher finished");
                         if let Err(e) = r {
-                            error!("notification dispatcher error - {}", e);
+                            incident!(
+                                error,
+                                ps,
+                                Incident::NotificationDispatcher,
+                                "notification dispatcher error - {}",
+                                e
+                            );
                         }
                     }
                 }
             }
 
             Err(e) => {
-                error!("unable to advertise - {}", e);
+                incident!(
+                    error,
+                    ps,
+                    Incident::Advertise,
+                    "unable to advertise - {}",
+                    e
+                );
 
                 // might need some time to recover
                 Timer::after_secs(1).await;
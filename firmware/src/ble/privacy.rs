@@ -0,0 +1,122 @@
+// Privacy
+//
+// We go by a resolvable private address, made anew every now and then, so the heli
+// can't be followed around by a fixed one. Bonded peers get our identity key and see
+// through it. The key has to survive reboots for that, so it's made once and stored
+// with the settings. It works the other way around as well: a controller with a
+// private address hands its own key over on bonding, and with that the softdevice
+// finds it behind whatever address it's using at the moment.
+//
+// Identity address is the one from FICR, unless the board was provisioned with a static
+// one (see factory.rs) - that one stays the same across chips, for whoever wants it to.
+
+use core::ptr;
+
+use defmt::{info, unwrap, warn};
+use embassy_time::{Duration, Timer};
+use nrf_softdevice::{raw, Softdevice};
+
+use crate::factory;
+use crate::state::{Request, SystemState};
+use crate::types::{ControllerAddress, DeviceIrk};
+
+// Same as the softdevice default
+const ADDRESS_CYCLE: Duration = Duration::from_secs(15 * 60);
+
+// Entropy pool is empty right after the softdevice is enabled, it fills up in a moment
+const RNG_ATTEMPTS: usize = 10;
+
+async fn generate_irk(sd: &Softdevice) -> Option<DeviceIrk> {
+    let mut irk = DeviceIrk {
+        valid: 1,
+        ..DeviceIrk::default()
+    };
+
+    for _ in 0..RNG_ATTEMPTS {
+        match nrf_softdevice::random_bytes(sd, &mut irk.key) {
+            Ok(()) => return Some(irk),
+            Err(_) => Timer::after_millis(10).await,
+        }
+    }
+
+    None
+}
+
+// Softdevice doesn't take a new identity once privacy is on
+fn set_identity_address() {
+    let Some(address) = factory::get().and_then(|unit| unit.address) else {
+        return;
+    };
+
+    let addr = raw::ble_gap_addr_t {
+        _bitfield_1: raw::ble_gap_addr_t::new_bitfield_1(
+            0,
+            raw::BLE_GAP_ADDR_TYPE_RANDOM_STATIC as u8,
+        ),
+        addr: address,
+    };
+
+    match unsafe { raw::sd_ble_gap_addr_set(&addr) } {
+        0 => info!("using the provisioned address"),
+        e => warn!("unable to set the provisioned address - {}", e),
+    }
+}
+
+// Has to go before anything starts advertising
+pub async fn enable(sd: &Softdevice, state: &SystemState) {
+    set_identity_address();
+
+    let mut irk = unwrap!(state.device_irk.receiver()).get().await;
+
+    if irk.valid == 0 {
+        let Some(new) = generate_irk(sd).await else {
+            warn!("unable to generate the identity key, using the public address");
+            return;
+        };
+
+        info!("new identity key generated");
+
+        irk = new;
+        state.requests.sender().send(Request::DeviceIrkUpdate(irk));
+    }
+
+    let mut key = raw::ble_gap_irk_t { irk: irk.key };
+    let params = raw::ble_gap_privacy_params_t {
+        privacy_mode: raw::BLE_GAP_PRIVACY_MODE_DEVICE_PRIVACY as u8,
+        private_addr_type: raw::BLE_GAP_ADDR_TYPE_RANDOM_PRIVATE_RESOLVABLE as u8,
+        private_addr_cycle_s: ADDRESS_CYCLE.as_secs() as u16,
+        p_device_irk: &mut key,
+    };
+
+    match unsafe { raw::sd_ble_gap_privacy_set(&params) } {
+        0 => info!("privacy enabled"),
+        e => warn!("unable to enable privacy - {}", e),
+    }
+}
+
+// Only one controller is ever connected to, so the list is that one or nothing.
+// Not allowed while connecting, which is the only time the list is used anyway
+#[cfg_attr(feature = "peripheral-only", allow(dead_code))]
+pub fn set_controller_identity(controller: &ControllerAddress) {
+    let irk = controller.irk;
+
+    let ret = match irk == [0; 16] {
+        true => unsafe { raw::sd_ble_gap_device_identities_set(ptr::null(), ptr::null(), 0) },
+        false => {
+            let id = raw::ble_gap_id_key_t {
+                id_info: raw::ble_gap_irk_t { irk },
+                id_addr_info: raw::ble_gap_addr_t {
+                    _bitfield_1: raw::ble_gap_addr_t::new_bitfield_1(0, controller.kind),
+                    addr: controller.bytes,
+                },
+            };
+
+            let ids = [&id as *const raw::ble_gap_id_key_t];
+            unsafe { raw::sd_ble_gap_device_identities_set(ids.as_ptr(), ptr::null(), 1) }
+        }
+    };
+
+    if ret != 0 {
+        warn!("unable to set the controller identity - {}", ret);
+    }
+}
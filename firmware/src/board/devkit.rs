@@ -0,0 +1,146 @@
+// nRF52 DK (PCA10040), for working on the firmware without an airframe at hand. The
+// on-board LEDs and buttons stand in for ours. There's no gauge, charger or gyro, those
+// go on the Arduino headers if needed. Motor outputs can be watched with a scope
+
+use embassy_nrf::{peripherals, pwm, saadc, Peri};
+
+use super::LfClock;
+
+board_resources! {
+    led: LedResources {
+        // LED1
+        led: P0_17,
+        // NFC2 on the header, with the buzzer feature. The board feature turns the NFC
+        // pins into plain ones
+        buzzer: P0_10,
+        pwm: PWM1
+    },
+    rgb_led: RgbLedResources {
+        // LED2..4
+        red: P0_18,
+        green: P0_19,
+        blue: P0_20,
+        pwm: PWM2
+    },
+    switch: SwitchResources {
+        // Button 1, check SWITCH_PIN below if changing
+        switch: P0_13,
+    },
+    i2c: I2cResources {
+        // SDA / SCL on the Arduino header
+        i2c: TWISPI0,
+        sda: P0_26,
+        scl: P0_27,
+    },
+    power: PowerResources {
+        fuelgauge_int: P0_11,
+        charging_int: P0_12,
+        // Button 2, pressing it raises a charger fault
+        fault_int: P0_14,
+        // With the charger-control feature, resistors on a breadboard
+        charger_iset_sel: P0_22,
+        charger_vterm_sel: P0_23,
+        // With the charge-inhibit feature
+        charger_inhibit: P0_24,
+    },
+    controller: ControllerResources {
+        adc: SAADC,
+        pwm: PWM0,
+
+        rotor1: P0_06,
+        rotor2: P0_07,
+        tail_p: P0_08,
+        tail_n: P0_25,
+        gyro_power: P0_30,
+        // A1 / A2 on the Arduino header
+        gyro_input: P0_03,
+        gyro_vref: P0_04,
+        // A3 / A4, with the motor-current feature
+        rotor1_sense: P0_28,
+        rotor2_sense: P0_29,
+        // check the sampler types below if changing
+        sample_timer: TIMER1,
+        sample_ppi: PPI_CH0,
+        start_ppi: PPI_CH1,
+    },
+    flow: FlowResources {
+        // Free pins, buttons 3 and 4 among them, with the optical-flow feature
+        spi: TWISPI1,
+        sck: P0_15,
+        mosi: P0_16,
+        miso: P0_31,
+        cs: P0_05,
+    },
+    ir: IrResources {
+        // A0 on the Arduino header, with the ir-remote feature
+        ir: P0_02,
+        gpiote: GPIOTE_CH0,
+        timer: TIMER2,
+        ppi: PPI_CH2,
+    },
+    aux: AuxResources {
+        // NFC1 and the reset line on the header, with the aux-outputs feature. The board
+        // feature turns both into plain pins, so the reset button does nothing then
+        aux1: P0_09,
+        aux2: P0_21,
+        timer: TIMER3,
+        gpiote1: GPIOTE_CH1,
+        gpiote2: GPIOTE_CH2,
+        ppi1_start: PPI_CH3,
+        ppi1_end: PPI_CH4,
+        ppi2_start: PPI_CH5,
+        ppi2_end: PPI_CH6,
+    },
+    watchdog: WatchdogResources {
+        wdt: WDT,
+    },
+}
+
+pub const SWITCH_PIN: usize = 13;
+
+// 32.768 kHz crystal is fitted on the DK
+pub const LF_CLOCK: LfClock = LfClock::Xtal;
+
+// Nothing is hooked up to the NFC header, see the board-devkit feature
+pub const NFC_PINS_AS_GPIO: bool = true;
+// Out of pins otherwise, the debugger resets it just as well
+pub const RESET_PIN_AS_GPIO: bool = true;
+
+// Same as on the S107 board, so the motor outputs look the same
+pub const MOTOR_PWM_PRESCALER: pwm::Prescaler = pwm::Prescaler::Div16;
+pub const MOTOR_PWM_MAX_DUTY: u16 = 512;
+
+// Expects the same gyro as on the S107 board
+pub const GYRO_ADC_GAIN: saadc::Gain = saadc::Gain::GAIN1_2;
+pub const GYRO_ADC_GAIN_VALUE: f32 = 0.5;
+pub const GYRO_ADC_TIME: saadc::Time = saadc::Time::_40US;
+pub const GYRO_MV_PER_DPS: f32 = 0.67;
+
+// Nothing on the header most of the time, so no gyro check. Same gyro if there is one
+pub const GYRO_CHECK: bool = false;
+pub const GYRO_VREF_MV: (u16, u16) = (1200, 1500);
+pub const GYRO_REST_MAX_MV: u16 = 600;
+pub const GYRO_OFF_MAX_MV: u16 = 300;
+
+// Same shunts as on the S107 board, on a breadboard
+pub const MOTOR_SENSE_GAIN: saadc::Gain = saadc::Gain::GAIN1;
+pub const MOTOR_SENSE_GAIN_VALUE: f32 = 1.0;
+pub const MOTOR_SENSE_MV_PER_A: f32 = 100.0;
+
+// Breakout on the bus, lying flat next to the board with X away from the USB (see imu.rs)
+pub const IMU_AXES: [(usize, f32); 3] = [(0, 1.0), (1, 1.0), (2, 1.0)];
+pub const IMU_YAW_SIGN: f32 = 1.0;
+
+// Breakout next to the IMU one, same way around (see compass.rs)
+pub const MAG_AXES: [(usize, f32); 3] = [(0, 1.0), (1, 1.0), (2, 1.0)];
+
+// Held over the floor by hand, Y away from the USB (see flow.rs)
+pub const FLOW_AXES: [(usize, f32); 2] = [(1, 1.0), (0, -1.0)];
+
+// Same as on the S107 board
+pub type SampleTimer = peripherals::TIMER1;
+pub type SamplePpi = peripherals::PPI_CH0;
+pub type StartPpi = peripherals::PPI_CH1;
+
+// Nothing to go by, calibrate before use
+pub const DEFAULT_GYRO_OFFSET: i16 = 0;
@@ -0,0 +1,186 @@
+// Board variants
+//
+// Pin map and whatever else differs between hardware spins. Exactly one of the board-*
+// features picks the one we're building for, and everything else only ever goes
+// through here.
+
+// Same as assign_resources!, with split() checking every resource, see check() below.
+// Has to come before the boards
+macro_rules! board_resources {
+    ($($group:ident: $name:ident { $($field:ident: $resource:ident),* $(,)? }),* $(,)?) => {
+        assign_resources::assign_resources! {
+            $($group: $name { $($field: $resource),* }),*
+        }
+
+        pub fn split(p: embassy_nrf::Peripherals) -> AssignedResources {
+            let r = split_resources!(p);
+
+            $($(super::check(&r.$group.$field);)*)*
+            super::check_switch(&r.switch.switch);
+
+            r
+        }
+    };
+}
+
+#[cfg(feature = "board-s107")]
+mod s107;
+#[cfg(feature = "board-s107")]
+pub use s107::*;
+
+#[cfg(feature = "board-devkit")]
+mod devkit;
+#[cfg(feature = "board-devkit")]
+pub use devkit::*;
+
+#[cfg(not(any(feature = "board-s107", feature = "board-devkit")))]
+compile_error!("pick the board with one of the board-* features");
+
+#[cfg(all(feature = "board-s107", feature = "board-devkit"))]
+compile_error!("only one of the board-* features at a time");
+
+use embassy_nrf::{config::LfclkSource, peripherals, Peri};
+use nrf_softdevice::raw;
+
+// Where the 32 kHz clock comes from. Softdevice keeps it running through sleep and times
+// the radio events with it, so a worse one means wider receive windows and more current
+#[derive(Clone, Copy)]
+pub enum LfClock {
+    // Needs a crystal on XL1 / XL2
+    Xtal,
+    // Calibrated against the HFXO every now and then, way worse than a crystal
+    Rc,
+}
+
+impl LfClock {
+    pub fn source(self) -> LfclkSource {
+        match self {
+            Self::Xtal => LfclkSource::ExternalXtal,
+            Self::Rc => LfclkSource::InternalRC,
+        }
+    }
+
+    // Has to match source(), whoever starts the clock first
+    pub fn softdevice_config(self) -> raw::nrf_clock_lf_cfg_t {
+        match self {
+            Self::Xtal => raw::nrf_clock_lf_cfg_t {
+                source: raw::NRF_CLOCK_LF_SRC_XTAL as u8,
+                rc_ctiv: 0,
+                rc_temp_ctiv: 0,
+                accuracy: raw::NRF_CLOCK_LF_ACCURACY_20_PPM as u8,
+            },
+            // Every 4s, but only when the temperature has moved since the last time,
+            // and at least every 8s regardless. Nordic's recommended setting
+            Self::Rc => raw::nrf_clock_lf_cfg_t {
+                source: raw::NRF_CLOCK_LF_SRC_RC as u8,
+                rc_ctiv: 16,
+                rc_temp_ctiv: 2,
+                accuracy: raw::NRF_CLOCK_LF_ACCURACY_500_PPM as u8,
+            },
+        }
+    }
+}
+
+// Whatever goes into the resources has to say what it is. A peripheral that isn't
+// listed below won't build until it is - check the softdevice spec for it first
+pub trait Resource {
+    // Taking it away from the softdevice ends with a fault, if not worse
+    const SOFTDEVICE: bool = false;
+    // GPIO number, for the pins
+    const PIN: Option<usize> = None;
+}
+
+macro_rules! pins {
+    ($($pin:ident = $number:literal),*) => {
+        $(impl Resource for peripherals::$pin {
+            const PIN: Option<usize> = Some($number);
+        })*
+    };
+}
+
+macro_rules! peripherals {
+    ($softdevice:literal: $($p:ident),*) => {
+        $(impl Resource for peripherals::$p {
+            const SOFTDEVICE: bool = $softdevice;
+        })*
+    };
+}
+
+pins!(
+    P0_00 = 0,
+    P0_01 = 1,
+    P0_02 = 2,
+    P0_03 = 3,
+    P0_04 = 4,
+    P0_05 = 5,
+    P0_06 = 6,
+    P0_07 = 7,
+    P0_08 = 8,
+    P0_09 = 9,
+    P0_10 = 10,
+    P0_11 = 11,
+    P0_12 = 12,
+    P0_13 = 13,
+    P0_14 = 14,
+    P0_15 = 15,
+    P0_16 = 16,
+    P0_17 = 17,
+    P0_18 = 18,
+    P0_19 = 19,
+    P0_20 = 20,
+    P0_21 = 21,
+    P0_22 = 22,
+    P0_23 = 23,
+    P0_24 = 24,
+    P0_25 = 25,
+    P0_26 = 26,
+    P0_27 = 27,
+    P0_28 = 28,
+    P0_29 = 29,
+    P0_30 = 30,
+    P0_31 = 31
+);
+
+peripherals!(false:
+    SAADC, PWM0, PWM1, PWM2, TWISPI0, TWISPI1, UARTE0, WDT, QDEC, PDM, I2S, RTC2, TIMER1,
+    TIMER2, TIMER3, TIMER4, EGU0, EGU3, PPI_CH0, PPI_CH1, PPI_CH2, PPI_CH3, PPI_CH4,
+    PPI_CH5, PPI_CH6, PPI_CH7, PPI_CH8, PPI_CH9, PPI_CH10, PPI_CH11, PPI_CH12, PPI_CH13,
+    PPI_CH14, PPI_CH15, PPI_CH16, GPIOTE_CH0, GPIOTE_CH1, GPIOTE_CH2, GPIOTE_CH3, GPIOTE_CH4,
+    GPIOTE_CH5, GPIOTE_CH6, GPIOTE_CH7
+);
+
+// S132 spec, "Hardware peripherals"
+peripherals!(true:
+    TIMER0, RTC0, RNG, TEMP, EGU1, EGU2, EGU4, EGU5, PPI_CH17, PPI_CH18, PPI_CH19
+);
+
+// Called by split() for everything assigned. Fails the build rather than the boot
+pub fn check<T: Resource>(_: &Peri<'_, T>) {
+    const {
+        assert!(!T::SOFTDEVICE, "resource belongs to the softdevice");
+        assert!(
+            !(matches!(LF_CLOCK, LfClock::Xtal) && matches!(T::PIN, Some(0 | 1))),
+            "XL1 / XL2 are taken by the 32 kHz crystal"
+        );
+        // Only GPIOs with embassy-nrf/nfc-pins-as-gpio and reset-pin-as-gpio, which the
+        // board feature has to bring along
+        assert!(
+            NFC_PINS_AS_GPIO || !matches!(T::PIN, Some(9 | 10)),
+            "P0.09 / P0.10 are the NFC pins"
+        );
+        assert!(
+            RESET_PIN_AS_GPIO || !matches!(T::PIN, Some(21)),
+            "P0.21 is the reset pin"
+        );
+    }
+}
+
+// Wakeup from System OFF goes by the number, see shutdown.rs
+pub fn check_switch<T: Resource>(_: &Peri<'_, T>) {
+    const {
+        assert!(
+            matches!(T::PIN, Some(SWITCH_PIN)),
+            "SWITCH_PIN is not the switch"
+        );
+    }
+}
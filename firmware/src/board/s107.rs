@@ -0,0 +1,156 @@
+// The S107 mod board itself, see hardware/
+
+// ISET and VTERMSET only have their resistors here, see charger.rs
+#[cfg(feature = "charger-control")]
+compile_error!("the S107 board has no charger control, it needs a rework and the pins below");
+#[cfg(feature = "charge-inhibit")]
+compile_error!("the S107 board has no charge inhibit, P0.15 is an unconnected pad");
+
+use embassy_nrf::{peripherals, pwm, saadc, Peri};
+
+use super::LfClock;
+
+board_resources! {
+    led: LedResources {
+        led: P0_00,
+        // only driven with the buzzer feature, piezo on the spare pad
+        buzzer: P0_27,
+        pwm: PWM1
+    },
+    rgb_led: RgbLedResources {
+        // only driven with the rgb-led feature, otherwise left alone
+        red: P0_16,
+        green: P0_17,
+        blue: P0_18,
+        pwm: PWM2
+    },
+    switch: SwitchResources {
+        // check SWITCH_PIN below if changing, it's used for wakeup
+        switch: P0_05,
+    },
+    i2c: I2cResources {
+        // make sure to check interrupt priority in main.rs if changing
+        i2c: TWISPI0,
+        sda: P0_07,
+        scl: P0_08,
+    },
+    power: PowerResources {
+        fuelgauge_int: P0_06,
+        charging_int: P0_11,
+        fault_int: P0_12,
+    },
+    controller: ControllerResources {
+        // in current implementation, there's no need to share them, so just
+        // keep them here for simplicity
+        adc: SAADC,
+        pwm: PWM0,
+
+        rotor1: P0_01,
+        rotor2: P0_02,
+        tail_p: P0_03,
+        tail_n: P0_04,
+        gyro_power: P0_26,
+        gyro_input: P0_28,
+        gyro_vref: P0_29,
+        // only read with the motor-current feature, otherwise left alone. Nothing
+        // is connected to these pads, the shunts below need a rework
+        rotor1_sense: P0_30,
+        rotor2_sense: P0_31,
+        // check the sampler types below if changing
+        sample_timer: TIMER1,
+        sample_ppi: PPI_CH0,
+        start_ppi: PPI_CH1,
+    },
+    flow: FlowResources {
+        // only used with the optical-flow feature, on the expansion pads (see flow.rs)
+        spi: TWISPI1,
+        sck: P0_22,
+        mosi: P0_23,
+        miso: P0_24,
+        cs: P0_25,
+    },
+    ir: IrResources {
+        // only used with the ir-remote feature, TSOP-style receiver (see ir.rs)
+        ir: P0_19,
+        gpiote: GPIOTE_CH0,
+        timer: TIMER2,
+        ppi: PPI_CH2,
+    },
+    aux: AuxResources {
+        // only driven with the aux-outputs feature. Inputs of the aux driver, /AUX_IN2 sinks
+        // AUXA (TP8) and /AUX_IN1 sinks AUXB (TP9), see aux.rs
+        aux1: P0_13,
+        aux2: P0_14,
+        timer: TIMER3,
+        gpiote1: GPIOTE_CH1,
+        gpiote2: GPIOTE_CH2,
+        ppi1_start: PPI_CH3,
+        ppi1_end: PPI_CH4,
+        ppi2_start: PPI_CH5,
+        ppi2_end: PPI_CH6,
+    },
+    watchdog: WatchdogResources {
+        wdt: WDT,
+    },
+}
+
+// Wakes us up from System OFF, see shutdown.rs
+pub const SWITCH_PIN: usize = 5;
+
+// There's no 32 kHz crystal, XL1 drives the LED instead
+pub const LF_CLOCK: LfClock = LfClock::Rc;
+
+pub const NFC_PINS_AS_GPIO: bool = false;
+pub const RESET_PIN_AS_GPIO: bool = false;
+
+// 1 MHz PWM clock, so ~2 kHz on the motors
+pub const MOTOR_PWM_PRESCALER: pwm::Prescaler = pwm::Prescaler::Div16;
+pub const MOTOR_PWM_MAX_DUTY: u16 = 512;
+
+// Some considerations here:
+// - gyro vref is 1.35v, our ADC vref is 600 mV;
+// - 0.67 mV per deg/s;
+// - maximum angular velocity is 300 deg/s, which is ~200 mV;
+// - however, some natural DC offset seem to be taking place, so we need wider range
+pub const GYRO_ADC_GAIN: saadc::Gain = saadc::Gain::GAIN1_2;
+// Same as above, for the conversion
+pub const GYRO_ADC_GAIN_VALUE: f32 = 0.5;
+pub const GYRO_ADC_TIME: saadc::Time = saadc::Time::_40US;
+pub const GYRO_MV_PER_DPS: f32 = 0.67;
+
+// Power-on gyro check, see control.rs. Single-ended, in mV: the reference is 1.35V
+// nominal, and at rest the output sits within the DC offset above of it. Unpowered,
+// both drop to the ground
+pub const GYRO_CHECK: bool = true;
+pub const GYRO_VREF_MV: (u16, u16) = (1200, 1500);
+pub const GYRO_REST_MAX_MV: u16 = 600;
+pub const GYRO_OFF_MAX_MV: u16 = 300;
+
+// Rotor current sense, with the motor-current feature. The board doesn't have it, it's
+// for a rework: 0.1 ohm low side shunts added to the rotor FETs, RC filtered way below
+// the PWM frequency so a reading is the average and wired to P0.30/P0.31. 3A is 300 mV
+pub const MOTOR_SENSE_GAIN: saadc::Gain = saadc::Gain::GAIN1;
+pub const MOTOR_SENSE_GAIN_VALUE: f32 = 1.0;
+pub const MOTOR_SENSE_MV_PER_A: f32 = 100.0;
+
+// External IMU, where there's one (see imu.rs). Sensor axis and sign for each of forward,
+// left and up. Flat on the board, with X towards the nose
+pub const IMU_AXES: [(usize, f32); 3] = [(0, 1.0), (1, 1.0), (2, 1.0)];
+// Analog gyro sees a counterclockwise turn the same way
+pub const IMU_YAW_SIGN: f32 = 1.0;
+
+// Magnetometer, where there's one (see compass.rs). Same as above, flat on the board
+pub const MAG_AXES: [(usize, f32); 3] = [(0, 1.0), (1, 1.0), (2, 1.0)];
+
+// Flow sensor, where there's one (see flow.rs). Sensor axis and sign for forward and
+// left, lens down with Y towards the nose
+pub const FLOW_AXES: [(usize, f32); 2] = [(1, 1.0), (0, -1.0)];
+
+// Drive the SAADC at the control loop rate, see control.rs. TIMER0 and the upper PPI
+// channels belong to the softdevice
+pub type SampleTimer = peripherals::TIMER1;
+pub type SamplePpi = peripherals::PPI_CH0;
+pub type StartPpi = peripherals::PPI_CH1;
+
+// Raw gyro reading at rest on the prototype, until calibrated
+pub const DEFAULT_GYRO_OFFSET: i16 = 742;
@@ -0,0 +1,128 @@
+// Bootloader handshake, see bootloader/
+//
+// Without the "bootloader" feature there's nobody to talk to, and every image counts
+// as confirmed. With it, a freshly swapped in image has to stay up for a while before
+// it's marked as booted, otherwise the bootloader brings the previous one back. Panics,
+// hard faults and watchdog resets are all reboots, so they never get that far. Marking
+// needs the flash, so it's up to the settings task. Same goes for updates (see dfu.rs):
+// the image is written to the DFU partition and marked for the bootloader to swap in.
+
+use core::ops::Range;
+
+use copter_core::boot::ImageState;
+use embassy_time::Duration;
+use nrf_softdevice::Flash;
+
+use crate::types::BootInfo;
+
+pub const CONFIRM_AFTER: Duration = Duration::from_secs(60);
+
+pub fn image_state(boot_info: &BootInfo) -> ImageState {
+    match cfg!(feature = "bootloader") {
+        true => ImageState::from_gpregret(boot_info.gpregret),
+        false => ImageState::Confirmed,
+    }
+}
+
+#[cfg(feature = "bootloader")]
+type StatePartition<'a, 'f> = embassy_embedded_hal::flash::partition::Partition<
+    'a,
+    embassy_sync::blocking_mutex::raw::NoopRawMutex,
+    &'f mut Flash,
+>;
+
+// What the bootloader reads on every boot, to tell whether there's an image to swap in
+#[cfg(feature = "bootloader")]
+fn state_partition() -> Range<u32> {
+    use core::ptr;
+
+    extern "C" {
+        // Provided by memory-bootloader.x
+        static __bootloader_state_start: u32;
+        static __bootloader_state_end: u32;
+    }
+
+    unsafe {
+        ptr::addr_of!(__bootloader_state_start) as u32..ptr::addr_of!(__bootloader_state_end) as u32
+    }
+}
+
+// Confirming and marking an update only differ in what's written there
+#[cfg(feature = "bootloader")]
+async fn with_state<R>(
+    flash: &mut Flash,
+    f: impl AsyncFnOnce(&mut embassy_boot::FirmwareState<'_, StatePartition<'_, '_>>) -> R,
+) -> R {
+    use embassy_boot::{AlignedBuffer, FirmwareState};
+    use embassy_embedded_hal::flash::partition::Partition;
+    use embassy_sync::mutex::Mutex;
+    use embedded_storage_async::nor_flash::NorFlash;
+
+    let Range { start, end } = state_partition();
+
+    let flash = Mutex::new(flash);
+    let mut aligned = AlignedBuffer([0; Flash::WRITE_SIZE]);
+    let mut state = FirmwareState::new(Partition::new(&flash, start, end - start), &mut aligned.0);
+
+    f(&mut state).await
+}
+
+#[cfg(feature = "bootloader")]
+pub async fn confirm(flash: &mut Flash) {
+    use defmt::{info, warn};
+
+    match with_state(flash, async |state| state.mark_booted().await).await {
+        Ok(()) => info!("firmware image is confirmed"),
+        Err(e) => warn!("unable to confirm the firmware image - {}", e),
+    }
+}
+
+// Nothing to confirm, image_state() never says it's on trial
+#[cfg(not(feature = "bootloader"))]
+pub async fn confirm(_flash: &mut Flash) {}
+
+// Where new images go, None without the bootloader
+#[cfg(feature = "bootloader")]
+pub fn dfu_partition() -> Option<Range<u32>> {
+    use core::ptr;
+
+    extern "C" {
+        // Provided by memory-bootloader.x
+        static __bootloader_dfu_start: u32;
+        static __bootloader_dfu_end: u32;
+    }
+
+    let (start, end) = unsafe {
+        (
+            ptr::addr_of!(__bootloader_dfu_start) as u32,
+            ptr::addr_of!(__bootloader_dfu_end) as u32,
+        )
+    };
+
+    Some(start..end)
+}
+
+#[cfg(not(feature = "bootloader"))]
+pub fn dfu_partition() -> Option<Range<u32>> {
+    None
+}
+
+// Bootloader swaps the DFU partition in on the next boot, and the new image is on trial
+// from then on
+#[cfg(feature = "bootloader")]
+pub async fn mark_updated(flash: &mut Flash) -> bool {
+    use defmt::warn;
+
+    match with_state(flash, async |state| state.mark_updated().await).await {
+        Ok(()) => true,
+        Err(e) => {
+            warn!("unable to mark the firmware image for an update - {}", e);
+            false
+        }
+    }
+}
+
+#[cfg(not(feature = "bootloader"))]
+pub async fn mark_updated(_flash: &mut Flash) -> bool {
+    false
+}
@@ -0,0 +1,81 @@
+// nPM1100 charge control
+//
+// Charge current is set by the resistor on ISET and termination voltage by
+// VTERMSET. On the S107 board both only have their resistors, nothing the MCU can
+// switch. Boards that route them to spare GPIOs (the devkit, or an S107 board with a
+// rework) build with the charger-control feature, so we can switch between the
+// regular charging and a gentle one, which is nicer for cells that are going to sit
+// on a shelf for a while. Without it the charge mode reads back as CHARGE_MODE_NONE.
+
+use embassy_nrf::gpio::{Level, Output, OutputDrive, Pin};
+use embassy_nrf::Peri;
+
+#[repr(u8)]
+#[derive(Clone, Copy, PartialEq, Default, defmt::Format)]
+pub enum ChargeMode {
+    // ~100 mA, terminate at 4.2V
+    #[default]
+    Normal = 0,
+    // ~50 mA, terminate at 4.1V
+    Gentle = 1,
+}
+
+impl ChargeMode {
+    pub fn from_u8(v: u8) -> Option<Self> {
+        match v {
+            0 => Some(Self::Normal),
+            1 => Some(Self::Gentle),
+            _ => None,
+        }
+    }
+}
+
+// What boards without charger-control report over GATT, there's nothing to pick
+pub const CHARGE_MODE_NONE: u8 = 0xff;
+
+pub struct Charger<'a> {
+    // High connects the second ISET resistor in parallel, doubling the current
+    iset_sel: Output<'a>,
+    // High ties VTERMSET to VOUTB (4.2V), low to ground (4.1V)
+    vterm_sel: Output<'a>,
+}
+
+impl<'a> Charger<'a> {
+    pub fn new(iset_sel: Peri<'a, impl Pin>, vterm_sel: Peri<'a, impl Pin>) -> Self {
+        let mut charger = Self {
+            iset_sel: Output::new(iset_sel, Level::High, OutputDrive::Standard),
+            vterm_sel: Output::new(vterm_sel, Level::High, OutputDrive::Standard),
+        };
+
+        charger.set_mode(ChargeMode::default());
+        charger
+    }
+
+    pub fn set_mode(&mut self, mode: ChargeMode) {
+        let level = match mode {
+            ChargeMode::Normal => Level::High,
+            ChargeMode::Gentle => Level::Low,
+        };
+
+        self.iset_sel.set_level(level);
+        self.vterm_sel.set_level(level);
+    }
+}
+
+// With the charge-inhibit feature, high disconnects the ISET resistors altogether, so
+// charging stops
+pub struct ChargeInhibit<'a> {
+    inhibit: Output<'a>,
+}
+
+impl<'a> ChargeInhibit<'a> {
+    pub fn new(inhibit: Peri<'a, impl Pin>) -> Self {
+        Self {
+            inhibit: Output::new(inhibit, Level::Low, OutputDrive::Standard),
+        }
+    }
+
+    pub fn set_inhibited(&mut self, inhibited: bool) {
+        self.inhibit.set_level(Level::from(inhibited));
+    }
+}
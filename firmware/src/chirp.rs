@@ -0,0 +1,145 @@
+// Motor chirps
+//
+// Tail motor makes a decent beeper when driven with short, low duty PWM bursts at
+// audible frequencies. Handy when the LED can't be seen, e.g. to find the heli in
+// the grass. Chirps are played by the control task and only while disarmed.
+
+use core::future;
+
+use copter_core::policy::SocStage;
+use defmt::{info, unwrap};
+use embassy_futures::select::{select, select3, Either, Either3};
+use embassy_nrf::{
+    gpio::{Level, Output, OutputDrive},
+    pwm::{self, DutyCycle, SimplePwm},
+};
+use embassy_time::{Duration, Timer};
+
+use crate::{
+    state::{Mode, SystemState},
+    taskstats::{self, Task},
+    ControllerResources,
+};
+
+#[derive(Clone, Copy, defmt::Format)]
+pub enum Chirp {
+    PowerOn,
+    Paired,
+    LowBattery,
+    // Lost model beacon, repeats while the controller is gone after a flight
+    Beacon,
+}
+
+// Zero frequency is a rest
+struct Note {
+    freq: u32, // Hz
+    ms: u64,
+}
+
+const fn note(freq: u32, ms: u64) -> Note {
+    Note { freq, ms }
+}
+
+impl Chirp {
+    fn notes(self) -> &'static [Note] {
+        match self {
+            Self::PowerOn => &[note(2093, 80), note(2637, 80), note(3136, 120)],
+            Self::Paired => &[note(3136, 60), note(0, 60), note(3136, 60)],
+            Self::LowBattery => &[note(2637, 150), note(2093, 250)],
+            Self::Beacon => &[
+                note(3520, 40),
+                note(0, 40),
+                note(3520, 40),
+                note(0, 40),
+                note(3520, 40),
+            ],
+        }
+    }
+}
+
+// Enough to be heard, way too little to move the tail
+const CHIRP_DUTY_PERCENT: u32 = 5;
+
+pub async fn play(r: &mut ControllerResources, chirp: Chirp) {
+    info!("chirp: {}", chirp);
+
+    // Keep the bridge direction fixed, PWM does the rest
+    let _tail_n = Output::new(r.tail_n.reborrow(), Level::Low, OutputDrive::Standard);
+
+    for note in chirp.notes() {
+        if note.freq == 0 {
+            Timer::after_millis(note.ms).await;
+            continue;
+        }
+
+        // 1 MHz PWM clock
+        let mut config = pwm::SimpleConfig::default();
+        config.prescaler = pwm::Prescaler::Div16;
+        config.max_duty = (1_000_000 / note.freq) as u16;
+
+        let duty = (config.max_duty as u32 * CHIRP_DUTY_PERCENT / 100) as u16;
+        let mut pwm = SimplePwm::new_1ch(r.pwm.reborrow(), r.tail_p.reborrow(), &config);
+
+        pwm.set_duty(0, DutyCycle::inverted(duty));
+        Timer::after_millis(note.ms).await;
+        pwm.set_duty(0, DutyCycle::inverted(0));
+    }
+}
+
+// Decides when to chirp. Playback is up to the control task
+#[embassy_executor::task]
+pub async fn run(state: &'static SystemState) {
+    taskstats::accounted(Task::Chirp, task(state)).await
+}
+
+async fn task(state: &'static SystemState) {
+    const BEACON_INTERVAL: Duration = Duration::from_secs(10);
+
+    let mut controller_connected_receiver = unwrap!(state.controller_connected.receiver());
+    let mut soc_stage_receiver = unwrap!(state.soc_stage.receiver());
+    let mut mode_receiver = unwrap!(state.mode.receiver());
+
+    let mut connected = false;
+    let mut low_battery = false;
+    let mut flown = false;
+
+    state.chirp.signal(Chirp::PowerOn);
+
+    loop {
+        // Nobody is looking for a heli that hasn't been in the air
+        let beacon = async || match flown && !connected {
+            true => Timer::after(BEACON_INTERVAL).await,
+            false => future::pending().await,
+        };
+
+        let s = select3(
+            controller_connected_receiver.changed(),
+            soc_stage_receiver.changed(),
+            select(mode_receiver.changed(), beacon()),
+        )
+        .await;
+
+        match s {
+            Either3::First(c) => {
+                if c && !connected {
+                    state.chirp.signal(Chirp::Paired);
+                }
+
+                connected = c;
+            }
+
+            Either3::Second(stage) => {
+                let low = stage >= SocStage::Warn;
+
+                if low && !low_battery {
+                    state.chirp.signal(Chirp::LowBattery);
+                }
+
+                low_battery = low;
+            }
+
+            Either3::Third(Either::First(mode)) => flown |= mode == Mode::Flying,
+            Either3::Third(Either::Second(_)) => state.chirp.signal(Chirp::Beacon),
+        }
+    }
+}
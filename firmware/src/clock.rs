@@ -0,0 +1,106 @@
+// Device time
+//
+// Uptime starts over on every boot, and we reboot on purpose a lot. Device time keeps
+// going instead: the epoch (device time at boot) is kept in retained RAM across soft
+// resets, and in the settings across power cycles. Every timestamp handed out is
+// written back to the retained RAM first, so nothing after a reset can come out older.
+// Power cycles only resume from the last settings store, the boot number sorts
+// those out.
+
+use core::{mem::MaybeUninit, ptr};
+
+use embassy_time::{Instant, Timer};
+
+use crate::taskstats::{self, Task};
+
+const CLOCK_MAGIC: u32 = 0x5107_c10c;
+
+#[repr(C)]
+struct Retained {
+    magic: u32,
+    epoch: u64, // ms
+    // Latest device time handed out
+    latest: u64, // ms
+}
+
+#[link_section = ".uninit.CLOCK"]
+static mut RETAINED: MaybeUninit<Retained> = MaybeUninit::uninit();
+
+fn retained() -> *mut Retained {
+    unsafe { ptr::addr_of_mut!(RETAINED).cast() }
+}
+
+// Stored along with the settings
+#[repr(C, packed)]
+#[derive(Default, Copy, Clone)]
+pub struct DeviceTime {
+    pub seconds: u32,
+}
+
+// Has to be called once early at boot, before anyone asks for the time
+pub fn init() {
+    unsafe {
+        let r = retained();
+
+        // Power-on leaves garbage there
+        let epoch = match ptr::read_volatile(ptr::addr_of!((*r).magic)) {
+            CLOCK_MAGIC => ptr::read_volatile(ptr::addr_of!((*r).latest)),
+            _ => 0,
+        };
+
+        ptr::write_volatile(
+            r,
+            Retained {
+                magic: CLOCK_MAGIC,
+                epoch,
+                latest: epoch,
+            },
+        );
+    }
+}
+
+// Device time in ms. Only moves forward
+pub fn now() -> u64 {
+    unsafe {
+        let r = retained();
+        let now = ptr::read_volatile(ptr::addr_of!((*r).epoch)) + Instant::now().as_millis();
+
+        ptr::write_volatile(ptr::addr_of_mut!((*r).latest), now);
+        now
+    }
+}
+
+// Settings know better after a power cycle, otherwise we're already ahead
+pub fn resume(stored: DeviceTime) {
+    let stored = stored.seconds as u64 * 1000;
+    let now = now();
+
+    if stored > now {
+        unsafe {
+            let r = retained();
+            let epoch = ptr::read_volatile(ptr::addr_of!((*r).epoch));
+
+            ptr::write_volatile(ptr::addr_of_mut!((*r).epoch), epoch + stored - now);
+        }
+    }
+}
+
+pub fn device_time() -> DeviceTime {
+    DeviceTime {
+        seconds: (now() / 1000) as u32,
+    }
+}
+
+// Keeps the retained copy fresh while nothing is logged, an unexpected reset
+// loses a second at most
+#[embassy_executor::task]
+pub async fn run() {
+    taskstats::accounted(Task::Clock, task()).await
+}
+
+async fn task() {
+    loop {
+        now();
+        Timer::after_secs(1).await;
+    }
+}
@@ -0,0 +1,313 @@
+// Magnetometer
+//
+// Some builds carry a magnetometer on the gauge's I2C bus. The gyro heading drifts off
+// over a flight, the earth field doesn't, so while there's a fresh sample the attitude
+// filter pulls the heading towards it (see imu.rs and copter_core::attitude). QST
+// QMC5883L and ST LIS3MDL are known. Nothing on the bus, or no calibration, means the
+// heading is gyro only, same as before.
+//
+// Readings are of no use until calibrated (see copter_core::compass): disarmed, ask for
+// it from the console or the app, then turn the airframe through every orientation until
+// the LED says it's done. Calibration is kept in the settings, it only has to be redone
+// when something magnetic moves around on the airframe.
+
+use core::fmt::Write;
+
+use copter_core::compass::{Calibration, Calibrator};
+use copter_core::console::{Args, Error};
+use defmt::{info, unwrap, warn};
+use embassy_futures::select::{select, Either};
+use embassy_nrf::twim;
+use embassy_time::{Duration, Instant, Ticker, Timer};
+use embedded_hal_async::i2c::I2c;
+
+use crate::{
+    board,
+    console::Command,
+    indications::OneShot,
+    state::{Request, SystemState},
+    taskstats::{self, Task},
+    types::CompassCalibration,
+    utils, SharedI2cBus,
+};
+
+// Heading doesn't move that fast, and the bus is shared with everything else
+const SAMPLE_RATE_HZ: u64 = 50;
+// Couple of samples, the filter goes gyro only after that
+const STALE_AFTER: Duration = Duration::from_millis(100);
+// Long enough to turn it around through everything without hurrying
+const CALIBRATION_TIME: Duration = Duration::from_secs(30);
+
+#[derive(Clone, Copy)]
+pub struct CompassSample {
+    // Calibrated, so about unit length. Forward, left and up (see the board)
+    pub field: [f32; 3],
+    pub at: Instant,
+}
+
+impl CompassSample {
+    pub fn fresh(&self) -> bool {
+        self.at.elapsed() < STALE_AFTER
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, defmt::Format)]
+enum Chip {
+    Qmc5883l,
+    Lis3mdl,
+}
+
+impl Chip {
+    // QMC5883L is fixed, LIS3MDL depends on how SDO is strapped
+    fn addresses(self) -> &'static [u8] {
+        match self {
+            Self::Qmc5883l => &[0x0d],
+            Self::Lis3mdl => &[0x1c, 0x1e],
+        }
+    }
+
+    fn id_register(self) -> u8 {
+        match self {
+            Self::Qmc5883l => 0x0d,
+            Self::Lis3mdl => 0x0f,
+        }
+    }
+
+    fn knows(self, id: u8) -> bool {
+        match self {
+            Self::Qmc5883l => id == 0xff,
+            Self::Lis3mdl => id == 0x3d,
+        }
+    }
+
+    // Register, value. Scale doesn't matter, calibration takes care of it
+    fn setup(self) -> &'static [(u8, u8)] {
+        match self {
+            Self::Qmc5883l => &[
+                // Set / reset period, the datasheet says so
+                (0x0b, 0x01),
+                // Oversampling 512, ±8 G, 100 Hz, continuous
+                (0x09, 0x19),
+            ],
+            Self::Lis3mdl => &[
+                // X and Y ultra high performance, 80 Hz
+                (0x20, 0x7c),
+                // ±4 G
+                (0x21, 0x00),
+                // Continuous
+                (0x22, 0x00),
+                // Z ultra high performance, little endian
+                (0x23, 0x0c),
+                // Block data update
+                (0x24, 0x40),
+            ],
+        }
+    }
+
+    // First of the 6 data bytes, X, Y and Z, little endian on both. LIS3MDL only
+    // auto-increments with the top bit set
+    fn data_register(self) -> u8 {
+        match self {
+            Self::Qmc5883l => 0x00,
+            Self::Lis3mdl => 0x28 | 0x80,
+        }
+    }
+}
+
+struct Compass<'a> {
+    i2c: &'a SharedI2cBus,
+    chip: Chip,
+    address: u8,
+}
+
+impl<'a> Compass<'a> {
+    async fn probe(i2c: &'a SharedI2cBus) -> Option<Self> {
+        for chip in [Chip::Qmc5883l, Chip::Lis3mdl] {
+            for &address in chip.addresses() {
+                let mut id = [0];
+                let r = i2c
+                    .lock()
+                    .await
+                    .write_read(address, &[chip.id_register()], &mut id)
+                    .await;
+
+                if r.is_ok() && chip.knows(id[0]) {
+                    return Some(Self { i2c, chip, address });
+                }
+            }
+        }
+
+        None
+    }
+
+    async fn configure(&self) -> Result<(), twim::Error> {
+        for &(register, value) in self.chip.setup() {
+            self.i2c
+                .lock()
+                .await
+                .write(self.address, &[register, value])
+                .await?;
+        }
+
+        // First conversion
+        Timer::after_millis(20).await;
+        Ok(())
+    }
+
+    // Sensor axes, as they come
+    async fn read(&self) -> Result<[f32; 3], twim::Error> {
+        let mut buf = [0; 6];
+
+        self.i2c
+            .lock()
+            .await
+            .write_read(self.address, &[self.chip.data_register()], &mut buf)
+            .await?;
+
+        Ok([0, 2, 4].map(|i| i16::from_le_bytes([buf[i], buf[i + 1]]) as f32))
+    }
+}
+
+// Sensor axes to the airframe ones
+fn to_body(v: [f32; 3]) -> [f32; 3] {
+    board::MAG_AXES.map(|(axis, sign)| v[axis] * sign)
+}
+
+fn calibration(state: &SystemState) -> Option<Calibration> {
+    state.compass_calibration.try_get().and_then(|c| c.get())
+}
+
+async fn calibrate(compass: &Compass<'_>) -> Option<Calibration> {
+    let mut ticker = Ticker::every(Duration::from_hz(SAMPLE_RATE_HZ));
+    let mut calibrator = Calibrator::new();
+    let until = Instant::now() + CALIBRATION_TIME;
+
+    while Instant::now() < until {
+        ticker.next().await;
+
+        // Missing a couple of samples is no big deal, there are plenty
+        if let Ok(raw) = compass.read().await {
+            calibrator.add(raw);
+        }
+    }
+
+    calibrator.finish()
+}
+
+// Same as the gyro one, takes effect once disarmed
+fn calibrate_command(state: &SystemState, args: Args, _: &mut dyn Write) -> Result<(), Error> {
+    args.finish()?;
+
+    if state.armed.try_get().unwrap_or_default() {
+        return Err(Error::Refused);
+    }
+
+    state.calibrate_compass.signal(());
+    Ok(())
+}
+
+fn compass_command(state: &SystemState, args: Args, out: &mut dyn Write) -> Result<(), Error> {
+    args.finish()?;
+
+    match calibration(state) {
+        Some(c) => writeln!(
+            out,
+            "offset {:?}, radius {:?}",
+            c.offset.map(|v| v as i16),
+            c.radius.map(|v| v as i16)
+        )?,
+        None => writeln!(out, "not calibrated")?,
+    }
+
+    Ok(())
+}
+
+pub const COMMANDS: &[Command] = &[
+    Command {
+        name: "compass-calibrate",
+        help: "- calibrate the magnetometer, turn the airframe around for 30s",
+        run: calibrate_command,
+    },
+    Command {
+        name: "compass",
+        help: "- magnetometer calibration",
+        run: compass_command,
+    },
+];
+
+#[embassy_executor::task]
+pub async fn run(state: &'static SystemState, i2c: &'static SharedI2cBus) {
+    taskstats::accounted(Task::Compass, task(state, i2c)).await
+}
+
+async fn task(state: &'static SystemState, i2c: &'static SharedI2cBus) {
+    let Some(compass) = Compass::probe(i2c).await else {
+        info!("no magnetometer on the bus, heading is gyro only");
+        return;
+    };
+
+    info!("found {} at {=u8:#x}", compass.chip, compass.address);
+
+    if let Err(e) = compass.configure().await {
+        warn!("unable to configure the magnetometer - {}", e);
+        return;
+    }
+
+    let compass_sender = state.compass.sender();
+    let mut armed_receiver = unwrap!(state.armed.receiver());
+
+    loop {
+        let sampling =
+            utils::sample_while_armed(&mut armed_receiver, SAMPLE_RATE_HZ, async |sampler| {
+                let Some(calibration) = calibration(state) else {
+                    info!("magnetometer is not calibrated, heading is gyro only");
+                    return core::future::pending::<()>().await;
+                };
+
+                loop {
+                    // Filter goes gyro only once the samples are stale
+                    if let Some(raw) = sampler.next("magnetometer", compass.read()).await {
+                        compass_sender.send(CompassSample {
+                            field: to_body(calibration.apply(raw)),
+                            at: Instant::now(),
+                        });
+                    }
+                }
+            });
+
+        if let Either::First(_) = select(sampling, state.calibrate_compass.wait()).await {
+            continue;
+        }
+
+        // Asked for while armed, or armed since
+        if state.armed.try_get().unwrap_or_default() {
+            continue;
+        }
+
+        info!("calibrating magnetometer, turn the airframe around every way");
+        state.indicate_once(OneShot::Flashes(2));
+
+        match calibrate(&compass).await {
+            Some(c) => {
+                info!(
+                    "magnetometer calibrated, offset {}, radius {}",
+                    c.offset.map(|v| v as i16),
+                    c.radius.map(|v| v as i16)
+                );
+
+                state
+                    .requests
+                    .sender()
+                    .send(Request::CompassCalibrationUpdate(CompassCalibration::from(
+                        c,
+                    )));
+                state.indicate_once(OneShot::Confirm);
+            }
+
+            None => {
+                warn!("magnetometer calibration failed, it wasn't turned around enough");
+                state.indicate_once(OneShot::Reject);
+            }
+        }
+    }
+}
@@ -0,0 +1,98 @@
+// Debug console
+//
+// Same commands over RTT and over the Nordic UART service (see ble/peripheral.rs),
+// parsing is in copter_core::console. Commands are kept by the subsystems they poke
+// at, only the list of their tables is here.
+
+use core::fmt::Write;
+
+use copter_core::console::{self, Error, LineBuffer};
+use embassy_time::{Duration, Timer};
+use rtt_target::{rtt_init, DownChannel, UpChannel};
+
+use crate::state::SystemState;
+use crate::taskstats::{self, Task};
+use crate::{ble, compass, control, power};
+
+pub type Command = console::Command<SystemState>;
+pub type Reply = console::Reply<256>;
+
+pub const LINE_LEN: usize = 64;
+
+const TABLES: &[&[Command]] = &[
+    power::COMMANDS,
+    control::COMMANDS,
+    compass::COMMANDS,
+    ble::COMMANDS,
+];
+
+// Nothing wakes us up when the debugger writes something, and nobody types that fast
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+pub struct Rtt {
+    output: UpChannel,
+    input: DownChannel,
+}
+
+// Has to come before anything is logged, defmt goes through the first channel
+pub fn init() -> Rtt {
+    let channels = rtt_init! {
+        up: {
+            0: { size: 1024, name: "defmt" }
+            1: { size: 512, name: "Console" }
+        }
+        down: {
+            0: { size: LINE_LEN, name: "Console" }
+        }
+    };
+
+    rtt_target::set_defmt_channel(channels.up.0);
+
+    Rtt {
+        output: channels.up.1,
+        input: channels.down.0,
+    }
+}
+
+fn error_reply(e: Error) -> Reply {
+    let mut reply = Reply::new();
+    let _ = writeln!(reply, "error: {}", e.as_str());
+    reply
+}
+
+pub fn execute(state: &SystemState, line: &str) -> Reply {
+    let mut reply = Reply::new();
+
+    match console::dispatch(TABLES, state, line, &mut reply) {
+        // Whatever fit is better than nothing
+        Ok(()) | Err(Error::Output) => reply,
+        Err(e) => error_reply(e),
+    }
+}
+
+#[embassy_executor::task]
+pub async fn run(state: &'static SystemState, rtt: Rtt) {
+    taskstats::accounted(Task::Console, task(state, rtt)).await
+}
+
+async fn task(state: &'static SystemState, mut rtt: Rtt) {
+    let mut lines = LineBuffer::<LINE_LEN>::new();
+    let mut buf = [0; LINE_LEN];
+
+    loop {
+        Timer::after(POLL_INTERVAL).await;
+
+        let len = rtt.input.read(&mut buf);
+
+        for &byte in &buf[..len] {
+            let reply = match lines.push(byte) {
+                None => continue,
+                Some(Ok(line)) => execute(state, line),
+                Some(Err(e)) => error_reply(e),
+            };
+
+            // Dropped if nobody reads it, same as the logs
+            rtt.output.write(reply.as_bytes());
+        }
+    }
+}
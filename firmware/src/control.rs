@@ -1,105 +1,479 @@
-use defmt::{info, unwrap};
-use embassy_futures::select::{select3, Either3};
+use core::fmt::Write;
+
+use copter_core::console::{Args, Error};
+use copter_core::control::{ControlCore, Estimates, Gains, Sticks, MIN_CONTROL_THROTTLE};
+#[cfg(feature = "motor-current")]
+use copter_core::current::{CurrentMonitor, MotorFault};
+use copter_core::sag::{Sag, SagMeter};
+use copter_core::{mixer, policy::SocStage, shaping};
+use defmt::{info, unwrap, warn};
+use embassy_futures::select::{select, select4, Either, Either4};
 use embassy_nrf::{
     gpio::{self, Level, Output, OutputDrive},
     pwm::{self, DutyCycle, SimplePwm},
-    saadc::{self, Saadc},
+    saadc::{self, CallbackResult, Saadc},
+    timer, Peri,
 };
-use embassy_time::{Duration, Ticker, Timer};
-use pid::Pid;
+use embassy_sync::signal::Signal;
+use embassy_time::{with_timeout, Duration, Instant, Timer};
+use scopeguard::guard;
+
+#[cfg(feature = "motor-current")]
+use crate::blackbox::{incident, Incident};
 
 use crate::{
-    state::{Request, SystemState},
-    types::JoystickData,
-    utils, ControllerResources, Irqs,
+    assertion::{soft_assert, Assertion},
+    baro::AltitudeSample,
+    board::{self, DEFAULT_GYRO_OFFSET},
+    chirp,
+    console::Command,
+    eventlog::Event,
+    flow::FlowSample,
+    history::{self, HistorySample, HistoryTrigger},
+    imu::ImuSample,
+    indications::OneShot,
+    params::{Param, ParamValues},
+    selftest::SelfTestMode,
+    state::{Request, StateMutex, SystemState},
+    taskstats::{self, Task},
+    tof::RangeSample,
+    types::{
+        Faults, FlightLog, JoystickData, PidParams, PilotProfile, RateLoopReport, RateSensor,
+        SettingsGroups, RAIL_VOLTAGE_UNKNOWN,
+    },
+    utils,
+    watchdog::Supervised,
+    ControllerResources, Irqs,
 };
 
+// Gyro is on channel 0, the supply rail on channel 1, the rotor currents on 2 and 3
+#[cfg(not(feature = "motor-current"))]
+const ADC_CHANNELS: usize = 2;
+#[cfg(feature = "motor-current")]
+const ADC_CHANNELS: usize = 4;
+
+type Readings = [i16; ADC_CHANNELS];
+
+// Sampled a few times per control loop tick, so the rail sag is caught at its bottom
+// (see copter_core::sag). Everything else goes with the latest one. That's 1 kHz, and a
+// scan of all the channels takes ~300us even with the long gyro acquisition time
+const SAMPLES_PER_TICK: usize = 5;
+
+type Burst = [Readings; SAMPLES_PER_TICK];
+
+// IMU takes ~1s of sitting still, see imu.rs
+const IMU_CALIBRATION_TIMEOUT: Duration = Duration::from_secs(3);
+
+struct Sensors<'a> {
+    adc: Saadc<'a, ADC_CHANNELS>,
+    // While armed, samples are triggered by the timer rather than the control loop, so
+    // they come at a steady rate whatever else the executor is up to
+    timer: Peri<'a, board::SampleTimer>,
+    sample_ppi: Peri<'a, board::SamplePpi>,
+    start_ppi: Peri<'a, board::StartPpi>,
+}
+
+impl Sensors<'_> {
+    const TIMER_FREQUENCY: timer::Frequency = timer::Frequency::F1MHz;
+    const SAMPLE_HZ: u32 = Controller::CONTROL_LOOP_HZ as u32 * SAMPLES_PER_TICK as u32;
+    const TIMER_TICKS: u32 = 1_000_000 / Self::SAMPLE_HZ;
+
+    async fn sample(&mut self) -> Readings {
+        let mut buf = [0; ADC_CHANNELS];
+
+        self.adc.sample(&mut buf).await;
+        buf
+    }
+
+    // Never returns, dropping it stops the sampling. Buffers are swapped over PPI, so
+    // if the loop is late, it only misses the samples in between, the timing stays
+    async fn run(&mut self, latest: &Signal<StateMutex, Burst>) {
+        let mut bufs = [[[0; ADC_CHANNELS]; SAMPLES_PER_TICK]; 2];
+
+        self.adc
+            .run_task_sampler(
+                self.timer.reborrow(),
+                self.sample_ppi.reborrow(),
+                self.start_ppi.reborrow(),
+                Self::TIMER_FREQUENCY,
+                Self::TIMER_TICKS,
+                &mut bufs,
+                |buf| {
+                    let mut burst = [[0; ADC_CHANNELS]; SAMPLES_PER_TICK];
+
+                    burst.copy_from_slice(buf);
+                    latest.signal(burst);
+                    CallbackResult::Continue
+                },
+            )
+            .await;
+    }
+
+    // Averaged over ~1.5s. Wider spread than that means the airframe was moved
+    const GYRO_CALIBRATION_SAMPLES: i32 = 256;
+    const GYRO_CALIBRATION_SPREAD: i32 = 40;
+
+    async fn calibrate_gyro(&mut self) -> Option<i16> {
+        let (mut sum, mut min, mut max) = (0, i32::MAX, i32::MIN);
+
+        for _ in 0..Self::GYRO_CALIBRATION_SAMPLES {
+            let raw = self.sample().await[0] as i32;
+
+            sum += raw;
+            min = min.min(raw);
+            max = max.max(raw);
+
+            Timer::after_millis(5).await;
+        }
+
+        if max - min > Self::GYRO_CALIBRATION_SPREAD {
+            warn!("gyro readings spread over {}, was it moved?", max - min);
+            return None;
+        }
+
+        Some((-sum / Self::GYRO_CALIBRATION_SAMPLES) as i16)
+    }
+}
+
+// Reads the gyro pins on their own, in mV
+async fn gyro_levels(r: &mut ControllerResources) -> [u16; 2] {
+    let mut config = saadc::Config::default();
+    config.resolution = saadc::Resolution::_12BIT;
+    config.oversample = saadc::Oversample::OVER4X;
+
+    // Default 1/6 gain, so the full scale is 3.6V
+    let vref = saadc::ChannelConfig::single_ended(r.gyro_vref.reborrow());
+    let output = saadc::ChannelConfig::single_ended(r.gyro_input.reborrow());
+
+    let mut adc = saadc::Saadc::new(r.adc.reborrow(), Irqs, config, [vref, output]);
+    let mut buf = [0; 2];
+
+    adc.sample(&mut buf).await;
+    buf.map(|raw| (raw.max(0) as u32 * 3600 / 4096) as u16)
+}
+
+// Runs at every boot, self-test or not. Powered off, the reference has to drop, and
+// powered on, it has to come up where it should with the output sitting close to it.
+// Pins of a missing or dead gyro read whatever they float to, and that doesn't follow
+// the power. Flying on that would be worse than not flying at all
+async fn check_gyro(r: &mut ControllerResources) -> bool {
+    let mut power = Output::new(r.gyro_power.reborrow(), Level::Low, OutputDrive::Standard);
+
+    Timer::after_millis(50).await;
+    let [vref_off, _] = gyro_levels(r).await;
+
+    power.set_high();
+    Timer::after_millis(50).await;
+    let [vref, output] = gyro_levels(r).await;
+
+    power.set_low();
+
+    let (min, max) = board::GYRO_VREF_MV;
+    let passed = vref_off <= board::GYRO_OFF_MAX_MV
+        && (min..=max).contains(&vref)
+        && output.abs_diff(vref) <= board::GYRO_REST_MAX_MV;
+
+    if !passed {
+        warn!(
+            "gyro check failed, reference is {} mV off and {} mV on, output is {} mV",
+            vref_off, vref, output
+        );
+    }
+
+    passed
+}
+
 struct Controller<'a> {
     pwm: SimplePwm<'a>,
-    adc: Saadc<'a, 1>,
-    _gyro_power: gpio::Output<'a>,
+    gyro_power: gpio::Output<'a>,
     tail_n: gpio::Output<'a>,
-    pid: Pid<f32>,
+    core: ControlCore,
     input: JoystickData,
     gyro_offset: i32,
+    rate_sensor: RateSensor,
+    soc_stage: SocStage,
+    // Control ticks in a row with the yaw rate off the charts
+    spin_ticks: u32,
+    crash_yaw_rate: f32,
+    profile: PilotProfile,
+    // What the last tick did, see history.rs
+    sample: HistorySample,
+    gains: PidParams,
+    // Same for tuning, at a much higher rate (see tools/tune)
+    rate_loop: RateLoopReport,
+    sag: SagMeter,
+    #[cfg(feature = "motor-current")]
+    current: CurrentMonitor,
+    #[cfg(feature = "motor-current")]
+    motor_fault: Option<MotorFault>,
 }
 
 impl<'a> Controller<'a> {
-    const PWM_MAX_DUTY: u16 = 512;
-    const PID_CONTROL_LIMIT: u16 = Self::PWM_MAX_DUTY / 2;
+    const PWM_MAX_DUTY: u16 = board::MOTOR_PWM_MAX_DUTY;
     const RECEIVE_TIMEOUT: Duration = Duration::from_secs(1);
+    // Reports are late enough for the pilot to know, but not yet lost
+    const LINK_STALE_THRESHOLD: Duration = Duration::from_millis(250);
+    const CONTROL_LOOP_HZ: u64 = 200;
+    // ~10s worth of history
+    const HISTORY_HZ: u64 = 20;
+    // Enough to see an oscillation, and about what a connection interval can carry
+    const TUNING_HZ: u64 = 50;
+    // Flying on a stuck control loop is the worst thing that could happen
+    const CHECK_IN_WITHIN: Duration = Duration::from_millis(250);
 
     fn set_pwm(&mut self, r1: i32, r2: i32, v: i32) {
-        let clamp_to_pwm = |x: i32| x.clamp(0, Self::PWM_MAX_DUTY as i32) as u16;
+        let d = mixer::duties(r1, r2, v, Self::PWM_MAX_DUTY);
 
-        let tail = if v > 0 {
-            self.tail_n.set_high();
-
-            Self::PWM_MAX_DUTY as i32 - v
-        } else {
-            self.tail_n.set_low();
-            -v
-        };
+        self.tail_n.set_level(d.tail_n.into());
 
         let duties = [
-            DutyCycle::inverted(clamp_to_pwm(r1)),
-            DutyCycle::inverted(clamp_to_pwm(r2)),
-            DutyCycle::inverted(clamp_to_pwm(tail)),
+            DutyCycle::inverted(d.rotor1),
+            DutyCycle::inverted(d.rotor2),
+            DutyCycle::inverted(d.tail),
             DutyCycle::inverted(0), // unused
         ];
 
         self.pwm.set_all_duties(duties);
     }
 
-    async fn read_angular_speed(&mut self) -> f32 {
-        let mut buf = [0; 1];
+    fn angular_speed(&self, raw: i16) -> f32 {
+        // ADC equations are:
+        // Vdiff (volts) = reading * 0.6 / (gain * 2^(resolution-1)) = reading * 0.6 / (gain * 2048)
+        // speed = Vdiff (volts) * 1000 / sensitivity = reading * 600 / (gain * 2048 * sensitivity)
 
-        self.adc.sample(&mut buf).await;
+        let val = raw as i32 + self.gyro_offset;
+        val as f32 * 600.0 / (2048.0 * board::GYRO_ADC_GAIN_VALUE * board::GYRO_MV_PER_DPS)
+    }
 
-        // ADC equations are:
-        // Vdiff (volts) = reading * 0.6 / (gain * 2^resolution-1) = reading * 0.6 / 2048
-        // speed = Vdiff (volts) * 1000 / 0.67 = Vdiff * 600 / (2048 * 0.67)
+    // Single-ended with the default 1/6 gain, so the full scale is 3.6V
+    fn rail_mv(raw: i16) -> u16 {
+        (raw.max(0) as u32 * 3600 / 4096) as u16
+    }
+
+    // Motors are the ones dragging the rail down, so it's only interesting while armed
+    fn rail_voltage(&mut self, raw: i16) -> u16 {
+        let voltage = Self::rail_mv(raw);
+
+        self.sample.rail_voltage = voltage;
+        voltage
+    }
+
+    // All of the burst, at the throttle of the last tick. Once per throttle punch
+    fn rail_sag(&mut self, burst: &Burst) -> Option<Sag> {
+        let throttle = self.sample.throttle as i32;
 
-        let val = buf[0] as i32 + self.gyro_offset;
-        val as f32 * 600.0 / (2048.0 * 0.5 * 0.67)
+        burst
+            .iter()
+            .filter_map(|r| self.sag.update(Self::rail_mv(r[1]) as f32, throttle))
+            .last()
     }
 
-    async fn tick(&mut self) {
-        let throttle = (self.input.j1.1 >> 6).max(0);
-        let yaw = self.input.j2.0 >> 6;
+    // Spinning faster than Param::CrashYawRate for that long is a crash
+    const CRASH_SPIN_TICKS: u32 = (Self::CONTROL_LOOP_HZ / 4) as u32;
 
-        let control = if throttle > 10 {
-            let ang_rate = self.read_angular_speed().await;
+    // Way below what it takes to lift off, just enough to hear each motor spin
+    const SELF_TEST_DUTY: i32 = Self::PWM_MAX_DUTY as i32 / 10;
+    // Gyro at rest should read close to zero once the offset is applied. Missing or
+    // unpowered one ends up near either rail
+    const GYRO_REST_TOLERANCE: i32 = 300;
 
-            self.pid.setpoint = -yaw as f32;
-            self.pid.next_control_output(ang_rate).output as i32
-        } else {
-            0
+    async fn self_test(&mut self, sensors: &mut Sensors<'_>, motors: bool) -> Faults {
+        let mut failures = Faults::empty();
+
+        let rest = sensors.sample().await[0] as i32 + self.gyro_offset;
+        if rest.abs() > Self::GYRO_REST_TOLERANCE {
+            warn!("gyro reads {} at rest", rest);
+            failures |= Faults::SELF_TEST_GYRO;
+        }
+
+        if motors {
+            let d = Self::SELF_TEST_DUTY;
+
+            for (r1, r2, tail) in [(d, 0, 0), (0, d, 0), (0, 0, d)] {
+                self.set_pwm(r1, r2, tail);
+                Timer::after_millis(200).await;
+                self.set_pwm(0, 0, 0);
+                Timer::after_millis(200).await;
+            }
+        }
+
+        failures
+    }
+
+    // Takes the latest samples, see Sensors::run(). The pipeline itself is in
+    // copter_core::control, so it can be tested on the host
+    fn tick(
+        &mut self,
+        samples: Readings,
+        imu: Option<ImuSample>,
+        altitude: Option<AltitudeSample>,
+        range: Option<RangeSample>,
+        flow: Option<FlowSample>,
+    ) {
+        let locked_out = self.core.locked_out();
+
+        let ang_rate = self.yaw_rate(&samples, imu);
+
+        // Motors were cut along with the lockout
+        let Some(step) = self.core.tick(
+            &Self::sticks(&self.input),
+            &self.profile.shaping(),
+            self.soc_stage,
+            ang_rate,
+            &Estimates {
+                vertical: altitude.filter(|s| s.fresh()).map(|s| s.vertical),
+                range: range.filter(|s| s.fresh()).map(|s| s.vertical),
+                flow: flow.filter(|s| s.fresh()).map(|s| s.flow),
+            },
+        ) else {
+            return;
         };
 
-        let rotor1 = throttle + control;
-        let rotor2 = throttle - control;
-        let elevator = self.input.j2.1 >> 6;
+        if locked_out {
+            info!("undervoltage lockout is over");
+        }
+
+        // Gyro only means something once the rotors are up to speed
+        let mut yaw_rate = 0.0;
+
+        if step.throttle > MIN_CONTROL_THROTTLE {
+            yaw_rate = ang_rate;
+
+            self.spin_ticks = match ang_rate.abs() > self.crash_yaw_rate {
+                true => self.spin_ticks + 1,
+                false => 0,
+            };
+        }
+
+        self.set_pwm(step.rotor1, step.rotor2, step.elevator);
+
+        self.sample = HistorySample {
+            yaw_rate: yaw_rate as i16,
+            throttle: step.throttle as i16,
+            yaw: step.yaw as i16,
+            pitch: step.elevator as i16,
+            rotor1: step.rotor1 as i16,
+            rotor2: step.rotor2 as i16,
+            throttle_limit: self.core.throttle_limit() as i16,
+            ..self.sample
+        };
+
+        // PID is after the negated yaw, see copter_core::control
+        self.rate_loop = RateLoopReport {
+            gyro: (yaw_rate * 10.0) as i16,
+            setpoint: (-step.yaw * 10) as i16,
+            output: step.control as i16,
+            throttle: step.throttle as i16,
+            gains: self.gains,
+        };
+
+        #[cfg(feature = "motor-current")]
+        self.check_currents(&samples, [step.rotor1, step.rotor2]);
+    }
+
+    // Rotor duty cycles the samples were taken at
+    #[cfg(feature = "motor-current")]
+    fn check_currents(&mut self, samples: &Readings, duties: [i32; 2]) {
+        let amps = |raw: i16| {
+            raw.max(0) as f32 * 600.0
+                / (4096.0 * board::MOTOR_SENSE_GAIN_VALUE * board::MOTOR_SENSE_MV_PER_A)
+        };
+        let duty = |d: i32| d as f32 / Self::PWM_MAX_DUTY as f32;
+
+        let fault = self.current.update(
+            [amps(samples[2]), amps(samples[3])],
+            [duty(duties[0]), duty(duties[1])],
+        );
+
+        self.motor_fault = self.motor_fault.or(fault);
+    }
+
+    // First one found since arming, the motors have to be cut then
+    #[cfg(feature = "motor-current")]
+    fn motor_fault(&self) -> Option<MotorFault> {
+        self.motor_fault
+    }
+
+    #[cfg(feature = "motor-current")]
+    fn set_motor_current_limit(&mut self, ma: i32) {
+        self.current = CurrentMonitor::new(ma as f32 / 1000.0, Self::CONTROL_LOOP_HZ as u32);
+    }
+
+    fn crashed(&self) -> bool {
+        self.spin_ticks >= Self::CRASH_SPIN_TICKS
+    }
+
+    fn throttle(jd: &JoystickData) -> i32 {
+        shaping::throttle(jd.j1.1)
+    }
 
-        self.set_pwm(rotor1, rotor2, elevator);
+    fn sticks(jd: &JoystickData) -> Sticks {
+        Sticks {
+            throttle: jd.j1.1,
+            yaw: jd.j2.0,
+            pitch: jd.j2.1,
+        }
     }
 
     fn add_input(&mut self, jd: JoystickData) {
         self.input = jd;
     }
 
-    fn set_pid(&mut self, p: f32, i: f32, d: f32) {
-        self.pid
-            .p(p, Self::PID_CONTROL_LIMIT)
-            .i(i, Self::PID_CONTROL_LIMIT)
-            .d(d, Self::PID_CONTROL_LIMIT);
+    // Only engages with a barometer, the request is kept until then
+    fn toggle_altitude_hold(&mut self) {
+        self.core.toggle_altitude_hold();
+        info!(
+            "altitude hold requested: {}",
+            self.core.altitude_hold_requested()
+        );
+    }
+
+    fn set_profile(&mut self, profile: PilotProfile) {
+        self.profile = profile;
+    }
+
+    fn set_soc_stage(&mut self, stage: SocStage) {
+        self.soc_stage = stage;
+    }
+
+    // Whatever imu.rs has found, for the whole flight
+    fn set_rate_sensor(&mut self, sensor: RateSensor) {
+        info!("flying on the {} gyro", sensor);
+        self.rate_sensor = sensor;
+    }
+
+    // Digital gyro is the better one, as long as it keeps up. Analog one is always
+    // there to fall back to
+    fn yaw_rate(&self, samples: &Readings, imu: Option<ImuSample>) -> f32 {
+        match (self.rate_sensor, imu.filter(|s| s.fresh())) {
+            (RateSensor::Digital, Some(sample)) => sample.yaw_rate(),
+            _ => self.angular_speed(samples[0]),
+        }
+    }
+
+    fn set_crash_yaw_rate(&mut self, rate: i32) {
+        self.crash_yaw_rate = rate as f32;
+    }
+
+    // Battery can't hold the load - cut the motors before the MCU browns out
+    fn undervoltage(&mut self) {
+        self.set_pwm(0, 0, 0);
+        self.core.undervoltage();
     }
 
-    async fn init(r: &'a mut ControllerResources) -> Self {
+    fn set_pid(&mut self, pid: &PidParams) {
+        let (p, i, d) = (pid.get_p(), pid.get_i(), pid.get_d());
+        info!("updating pid params: p: {}, i: {}, d: {}", p, i, d);
+
+        self.core.set_gains(Gains { p, i, d });
+        self.gains = *pid;
+    }
+
+    async fn init(r: &'a mut ControllerResources, gyro_offset: i16) -> (Self, Sensors<'a>) {
         let mut pwm_config = pwm::SimpleConfig::default();
 
         pwm_config.max_duty = Controller::PWM_MAX_DUTY;
-        pwm_config.prescaler = pwm::Prescaler::Div16;
+        pwm_config.prescaler = board::MOTOR_PWM_PRESCALER;
 
         let mut adc_config = saadc::Config::default();
 
@@ -109,14 +483,9 @@ impl<'a> Controller<'a> {
         let mut adc_channel_config =
             saadc::ChannelConfig::differential(r.gyro_input.reborrow(), r.gyro_vref.reborrow());
 
-        // Some considerations here:
-        // - gyro vref is 1.35v, our ADC vref is 600 mV;
-        // - 0.67 mV per deg/s;
-        // - maximum angular velocity is 300 deg/s, which is ~200 mV;
-        // - however, some natural DC offset seem to be taking place, so we need wider range
-
-        adc_channel_config.time = saadc::Time::_40US;
-        adc_channel_config.gain = saadc::Gain::GAIN1_2;
+        // See the board for the considerations
+        adc_channel_config.time = board::GYRO_ADC_TIME;
+        adc_channel_config.gain = board::GYRO_ADC_GAIN;
 
         let pwm = SimplePwm::new_3ch(
             r.pwm.reborrow(),
@@ -127,70 +496,511 @@ impl<'a> Controller<'a> {
             &pwm_config,
         );
 
-        let adc = saadc::Saadc::new(r.adc.reborrow(), Irqs, adc_config, [adc_channel_config]);
+        let rail_channel_config = saadc::ChannelConfig::single_ended(saadc::VddInput);
+
+        #[cfg(not(feature = "motor-current"))]
+        let channels = [adc_channel_config, rail_channel_config];
+
+        #[cfg(feature = "motor-current")]
+        let channels = {
+            let mut rotor1 = saadc::ChannelConfig::single_ended(r.rotor1_sense.reborrow());
+            let mut rotor2 = saadc::ChannelConfig::single_ended(r.rotor2_sense.reborrow());
+
+            rotor1.gain = board::MOTOR_SENSE_GAIN;
+            rotor2.gain = board::MOTOR_SENSE_GAIN;
+
+            [adc_channel_config, rail_channel_config, rotor1, rotor2]
+        };
+
+        let adc = saadc::Saadc::new(r.adc.reborrow(), Irqs, adc_config, channels);
 
         let gyro_power = Output::new(r.gyro_power.reborrow(), Level::High, OutputDrive::Standard);
         let tail_n = Output::new(r.tail_n.reborrow(), Level::Low, OutputDrive::Standard);
 
-        let mut pid = Pid::new(0.0, Self::PWM_MAX_DUTY);
-        pid.p(0.5, Self::PID_CONTROL_LIMIT)
-            .i(0.2, Self::PID_CONTROL_LIMIT)
-            .d(0.2, Self::PID_CONTROL_LIMIT);
-
         adc.calibrate().await;
 
         // Give gyro some time to settle
         Timer::after_millis(50).await;
 
-        Self {
+        let sensors = Sensors {
             adc,
-            _gyro_power: gyro_power,
+            timer: r.sample_timer.reborrow(),
+            sample_ppi: r.sample_ppi.reborrow(),
+            start_ppi: r.start_ppi.reborrow(),
+        };
+
+        let controller = Self {
+            gyro_power,
             pwm,
             tail_n,
-            pid,
+            core: ControlCore::new(Self::PWM_MAX_DUTY, Self::CONTROL_LOOP_HZ as u32),
             input: Default::default(),
-            gyro_offset: 742,
+            gyro_offset: gyro_offset as i32,
+            rate_sensor: RateSensor::Analog,
+            soc_stage: SocStage::Normal,
+            spin_ticks: 0,
+            crash_yaw_rate: ParamValues::default().get(Param::CrashYawRate) as f32,
+            profile: PilotProfile::default(),
+            sample: HistorySample::default(),
+            gains: Gains::default().into(),
+            rate_loop: RateLoopReport::default(),
+            sag: SagMeter::new(Sensors::SAMPLE_HZ),
+            #[cfg(feature = "motor-current")]
+            current: CurrentMonitor::new(
+                ParamValues::default().get(Param::MotorCurrentLimit) as f32 / 1000.0,
+                Self::CONTROL_LOOP_HZ as u32,
+            ),
+            #[cfg(feature = "motor-current")]
+            motor_fault: None,
+        };
+
+        (controller, sensors)
+    }
+}
+
+// Arm button toggles arming, it's the menu one unless the pilot profile says otherwise.
+// Only the press itself counts, not holding it
+fn arm_toggled(state: &SystemState, previous: &JoystickData, current: &JoystickData) -> bool {
+    let button = state
+        .pilot_profile
+        .try_get()
+        .unwrap_or_default()
+        .arm_button();
+
+    shaping::pressed(previous.buttons.bits, current.buttons.bits, button.bits)
+}
+
+// Same as above, for the altitude hold. Pilot profile may have none
+fn hold_toggled(state: &SystemState, previous: &JoystickData, current: &JoystickData) -> bool {
+    let button = state
+        .pilot_profile
+        .try_get()
+        .unwrap_or_default()
+        .hold_button();
+
+    !button.is_empty() && shaping::pressed(previous.buttons.bits, current.buttons.bits, button.bits)
+}
+
+// Peripherals are released once we're disarmed, but make sure we leave
+// the motors stopped and the gyro unpowered
+impl<'a> Drop for Controller<'a> {
+    fn drop(&mut self) {
+        self.set_pwm(0, 0, 0);
+        self.gyro_power.set_low();
+    }
+}
+
+// Anything borrowing the motors is only fine while disarmed
+async fn wait_disarmed<T: Send>(state: &SystemState, signal: &Signal<StateMutex, T>) -> T {
+    loop {
+        let value = signal.wait().await;
+
+        if !state.armed.try_get().unwrap_or_default() {
+            return value;
         }
     }
 }
 
+// Waits for the next time we're disarmed anyway, but it's less confusing to say so
+fn calibrate_command(state: &SystemState, args: Args, _: &mut dyn Write) -> Result<(), Error> {
+    args.finish()?;
+
+    if state.armed.try_get().unwrap_or_default() {
+        return Err(Error::Refused);
+    }
+
+    state.calibrate_gyro.signal(());
+    Ok(())
+}
+
+fn gyro_command(state: &SystemState, args: Args, out: &mut dyn Write) -> Result<(), Error> {
+    args.finish()?;
+
+    let sensor = match state.rate_sensor.try_get().unwrap_or_default() {
+        RateSensor::Analog => "analog",
+        RateSensor::Digital => "digital",
+    };
+    let offset = state.gyro_offset.try_get().unwrap_or(DEFAULT_GYRO_OFFSET);
+
+    writeln!(out, "using {}, analog offset {}", sensor, offset)?;
+
+    match state.imu_gyro_bias.try_get().and_then(|b| b.get()) {
+        Some(bias) => writeln!(out, "digital bias {:?} dps", bias)?,
+        None => writeln!(out, "digital bias not stored")?,
+    }
+
+    Ok(())
+}
+
+fn arming_command(state: &SystemState, args: Args, out: &mut dyn Write) -> Result<(), Error> {
+    args.finish()?;
+
+    let allowed = state.controller_run_allowed.try_get().unwrap_or_default();
+    let faults = state.faults.try_get().unwrap_or_default();
+
+    writeln!(out, "allowed {}, faults {:#x}", allowed, faults.bits())?;
+    Ok(())
+}
+
+pub const COMMANDS: &[Command] = &[
+    Command {
+        name: "calibrate",
+        help: "- calibrate the gyro, not while armed",
+        run: calibrate_command,
+    },
+    Command {
+        name: "gyro",
+        help: "- which gyro is in use, and the stored calibrations",
+        run: gyro_command,
+    },
+    Command {
+        name: "arming",
+        help: "- whether arming is allowed, and the faults that inhibit it",
+        run: arming_command,
+    },
+];
+
 #[embassy_executor::task]
-pub async fn run(state: &'static SystemState, mut r: ControllerResources) {
+pub async fn run(state: &'static SystemState, r: ControllerResources) {
+    taskstats::accounted(Task::Control, task(state, r)).await
+}
+
+async fn task(state: &'static SystemState, mut r: ControllerResources) {
     let mut request_receiver = unwrap!(state.requests.receiver());
     let mut controller_sample_receiver = unwrap!(state.controller_sample.receiver());
-    let controller_run_allowed_receiver = unwrap!(state.controller_run_allowed.receiver());
+    let mut controller_run_allowed_receiver = unwrap!(state.controller_run_allowed.receiver());
+    let mut soc_stage_receiver = unwrap!(state.soc_stage.receiver());
+    let armed_sender = state.armed.sender();
+    let flying_sender = state.flying.sender();
+    let rail_voltage_sender = state.rail_voltage.sender();
+    let rail_sag_sender = state.rail_sag.sender();
+    let rate_loop_sender = state.rate_loop.sender();
+
+    // Survives power gating of the controller
+    let mut pid_params = None;
+
+    if board::GYRO_CHECK && !check_gyro(&mut r).await {
+        state.set_faults(Faults::GYRO_SENSOR, true);
+    }
 
-    let run_controller = async || {
-        info!("running controller");
+    let gyro_offset = || state.gyro_offset.try_get().unwrap_or(DEFAULT_GYRO_OFFSET);
+
+    loop {
+        let run_controller = async || {
+            info!("waiting for arming");
+
+            let mut last_input = JoystickData::default();
+
+            loop {
+                // Everything stays powered down until armed
+                loop {
+                    match select(
+                        request_receiver.changed(),
+                        controller_sample_receiver.changed(),
+                    )
+                    .await
+                    {
+                        Either::First(Request::PidUpdate(pid)) => {
+                            pid_params = Some(pid);
+                            state.indicate_once(OneShot::Flashes(1));
+                        }
+                        // Back to whatever init() comes up with
+                        Either::First(Request::RestoreDefaults(groups)) => {
+                            if groups.contains(SettingsGroups::CONTROL) {
+                                pid_params = None;
+                            }
+                        }
+                        Either::First(_) => {}
+
+                        Either::Second(input) => {
+                            let toggled = arm_toggled(state, &last_input, &input);
+                            last_input = input;
+
+                            if toggled && Controller::throttle(&input) == 0 {
+                                break;
+                            }
+                        }
+                    }
+                }
 
-        const CONTROL_LOOP_RATE: Duration = Duration::from_hz(200);
+                info!("armed, running controller");
+
+                let (mut controller, mut sensors) = Controller::init(&mut r, gyro_offset()).await;
+                let samples = Signal::new();
+                let mut idle_since = Instant::now();
+
+                armed_sender.send(true);
+                state.log_event(Event::Armed, 0);
+                state.indicate_once(OneShot::Armed);
+                history::restart();
+
+                let armed_at = Instant::now();
+
+                // Flight is logged whichever way it ends
+                let mut crashed = guard(false, |crashed| {
+                    state.supervisor.release(Supervised::Control);
+                    flying_sender.send(false);
+                    armed_sender.send(false);
+                    state.log_event(Event::Disarmed, 0);
+                    rail_voltage_sender.send(RAIL_VOLTAGE_UNKNOWN);
+
+                    state
+                        .requests
+                        .sender()
+                        .send(Request::FlightLogged(FlightLog {
+                            duration: armed_at.elapsed().as_secs() as u32,
+                            crashed,
+                        }));
+                });
+
+                let mut ticks = 0;
+                let mut last_sample_at = Instant::now();
+                let mut link_stale = false;
+
+                if let Some(pid) = pid_params {
+                    controller.set_pid(&pid);
+                }
 
-        let mut controller = Controller::init(&mut r).await;
-        let mut ticker = Ticker::every(CONTROL_LOOP_RATE);
+                if let Some(stage) = soc_stage_receiver.try_get() {
+                    controller.set_soc_stage(stage);
+                }
 
-        loop {
-            let r = select3(
-                request_receiver.changed(),
-                controller_sample_receiver.changed(),
-                ticker.next(),
-            )
-            .await;
+                let params = state.params.try_get().unwrap_or_default();
+                let idle_disarm_timeout =
+                    Duration::from_secs(params.get(Param::IdleDisarmTimeout) as u64);
+
+                controller.set_rate_sensor(state.rate_sensor.try_get().unwrap_or_default());
+                controller.set_crash_yaw_rate(params.get(Param::CrashYawRate));
+                #[cfg(feature = "motor-current")]
+                controller.set_motor_current_limit(params.get(Param::MotorCurrentLimit));
+                controller.set_profile(state.pilot_profile.try_get().unwrap_or_default());
+
+                controller.add_input(last_input);
+
+                let control = async {
+                    loop {
+                        let e = select(
+                            select4(
+                                request_receiver.changed(),
+                                controller_sample_receiver.changed(),
+                                samples.wait(),
+                                soc_stage_receiver.changed(),
+                            ),
+                            state.undervoltage.wait(),
+                        )
+                        .await;
+
+                        let e = match e {
+                            Either::First(e) => e,
+                            Either::Second(_) => {
+                                warn!("supply rail undervoltage, cutting the motors");
+                                controller.undervoltage();
+                                continue;
+                            }
+                        };
+
+                        match e {
+                            Either4::First(Request::PidUpdate(pid)) => {
+                                pid_params = Some(pid);
+                                controller.set_pid(&pid);
+                                state.indicate_once(OneShot::Flashes(1));
+                            }
+
+                            Either4::First(_) => {}
+
+                            Either4::Second(input) => {
+                                let toggled = arm_toggled(state, &last_input, &input);
+                                let hold = hold_toggled(state, &last_input, &input);
+                                last_input = input;
+                                last_sample_at = Instant::now();
+                                link_stale = false;
+
+                                // Same as arming, a stray press in the air would
+                                // cut the motors
+                                if toggled && Controller::throttle(&input) == 0 {
+                                    info!("disarmed");
+                                    state.indicate_once(OneShot::Disarmed);
+                                    break;
+                                }
+
+                                if hold {
+                                    controller.toggle_altitude_hold();
+                                }
+
+                                controller.add_input(input);
+                            }
+
+                            Either4::Third(burst) => {
+                                let readings = burst[SAMPLES_PER_TICK - 1];
+
+                                controller.tick(
+                                    readings,
+                                    state.imu.try_get(),
+                                    state.altitude.try_get(),
+                                    state.range.try_get(),
+                                    state.flow.try_get(),
+                                );
+
+                                #[cfg(feature = "motor-current")]
+                                if let Some(fault) = controller.motor_fault() {
+                                    incident!(
+                                        warn,
+                                        state,
+                                        Incident::MotorCurrent,
+                                        "{}, disarming",
+                                        fault
+                                    );
+                                    state.indicate_once(OneShot::Reject);
+                                    break;
+                                }
+
+                                if let Some(sag) = controller.rail_sag(&burst) {
+                                    info!(
+                                        "rail sagged {}mV on a throttle step of {}",
+                                        sag.droop(),
+                                        sag.step
+                                    );
+                                    rail_sag_sender.send(sag.into());
+                                }
+
+                                state
+                                    .supervisor
+                                    .check_in(Supervised::Control, Controller::CHECK_IN_WITHIN);
+
+                                if controller.crashed() && !*crashed {
+                                    warn!("looks like a crash");
+                                    *crashed = true;
+
+                                    // Settings take it from there
+                                    if history::freeze(HistoryTrigger::Crash) {
+                                        state.requests.sender().send(Request::HistoryFrozen);
+                                    }
+                                }
+
+                                // Power task picks it up with the rest of the telemetry
+                                ticks += 1;
+                                if ticks % Controller::CONTROL_LOOP_HZ == 0 {
+                                    rail_voltage_sender.send(controller.rail_voltage(readings[1]));
+                                }
+
+                                if ticks % (Controller::CONTROL_LOOP_HZ / Controller::HISTORY_HZ)
+                                    == 0
+                                {
+                                    history::record(controller.sample);
+                                }
+
+                                if ticks % (Controller::CONTROL_LOOP_HZ / Controller::TUNING_HZ)
+                                    == 0
+                                {
+                                    rate_loop_sender.send(controller.rate_loop);
+                                }
+
+                                // Once per each gap in the reports, flashing all the time won't help
+                                if !link_stale
+                                    && last_sample_at.elapsed() > Controller::LINK_STALE_THRESHOLD
+                                {
+                                    warn!("controller link is degrading");
+                                    state.indicate_once(OneShot::Blip);
+                                    link_stale = true;
+                                }
+
+                                let flying = Controller::throttle(&last_input) > 0;
+                                flying_sender.send_if_modified(|current| {
+                                    let modified = *current != Some(flying);
+                                    *current = Some(flying);
+                                    modified
+                                });
+
+                                if flying {
+                                    idle_since = Instant::now();
+                                } else if idle_since.elapsed() > idle_disarm_timeout {
+                                    info!("disarmed due to inactivity");
+                                    state.indicate_once(OneShot::Disarmed);
+                                    break;
+                                }
+                            }
+
+                            Either4::Fourth(stage) => controller.set_soc_stage(stage),
+                        }
+                    }
+                };
+
+                // Sampling only stops along with the control loop. Otherwise it's
+                // back on the ground with whatever the gyro did last
+                if let Either::First(_) = select(sensors.run(&samples), control).await {
+                    soft_assert!(state, Degrade, Assertion::GyroSampler, false);
+                }
+            }
+        };
+
+        let s = select4(
+            utils::run_while(
+                &mut controller_run_allowed_receiver,
+                |allowed| *allowed,
+                run_controller,
+            ),
+            wait_disarmed(state, &state.chirp),
+            wait_disarmed(state, &state.self_test),
+            wait_disarmed(state, &state.calibrate_gyro),
+        )
+        .await;
+
+        match s {
+            Either4::First(_) => {}
+            Either4::Second(chirp) => chirp::play(&mut r, chirp).await,
+
+            // Borrows the controller for a moment, and leaves everything off once dropped
+            Either4::Third(mode) => {
+                let (mut controller, mut sensors) = Controller::init(&mut r, gyro_offset()).await;
+                let failures = controller
+                    .self_test(&mut sensors, mode == SelfTestMode::Full)
+                    .await;
+
+                drop((controller, sensors));
+                state.self_test_report.signal(failures);
+            }
 
-            match r {
-                Either3::First(Request::PidUpdate(pid)) => {
-                    let (p, i, d) = (pid.get_p(), pid.get_i(), pid.get_d());
+            Either4::Fourth(_) => {
+                info!("calibrating gyro, keep the airframe still");
 
-                    info!("updating pid params: p: {}, i: {}, d: {}", p, i, d);
-                    controller.set_pid(p, i, d);
+                let gyro_calibrating_sender = state.gyro_calibrating.sender();
+                gyro_calibrating_sender.send(true);
+
+                // Gyro stays powered for as long as the controller is around
+                let (controller, mut sensors) = Controller::init(&mut r, gyro_offset()).await;
+                let offset = sensors.calibrate_gyro().await;
+
+                drop((controller, sensors));
+
+                if let Some(offset) = offset {
+                    info!("gyro offset is now {}", offset);
+
+                    state
+                        .requests
+                        .sender()
+                        .send(Request::GyroOffsetUpdate(offset));
                 }
 
-                Either3::First(_) => {}
+                // Digital one has its own bias to keep, and the IMU task is the one reading it
+                let imu_done = match state.imu_found.try_get() {
+                    Some(true) => {
+                        state.imu_calibration_report.reset();
+                        state.calibrate_imu.signal(());
+
+                        with_timeout(IMU_CALIBRATION_TIMEOUT, state.imu_calibration_report.wait())
+                            .await
+                            .unwrap_or(false)
+                    }
+                    _ => true,
+                };
 
-                Either3::Second(input) => controller.add_input(input),
-                Either3::Third(_) => controller.tick().await,
+                gyro_calibrating_sender.send(false);
+
+                match offset.is_some() && imu_done {
+                    true => state.indicate_once(OneShot::Confirm),
+                    false => state.indicate_once(OneShot::Reject),
+                }
             }
         }
-    };
-
-    utils::run_with_receiver(controller_run_allowed_receiver, run_controller).await;
+    }
 }
@@ -0,0 +1,305 @@
+// Firmware update over BLE, the board's end of copter_core::dfu
+//
+// Commands and chunks come in through the update service (see ble/peripheral.rs) and are
+// queued for the settings task, since that's who has the flash. Chunks the queue has no
+// room for are dropped, the status says where to pick up from and the updater sends them
+// again. Nothing is touched while armed: flash writes stall the CPU, and the control loop
+// with it. Activating marks the image for the bootloader (see boot.rs) and reboots.
+//
+// Who's on the other end of the link isn't checked, there's no way to tell a peer apart
+// with Just Works anyway. The image is checked instead: it has to be signed with the
+// update key, the public half of which is built in from DFU_PUBLIC_KEY. Firmware built
+// without it takes no updates at all.
+
+use copter_core::dfu::{
+    self, Chunk, Command, Crc32, Failure, Phase, SignatureCheck, Status, CHUNK_LEN, IMAGE_ALIGN,
+    KEY_LEN, SIGNATURE_LEN,
+};
+use defmt::{info, unwrap, warn};
+use embassy_time::{Duration, Timer};
+use embedded_storage_async::nor_flash::{NorFlash, ReadNorFlash};
+use nrf_softdevice::Flash;
+
+use crate::boot;
+use crate::state::{Request, SystemState};
+
+// Enough for most of a window (see tools/ota), the rest is sent again
+pub const QUEUE_LEN: usize = 16;
+
+// Write response has to make it out before we're gone
+const REBOOT_DELAY: Duration = Duration::from_millis(500);
+
+const PAGE_SIZE: u32 = Flash::ERASE_SIZE as u32;
+
+// See tools/ota --keygen
+const UPDATE_KEY: Option<[u8; KEY_LEN]> = match option_env!("DFU_PUBLIC_KEY") {
+    Some(hex) => match dfu::parse_key(hex) {
+        Some(key) => Some(key),
+        None => panic!("DFU_PUBLIC_KEY should be 64 hex digits"),
+    },
+    None => None,
+};
+
+// Softdevice only writes whole words, from word-aligned buffers
+#[repr(C, align(4))]
+#[derive(Clone, Copy)]
+pub struct ChunkWrite {
+    data: [u8; CHUNK_LEN],
+    offset: u32,
+    len: u8,
+}
+
+impl ChunkWrite {
+    pub fn new(chunk: &Chunk) -> Self {
+        let mut data = [0; CHUNK_LEN];
+        data[..chunk.data.len()].copy_from_slice(chunk.data);
+
+        Self {
+            data,
+            offset: chunk.offset,
+            len: chunk.data.len() as u8,
+        }
+    }
+
+    fn data(&self) -> &[u8] {
+        &self.data[..self.len as usize]
+    }
+}
+
+#[derive(Clone, Copy)]
+pub enum DfuRequest {
+    Command(Command),
+    Chunk(ChunkWrite),
+}
+
+// Called from the GATT handler, never blocks. A lost command would leave the updater
+// waiting for nothing, so it's told right away instead
+pub fn request(state: &SystemState, request: DfuRequest) {
+    if state.dfu_requests.try_send(request).is_ok() {
+        return;
+    }
+
+    if let DfuRequest::Command(_) = request {
+        state.dfu_status.sender().send_modify(|status| {
+            if let Some(status) = status {
+                status.phase = Phase::Failed;
+                status.failure = Failure::Sequence;
+            }
+        });
+    }
+}
+
+pub struct Updater {
+    status: Status,
+    crc: u32,
+    // Signature is the rest of it
+    image_len: u32,
+}
+
+impl Updater {
+    pub fn new(state: &SystemState) -> Self {
+        state.dfu_status.sender().send(Status::IDLE);
+
+        Self {
+            status: Status::IDLE,
+            crc: 0,
+            image_len: 0,
+        }
+    }
+
+    fn publish(&self, state: &SystemState) {
+        state.dfu_status.sender().send(self.status);
+    }
+
+    fn fail(&mut self, failure: Failure) {
+        warn!("firmware update failed - {}", failure);
+
+        self.status.phase = Phase::Failed;
+        self.status.failure = failure;
+    }
+
+    async fn start(&mut self, state: &SystemState, flash: &mut Flash, len: u32, crc: u32) {
+        let (Some(partition), Some(_)) = (boot::dfu_partition(), UPDATE_KEY) else {
+            return self.fail(Failure::Unsupported);
+        };
+
+        let Ok(image_len) = dfu::image_len(len) else {
+            return self.fail(Failure::Image);
+        };
+
+        self.status = Status {
+            phase: Phase::Erasing,
+            failure: Failure::None,
+            received: 0,
+            len,
+        };
+        self.crc = crc;
+        self.image_len = image_len;
+        self.publish(state);
+
+        // Only as much as the image needs, the whole partition takes seconds
+        let end = partition.start + len.div_ceil(PAGE_SIZE) * PAGE_SIZE;
+
+        match flash.erase(partition.start, end).await {
+            Ok(()) => {
+                info!("receiving a {} byte firmware image", len);
+                self.status.phase = Phase::Receiving;
+            }
+            Err(e) => {
+                warn!("unable to erase the DFU partition - {}", e);
+                self.fail(Failure::Flash);
+            }
+        }
+    }
+
+    // Out of order or odd-sized ones are dropped, the status says where we are
+    async fn chunk(&mut self, flash: &mut Flash, chunk: &ChunkWrite) {
+        let Some(partition) = boot::dfu_partition() else {
+            return;
+        };
+
+        // Offset is whatever the peer wrote, it may well be near the top of the range
+        let fits = chunk
+            .offset
+            .checked_add(chunk.len as u32)
+            .is_some_and(|end| end <= self.status.len);
+
+        if self.status.phase != Phase::Receiving
+            || chunk.offset != self.status.received
+            || !fits
+            || chunk.len as u32 % IMAGE_ALIGN != 0
+        {
+            return;
+        }
+
+        // Vector table is in the first one, no point in taking the rest of an image
+        // that would never boot. Too short to hold it is just as bad
+        if chunk.offset == 0 {
+            let word = |at: usize| u32::from_le_bytes(unwrap!(chunk.data[at..at + 4].try_into()));
+
+            if chunk.len < 8 || dfu::check_vectors(self.image_len, word(0), word(4)).is_err() {
+                return self.fail(Failure::Image);
+            }
+        }
+
+        if let Err(e) = flash
+            .write(partition.start + chunk.offset, chunk.data())
+            .await
+        {
+            warn!("unable to write the DFU partition - {}", e);
+            return self.fail(Failure::Flash);
+        }
+
+        self.status.received += chunk.len as u32;
+
+        if self.status.received == self.status.len {
+            self.verify(flash, partition.start).await;
+        }
+    }
+
+    // What's in the flash, not what we think we wrote. Last thing before it can be
+    // activated, so the signature is checked right here too
+    async fn verify(&mut self, flash: &mut Flash, start: u32) {
+        let Some(key) = UPDATE_KEY else {
+            return self.fail(Failure::Unsupported);
+        };
+
+        let mut signature = [0; SIGNATURE_LEN as usize];
+        if let Err(e) = flash.read(start + self.image_len, &mut signature).await {
+            warn!("unable to read the DFU partition back - {}", e);
+            return self.fail(Failure::Flash);
+        }
+
+        let Some(mut check) = SignatureCheck::new(&key, &signature) else {
+            return self.fail(Failure::Signature);
+        };
+
+        let mut crc = Crc32::new();
+        let mut b = [0; 256];
+        let mut at = 0;
+
+        while at < self.status.len {
+            let n = b.len().min((self.status.len - at) as usize);
+
+            if let Err(e) = flash.read(start + at, &mut b[..n]).await {
+                warn!("unable to read the DFU partition back - {}", e);
+                return self.fail(Failure::Flash);
+            }
+
+            crc.update(&b[..n]);
+            check.update(&b[..n.min(self.image_len.saturating_sub(at) as usize)]);
+            at += n as u32;
+        }
+
+        if crc.finish() != self.crc {
+            return self.fail(Failure::Crc);
+        }
+
+        // Takes a good part of a second, nothing else gets to run meanwhile. Not armed
+        // though, so nothing is waiting for it either
+        match check.finish() {
+            true => {
+                info!("firmware image is in, CRC and signature match");
+                self.status.phase = Phase::Ready;
+            }
+            false => self.fail(Failure::Signature),
+        }
+    }
+
+    async fn activate(&mut self, state: &SystemState, flash: &mut Flash) {
+        if self.status.phase != Phase::Ready {
+            return self.fail(Failure::Sequence);
+        }
+
+        if !boot::mark_updated(flash).await {
+            return self.fail(Failure::Flash);
+        }
+
+        info!("firmware image is activated, rebooting");
+        self.status.phase = Phase::Activated;
+        self.publish(state);
+
+        Timer::after(REBOOT_DELAY).await;
+        state.requests.sender().send(Request::Reboot);
+    }
+
+    // An image on trial has to be confirmed first, the bootloader would refuse to
+    // swap the one after it in otherwise
+    pub async fn handle(
+        &mut self,
+        state: &SystemState,
+        flash: &mut Flash,
+        request: DfuRequest,
+        confirmed: bool,
+    ) {
+        let armed = state.armed.try_get() == Some(true);
+
+        match request {
+            // Reboot is on its way, nothing else makes sense
+            _ if self.status.phase == Phase::Activated => return,
+
+            DfuRequest::Command(Command::Abort) => {
+                self.status = Status::IDLE;
+            }
+
+            // Whatever was going on stays in the partition and is never used
+            _ if armed => {
+                if self.status.phase == Phase::Idle && matches!(request, DfuRequest::Chunk(_)) {
+                    return;
+                }
+
+                self.fail(Failure::Armed);
+            }
+
+            DfuRequest::Command(Command::Start { len, crc }) => match confirmed {
+                true => self.start(state, flash, len, crc).await,
+                false => self.fail(Failure::Sequence),
+            },
+
+            DfuRequest::Command(Command::Activate) => self.activate(state, flash).await,
+            DfuRequest::Chunk(chunk) => self.chunk(flash, &chunk).await,
+        }
+
+        self.publish(state);
+    }
+}
@@ -0,0 +1,108 @@
+// In-RAM event log
+//
+// Keeps the last few things that happened, so the sequence of events leading to a
+// misbehavior can be read back over BLE once a client connects. Everything that goes
+// in is printed over RTT as well. Only lasts until the next reset.
+
+use crate::clock;
+
+#[repr(u8)]
+#[derive(Clone, Copy, defmt::Format)]
+pub enum Event {
+    // Value is the reset reason, truncated
+    Boot = 1,
+    // Value is 1 for the IR remote (see ir.rs), 2 for the gamepad relay (relay.rs)
+    ControllerConnected = 2,
+    ControllerDisconnected = 3,
+    Armed = 4,
+    Disarmed = 5,
+    // Value is the whole set of active faults
+    Faults = 6,
+    // Value is the new SocStage
+    SocStage = 7,
+    // Value is the ShutdownReason
+    Shutdown = 8,
+    // Value is the new Mode
+    Mode = 9,
+    // Value is the Incident, see blackbox.rs
+    Warning = 10,
+    Error = 11,
+}
+
+#[repr(C, packed)]
+#[derive(Clone, Copy, Default)]
+pub struct LoggedEvent {
+    // Device time in ms, see clock.rs. Wraps around every 49 days or so
+    timestamp: u32,
+    // Event, zero for an empty slot
+    kind: u8,
+    _reserved: u8,
+    value: u16,
+}
+
+impl LoggedEvent {
+    pub const EMPTY: Self = Self {
+        timestamp: 0,
+        kind: 0,
+        _reserved: 0,
+        value: 0,
+    };
+
+    pub fn new(event: Event, value: u16) -> Self {
+        Self {
+            timestamp: clock::now() as u32,
+            kind: event as u8,
+            _reserved: 0,
+            value,
+        }
+    }
+
+    // Same thing happening again, whenever that was
+    pub fn same_as(&self, other: &Self) -> bool {
+        self.kind == other.kind && { self.value } == { other.value }
+    }
+
+    pub fn is(&self, event: Event, value: u16) -> bool {
+        self.kind == event as u8 && { self.value } == value
+    }
+}
+
+pub const EVENT_LOG_LEN: usize = 32;
+
+// What clients get to see, oldest event first
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+pub struct EventLog {
+    pub entries: [LoggedEvent; EVENT_LOG_LEN],
+}
+
+pub struct EventRing {
+    entries: [LoggedEvent; EVENT_LOG_LEN],
+    // Next slot to write, the oldest event once the ring is full
+    head: usize,
+}
+
+impl EventRing {
+    pub const fn new() -> Self {
+        Self {
+            entries: [LoggedEvent::EMPTY; EVENT_LOG_LEN],
+            head: 0,
+        }
+    }
+
+    pub fn push(&mut self, entry: LoggedEvent) {
+        self.entries[self.head] = entry;
+
+        self.head = (self.head + 1) % EVENT_LOG_LEN;
+    }
+
+    // Empty slots come first until the ring wraps, clients skip them by the zero kind
+    pub fn snapshot(&self) -> EventLog {
+        let mut log = EventLog {
+            entries: self.entries,
+        };
+
+        log.entries.rotate_left(self.head);
+        log
+    }
+}
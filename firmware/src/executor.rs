@@ -1,10 +1,50 @@
 pub(super) const THREAD_PENDER: usize = usize::MAX;
 
-use core::marker::PhantomData;
+use core::{cell::Cell, marker::PhantomData};
 use cortex_m::asm;
 use embassy_nrf::pac;
 
 use embassy_executor::{raw, Spawner};
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
+use embassy_time::Instant;
+
+// Where the thread mode executor spends its time, since boot. Interrupts, the control
+// loop included, run while it's in WFE and count as asleep
+#[derive(Clone, Copy)]
+pub struct ExecutorStats {
+    pub wakeups: u32,
+    // Both in embassy_time ticks
+    pub busy: u64,
+    pub asleep: u64,
+}
+
+impl ExecutorStats {
+    const fn new() -> Self {
+        Self {
+            wakeups: 0,
+            busy: 0,
+            asleep: 0,
+        }
+    }
+
+    // Share of the time spent polling since the earlier stats, in 0.1%
+    pub fn load_since(&self, earlier: &Self) -> u16 {
+        let busy = self.busy - earlier.busy;
+        let total = busy + self.asleep - earlier.asleep;
+
+        match total {
+            0 => 0,
+            total => (busy * 1000 / total) as u16,
+        }
+    }
+}
+
+static STATS: Mutex<CriticalSectionRawMutex, Cell<ExecutorStats>> =
+    Mutex::new(Cell::new(ExecutorStats::new()));
+
+pub fn stats() -> ExecutorStats {
+    STATS.lock(Cell::get)
+}
 
 pub struct MwuWorkaroundExecutor {
     inner: raw::Executor,
@@ -37,13 +77,22 @@ impl MwuWorkaroundExecutor {
 
     pub fn run(&'static mut self, init: impl FnOnce(Spawner)) -> ! {
         init(self.inner.spawner());
+
+        let mut stats = ExecutorStats::new();
+
         loop {
+            // Time driver only comes up in the first poll, so don't trust the order
+            let polled_at = Instant::now();
+
             unsafe {
                 self.inner.poll();
                 // nRF52832 errata: MWU: Increased current consumption
                 // high current consumption with MWU enabled
                 // The only workaround is to disable MWU while going into sleep
 
+                let asleep_at = Instant::now();
+                stats.busy += asleep_at.saturating_duration_since(polled_at).as_ticks();
+
                 Self::mwu_disable();
                 asm::wfe();
 
@@ -53,7 +102,14 @@ impl MwuWorkaroundExecutor {
                 asm::nop();
 
                 Self::mwu_enable();
+
+                stats.asleep += Instant::now()
+                    .saturating_duration_since(asleep_at)
+                    .as_ticks();
             };
+
+            stats.wakeups += 1;
+            STATS.lock(|s| s.set(stats));
         }
     }
 }
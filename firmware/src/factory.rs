@@ -0,0 +1,47 @@
+// Per-unit data from the bench, see copter_core::factory and tools/provision
+//
+// Read straight from the flash, it's memory mapped and nobody writes that page while
+// we're running.
+
+use copter_core::factory::{self, Factory, RECORD_LEN};
+
+use crate::types::BatteryProfile;
+
+extern "C" {
+    // Provided by memory.x
+    static __factory_start: u32;
+}
+
+// None if the board was never provisioned
+pub fn get() -> Option<Factory> {
+    let record = unsafe {
+        core::slice::from_raw_parts(&__factory_start as *const u32 as *const u8, RECORD_LEN)
+    };
+
+    Factory::parse(record)
+}
+
+// What the board ships with, the built-in one unless the bench said otherwise
+pub fn battery_profile() -> BatteryProfile {
+    let Some(battery) = get().and_then(|f| f.battery) else {
+        return BatteryProfile::default();
+    };
+
+    let factory::Battery {
+        capacity,
+        energy,
+        terminate_voltage,
+        taper_rate,
+        soc_delta,
+        chemistry,
+    } = battery;
+
+    BatteryProfile {
+        capacity,
+        energy,
+        terminate_voltage,
+        taper_rate,
+        soc_delta,
+        chemistry,
+    }
+}
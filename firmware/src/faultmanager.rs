@@ -0,0 +1,129 @@
+// Fault manager
+//
+// Everything raised with incident! (see blackbox.rs) comes through here first. All of
+// them are counted, but one that keeps coming back is only reported once in a while,
+// otherwise a retry loop floods RTT and the event log. Most failures are worth nothing
+// more than a retry. A few, when they come back over and over with no break in between,
+// mean something like a wedged softdevice, which only a reboot gets out of.
+
+use core::{cell::RefCell, mem};
+
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_time::{Duration, Instant};
+
+use crate::blackbox::Incident;
+use crate::state::StateMutex;
+
+// Same incident again within that long is counted, but not reported
+const REPORT_INTERVAL: Duration = Duration::from_secs(60);
+// Anything closer together than that is one streak
+const STREAK_GAP: Duration = Duration::from_secs(10);
+
+// Indexed by the incident code
+pub const INCIDENT_SLOTS: usize = 16;
+
+#[derive(Clone, Copy, PartialEq, defmt::Format)]
+pub enum Verdict {
+    // Let everyone know, some more of the same went unreported since the last time
+    Report { suppressed: u16 },
+    // Counted, nothing else
+    Suppress,
+    // Retrying doesn't help, time to reboot. Reported as well
+    Escalate { suppressed: u16 },
+}
+
+#[derive(Clone, Copy)]
+struct Tracker {
+    reported_at: Option<Instant>,
+    last_at: Option<Instant>,
+    suppressed: u16,
+    streak: u16,
+}
+
+impl Tracker {
+    const NEW: Self = Self {
+        reported_at: None,
+        last_at: None,
+        suppressed: 0,
+        streak: 0,
+    };
+}
+
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+pub struct IncidentCounts {
+    // Since boot, by the incident code. Saturates instead of wrapping
+    pub counts: [u16; INCIDENT_SLOTS],
+}
+
+// How many in a streak it takes to give up on retrying
+fn streak_limit(incident: Incident) -> Option<u16> {
+    match incident {
+        // Retried every second, a couple of minutes of that is not getting any better
+        Incident::Advertise => Some(120),
+        // Gauge and flash failures won't go away with a reboot, and the controller side
+        // fails all the time while the pad is switched off
+        _ => None,
+    }
+}
+
+pub struct FaultManager {
+    trackers: Mutex<StateMutex, RefCell<[Tracker; INCIDENT_SLOTS]>>,
+    counts: Mutex<StateMutex, RefCell<[u16; INCIDENT_SLOTS]>>,
+}
+
+impl FaultManager {
+    pub const fn new() -> Self {
+        Self {
+            trackers: Mutex::new(RefCell::new([Tracker::NEW; INCIDENT_SLOTS])),
+            counts: Mutex::new(RefCell::new([0; INCIDENT_SLOTS])),
+        }
+    }
+
+    pub fn raise(&self, incident: Incident) -> Verdict {
+        let slot = incident as usize % INCIDENT_SLOTS;
+        let now = Instant::now();
+
+        self.counts.lock(|counts| {
+            let c = &mut counts.borrow_mut()[slot];
+            *c = c.saturating_add(1);
+        });
+
+        self.trackers.lock(|trackers| {
+            let t = &mut trackers.borrow_mut()[slot];
+
+            t.streak = match t.last_at {
+                Some(at) if now - at < STREAK_GAP => t.streak.saturating_add(1),
+                _ => 1,
+            };
+            t.last_at = Some(now);
+
+            if streak_limit(incident).is_some_and(|limit| t.streak >= limit) {
+                t.streak = 0;
+                t.reported_at = Some(now);
+                return Verdict::Escalate {
+                    suppressed: mem::take(&mut t.suppressed),
+                };
+            }
+
+            match t.reported_at {
+                Some(at) if now - at < REPORT_INTERVAL => {
+                    t.suppressed = t.suppressed.saturating_add(1);
+                    Verdict::Suppress
+                }
+                _ => {
+                    t.reported_at = Some(now);
+                    Verdict::Report {
+                        suppressed: mem::take(&mut t.suppressed),
+                    }
+                }
+            }
+        })
+    }
+
+    pub fn counts(&self) -> IncidentCounts {
+        IncidentCounts {
+            counts: self.counts.lock(|counts| *counts.borrow()),
+        }
+    }
+}
@@ -0,0 +1,315 @@
+// Optical flow sensor
+//
+// Builds with the optical-flow feature carry a PixArt PMW3901 (or the PAA5100, same
+// registers) on SPI, looking straight down next to the rangefinder. Breakouts sold as
+// I2C ones have the same chip behind a bridge, so they're not of much use here. The
+// picture is turned into copter_core::flow::Flow, which the drift hold flies on along
+// with the rangefinder height (see copter_core::control).
+//
+// Only sampling while armed. It doesn't draw much, but there's nothing to see before.
+
+use defmt::{info, unwrap, warn};
+use embassy_futures::select::{select, Either};
+use embassy_nrf::{
+    gpio::{Level, Output, OutputDrive},
+    spim::{self, Spim},
+};
+use embassy_time::{Duration, Instant, Ticker, Timer};
+
+use copter_core::flow::Flow;
+
+use crate::{
+    board::{self, FlowResources},
+    state::SystemState,
+    taskstats::{self, Task},
+    utils, Irqs,
+};
+
+// Sensor frame rate is way up, counts pile up in between
+const POLL_RATE_HZ: u64 = 100;
+// Couple of readings, the drift hold lets go after that
+const STALE_AFTER: Duration = Duration::from_millis(50);
+// 35 pixels over ~42°, and a count is about a pixel
+const RAD_PER_COUNT: f32 = 0.0205;
+// Below that the floor is too dark or too plain to trust, or so PX4 reckons
+const MIN_SURFACE_QUALITY: u8 = 0x19;
+// Shutter all the way open, same
+const MAX_SHUTTER_UPPER: u8 = 0x1f;
+
+#[derive(Clone, Copy)]
+pub struct FlowSample {
+    pub flow: Flow,
+    pub at: Instant,
+}
+
+impl FlowSample {
+    pub fn fresh(&self) -> bool {
+        self.at.elapsed() < STALE_AFTER
+    }
+}
+
+// PixArt's init sequence, undocumented. 0x7f is the bank, the 100ms wait goes between
+// the two halves
+const INIT_FIRST: &[(u8, u8)] = &[
+    (0x7f, 0x00),
+    (0x61, 0xad),
+    (0x7f, 0x03),
+    (0x40, 0x00),
+    (0x7f, 0x05),
+    (0x41, 0xb3),
+    (0x43, 0xf1),
+    (0x45, 0x14),
+    (0x5b, 0x32),
+    (0x5f, 0x34),
+    (0x7b, 0x08),
+    (0x7f, 0x06),
+    (0x44, 0x1b),
+    (0x40, 0xbf),
+    (0x4e, 0x3f),
+    (0x7f, 0x08),
+    (0x65, 0x20),
+    (0x6a, 0x18),
+    (0x7f, 0x09),
+    (0x4f, 0xaf),
+    (0x5f, 0x40),
+    (0x48, 0x80),
+    (0x49, 0x80),
+    (0x57, 0x77),
+    (0x60, 0x78),
+    (0x61, 0x78),
+    (0x62, 0x08),
+    (0x63, 0x50),
+    (0x7f, 0x0a),
+    (0x45, 0x60),
+    (0x7f, 0x00),
+    (0x4d, 0x11),
+    (0x55, 0x80),
+    (0x74, 0x1f),
+    (0x75, 0x1f),
+    (0x4a, 0x78),
+    (0x4b, 0x78),
+    (0x44, 0x08),
+    (0x45, 0x50),
+    (0x64, 0xff),
+    (0x65, 0x1f),
+    (0x7f, 0x14),
+    (0x65, 0x60),
+    (0x66, 0x08),
+    (0x63, 0x78),
+    (0x7f, 0x15),
+    (0x48, 0x58),
+    (0x7f, 0x07),
+    (0x41, 0x0d),
+    (0x43, 0x14),
+    (0x4b, 0x0e),
+    (0x45, 0x0f),
+    (0x44, 0x42),
+    (0x4c, 0x80),
+    (0x7f, 0x10),
+    (0x5b, 0x02),
+    (0x7f, 0x07),
+    (0x40, 0x41),
+    (0x70, 0x00),
+];
+
+const INIT_SECOND: &[(u8, u8)] = &[
+    (0x32, 0x44),
+    (0x7f, 0x07),
+    (0x40, 0x40),
+    (0x7f, 0x06),
+    (0x62, 0xf0),
+    (0x63, 0x00),
+    (0x7f, 0x0d),
+    (0x48, 0xc0),
+    (0x6f, 0xd5),
+    (0x7f, 0x00),
+    (0x5b, 0xa0),
+    (0x4e, 0xa8),
+    (0x5a, 0x50),
+    (0x40, 0x80),
+];
+
+const PRODUCT_ID: u8 = 0x00;
+const INVERSE_PRODUCT_ID: u8 = 0x5f;
+const POWER_UP_RESET: u8 = 0x3a;
+const MOTION: u8 = 0x02;
+const MOTION_BURST: u8 = 0x16;
+
+// As it comes out of the motion burst
+struct Motion {
+    // Counts since the last read, sensor axes
+    delta: [i16; 2],
+    surface_quality: u8,
+    shutter_upper: u8,
+}
+
+impl Motion {
+    fn trusted(&self) -> bool {
+        self.surface_quality >= MIN_SURFACE_QUALITY && self.shutter_upper < MAX_SHUTTER_UPPER
+    }
+}
+
+struct FlowSensor<'a> {
+    spi: Spim<'a>,
+    cs: Output<'a>,
+}
+
+impl<'a> FlowSensor<'a> {
+    async fn read(&mut self, register: u8, buf: &mut [u8]) -> Result<(), spim::Error> {
+        self.cs.set_low();
+
+        // Sensor needs a moment between the address and the data
+        let result = async {
+            self.spi.write(&[register & 0x7f]).await?;
+            Timer::after_micros(50).await;
+            self.spi.read(buf).await
+        }
+        .await;
+
+        self.cs.set_high();
+        Timer::after_micros(20).await;
+        result
+    }
+
+    async fn read_u8(&mut self, register: u8) -> Result<u8, spim::Error> {
+        let mut buf = [0];
+        self.read(register, &mut buf).await?;
+        Ok(buf[0])
+    }
+
+    async fn write_u8(&mut self, register: u8, value: u8) -> Result<(), spim::Error> {
+        self.cs.set_low();
+        let result = self.spi.write(&[register | 0x80, value]).await;
+        self.cs.set_high();
+
+        Timer::after_micros(20).await;
+        result
+    }
+
+    async fn write_all(&mut self, values: &[(u8, u8)]) -> Result<(), spim::Error> {
+        for &(register, value) in values {
+            self.write_u8(register, value).await?;
+        }
+
+        Ok(())
+    }
+
+    // Whether it's there at all, and came up
+    async fn init(&mut self) -> Result<bool, spim::Error> {
+        self.write_u8(POWER_UP_RESET, 0x5a).await?;
+        Timer::after_millis(5).await;
+
+        let id = self.read_u8(PRODUCT_ID).await?;
+        let inverse = self.read_u8(INVERSE_PRODUCT_ID).await?;
+
+        if id != 0x49 || inverse != 0xb6 {
+            return Ok(false);
+        }
+
+        // Motion registers have to be read once after the reset
+        let mut buf = [0; 5];
+        self.read(MOTION, &mut buf).await?;
+
+        self.write_all(INIT_FIRST).await?;
+        Timer::after_millis(100).await;
+        self.write_all(INIT_SECOND).await?;
+
+        Ok(true)
+    }
+
+    async fn motion(&mut self) -> Result<Motion, spim::Error> {
+        let mut buf = [0; 12];
+        self.read(MOTION_BURST, &mut buf).await?;
+
+        Ok(Motion {
+            delta: [
+                i16::from_le_bytes([buf[2], buf[3]]),
+                i16::from_le_bytes([buf[4], buf[5]]),
+            ],
+            surface_quality: buf[6],
+            shutter_upper: buf[10],
+        })
+    }
+}
+
+#[embassy_executor::task]
+pub async fn run(state: &'static SystemState, r: FlowResources) {
+    taskstats::accounted(Task::Flow, task(state, r)).await
+}
+
+async fn task(state: &'static SystemState, r: FlowResources) {
+    if !cfg!(feature = "optical-flow") {
+        return;
+    }
+
+    let mut config = spim::Config::default();
+    config.frequency = spim::Frequency::M2;
+    config.mode = spim::MODE_3;
+
+    let mut sensor = FlowSensor {
+        spi: Spim::new(r.spi, Irqs, r.sck, r.miso, r.mosi, config),
+        cs: Output::new(r.cs, Level::High, OutputDrive::Standard),
+    };
+
+    match sensor.init().await {
+        Ok(true) => info!("flow sensor running..."),
+        Ok(false) => {
+            warn!("no flow sensor found");
+            return;
+        }
+        Err(e) => {
+            warn!("unable to set up the flow sensor - {}", e);
+            return;
+        }
+    }
+
+    let mut armed_receiver = unwrap!(state.armed.receiver());
+
+    loop {
+        utils::wait_for(&mut armed_receiver, |armed| *armed).await;
+
+        let disarmed = armed_receiver.changed_and(|armed| !*armed);
+
+        // Stale samples are as good as none, the drift hold lets go on its own
+        if let Either::First(Err(e)) = select(sample(state, &mut sensor), disarmed).await {
+            warn!("unable to read the flow sensor - {}", e);
+            utils::wait_for(&mut armed_receiver, |armed| !*armed).await;
+        }
+    }
+}
+
+async fn sample(state: &SystemState, sensor: &mut FlowSensor<'_>) -> Result<(), spim::Error> {
+    let flow_sender = state.flow.sender();
+    let mut ticker = Ticker::every(Duration::from_hz(POLL_RATE_HZ));
+
+    // Whatever piled up while disarmed
+    sensor.motion().await?;
+    let mut last = Instant::now();
+
+    loop {
+        ticker.next().await;
+
+        let motion = sensor.motion().await?;
+        let now = Instant::now();
+        let dt = (now - last).as_micros() as f32 / 1_000_000.0;
+        last = now;
+
+        if !motion.trusted() {
+            continue;
+        }
+
+        let picture = board::FLOW_AXES
+            .map(|(axis, sign)| motion.delta[axis] as f32 * sign * RAD_PER_COUNT / dt);
+
+        // Only the picture to go by without an IMU, the airframe doesn't tilt that much
+        let rates = match state.imu.try_get().filter(|s| s.fresh()) {
+            Some(imu) => [imu.gyro[0].to_radians(), imu.gyro[1].to_radians()],
+            None => [0.0; 2],
+        };
+
+        flow_sender.send(FlowSample {
+            flow: Flow::new(picture, rates),
+            at: now,
+        });
+    }
+}
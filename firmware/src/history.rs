@@ -0,0 +1,328 @@
+// Pre-crash history
+//
+// The control task keeps the last few seconds of what it was doing in a RAM ring,
+// decimated down from the control loop. Once a crash is detected the ring is frozen and
+// copied to flash, so there's something to look at even if nobody was logging. The ring
+// is kept in retained RAM, panics and hard faults freeze it on their way out and the
+// copy is made after the reboot. Only the latest dump is kept.
+
+use core::{
+    mem::{size_of, MaybeUninit},
+    ptr,
+};
+
+use embedded_storage_async::nor_flash::{NorFlash, ReadNorFlash};
+use nrf_softdevice::{Flash, FlashError};
+
+use crate::clock::{self, DeviceTime};
+
+extern "C" {
+    // Provided by memory.x
+    static __history_start: u32;
+    static __history_end: u32;
+}
+
+const RING_MAGIC: u32 = 0x5107_4157;
+const DUMP_MAGIC: u32 = 0x5107_d0c5;
+
+#[repr(u8)]
+#[derive(Clone, Copy, PartialEq, defmt::Format)]
+pub enum HistoryTrigger {
+    Crash = 1,
+    Panic = 2,
+    HardFault = 3,
+}
+
+impl HistoryTrigger {
+    fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            1 => Some(Self::Crash),
+            2 => Some(Self::Panic),
+            3 => Some(Self::HardFault),
+            _ => None,
+        }
+    }
+}
+
+#[repr(C, packed)]
+#[derive(Clone, Copy, Default)]
+pub struct HistorySample {
+    // Same units the PID works with, zero while the gyro is not read
+    pub yaw_rate: i16,
+    pub throttle: i16,
+    // Sticks, after the pilot profile. Pitch goes to the tail rotor as it is
+    pub yaw: i16,
+    pub pitch: i16,
+    // Duty cycles as asked for, before clamping
+    pub rotor1: i16,
+    pub rotor2: i16,
+    // Low battery and undervoltage recovery hold the throttle below that
+    pub throttle_limit: i16,
+    // mV, only measured once a second
+    pub rail_voltage: u16,
+}
+
+pub const HISTORY_LEN: usize = 200;
+
+#[repr(C)]
+struct Retained {
+    magic: u32,
+    // HistoryTrigger once frozen, zero while recording
+    trigger: u8,
+    _reserved: [u8; 3],
+    // Where the next sample goes, and how many of them are there
+    next: u32,
+    len: u32,
+    samples: [HistorySample; HISTORY_LEN],
+}
+
+#[link_section = ".uninit.HISTORY"]
+static mut RETAINED: MaybeUninit<Retained> = MaybeUninit::uninit();
+
+fn retained() -> *mut Retained {
+    unsafe { ptr::addr_of_mut!(RETAINED).cast() }
+}
+
+unsafe fn reset(r: *mut Retained) {
+    ptr::write_volatile(ptr::addr_of_mut!((*r).trigger), 0);
+    ptr::write_volatile(ptr::addr_of_mut!((*r).next), 0);
+    ptr::write_volatile(ptr::addr_of_mut!((*r).len), 0);
+    ptr::write_volatile(ptr::addr_of_mut!((*r).magic), RING_MAGIC);
+}
+
+// Has to be called once early at boot. A frozen ring is left for the settings to dump
+pub fn init() {
+    unsafe {
+        let r = retained();
+
+        // Power-on leaves garbage there
+        let valid = ptr::read_volatile(ptr::addr_of!((*r).magic)) == RING_MAGIC
+            && (ptr::read_volatile(ptr::addr_of!((*r).next)) as usize) < HISTORY_LEN
+            && (ptr::read_volatile(ptr::addr_of!((*r).len)) as usize) <= HISTORY_LEN;
+
+        if !valid || frozen().is_none() {
+            reset(r);
+        }
+    }
+}
+
+pub fn frozen() -> Option<HistoryTrigger> {
+    unsafe { HistoryTrigger::from_u8(ptr::read_volatile(ptr::addr_of!((*retained()).trigger))) }
+}
+
+// Ignored while frozen, there's nobody to make room until the dump is done
+pub fn record(sample: HistorySample) {
+    if frozen().is_some() {
+        return;
+    }
+
+    unsafe {
+        let r = retained();
+        let next = ptr::read_volatile(ptr::addr_of!((*r).next)) as usize;
+        let len = ptr::read_volatile(ptr::addr_of!((*r).len)) as usize;
+
+        ptr::write_volatile(ptr::addr_of_mut!((*r).samples[next]), sample);
+        ptr::write_volatile(
+            ptr::addr_of_mut!((*r).next),
+            ((next + 1) % HISTORY_LEN) as u32,
+        );
+        ptr::write_volatile(
+            ptr::addr_of_mut!((*r).len),
+            (len + 1).min(HISTORY_LEN) as u32,
+        );
+    }
+}
+
+// Every flight starts with an empty ring, whatever was before has nothing to do with it
+pub fn restart() {
+    if frozen().is_none() {
+        unsafe { reset(retained()) };
+    }
+}
+
+// Safe to call from the panic and hard fault handlers. Tells whether there is
+// anything to dump
+pub fn freeze(trigger: HistoryTrigger) -> bool {
+    if frozen().is_some() {
+        return false;
+    }
+
+    unsafe {
+        let r = retained();
+
+        if ptr::read_volatile(ptr::addr_of!((*r).len)) == 0 {
+            return false;
+        }
+
+        ptr::write_volatile(ptr::addr_of_mut!((*r).trigger), trigger as u8);
+    }
+
+    true
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct HistoryHeader {
+    magic: u32,
+    // Zero if there's no dump
+    pub trigger: u8,
+    _reserved: [u8; 3],
+    // Same as with the black box, tells dumps apart across power cycles
+    pub boot: u32,
+    pub device_time: DeviceTime,
+    pub len: u32,
+}
+
+impl HistoryHeader {
+    const EMPTY: Self = Self {
+        magic: 0,
+        trigger: 0,
+        _reserved: [0; 3],
+        boot: 0,
+        device_time: DeviceTime { seconds: 0 },
+        len: 0,
+    };
+}
+
+pub const HISTORY_PAGE_LEN: usize = 20;
+pub const HISTORY_PAGES: usize = HISTORY_LEN / HISTORY_PAGE_LEN;
+
+// Dump is too large for a single read, so clients pick a page and read it back.
+// Samples past the end of the dump are zeroed
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+pub struct HistoryPage {
+    pub header: HistoryHeader,
+    pub page: u32,
+    pub samples: [HistorySample; HISTORY_PAGE_LEN],
+}
+
+// Header goes first in the page, the samples follow with the oldest first
+const PAGE_SIZE: u32 = 4096;
+const HEADER_SIZE: u32 = size_of::<HistoryHeader>() as u32;
+const SAMPLE_SIZE: u32 = size_of::<HistorySample>() as u32;
+
+const _: () = assert!(HEADER_SIZE % 4 == 0 && SAMPLE_SIZE % 4 == 0);
+const _: () = assert!(HEADER_SIZE + SAMPLE_SIZE * HISTORY_LEN as u32 <= PAGE_SIZE);
+
+fn history_region() -> (u32, u32) {
+    unsafe {
+        (
+            &__history_start as *const u32 as u32,
+            &__history_end as *const u32 as u32,
+        )
+    }
+}
+
+async fn write_dump(flash: &mut Flash, boot: u32) -> Result<(), FlashError> {
+    let r = retained();
+    let (start, _) = history_region();
+
+    let (trigger, next, len) = unsafe {
+        (
+            ptr::read_volatile(ptr::addr_of!((*r).trigger)),
+            ptr::read_volatile(ptr::addr_of!((*r).next)) as usize,
+            ptr::read_volatile(ptr::addr_of!((*r).len)) as usize,
+        )
+    };
+
+    // Nothing is recorded while frozen, so the samples can be written right from there
+    let samples = unsafe {
+        core::slice::from_raw_parts(
+            ptr::addr_of!((*r).samples) as *const u8,
+            size_of::<[HistorySample; HISTORY_LEN]>(),
+        )
+    };
+
+    // Until the ring fills up the oldest one is the first
+    let oldest = match len < HISTORY_LEN {
+        true => 0,
+        false => next,
+    };
+
+    let size = SAMPLE_SIZE as usize;
+    let older = &samples[oldest * size..(oldest + len).min(HISTORY_LEN) * size];
+    let newer = &samples[..(oldest + len).saturating_sub(HISTORY_LEN) * size];
+
+    flash.erase(start, start + PAGE_SIZE).await?;
+    flash.write(start + HEADER_SIZE, older).await?;
+
+    if !newer.is_empty() {
+        flash
+            .write(start + HEADER_SIZE + older.len() as u32, newer)
+            .await?;
+    }
+
+    // Last thing to go in, so a torn dump doesn't pass for a good one
+    let header = HistoryHeader {
+        magic: DUMP_MAGIC,
+        trigger,
+        _reserved: [0; 3],
+        boot,
+        device_time: clock::device_time(),
+        len: len as u32,
+    };
+
+    let bytes = unsafe {
+        core::slice::from_raw_parts(
+            &header as *const HistoryHeader as *const u8,
+            size_of::<HistoryHeader>(),
+        )
+    };
+
+    flash.write(start, bytes).await
+}
+
+// The flash belongs to the settings task, which is the one driving this. Recording
+// starts over whether the dump made it or not
+pub async fn dump(flash: &mut Flash, boot: u32) -> Result<(), FlashError> {
+    let result = write_dump(flash, boot).await;
+
+    unsafe { reset(retained()) };
+    result
+}
+
+pub async fn read_page(flash: &mut Flash, page: u32) -> Result<HistoryPage, FlashError> {
+    let (start, _) = history_region();
+
+    let mut result = HistoryPage {
+        header: HistoryHeader::EMPTY,
+        page,
+        samples: [HistorySample::default(); HISTORY_PAGE_LEN],
+    };
+
+    let mut header = HistoryHeader::EMPTY;
+    let bytes = unsafe {
+        core::slice::from_raw_parts_mut(
+            &mut header as *mut HistoryHeader as *mut u8,
+            size_of::<HistoryHeader>(),
+        )
+    };
+
+    flash.read(start, bytes).await?;
+
+    if header.magic != DUMP_MAGIC {
+        return Ok(result);
+    }
+
+    let first = (page as usize * HISTORY_PAGE_LEN).min(HISTORY_LEN);
+    let count = (header.len as usize).min(HISTORY_LEN).saturating_sub(first);
+    let count = count.min(HISTORY_PAGE_LEN);
+
+    let mut samples = [HistorySample::default(); HISTORY_PAGE_LEN];
+    let bytes = unsafe {
+        core::slice::from_raw_parts_mut(
+            samples.as_mut_ptr() as *mut u8,
+            count * SAMPLE_SIZE as usize,
+        )
+    };
+
+    flash
+        .read(start + HEADER_SIZE + first as u32 * SAMPLE_SIZE, bytes)
+        .await?;
+
+    result.header = header;
+    result.samples = samples;
+
+    Ok(result)
+}
@@ -0,0 +1,432 @@
+// External IMU
+//
+// Some builds carry a 6-axis IMU on the gauge's I2C bus. Its rate data beats the analog
+// gyro by far, three axes and no ADC noise, so once it's calibrated it becomes the rate
+// sensor (see RateSensor) and the control loop takes the yaw rate from here, going back
+// to the analog gyro whenever the samples are late (see Controller::yaw_rate()). ST
+// LSM6DS3 and its relatives and TDK ICM-42688 are known, they are told apart by
+// WHO_AM_I. Nothing on the bus means there's nothing to do, same firmware either way.
+//
+// Bias is measured at every boot. Calibrating the gyro on purpose stores it as well,
+// separately from the analog offset, and that's what it flies on if it's carried around
+// at boot.
+//
+// Samples go through the attitude filter (copter_core::attitude) on the way out, so the
+// control loop gets roll, pitch and heading along with the rates. Clients get them too,
+// at a rate the link can take.
+
+use core::{array, future::pending};
+
+use copter_core::attitude::{Attitude, Mahony};
+use defmt::{info, unwrap, warn};
+use embassy_futures::select::select;
+use embassy_nrf::twim;
+use embassy_time::{Duration, Instant, Ticker, Timer};
+use embedded_hal_async::i2c::I2c;
+
+use crate::{
+    board,
+    state::{Request, StateReceiver, SystemState},
+    taskstats::{self, Task},
+    types::{ImuGyroBias, RateSensor},
+    utils, SharedI2cBus,
+};
+
+// Same as the control loop, nothing would make use of anything faster
+const SAMPLE_RATE_HZ: u64 = 200;
+// Couple of control loop ticks, anything older is not worth flying on
+const STALE_AFTER: Duration = Duration::from_millis(15);
+// Every that many samples go to the clients, ~10 Hz
+const ATTITUDE_REPORT_DIVIDER: u32 = 20;
+
+// ~1s of sitting still at boot
+const CALIBRATION_SAMPLES: usize = 200;
+const CALIBRATION_ATTEMPTS: usize = 5;
+// dps, gyro readings spread wider than that mean someone's carrying it around
+const STILL_SPREAD: f32 = 10.0;
+
+#[derive(Clone, Copy)]
+pub struct ImuSample {
+    // dps and g, forward, left and up (see the board), with the biases taken out
+    pub gyro: [f32; 3],
+    pub accel: [f32; 3],
+    // As of this sample
+    pub attitude: Attitude,
+    // g along the world up, gravity taken out
+    pub vertical_accel: f32,
+    // Cosine of the tilt, see Mahony::tilt()
+    pub tilt: f32,
+    pub at: Instant,
+}
+
+impl ImuSample {
+    // Same sense as the analog gyro, see the board
+    pub fn yaw_rate(&self) -> f32 {
+        self.gyro[2] * board::IMU_YAW_SIGN
+    }
+
+    pub fn fresh(&self) -> bool {
+        self.at.elapsed() < STALE_AFTER
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, defmt::Format)]
+enum Chip {
+    Lsm6ds3,
+    Icm42688,
+}
+
+// Gyro and accel, as they come
+type Raw = ([i16; 3], [i16; 3]);
+
+impl Chip {
+    // Depends on how SA0 / AD0 is strapped
+    fn addresses(self) -> [u8; 2] {
+        match self {
+            Self::Lsm6ds3 => [0x6a, 0x6b],
+            Self::Icm42688 => [0x68, 0x69],
+        }
+    }
+
+    fn who_am_i_register(self) -> u8 {
+        match self {
+            Self::Lsm6ds3 => 0x0f,
+            Self::Icm42688 => 0x75,
+        }
+    }
+
+    fn knows(self, id: u8) -> bool {
+        match self {
+            // LSM6DS3, LSM6DSL / LSM6DSM, LSM6DSO
+            Self::Lsm6ds3 => matches!(id, 0x69 | 0x6a | 0x6c),
+            Self::Icm42688 => id == 0x47,
+        }
+    }
+
+    // Register, value. Both end up at ±2000 dps and ±8 g
+    fn setup(self) -> &'static [(u8, u8)] {
+        match self {
+            Self::Lsm6ds3 => &[
+                // Block data update, address auto-increment
+                (0x12, 0x44),
+                // Accel at 416 Hz
+                (0x10, 0x6c),
+                // Gyro at 416 Hz
+                (0x11, 0x6c),
+            ],
+            Self::Icm42688 => &[
+                // Gyro at 1 kHz
+                (0x4f, 0x06),
+                // Accel at 1 kHz
+                (0x50, 0x26),
+                // Both in the low noise mode, has to go last
+                (0x4e, 0x0f),
+            ],
+        }
+    }
+
+    // First of the 12 data bytes
+    fn data_register(self) -> u8 {
+        match self {
+            Self::Lsm6ds3 => 0x22,
+            Self::Icm42688 => 0x1f,
+        }
+    }
+
+    fn dps_per_lsb(self) -> f32 {
+        match self {
+            Self::Lsm6ds3 => 0.07,
+            Self::Icm42688 => 1.0 / 16.4,
+        }
+    }
+
+    fn g_per_lsb(self) -> f32 {
+        match self {
+            Self::Lsm6ds3 => 0.000244,
+            Self::Icm42688 => 1.0 / 4096.0,
+        }
+    }
+
+    // ST goes gyro first and little endian, TDK the other way around on both
+    fn decode(self, buf: &[u8; 12]) -> Raw {
+        let le = |i: usize| i16::from_le_bytes([buf[i], buf[i + 1]]);
+        let be = |i: usize| i16::from_be_bytes([buf[i], buf[i + 1]]);
+
+        match self {
+            Self::Lsm6ds3 => ([le(0), le(2), le(4)], [le(6), le(8), le(10)]),
+            Self::Icm42688 => ([be(6), be(8), be(10)], [be(0), be(2), be(4)]),
+        }
+    }
+}
+
+struct Imu<'a> {
+    i2c: &'a SharedI2cBus,
+    chip: Chip,
+    address: u8,
+}
+
+impl<'a> Imu<'a> {
+    async fn probe(i2c: &'a SharedI2cBus) -> Option<Self> {
+        for chip in [Chip::Lsm6ds3, Chip::Icm42688] {
+            for address in chip.addresses() {
+                let mut id = [0];
+                let r = i2c
+                    .lock()
+                    .await
+                    .write_read(address, &[chip.who_am_i_register()], &mut id)
+                    .await;
+
+                if r.is_ok() && chip.knows(id[0]) {
+                    return Some(Self { i2c, chip, address });
+                }
+            }
+        }
+
+        None
+    }
+
+    async fn configure(&self) -> Result<(), twim::Error> {
+        for &(register, value) in self.chip.setup() {
+            self.i2c
+                .lock()
+                .await
+                .write(self.address, &[register, value])
+                .await?;
+        }
+
+        // Gyros take a moment to start up
+        Timer::after_millis(50).await;
+        Ok(())
+    }
+
+    async fn read(&self) -> Result<Raw, twim::Error> {
+        let mut buf = [0; 12];
+
+        self.i2c
+            .lock()
+            .await
+            .write_read(self.address, &[self.chip.data_register()], &mut buf)
+            .await?;
+
+        Ok(self.chip.decode(&buf))
+    }
+}
+
+struct Calibration {
+    gyro: [f32; 3],
+    accel: [f32; 3],
+}
+
+// Sensor axes to the airframe ones
+fn to_body(v: [i16; 3], scale: f32) -> [f32; 3] {
+    board::IMU_AXES.map(|(axis, sign)| v[axis] as f32 * scale * sign)
+}
+
+impl Calibration {
+    // Attitude is up to the filter
+    fn apply(&self, chip: Chip, (gyro, accel): Raw) -> ImuSample {
+        let gyro = to_body(gyro, chip.dps_per_lsb());
+        let accel = to_body(accel, chip.g_per_lsb());
+
+        ImuSample {
+            gyro: array::from_fn(|i| gyro[i] - self.gyro[i]),
+            accel: array::from_fn(|i| accel[i] - self.accel[i]),
+            attitude: Attitude::default(),
+            vertical_accel: 0.0,
+            tilt: 1.0,
+            at: Instant::now(),
+        }
+    }
+}
+
+// Has to sit still and level, which is how it's left on the ground when switched on.
+// None if it didn't hold still
+async fn calibrate(imu: &Imu<'_>) -> Result<Option<Calibration>, twim::Error> {
+    let none = Calibration {
+        gyro: [0.0; 3],
+        accel: [0.0; 3],
+    };
+
+    let mut ticker = Ticker::every(Duration::from_hz(SAMPLE_RATE_HZ));
+    let mut gyro = [0.0; 3];
+    let mut accel = [0.0; 3];
+    let (mut low, mut high) = ([f32::MAX; 3], [f32::MIN; 3]);
+
+    for _ in 0..CALIBRATION_SAMPLES {
+        ticker.next().await;
+
+        let sample = none.apply(imu.chip, imu.read().await?);
+
+        for i in 0..3 {
+            gyro[i] += sample.gyro[i] / CALIBRATION_SAMPLES as f32;
+            accel[i] += sample.accel[i] / CALIBRATION_SAMPLES as f32;
+            low[i] = low[i].min(sample.gyro[i]);
+            high[i] = high[i].max(sample.gyro[i]);
+        }
+    }
+
+    if (0..3).any(|i| high[i] - low[i] > STILL_SPREAD) {
+        return Ok(None);
+    }
+
+    // Gravity is not a bias
+    accel[2] -= 1.0;
+
+    Ok(Some(Calibration { gyro, accel }))
+}
+
+#[embassy_executor::task]
+pub async fn run(state: &'static SystemState, i2c: &'static SharedI2cBus) {
+    taskstats::accounted(Task::Imu, task(state, i2c)).await
+}
+
+async fn task(state: &'static SystemState, i2c: &'static SharedI2cBus) {
+    let Some(imu) = Imu::probe(i2c).await else {
+        info!("no IMU on the bus, staying with the analog gyro");
+        return;
+    };
+
+    info!("found {} at {=u8:#x}", imu.chip, imu.address);
+
+    if let Err(e) = imu.configure().await {
+        warn!("unable to configure the IMU - {}", e);
+        return;
+    }
+
+    state.imu_found.sender().send(true);
+
+    let mut calibration = None;
+
+    for _ in 0..CALIBRATION_ATTEMPTS {
+        match calibrate(&imu).await {
+            Ok(Some(c)) => {
+                calibration = Some(c);
+                break;
+            }
+            Ok(None) => info!("IMU is not holding still, calibrating again"),
+            Err(e) => warn!("unable to read the IMU - {}", e),
+        }
+
+        Timer::after_secs(1).await;
+    }
+
+    // Gyro bias from the last time it was calibrated on purpose is good enough to fly
+    // on. Accel biases are small next to gravity, the filter copes without them
+    if calibration.is_none() {
+        calibration = state
+            .imu_gyro_bias
+            .try_get()
+            .and_then(|b| b.get())
+            .map(|gyro| Calibration {
+                gyro,
+                accel: [0.0; 3],
+            });
+
+        if calibration.is_some() {
+            info!("IMU is not holding still, going with the stored bias");
+        }
+    }
+
+    let rate_sensor_sender = state.rate_sensor.sender();
+    let mut armed_receiver = unwrap!(state.armed.receiver());
+
+    loop {
+        match &calibration {
+            Some(c) => {
+                info!("IMU calibrated, gyro bias {} dps", c.gyro.map(|b| b as i16));
+                rate_sensor_sender.send(RateSensor::Digital);
+            }
+            None => {
+                warn!("IMU couldn't be calibrated, staying with the analog gyro");
+                rate_sensor_sender.send(RateSensor::Analog);
+            }
+        }
+
+        let flying = async {
+            match &calibration {
+                Some(c) => sample(state, &imu, c, &mut armed_receiver).await,
+                None => pending().await,
+            }
+        };
+
+        // Control task only passes it on while disarmed, see control.rs
+        select(flying, state.calibrate_imu.wait()).await;
+
+        let done = match calibrate(&imu).await {
+            Ok(Some(c)) => {
+                state
+                    .requests
+                    .sender()
+                    .send(Request::ImuGyroBiasUpdate(ImuGyroBias::from(c.gyro)));
+
+                calibration = Some(c);
+                true
+            }
+            Ok(None) => {
+                warn!("IMU is not holding still");
+                false
+            }
+            Err(e) => {
+                warn!("unable to read the IMU - {}", e);
+                false
+            }
+        };
+
+        state.imu_calibration_report.signal(done);
+    }
+}
+
+async fn sample(
+    state: &SystemState,
+    imu: &Imu<'_>,
+    calibration: &Calibration,
+    armed_receiver: &mut StateReceiver<'_, bool>,
+) {
+    let imu_sender = state.imu.sender();
+    let attitude_sender = state.attitude.sender();
+
+    utils::sample_while_armed(armed_receiver, SAMPLE_RATE_HZ, async |sampler| {
+        // Without a magnetometer, heading starts over with every arming and is only
+        // good for holding it. With one it's magnetic, see compass.rs
+        let mut filter = Mahony::default();
+        let mut last: Option<Instant> = None;
+
+        loop {
+            // Control loop goes back to the analog gyro once the samples are stale
+            let Some(raw) = sampler.next("IMU", imu.read()).await else {
+                continue;
+            };
+
+            let mut sample = calibration.apply(imu.chip, raw);
+            let mag = state
+                .compass
+                .try_get()
+                .filter(|s| s.fresh())
+                .map(|s| s.field);
+
+            // Reads that failed are skipped over, not made up for
+            match (last, mag) {
+                (Some(at), Some(mag)) => {
+                    let dt = (sample.at - at).as_micros() as f32 / 1_000_000.0;
+                    filter.update_with_mag(sample.gyro, sample.accel, mag, dt);
+                }
+                (Some(at), None) => {
+                    let dt = (sample.at - at).as_micros() as f32 / 1_000_000.0;
+                    filter.update(sample.gyro, sample.accel, dt);
+                }
+                (None, mag) => filter.align(sample.accel, mag),
+            }
+
+            last = Some(sample.at);
+            sample.attitude = filter.attitude();
+            sample.vertical_accel = filter.vertical_accel(sample.accel);
+            sample.tilt = filter.tilt();
+            imu_sender.send(sample);
+
+            if sampler.every(ATTITUDE_REPORT_DIVIDER) {
+                attitude_sender.send(sample.attitude.into());
+            }
+        }
+    })
+    .await;
+}
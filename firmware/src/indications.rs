@@ -1,31 +1,786 @@
+use core::future;
+
+use copter_core::policy::SocStage;
 use defmt::{info, unwrap};
-use embassy_futures::select::select3;
-use embassy_nrf::gpio;
-use embassy_time::Timer;
+use embassy_futures::{
+    join::join,
+    select::{select3, select4, Either4},
+};
+use embassy_nrf::pwm::{self, DutyCycle, SimplePwm};
+use embassy_time::{Duration, Instant, Timer};
 
-use crate::{state::SystemState, LedSwitchResources};
+use crate::{
+    state::{ActivityLevel, Mode, SystemState},
+    taskstats::{self, Task},
+    types::Faults,
+    LedResources, RgbLedResources,
+};
 
-#[embassy_executor::task]
-pub async fn run(state: &'static SystemState, r: LedSwitchResources) {
-    info!("led indications running...");
+// What the LED is telling the user. Ordered by priority, the lowest one goes first
+#[repr(u8)]
+#[derive(Clone, Copy, PartialEq, defmt::Format)]
+pub enum IndicationStyle {
+    // Looking for the controller we know
+    Searching,
+    // Ready to take a new controller
+    PairingMode,
+    ConnectedIdle,
+    ChargeComplete,
+    LowBattery,
+    // Link went away on the ground a while ago and nobody came back for it, so it's
+    // calling out to be found. See LOST_MODEL_AFTER
+    LostModel,
+    BlinkSlow,
+    BlinkFast,
+    Charging,
+    ChargeInhibited,
+    // Airframe has to be kept still until it's over
+    GyroCalibration,
+    Fault,
+    // Switch was held long enough at power-on, settings go away once it's released
+    FactoryReset,
+    // Keeps the LED off no matter what, e.g. when shutting down
+    Disabled,
+}
 
-    let mut soc_receiver = unwrap!(state.soc.receiver());
+impl IndicationStyle {
+    const ALL: [Self; 14] = [
+        Self::Searching,
+        Self::PairingMode,
+        Self::ConnectedIdle,
+        Self::ChargeComplete,
+        Self::LowBattery,
+        Self::LostModel,
+        Self::BlinkSlow,
+        Self::BlinkFast,
+        Self::Charging,
+        Self::ChargeInhibited,
+        Self::GyroCalibration,
+        Self::Fault,
+        Self::FactoryReset,
+        Self::Disabled,
+    ];
+}
+
+// Every style that applies at the moment. Only the one with the highest priority is
+// shown, the rest are waiting for their turn, so nothing is lost when conditions overlap
+#[derive(Clone, Copy, Default, PartialEq)]
+pub struct ActiveIndications(u16);
+
+impl ActiveIndications {
+    pub fn set(&mut self, style: IndicationStyle, active: bool) {
+        let bit = 1 << style as u16;
+
+        match active {
+            true => self.0 |= bit,
+            false => self.0 &= !bit,
+        }
+    }
+
+    pub fn top(&self) -> IndicationStyle {
+        IndicationStyle::ALL
+            .into_iter()
+            .rev()
+            .find(|&style| self.0 & (1 << style as u16) != 0)
+            .unwrap_or(IndicationStyle::Disabled)
+    }
+}
+
+// How each style looks, and sounds with the buzzer feature. Kept in flash and editable
+// over BLE, so some can be toned down or silenced altogether, e.g. for a stealthy night
+// flight
+#[repr(C, packed)]
+#[derive(Clone, Copy, Default)]
+pub struct ThemeEntry {
+    // `count` flashes of `on` ms with `off` ms in between, then `pause` ms of darkness.
+    // Zero count means the LED is on all the time
+    pub on: u16,
+    pub off: u16,
+    pub pause: u16,
+    pub count: u8,
+    // %, zero keeps the style dark
+    pub brightness: u8,
+    // Beeps along with the flashes, in 100 Hz. Zero keeps the style quiet, and so does
+    // the LED being on all the time
+    pub tone: u8,
+    // %
+    pub volume: u8,
+}
+
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+pub struct IndicationTheme {
+    pub entries: [ThemeEntry; IndicationStyle::ALL.len()],
+}
+
+impl IndicationTheme {
+    pub fn entry(&self, style: IndicationStyle) -> ThemeEntry {
+        self.entries[style as usize]
+    }
+
+    pub fn set_entry(&mut self, style: IndicationStyle, entry: ThemeEntry) {
+        self.entries[style as usize] = entry;
+    }
+}
+
+impl Default for IndicationTheme {
+    fn default() -> Self {
+        let flashes = |on, off, count, pause| ThemeEntry {
+            on,
+            off,
+            pause,
+            count,
+            brightness: 100,
+            tone: 0,
+            volume: 0,
+        };
+
+        // Piezos are loudest around 4 kHz, a low battery in the air is worth it
+        let beeps = |on, off, count, pause, tone| ThemeEntry {
+            tone,
+            volume: 100,
+            ..flashes(on, off, count, pause)
+        };
+
+        let mut theme = Self {
+            entries: [ThemeEntry::default(); IndicationStyle::ALL.len()],
+        };
+
+        for style in IndicationStyle::ALL {
+            let entry = match style {
+                IndicationStyle::Disabled => ThemeEntry::default(),
+                IndicationStyle::BlinkFast => beeps(50, 150, 1, 0, 40),
+                IndicationStyle::BlinkSlow => flashes(50, 950, 1, 0),
+                IndicationStyle::Charging => flashes(50, 0, 1, 1950),
+                IndicationStyle::ChargeComplete => flashes(0, 0, 0, 0),
+                IndicationStyle::LowBattery => flashes(50, 150, 2, 1800),
+                IndicationStyle::LostModel => beeps(100, 100, 3, 2400, 30),
+                // Timing is fixed, see FAULT_CODES
+                IndicationStyle::Fault => flashes(0, 0, 0, 0),
+                IndicationStyle::FactoryReset => flashes(30, 30, 1, 0),
+                IndicationStyle::ChargeInhibited => flashes(50, 150, 3, 1000),
+                IndicationStyle::GyroCalibration => flashes(200, 200, 1, 0),
+                IndicationStyle::Searching => flashes(50, 0, 1, 950),
+                IndicationStyle::PairingMode => flashes(50, 80, 2, 300),
+                IndicationStyle::ConnectedIdle => flashes(50, 0, 1, 2950),
+            };
+
+            theme.set_entry(style, entry);
+        }
+
+        theme
+    }
+}
+
+// What the LED does in the air, while nothing needs attention. It's used as an
+// orientation light then, and blinking at full brightness does not help with that
+#[repr(u8)]
+#[derive(Clone, Copy, PartialEq, Default, defmt::Format)]
+pub enum FlightLight {
+    // Whatever the theme says for BlinkSlow
+    #[default]
+    Theme = 0,
+    Off = 1,
+    DimSolid = 2,
+    SlowPulse = 3,
+}
+
+impl FlightLight {
+    pub fn from_u8(v: u8) -> Option<Self> {
+        match v {
+            0 => Some(Self::Theme),
+            1 => Some(Self::Off),
+            2 => Some(Self::DimSolid),
+            3 => Some(Self::SlowPulse),
+            _ => None,
+        }
+    }
+}
+
+// Momentary feedback for events. Shown on top of whatever is going on, and then
+// the active style takes over again
+#[derive(Clone, Copy, defmt::Format)]
+pub enum OneShot {
+    // That many quick flashes
+    Flashes(u8),
+    // One flash per each 10% of charge
+    BatteryLevel,
+    // Short and sweet, for things that are happening in the air
+    Blip,
+    // Goes through the whole brightness range, so a dead LED or channel stands out
+    Sweep,
+    // Something that took a while went well, or did not
+    Confirm,
+    Reject,
+    // Rising and falling beeps, so it's heard from behind the airframe
+    Armed,
+    Disarmed,
+}
+
+// Nobody turns the controller off in the air. Shorter than that is someone done flying
+// and about to switch it off
+const LOST_MODEL_AFTER: Duration = Duration::from_secs(60);
+
+// Fault number is blinked out as N long pulses followed by M short ones, so it can
+// be told over the phone. First digit is the group: 1 - battery, 2 - charging, 3 - self-test,
+// 4 - firmware
+const FAULT_CODES: [(Faults, u8, u8); 8] = [
+    (Faults::BATTERY_OVERTEMP, 1, 1),
+    (Faults::BATTERY_UNDERTEMP, 1, 2),
+    (Faults::CHARGE_TEMPERATURE, 2, 1),
+    (Faults::CHARGER_FAILURE, 2, 2),
+    (Faults::SELF_TEST_GYRO, 3, 1),
+    (Faults::SELF_TEST_GAUGE, 3, 2),
+    (Faults::GYRO_SENSOR, 3, 3),
+    (Faults::ASSERTION, 4, 1),
+];
+
+impl IndicationStyle {
+    // Color on the RGB LED, if there's one. The pattern is the same as on the main LED,
+    // color is there to tell the state from afar
+    fn color(self) -> Color {
+        match self {
+            Self::Searching => Color::BLUE,
+            Self::PairingMode => Color::CYAN,
+            Self::ConnectedIdle | Self::ChargeComplete => Color::GREEN,
+            Self::LowBattery | Self::BlinkFast | Self::ChargeInhibited => Color::AMBER,
+            Self::LostModel => Color::RED,
+            Self::BlinkSlow | Self::GyroCalibration => Color::WHITE,
+            Self::Charging => Color::YELLOW,
+            Self::Fault => Color::RED,
+            Self::FactoryReset => Color::MAGENTA,
+            Self::Disabled => Color::OFF,
+        }
+    }
+
+    pub fn from_u8(v: u8) -> Option<Self> {
+        Self::ALL.into_iter().find(|&style| style as u8 == v)
+    }
+}
+
+// Conditions are tracked independently, priorities sort them out
+fn update_indications(state: &SystemState, lost: bool) {
+    let faults = state.faults.try_get().unwrap_or_default();
+    let charging = matches!(state.charger_state.try_get(), Some(s) if s.charging);
+    let low_battery = matches!(state.soc_stage.try_get(), Some(s) if s >= SocStage::Warn);
+    let mode = state.mode.try_get().unwrap_or(Mode::Booting);
+    let armed = mode.armed();
+    let connected = state.controller_connected.try_get().unwrap_or_default();
+    let gyro_calibrating = state.gyro_calibrating.try_get().unwrap_or_default();
+
+    let conditions = [
+        (IndicationStyle::Disabled, mode == Mode::ShuttingDown),
+        (
+            IndicationStyle::Fault,
+            !faults.difference(Faults::CHARGE_TEMPERATURE).is_empty(),
+        ),
+        (
+            IndicationStyle::ChargeInhibited,
+            faults.contains(Faults::CHARGE_TEMPERATURE),
+        ),
+        (IndicationStyle::GyroCalibration, gyro_calibrating),
+        (IndicationStyle::Charging, charging),
+        // Time to land
+        (IndicationStyle::BlinkFast, armed && low_battery),
+        (IndicationStyle::BlinkSlow, armed),
+        (IndicationStyle::LostModel, lost),
+        (IndicationStyle::LowBattery, low_battery),
+        // Charger does not tell us whether it's still plugged in, but a full pack
+        // that is not charging is as complete as it gets
+        (
+            IndicationStyle::ChargeComplete,
+            !charging && state.soc.try_get() == Some(100),
+        ),
+        (IndicationStyle::ConnectedIdle, connected),
+        (
+            IndicationStyle::PairingMode,
+            mode == Mode::Pairing && !connected,
+        ),
+        // Controller is scanned for whenever it's not there
+        (IndicationStyle::Searching, !connected),
+    ];
+
+    for (style, active) in conditions {
+        state.set_indication(style, active);
+    }
+}
+
+// Keeps the indication in sync with the system state
+async fn follow_state(state: &'static SystemState) {
+    let mut faults_receiver = unwrap!(state.faults.receiver());
     let mut charger_state_receiver = unwrap!(state.charger_state.receiver());
-    let mut controller_connection_receiver = unwrap!(state.controller_connected.receiver());
+    let mut soc_receiver = unwrap!(state.soc.receiver());
+    let mut soc_stage_receiver = unwrap!(state.soc_stage.receiver());
+    let mut mode_receiver = unwrap!(state.mode.receiver());
+    let mut controller_connected_receiver = unwrap!(state.controller_connected.receiver());
+    let mut gyro_calibrating_receiver = unwrap!(state.gyro_calibrating.receiver());
+    let mut remote_engaged_receiver = unwrap!(state.remote_engaged.receiver());
 
-    let mut output = gpio::Output::new(r.led, gpio::Level::Low, gpio::OutputDrive::Standard);
+    // Nothing to go looking for before anything has connected
+    let mut was_connected = false;
+    let mut lost_at: Option<Instant> = None;
 
     loop {
-        // Just blink once per each monitored event for now
-        output.set_high();
-        Timer::after_millis(50).await;
-        output.set_low();
+        let connected = controller_connected_receiver.try_get() == Some(true)
+            || remote_engaged_receiver.try_get() == Some(true);
+
+        if connected {
+            was_connected = true;
+            lost_at = None;
+        } else if was_connected && lost_at.is_none() {
+            lost_at = Some(Instant::now());
+        }
+
+        let lost = lost_at.is_some_and(|at| at.elapsed() >= LOST_MODEL_AFTER);
+        update_indications(state, lost);
+
+        let beacon_due = async {
+            match lost_at {
+                Some(at) if !lost => Timer::at(at + LOST_MODEL_AFTER).await,
+                _ => future::pending().await,
+            }
+        };
 
         select3(
-            soc_receiver.changed(),
-            charger_state_receiver.changed(),
-            controller_connection_receiver.changed(),
+            select4(
+                faults_receiver.changed(),
+                charger_state_receiver.changed(),
+                soc_receiver.changed(),
+                soc_stage_receiver.changed(),
+            ),
+            select4(
+                mode_receiver.changed(),
+                gyro_calibrating_receiver.changed(),
+                controller_connected_receiver.changed(),
+                remote_engaged_receiver.changed(),
+            ),
+            beacon_due,
         )
         .await;
     }
 }
+
+// Channel levels, 0-100
+#[derive(Clone, Copy, PartialEq)]
+struct Color(u8, u8, u8);
+
+impl Color {
+    const OFF: Self = Self(0, 0, 0);
+    const WHITE: Self = Self(100, 100, 100);
+    const RED: Self = Self(100, 0, 0);
+    const GREEN: Self = Self(0, 100, 0);
+    const BLUE: Self = Self(0, 0, 100);
+    const CYAN: Self = Self(0, 60, 100);
+    const MAGENTA: Self = Self(100, 0, 100);
+    const YELLOW: Self = Self(100, 70, 0);
+    const AMBER: Self = Self(100, 30, 0);
+}
+
+// Tone is in 100 Hz, volume in %
+#[derive(Clone, Copy)]
+struct Beep {
+    tone: u8,
+    volume: u8,
+}
+
+struct Led<'a> {
+    // Buzzer is on the second channel, with the buzzer feature
+    pwm: SimplePwm<'a>,
+    // Optional navigation lights, blinking along with the main LED
+    rgb: Option<SimplePwm<'a>>,
+    color: Color,
+    // Scales everything down while charging, canopy is heating up already
+    dim: bool,
+    // PWM period, in 16 MHz ticks. Channels can't have periods of their own, so it's
+    // the tone while beeping and the LED follows along, it can't tell either way
+    top: u16,
+}
+
+impl<'a> Led<'a> {
+    // Plain LED, way above anything visible
+    const LED_TOP: u16 = 100;
+    // Tones below that don't fit into the counter
+    const MIN_TONE: u8 = 5;
+
+    fn on(&mut self, brightness: u8) {
+        let brightness = match self.dim {
+            true => brightness / 4,
+            false => brightness,
+        };
+
+        let brightness = brightness.min(100) as u16;
+        let level = |brightness: u16| (brightness as u32 * self.top as u32 / 100) as u16;
+
+        self.pwm.set_duty(0, DutyCycle::inverted(level(brightness)));
+
+        // Common anode, same as the main LED
+        if let Some(rgb) = &mut self.rgb {
+            let Color(r, g, b) = self.color;
+
+            for (ch, level) in [r, g, b].into_iter().enumerate() {
+                rgb.set_duty(ch, DutyCycle::inverted(brightness * level as u16 / 100));
+            }
+        }
+    }
+
+    // Piezo is loudest at half the period, and goes quiet towards either end
+    fn beep(&mut self, brightness: u8, beep: Beep) {
+        if !cfg!(feature = "buzzer") || beep.tone < Self::MIN_TONE || beep.volume == 0 {
+            return self.on(brightness);
+        }
+
+        self.top = (16_000_000 / (beep.tone as u32 * 100)) as u16;
+        self.pwm.set_max_duty(self.top);
+        self.on(brightness);
+
+        let level = self.top as u32 / 2 * beep.volume.min(100) as u32 / 100;
+        self.pwm.set_duty(1, DutyCycle::normal(level as u16));
+    }
+
+    fn quiet(&mut self) {
+        if cfg!(feature = "buzzer") && self.top != Self::LED_TOP {
+            self.pwm.set_duty(1, DutyCycle::normal(0));
+            self.top = Self::LED_TOP;
+            self.pwm.set_max_duty(self.top);
+        }
+    }
+
+    fn off(&mut self) {
+        self.pwm.set_duty(0, DutyCycle::inverted(0));
+
+        if let Some(rgb) = &mut self.rgb {
+            for ch in 0..3 {
+                rgb.set_duty(ch, DutyCycle::inverted(0));
+            }
+        }
+    }
+
+    async fn fade(&mut self, from: u8, to: u8, duration: u64) {
+        const STEP_MS: u64 = 20;
+
+        let steps = (duration / STEP_MS).max(1);
+
+        for step in 0..=steps {
+            let level = from as i64 + (to as i64 - from as i64) * step as i64 / steps as i64;
+
+            self.on(level as u8);
+            Timer::after_millis(STEP_MS).await;
+        }
+    }
+}
+
+// Patterns are plain data, so a new one is a new table rather than a new function
+#[derive(Clone, Copy)]
+enum Step {
+    // Brightness in %, held for that many ms
+    On(u8, u16),
+    Off(u16),
+    // From and to brightness in %, over that many ms
+    Fade(u8, u8, u16),
+    // Same as On, with the buzzer going
+    Beep(u8, Beep, u16),
+    // Goes back by `steps` and plays them `times` more times. Repeats don't nest
+    Repeat { steps: u8, times: u8 },
+}
+
+const SLOW_PULSE: &[Step] = &[
+    Step::Fade(0, 30, 1500),
+    Step::Fade(30, 0, 1500),
+    Step::Off(500),
+];
+
+const CONFIRM: &[Step] = &[
+    Step::Off(300),
+    Step::Fade(0, 100, 300),
+    Step::On(100, 600),
+    Step::Fade(100, 0, 300),
+    Step::Off(300),
+];
+
+const fn beep(tone: u8, ms: u16) -> Step {
+    Step::Beep(100, Beep { tone, volume: 100 }, ms)
+}
+
+const ARMED: &[Step] = &[
+    Step::Off(100),
+    beep(20, 80),
+    Step::Off(40),
+    beep(30, 80),
+    Step::Off(40),
+    beep(40, 160),
+    Step::Off(100),
+];
+
+const DISARMED: &[Step] = &[
+    Step::Off(100),
+    beep(40, 80),
+    Step::Off(40),
+    beep(30, 80),
+    Step::Off(40),
+    beep(20, 160),
+    Step::Off(100),
+];
+
+const SWEEP: &[Step] = &[
+    Step::Off(200),
+    Step::Fade(0, 100, 500),
+    Step::Fade(100, 0, 500),
+    Step::Off(200),
+];
+
+// Dark gaps around make it stand out from the style that was there before
+const fn one_shot_flashes(count: u8, on: u16, gap: u16) -> [Step; 5] {
+    [
+        Step::Off(gap),
+        Step::On(100, on),
+        Step::Off(250),
+        Step::Repeat {
+            steps: 2,
+            times: count.saturating_sub(1),
+        },
+        Step::Off(gap),
+    ]
+}
+
+// Timing is not configurable, long and short pulses have to be easy to tell apart
+const fn fault_code(brightness: u8, long: u8, short: u8) -> [Step; 8] {
+    [
+        Step::On(brightness, 500),
+        Step::Off(300),
+        Step::Repeat {
+            steps: 2,
+            times: long.saturating_sub(1),
+        },
+        Step::Off(500),
+        Step::On(brightness, 100),
+        Step::Off(300),
+        Step::Repeat {
+            steps: 2,
+            times: short.saturating_sub(1),
+        },
+        Step::Off(2000),
+    ]
+}
+
+impl ThemeEntry {
+    fn script(&self) -> [Step; 4] {
+        let beep = Beep {
+            tone: self.tone,
+            volume: self.volume,
+        };
+
+        [
+            Step::Beep(self.brightness, beep, self.on),
+            Step::Off(self.off),
+            Step::Repeat {
+                steps: 2,
+                times: self.count.saturating_sub(1),
+            },
+            Step::Off(self.pause),
+        ]
+    }
+}
+
+async fn play(led: &mut Led<'_>, script: &[Step]) {
+    let mut i = 0;
+    // Repeat step in progress and the rounds left
+    let mut repeat: Option<(usize, u8)> = None;
+
+    while let Some(&step) = script.get(i) {
+        match step {
+            Step::On(brightness, ms) => {
+                led.on(brightness);
+                Timer::after_millis(ms as u64).await;
+            }
+
+            Step::Beep(brightness, beep, ms) => {
+                led.beep(brightness, beep);
+                Timer::after_millis(ms as u64).await;
+                led.quiet();
+            }
+
+            Step::Off(ms) => {
+                led.off();
+                Timer::after_millis(ms as u64).await;
+            }
+
+            Step::Fade(from, to, ms) => led.fade(from, to, ms as u64).await,
+
+            Step::Repeat { steps, times } => {
+                let left = match repeat {
+                    Some((at, left)) if at == i => left,
+                    _ => times,
+                };
+
+                if left > 0 {
+                    repeat = Some((i, left - 1));
+                    i = i.saturating_sub(steps as usize);
+                    continue;
+                }
+
+                repeat = None;
+            }
+        }
+
+        i += 1;
+    }
+}
+
+async fn render(led: &mut Led<'_>, state: &SystemState, style: IndicationStyle) {
+    // Whatever was cut short on the way here may have left the buzzer going
+    led.quiet();
+
+    let theme = state.indication_theme.try_get().unwrap_or_default();
+    let entry = theme.entry(style);
+    let brightness = entry.brightness;
+
+    let flight_light = state.flight_light.try_get().unwrap_or_default();
+
+    match (style, entry.count) {
+        (IndicationStyle::Disabled, _) => led.off(),
+
+        // Warnings in the air still go by the theme, they are worth being blinded by
+        (IndicationStyle::BlinkSlow, _) if flight_light != FlightLight::Theme => match flight_light
+        {
+            FlightLight::DimSolid => led.on(10),
+            FlightLight::SlowPulse => loop {
+                play(led, SLOW_PULSE).await;
+            },
+            _ => led.off(),
+        },
+
+        (_, _) if brightness == 0 => led.off(),
+
+        // Faults are re-read every round, since they may change under the same style
+        (IndicationStyle::Fault, _) => loop {
+            let faults = state.faults.try_get().unwrap_or_default();
+
+            for (fault, long, short) in FAULT_CODES {
+                if faults.contains(fault) {
+                    play(led, &fault_code(brightness, long, short)).await;
+                }
+            }
+
+            // Nothing to show - fault was cleared, and the style is about to change
+            Timer::after_millis(100).await;
+        },
+
+        // Duty cycle follows the SoC, so the progress can be seen at a glance
+        (IndicationStyle::Charging, count) if count > 0 => loop {
+            let period =
+                (entry.on as u32 + entry.off as u32 + entry.pause as u32).min(u16::MAX as u32);
+            let soc = state.soc.try_get().unwrap_or_default().min(100) as u32;
+            let on = (period * soc / 100).max(20).min(period);
+
+            play(
+                led,
+                &[
+                    Step::On(brightness, on as u16),
+                    Step::Off((period - on) as u16),
+                ],
+            )
+            .await;
+        },
+
+        (_, 0) => led.on(brightness),
+
+        (_, _) => loop {
+            play(led, &entry.script()).await;
+        },
+    }
+
+    future::pending().await
+}
+
+async fn render_once(led: &mut Led<'_>, state: &SystemState, shot: OneShot) {
+    led.quiet();
+
+    // Events are not states, so they don't get a color of their own
+    let color = core::mem::replace(&mut led.color, Color::WHITE);
+
+    match shot {
+        OneShot::Flashes(count) => play(led, &one_shot_flashes(count, 150, 500)).await,
+        // Empty pack still gets a flash, so it's not confused with nothing happening
+        OneShot::BatteryLevel => {
+            let soc = state.soc.try_get().unwrap_or_default();
+            play(led, &one_shot_flashes((soc / 10).max(1), 150, 500)).await
+        }
+        OneShot::Blip => play(led, &one_shot_flashes(1, 30, 100)).await,
+        OneShot::Sweep => play(led, SWEEP).await,
+        OneShot::Confirm => play(led, CONFIRM).await,
+        // Nervous flicker, hard to mistake for anything else
+        OneShot::Reject => play(led, &one_shot_flashes(6, 40, 300)).await,
+        OneShot::Armed => play(led, ARMED).await,
+        OneShot::Disarmed => play(led, DISARMED).await,
+    }
+
+    led.color = color;
+}
+
+#[embassy_executor::task]
+pub async fn run(state: &'static SystemState, r: LedResources, rgb: RgbLedResources) {
+    taskstats::accounted(Task::Indications, task(state, r, rgb)).await
+}
+
+async fn task(state: &'static SystemState, r: LedResources, rgb: RgbLedResources) {
+    info!("led indications running...");
+
+    let mut activity_receiver = unwrap!(state.activity.receiver());
+    let mut indications_receiver = unwrap!(state.indications.receiver());
+    let mut indication_theme_receiver = unwrap!(state.indication_theme.receiver());
+    let mut flight_light_receiver = unwrap!(state.flight_light.receiver());
+
+    let mut pwm_config = pwm::SimpleConfig::default();
+    pwm_config.max_duty = Led::LED_TOP;
+
+    let pwm = match cfg!(feature = "buzzer") {
+        true => SimplePwm::new_2ch(r.pwm, r.led, r.buzzer, &pwm_config),
+        false => SimplePwm::new_1ch(r.pwm, r.led, &pwm_config),
+    };
+
+    let mut led = Led {
+        pwm,
+        rgb: cfg!(feature = "rgb-led")
+            .then(|| SimplePwm::new_3ch(rgb.pwm, rgb.red, rgb.green, rgb.blue, &pwm_config)),
+        color: Color::OFF,
+        dim: false,
+        top: Led::LED_TOP,
+    };
+
+    let show = async || {
+        let mut style = IndicationStyle::Disabled;
+
+        loop {
+            led.dim = activity_receiver.try_get() == Some(ActivityLevel::Reduced);
+
+            let s = select4(
+                render(&mut led, state, style),
+                indications_receiver.changed(),
+                select3(
+                    activity_receiver.changed(),
+                    indication_theme_receiver.changed(),
+                    flight_light_receiver.changed(),
+                ),
+                state.one_shot.wait(),
+            )
+            .await;
+
+            match s {
+                Either4::Second(active) => {
+                    if active.top() != style {
+                        info!("indication is now {}", active.top());
+                    }
+
+                    style = active.top();
+                    led.color = style.color();
+                }
+
+                // Style changes are kept by the receiver in the meantime
+                Either4::Fourth(shot) => render_once(&mut led, state, shot).await,
+                _ => {}
+            }
+        }
+    };
+
+    join(follow_state(state), show()).await;
+}
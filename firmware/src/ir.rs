@@ -0,0 +1,134 @@
+// Stock Syma remote
+//
+// Builds with the ir-remote feature carry a 38 kHz IR receiver on a spare pin, the
+// TSOP kind that pulls its output low while it sees the carrier. Decoding lives in
+// copter_core::syma. Every edge captures a free running 1 MHz timer over PPI, so the
+// timestamps are right however late the task gets to them, as long as it's there before
+// the next edge. Otherwise the frame is lost, and the next one is ~120ms away.
+//
+// It's a fallback: the remote only flies while no BLE controller is connected and the
+// gamepad relay isn't flying (see relay.rs), and a controller connecting takes over
+// right away. Remote takes over once its throttle is
+// held down for a second, and that arms as well, there are no buttons on it. Disarming
+// is the idle timeout, or losing it. Switching the remote off and on arms it again.
+
+use defmt::{info, unwrap};
+use embassy_nrf::{
+    gpio::{Input, Pull},
+    gpiote::{InputChannel, InputChannelPolarity},
+    ppi::Ppi,
+    timer,
+};
+use embassy_time::{with_timeout, Duration, Instant};
+
+use copter_core::syma::{self, Decoder, Frame, Remote};
+
+use crate::{
+    board::IrResources,
+    eventlog::Event,
+    state::SystemState,
+    taskstats::{self, Task},
+    types::{JoystickData, PilotProfile},
+};
+
+// Channel B repeats every ~180ms, missing a couple in a row is too many
+const LOST_AFTER: Duration = Duration::from_millis(500);
+const SILENCE: Duration = Duration::from_micros(Decoder::SILENCE_US as u64);
+
+fn joystick(frame: &Frame) -> JoystickData {
+    JoystickData {
+        j1: (0, frame.throttle_stick()),
+        j2: (frame.yaw_stick(), frame.pitch_stick()),
+        ..Default::default()
+    }
+}
+
+#[embassy_executor::task]
+pub async fn run(state: &'static SystemState, r: IrResources) {
+    taskstats::accounted(Task::Ir, task(state, r)).await
+}
+
+async fn task(state: &'static SystemState, r: IrResources) {
+    if !cfg!(feature = "ir-remote") {
+        return;
+    }
+
+    let mut timer = timer::Timer::new(r.timer);
+    timer.set_frequency(timer::Frequency::F1MHz);
+
+    let input = Input::new(r.ir, Pull::Up);
+    let edges = InputChannel::new(r.gpiote, input, InputChannelPolarity::Toggle);
+
+    let mut ppi = Ppi::new_one_to_one(r.ppi, edges.event_in(), timer.cc(0).task_capture());
+    ppi.enable();
+    timer.start();
+
+    info!("ir receiver running...");
+
+    let mut controller_connected_receiver = unwrap!(state.controller_connected.receiver());
+    let mut relay_engaged_receiver = unwrap!(state.relay_engaged.receiver());
+    let controller_sample_sender = state.controller_sample.sender();
+    let remote_engaged_sender = state.remote_engaged.sender();
+
+    let mut decoder = Decoder::new();
+    let mut remote = Remote::new();
+    let mut last_edge = 0u32;
+    let mut last_frame = Instant::now();
+
+    loop {
+        // Nothing to wait for while idle, only to notice the remote is gone
+        let wait = match decoder.busy() {
+            true => SILENCE,
+            false => LOST_AFTER,
+        };
+
+        let frame = match with_timeout(wait, edges.wait()).await {
+            Ok(_) => {
+                let at = timer.cc(0).read();
+
+                decoder.edge(at.wrapping_sub(last_edge));
+                last_edge = at;
+                None
+            }
+            Err(_) => decoder.silence(),
+        };
+
+        let connected = controller_connected_receiver.try_get() == Some(true)
+            || relay_engaged_receiver.try_get() == Some(true);
+
+        if remote.engaged() && (connected || last_frame.elapsed() > LOST_AFTER) {
+            info!("ir remote is no longer in control");
+            remote.lost();
+            remote_engaged_sender.send(false);
+            state.log_event(Event::ControllerDisconnected, 1);
+        }
+
+        let Some(frame) = frame.filter(|_| !connected) else {
+            continue;
+        };
+
+        last_frame = Instant::now();
+
+        match remote.update(frame) {
+            Some(syma::Event::Engaged) => {
+                info!("ir remote took over, on channel {}", frame.channel);
+
+                let profile = PilotProfile::default();
+                state.pilot_profile.sender().send(profile);
+
+                remote_engaged_sender.send(true);
+                state.log_event(Event::ControllerConnected, 1);
+
+                // Throttle is down, as the control loop wants it for arming
+                if !state.armed.try_get().unwrap_or_default() {
+                    controller_sample_sender.send(JoystickData {
+                        buttons: profile.arm_button(),
+                        ..joystick(&frame)
+                    });
+                }
+            }
+            Some(syma::Event::Input(frame)) => controller_sample_sender.send(joystick(&frame)),
+            None => {}
+        }
+    }
+}
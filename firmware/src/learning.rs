@@ -0,0 +1,197 @@
+// bq27427 learning cycle assistant
+//
+// Walks through the learning cycle described in TI's "Achieving The Successful
+// Learning Cycle" app note, so the pack can be characterized without hand-editing
+// gauge constants:
+//
+// 1. Charge to full and let the charger terminate;
+// 2. Rest for 2 hours, so gauge can take the OCV reading;
+// 3. Discharge (hover or put a load) down to the terminate voltage. Gauge updates QMAX;
+// 4. Rest for 5 hours. Gauge updates the Ra table.
+//
+// Update status goes 0x03 -> 0x05 -> 0x06 as the gauge makes progress.
+
+use defmt::{info, warn};
+use embassy_time::{Duration, Instant};
+
+use crate::types::LearningProgress;
+
+#[repr(u8)]
+#[derive(Clone, Copy, PartialEq, Default, defmt::Format)]
+pub enum LearningPhase {
+    #[default]
+    Idle = 0,
+    ChargeToFull = 1,
+    RestCharged = 2,
+    Discharge = 3,
+    RestDischarged = 4,
+    Complete = 5,
+}
+
+impl LearningPhase {
+    pub fn from_u8(v: u8) -> Option<Self> {
+        match v {
+            0 => Some(Self::Idle),
+            1 => Some(Self::ChargeToFull),
+            2 => Some(Self::RestCharged),
+            3 => Some(Self::Discharge),
+            4 => Some(Self::RestDischarged),
+            5 => Some(Self::Complete),
+            _ => None,
+        }
+    }
+
+    // The cell has to go all the way down to the terminate voltage and then sit there
+    // untouched, so the low battery policy can't be allowed to cut the discharge
+    // short or power us off halfway through the rest
+    pub fn holds_off_soc_policy(self) -> bool {
+        matches!(
+            self,
+            Self::RestCharged | Self::Discharge | Self::RestDischarged
+        )
+    }
+}
+
+// Update status values the gauge reports during the learning cycle
+pub const UPDATE_STATUS_LEARNING: u8 = 0x03;
+pub const UPDATE_STATUS_QMAX_UPDATED: u8 = 0x05;
+pub const UPDATE_STATUS_RA_UPDATED: u8 = 0x06;
+
+pub struct LearningSample {
+    pub voltage: u16,
+    pub current: i16,
+    pub charging: bool,
+    pub full_charge: bool,
+    pub update_status: u8,
+    pub terminate_voltage: u16,
+}
+
+pub struct LearningCycle {
+    phase: LearningPhase,
+    rest_started: Option<Instant>,
+    // Rest done before a power cycle, see restore()
+    rest_carried: Duration,
+}
+
+impl LearningCycle {
+    const CHARGED_REST: Duration = Duration::from_secs(2 * 60 * 60);
+    const DISCHARGED_REST: Duration = Duration::from_secs(5 * 60 * 60);
+
+    // Gauge only considers the cell relaxed below the quit current
+    const RELAX_CURRENT_MA: i16 = 10;
+
+    pub const fn new() -> Self {
+        Self {
+            phase: LearningPhase::Idle,
+            rest_started: None,
+            rest_carried: Duration::from_ticks(0),
+        }
+    }
+
+    pub fn phase(&self) -> LearningPhase {
+        self.phase
+    }
+
+    pub fn start(&mut self) {
+        info!("learning: started, charge the battery to full");
+        self.set_phase(LearningPhase::ChargeToFull);
+    }
+
+    // Picks up where the cycle was before a power cycle. Gauge keeps its own state
+    // through it, it's only us who forget
+    pub fn restore(&mut self, progress: LearningProgress) {
+        let Some(phase) = LearningPhase::from_u8(progress.phase) else {
+            return;
+        };
+
+        if phase != LearningPhase::Idle {
+            info!("learning: resuming at {}", phase);
+        }
+
+        self.set_phase(phase);
+        self.rest_carried = Duration::from_secs(progress.rested as u64 * 60);
+    }
+
+    pub fn progress(&self) -> LearningProgress {
+        LearningProgress {
+            phase: self.phase as u8,
+            _reserved: 0,
+            rested: (self.rested_for().as_secs() / 60).min(u16::MAX as u64) as u16,
+        }
+    }
+
+    fn set_phase(&mut self, phase: LearningPhase) {
+        self.phase = phase;
+        self.rest_started = None;
+        self.rest_carried = Duration::from_ticks(0);
+    }
+
+    pub fn rested_for(&self) -> Duration {
+        self.rest_carried
+            + self
+                .rest_started
+                .map_or(Duration::from_ticks(0), |started| started.elapsed())
+    }
+
+    // Returns true once the cell rested long enough. Any load restarts the timer
+    fn rested(&mut self, s: &LearningSample, duration: Duration) -> bool {
+        if s.current.abs() > Self::RELAX_CURRENT_MA {
+            self.rest_started = None;
+            self.rest_carried = Duration::from_ticks(0);
+            return false;
+        }
+
+        self.rest_started.get_or_insert(Instant::now());
+        self.rested_for() >= duration
+    }
+
+    // Returns the new phase, if it has changed
+    pub fn update(&mut self, s: &LearningSample) -> Option<LearningPhase> {
+        let previous = self.phase;
+
+        match self.phase {
+            LearningPhase::Idle | LearningPhase::Complete => {}
+
+            LearningPhase::ChargeToFull => {
+                if s.full_charge && !s.charging {
+                    info!("learning: charged, unplug and let the battery rest for 2 hours");
+                    self.set_phase(LearningPhase::RestCharged);
+                }
+            }
+
+            LearningPhase::RestCharged => {
+                if self.rested(s, Self::CHARGED_REST) {
+                    info!("learning: rest done, discharge the battery at ~C/5 now");
+                    self.set_phase(LearningPhase::Discharge);
+                }
+            }
+
+            LearningPhase::Discharge => {
+                if s.voltage <= s.terminate_voltage || s.update_status == UPDATE_STATUS_QMAX_UPDATED
+                {
+                    info!(
+                        "learning: discharged (update status {=u8:#x}), let the battery rest for 5 hours",
+                        s.update_status
+                    );
+                    self.set_phase(LearningPhase::RestDischarged);
+                }
+            }
+
+            LearningPhase::RestDischarged => {
+                if self.rested(s, Self::DISCHARGED_REST) {
+                    if s.update_status == UPDATE_STATUS_RA_UPDATED {
+                        info!("learning: complete!");
+                        self.set_phase(LearningPhase::Complete);
+                    } else {
+                        warn!(
+                            "learning: still waiting for Ra update (update status {=u8:#x})",
+                            s.update_status
+                        );
+                    }
+                }
+            }
+        }
+
+        (self.phase != previous).then_some(self.phase)
+    }
+}
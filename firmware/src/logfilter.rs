@@ -0,0 +1,107 @@
+// Runtime log filter
+//
+// defmt only filters at compile time, so the firmware is built with everything in and
+// the chatty parts log through log! instead, which checks the level of their module
+// first. Levels are changed over GATT, or by poking LEVELS from the debugger, and are
+// back to the defaults after a reboot. Disabled logs still cost a branch, but nothing
+// goes over RTT.
+
+use core::sync::atomic::{AtomicU8, Ordering};
+
+#[repr(u8)]
+#[derive(Clone, Copy, PartialEq, PartialOrd, defmt::Format)]
+pub enum Level {
+    Off,
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl Level {
+    // Anything past the last one opens everything up
+    fn from_u8(v: u8) -> Self {
+        match v {
+            0 => Self::Off,
+            1 => Self::Error,
+            2 => Self::Warn,
+            3 => Self::Info,
+            4 => Self::Debug,
+            _ => Self::Trace,
+        }
+    }
+}
+
+#[repr(u8)]
+#[derive(Clone, Copy, defmt::Format)]
+pub enum Module {
+    // Fuel gauge polling and interrupts
+    Gauge,
+    // Every report coming from the controller
+    Hid,
+    // Connection housekeeping
+    Ble,
+}
+
+pub const MODULE_COUNT: usize = 3;
+
+const DEFAULT_LEVEL: Level = Level::Info;
+
+#[no_mangle]
+static LEVELS: [AtomicU8; MODULE_COUNT] =
+    [const { AtomicU8::new(DEFAULT_LEVEL as u8) }; MODULE_COUNT];
+
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+pub struct LogLevels {
+    // See Module for the order and Level for the values
+    pub levels: [u8; MODULE_COUNT],
+}
+
+pub fn enabled(module: Module, level: Level) -> bool {
+    level as u8 <= LEVELS[module as usize].load(Ordering::Relaxed)
+}
+
+pub fn levels() -> LogLevels {
+    LogLevels {
+        levels: LEVELS.each_ref().map(|l| l.load(Ordering::Relaxed)),
+    }
+}
+
+pub fn set_levels(levels: LogLevels) {
+    for (module, level) in LEVELS.iter().zip(levels.levels) {
+        module.store(Level::from_u8(level) as u8, Ordering::Relaxed);
+    }
+
+    defmt::info!("log levels are now {}", self::levels().levels);
+}
+
+// Same as defmt::info! and friends, except that the module has to let it through
+macro_rules! log {
+    ($module:ident, error, $($arg:tt)+) => {
+        $crate::logfilter::log!(@ $module, Error, error, $($arg)+)
+    };
+    ($module:ident, warn, $($arg:tt)+) => {
+        $crate::logfilter::log!(@ $module, Warn, warn, $($arg)+)
+    };
+    ($module:ident, info, $($arg:tt)+) => {
+        $crate::logfilter::log!(@ $module, Info, info, $($arg)+)
+    };
+    ($module:ident, debug, $($arg:tt)+) => {
+        $crate::logfilter::log!(@ $module, Debug, debug, $($arg)+)
+    };
+    ($module:ident, trace, $($arg:tt)+) => {
+        $crate::logfilter::log!(@ $module, Trace, trace, $($arg)+)
+    };
+    (@ $module:ident, $level:ident, $mac:ident, $($arg:tt)+) => {
+        if $crate::logfilter::enabled(
+            $crate::logfilter::Module::$module,
+            $crate::logfilter::Level::$level,
+        ) {
+            defmt::$mac!($($arg)+);
+        }
+    };
+}
+
+pub(crate) use log;
@@ -1,84 +1,129 @@
 #![no_std]
 #![no_main]
 
-use assign_resources::assign_resources;
+use board::{
+    AssignedResources, AuxResources, ControllerResources, FlowResources, I2cResources, IrResources,
+    LedResources, PowerResources, RgbLedResources, SwitchResources,
+};
+use copter_core::boot::ImageState;
 use state::SystemState;
 use static_cell::StaticCell;
+use types::BootInfo;
 
 use core::panic::PanicInfo;
-use embassy_executor::Spawner;
+use cortex_m_rt::{exception, ExceptionFrame};
+use embassy_executor::{InterruptExecutor, Spawner};
 use embassy_nrf::{
-    bind_interrupts,
-    interrupt::{self, InterruptExt},
-    peripherals, saadc,
+    bind_interrupts, interrupt,
+    interrupt::InterruptExt,
+    peripherals, saadc, spim,
     twim::{self, Twim},
-    Peri,
 };
 use embassy_sync::{blocking_mutex::raw::NoopRawMutex, mutex::Mutex};
 use git_version::git_version;
-use nrf_softdevice::{raw, Softdevice};
+use nrf_softdevice::{raw, Flash, Softdevice};
 
-use defmt::{info, unwrap};
+use defmt::{error, info, unwrap};
 
+mod assertion;
+mod aux;
+mod baro;
+mod blackbox;
 mod ble;
+mod board;
+mod boot;
+mod charger;
+mod chirp;
+mod clock;
+mod compass;
+mod console;
 mod control;
+mod dfu;
+mod eventlog;
 mod executor;
+mod factory;
+mod faultmanager;
+mod flow;
+mod history;
+mod imu;
 mod indications;
+mod ir;
+mod learning;
+mod logfilter;
+mod params;
+mod postmortem;
 mod power;
+mod relay;
+mod selftest;
+mod settings;
+mod shutdown;
 mod state;
+mod switch;
+mod taskstats;
+mod tof;
 mod types;
 mod utils;
+mod watchdog;
 mod xbox;
 
-use defmt_rtt as _;
-
 type SharedI2cBus = Mutex<NoopRawMutex, Twim<'static>>;
 
+// Shown over BLE as well, see ble/peripheral.rs
+pub const VERSION: &str = git_version!();
+
 bind_interrupts!(struct Irqs {
     TWISPI0 => twim::InterruptHandler<peripherals::TWISPI0>;
     SAADC => saadc::InterruptHandler;
+    TWISPI1 => spim::InterruptHandler<peripherals::TWISPI1>;
 });
 
-assign_resources! {
-    led_switch: LedSwitchResources {
-        led: P0_00,
-        switch: P0_05,
-        pwm: PWM1
-    },
-    i2c: I2cResources {
-        // make sure to check interrupt priority below if changing
-        i2c: TWISPI0,
-        sda: P0_07,
-        scl: P0_08,
-    },
-    power: PowerResources {
-        fuelgauge_int: P0_06,
-        charging_int: P0_11,
-        fault_int: P0_12
-    },
-    controller: ControllerResources {
-        // in current implementation, there's no need to share them, so just
-        // keep them here for simplicity
-        adc: SAADC,
-        pwm: PWM0,
-
-        rotor1: P0_01,
-        rotor2: P0_02,
-        tail_p: P0_03,
-        tail_n: P0_04,
-        gyro_power: P0_26,
-        gyro_input: P0_28,
-        gyro_vref: P0_29,
-    },
+// Control loop gets an executor of its own, running off a spare software interrupt.
+// It preempts all the thread mode tasks, so GATT bursts and gauge traffic can't delay
+// the motor updates. Everything shared with it goes through the SystemState
+static CONTROL_EXECUTOR: InterruptExecutor = InterruptExecutor::new();
+
+#[interrupt]
+unsafe fn SWI0_EGU0() {
+    CONTROL_EXECUTOR.on_interrupt()
 }
 
-// It's safer to reboot rather than hang
+// It's safer to reboot rather than hang. What happened is reported after the reboot
 #[panic_handler]
-fn panic(_info: &PanicInfo) -> ! {
+fn panic(info: &PanicInfo) -> ! {
+    postmortem::capture(info);
+    history::freeze(history::HistoryTrigger::Panic);
+    cortex_m::peripheral::SCB::sys_reset();
+}
+
+// Same as with panics, only there's no message, just the registers
+#[exception]
+unsafe fn HardFault(frame: &ExceptionFrame) -> ! {
+    postmortem::capture_fault(frame);
+    history::freeze(history::HistoryTrigger::HardFault);
     cortex_m::peripheral::SCB::sys_reset();
 }
 
-fn hw_init() -> (AssignedResources, &'static mut Softdevice) {
+// Has to be called after the softdevice is enabled, since it owns POWER from now on
+fn read_boot_info() -> BootInfo {
+    let mut reset_reason = 0;
+    let mut gpregret = 0;
+
+    unsafe {
+        raw::sd_power_reset_reason_get(&mut reset_reason);
+        raw::sd_power_gpregret_get(0, &mut gpregret);
+
+        // Both are retained across resets, so clear them for the next time
+        raw::sd_power_reset_reason_clr(reset_reason);
+        raw::sd_power_gpregret_clr(0, gpregret);
+    }
+
+    BootInfo {
+        reset_reason,
+        gpregret,
+    }
+}
+
+fn hw_init() -> (AssignedResources, &'static mut Softdevice, BootInfo) {
     let mut config = embassy_nrf::config::Config::default();
 
     /*
@@ -89,21 +134,38 @@ fn hw_init() -> (AssignedResources, &'static mut Softdevice) {
     config.gpiote_interrupt_priority = interrupt::Priority::P2;
     config.time_interrupt_priority = interrupt::Priority::P2;
 
+    // Softdevice takes the clock over once enabled, and both have to agree on it
+    config.lfclk_source = board::LF_CLOCK.source();
+
     interrupt::TWISPI0.set_priority(interrupt::Priority::P2);
     interrupt::SAADC.set_priority(interrupt::Priority::P2);
+    interrupt::TWISPI1.set_priority(interrupt::Priority::P2);
+
+    // Below the drivers it relies on (SAADC, RTC), so they can still get through
+    interrupt::SWI0_EGU0.set_priority(interrupt::Priority::P3);
 
     let sd_config = nrf_softdevice::Config {
         conn_gap: Some(raw::ble_gap_conn_cfg_t {
-            conn_count: 2,
+            conn_count: ble::CONN_COUNT,
             event_length: 24,
         }),
+        gap_role_count: Some(ble::softdevice_roles()),
+        clock: Some(board::LF_CLOCK.softdevice_config()),
         ..nrf_softdevice::Config::default()
     };
 
     let p = embassy_nrf::init(config);
     let sd = Softdevice::enable(&sd_config);
+    let boot_info = read_boot_info();
+
+    // Motors share the battery with the regulator, so heavy load on a weak cell
+    // drags VDD down. POF warning lets us cut the motors before we brown out
+    unsafe {
+        raw::sd_power_pof_threshold_set(raw::NRF_POWER_THRESHOLDS_NRF_POWER_THRESHOLD_V28 as u8);
+        raw::sd_power_pof_enable(1);
+    }
 
-    (split_resources!(p), sd)
+    (board::split(p), sd, boot_info)
 }
 
 fn make_shared_i2c(r: I2cResources) -> &'static SharedI2cBus {
@@ -126,17 +188,140 @@ fn make_shared_i2c(r: I2cResources) -> &'static SharedI2cBus {
 
 #[embassy_executor::main(executor = "executor::MwuWorkaroundExecutor")]
 async fn main(spawner: Spawner) {
-    let (r, sd) = hw_init();
+    let rtt = console::init();
+    taskstats::init();
+
+    let (r, sd, boot_info) = hw_init();
+    clock::init();
+    history::init();
+
     let i2c = make_shared_i2c(r.i2c);
 
-    info!("ble-copter ({}) is running. Hello!", git_version!());
+    info!("ble-copter ({}) is running. Hello!", VERSION);
+    info!(
+        "reset reason: {}, gpregret: {=u32:#x}",
+        boot_info.reset_reason(),
+        { boot_info.gpregret }
+    );
+
+    match factory::get() {
+        Some(unit) => info!(
+            "board revision {}, name {}",
+            unit.board_revision,
+            unit.name().unwrap_or("built-in")
+        ),
+        None => info!("board was never provisioned"),
+    }
+
+    let panic_report = postmortem::take();
+
+    if let Some(report) = panic_report {
+        error!(
+            "previous run panicked at {=[u8]:a}:{} - {=[u8]:a}",
+            report.file,
+            { report.line },
+            report.message
+        );
+    }
+
+    let fault_report = postmortem::take_fault();
+
+    if let Some(report) = fault_report {
+        error!(
+            "previous run hard faulted at pc {=u32:#x}, lr {=u32:#x}, cfsr {=u32:#x}, hfsr {=u32:#x}",
+            { report.pc },
+            { report.lr },
+            { report.cfsr },
+            { report.hfsr }
+        );
+    }
+
+    let assertion_report = postmortem::take_assertion();
+
+    if let Some(report) = assertion_report {
+        error!(
+            "previous run failed assertion {} at {=[u8]:a}:{}",
+            { report.assertion },
+            report.module,
+            { report.line }
+        );
+    }
+
+    let image_state = boot::image_state(&boot_info);
+
+    match image_state {
+        ImageState::Trial => info!("new firmware image is on trial"),
+        ImageState::RolledBack => error!("new firmware image didn't make it, rolled back"),
+        ImageState::Confirmed => {}
+    }
+
+    let overdue = watchdog::take_overdue();
+
+    if let Some(task) = overdue {
+        error!(
+            "previous run was reset by the watchdog, {} stopped checking in",
+            task
+        );
+    }
 
     static SYSTEM_STATE: StaticCell<SystemState> = StaticCell::new();
-    let system_state = SYSTEM_STATE.init(SystemState::new());
+    let system_state = SYSTEM_STATE.init(SystemState::new(boot_info, panic_report, fault_report));
+    system_state.log_event(eventlog::Event::Boot, boot_info.reset_reason as u16);
+
+    // Already in the black box, it was raised as an incident back then
+    if let Some(report) = assertion_report {
+        system_state.assertion.sender().send(report);
+    }
+
+    // Reported in full above, the black box only needs to know it happened
+    if panic_report.is_some() {
+        system_state.raise_incident(eventlog::Event::Error, blackbox::Incident::Panic);
+    }
+
+    if fault_report.is_some() {
+        system_state.raise_incident(eventlog::Event::Error, blackbox::Incident::HardFault);
+    }
+
+    if image_state == ImageState::RolledBack {
+        system_state.raise_incident(eventlog::Event::Error, blackbox::Incident::ImageRolledBack);
+    }
 
-    spawner.spawn(unwrap!(indications::run(system_state, r.led_switch)));
+    // Executor getting stuck as a whole leaves no one to blame
+    if boot_info
+        .reset_reason()
+        .contains(types::ResetReason::WATCHDOG)
+    {
+        if overdue.is_none() {
+            error!("previous run was reset by the watchdog");
+        }
+
+        system_state.raise_incident(eventlog::Event::Error, blackbox::Incident::Watchdog);
+    }
+
+    let flash = Flash::take(sd);
+
+    spawner.spawn(unwrap!(clock::run()));
+    spawner.spawn(unwrap!(watchdog::run(system_state, r.watchdog.wdt)));
+    spawner.spawn(unwrap!(indications::run(system_state, r.led, r.rgb_led)));
+    spawner.spawn(unwrap!(switch::run(system_state, r.switch)));
+    spawner.spawn(unwrap!(shutdown::run(system_state)));
     spawner.spawn(unwrap!(ble::run(sd, system_state)));
-    spawner.spawn(unwrap!(control::run(system_state, r.controller,)));
+    spawner.spawn(unwrap!(chirp::run(system_state)));
     spawner.spawn(unwrap!(power::run(system_state, r.power, i2c)));
     spawner.spawn(unwrap!(state::run(system_state)));
+    spawner.spawn(unwrap!(settings::run(system_state, flash)));
+    spawner.spawn(unwrap!(selftest::run(system_state, i2c)));
+    spawner.spawn(unwrap!(imu::run(system_state, i2c)));
+    spawner.spawn(unwrap!(baro::run(system_state, i2c)));
+    spawner.spawn(unwrap!(compass::run(system_state, i2c)));
+    spawner.spawn(unwrap!(tof::run(system_state, i2c)));
+    spawner.spawn(unwrap!(ir::run(system_state, r.ir)));
+    spawner.spawn(unwrap!(relay::run(system_state)));
+    spawner.spawn(unwrap!(flow::run(system_state, r.flow)));
+    spawner.spawn(unwrap!(aux::run(system_state, r.aux)));
+    spawner.spawn(unwrap!(taskstats::run(system_state)));
+    spawner.spawn(unwrap!(console::run(system_state, rtt)));
+
+    let control_spawner = CONTROL_EXECUTOR.start(interrupt::SWI0_EGU0);
+    control_spawner.spawn(unwrap!(control::run(system_state, r.controller)));
 }
@@ -0,0 +1,142 @@
+// Parameter registry
+//
+// Plain numeric tunables are described by a single table. Clients read the table to
+// learn what's there and how far each value can go, and write values by id. Settings
+// keep the values in flash, and whoever uses them reads them through the state.
+// A new tunable is a new Param variant and a new entry in PARAMS, nothing else.
+
+#[repr(u8)]
+#[derive(Clone, Copy, PartialEq, defmt::Format)]
+pub enum Param {
+    // s, nobody is going to hover with the stick at zero for that long
+    IdleDisarmTimeout = 0,
+    // Raw gyro units. Tail alone can't spin us that fast, keeping at it means the rotor
+    // hit something, or the heli is lying on its side
+    CrashYawRate = 1,
+    // s, how long a new controller is welcome once pairing is started
+    PairingTimeout = 2,
+    // mA per rotor, about twice what one draws at full throttle. Only with the
+    // motor-current feature, see copter_core::current
+    MotorCurrentLimit = 3,
+}
+
+#[repr(u8)]
+#[derive(Clone, Copy, PartialEq, defmt::Format)]
+pub enum ParamKind {
+    Bool = 0,
+    Integer = 1,
+}
+
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+pub struct ParamInfo {
+    pub kind: u8,
+    pub _reserved: [u8; 3],
+    pub min: i32,
+    pub max: i32,
+    pub default: i32,
+}
+
+impl ParamInfo {
+    const UNUSED: Self = Self::new(ParamKind::Integer, 0, 0, 0);
+
+    const fn new(kind: ParamKind, min: i32, max: i32, default: i32) -> Self {
+        Self {
+            kind: kind as u8,
+            _reserved: [0; 3],
+            min,
+            max,
+            default,
+        }
+    }
+}
+
+// Room to grow without changing the layout of what's stored and what clients see
+pub const PARAM_SLOTS: usize = 8;
+
+// Indexed by Param
+const PARAMS: [ParamInfo; PARAM_SLOTS] = [
+    ParamInfo::new(ParamKind::Integer, 3, 120, 10),
+    ParamInfo::new(ParamKind::Integer, 100, 1000, 250),
+    ParamInfo::new(ParamKind::Integer, 10, 600, 60),
+    ParamInfo::new(ParamKind::Integer, 500, 6000, 3000),
+    ParamInfo::UNUSED,
+    ParamInfo::UNUSED,
+    ParamInfo::UNUSED,
+    ParamInfo::UNUSED,
+];
+
+// What clients read to find out about the parameters. Unused slots have nothing
+// between min and max
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+pub struct ParamTable {
+    pub entries: [ParamInfo; PARAM_SLOTS],
+}
+
+pub const PARAM_TABLE: ParamTable = ParamTable { entries: PARAMS };
+
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+pub struct ParamUpdate {
+    pub id: u8,
+    pub _reserved: [u8; 3],
+    pub value: i32,
+}
+
+#[derive(Clone, Copy, defmt::Format)]
+pub enum ParamError {
+    UnknownId,
+    OutOfRange,
+}
+
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+pub struct ParamValues {
+    values: [i32; PARAM_SLOTS],
+}
+
+impl Default for ParamValues {
+    fn default() -> Self {
+        let mut values = [0; PARAM_SLOTS];
+
+        for (value, info) in values.iter_mut().zip(PARAMS) {
+            *value = info.default;
+        }
+
+        Self { values }
+    }
+}
+
+impl ParamValues {
+    pub fn get(&self, param: Param) -> i32 {
+        self.values[param as usize]
+    }
+
+    pub fn set(&mut self, update: ParamUpdate) -> Result<(), ParamError> {
+        let info = PARAMS
+            .get(update.id as usize)
+            .filter(|info| info.max > info.min)
+            .ok_or(ParamError::UnknownId)?;
+
+        if !(info.min..=info.max).contains(&update.value) {
+            return Err(ParamError::OutOfRange);
+        }
+
+        self.values[update.id as usize] = update.value;
+        Ok(())
+    }
+
+    // Stored values may come from an older table, with different limits
+    pub fn sanitize(&mut self) {
+        let mut values = self.values;
+
+        for (value, info) in values.iter_mut().zip(PARAMS) {
+            if !(info.min..=info.max).contains(value) {
+                *value = info.default;
+            }
+        }
+
+        self.values = values;
+    }
+}
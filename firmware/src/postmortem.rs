@@ -0,0 +1,225 @@
+// Panic and hard fault post-mortem
+//
+// The panic and hard fault handlers leave a report in a RAM section that the startup
+// code doesn't touch, so it survives the reset that follows. It's picked up on the
+// next boot and kept around for clients to read, otherwise field crashes are just
+// unexplained resets. Flash is out of reach from in there, the softdevice API needs
+// its event loop running to finish a write, so settings store the fault report later.
+
+use core::{
+    fmt::{self, Write},
+    mem::{size_of, MaybeUninit},
+    panic::PanicInfo,
+    ptr,
+};
+
+use cortex_m::peripheral::SCB;
+use cortex_m_rt::ExceptionFrame;
+
+use crate::assertion::AssertionReport;
+
+const REPORT_MAGIC: u32 = 0x5107_dead;
+const FAULT_MAGIC: u32 = 0x5107_fa17;
+const ASSERTION_MAGIC: u32 = 0x5107_a55e;
+
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+pub struct PanicReport {
+    // Zero if the previous run did not panic
+    pub line: u32,
+    // Main stack pointer at the time, tells a stack overflow apart
+    pub sp: u32,
+    // Tail of the path, the beginning is the same for all of them anyway
+    pub file: [u8; 32],
+    pub message: [u8; 64],
+}
+
+impl PanicReport {
+    pub const EMPTY: Self = Self {
+        line: 0,
+        sp: 0,
+        file: [0; 32],
+        message: [0; 64],
+    };
+}
+
+pub const FAULT_STACK_WORDS: usize = 16;
+
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+pub struct FaultReport {
+    // Zero if there was no hard fault
+    pub pc: u32,
+    pub lr: u32,
+    pub xpsr: u32,
+    // Where the exception frame is
+    pub sp: u32,
+    pub cfsr: u32,
+    pub hfsr: u32,
+    // Whatever the faulting code had on the stack right above the frame
+    pub stack: [u32; FAULT_STACK_WORDS],
+}
+
+impl FaultReport {
+    pub const EMPTY: Self = Self {
+        pc: 0,
+        lr: 0,
+        xpsr: 0,
+        sp: 0,
+        cfsr: 0,
+        hfsr: 0,
+        stack: [0; FAULT_STACK_WORDS],
+    };
+}
+
+#[repr(C)]
+struct Retained<T> {
+    magic: u32,
+    report: T,
+}
+
+#[link_section = ".uninit.PANIC_REPORT"]
+static mut RETAINED: MaybeUninit<Retained<PanicReport>> = MaybeUninit::uninit();
+
+#[link_section = ".uninit.FAULT_REPORT"]
+static mut RETAINED_FAULT: MaybeUninit<Retained<FaultReport>> = MaybeUninit::uninit();
+
+#[link_section = ".uninit.ASSERTION_REPORT"]
+static mut RETAINED_ASSERTION: MaybeUninit<Retained<AssertionReport>> = MaybeUninit::uninit();
+
+unsafe fn retain<T>(slot: *mut Retained<T>, magic: u32, report: T) {
+    ptr::write_volatile(slot, Retained { magic, report });
+}
+
+// Only once, so the same crash is not reported again after a clean reset
+unsafe fn take_retained<T>(slot: *mut Retained<T>, magic: u32) -> Option<T> {
+    // Power-on leaves garbage there, which is what the magic is for
+    if ptr::read_volatile(ptr::addr_of!((*slot).magic)) != magic {
+        return None;
+    }
+
+    ptr::write_volatile(ptr::addr_of_mut!((*slot).magic), 0);
+    Some(ptr::read_volatile(ptr::addr_of!((*slot).report)))
+}
+
+// Whatever doesn't fit is dropped, no allocations in the panic handler
+struct Truncating<'a> {
+    buf: &'a mut [u8],
+    len: usize,
+}
+
+impl Write for Truncating<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let n = s.len().min(self.buf.len() - self.len);
+
+        self.buf[self.len..self.len + n].copy_from_slice(&s.as_bytes()[..n]);
+        self.len += n;
+
+        Ok(())
+    }
+}
+
+pub fn capture(info: &PanicInfo) {
+    let mut file = [0; 32];
+    let mut message = [0; 64];
+    let mut line = 0;
+
+    if let Some(location) = info.location() {
+        let path = location.file().as_bytes();
+        let tail = &path[path.len().saturating_sub(file.len())..];
+
+        file[..tail.len()].copy_from_slice(tail);
+        line = location.line();
+    }
+
+    let _ = write!(
+        Truncating {
+            buf: &mut message,
+            len: 0,
+        },
+        "{}",
+        info.message()
+    );
+
+    let report = PanicReport {
+        line,
+        sp: cortex_m::register::msp::read(),
+        file,
+        message,
+    };
+
+    unsafe {
+        retain(ptr::addr_of_mut!(RETAINED).cast(), REPORT_MAGIC, report);
+    }
+}
+
+pub fn capture_fault(frame: &ExceptionFrame) {
+    extern "C" {
+        // Top of the stack, provided by cortex-m-rt
+        static _stack_start: u32;
+    }
+
+    let sp = frame as *const ExceptionFrame as u32;
+    let top = unsafe { &_stack_start as *const u32 as u32 };
+    let mut stack = [0; FAULT_STACK_WORDS];
+
+    // Stack may be what went wrong, so don't read past its top
+    let above = sp + size_of::<ExceptionFrame>() as u32;
+    for (i, word) in stack.iter_mut().enumerate() {
+        let address = above + i as u32 * 4;
+        if address + 4 > top {
+            break;
+        }
+
+        *word = unsafe { ptr::read_volatile(address as *const u32) };
+    }
+
+    let scb = unsafe { &*SCB::PTR };
+
+    let report = FaultReport {
+        pc: frame.pc(),
+        lr: frame.lr(),
+        xpsr: frame.xpsr(),
+        sp,
+        cfsr: scb.cfsr.read(),
+        hfsr: scb.hfsr.read(),
+        stack,
+    };
+
+    unsafe {
+        retain(
+            ptr::addr_of_mut!(RETAINED_FAULT).cast(),
+            FAULT_MAGIC,
+            report,
+        );
+    }
+}
+
+// Hands out the report left by the previous run, if any
+pub fn take() -> Option<PanicReport> {
+    unsafe { take_retained(ptr::addr_of_mut!(RETAINED).cast(), REPORT_MAGIC) }
+}
+
+pub fn take_fault() -> Option<FaultReport> {
+    unsafe { take_retained(ptr::addr_of_mut!(RETAINED_FAULT).cast(), FAULT_MAGIC) }
+}
+
+// Not a crash by itself, but one may well follow. The latest one wins
+pub fn capture_assertion(report: AssertionReport) {
+    unsafe {
+        retain(
+            ptr::addr_of_mut!(RETAINED_ASSERTION).cast(),
+            ASSERTION_MAGIC,
+            report,
+        );
+    }
+}
+
+pub fn take_assertion() -> Option<AssertionReport> {
+    unsafe {
+        take_retained(
+            ptr::addr_of_mut!(RETAINED_ASSERTION).cast(),
+            ASSERTION_MAGIC,
+        )
+    }
+}
@@ -1,90 +1,263 @@
+use core::fmt::Write;
 use core::future;
 
+#[cfg(feature = "charge-inhibit")]
+use crate::charger::ChargeInhibit;
+#[cfg(feature = "charger-control")]
+use crate::charger::Charger;
+
 use crate::{
-    state::{Request, SystemState},
-    types::{ChargerState, PeriodicUpdate},
+    blackbox::{incident, Incident},
+    console::Command,
+    executor,
+    learning::{LearningCycle, LearningPhase, LearningSample, UPDATE_STATUS_LEARNING},
+    logfilter::log,
+    state::{ActivityLevel, Request, SystemState},
+    taskstats::{self, Task},
+    types::{
+        BatteryChemistry, BatteryProfile, ChargerState, Faults, GaugeLearnedData, GaugeSocFlags,
+        LearningProgress, LearningReport, PeriodicUpdate, ShutdownAcks, ShutdownReason,
+        SocThresholds, FLIGHT_TIME_UNKNOWN, RAIL_VOLTAGE_UNKNOWN,
+    },
+    utils::RollingAverage,
+    watchdog::Supervised,
     PowerResources, SharedI2cBus,
 };
 use bq27xxx::{
-    chips::bq27427::{ChemInfo, CurrentThresholds, RaTable, StateClass},
+    chips::bq27427::{
+        ChemInfo, CurrentThresholds, Discharge, RaTable, Registers, Safety, StateClass,
+    },
     defs::{ControlStatusFlags, StatusFlags},
     memory::MemoryBlock,
     Bq27xx, ChemId,
 };
-use defmt::{error, info, unwrap, warn};
+use copter_core::console::{Args, Error};
+use copter_core::policy::SocPolicy;
+use defmt::{info, unwrap, warn};
 use embassy_embedded_hal::shared_bus::{asynch::i2c::I2cDevice, I2cDeviceError};
-use embassy_futures::select::{select, select3, Either, Either3};
+use embassy_futures::select::{select, select4, Either, Either4};
 use embassy_nrf::{
     gpio::{Input, Pull},
     twim,
 };
 use embassy_sync::blocking_mutex::raw::NoopRawMutex;
-use embassy_time::{Duration, Timer};
+use embassy_time::{Duration, Instant, Timer};
+use nrf_softdevice::raw;
 
 type Gauge<'a> = Bq27xx<I2cDevice<'a, NoopRawMutex, twim::Twim<'a>>, embassy_time::Delay>;
 type GaugeResult<T> = Result<T, bq27xxx::ChipError<I2cDeviceError<twim::Error>>>;
 
-async fn wait_gauge_init_complete<'a>(gauge: &mut Gauge<'a>) -> GaugeResult<()> {
-    info!("waiting for fuelgauge init");
+// Aggregates gauge readings over a single flight
+struct PowerStats {
+    started: Instant,
+    samples: i32,
+    voltage_sum: i32,
+    current_sum: i32,
+    summary: FlightPowerSummary,
+}
+
+impl PowerStats {
+    fn new() -> Self {
+        Self {
+            started: Instant::now(),
+            samples: 0,
+            voltage_sum: 0,
+            current_sum: 0,
+            summary: FlightPowerSummary {
+                voltage_min: u16::MAX,
+                current_min: i16::MAX,
+                voltage_max: u16::MIN,
+                current_max: i16::MIN,
+                ..Default::default()
+            },
+        }
+    }
+
+    fn add(&mut self, voltage: u16, current: i16) {
+        let s = &mut self.summary;
+
+        s.voltage_min = s.voltage_min.min(voltage);
+        s.voltage_max = s.voltage_max.max(voltage);
+        s.current_min = s.current_min.min(current);
+        s.current_max = s.current_max.max(current);
 
-    for _ in 0..10 {
-        let control_flags = gauge.get_control_status().await?;
+        self.samples += 1;
+        self.voltage_sum += voltage as i32;
+        self.current_sum += current as i32;
+    }
 
-        if control_flags.contains(ControlStatusFlags::INITCOMP) {
-            info!("fuelgauge init complete!");
-            return Ok(());
+    fn summary(&self) -> Option<FlightPowerSummary> {
+        if self.samples == 0 {
+            return None;
         }
 
-        Timer::after_secs(1).await;
+        Some(FlightPowerSummary {
+            duration: self.started.elapsed().as_secs().min(u16::MAX as u64) as u16,
+            voltage_avg: (self.voltage_sum / self.samples) as u16,
+            current_avg: (self.current_sum / self.samples) as i16,
+            ..self.summary
+        })
     }
+}
 
-    Err(bq27xxx::ChipError::PollTimeout)
+// Estimates remaining flight time from the recent discharge power and
+// the energy left in the pack
+struct FlightTimeEstimator {
+    // mW
+    power: RollingAverage<30>,
 }
 
-async fn configure_gauge<'a>(gauge: &mut Gauge<'a>) -> GaugeResult<()> {
-    gauge.write_chem_id(ChemId::B4200).await?;
+impl FlightTimeEstimator {
+    // Below that we're certainly not flying, and the estimate makes no sense
+    const MIN_FLIGHT_POWER_MW: i32 = 100;
 
-    let start_learning = false;
+    const fn new() -> Self {
+        Self {
+            power: RollingAverage::new(),
+        }
+    }
+
+    fn add_sample(&mut self, voltage: u16, current: i16) {
+        // Negative current means discharge
+        if current < 0 {
+            self.power.add(voltage as i32 * -(current as i32) / 1000);
+        }
+    }
 
-    info!("updating fuelgauge memory...");
+    fn estimate(&self, soc: u8, profile: &BatteryProfile) -> u16 {
+        match self.power.get() {
+            Some(power) if power >= Self::MIN_FLIGHT_POWER_MW => {
+                let energy_left = profile.energy as i32 * soc as i32 / 100; // mWh
+                let minutes = energy_left * 60 / power;
+
+                minutes.min(FLIGHT_TIME_UNKNOWN as i32 - 1) as u16
+            }
+            _ => FLIGHT_TIME_UNKNOWN,
+        }
+    }
+}
+
+const DISCHARGE_CURRENT_THRESHOLD: u16 = 400;
+const QUIT_CURRENT_THRESHOLD: u16 = 200;
+// Charger never goes above 4.2V, even for the high voltage cells
+const V_TAPER: u16 = 4200; // mV
+
+const LEARNING_STORE_MINUTES: u16 = 10;
+
+fn chem_id(chemistry: BatteryChemistry) -> ChemId {
+    match chemistry {
+        BatteryChemistry::B4200 => ChemId::B4200,
+        BatteryChemistry::A4350 => ChemId::A4350,
+        BatteryChemistry::C4400 => ChemId::C4400,
+    }
+}
+
+// Gauge takes a while to boot after ITPOR. Rather than sitting in a loop, this is checked
+// on every periodic update, so the SoC interrupts are still serviced in the meantime
+async fn gauge_init_complete<'a>(gauge: &mut Gauge<'a>, polls: &mut u8) -> GaugeResult<bool> {
+    const MAX_POLLS: u8 = 10;
+
+    if gauge
+        .get_control_status()
+        .await?
+        .contains(ControlStatusFlags::INITCOMP)
+    {
+        info!("fuelgauge init complete!");
+        return Ok(true);
+    }
+
+    *polls += 1;
+
+    match *polls >= MAX_POLLS {
+        true => Err(bq27xxx::ChipError::PollTimeout),
+        false => Ok(false),
+    }
+}
+
+// SOC1 and SOCF follow the low battery warning and arming inhibition levels, with
+// the same hysteresis the policy applies on its side
+async fn configure_soc_thresholds<'a>(
+    gauge: &mut Gauge<'a>,
+    thresholds: SocThresholds,
+) -> GaugeResult<()> {
+    info!(
+        "updating fuelgauge SoC thresholds ({}% / {}%)",
+        { thresholds.warn },
+        { thresholds.inhibit_arming }
+    );
 
     gauge
-        .memory_modify(|b: &mut StateClass| {
-            b.set_capacity(200);
-            b.set_energy(740); // capacity * 3.7
-            b.set_terminate_voltage(3200); // mV
+        .memory_modify(|b: &mut Discharge| {
+            b.set_soc1_set_threshold(thresholds.warn);
+            b.set_soc1_clear_threshold(thresholds.warn + SocPolicy::HYSTERESIS);
+            b.set_socf_set_threshold(thresholds.inhibit_arming);
+            b.set_socf_clear_threshold(thresholds.inhibit_arming + SocPolicy::HYSTERESIS);
+        })
+        .await
+}
 
-            // Taper Rate = Design Capacity / (0.1 × taper current)
-            // XXX: This assumes charge current is 100 mA, taper current is 25 ma
-            // npm1100 seems to come closer to 20 ma, then switches to 10 ma for 300ms, then drops to 0
-            b.set_taper_rate(75);
+async fn configure_gauge<'a>(
+    gauge: &mut Gauge<'a>,
+    profile: BatteryProfile,
+    learned: GaugeLearnedData,
+    thresholds: SocThresholds,
+) -> GaugeResult<()> {
+    // Chemistry goes first, since changing it reloads the rest of the data memory
+    gauge.write_chem_id(chem_id(profile.chemistry())).await?;
 
-            if start_learning {
-                b.set_update_status(0x03);
-            }
+    info!(
+        "updating fuelgauge memory ({}, {} mAh, {} mWh)...",
+        profile.chemistry(),
+        { profile.capacity },
+        { profile.energy }
+    );
 
-            // Learned value
-            b.set_qmax(17449);
+    gauge
+        .memory_modify(|b: &mut StateClass| {
+            b.set_capacity(profile.capacity);
+            b.set_energy(profile.energy);
+            b.set_terminate_voltage(profile.terminate_voltage);
+            b.set_taper_rate(profile.taper_rate);
+            b.set_qmax(learned.qmax);
+        })
+        .await?;
+
+    // GPOUT is in SOC_INT mode by default, so this is all it takes to
+    // get notified on every SoC change instead of polling for it
+    gauge
+        .memory_modify(|b: &mut Registers| {
+            b.set_soc_delta(profile.soc_delta);
         })
         .await?;
 
+    configure_soc_thresholds(gauge, thresholds).await?;
+
     gauge
         .memory_modify(|b: &mut CurrentThresholds| {
-            b.set_discharge_current_threshold(400);
-            b.set_quit_current_threshold(200);
+            b.set_discharge_current_threshold(DISCHARGE_CURRENT_THRESHOLD);
+            b.set_quit_current_threshold(QUIT_CURRENT_THRESHOLD);
         })
         .await?;
 
     gauge
         .memory_modify(|b: &mut RaTable| {
-            // This is obtained from learning cycle :)
-            b.set_points([50, 30, 34, 46, 38, 32, 37, 31, 32, 35, 39, 39, 61, 115, 200]);
+            b.set_points(learned.ra_table);
+        })
+        .await?;
+
+    gauge
+        .memory_modify(|b: &mut Safety| {
+            // 0.1 °C units. Plastic canopy in a hot car is a real thing
+            b.set_ot_chg(450);
+            b.set_ot_chg_recovery(400);
+            b.set_ot_dsg(600);
+            b.set_ot_dsg_recovery(550);
         })
         .await?;
 
     gauge
         .memory_modify(|b: &mut ChemInfo| {
-            b.set_v_taper(4200); // mV
+            b.set_v_taper(V_TAPER);
         })
         .await?;
 
@@ -100,16 +273,250 @@ async fn configure_gauge<'a>(gauge: &mut Gauge<'a>) -> GaugeResult<()> {
     Ok(())
 }
 
+// Compares the static part of the gauge configuration with what we expect. QMAX and
+// Ra table are left out, since gauge keeps updating them on its own
+async fn verify_gauge<'a>(
+    gauge: &mut Gauge<'a>,
+    profile: BatteryProfile,
+    soc_thresholds: SocThresholds,
+) -> GaugeResult<bool> {
+    let state_class = gauge.memblock_read::<StateClass>().await?;
+    let registers = gauge.memblock_read::<Registers>().await?;
+    let discharge = gauge.memblock_read::<Discharge>().await?;
+    let thresholds = gauge.memblock_read::<CurrentThresholds>().await?;
+    let chem_info = gauge.memblock_read::<ChemInfo>().await?;
+
+    let checks = [
+        ("capacity", state_class.capacity(), profile.capacity),
+        ("energy", state_class.energy(), profile.energy),
+        (
+            "terminate voltage",
+            state_class.terminate_voltage(),
+            profile.terminate_voltage,
+        ),
+        ("taper rate", state_class.taper_rate(), profile.taper_rate),
+        (
+            "soc delta",
+            registers.soc_delta() as u16,
+            profile.soc_delta as u16,
+        ),
+        (
+            "soc1 threshold",
+            discharge.soc1_set_threshold() as u16,
+            soc_thresholds.warn as u16,
+        ),
+        (
+            "socf threshold",
+            discharge.socf_set_threshold() as u16,
+            soc_thresholds.inhibit_arming as u16,
+        ),
+        (
+            "discharge current threshold",
+            thresholds.discharge_current_threshold(),
+            DISCHARGE_CURRENT_THRESHOLD,
+        ),
+        (
+            "quit current threshold",
+            thresholds.quit_current_threshold(),
+            QUIT_CURRENT_THRESHOLD,
+        ),
+        ("v taper", chem_info.v_taper(), V_TAPER),
+    ];
+
+    let mut matches = true;
+
+    if gauge.chem_id().await? != chem_id(profile.chemistry()) {
+        warn!(
+            "gauge config drift: chemistry is not {}",
+            profile.chemistry()
+        );
+        matches = false;
+    }
+
+    for (name, actual, expected) in checks {
+        if actual != expected {
+            warn!(
+                "gauge config drift: {} is {}, expected {}",
+                name, actual, expected
+            );
+            matches = false;
+        }
+    }
+
+    Ok(matches)
+}
+
+// Feeds the learning cycle with fresh gauge data. Returns the new phase, if it has
+// changed, and where the cycle is at
+async fn learning_step<'a>(
+    gauge: &mut Gauge<'a>,
+    learning: &mut LearningCycle,
+    mut sample: LearningSample,
+) -> GaugeResult<(Option<LearningPhase>, LearningReport)> {
+    let state_class = gauge.memblock_read::<StateClass>().await?;
+    sample.update_status = state_class.update_status();
+
+    let changed = learning.update(&sample);
+    let report = LearningReport {
+        phase: learning.phase() as u8,
+        update_status: sample.update_status,
+        qmax: state_class.qmax(),
+        voltage: sample.voltage,
+        current: sample.current,
+        rested: (learning.rested_for().as_secs() / 60).min(u16::MAX as u64) as u16,
+    };
+
+    Ok((changed, report))
+}
+
+async fn read_learned_data<'a>(gauge: &mut Gauge<'a>) -> GaugeResult<GaugeLearnedData> {
+    let state_class = gauge.memblock_read::<StateClass>().await?;
+    let ra_table = gauge.memblock_read::<RaTable>().await?;
+
+    Ok(GaugeLearnedData {
+        qmax: state_class.qmax(),
+        ra_table: ra_table.points(),
+    })
+}
+
+// Li-ion shouldn't be charged below 0 °C or above 45 °C. Temperature is in 0.1 K
+fn charge_temperature_ok(temperature: u16, currently_ok: bool) -> bool {
+    const MIN: i32 = 0;
+    const MAX: i32 = 450;
+    const HYSTERESIS: i32 = 20;
+
+    let celsius = temperature as i32 - 2731;
+    let margin = if currently_ok { 0 } else { HYSTERESIS };
+
+    celsius >= MIN + margin && celsius <= MAX - margin
+}
+
+// TEMP peripheral belongs to the softdevice. Its unit is 0.25 °C
+fn mcu_temperature() -> i16 {
+    let mut temperature = 0;
+
+    match unsafe { raw::sd_temp_get(&mut temperature) } {
+        0 => (temperature * 10 / 4) as i16,
+        e => {
+            warn!("unable to read the MCU temperature - {}", e);
+            i16::MIN
+        }
+    }
+}
+
+// Gauge keeps track of the temperature limits on its own, we just mirror the flags
+fn update_temperature_faults(state: &SystemState, flags: StatusFlags) {
+    state.set_faults(Faults::BATTERY_OVERTEMP, flags.contains(StatusFlags::OT));
+    state.set_faults(Faults::BATTERY_UNDERTEMP, flags.contains(StatusFlags::UT));
+}
+
+fn update_soc_flags(state: &SystemState, flags: StatusFlags) {
+    let mut soc_flags = GaugeSocFlags::empty();
+
+    soc_flags.set(GaugeSocFlags::LOW, flags.contains(StatusFlags::SOC1));
+    soc_flags.set(GaugeSocFlags::FINAL, flags.contains(StatusFlags::SOCF));
+
+    state.gauge_soc_flags.sender().send_if_modified(|current| {
+        let modified = *current != Some(soc_flags);
+        if modified {
+            info!("gauge SoC flags: {}", soc_flags);
+            *current = Some(soc_flags);
+        }
+
+        modified
+    });
+}
+
+fn battery_command(state: &SystemState, args: Args, out: &mut dyn Write) -> Result<(), Error> {
+    args.finish()?;
+
+    match state.soc.try_get() {
+        Some(soc) => write!(out, "soc {}%", soc)?,
+        None => write!(out, "soc unknown")?,
+    }
+
+    if state.soc_cached.try_get().unwrap_or_default() {
+        write!(out, " (cached)")?;
+    }
+
+    match state.rail_voltage.try_get() {
+        Some(mv) if mv != RAIL_VOLTAGE_UNKNOWN => writeln!(out, ", rail {} mV", mv)?,
+        _ => writeln!(out)?,
+    }
+
+    Ok(())
+}
+
+fn shutdown_command(state: &SystemState, args: Args, _: &mut dyn Write) -> Result<(), Error> {
+    args.finish()?;
+    state.request_shutdown(ShutdownReason::Host);
+    Ok(())
+}
+
+fn gauge_reset_command(state: &SystemState, args: Args, _: &mut dyn Write) -> Result<(), Error> {
+    args.finish()?;
+    state.requests.sender().send(Request::FuelgaugeReset);
+    Ok(())
+}
+
+pub const COMMANDS: &[Command] = &[
+    Command {
+        name: "battery",
+        help: "- SoC and the rail voltage",
+        run: battery_command,
+    },
+    Command {
+        name: "shutdown",
+        help: "- same as the switch",
+        run: shutdown_command,
+    },
+    Command {
+        name: "gauge-reset",
+        help: "- reset the fuel gauge",
+        run: gauge_reset_command,
+    },
+];
+
 #[embassy_executor::task]
-pub async fn run(state: &'static SystemState, mut r: PowerResources, i2c: &'static SharedI2cBus) {
+pub async fn run(state: &'static SystemState, r: PowerResources, i2c: &'static SharedI2cBus) {
+    taskstats::accounted(Task::Power, task(state, r, i2c)).await
+}
+
+async fn task(state: &'static SystemState, mut r: PowerResources, i2c: &'static SharedI2cBus) {
     const GAUGE_I2C_ADDR: u8 = 0x55;
     const GAUGE_INIT_RETRY_INTERVAL: Duration = Duration::from_secs(10);
     const GAUGE_PERIODIC_POLL_INTERVAL: Duration = Duration::from_secs(1);
+    const GAUGE_REDUCED_POLL_INTERVAL: Duration = Duration::from_secs(10);
+    // Gauge configuration takes a few seconds on top of the poll interval
+    const CHECK_IN_SLACK: Duration = Duration::from_secs(10);
 
     let soc_sender = state.soc.sender();
+    let soc_cached_sender = state.soc_cached.sender();
     let periodic_update_sender = state.periodic_update.sender();
+    let flight_time_sender = state.flight_time.sender();
     let charger_state_sender = state.charger_state.sender();
     let mut requests_receiver = unwrap!(state.requests.receiver());
+    let mut battery_profile_receiver = unwrap!(state.battery_profile.receiver());
+    let mut charge_mode_receiver = unwrap!(state.charge_mode.receiver());
+    let mut charger_faults_receiver = unwrap!(state.faults.receiver());
+    let mut gauge_learned_receiver = unwrap!(state.gauge_learned.receiver());
+    let mut soc_thresholds_receiver = unwrap!(state.soc_thresholds.receiver());
+    let mut shutdown_receiver = unwrap!(state.shutdown.receiver());
+    let mut rail_voltage_receiver = unwrap!(state.rail_voltage.receiver());
+    let mut charger_state_receiver = unwrap!(state.charger_state.receiver());
+    let learning_phase_sender = state.learning_phase.sender();
+    let learning_report_sender = state.learning_report.sender();
+    let mut learning_progress_receiver = unwrap!(state.learning_progress.receiver());
+    let requests_sender = state.requests.sender();
+
+    let mut learning = LearningCycle::new();
+    // Whatever the settings had, once they're loaded
+    let mut stored_learning: Option<LearningProgress> = None;
+
+    let mut armed_receiver = unwrap!(state.armed.receiver());
+    let flight_summary_sender = state.flight_summary.sender();
+    let mut power_stats: Option<PowerStats> = None;
+    let mut activity_receiver = unwrap!(state.activity.receiver());
 
     let force_memory_update = false;
 
@@ -121,57 +528,271 @@ pub async fn run(state: &'static SystemState, mut r: PowerResources, i2c: &'stat
         let mut int = Input::new(r.fuelgauge_int.reborrow(), Pull::Up);
         let mut gauge = Bq27xx::new(dev, embassy_time::Delay, GAUGE_I2C_ADDR);
 
-        let next_periodic_update = async || match do_periodic {
-            true => Timer::after(GAUGE_PERIODIC_POLL_INTERVAL).await,
+        let next_periodic_update = async |interval| match do_periodic {
+            true => Timer::after(interval).await,
             false => future::pending().await,
         };
 
+        let mut flight_time_estimator = FlightTimeEstimator::new();
+        let mut charge_temperature = true;
+        let mut executor_stats = (Instant::now(), executor::stats());
+
+        // Cached SoC from the settings stands in until the gauge has a proper reading
+        let publish_soc = async |gauge: &mut Gauge<'_>| -> GaugeResult<u8> {
+            let soc = gauge.state_of_charge().await? as u8;
+
+            soc_sender.send(soc);
+            soc_cached_sender.send(false);
+
+            Ok(soc)
+        };
+
+        // Number of polls spent waiting for the gauge to initialize after ITPOR, if any
+        let mut init_polls = None;
+        let mut soc = None;
+
         // SoC is important for internal decisions, so poll it once to see where we stand.
-        // Other stats will be gathered as we go
-        soc_sender.send(gauge.state_of_charge().await? as u8);
+        // Other stats will be gathered as we go. Gauge that has just been through ITPOR
+        // reports garbage until it's configured - this is handled with the periodic
+        // update below. Otherwise, configuration is supposed to be there already, but
+        // make sure it did not drift away
+        if gauge.get_flags().await?.contains(StatusFlags::ITPOR) || force_memory_update {
+            info!("fuelgauge ITPOR condition");
+            init_polls = Some(0);
+        } else {
+            soc = Some(publish_soc(&mut gauge).await?);
+
+            let profile = battery_profile_receiver.try_get().unwrap_or_default();
+            let learned = gauge_learned_receiver.try_get().unwrap_or_default();
+            let thresholds = soc_thresholds_receiver.try_get().unwrap_or_default();
+
+            gauge.probe().await?;
+
+            if !verify_gauge(&mut gauge, profile, thresholds).await? {
+                warn!("repairing fuelgauge configuration");
+                configure_gauge(&mut gauge, profile, learned, thresholds).await?;
+            }
+        }
 
         loop {
-            let s = select3(
-                int.wait_for_low(),
-                next_periodic_update(),
+            // Telemetry is much less interesting while sitting on the charger
+            let interval = match activity_receiver.try_get() {
+                Some(ActivityLevel::Reduced) => GAUGE_REDUCED_POLL_INTERVAL,
+                _ => GAUGE_PERIODIC_POLL_INTERVAL,
+            };
+
+            state
+                .supervisor
+                .check_in(Supervised::Power, interval + CHECK_IN_SLACK);
+
+            // SOC_INT is a short pulse, so don't rely on the level
+            let s = select4(
+                int.wait_for_falling_edge(),
+                next_periodic_update(interval),
                 requests_receiver.changed(),
+                shutdown_receiver.changed(),
             )
             .await;
 
             match s {
-                Either3::First(_) => {
-                    info!("fuelgauge interrupt");
-                    soc_sender.send(gauge.state_of_charge().await? as u8);
+                Either4::First(_) => {
+                    log!(Gauge, debug, "fuelgauge interrupt");
+
+                    if init_polls.is_none() {
+                        soc = Some(publish_soc(&mut gauge).await?);
+                    }
+
+                    let flags = gauge.get_flags().await?;
+                    update_temperature_faults(state, flags);
+                    update_soc_flags(state, flags);
                 }
-                Either3::Second(_) => {
+                Either4::Second(_) => {
                     let voltage = gauge.voltage().await?;
                     let current = gauge.average_current().await?;
                     let temperature = gauge.temperature().await?;
                     let flags = gauge.get_flags().await?;
 
-                    info!("{} mV, {} mA - {}", voltage, current, flags);
+                    log!(Gauge, info, "{} mV, {} mA - {}", voltage, current, flags);
+                    update_temperature_faults(state, flags);
+                    update_soc_flags(state, flags);
 
-                    if flags.contains(StatusFlags::ITPOR) || force_memory_update {
+                    charge_temperature = charge_temperature_ok(temperature, charge_temperature);
+                    state.set_faults(Faults::CHARGE_TEMPERATURE, !charge_temperature);
+
+                    if flags.contains(StatusFlags::ITPOR) && init_polls.is_none() {
                         info!("fuelgauge ITPOR condition");
+                        init_polls = Some(0);
+                    }
+
+                    if let Some(polls) = init_polls.as_mut() {
+                        if gauge_init_complete(&mut gauge, polls).await? {
+                            // Profile is loaded from flash at boot, it's fine to fall back to defaults
+                            // if settings are not there yet - next ITPOR will pick up the edited one
+                            let profile = battery_profile_receiver.try_get().unwrap_or_default();
+                            let learned = gauge_learned_receiver.try_get().unwrap_or_default();
+                            let thresholds = soc_thresholds_receiver.try_get().unwrap_or_default();
+
+                            gauge.probe().await?;
+                            configure_gauge(&mut gauge, profile, learned, thresholds).await?;
+
+                            init_polls = None;
+                            soc = Some(publish_soc(&mut gauge).await?);
+                        }
+                    }
 
-                        wait_gauge_init_complete(&mut gauge).await?;
+                    let profile = battery_profile_receiver.try_get().unwrap_or_default();
 
-                        gauge.probe().await?;
-                        configure_gauge(&mut gauge).await?;
+                    if stored_learning.is_none() {
+                        if let Some(progress) = learning_progress_receiver.try_get() {
+                            learning.restore(progress);
+                            learning_phase_sender.send(learning.phase());
+                            stored_learning = Some(progress);
+                        }
                     }
 
+                    if !matches!(
+                        learning.phase(),
+                        LearningPhase::Idle | LearningPhase::Complete
+                    ) {
+                        let sample = LearningSample {
+                            voltage,
+                            current,
+                            charging: matches!(charger_state_receiver.try_get(), Some(s) if s.charging),
+                            full_charge: flags.contains(StatusFlags::FC),
+                            update_status: 0,
+                            terminate_voltage: profile.terminate_voltage,
+                        };
+
+                        let (changed, report) =
+                            learning_step(&mut gauge, &mut learning, sample).await?;
+                        learning_report_sender.send(report);
+
+                        // Rests take hours, so a power cycle in the middle shouldn't
+                        // start them over. Every few minutes is plenty for that
+                        let progress = learning.progress();
+                        let store = stored_learning.is_some_and(|stored| {
+                            stored.phase != progress.phase || { stored.rested }
+                                .abs_diff(progress.rested)
+                                >= LEARNING_STORE_MINUTES
+                        });
+
+                        if store {
+                            requests_sender.send(Request::LearningProgressUpdate(progress));
+                            stored_learning = Some(progress);
+                        }
+
+                        if let Some(phase) = changed {
+                            learning_phase_sender.send(phase);
+
+                            if phase == LearningPhase::Complete {
+                                let learned = read_learned_data(&mut gauge).await?;
+
+                                info!("learned qmax: {}", { learned.qmax });
+                                requests_sender.send(Request::GaugeLearnedUpdate(learned));
+                            }
+                        }
+                    }
+
+                    // Flight begins once armed. Summary is published when it's over,
+                    // so we don't need to stream everything
+                    match (armed_receiver.try_get(), power_stats.as_mut()) {
+                        (Some(true), Some(stats)) => stats.add(voltage, current),
+                        (Some(true), None) => power_stats = Some(PowerStats::new()),
+                        (_, Some(stats)) => {
+                            if let Some(summary) = stats.summary() {
+                                info!(
+                                    "flight is over: {}s, {}..{} mV, {}..{} mA",
+                                    { summary.duration },
+                                    { summary.voltage_min },
+                                    { summary.voltage_max },
+                                    { summary.current_min },
+                                    { summary.current_max }
+                                );
+
+                                flight_summary_sender.send(summary);
+                            }
+
+                            power_stats = None;
+                        }
+                        _ => {}
+                    }
+
+                    flight_time_estimator.add_sample(voltage, current);
+                    let flight_time = soc.map_or(FLIGHT_TIME_UNKNOWN, |soc| {
+                        flight_time_estimator.estimate(soc, &profile)
+                    });
+                    flight_time_sender.send(flight_time);
+
+                    let (stats_at, earlier) = executor_stats;
+                    executor_stats = (Instant::now(), executor::stats());
+
+                    let (now, stats) = executor_stats;
+                    let wakeups = stats.wakeups.wrapping_sub(earlier.wakeups) as u64 * 1000
+                        / (now - stats_at).as_millis().max(1);
+
                     periodic_update_sender.send(PeriodicUpdate {
                         voltage,
                         current,
                         temperature,
+                        flight_time,
+                        mcu_temperature: mcu_temperature(),
+                        rail_voltage: rail_voltage_receiver
+                            .try_get()
+                            .unwrap_or(RAIL_VOLTAGE_UNKNOWN),
+                        cpu_load: stats.load_since(&earlier),
+                        wakeups: wakeups.min(u16::MAX as u64) as u16,
                     });
                 }
 
-                Either3::Third(Request::FuelgaugeReset) => {
+                Either4::Third(Request::FuelgaugeReset) => {
                     warn!("resetting the fuel-gauge!");
                     gauge.reset().await?;
                 }
-                Either3::Third(_) => {}
+
+                Either4::Third(Request::StartLearning) => {
+                    gauge
+                        .memory_modify(|b: &mut StateClass| {
+                            b.set_update_status(UPDATE_STATUS_LEARNING);
+                        })
+                        .await?;
+
+                    learning.start();
+                    learning_phase_sender.send(learning.phase());
+                }
+                // Other profile changes can wait for the next ITPOR, but the chemistry
+                // is what the whole model is built upon
+                Either4::Third(Request::BatteryProfileUpdate(profile)) if init_polls.is_none() => {
+                    if gauge.chem_id().await? != chem_id(profile.chemistry()) {
+                        info!("battery chemistry changed to {}", profile.chemistry());
+
+                        let learned = gauge_learned_receiver.try_get().unwrap_or_default();
+                        let thresholds = soc_thresholds_receiver.try_get().unwrap_or_default();
+
+                        configure_gauge(&mut gauge, profile, learned, thresholds).await?;
+                    }
+                }
+
+                // Not worth waiting for the next ITPOR, unlike the profile
+                Either4::Third(Request::SocThresholdsUpdate(thresholds))
+                    if init_polls.is_none() =>
+                {
+                    configure_soc_thresholds(&mut gauge, thresholds).await?;
+                }
+                Either4::Third(_) => {}
+
+                // Gauge draws way more than we do in System OFF, which matters for a cell
+                // that is already depleted. Otherwise, keep it running, so it can track
+                // the self-discharge and we don't need to go through ITPOR next time
+                Either4::Fourth(reason) => {
+                    if reason == ShutdownReason::BatteryDepleted {
+                        info!("shutting the fuelgauge down");
+                        gauge.shutdown().await?;
+                    }
+
+                    state.ack_shutdown(ShutdownAcks::GAUGE);
+                    state.supervisor.release(Supervised::Power);
+                    future::pending::<()>().await;
+                }
             }
         }
     };
@@ -179,15 +800,46 @@ pub async fn run(state: &'static SystemState, mut r: PowerResources, i2c: &'stat
     let mut poll_charger = async || {
         let mut fault = Input::new(r.fault_int.reborrow(), Pull::Up);
         let mut charging = Input::new(r.charging_int.reborrow(), Pull::Up);
+        #[cfg(feature = "charger-control")]
+        let mut charger = Charger::new(
+            r.charger_iset_sel.reborrow(),
+            r.charger_vterm_sel.reborrow(),
+        );
+        #[cfg(feature = "charge-inhibit")]
+        let mut inhibit = ChargeInhibit::new(r.charger_inhibit.reborrow());
 
         loop {
+            #[cfg(feature = "charger-control")]
+            charger.set_mode(charge_mode_receiver.try_get().unwrap_or_default());
+            // Without it, the fault and its blink pattern are all there is
+            #[cfg(feature = "charge-inhibit")]
+            inhibit.set_inhibited(
+                charger_faults_receiver
+                    .try_get()
+                    .unwrap_or_default()
+                    .contains(Faults::CHARGE_TEMPERATURE),
+            );
+
             charger_state_sender.send(ChargerState {
                 charging: charging.is_low(),
                 failure: fault.is_low(),
             });
 
-            select(charging.wait_for_any_edge(), fault.wait_for_any_edge()).await;
-            info!("charger status update");
+            state.set_faults(Faults::CHARGER_FAILURE, fault.is_low());
+
+            let s = select4(
+                charging.wait_for_any_edge(),
+                fault.wait_for_any_edge(),
+                charge_mode_receiver.changed(),
+                charger_faults_receiver.changed(),
+            )
+            .await;
+
+            match s {
+                Either4::Third(mode) => info!("charge mode is now {}", mode),
+                Either4::Fourth(_) => {}
+                _ => info!("charger status update"),
+            }
         }
     };
 
@@ -196,7 +848,15 @@ pub async fn run(state: &'static SystemState, mut r: PowerResources, i2c: &'stat
 
         match select(poll_gauge(periodic_update), poll_charger()).await {
             Either::First(Err(e)) => {
-                error!("gauge communication failure - {}", e);
+                state.supervisor.release(Supervised::Power);
+
+                incident!(
+                    error,
+                    state,
+                    Incident::GaugeCommunication,
+                    "gauge communication failure - {}",
+                    e
+                );
                 Timer::after(GAUGE_INIT_RETRY_INTERVAL).await
             }
 
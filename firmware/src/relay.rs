@@ -0,0 +1,130 @@
+// Gamepad relay
+//
+// Pads that can't pair with us, wired ones or anything that isn't an Xbox one, fly
+// through a PC instead: tools/relay reads the pad and writes it to the direct control
+// characteristic at 50Hz. Frames are laid out as the Xbox HID report, so they go
+// through the same decoding and the same pilot profile buttons (see xbox.rs).
+//
+// Pairing is Just Works, so anyone in range could do it - the encrypted link is only
+// there so the OS gets prompted. What lets a peer fly is a press of the switch: its
+// first frame asks for access, the LED flashes twice and a short press lets it in.
+// That's good for the one connection, the next one asks again.
+//
+// Same as the stock remote it's a fallback. It only takes over while neither a BLE
+// controller nor the remote is flying, and a controller connecting takes over right
+// away. Frames that stop coming for a moment are a lost relay, same as with the remote.
+
+use defmt::{info, unwrap};
+use embassy_time::{with_timeout, Duration};
+
+use crate::{
+    eventlog::Event,
+    indications::OneShot,
+    state::SystemState,
+    taskstats::{self, Task},
+    types::PilotProfile,
+};
+
+// A dozen frames or so, the PC's BLE stack tends to bunch them up
+const LOST_AFTER: Duration = Duration::from_millis(250);
+
+#[derive(Clone, Copy, PartialEq, Default, defmt::Format)]
+pub enum RelayAccess {
+    #[default]
+    Closed,
+    // Frames came in, waiting for the switch
+    Pending,
+    Approved,
+}
+
+// From the GATT handler, on a frame from a peer that isn't let in yet
+pub fn request_access(state: &SystemState) {
+    let requested = state.relay_access.sender().send_if_modified(|access| {
+        let closed = !matches!(access, Some(RelayAccess::Pending | RelayAccess::Approved));
+        if closed {
+            *access = Some(RelayAccess::Pending);
+        }
+
+        closed
+    });
+
+    if requested {
+        info!("gamepad relay is asking to fly, press the switch to let it");
+        state.indicate_once(OneShot::Flashes(2));
+    }
+}
+
+// From the switch, returns whether there was anyone to let in
+pub fn grant_access(state: &SystemState) -> bool {
+    let granted = state.relay_access.sender().send_if_modified(|access| {
+        let pending = *access == Some(RelayAccess::Pending);
+        if pending {
+            *access = Some(RelayAccess::Approved);
+        }
+
+        pending
+    });
+
+    if granted {
+        info!("gamepad relay is let in");
+        state.indicate_once(OneShot::Confirm);
+    }
+
+    granted
+}
+
+// Once the peer is gone, whoever connects next has to ask again
+pub fn revoke_access(state: &SystemState) {
+    state.relay_access.sender().send(RelayAccess::Closed);
+}
+
+#[embassy_executor::task]
+pub async fn run(state: &'static SystemState) {
+    taskstats::accounted(Task::Relay, task(state)).await
+}
+
+async fn task(state: &'static SystemState) {
+    let mut controller_connected_receiver = unwrap!(state.controller_connected.receiver());
+    let mut remote_engaged_receiver = unwrap!(state.remote_engaged.receiver());
+    let controller_sample_sender = state.controller_sample.sender();
+    let relay_engaged_sender = state.relay_engaged.sender();
+
+    let mut engaged = false;
+
+    loop {
+        // Idle until the first frame, no need to notice anything before that
+        let frame = match engaged {
+            true => with_timeout(LOST_AFTER, state.relay_frame.wait())
+                .await
+                .ok(),
+            false => Some(state.relay_frame.wait().await),
+        };
+
+        let taken = controller_connected_receiver.try_get() == Some(true)
+            || remote_engaged_receiver.try_get() == Some(true);
+
+        if engaged && (taken || frame.is_none()) {
+            info!("gamepad relay is no longer in control");
+            engaged = false;
+            relay_engaged_sender.send(false);
+            state.log_event(Event::ControllerDisconnected, 2);
+        }
+
+        let Some(frame) = frame.filter(|_| !taken) else {
+            continue;
+        };
+
+        if !engaged {
+            info!("gamepad relay took over");
+
+            // Frames are the Xbox layout, whatever the pad is
+            state.pilot_profile.sender().send(PilotProfile::default());
+
+            engaged = true;
+            relay_engaged_sender.send(true);
+            state.log_event(Event::ControllerConnected, 2);
+        }
+
+        controller_sample_sender.send(frame);
+    }
+}
@@ -0,0 +1,92 @@
+// Power-on self-test
+//
+// Catches broken wiring before the first flight attempt of the day. The LED goes
+// through its whole range, the gauge is probed on the bus, and the control task reads
+// the gyro and pulses each motor. Failures are raised as faults, so they are blinked
+// out and keep us on the ground until the next power cycle.
+
+use defmt::{info, unwrap, warn};
+use embassy_time::{with_timeout, Duration};
+use embedded_hal_async::i2c::I2c;
+
+use crate::{
+    indications::OneShot,
+    state::SystemState,
+    taskstats::{self, Task},
+    types::Faults,
+    SharedI2cBus,
+};
+
+#[repr(u8)]
+#[derive(Clone, Copy, PartialEq, Default, defmt::Format)]
+pub enum SelfTestMode {
+    #[default]
+    Off = 0,
+    // Everything but the motors, e.g. when the heli can't be left to spin up on the bench
+    NoMotors = 1,
+    Full = 2,
+}
+
+impl SelfTestMode {
+    pub fn from_u8(v: u8) -> Option<Self> {
+        match v {
+            0 => Some(Self::Off),
+            1 => Some(Self::NoMotors),
+            2 => Some(Self::Full),
+            _ => None,
+        }
+    }
+}
+
+// Gauge answers on this address no matter what state it's in
+const GAUGE_ADDRESS: u8 = 0x55;
+
+async fn probe_gauge(i2c: &SharedI2cBus) -> bool {
+    let mut buf = [0; 1];
+    i2c.lock().await.read(GAUGE_ADDRESS, &mut buf).await.is_ok()
+}
+
+#[embassy_executor::task]
+pub async fn run(state: &'static SystemState, i2c: &'static SharedI2cBus) {
+    taskstats::accounted(Task::SelfTest, task(state, i2c)).await
+}
+
+async fn task(state: &'static SystemState, i2c: &'static SharedI2cBus) {
+    // Gyro settles and motors take a bit to pulse, nothing else should take that long
+    const CONTROL_TEST_TIMEOUT: Duration = Duration::from_secs(3);
+
+    let mut self_test_mode_receiver = unwrap!(state.self_test_mode.receiver());
+
+    // Comes from the settings
+    let mode = self_test_mode_receiver.get().await;
+    if mode == SelfTestMode::Off {
+        return;
+    }
+
+    info!("running self-test ({})", mode);
+
+    state.indicate_once(OneShot::Sweep);
+
+    let mut failures = Faults::empty();
+
+    if !probe_gauge(i2c).await {
+        failures |= Faults::SELF_TEST_GAUGE;
+    }
+
+    // Control task owns the gyro and the motors
+    state.self_test.signal(mode);
+
+    match with_timeout(CONTROL_TEST_TIMEOUT, state.self_test_report.wait()).await {
+        Ok(report) => failures |= report,
+        Err(_) => failures |= Faults::SELF_TEST_GYRO,
+    }
+
+    if !failures.is_empty() {
+        warn!("self-test failed - {}", failures);
+        state.set_faults(failures, true);
+        return;
+    }
+
+    info!("self-test passed");
+    state.indicate_once(OneShot::Flashes(2));
+}
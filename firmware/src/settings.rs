@@ -0,0 +1,840 @@
+use core::mem::size_of;
+
+use copter_core::boot::ImageState;
+use defmt::{info, unwrap, warn};
+use embassy_futures::select::{select3, select4, Either3, Either4};
+use embassy_time::{Duration, Instant, Timer};
+use embedded_storage_async::nor_flash::{NorFlash, ReadNorFlash};
+use nrf_softdevice::{Flash, FlashError};
+
+use crate::{
+    aux::AuxOutputs,
+    blackbox::{incident, Blackbox, Incident},
+    board::DEFAULT_GYRO_OFFSET,
+    boot,
+    charger::ChargeMode,
+    clock::{self, DeviceTime},
+    dfu,
+    eventlog::{Event, LoggedEvent},
+    factory, history,
+    indications::{FlightLight, IndicationTheme, OneShot},
+    params::ParamValues,
+    postmortem::FaultReport,
+    selftest::SelfTestMode,
+    state::{Request, StateReceiver, SystemState},
+    taskstats::{self, Task},
+    types::{
+        BatteryProfile, BootCounters, CompassCalibration, ControllerAddress, ControllerBond,
+        ControllerProfile, DeviceIrk, GaugeLearnedData, ImuGyroBias, LearningProgress, Odometer,
+        PeerAttrs, SettingsGroups, ShutdownAcks, SocThresholds,
+    },
+    utils,
+};
+
+extern "C" {
+    // Provided by memory.x
+    static __settings_start: u32;
+    static __settings_end: u32;
+}
+
+// Change this whenever the layout of the record changes, so stale data is discarded
+const SETTINGS_MAGIC: u32 = 0x5107_0018;
+
+#[repr(C)]
+#[derive(Copy, Clone)]
+struct Record {
+    magic: u32,
+    battery_profile: BatteryProfile,
+    charge_mode: u8,
+    soc_thresholds: SocThresholds,
+    gauge_learned: GaugeLearnedData,
+    learning: LearningProgress,
+    // Last known SoC, so we have something to go with while the gauge boots
+    last_soc: u8,
+    flight_light: u8,
+    self_test_mode: u8,
+    _reserved: [u8; 1],
+    indication_theme: IndicationTheme,
+    gyro_offset: i16,
+    _reserved2: [u8; 2],
+    odometer: Odometer,
+    controller: ControllerAddress,
+    controller_bond: ControllerBond,
+    device_irk: DeviceIrk,
+    peer_attrs: [PeerAttrs; PeerAttrs::TABLE_LEN],
+    boot_counters: BootCounters,
+    params: ParamValues,
+    last_fault: FaultReport,
+    device_time: DeviceTime,
+    pilot_profiles: [ControllerProfile; ControllerProfile::TABLE_LEN],
+    compass_calibration: CompassCalibration,
+    imu_gyro_bias: ImuGyroBias,
+    aux_outputs: AuxOutputs,
+}
+
+// Softdevice flash API works with whole words only
+const _: () = assert!(size_of::<Record>() % 4 == 0);
+
+impl Default for Record {
+    fn default() -> Self {
+        Self {
+            magic: SETTINGS_MAGIC,
+            // Provisioned cell, if any
+            battery_profile: factory::battery_profile(),
+            charge_mode: ChargeMode::default() as u8,
+            soc_thresholds: SocThresholds::default(),
+            gauge_learned: GaugeLearnedData::default(),
+            learning: LearningProgress::default(),
+            last_soc: 0xff,
+            flight_light: FlightLight::default() as u8,
+            self_test_mode: SelfTestMode::default() as u8,
+            _reserved: [0; 1],
+            indication_theme: IndicationTheme::default(),
+            gyro_offset: DEFAULT_GYRO_OFFSET,
+            _reserved2: [0; 2],
+            odometer: Odometer::default(),
+            controller: ControllerAddress::default(),
+            controller_bond: ControllerBond::default(),
+            device_irk: DeviceIrk::default(),
+            peer_attrs: [PeerAttrs::EMPTY; PeerAttrs::TABLE_LEN],
+            boot_counters: BootCounters::default(),
+            params: ParamValues::default(),
+            last_fault: FaultReport::EMPTY,
+            device_time: DeviceTime::default(),
+            pilot_profiles: [ControllerProfile::default(); ControllerProfile::TABLE_LEN],
+            compass_calibration: CompassCalibration::default(),
+            imu_gyro_bias: ImuGyroBias::default(),
+            aux_outputs: AuxOutputs::default(),
+        }
+    }
+}
+
+impl Record {
+    // Whatever is not in the groups stays as it is, and so does everything that
+    // isn't a setting as such, like the device time
+    fn restore_defaults(&mut self, groups: SettingsGroups) {
+        let defaults = Self::default();
+
+        if groups.contains(SettingsGroups::BATTERY) {
+            self.battery_profile = defaults.battery_profile;
+            self.charge_mode = defaults.charge_mode;
+            self.soc_thresholds = defaults.soc_thresholds;
+        }
+
+        if groups.contains(SettingsGroups::GAUGE_LEARNED) {
+            self.gauge_learned = defaults.gauge_learned;
+            self.learning = defaults.learning;
+        }
+
+        if groups.contains(SettingsGroups::INDICATIONS) {
+            self.indication_theme = defaults.indication_theme;
+            self.flight_light = defaults.flight_light;
+            self.aux_outputs = defaults.aux_outputs;
+        }
+
+        // PID gains are not stored, the control task drops them on its own
+        if groups.contains(SettingsGroups::CONTROL) {
+            self.gyro_offset = defaults.gyro_offset;
+            self.compass_calibration = defaults.compass_calibration;
+            self.imu_gyro_bias = defaults.imu_gyro_bias;
+            self.params = defaults.params;
+            self.pilot_profiles = defaults.pilot_profiles;
+        }
+
+        // Peers that knew the old key won't recognize us anymore, a new one is made
+        // on the next boot
+        if groups.contains(SettingsGroups::PEERS) {
+            self.controller = defaults.controller;
+            self.controller_bond = defaults.controller_bond;
+            self.device_irk = defaults.device_irk;
+            self.peer_attrs = defaults.peer_attrs;
+        }
+
+        if groups.contains(SettingsGroups::SELF_TEST) {
+            self.self_test_mode = defaults.self_test_mode;
+        }
+
+        if groups.contains(SettingsGroups::STATISTICS) {
+            self.odometer = defaults.odometer;
+            self.boot_counters = defaults.boot_counters;
+            self.last_fault = defaults.last_fault;
+        }
+    }
+}
+
+// Records are appended to a log instead of being rewritten in place, and a page is only
+// erased once the log wraps around to it. The other page holds the latest record by then,
+// so losing power halfway through a write or an erase costs one update at most
+const PAGE_SIZE: u32 = 4096;
+
+#[repr(C)]
+#[derive(Copy, Clone)]
+struct Slot {
+    record: Record,
+    // The highest one is the latest
+    sequence: u32,
+    // Over everything above, catches records torn by a power loss
+    checksum: u32,
+}
+
+const SLOT_SIZE: u32 = size_of::<Slot>() as u32;
+const SLOTS_PER_PAGE: u32 = PAGE_SIZE / SLOT_SIZE;
+
+// Erased flash reads as 0xff
+const BLANK: u32 = u32::MAX;
+
+fn settings_region() -> (u32, u32) {
+    unsafe {
+        (
+            &__settings_start as *const u32 as u32,
+            &__settings_end as *const u32 as u32,
+        )
+    }
+}
+
+fn slot_count() -> u32 {
+    let (start, end) = settings_region();
+    (end - start) / PAGE_SIZE * SLOTS_PER_PAGE
+}
+
+// Slots never cross page boundaries, so each page can be erased on its own
+fn slot_address(index: u32) -> u32 {
+    let (start, _) = settings_region();
+    start + index / SLOTS_PER_PAGE * PAGE_SIZE + index % SLOTS_PER_PAGE * SLOT_SIZE
+}
+
+// FNV-1a, good enough to tell a torn write
+fn checksum(slot: &Slot) -> u32 {
+    let bytes = unsafe {
+        core::slice::from_raw_parts(
+            slot as *const Slot as *const u8,
+            size_of::<Slot>() - size_of::<u32>(),
+        )
+    };
+
+    bytes.iter().fold(0x811c_9dc5, |hash, &b| {
+        (hash ^ b as u32).wrapping_mul(0x0100_0193)
+    })
+}
+
+struct Storage {
+    flash: Flash,
+    // Where the next record goes, and its sequence number
+    next: u32,
+    sequence: u32,
+    // When the latest record went in
+    stored_at: Instant,
+}
+
+impl Storage {
+    async fn read_slot(&mut self, index: u32) -> Result<Slot, FlashError> {
+        let mut slot = Slot {
+            record: Record::default(),
+            sequence: 0,
+            checksum: 0,
+        };
+
+        let bytes = unsafe {
+            core::slice::from_raw_parts_mut(&mut slot as *mut Slot as *mut u8, size_of::<Slot>())
+        };
+
+        self.flash.read(slot_address(index), bytes).await?;
+        Ok(slot)
+    }
+
+    // Goes through the whole log to find the latest record, and where the next one goes
+    async fn load(&mut self) -> Result<Option<Record>, FlashError> {
+        let mut latest: Option<(u32, Slot)> = None;
+
+        for index in 0..slot_count() {
+            let slot = self.read_slot(index).await?;
+
+            // Stale layouts and torn writes alike
+            if slot.record.magic != SETTINGS_MAGIC || slot.checksum != checksum(&slot) {
+                continue;
+            }
+
+            if latest.is_none_or(|(_, l)| slot.sequence > l.sequence) {
+                latest = Some((index, slot));
+            }
+        }
+
+        let Some((index, slot)) = latest else {
+            return Ok(None);
+        };
+
+        self.next = (index + 1) % slot_count();
+        self.sequence = slot.sequence + 1;
+
+        Ok(Some(slot.record))
+    }
+
+    async fn store(&mut self, record: &Record) -> Result<(), FlashError> {
+        loop {
+            let index = self.next;
+            let address = slot_address(index);
+
+            self.next = (index + 1) % slot_count();
+
+            if index % SLOTS_PER_PAGE == 0 {
+                self.flash.erase(address, address + PAGE_SIZE).await?;
+            } else if self.read_slot(index).await?.record.magic != BLANK {
+                // Whatever was written there did not make it, look further
+                continue;
+            }
+
+            let mut slot = Slot {
+                record: *record,
+                sequence: self.sequence,
+                checksum: 0,
+            };
+
+            slot.checksum = checksum(&slot);
+
+            let bytes = unsafe {
+                core::slice::from_raw_parts(&slot as *const Slot as *const u8, size_of::<Slot>())
+            };
+
+            self.flash.write(address, bytes).await?;
+            self.sequence += 1;
+            self.stored_at = Instant::now();
+
+            return Ok(());
+        }
+    }
+}
+
+// Every store takes up a slot, so only track the SoC coarsely
+const SOC_CACHE_STEP: u8 = 5;
+
+// Statistics (odometer, cached SoC) change all the time, so they're not stored right
+// away. Changes pile up in RAM and go in with whatever is stored next, or once the heli
+// is disarmed, but no more often than that. The shutdown sequence stores them anyway,
+// so only a dead battery could lose some. A page takes ~10k erases, which is years of
+// flying at that rate
+const STATISTICS_STORE_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+// Never while armed, flash writes stall the CPU and the control loop with it
+async fn disarmed_at(armed_receiver: &mut StateReceiver<'_, bool>, due_at: Option<Instant>) {
+    let Some(at) = due_at else {
+        return core::future::pending().await;
+    };
+
+    loop {
+        utils::wait_for(armed_receiver, |armed| !*armed).await;
+        Timer::at(at).await;
+
+        if armed_receiver.try_get() == Some(false) {
+            return;
+        }
+    }
+}
+
+// The same incident over and over again is most likely a single problem, and the
+// black box has better things to do than to wear the flash out
+const BLACKBOX_REPEAT_INTERVAL: Duration = Duration::from_secs(60);
+
+async fn store_or_complain(state: &SystemState, storage: &mut Storage, record: &Record) {
+    // Goes along with whatever else is stored, see clock.rs
+    let record = Record {
+        device_time: clock::device_time(),
+        ..*record
+    };
+
+    if let Err(e) = storage.store(&record).await {
+        incident!(
+            error,
+            state,
+            Incident::SettingsStore,
+            "unable to store settings - {}",
+            e
+        );
+    }
+}
+
+// Everyone else gets to know about the settings through the state
+fn publish(state: &SystemState, record: &Record) {
+    let charge_mode = ChargeMode::from_u8(record.charge_mode).unwrap_or_default();
+    let flight_light = FlightLight::from_u8(record.flight_light).unwrap_or_default();
+    let self_test_mode = SelfTestMode::from_u8(record.self_test_mode).unwrap_or_default();
+
+    state.battery_profile.sender().send(record.battery_profile);
+    state.charge_mode.sender().send(charge_mode);
+    state.soc_thresholds.sender().send(record.soc_thresholds);
+    state.gauge_learned.sender().send(record.gauge_learned);
+    state.learning_progress.sender().send(record.learning);
+    state
+        .indication_theme
+        .sender()
+        .send(record.indication_theme);
+    state.flight_light.sender().send(flight_light);
+    state.aux_outputs.sender().send(record.aux_outputs);
+    state.self_test_mode.sender().send(self_test_mode);
+    state.gyro_offset.sender().send(record.gyro_offset);
+    state
+        .compass_calibration
+        .sender()
+        .send(record.compass_calibration);
+    state.imu_gyro_bias.sender().send(record.imu_gyro_bias);
+    state.params.sender().send(record.params);
+    state.odometer.sender().send(record.odometer);
+    state.known_controller.sender().send(record.controller);
+    state.controller_bond.sender().send(record.controller_bond);
+    state.device_irk.sender().send(record.device_irk);
+    state.peer_attrs.sender().send(record.peer_attrs);
+    state.pilot_profiles.sender().send(record.pilot_profiles);
+
+    // Peripheral picks up the fault along with the counters
+    state.last_fault.sender().send(record.last_fault);
+    state.boot_counters.sender().send(record.boot_counters);
+}
+
+async fn publish_blackbox(state: &SystemState, blackbox: &Blackbox, flash: &mut Flash) {
+    match blackbox.recent(flash).await {
+        Ok(log) => state.blackbox.sender().send(log),
+        Err(e) => warn!("unable to read the black box - {}", e),
+    }
+}
+
+async fn publish_history(state: &SystemState, flash: &mut Flash, page: u8) {
+    match history::read_page(flash, page as u32).await {
+        Ok(page) => state.history_page.sender().send(page),
+        Err(e) => warn!("unable to read the history - {}", e),
+    }
+}
+
+async fn dump_history(state: &SystemState, flash: &mut Flash, boot: u32) {
+    let Some(trigger) = history::frozen() else {
+        return;
+    };
+
+    info!("dumping the history, frozen by {}", trigger);
+
+    if let Err(e) = history::dump(flash, boot).await {
+        warn!("unable to dump the history - {}", e);
+    }
+
+    publish_history(state, flash, 0).await;
+}
+
+#[embassy_executor::task]
+pub async fn run(state: &'static SystemState, flash: Flash) {
+    taskstats::accounted(Task::Settings, task(state, flash)).await
+}
+
+async fn task(state: &'static SystemState, flash: Flash) {
+    info!("settings task running");
+
+    let mut requests_receiver = unwrap!(state.requests.receiver());
+    let mut soc_receiver = unwrap!(state.soc.receiver());
+    let mut shutdown_receiver = unwrap!(state.shutdown.receiver());
+    let mut armed_receiver = unwrap!(state.armed.receiver());
+    let battery_profile_sender = state.battery_profile.sender();
+    let charge_mode_sender = state.charge_mode.sender();
+    let soc_thresholds_sender = state.soc_thresholds.sender();
+    let gauge_learned_sender = state.gauge_learned.sender();
+    let indication_theme_sender = state.indication_theme.sender();
+    let flight_light_sender = state.flight_light.sender();
+    let aux_outputs_sender = state.aux_outputs.sender();
+    let self_test_mode_sender = state.self_test_mode.sender();
+    let gyro_offset_sender = state.gyro_offset.sender();
+    let compass_calibration_sender = state.compass_calibration.sender();
+    let imu_gyro_bias_sender = state.imu_gyro_bias.sender();
+    let odometer_sender = state.odometer.sender();
+    let known_controller_sender = state.known_controller.sender();
+    let peer_attrs_sender = state.peer_attrs.sender();
+    let pilot_profiles_sender = state.pilot_profiles.sender();
+    let params_sender = state.params.sender();
+
+    let mut storage = Storage {
+        flash,
+        next: 0,
+        sequence: 0,
+        stored_at: Instant::now(),
+    };
+
+    let mut record = match storage.load().await {
+        Ok(Some(record)) => record,
+        Ok(None) => {
+            warn!("no stored settings, using defaults");
+            Record::default()
+        }
+        Err(e) => {
+            incident!(
+                error,
+                state,
+                Incident::SettingsRead,
+                "unable to read settings - {}",
+                e
+            );
+            Record::default()
+        }
+    };
+
+    clock::resume(record.device_time);
+    record.params.sanitize();
+
+    // This is the earliest we can get to the flash. Stored right away, a unit that
+    // keeps resetting might not live long enough for anything else to do that
+    let mut boot_counters = record.boot_counters;
+    boot_counters.count(
+        &state.boot_info,
+        state.panic_report.is_some(),
+        state.fault_report.is_some(),
+    );
+
+    info!(
+        "boots: {}, watchdog resets: {}, panic resets: {}, fault resets: {}",
+        { boot_counters.boots },
+        { boot_counters.watchdog_resets },
+        { boot_counters.panic_resets },
+        { boot_counters.fault_resets }
+    );
+
+    // Retained RAM doesn't survive a power cycle, flash does
+    if let Some(report) = state.fault_report {
+        record.last_fault = report;
+    }
+
+    record.boot_counters = boot_counters;
+
+    publish(state, &record);
+    store_or_complain(state, &mut storage, &record).await;
+
+    let mut blackbox = match Blackbox::load(&mut storage.flash).await {
+        Ok(blackbox) => Some(blackbox),
+        Err(e) => {
+            warn!(
+                "unable to read the black box, not keeping incidents - {}",
+                e
+            );
+            None
+        }
+    };
+
+    if let Some(blackbox) = &blackbox {
+        publish_blackbox(state, blackbox, &mut storage.flash).await;
+    }
+
+    // Panics and hard faults leave it frozen on their way out
+    match history::frozen() {
+        Some(_) => dump_history(state, &mut storage.flash, record.boot_counters.boots).await,
+        None => publish_history(state, &mut storage.flash, 0).await,
+    }
+
+    let mut last_incident: Option<(LoggedEvent, Instant)> = None;
+    // Set while there are statistics waiting to be stored
+    let mut statistics_due_at: Option<Instant> = None;
+    // Set while the firmware image is on trial, see boot.rs
+    let mut confirm_image_at = match boot::image_state(&state.boot_info) {
+        ImageState::Trial => Some(Instant::MIN + boot::CONFIRM_AFTER),
+        _ => None,
+    };
+    let mut updater = dfu::Updater::new(state);
+
+    // Power task may have beaten us to it, in which case the gauge reading wins
+    if record.last_soc <= 100 {
+        state.soc.sender().send_if_modified(|soc| match soc {
+            Some(_) => false,
+            None => {
+                info!(
+                    "using cached SoC {}% until the gauge is ready",
+                    record.last_soc
+                );
+
+                *soc = Some(record.last_soc);
+                state.soc_cached.sender().send(true);
+                true
+            }
+        });
+    }
+
+    loop {
+        let s = select4(
+            requests_receiver.changed(),
+            soc_receiver.changed(),
+            shutdown_receiver.changed(),
+            select3(
+                state.incidents.receive(),
+                state.dfu_requests.receive(),
+                disarmed_at(
+                    &mut armed_receiver,
+                    statistics_due_at.into_iter().chain(confirm_image_at).min(),
+                ),
+            ),
+        )
+        .await;
+
+        let request = match s {
+            Either4::First(request) => request,
+            Either4::Second(soc) => {
+                if soc.abs_diff(record.last_soc) < SOC_CACHE_STEP {
+                    continue;
+                }
+
+                record.last_soc = soc;
+                statistics_due_at.get_or_insert(storage.stored_at + STATISTICS_STORE_INTERVAL);
+                continue;
+            }
+
+            // Make sure the latest SoC makes it to flash, step or not
+            Either4::Third(_) => {
+                if let Some(soc) = soc_receiver.try_get() {
+                    record.last_soc = soc;
+                }
+
+                store_or_complain(state, &mut storage, &record).await;
+                statistics_due_at = None;
+                state.ack_shutdown(ShutdownAcks::SETTINGS);
+                continue;
+            }
+
+            Either4::Fourth(Either3::Third(_)) => {
+                let now = Instant::now();
+
+                if confirm_image_at.is_some_and(|at| at <= now) {
+                    boot::confirm(&mut storage.flash).await;
+                    confirm_image_at = None;
+                }
+
+                if statistics_due_at.is_some_and(|at| at <= now) {
+                    store_or_complain(state, &mut storage, &record).await;
+                    statistics_due_at = None;
+                }
+
+                continue;
+            }
+
+            Either4::Fourth(Either3::First(incident)) => {
+                let repeated = last_incident.is_some_and(|(last, at)| {
+                    last.same_as(&incident) && at.elapsed() < BLACKBOX_REPEAT_INTERVAL
+                });
+
+                if let (Some(blackbox), false) = (&mut blackbox, repeated) {
+                    last_incident = Some((incident, Instant::now()));
+
+                    let boot = record.boot_counters.boots;
+                    if let Err(e) = blackbox.append(&mut storage.flash, boot, incident).await {
+                        warn!("unable to write to the black box - {}", e);
+                    }
+
+                    publish_blackbox(state, blackbox, &mut storage.flash).await;
+                }
+
+                // Fault manager gave up on whatever it was, see faultmanager.rs
+                if incident.is(Event::Error, Incident::Unrecoverable as u16) {
+                    state.requests.sender().send(Request::Reboot);
+                }
+
+                continue;
+            }
+
+            Either4::Fourth(Either3::Second(request)) => {
+                let confirmed = confirm_image_at.is_none();
+                updater
+                    .handle(state, &mut storage.flash, request, confirmed)
+                    .await;
+                continue;
+            }
+        };
+
+        match request {
+            Request::BatteryProfileUpdate(profile) => {
+                info!("battery profile updated, applied on next ITPOR or chemistry change");
+
+                record.battery_profile = profile;
+                battery_profile_sender.send(profile);
+                state.indicate_once(OneShot::Flashes(1));
+            }
+
+            Request::ChargeModeUpdate(mode) => {
+                record.charge_mode = mode as u8;
+                charge_mode_sender.send(mode);
+
+                // Tells which mode is on now
+                state.indicate_once(OneShot::Flashes(mode as u8 + 1));
+            }
+
+            Request::SocThresholdsUpdate(thresholds) => {
+                record.soc_thresholds = thresholds;
+                soc_thresholds_sender.send(thresholds);
+                state.indicate_once(OneShot::Flashes(1));
+            }
+
+            // Controller bond lives in the record too, so its LTK goes along with the rest
+            // and the controller has to pair again
+            Request::FactoryReset => {
+                warn!("factory reset!");
+
+                record.restore_defaults(SettingsGroups::all());
+                store_or_complain(state, &mut storage, &record).await;
+
+                state.requests.sender().send(Request::Reboot);
+                continue;
+            }
+
+            Request::RestoreDefaults(groups) => {
+                info!("restoring defaults of {}", groups);
+
+                record.restore_defaults(groups);
+                publish(state, &record);
+                state.indicate_once(OneShot::Flashes(1));
+            }
+
+            // Whatever has changed is visible right away, no need for extra feedback
+            Request::IndicationThemeUpdate(style, entry) => {
+                record.indication_theme.set_entry(style, entry);
+                indication_theme_sender.send(record.indication_theme);
+            }
+
+            Request::FlightLightUpdate(light) => {
+                record.flight_light = light as u8;
+                flight_light_sender.send(light);
+            }
+
+            Request::AuxOutputsUpdate(outputs) => {
+                record.aux_outputs = outputs;
+                aux_outputs_sender.send(outputs);
+            }
+
+            // Takes effect on the next power-on
+            Request::SelfTestModeUpdate(mode) => {
+                record.self_test_mode = mode as u8;
+                self_test_mode_sender.send(mode);
+                state.indicate_once(OneShot::Flashes(1));
+            }
+
+            // Calibration has its own feedback
+            Request::GyroOffsetUpdate(offset) => {
+                record.gyro_offset = offset;
+                gyro_offset_sender.send(offset);
+            }
+
+            Request::CompassCalibrationUpdate(calibration) => {
+                record.compass_calibration = calibration;
+                compass_calibration_sender.send(calibration);
+            }
+
+            Request::ImuGyroBiasUpdate(bias) => {
+                record.imu_gyro_bias = bias;
+                imu_gyro_bias_sender.send(bias);
+            }
+
+            Request::FlightLogged(flight) => {
+                let mut odometer = record.odometer;
+
+                odometer.flight_time += flight.duration;
+                odometer.arms += 1;
+                odometer.crashes += flight.crashed as u32;
+
+                record.odometer = odometer;
+                odometer_sender.send(odometer);
+
+                // Crashes are rare enough, and tend to end with the battery popping out
+                if !flight.crashed {
+                    statistics_due_at.get_or_insert(storage.stored_at + STATISTICS_STORE_INTERVAL);
+                    continue;
+                }
+            }
+
+            // Only sent when a different controller shows up or bonds, so it's rare enough
+            Request::ControllerUpdate(controller) => {
+                record.controller = controller;
+                known_controller_sender.send(controller);
+            }
+
+            // Only the latest one is kept, it's tied to its controller (see ble/central.rs)
+            Request::ControllerBondUpdate(bond) => {
+                record.controller_bond = bond;
+                state.controller_bond.sender().send(bond);
+            }
+
+            Request::DeviceIrkUpdate(irk) => {
+                record.device_irk = irk;
+                state.device_irk.sender().send(irk);
+            }
+
+            Request::ParamUpdate(update) => {
+                if let Err(e) = record.params.set(update) {
+                    warn!("parameter {} not updated - {}", update.id, e);
+                    state.indicate_once(OneShot::Reject);
+                    continue;
+                }
+
+                params_sender.send(record.params);
+                state.indicate_once(OneShot::Flashes(1));
+            }
+
+            // Same as with the peer attributes, the one edited the longest ago goes away
+            Request::PilotProfileUpdate(profile) => {
+                let controller = record.controller;
+                if controller.valid == 0 {
+                    warn!("no controller to keep the profile for");
+                    state.indicate_once(OneShot::Reject);
+                    continue;
+                }
+
+                let table = &mut record.pilot_profiles;
+                let index = table
+                    .iter()
+                    .position(|entry| entry.controller.same_controller(&controller))
+                    .unwrap_or(table.len() - 1);
+
+                table[..=index].rotate_right(1);
+                table[0] = ControllerProfile {
+                    controller,
+                    profile,
+                };
+
+                pilot_profiles_sender.send(*table);
+                state.pilot_profile.sender().send(profile);
+                state.indicate_once(OneShot::Flashes(1));
+            }
+
+            // Latest goes first, the one seen the longest ago falls off the end
+            Request::PeerAttrsUpdate(attrs) => {
+                let table = &mut record.peer_attrs;
+                let index = table
+                    .iter()
+                    .position(|entry| entry.same_peer(&attrs))
+                    .unwrap_or(table.len() - 1);
+
+                table[..=index].rotate_right(1);
+                table[0] = attrs;
+
+                peer_attrs_sender.send(*table);
+            }
+
+            Request::HistoryFrozen => {
+                let boot = record.boot_counters.boots;
+                dump_history(state, &mut storage.flash, boot).await;
+                continue;
+            }
+
+            Request::HistoryPageRead(page) => {
+                publish_history(state, &mut storage.flash, page).await;
+                continue;
+            }
+
+            Request::GaugeLearnedUpdate(learned) => {
+                info!("storing gauge learning results");
+
+                record.gauge_learned = learned;
+                gauge_learned_sender.send(learned);
+
+                // Calibration is a long process, let it end with a bit of a celebration
+                state.indicate_once(OneShot::Flashes(3));
+            }
+
+            Request::LearningProgressUpdate(progress) => {
+                record.learning = progress;
+            }
+
+            _ => continue,
+        }
+
+        // Statistics go along
+        store_or_complain(state, &mut storage, &record).await;
+        statistics_due_at = None;
+    }
+}
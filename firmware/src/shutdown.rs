@@ -0,0 +1,69 @@
+// Orderly shutdown
+//
+// Whoever wants us off (low battery policy, the switch, a host) only requests the
+// shutdown. Motors are cut first, then every subsystem takes care of its own bits
+// and acks, and only after that we enter System OFF. Wakeup is by the switch or by
+// the reset, e.g. when the pack is reconnected.
+
+use defmt::{info, unwrap, warn};
+use embassy_nrf::pac::{self, gpio::vals};
+use embassy_time::{with_timeout, Duration};
+use nrf_softdevice::raw;
+
+use crate::{
+    board::SWITCH_PIN,
+    state::SystemState,
+    taskstats::{self, Task},
+    types::ShutdownAcks,
+};
+
+// Switch is the only way to wake up, short of the reset
+fn enable_switch_wakeup() {
+    pac::P0.pin_cnf(SWITCH_PIN).write(|w| {
+        w.set_dir(vals::Dir::INPUT);
+        w.set_input(vals::Input::CONNECT);
+        w.set_pull(vals::Pull::PULLUP);
+        w.set_sense(vals::Sense::LOW);
+    });
+}
+
+#[embassy_executor::task]
+pub async fn run(state: &'static SystemState) {
+    taskstats::accounted(Task::Shutdown, task(state)).await
+}
+
+async fn task(state: &'static SystemState) {
+    // Things that are still not done by then aren't going to get done
+    const DISARM_TIMEOUT: Duration = Duration::from_millis(500);
+    const ACK_TIMEOUT: Duration = Duration::from_secs(3);
+
+    let mut shutdown_receiver = unwrap!(state.shutdown.receiver());
+    let mut armed_receiver = unwrap!(state.armed.receiver());
+    let mut acks_receiver = unwrap!(state.shutdown_acks.receiver());
+
+    let reason = shutdown_receiver.changed().await;
+    warn!("shutting down ({})", reason);
+
+    // Controller is not allowed to run from now on, so wait for it to drop
+    if with_timeout(DISARM_TIMEOUT, armed_receiver.get_and(|armed| !armed))
+        .await
+        .is_err()
+    {
+        warn!("controller did not disarm in time");
+    }
+
+    let all_done = acks_receiver.get_and(|acks| acks.contains(ShutdownAcks::all()));
+
+    if with_timeout(ACK_TIMEOUT, all_done).await.is_err() {
+        let acks = acks_receiver.try_get().unwrap_or_default();
+        warn!(
+            "shutdown is missing acks: {}",
+            ShutdownAcks::all().difference(acks)
+        );
+    }
+
+    info!("entering system off, bye!");
+
+    enable_switch_wakeup();
+    unsafe { raw::sd_power_system_off() };
+}
@@ -1,74 +1,811 @@
-use defmt::{info, unwrap, warn};
-use embassy_futures::select::{select4, Either4};
+use core::cell::{Cell, RefCell};
+
+use copter_core::dfu::Status as DfuStatus;
+use copter_core::policy::{SocPolicy, SocStage};
+use defmt::{error, info, unwrap, warn};
+use embassy_futures::select::{select, select3, select4, Either3, Either4};
 use embassy_sync::{
-    blocking_mutex::raw::NoopRawMutex,
+    blocking_mutex::{raw::CriticalSectionRawMutex, Mutex},
+    channel::Channel,
+    pubsub::{PubSubChannel, Subscriber, WaitResult},
+    signal::Signal,
     watch::{Receiver, Watch},
 };
 
-use crate::types::{ChargerState, JoystickData, PeriodicUpdate, PidParams};
+use crate::assertion::{soft_assert, Assertion, AssertionReport};
+use crate::aux::AuxOutputs;
+use crate::baro::AltitudeSample;
+use crate::blackbox::{BlackboxLog, Incident};
+use crate::charger::ChargeMode;
+use crate::chirp::Chirp;
+use crate::compass::CompassSample;
+use crate::dfu::{self, DfuRequest};
+use crate::eventlog::{Event, EventLog, EventRing, LoggedEvent};
+use crate::faultmanager::{FaultManager, Verdict};
+use crate::flow::FlowSample;
+use crate::history::HistoryPage;
+use crate::imu::ImuSample;
+use crate::indications::{
+    ActiveIndications, FlightLight, IndicationStyle, IndicationTheme, OneShot, ThemeEntry,
+};
+use crate::learning::LearningPhase;
+use crate::params::{ParamUpdate, ParamValues};
+use crate::postmortem::{FaultReport, PanicReport};
+use crate::relay::RelayAccess;
+use crate::selftest::SelfTestMode;
+use crate::taskstats::{self, Task, TaskStats};
+use crate::tof::RangeSample;
+use crate::types::{
+    AltitudeReport, AttitudeReport, BatteryProfile, BootCounters, BootInfo, ChargerState,
+    CompassCalibration, ControllerAddress, ControllerBond, ControllerProfile, DeviceIrk, Faults,
+    FlightLog, FlightPowerSummary, GaugeLearnedData, GaugeSocFlags, ImuGyroBias, JoystickData,
+    LearningProgress, LearningReport, Odometer, PeerAttrs, PeriodicUpdate, PidParams, PilotProfile,
+    RailSagReport, RateLoopReport, RateSensor, SettingsGroups, ShutdownAcks, ShutdownReason,
+    SocThresholds, RAIL_VOLTAGE_UNKNOWN,
+};
+use crate::watchdog::Supervisor;
+
+// Control task runs on its own executor and preempts everything else (see main.rs),
+// so whatever it shares with the rest has to be guarded by a critical section
+pub type StateMutex = CriticalSectionRawMutex;
 
-pub type StateWatch<T> = Watch<NoopRawMutex, T, 8>;
-pub type StateReceiver<'a, T> = Receiver<'a, NoopRawMutex, T, 8>;
+// Receivers per watch. Armed is the busiest one, every sensor waits for it
+pub type StateWatch<T> = Watch<StateMutex, T, 12>;
+pub type StateReceiver<'a, T> = Receiver<'a, StateMutex, T, 12>;
+
+// Charging with the canopy on traps heat, so things that are not essential
+// on the ground are slowed down until the charger is unplugged
+#[derive(Clone, Copy, PartialEq, defmt::Format)]
+pub enum ActivityLevel {
+    Full,
+    Reduced,
+}
+
+// One thing the system is doing as a whole, derived from everything else. Policies
+// that cut across tasks should key off this instead of piecing it together themselves
+#[repr(u8)]
+#[derive(Clone, Copy, PartialEq, defmt::Format)]
+pub enum Mode {
+    // SoC is not known yet
+    Booting,
+    Idle,
+    // Waiting for a new controller
+    Pairing,
+    // Motors are live, throttle is at zero
+    Armed,
+    Flying,
+    Charging,
+    // Something is wrong, and we're on the ground
+    Fault,
+    // No way back from there
+    ShuttingDown,
+}
+
+impl Mode {
+    // Catches the places where the rest of the system disagrees with itself, e.g.
+    // the motors being armed while the charger is on
+    fn transition_allowed(self, to: Self) -> bool {
+        match (self, to) {
+            (Self::ShuttingDown, _) => false,
+            (_, Self::ShuttingDown) | (Self::Booting, _) => true,
+            // Only ever through arming
+            (from, Self::Flying) => from == Self::Armed,
+            (Self::Charging | Self::Fault, Self::Armed) => false,
+            _ => true,
+        }
+    }
+
+    pub fn armed(self) -> bool {
+        matches!(self, Self::Armed | Self::Flying)
+    }
+}
 
 #[derive(Clone)]
 pub enum Request {
     PidUpdate(PidParams),
     Reboot,
     FuelgaugeReset,
+    BatteryProfileUpdate(BatteryProfile),
+    ChargeModeUpdate(ChargeMode),
+    SocThresholdsUpdate(SocThresholds),
+    StartLearning,
+    GaugeLearnedUpdate(GaugeLearnedData),
+    // Every phase change and every few minutes of rest, see power.rs
+    LearningProgressUpdate(LearningProgress),
+    FactoryReset,
+    IndicationThemeUpdate(IndicationStyle, ThemeEntry),
+    FlightLightUpdate(FlightLight),
+    AuxOutputsUpdate(AuxOutputs),
+    SelfTestModeUpdate(SelfTestMode),
+    GyroOffsetUpdate(i16),
+    CompassCalibrationUpdate(CompassCalibration),
+    ImuGyroBiasUpdate(ImuGyroBias),
+    FlightLogged(FlightLog),
+    ControllerUpdate(ControllerAddress),
+    // Whenever the controller bonds, see ble/central.rs
+    ControllerBondUpdate(ControllerBond),
+    // Generated on the first boot, see ble/privacy.rs
+    DeviceIrkUpdate(DeviceIrk),
+    PeerAttrsUpdate(PeerAttrs),
+    // Goes to the controller we know, see known_controller
+    PilotProfileUpdate(PilotProfile),
+    RestoreDefaults(SettingsGroups),
+    ParamUpdate(ParamUpdate),
+    StartPairing,
+    // Control task has frozen the pre-crash history, see history.rs
+    HistoryFrozen,
+    HistoryPageRead(u8),
+}
+
+// Requests are events rather than state, so unlike everything else they are queued,
+// and every subscriber gets to see each one of them
+const REQUEST_QUEUE_LEN: usize = 8;
+const REQUEST_SUBSCRIBERS: usize = 6;
+
+// Flash writes are slow, so incidents coming in a burst have to wait for their turn
+const INCIDENT_QUEUE_LEN: usize = 4;
+
+type RequestChannel = PubSubChannel<StateMutex, Request, REQUEST_QUEUE_LEN, REQUEST_SUBSCRIBERS, 0>;
+
+pub struct Requests {
+    channel: RequestChannel,
+    // Requests that somebody missed by falling too far behind
+    lost: Mutex<StateMutex, Cell<u32>>,
+}
+
+impl Requests {
+    const fn new() -> Self {
+        Self {
+            channel: PubSubChannel::new(),
+            lost: Mutex::new(Cell::new(0)),
+        }
+    }
+
+    pub fn sender(&self) -> RequestSender<'_> {
+        RequestSender(self)
+    }
+
+    pub fn receiver(&self) -> Option<RequestReceiver<'_>> {
+        Some(RequestReceiver {
+            requests: self,
+            subscriber: self.channel.subscriber().ok()?,
+        })
+    }
+}
+
+pub struct RequestSender<'a>(&'a Requests);
+
+impl RequestSender<'_> {
+    // Never blocks. If the queue is full, the oldest request is dropped for whoever
+    // hasn't seen it yet, and they get to know about it
+    pub fn send(&self, request: Request) {
+        self.0
+            .channel
+            .immediate_publisher()
+            .publish_immediate(request);
+    }
+}
+
+pub struct RequestReceiver<'a> {
+    requests: &'a Requests,
+    subscriber: Subscriber<'a, StateMutex, Request, REQUEST_QUEUE_LEN, REQUEST_SUBSCRIBERS, 0>,
+}
+
+impl RequestReceiver<'_> {
+    pub async fn changed(&mut self) -> Request {
+        loop {
+            match self.subscriber.next_message().await {
+                WaitResult::Message(request) => return request,
+                WaitResult::Lagged(n) => {
+                    let lost = self.requests.lost.lock(|lost| {
+                        lost.set(lost.get() + n as u32);
+                        lost.get()
+                    });
+
+                    error!("{} requests were lost, {} so far", n, lost);
+                }
+            }
+        }
+    }
+}
+
+// What the telemetry is made of, as of a single moment
+#[derive(Clone, Copy)]
+pub struct Snapshot {
+    pub soc: Option<u8>,
+    pub soc_cached: bool,
+    pub charger_state: Option<ChargerState>,
+    pub mode: Mode,
+    pub controller_connected: bool,
+    pub periodic_update: Option<PeriodicUpdate>,
 }
 
 pub struct SystemState {
     pub charger_state: StateWatch<ChargerState>,
     pub soc: StateWatch<u8>,
+    // Set while the SoC above is the last known one from flash, not a gauge reading
+    pub soc_cached: StateWatch<bool>,
+    pub gauge_soc_flags: StateWatch<GaugeSocFlags>,
     pub controller_connected: StateWatch<bool>,
+    // Stock remote is flying, only while there's no controller. See ir.rs
+    pub remote_engaged: StateWatch<bool>,
+    // Same for a PC relaying its gamepad, see relay.rs
+    pub relay_engaged: StateWatch<bool>,
+    // Every frame the relay writes, decoded. Only flies while it's engaged
+    pub relay_frame: Signal<StateMutex, JoystickData>,
+    // Whether the connected peer was let in with the switch, see relay.rs
+    pub relay_access: StateWatch<RelayAccess>,
+    // Last one we were connected to, comes from the settings
+    pub known_controller: StateWatch<ControllerAddress>,
+    // Keys of whichever controller bonded last, comes from the settings
+    pub controller_bond: StateWatch<ControllerBond>,
+    // Comes from the settings, invalid until the first one is generated
+    pub device_irk: StateWatch<DeviceIrk>,
+    // Per controller, comes from the settings
+    pub pilot_profiles: StateWatch<[ControllerProfile; ControllerProfile::TABLE_LEN]>,
+    // Of the controller we know, picked on connection
+    pub pilot_profile: StateWatch<PilotProfile>,
+    // GATT clients seen lately, comes from the settings
+    pub peer_attrs: StateWatch<[PeerAttrs; PeerAttrs::TABLE_LEN]>,
+    // Accepting any controller, not just the one we know
+    pub pairing_mode: StateWatch<bool>,
     pub periodic_update: StateWatch<PeriodicUpdate>,
     pub controller_sample: StateWatch<JoystickData>,
-    pub requests: StateWatch<Request>,
+    // Only while armed, and only with one fitted. See imu.rs
+    pub imu: StateWatch<ImuSample>,
+    // Same as the one in the samples above, at the telemetry rate
+    pub attitude: StateWatch<AttitudeReport>,
+    // Only while armed, and only with a barometer fitted. See baro.rs
+    pub altitude: StateWatch<AltitudeSample>,
+    pub altitude_report: StateWatch<AltitudeReport>,
+    // Only while armed, and only with a calibrated magnetometer. See compass.rs
+    pub compass: StateWatch<CompassSample>,
+    // Only while armed, with a rangefinder fitted, and only while it can be trusted.
+    // See tof.rs
+    pub range: StateWatch<RangeSample>,
+    // Only while armed, with a flow sensor fitted, and only over a floor it can see.
+    // See flow.rs
+    pub flow: StateWatch<FlowSample>,
+    pub requests: Requests,
     pub controller_run_allowed: StateWatch<bool>,
+    pub armed: StateWatch<bool>,
+    // Armed and with some throttle
+    pub flying: StateWatch<bool>,
+    pub mode: StateWatch<Mode>,
+    pub battery_profile: StateWatch<BatteryProfile>,
+    pub activity: StateWatch<ActivityLevel>,
+    pub charge_mode: StateWatch<ChargeMode>,
+    pub flight_time: StateWatch<u16>,
+    pub rail_voltage: StateWatch<u16>,
+    // One per throttle punch, see copter_core::sag
+    pub rail_sag: StateWatch<RailSagReport>,
+    // Every few control ticks while armed, see tools/tune
+    pub rate_loop: StateWatch<RateLoopReport>,
+    pub soc_thresholds: StateWatch<SocThresholds>,
+    pub soc_stage: StateWatch<SocStage>,
+    pub gauge_learned: StateWatch<GaugeLearnedData>,
+    pub learning_phase: StateWatch<LearningPhase>,
+    // Only while the learning cycle runs
+    pub learning_report: StateWatch<LearningReport>,
+    // As stored by the settings, the power task picks it up once after boot
+    pub learning_progress: StateWatch<LearningProgress>,
+    pub faults: StateWatch<Faults>,
+    pub flight_summary: StateWatch<FlightPowerSummary>,
+    pub odometer: StateWatch<Odometer>,
+    // Already include this boot, come from the settings
+    pub boot_counters: StateWatch<BootCounters>,
+    // Latest hard fault, even if it was a few power cycles ago. Comes from the settings
+    pub last_fault: StateWatch<FaultReport>,
+    // Latest failed soft assertion, this run or the one before. See assertion.rs
+    pub assertion: StateWatch<AssertionReport>,
+    // Set once the shutdown is requested, there's no way back from there
+    pub shutdown: StateWatch<ShutdownReason>,
+    pub shutdown_acks: StateWatch<ShutdownAcks>,
+    pub indications: StateWatch<ActiveIndications>,
+    pub one_shot: Signal<StateMutex, OneShot>,
+    pub indication_theme: StateWatch<IndicationTheme>,
+    pub flight_light: StateWatch<FlightLight>,
+    // What each aux output does, comes from the settings. Switched as a whole by the
+    // one below, which isn't stored: lights come on with every power-on
+    pub aux_outputs: StateWatch<AuxOutputs>,
+    pub aux_lights: StateWatch<bool>,
+    pub chirp: Signal<StateMutex, Chirp>,
+    pub self_test_mode: StateWatch<SelfTestMode>,
+    // Asks the control task for its part of the self-test, and carries the outcome back
+    pub self_test: Signal<StateMutex, SelfTestMode>,
+    pub self_test_report: Signal<StateMutex, Faults>,
+    // Raw gyro reading at rest, comes from the settings
+    pub gyro_offset: StateWatch<i16>,
+    // Comes from the settings, same as the gyro offset
+    pub compass_calibration: StateWatch<CompassCalibration>,
+    // Same for the digital gyro, see imu.rs
+    pub imu_gyro_bias: StateWatch<ImuGyroBias>,
+    pub rate_sensor: StateWatch<RateSensor>,
+    // IMU is there, calibrated or not
+    pub imu_found: StateWatch<bool>,
+    // Everything from the parameter registry, comes from the settings
+    pub params: StateWatch<ParamValues>,
+    // Calibration needs the control task to be idle, same as the chirps do
+    pub calibrate_gyro: Signal<StateMutex, ()>,
+    pub gyro_calibrating: StateWatch<bool>,
+    // Control task passes the calibration on to the IMU task, if there's one running
+    pub calibrate_imu: Signal<StateMutex, ()>,
+    pub imu_calibration_report: Signal<StateMutex, bool>,
+    // Magnetometer calibration, has to be disarmed too. See compass.rs
+    pub calibrate_compass: Signal<StateMutex, ()>,
+    // Raised by the power-fail comparator, way faster than the gauge can notice
+    pub undervoltage: Signal<StateMutex, ()>,
+    // Tasks that have to check in to keep the watchdog fed
+    pub supervisor: Supervisor,
+    // Counts incidents and decides what to do about them
+    pub fault_manager: FaultManager,
+    events: Mutex<StateMutex, RefCell<EventRing>>,
+    // Raised whenever there's something new in the event log
+    pub events_changed: Signal<StateMutex, ()>,
+    // On their way to the black box
+    pub incidents: Channel<StateMutex, LoggedEvent, INCIDENT_QUEUE_LEN>,
+    // Latest black box entries, comes from the settings
+    pub blackbox: StateWatch<BlackboxLog>,
+    // Page of the latest pre-crash history dump some client has asked for
+    pub history_page: StateWatch<HistoryPage>,
+    // Firmware update commands and chunks for the settings task, and how it's going.
+    // See dfu.rs
+    pub dfu_requests: Channel<StateMutex, DfuRequest, { dfu::QUEUE_LEN }>,
+    pub dfu_status: StateWatch<DfuStatus>,
+    // Where the CPU time goes and how much stack is left, see taskstats.rs
+    pub task_stats: StateWatch<TaskStats>,
+    // Captured once at boot
+    pub boot_info: BootInfo,
+    pub panic_report: Option<PanicReport>,
+    pub fault_report: Option<FaultReport>,
 }
 
 impl<'a> SystemState {
-    pub fn new() -> Self {
+    pub fn new(
+        boot_info: BootInfo,
+        panic_report: Option<PanicReport>,
+        fault_report: Option<FaultReport>,
+    ) -> Self {
         Self {
             charger_state: Watch::new(),
             soc: Watch::new(),
+            soc_cached: Watch::new_with(false),
+            gauge_soc_flags: Watch::new_with(GaugeSocFlags::empty()),
             controller_connected: Watch::new_with(false),
+            remote_engaged: Watch::new_with(false),
+            relay_engaged: Watch::new_with(false),
+            relay_frame: Signal::new(),
+            relay_access: Watch::new_with(RelayAccess::Closed),
+            known_controller: Watch::new(),
+            controller_bond: Watch::new(),
+            device_irk: Watch::new(),
+            pilot_profiles: Watch::new(),
+            pilot_profile: Watch::new(),
+            peer_attrs: Watch::new(),
+            pairing_mode: Watch::new_with(false),
             periodic_update: Watch::new(),
             controller_sample: Watch::new(),
-            requests: Watch::new(),
+            imu: Watch::new(),
+            attitude: Watch::new(),
+            altitude: Watch::new(),
+            altitude_report: Watch::new(),
+            compass: Watch::new(),
+            range: Watch::new(),
+            flow: Watch::new(),
+            requests: Requests::new(),
             controller_run_allowed: Watch::new_with(false),
+            armed: Watch::new_with(false),
+            flying: Watch::new_with(false),
+            mode: Watch::new_with(Mode::Booting),
+            battery_profile: Watch::new(),
+            activity: Watch::new_with(ActivityLevel::Full),
+            charge_mode: Watch::new(),
+            flight_time: Watch::new(),
+            rail_voltage: Watch::new_with(RAIL_VOLTAGE_UNKNOWN),
+            rail_sag: Watch::new(),
+            rate_loop: Watch::new(),
+            soc_thresholds: Watch::new(),
+            soc_stage: Watch::new_with(SocStage::Normal),
+            gauge_learned: Watch::new(),
+            learning_phase: Watch::new_with(LearningPhase::Idle),
+            learning_report: Watch::new(),
+            learning_progress: Watch::new(),
+            faults: Watch::new_with(Faults::empty()),
+            flight_summary: Watch::new(),
+            odometer: Watch::new(),
+            boot_counters: Watch::new(),
+            last_fault: Watch::new(),
+            assertion: Watch::new(),
+            shutdown: Watch::new(),
+            shutdown_acks: Watch::new_with(ShutdownAcks::empty()),
+            indications: Watch::new_with(ActiveIndications::default()),
+            one_shot: Signal::new(),
+            indication_theme: Watch::new(),
+            flight_light: Watch::new(),
+            aux_outputs: Watch::new(),
+            aux_lights: Watch::new_with(true),
+            chirp: Signal::new(),
+            self_test_mode: Watch::new(),
+            self_test: Signal::new(),
+            self_test_report: Signal::new(),
+            gyro_offset: Watch::new(),
+            compass_calibration: Watch::new(),
+            imu_gyro_bias: Watch::new(),
+            rate_sensor: Watch::new_with(RateSensor::Analog),
+            imu_found: Watch::new_with(false),
+            params: Watch::new(),
+            calibrate_gyro: Signal::new(),
+            calibrate_compass: Signal::new(),
+            gyro_calibrating: Watch::new_with(false),
+            calibrate_imu: Signal::new(),
+            imu_calibration_report: Signal::new(),
+            undervoltage: Signal::new(),
+            supervisor: Supervisor::new(),
+            fault_manager: FaultManager::new(),
+            events: Mutex::new(RefCell::new(EventRing::new())),
+            events_changed: Signal::new(),
+            incidents: Channel::new(),
+            blackbox: Watch::new(),
+            history_page: Watch::new(),
+            dfu_requests: Channel::new(),
+            dfu_status: Watch::new(),
+            task_stats: Watch::new(),
+            boot_info,
+            panic_report,
+            fault_report,
+        }
+    }
+
+    // Raise or clear faults without touching the ones owned by others
+    pub fn set_faults(&self, faults: Faults, active: bool) {
+        self.faults.sender().send_if_modified(|current| {
+            let old = current.unwrap_or_default();
+            let mut new = old;
+
+            new.set(faults, active);
+            *current = Some(new);
+
+            if new != old {
+                warn!("faults: {}", new);
+                self.log_event(Event::Faults, new.bits as u16);
+            }
+
+            new != old
+        });
+    }
+
+    // Same as with faults, everyone owns their own indications
+    pub fn set_indication(&self, style: IndicationStyle, active: bool) {
+        self.indications.sender().send_if_modified(|current| {
+            let old = current.unwrap_or_default();
+            let mut new = old;
+
+            new.set(style, active);
+            *current = Some(new);
+
+            new != old
+        });
+    }
+
+    // Transient feedback that doesn't change the active style. Only the latest one
+    // is shown if they come too fast
+    pub fn indicate_once(&self, shot: OneShot) {
+        self.one_shot.signal(shot);
+    }
+
+    // First reason wins, the rest are just late to the party
+    pub fn request_shutdown(&self, reason: ShutdownReason) {
+        self.shutdown
+            .sender()
+            .send_if_modified(|current| match current {
+                Some(_) => false,
+                None => {
+                    warn!("shutdown requested ({})", reason);
+                    self.log_event(Event::Shutdown, reason as u16);
+                    *current = Some(reason);
+                    true
+                }
+            });
+    }
+
+    pub fn log_event(&self, event: Event, value: u16) {
+        info!("event: {} ({})", event, value);
+        self.push_event(LoggedEvent::new(event, value));
+    }
+
+    // Mostly goes through incident!, unless it's been printed already. Says whether
+    // it's worth printing
+    pub fn raise_incident(&self, event: Event, incident: Incident) -> bool {
+        let verdict = self.fault_manager.raise(incident);
+
+        // Counts go along with the event log, whatever the verdict
+        self.events_changed.signal(());
+
+        let suppressed = match verdict {
+            Verdict::Suppress => return false,
+            Verdict::Report { suppressed } | Verdict::Escalate { suppressed } => suppressed,
+        };
+
+        if suppressed > 0 {
+            warn!("{} more of {} since the last one", suppressed, incident);
+        }
+
+        self.keep_incident(event, incident);
+
+        if let Verdict::Escalate { .. } = verdict {
+            self.escalate(incident);
+        }
+
+        true
+    }
+
+    fn keep_incident(&self, event: Event, incident: Incident) -> bool {
+        let entry = LoggedEvent::new(event, incident as u16);
+
+        self.push_event(entry);
+
+        let kept = self.incidents.try_send(entry).is_ok();
+        if !kept {
+            warn!("black box is lagging behind, {} is not kept", incident);
+        }
+
+        kept
+    }
+
+    // Settings reboot once it's in the black box, so it's there to read afterwards
+    pub fn escalate(&self, incident: Incident) {
+        if self.armed.try_get() == Some(true) {
+            error!("{} keeps coming back, but we're armed", incident);
+            return;
+        }
+
+        error!("{} keeps coming back, rebooting", incident);
+
+        if !self.keep_incident(Event::Error, Incident::Unrecoverable) {
+            self.requests.sender().send(Request::Reboot);
         }
     }
+
+    fn push_event(&self, entry: LoggedEvent) {
+        self.events.lock(|events| events.borrow_mut().push(entry));
+        self.events_changed.signal(());
+    }
+
+    pub fn event_log(&self) -> EventLog {
+        self.events.lock(|events| events.borrow().snapshot())
+    }
+
+    // Tasks only ever run between awaits, and there are none in here, so nobody gets
+    // to change anything halfway through. The control task can preempt us, but none of
+    // these are its own. Anything that needs more than one of these
+    // at once should come here instead of reading them one by one
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            soc: self.soc.try_get(),
+            soc_cached: self.soc_cached.try_get().unwrap_or_default(),
+            charger_state: self.charger_state.try_get(),
+            mode: self.mode.try_get().unwrap_or(Mode::Booting),
+            controller_connected: self.controller_connected.try_get().unwrap_or_default(),
+            periodic_update: self.periodic_update.try_get(),
+        }
+    }
+
+    // Let the shutdown sequence know our part is done
+    pub fn ack_shutdown(&self, acks: ShutdownAcks) {
+        self.shutdown_acks.sender().send_modify(|current| {
+            *current = Some(current.unwrap_or_default() | acks);
+        });
+    }
 }
 
 #[embassy_executor::task]
 pub async fn run(state: &'static SystemState) {
+    taskstats::accounted(Task::State, task(state)).await
+}
+
+async fn task(state: &'static SystemState) {
     // Monitor the overall state of the system and provide a couple of
     // our own statuses as well
     info!("system state monitor running");
 
     let mut soc_receiver = unwrap!(state.soc.receiver());
+    let mut soc_cached_receiver = unwrap!(state.soc_cached.receiver());
+    let mut gauge_soc_flags_receiver = unwrap!(state.gauge_soc_flags.receiver());
+    let mut shutdown_receiver = unwrap!(state.shutdown.receiver());
     let mut controller_connected_receiver = unwrap!(state.controller_connected.receiver());
+    let mut remote_engaged_receiver = unwrap!(state.remote_engaged.receiver());
+    let mut relay_engaged_receiver = unwrap!(state.relay_engaged.receiver());
     let mut charger_state_receiver = unwrap!(state.charger_state.receiver());
     let mut requests_receiver = unwrap!(state.requests.receiver());
+    let mut soc_thresholds_receiver = unwrap!(state.soc_thresholds.receiver());
+    let mut faults_receiver = unwrap!(state.faults.receiver());
+    let mut armed_receiver = unwrap!(state.armed.receiver());
+    let mut flying_receiver = unwrap!(state.flying.receiver());
+    let mut pairing_mode_receiver = unwrap!(state.pairing_mode.receiver());
+    let mut learning_phase_receiver = unwrap!(state.learning_phase.receiver());
     let controller_run_allowed_sender = state.controller_run_allowed.sender();
+    let activity_sender = state.activity.sender();
+    let soc_stage_sender = state.soc_stage.sender();
+    let mode_sender = state.mode.sender();
+
+    let mut soc_policy = SocPolicy::new();
+    let mut run_allowed = false;
 
     loop {
-        controller_run_allowed_sender.send(matches!(
-            (soc_receiver.try_get(), controller_connected_receiver.try_get(), charger_state_receiver.try_get()),
-            (Some(soc), Some(true), Some(charger_state)) if soc > 5 && !charger_state.charging
-        ));
-
-        let s = select4(
-            requests_receiver.changed(),
-            soc_receiver.changed(),
-            controller_connected_receiver.changed(),
-            charger_state_receiver.changed(),
+        let charging = matches!(charger_state_receiver.try_get(), Some(s) if s.charging);
+        let thresholds = soc_thresholds_receiver.try_get().unwrap_or_default();
+
+        let soc_stage = match soc_receiver.try_get() {
+            Some(soc) => soc_policy.update(soc, charging, &thresholds.into()),
+            None => SocStage::Normal,
+        };
+
+        // Gauge flags only ever make things stricter - if they disagree with the
+        // SoC we have, it's most likely just stale
+        let gauge_soc_flags = gauge_soc_flags_receiver.try_get().unwrap_or_default();
+        let gauge_stage = if gauge_soc_flags.contains(GaugeSocFlags::FINAL) {
+            SocStage::InhibitArming
+        } else if gauge_soc_flags.contains(GaugeSocFlags::LOW) {
+            SocStage::Warn
+        } else {
+            SocStage::Normal
+        };
+
+        let soc_stage = match gauge_stage > soc_stage {
+            true => gauge_stage,
+            false => soc_stage,
+        };
+
+        // Learning discharge stops at the terminate voltage on its own, so the
+        // warning is all the policy gets to do until the cycle is over
+        let learning_phase = learning_phase_receiver.try_get().unwrap_or_default();
+        let soc_stage = match learning_phase.holds_off_soc_policy() {
+            true if soc_stage > SocStage::Warn => SocStage::Warn,
+            _ => soc_stage,
+        };
+
+        soc_stage_sender.send_if_modified(|current| {
+            let modified = *current != Some(soc_stage);
+            if modified {
+                warn!("low battery policy stage is now {}", soc_stage);
+                state.log_event(Event::SocStage, soc_stage as u16);
+                *current = Some(soc_stage);
+            }
+
+            modified
+        });
+
+        // Once in the air, we let the policy bring the heli down gently instead
+        // of cutting the motors, so arming inhibition only applies on the ground
+        let soc_allows_run = match run_allowed {
+            true => soc_stage < SocStage::Shutdown,
+            false => soc_stage < SocStage::InhibitArming,
+        };
+
+        // Same goes for faults - they prevent from taking off, but won't drop us from the sky
+        // Charging faults (e.g. charge temperature limits are way narrower than discharge
+        // ones) are not our concern here
+        let faults = faults_receiver.try_get().unwrap_or_default();
+        let faults_allow_run = run_allowed || faults.difference(Faults::CHARGING).is_empty();
+
+        let shutting_down = shutdown_receiver.try_get().is_some();
+
+        // Any of them can fly, see ir.rs and relay.rs for who goes first
+        let connected = controller_connected_receiver.try_get() == Some(true)
+            || remote_engaged_receiver.try_get() == Some(true)
+            || relay_engaged_receiver.try_get() == Some(true);
+
+        run_allowed = soc_receiver.try_get().is_some()
+            && connected
+            && soc_allows_run
+            && faults_allow_run
+            && !charging
+            && !shutting_down;
+
+        controller_run_allowed_sender.send(run_allowed);
+
+        let activity = match charging {
+            true => ActivityLevel::Reduced,
+            false => ActivityLevel::Full,
+        };
+
+        activity_sender.send_if_modified(|current| {
+            let modified = *current != Some(activity);
+            if modified {
+                info!("activity level is now {}", activity);
+                *current = Some(activity);
+            }
+
+            modified
+        });
+
+        // Faults don't drop us from the sky, so they only show up on the ground
+        let armed = armed_receiver.try_get().unwrap_or_default();
+        let flying = flying_receiver.try_get().unwrap_or_default();
+        let pairing = pairing_mode_receiver.try_get().unwrap_or_default();
+
+        let mode = if shutting_down {
+            Mode::ShuttingDown
+        } else if armed && flying {
+            Mode::Flying
+        } else if armed {
+            Mode::Armed
+        } else if !faults.difference(Faults::CHARGING).is_empty() {
+            Mode::Fault
+        } else if charging {
+            Mode::Charging
+        } else if soc_receiver.try_get().is_none() {
+            Mode::Booting
+        } else if pairing {
+            Mode::Pairing
+        } else {
+            Mode::Idle
+        };
+
+        // Mode only reflects what the rest of the system did, so a bad transition is
+        // reported rather than refused
+        mode_sender.send_if_modified(|current| {
+            let from = current.unwrap_or(Mode::Booting);
+            if from == mode {
+                return false;
+            }
+
+            soft_assert!(
+                state,
+                Continue,
+                Assertion::ModeTransition,
+                from.transition_allowed(mode),
+                "unexpected mode transition {} -> {}",
+                from,
+                mode
+            );
+
+            info!("mode is now {}", mode);
+            state.log_event(Event::Mode, mode as u16);
+
+            *current = Some(mode);
+            true
+        });
+
+        // Cached SoC is good enough to decide whether to take off, but we
+        // don't want to power off based on what the previous pack had left
+        let soc_cached = soc_cached_receiver.try_get().unwrap_or_default();
+
+        if soc_stage == SocStage::Shutdown && !soc_cached {
+            state.request_shutdown(ShutdownReason::BatteryDepleted);
+        }
+
+        let s = select3(
+            select4(
+                requests_receiver.changed(),
+                soc_receiver.changed(),
+                select3(
+                    controller_connected_receiver.changed(),
+                    remote_engaged_receiver.changed(),
+                    relay_engaged_receiver.changed(),
+                ),
+                charger_state_receiver.changed(),
+            ),
+            select4(
+                soc_thresholds_receiver.changed(),
+                armed_receiver.changed(),
+                flying_receiver.changed(),
+                pairing_mode_receiver.changed(),
+            ),
+            select4(
+                faults_receiver.changed(),
+                soc_cached_receiver.changed(),
+                select(
+                    gauge_soc_flags_receiver.changed(),
+                    learning_phase_receiver.changed(),
+                ),
+                shutdown_receiver.changed(),
+            ),
         )
         .await;
 
         match s {
-            Either4::First(Request::Reboot) => {
+            Either3::First(Either4::First(Request::Reboot)) => {
                 warn!("Reboot request is received");
                 cortex_m::peripheral::SCB::sys_reset();
             }
@@ -0,0 +1,81 @@
+// On-board switch
+//
+// Short press blinks out the battery level, or lets the gamepad relay in if it's asking
+// (see relay.rs). Holding it down powers us off. Holding it through the power-on for
+// long enough wipes the settings. Pressed switch pulls the line low.
+
+use defmt::info;
+use embassy_futures::select::{select, Either};
+use embassy_nrf::gpio::{Input, Pull};
+use embassy_time::{Duration, Instant, Timer};
+
+use crate::{
+    indications::{IndicationStyle, OneShot},
+    relay,
+    state::{Request, SystemState},
+    taskstats::{self, Task},
+    types::ShutdownReason,
+    SwitchResources,
+};
+
+// Last resort for when no client can connect to fix things. The pattern shows up
+// once the switch was held long enough, and the reset happens on release, so the
+// user knows what is about to happen and can still bail out by cutting the power
+async fn factory_reset_hold(state: &SystemState, switch: &mut Input<'_>) {
+    const FACTORY_RESET_HOLD: Duration = Duration::from_secs(10);
+
+    if switch.is_high() {
+        return;
+    }
+
+    info!("switch is held at power-on");
+
+    if let Either::Second(_) =
+        select(Timer::after(FACTORY_RESET_HOLD), switch.wait_for_high()).await
+    {
+        return;
+    }
+
+    state.set_indication(IndicationStyle::FactoryReset, true);
+    switch.wait_for_high().await;
+
+    state.requests.sender().send(Request::FactoryReset);
+}
+
+#[embassy_executor::task]
+pub async fn run(state: &'static SystemState, r: SwitchResources) {
+    taskstats::accounted(Task::Switch, task(state, r)).await
+}
+
+async fn task(state: &'static SystemState, r: SwitchResources) {
+    const DEBOUNCE: Duration = Duration::from_millis(30);
+    const LONG_PRESS: Duration = Duration::from_secs(3);
+
+    info!("switch monitor running");
+
+    let mut switch = Input::new(r.switch, Pull::Up);
+
+    factory_reset_hold(state, &mut switch).await;
+
+    loop {
+        switch.wait_for_low().await;
+
+        let pressed_at = Instant::now();
+
+        // Debounce and measure the press at the same time
+        match select(Timer::after(LONG_PRESS), switch.wait_for_high()).await {
+            Either::First(_) => state.request_shutdown(ShutdownReason::Switch),
+
+            // Nobody is looking at the LED in the air
+            Either::Second(_) if pressed_at.elapsed() >= DEBOUNCE => {
+                if !relay::grant_access(state) && !state.armed.try_get().unwrap_or_default() {
+                    state.indicate_once(OneShot::BatteryLevel);
+                }
+            }
+
+            Either::Second(_) => {}
+        }
+
+        switch.wait_for_high().await;
+    }
+}
@@ -0,0 +1,154 @@
+// Task runtime and stack usage
+//
+// Every task is wrapped in accounted(), which adds up the cycles spent polling it.
+// Interrupts are not taken out, so a thread mode task preempted by the control loop
+// gets charged for it as well - good enough to see the trends. All tasks share the one
+// stack, which is painted at boot. Whatever is still painted was never used.
+
+use core::{array, cell::Cell, future::poll_fn, future::Future, pin::pin, ptr};
+
+use cortex_m::peripheral::DWT;
+use defmt::info;
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
+use embassy_time::{Duration, Instant, Timer};
+
+use crate::state::SystemState;
+
+#[repr(u8)]
+#[derive(Clone, Copy, defmt::Format)]
+pub enum Task {
+    Clock,
+    Watchdog,
+    Indications,
+    Switch,
+    Shutdown,
+    Ble,
+    Control,
+    Chirp,
+    Power,
+    State,
+    Settings,
+    SelfTest,
+    Console,
+    Imu,
+    Baro,
+    Compass,
+    Tof,
+    Ir,
+    Flow,
+    Aux,
+    Relay,
+}
+
+pub const TASK_COUNT: usize = 21;
+
+const CPU_MHZ: u64 = 64;
+
+// Cycle counts wrap every ~67s, so reports have to come way more often than that
+const REPORT_INTERVAL: Duration = Duration::from_secs(10);
+
+const STACK_PAINT: u32 = 0x5107_57ac;
+// Keep away from whatever is on the stack right when painting
+const STACK_PAINT_MARGIN: usize = 256;
+
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+pub struct TaskStats {
+    // Bytes of the stack never touched since boot
+    pub stack_free: u32,
+    // Share of the time since the previous report, in 0.1%. See Task for the order
+    pub load: [u16; TASK_COUNT],
+}
+
+static CYCLES: Mutex<CriticalSectionRawMutex, [Cell<u32>; TASK_COUNT]> =
+    Mutex::new([const { Cell::new(0) }; TASK_COUNT]);
+
+extern "C" {
+    // Right past .bss and .uninit, there's no heap so the stack may grow down to there
+    static mut __sheap: u32;
+}
+
+fn stack_bottom() -> *mut u32 {
+    unsafe { ptr::addr_of_mut!(__sheap) }
+}
+
+// Has to come before anything else, whatever went deeper than us before that is missed
+pub fn init() {
+    let mut cp = unsafe { cortex_m::Peripherals::steal() };
+
+    cp.DCB.enable_trace();
+    cp.DWT.enable_cycle_counter();
+
+    let top = (cortex_m::register::msp::read() as usize - STACK_PAINT_MARGIN) as *mut u32;
+    let mut p = stack_bottom();
+
+    while p < top {
+        unsafe {
+            ptr::write_volatile(p, STACK_PAINT);
+            p = p.add(1);
+        }
+    }
+}
+
+fn stack_free() -> u32 {
+    let mut p = stack_bottom();
+    let mut free = 0;
+
+    while unsafe { ptr::read_volatile(p) } == STACK_PAINT {
+        free += 4;
+        p = unsafe { p.add(1) };
+    }
+
+    free
+}
+
+fn cycles() -> [u32; TASK_COUNT] {
+    CYCLES.lock(|cycles| array::from_fn(|i| cycles[i].get()))
+}
+
+pub async fn accounted<F: Future>(task: Task, fut: F) -> F::Output {
+    let mut fut = pin!(fut);
+
+    poll_fn(|cx| {
+        let started = DWT::cycle_count();
+        let poll = fut.as_mut().poll(cx);
+        let spent = DWT::cycle_count().wrapping_sub(started);
+
+        CYCLES.lock(|cycles| {
+            let c = &cycles[task as usize];
+            c.set(c.get().wrapping_add(spent));
+        });
+
+        poll
+    })
+    .await
+}
+
+#[embassy_executor::task]
+pub async fn run(state: &'static SystemState) {
+    let task_stats_sender = state.task_stats.sender();
+
+    let mut previous = cycles();
+    let mut reported_at = Instant::now();
+
+    loop {
+        Timer::after(REPORT_INTERVAL).await;
+
+        let current = cycles();
+        let total = reported_at.elapsed().as_micros().max(1) * CPU_MHZ;
+
+        let load =
+            array::from_fn(|i| (current[i].wrapping_sub(previous[i]) as u64 * 1000 / total) as u16);
+        let stack_free = stack_free();
+
+        info!(
+            "{} bytes of stack never used, task load (0.1%): {}",
+            stack_free, load
+        );
+
+        task_stats_sender.send(TaskStats { stack_free, load });
+
+        previous = current;
+        reported_at = Instant::now();
+    }
+}
@@ -0,0 +1,559 @@
+// Time-of-flight rangefinder
+//
+// Some builds carry an ST VL53L0X or VL53L1X on the gauge's I2C bus, looking straight
+// down. Close to the ground its height beats the barometer by far, so the altitude hold
+// flies on it while it can be trusted, and a forced landing comes down gently on it
+// rather than ramping the throttle down blind (see copter_core::control). Readings go
+// through copter_core::range first, anything it doesn't like and the control loop is
+// back to the barometer, or open loop.
+//
+// Only ranging while armed, it draws about as much as the rest of the board. Height is
+// relative to what it reads sitting on the ground at arming.
+
+use defmt::{info, unwrap, warn};
+use embassy_futures::select::{select, Either};
+use embassy_nrf::twim;
+use embassy_time::{Duration, Instant, Ticker, Timer};
+use embedded_hal_async::i2c::I2c;
+
+use copter_core::altitude::{Estimator, Vertical, GRAVITY};
+use copter_core::range::RangeCheck;
+
+use crate::{
+    state::SystemState,
+    taskstats::{self, Task},
+    utils, SharedI2cBus,
+};
+
+// Both sit there out of reset
+const ADDRESS: u8 = 0x29;
+// Faster than either of them measures, so nothing waits long
+const POLL_RATE_HZ: u64 = 50;
+// Couple of readings, the control loop lets go after that
+const STALE_AFTER: Duration = Duration::from_millis(150);
+// Ground is averaged over that many readings after arming
+const REFERENCE_SAMPLES: usize = 4;
+// Polled for when setting up
+const SETUP_TIMEOUT: Duration = Duration::from_millis(500);
+
+#[derive(Clone, Copy)]
+pub struct RangeSample {
+    // Above the ground, or whatever is under
+    pub vertical: Vertical,
+    pub at: Instant,
+}
+
+impl RangeSample {
+    pub fn fresh(&self) -> bool {
+        self.at.elapsed() < STALE_AFTER
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, defmt::Format)]
+enum Chip {
+    Vl53l0x,
+    Vl53l1x,
+}
+
+impl Chip {
+    // m, past that they're guessing. Both in their default modes
+    fn max_range(self) -> f32 {
+        match self {
+            Self::Vl53l0x => 1.2,
+            Self::Vl53l1x => 3.0,
+        }
+    }
+}
+
+// Straight from ST's API, nobody knows what most of these do
+const VL53L0X_TUNING: &[(u8, u8)] = &[
+    (0xff, 0x01),
+    (0x00, 0x00),
+    (0xff, 0x00),
+    (0x09, 0x00),
+    (0x10, 0x00),
+    (0x11, 0x00),
+    (0x24, 0x01),
+    (0x25, 0xff),
+    (0x75, 0x00),
+    (0xff, 0x01),
+    (0x4e, 0x2c),
+    (0x48, 0x00),
+    (0x30, 0x20),
+    (0xff, 0x00),
+    (0x30, 0x09),
+    (0x54, 0x00),
+    (0x31, 0x04),
+    (0x32, 0x03),
+    (0x40, 0x83),
+    (0x46, 0x25),
+    (0x60, 0x00),
+    (0x27, 0x00),
+    (0x50, 0x06),
+    (0x51, 0x00),
+    (0x52, 0x96),
+    (0x56, 0x08),
+    (0x57, 0x30),
+    (0x61, 0x00),
+    (0x62, 0x00),
+    (0x64, 0x00),
+    (0x65, 0x00),
+    (0x66, 0xa0),
+    (0xff, 0x01),
+    (0x22, 0x32),
+    (0x47, 0x14),
+    (0x49, 0xff),
+    (0x4a, 0x00),
+    (0xff, 0x00),
+    (0x7a, 0x0a),
+    (0x7b, 0x00),
+    (0x78, 0x21),
+    (0xff, 0x01),
+    (0x23, 0x34),
+    (0x42, 0x00),
+    (0x44, 0xff),
+    (0x45, 0x26),
+    (0x46, 0x05),
+    (0x40, 0x40),
+    (0x0e, 0x06),
+    (0x20, 0x1a),
+    (0x43, 0x40),
+    (0xff, 0x00),
+    (0x34, 0x03),
+    (0x35, 0x44),
+    (0xff, 0x01),
+    (0x31, 0x04),
+    (0x4b, 0x09),
+    (0x4c, 0x05),
+    (0x4d, 0x04),
+    (0xff, 0x00),
+    (0x44, 0x00),
+    (0x45, 0x20),
+    (0x47, 0x08),
+    (0x48, 0x28),
+    (0x67, 0x00),
+    (0x70, 0x04),
+    (0x71, 0x01),
+    (0x72, 0xfe),
+    (0x76, 0x00),
+    (0x77, 0x00),
+    (0xff, 0x01),
+    (0x0d, 0x01),
+    (0xff, 0x00),
+    (0x80, 0x01),
+    (0x01, 0xf8),
+    (0xff, 0x01),
+    (0x8e, 0x01),
+    (0x00, 0x01),
+    (0xff, 0x00),
+    (0x80, 0x00),
+];
+
+// ST's ultra lite driver default configuration, 0x2d onwards. Long distance mode
+const VL53L1X_CONFIG_START: u16 = 0x2d;
+const VL53L1X_CONFIG: [u8; 91] = [
+    0x00, 0x00, 0x00, 0x01, 0x02, 0x00, 0x02, 0x08, 0x00, 0x08, 0x10, 0x01, 0x01, 0x00, 0x00, 0x00,
+    0x00, 0xff, 0x00, 0x0f, 0x00, 0x00, 0x00, 0x00, 0x00, 0x20, 0x0b, 0x00, 0x00, 0x02, 0x0a, 0x21,
+    0x00, 0x00, 0x05, 0x00, 0x00, 0x00, 0x00, 0xc8, 0x00, 0x00, 0x38, 0xff, 0x01, 0x00, 0x08, 0x00,
+    0x00, 0x01, 0xcc, 0x0f, 0x01, 0xf1, 0x0d, 0x01, 0x68, 0x00, 0x80, 0x08, 0xb8, 0x00, 0x00, 0x00,
+    0x00, 0x0f, 0x89, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0x0f, 0x0d, 0x0e, 0x0e, 0x00,
+    0x00, 0x02, 0xc7, 0xff, 0x9b, 0x00, 0x00, 0x00, 0x01, 0x01, 0x00,
+];
+
+struct Tof<'a> {
+    i2c: &'a SharedI2cBus,
+    chip: Chip,
+    // VL53L0X wants it back every time ranging starts
+    stop_variable: u8,
+    // VL53L1X says data is ready with that level on its status bit
+    ready_level: u8,
+}
+
+impl<'a> Tof<'a> {
+    async fn probe(i2c: &'a SharedI2cBus) -> Option<Self> {
+        let mut tof = Self {
+            i2c,
+            chip: Chip::Vl53l0x,
+            stop_variable: 0,
+            ready_level: 1,
+        };
+
+        // VL53L0X goes first, the VL53L1X takes the one byte index as half of one and
+        // doesn't mind. The other way around would write a register on the VL53L0X
+        let mut id = [0; 2];
+        if tof.read(0xc0, &mut id[..1]).await.is_ok() && id[0] == 0xee {
+            return Some(tof);
+        }
+
+        tof.chip = Chip::Vl53l1x;
+        if tof.read(0x010f, &mut id).await.is_ok() && id == [0xea, 0xcc] {
+            return Some(tof);
+        }
+
+        None
+    }
+
+    // VL53L0X has 8 bit register numbers, VL53L1X 16 bit ones
+    fn index(&self, register: u16, buf: &mut [u8]) -> usize {
+        match self.chip {
+            Chip::Vl53l0x => {
+                buf[0] = register as u8;
+                1
+            }
+            Chip::Vl53l1x => {
+                buf[..2].copy_from_slice(&register.to_be_bytes());
+                2
+            }
+        }
+    }
+
+    async fn read(&self, register: u16, buf: &mut [u8]) -> Result<(), twim::Error> {
+        let mut index = [0; 2];
+        let len = self.index(register, &mut index);
+
+        self.i2c
+            .lock()
+            .await
+            .write_read(ADDRESS, &index[..len], buf)
+            .await
+    }
+
+    async fn read_u8(&self, register: u16) -> Result<u8, twim::Error> {
+        let mut buf = [0];
+        self.read(register, &mut buf).await?;
+        Ok(buf[0])
+    }
+
+    async fn write(&self, register: u16, data: &[u8]) -> Result<(), twim::Error> {
+        let mut buf = [0; 2 + VL53L1X_CONFIG.len()];
+        let len = self.index(register, &mut buf);
+
+        buf[len..len + data.len()].copy_from_slice(data);
+        self.i2c
+            .lock()
+            .await
+            .write(ADDRESS, &buf[..len + data.len()])
+            .await
+    }
+
+    async fn write_u8(&self, register: u16, value: u8) -> Result<(), twim::Error> {
+        self.write(register, &[value]).await
+    }
+
+    async fn write_all(&self, values: &[(u8, u8)]) -> Result<(), twim::Error> {
+        for &(register, value) in values {
+            self.write_u8(register as u16, value).await?;
+        }
+
+        Ok(())
+    }
+
+    // Polls until the condition holds, or gives up
+    async fn wait_until(
+        &self,
+        register: u16,
+        cond: impl Fn(u8) -> bool,
+    ) -> Result<bool, twim::Error> {
+        let until = Instant::now() + SETUP_TIMEOUT;
+
+        while Instant::now() < until {
+            if cond(self.read_u8(register).await?) {
+                return Ok(true);
+            }
+
+            Timer::after_millis(5).await;
+        }
+
+        Ok(false)
+    }
+
+    async fn configure(&mut self) -> Result<bool, twim::Error> {
+        match self.chip {
+            Chip::Vl53l0x => self.configure_vl53l0x().await,
+            Chip::Vl53l1x => self.configure_vl53l1x().await,
+        }
+    }
+
+    // Same as ST's API does it, minus the bits nothing here needs
+    async fn configure_vl53l0x(&mut self) -> Result<bool, twim::Error> {
+        // 2.8V I/O
+        let pad = self.read_u8(0x89).await?;
+        self.write_u8(0x89, pad | 0x01).await?;
+
+        // Standard I2C mode, and the stop variable from the hidden page
+        self.write_all(&[(0x88, 0x00), (0x80, 0x01), (0xff, 0x01), (0x00, 0x00)])
+            .await?;
+        self.stop_variable = self.read_u8(0x91).await?;
+        self.write_all(&[(0x00, 0x01), (0xff, 0x00), (0x80, 0x00)])
+            .await?;
+
+        // No signal rate limit checks on the pre-range steps, 0.25 MCPS on the final one
+        let msrc = self.read_u8(0x60).await?;
+        self.write_u8(0x60, msrc | 0x12).await?;
+        self.write(0x44, &[0x00, 0x20]).await?;
+        self.write_u8(0x01, 0xff).await?;
+
+        // Reference SPADs, how many and which kind, from the NVM
+        self.write_all(&[(0x80, 0x01), (0xff, 0x01), (0x00, 0x00), (0xff, 0x06)])
+            .await?;
+        let v = self.read_u8(0x83).await?;
+        self.write_u8(0x83, v | 0x04).await?;
+        self.write_all(&[
+            (0xff, 0x07),
+            (0x81, 0x01),
+            (0x80, 0x01),
+            (0x94, 0x6b),
+            (0x83, 0x00),
+        ])
+        .await?;
+
+        if !self.wait_until(0x83, |v| v != 0).await? {
+            return Ok(false);
+        }
+
+        self.write_u8(0x83, 0x01).await?;
+        let spads = self.read_u8(0x92).await?;
+        self.write_all(&[(0x81, 0x00), (0xff, 0x06)]).await?;
+        let v = self.read_u8(0x83).await?;
+        self.write_u8(0x83, v & !0x04).await?;
+        self.write_all(&[(0xff, 0x01), (0x00, 0x01), (0xff, 0x00), (0x80, 0x00)])
+            .await?;
+
+        let (count, aperture) = (spads & 0x7f, spads & 0x80 != 0);
+
+        let mut map = [0; 6];
+        self.read(0xb0, &mut map).await?;
+        self.write_all(&[
+            (0xff, 0x01),
+            (0x4f, 0x00),
+            (0x4e, 0x2c),
+            (0xff, 0x00),
+            (0xb6, 0xb4),
+        ])
+        .await?;
+
+        // Aperture SPADs start at 12, only that many of the good ones are enabled
+        let first = if aperture { 12 } else { 0 };
+        let mut enabled = 0;
+
+        for i in 0..48 {
+            let bit = 1 << (i % 8);
+
+            if i < first || enabled == count {
+                map[i / 8] &= !bit;
+            } else if map[i / 8] & bit != 0 {
+                enabled += 1;
+            }
+        }
+
+        self.write(0xb0, &map).await?;
+        self.write_all(VL53L0X_TUNING).await?;
+
+        // Interrupt on a new sample, active low
+        self.write_u8(0x0a, 0x04).await?;
+        let v = self.read_u8(0x84).await?;
+        self.write_u8(0x84, v & !0x10).await?;
+        self.write_u8(0x0b, 0x01).await?;
+
+        // VHV and phase calibration, one after the other
+        for (sequence, start) in [(0x01, 0x40), (0x02, 0x00)] {
+            self.write_u8(0x01, sequence).await?;
+            self.write_u8(0x00, 0x01 | start).await?;
+
+            if !self.wait_until(0x13, |v| v & 0x07 != 0).await? {
+                return Ok(false);
+            }
+
+            self.write_all(&[(0x0b, 0x01), (0x00, 0x00)]).await?;
+        }
+
+        self.write_u8(0x01, 0xe8).await?;
+        Ok(true)
+    }
+
+    async fn configure_vl53l1x(&mut self) -> Result<bool, twim::Error> {
+        if !self.wait_until(0x00e5, |v| v & 0x01 != 0).await? {
+            return Ok(false);
+        }
+
+        self.write(VL53L1X_CONFIG_START, &VL53L1X_CONFIG).await?;
+        self.ready_level = match self.read_u8(0x0030).await? & 0x10 {
+            0 => 1,
+            _ => 0,
+        };
+
+        // One measurement to get the VHV done
+        let ready_level = self.ready_level;
+        self.write_u8(0x0087, 0x40).await?;
+
+        if !self.wait_until(0x0031, |v| v & 0x01 == ready_level).await? {
+            return Ok(false);
+        }
+
+        self.write_u8(0x0086, 0x01).await?;
+        self.write_u8(0x0087, 0x00).await?;
+
+        // Two bounds VHV, starting from what it came up with just now
+        self.write_u8(0x0008, 0x09).await?;
+        self.write_u8(0x000b, 0x00).await?;
+        Ok(true)
+    }
+
+    async fn start(&self) -> Result<(), twim::Error> {
+        match self.chip {
+            // Back to back
+            Chip::Vl53l0x => {
+                self.write_all(&[(0x80, 0x01), (0xff, 0x01), (0x00, 0x00)])
+                    .await?;
+                self.write_u8(0x91, self.stop_variable).await?;
+                self.write_all(&[(0x00, 0x01), (0xff, 0x00), (0x80, 0x00), (0x00, 0x02)])
+                    .await
+            }
+            Chip::Vl53l1x => {
+                self.write_u8(0x0086, 0x01).await?;
+                self.write_u8(0x0087, 0x40).await
+            }
+        }
+    }
+
+    async fn stop(&self) -> Result<(), twim::Error> {
+        match self.chip {
+            Chip::Vl53l0x => {
+                self.write_all(&[(0x00, 0x01), (0xff, 0x01), (0x00, 0x00), (0x91, 0x00)])
+                    .await?;
+                self.write_all(&[(0x00, 0x01), (0xff, 0x00)]).await
+            }
+            Chip::Vl53l1x => self.write_u8(0x0087, 0x00).await,
+        }
+    }
+
+    // None until there's a new measurement. Range in m, None inside if the sensor says
+    // it's no good
+    async fn read_range(&self) -> Result<Option<Option<f32>>, twim::Error> {
+        let (ready, status, clear) = match self.chip {
+            Chip::Vl53l0x => (self.read_u8(0x13).await? & 0x07 != 0, 0x14, 0x0b),
+            Chip::Vl53l1x => (
+                self.read_u8(0x0031).await? & 0x01 == self.ready_level,
+                0x0089,
+                0x0086,
+            ),
+        };
+
+        if !ready {
+            return Ok(None);
+        }
+
+        // Status, then the range 10 (VL53L0X) or 13 (VL53L1X) bytes further on
+        let mut buf = [0; 15];
+        self.read(status, &mut buf).await?;
+        self.write_u8(clear, 0x01).await?;
+
+        let (valid, at) = match self.chip {
+            Chip::Vl53l0x => ((buf[0] >> 3) & 0x0f == 11, 10),
+            Chip::Vl53l1x => (buf[0] & 0x1f == 9, 13),
+        };
+
+        let mm = u16::from_be_bytes([buf[at], buf[at + 1]]);
+        Ok(Some(valid.then_some(mm as f32 / 1000.0)))
+    }
+}
+
+#[embassy_executor::task]
+pub async fn run(state: &'static SystemState, i2c: &'static SharedI2cBus) {
+    taskstats::accounted(Task::Tof, task(state, i2c)).await
+}
+
+async fn task(state: &'static SystemState, i2c: &'static SharedI2cBus) {
+    let Some(mut tof) = Tof::probe(i2c).await else {
+        info!("no rangefinder on the bus, altitude is barometer only");
+        return;
+    };
+
+    info!("found {} at {=u8:#x}", tof.chip, ADDRESS);
+
+    match tof.configure().await {
+        Ok(true) => {}
+        Ok(false) => {
+            warn!("rangefinder didn't come up");
+            return;
+        }
+        Err(e) => {
+            warn!("unable to configure the rangefinder - {}", e);
+            return;
+        }
+    }
+
+    let mut armed_receiver = unwrap!(state.armed.receiver());
+
+    loop {
+        utils::wait_for(&mut armed_receiver, |armed| *armed).await;
+
+        let disarmed = armed_receiver.changed_and(|armed| !*armed);
+
+        // Stale samples are as good as none, the control loop lets go on its own
+        if let Either::First(Err(e)) = select(sample(state, &tof), disarmed).await {
+            warn!("unable to read the rangefinder - {}", e);
+            utils::wait_for(&mut armed_receiver, |armed| !*armed).await;
+        }
+
+        if let Err(e) = tof.stop().await {
+            warn!("unable to stop the rangefinder - {}", e);
+        }
+    }
+}
+
+// For as long as it's armed, or until the bus gives up
+async fn sample(state: &SystemState, tof: &Tof<'_>) -> Result<(), twim::Error> {
+    let range_sender = state.range.sender();
+    let mut ticker = Ticker::every(Duration::from_hz(POLL_RATE_HZ));
+    let mut check = RangeCheck::new(tof.chip.max_range());
+    let mut estimator = Estimator::new();
+    let mut ground = None;
+    let mut reference = [0.0; REFERENCE_SAMPLES];
+    let mut count = 0;
+
+    tof.start().await?;
+    let mut last = Instant::now();
+
+    loop {
+        ticker.next().await;
+
+        let Some(range) = tof.read_range().await? else {
+            continue;
+        };
+
+        let now = Instant::now();
+        let dt = (now - last).as_micros() as f32 / 1_000_000.0;
+        last = now;
+
+        // Level if there's no IMU to tell, it's flying upright most of the time
+        let imu = state.imu.try_get().filter(|s| s.fresh());
+        let tilt = imu.map(|s| s.tilt).unwrap_or(1.0);
+
+        // Starts over once it can be trusted again, see RangeCheck
+        let Some(height) = check.check(range, tilt, dt) else {
+            estimator = Estimator::new();
+            continue;
+        };
+
+        let Some(ground) = ground else {
+            reference[count] = height;
+            count += 1;
+
+            if count == REFERENCE_SAMPLES {
+                let average = reference.iter().sum::<f32>() / REFERENCE_SAMPLES as f32;
+                info!("ground is {} mm away", (average * 1000.0) as u16);
+                ground = Some(average);
+            }
+
+            continue;
+        };
+
+        let accel = imu.map(|s| s.vertical_accel * GRAVITY).unwrap_or(0.0);
+
+        range_sender.send(RangeSample {
+            vertical: estimator.update(height - ground, accel, dt),
+            at: now,
+        });
+    }
+}
@@ -1,5 +1,11 @@
 // Use simple C-style packing to help with BLE serialization
 
+use copter_core::altitude::Vertical;
+use copter_core::attitude::Attitude;
+use copter_core::compass::Calibration;
+use copter_core::control::{Gains, Shaping};
+use copter_core::policy::Thresholds;
+use copter_core::sag::Sag;
 use defmt::bitflags;
 
 #[repr(C, packed)]
@@ -8,6 +14,408 @@ pub struct PeriodicUpdate {
     pub voltage: u16,
     pub current: i16,
     pub temperature: u16,
+    // Estimated minutes of flight left, FLIGHT_TIME_UNKNOWN if not discharging
+    pub flight_time: u16,
+    pub mcu_temperature: i16, // 0.1 °C
+    // mV, RAIL_VOLTAGE_UNKNOWN unless armed - SAADC belongs to the controller
+    pub rail_voltage: u16,
+    // Thread mode executor since the previous update, see executor.rs
+    pub cpu_load: u16, // 0.1 %
+    pub wakeups: u16,  // per second
+}
+
+pub const FLIGHT_TIME_UNKNOWN: u16 = u16::MAX;
+
+// Only while armed, and only with an IMU fitted (see imu.rs)
+#[repr(C, packed)]
+#[derive(Default, Copy, Clone)]
+pub struct AttitudeReport {
+    pub roll: i16,    // 0.1 °
+    pub pitch: i16,   // 0.1 °
+    pub heading: u16, // 0.1 °
+}
+
+// Only while armed, and only with a barometer fitted (see baro.rs)
+#[repr(C, packed)]
+#[derive(Default, Copy, Clone)]
+pub struct AltitudeReport {
+    pub altitude: i16, // cm above the ground at arming
+    pub climb: i16,    // cm/s
+}
+
+impl From<Vertical> for AltitudeReport {
+    fn from(v: Vertical) -> Self {
+        Self {
+            altitude: (v.altitude * 100.0) as i16,
+            climb: (v.climb * 100.0) as i16,
+        }
+    }
+}
+
+impl From<Attitude> for AttitudeReport {
+    fn from(a: Attitude) -> Self {
+        Self {
+            roll: (a.roll * 10.0) as i16,
+            pitch: (a.pitch * 10.0) as i16,
+            heading: (a.heading * 10.0) as u16,
+        }
+    }
+}
+
+// Magnetometer hard and soft iron, in raw units (see compass.rs). All zeroes until the
+// first calibration
+#[repr(C, packed)]
+#[derive(Default, Copy, Clone)]
+pub struct CompassCalibration {
+    pub offset: [i16; 3],
+    pub radius: [i16; 3],
+}
+
+impl CompassCalibration {
+    pub fn get(&self) -> Option<Calibration> {
+        let (offset, radius) = (self.offset, self.radius);
+
+        if radius.iter().any(|r| *r <= 0) {
+            return None;
+        }
+
+        Some(Calibration {
+            offset: offset.map(|v| v as f32),
+            radius: radius.map(|v| v as f32),
+        })
+    }
+}
+
+impl From<Calibration> for CompassCalibration {
+    fn from(c: Calibration) -> Self {
+        Self {
+            offset: c.offset.map(|v| v as i16),
+            radius: c.radius.map(|v| v as i16),
+        }
+    }
+}
+
+// Where the control loop takes the yaw rate from. Stock analog gyro is always there,
+// the digital one is whatever IMU turns up on the bus (see imu.rs)
+#[repr(u8)]
+#[derive(Clone, Copy, PartialEq, Default, defmt::Format)]
+pub enum RateSensor {
+    #[default]
+    Analog = 0,
+    Digital = 1,
+}
+
+// Digital gyro bias, 0.01 dps on the airframe axes. The analog one has its offset
+// stored separately, each sensor keeps its own
+#[repr(C, packed)]
+#[derive(Default, Copy, Clone)]
+pub struct ImuGyroBias {
+    pub bias: [i16; 3],
+    // Zero until the first calibration
+    pub valid: u8,
+    pub _reserved: [u8; 1],
+}
+
+impl ImuGyroBias {
+    pub fn get(&self) -> Option<[f32; 3]> {
+        let bias = self.bias;
+        (self.valid != 0).then(|| bias.map(|v| v as f32 / 100.0))
+    }
+}
+
+impl From<[f32; 3]> for ImuGyroBias {
+    fn from(bias: [f32; 3]) -> Self {
+        Self {
+            bias: bias.map(|v| (v * 100.0) as i16),
+            valid: 1,
+            _reserved: [0; 1],
+        }
+    }
+}
+pub const RAIL_VOLTAGE_UNKNOWN: u16 = 0;
+
+// Rail sag over a throttle punch, see copter_core::sag. Only while armed
+#[repr(C, packed)]
+#[derive(Default, Copy, Clone)]
+pub struct RailSagReport {
+    pub baseline: u16, // mV
+    pub min: u16,
+    pub droop: u16,
+    pub time_to_min: u16, // ms
+    // Stick units, up to STICK_FULL
+    pub step: i16,
+}
+
+impl From<Sag> for RailSagReport {
+    fn from(s: Sag) -> Self {
+        Self {
+            baseline: s.baseline,
+            min: s.min,
+            droop: s.droop(),
+            time_to_min: s.time_to_min,
+            step: s.step as i16,
+        }
+    }
+}
+
+// Yaw rate loop as the PID sees it, for tuning. Only while armed
+#[repr(C, packed)]
+#[derive(Default, Copy, Clone)]
+pub struct RateLoopReport {
+    pub gyro: i16, // 0.1 dps
+    pub setpoint: i16,
+    // Duty cycle units, zero while the throttle is too low for the PID to run
+    pub output: i16,
+    pub throttle: i16,
+    // Whatever the loop is running with right now
+    pub gains: PidParams,
+}
+
+// Published once the flight is over
+#[repr(C, packed)]
+#[derive(Default, Copy, Clone)]
+pub struct FlightPowerSummary {
+    pub duration: u16, // s
+    pub voltage_min: u16,
+    pub voltage_avg: u16,
+    pub voltage_max: u16,
+    pub current_min: i16,
+    pub current_avg: i16,
+    pub current_max: i16,
+}
+
+// Lifetime totals, kept in flash
+#[repr(C, packed)]
+#[derive(Default, Copy, Clone)]
+pub struct Odometer {
+    pub flight_time: u32, // s
+    pub arms: u32,
+    pub crashes: u32,
+}
+
+// Kept in flash as well. A unit stuck in a reboot loop looks perfectly healthy
+// otherwise, by the time someone connects it's up and running again
+#[repr(C, packed)]
+#[derive(Default, Copy, Clone)]
+pub struct BootCounters {
+    pub boots: u32,
+    pub watchdog_resets: u32,
+    pub panic_resets: u32,
+    pub fault_resets: u32,
+}
+
+impl BootCounters {
+    pub fn count(&mut self, boot_info: &BootInfo, panicked: bool, faulted: bool) {
+        self.boots += 1;
+
+        if boot_info.reset_reason().contains(ResetReason::WATCHDOG) {
+            self.watchdog_resets += 1;
+        }
+
+        // Panic and hard fault handlers reset with sys_reset(), same as a reboot
+        // request, so that's what the reports are for
+        if panicked {
+            self.panic_resets += 1;
+        }
+
+        if faulted {
+            self.fault_resets += 1;
+        }
+    }
+}
+
+// Controller we stick to, kept across reboots so we can go straight to it
+#[repr(C, packed)]
+#[derive(Default, Copy, Clone)]
+pub struct ControllerAddress {
+    // Zero if there's none yet
+    pub valid: u8,
+    pub kind: u8,
+    pub bytes: [u8; 6],
+    // Handed over on bonding by controllers with private addresses, the address above
+    // is the identity one then. Zero if there's none
+    pub irk: [u8; 16],
+}
+
+impl ControllerAddress {
+    // Key is left out, it only shows up once bonded
+    pub fn same_controller(&self, other: &Self) -> bool {
+        let (bytes, other_bytes) = (self.bytes, other.bytes);
+
+        self.valid != 0 && other.valid != 0 && self.kind == other.kind && bytes == other_bytes
+    }
+}
+
+// Keys the controller has bonded with, so reconnects go straight to encryption instead
+// of pairing all over again. Its identity key is the IRK in ControllerAddress
+#[repr(C, packed)]
+#[derive(Default, Copy, Clone)]
+pub struct ControllerBond {
+    // Of the controller they're for, same as in ControllerAddress. Zero if there's none
+    pub valid: u8,
+    pub kind: u8,
+    pub bytes: [u8; 6],
+    // Master ID, what the LTK goes by
+    pub ediv: u16,
+    pub rand: [u8; 8],
+    // As the softdevice has them - LESC, authenticated, key length
+    pub flags: u8,
+    pub _reserved: [u8; 1],
+    pub ltk: [u8; 16],
+}
+
+impl ControllerBond {
+    pub fn for_controller(&self, controller: &ControllerAddress) -> bool {
+        let (bytes, controller_bytes) = (self.bytes, controller.bytes);
+
+        self.valid != 0
+            && controller.valid != 0
+            && self.kind == controller.kind
+            && bytes == controller_bytes
+    }
+}
+
+// Ours, so bonded peers can tell our private addresses apart. Made up once and kept
+#[repr(C, packed)]
+#[derive(Default, Copy, Clone)]
+pub struct DeviceIrk {
+    // Zero until generated
+    pub valid: u8,
+    pub _reserved: [u8; 3],
+    pub key: [u8; 16],
+}
+
+// How one pilot likes it, picked by whichever controller connects. Stick values
+// are in the same units as control.rs works with, +-512 at full deflection
+#[repr(C, packed)]
+#[derive(Copy, Clone)]
+pub struct PilotProfile {
+    pub yaw_trim: i8,
+    pub pitch_trim: i8,
+    // %, full deflection gets that much of the full range
+    pub yaw_rate: u8,
+    pub pitch_rate: u8,
+    // %, how much softer the sticks are around the center. Zero is linear
+    pub expo: u8,
+    // Bit number in ButtonFlags
+    pub arm_button: u8,
+    // Toggles altitude hold. Bit number in ButtonFlags plus one, zero for none
+    pub hold_button: u8,
+    // Toggles the aux lights (see aux.rs), same as the one above
+    pub aux_button: u8,
+}
+
+impl Default for PilotProfile {
+    fn default() -> Self {
+        Self {
+            yaw_trim: 0,
+            pitch_trim: 0,
+            yaw_rate: 100,
+            pitch_rate: 100,
+            expo: 0,
+            arm_button: 11, // BUTTON_MENU
+            hold_button: 5, // BUTTON_Y
+            aux_button: 4,  // BUTTON_X
+        }
+    }
+}
+
+impl PilotProfile {
+    // Unknown values are coming from the outside, so fall back to something safe
+    pub fn arm_button(&self) -> ButtonFlags {
+        1u32.checked_shl(self.arm_button as u32)
+            .and_then(ButtonFlags::from_bits)
+            .unwrap_or(ButtonFlags::BUTTON_MENU)
+    }
+
+    // Profiles from before there was one have zero in there
+    pub fn hold_button(&self) -> ButtonFlags {
+        self.hold_button
+            .checked_sub(1)
+            .and_then(|bit| 1u32.checked_shl(bit as u32))
+            .and_then(ButtonFlags::from_bits)
+            .unwrap_or(ButtonFlags::empty())
+    }
+
+    pub fn aux_button(&self) -> ButtonFlags {
+        self.aux_button
+            .checked_sub(1)
+            .and_then(|bit| 1u32.checked_shl(bit as u32))
+            .and_then(ButtonFlags::from_bits)
+            .unwrap_or(ButtonFlags::empty())
+    }
+
+    pub fn shaping(&self) -> Shaping {
+        Shaping {
+            expo: self.expo,
+            yaw_rate: self.yaw_rate,
+            pitch_rate: self.pitch_rate,
+            yaw_trim: self.yaw_trim,
+            pitch_trim: self.pitch_trim,
+        }
+    }
+}
+
+#[repr(C, packed)]
+#[derive(Default, Copy, Clone)]
+pub struct ControllerProfile {
+    pub controller: ControllerAddress,
+    pub profile: PilotProfile,
+}
+
+impl ControllerProfile {
+    // Most recently edited first
+    pub const TABLE_LEN: usize = 4;
+
+    pub fn lookup(table: &[Self], controller: &ControllerAddress) -> PilotProfile {
+        table
+            .iter()
+            .find(|entry| entry.controller.same_controller(controller))
+            .map(|entry| entry.profile)
+            .unwrap_or_default()
+    }
+}
+
+// Softdevice-held state of a GATT client, mostly CCCDs, so it can be restored once
+// the same peer connects again
+#[repr(C, packed)]
+#[derive(Copy, Clone)]
+pub struct PeerAttrs {
+    // Zero for an empty entry
+    pub valid: u8,
+    pub kind: u8,
+    pub address: [u8; 6],
+    pub len: u8,
+    pub _reserved: [u8; 3],
+    pub data: [u8; Self::DATA_LEN],
+}
+
+impl PeerAttrs {
+    pub const DATA_LEN: usize = 64;
+    // Most recently seen first
+    pub const TABLE_LEN: usize = 2;
+
+    pub const EMPTY: Self = Self {
+        valid: 0,
+        kind: 0,
+        address: [0; 6],
+        len: 0,
+        _reserved: [0; 3],
+        data: [0; Self::DATA_LEN],
+    };
+
+    pub fn same_peer(&self, other: &Self) -> bool {
+        let (address, other_address) = (self.address, other.address);
+
+        self.valid != 0 && other.valid != 0 && self.kind == other.kind && address == other_address
+    }
+}
+
+// Reported by the control task once disarmed
+#[derive(Clone, Copy)]
+pub struct FlightLog {
+    pub duration: u32, // s
+    pub crashed: bool,
 }
 
 #[repr(C, packed)]
@@ -40,6 +448,162 @@ impl PidParams {
     }
 }
 
+impl From<Gains> for PidParams {
+    fn from(g: Gains) -> Self {
+        let unscaled = |v: f32| (v * 100.0 + 0.5) as u16;
+
+        Self {
+            unscaled_p: unscaled(g.p),
+            unscaled_i: unscaled(g.i),
+            unscaled_d: unscaled(g.d),
+        }
+    }
+}
+
+// Chemistry options of the bq27427, named after the charge voltage they are meant for
+#[repr(u8)]
+#[derive(Clone, Copy, PartialEq, Default, defmt::Format)]
+pub enum BatteryChemistry {
+    // Regular Li-ion / LiPo, which is what comes with the S107
+    #[default]
+    B4200 = 0,
+    // LiHV
+    A4350 = 1,
+    C4400 = 2,
+}
+
+impl BatteryChemistry {
+    pub fn from_u8(v: u8) -> Option<Self> {
+        match v {
+            0 => Some(Self::B4200),
+            1 => Some(Self::A4350),
+            2 => Some(Self::C4400),
+            _ => None,
+        }
+    }
+}
+
+#[repr(C, packed)]
+#[derive(Copy, Clone)]
+pub struct BatteryProfile {
+    pub capacity: u16,          // mAh
+    pub energy: u16,            // mWh
+    pub terminate_voltage: u16, // mV
+    pub taper_rate: u16,
+    pub soc_delta: u8, // %, SoC change that pulses the gauge interrupt line
+    pub chemistry: u8, // BatteryChemistry
+}
+
+impl BatteryProfile {
+    // Unknown values are coming from the outside, so fall back to something safe
+    pub fn chemistry(&self) -> BatteryChemistry {
+        BatteryChemistry::from_u8(self.chemistry).unwrap_or_default()
+    }
+
+    // Goes straight into the gauge, and whatever it makes of nonsense is what the SoC
+    // and the low battery policy go by
+    pub fn valid(&self) -> bool {
+        self.capacity > 0
+            && self.energy > 0
+            && (2800..=3700).contains(&{ self.terminate_voltage })
+            && self.taper_rate > 0
+            && (1..=100).contains(&{ self.soc_delta })
+    }
+}
+
+impl Default for BatteryProfile {
+    // Stock S107 cell
+    fn default() -> Self {
+        Self {
+            capacity: 200,
+            energy: 740, // capacity * 3.7
+            terminate_voltage: 3200,
+            // Taper Rate = Design Capacity / (0.1 × taper current)
+            // XXX: This assumes charge current is 100 mA, taper current is 25 ma
+            // npm1100 seems to come closer to 20 ma, then switches to 10 ma for 300ms, then drops to 0
+            taper_rate: 75,
+            soc_delta: 1,
+            chemistry: BatteryChemistry::B4200 as u8,
+        }
+    }
+}
+
+// Results of the gauge learning cycle, see learning.rs
+#[repr(C, packed)]
+#[derive(Copy, Clone)]
+pub struct GaugeLearnedData {
+    pub qmax: u16,
+    pub ra_table: [u16; 15],
+}
+
+impl Default for GaugeLearnedData {
+    // Obtained from a learning cycle of the stock S107 cell :)
+    fn default() -> Self {
+        Self {
+            qmax: 17449,
+            ra_table: [50, 30, 34, 46, 38, 32, 37, 31, 32, 35, 39, 39, 61, 115, 200],
+        }
+    }
+}
+
+// Where the learning cycle is at, once per gauge poll while it runs. See tools/gauge
+#[repr(C, packed)]
+#[derive(Default, Copy, Clone)]
+pub struct LearningReport {
+    pub phase: u8, // LearningPhase
+    pub update_status: u8,
+    pub qmax: u16,
+    pub voltage: u16, // mV
+    pub current: i16, // mA
+    // Minutes the cell has been resting for, zero while there's a load on it
+    pub rested: u16,
+}
+
+// Where an unfinished learning cycle is at, so it survives a power cycle
+#[repr(C, packed)]
+#[derive(Default, Copy, Clone)]
+pub struct LearningProgress {
+    pub phase: u8, // LearningPhase
+    pub _reserved: u8,
+    // Minutes of rest done so far
+    pub rested: u16,
+}
+
+// SoC levels (%) at which the low battery policy kicks in, see copter_core::policy
+#[repr(C, packed)]
+#[derive(Copy, Clone)]
+pub struct SocThresholds {
+    pub warn: u8,
+    pub limit_throttle: u8,
+    pub force_landing: u8,
+    pub inhibit_arming: u8,
+    pub shutdown: u8,
+}
+
+impl Default for SocThresholds {
+    fn default() -> Self {
+        Self {
+            warn: 20,
+            limit_throttle: 15,
+            force_landing: 10,
+            inhibit_arming: 5,
+            shutdown: 2,
+        }
+    }
+}
+
+impl From<SocThresholds> for Thresholds {
+    fn from(t: SocThresholds) -> Self {
+        Self {
+            warn: t.warn,
+            limit_throttle: t.limit_throttle,
+            force_landing: t.force_landing,
+            inhibit_arming: t.inhibit_arming,
+            shutdown: t.shutdown,
+        }
+    }
+}
+
 bitflags! {
     #[derive(Default)]
     pub struct ButtonFlags:u32 {
@@ -66,3 +630,112 @@ pub struct JoystickData {
     pub t2: u16,
     pub buttons: ButtonFlags,
 }
+
+bitflags! {
+    #[derive(Default)]
+    pub struct Faults: u32 {
+        const BATTERY_OVERTEMP = 1 << 0;
+        const BATTERY_UNDERTEMP = 1 << 1;
+
+        // Too hot or too cold to charge. Charger is only cut off with the
+        // charge-inhibit feature, otherwise it's up to the user to unplug
+        const CHARGE_TEMPERATURE = 1 << 2;
+        // Charger reports an error, e.g. the safety timer has expired
+        const CHARGER_FAILURE = 1 << 3;
+
+        // Power-on self-test, see selftest.rs. Stay until the next power cycle
+        const SELF_TEST_GYRO = 1 << 4;
+        const SELF_TEST_GAUGE = 1 << 5;
+
+        // Soft assertion failed, see assertion.rs. Stays until the next power cycle
+        const ASSERTION = 1 << 6;
+
+        // Gyro is missing or dead, checked at every boot (see control.rs). Stays until the
+        // next power cycle
+        const GYRO_SENSOR = 1 << 7;
+
+        const BATTERY_TEMPERATURE = Self::BATTERY_OVERTEMP.bits | Self::BATTERY_UNDERTEMP.bits;
+        // Only matter while on the charger
+        const CHARGING = Self::CHARGE_TEMPERATURE.bits | Self::CHARGER_FAILURE.bits;
+    }
+}
+
+bitflags! {
+    // SoC thresholds as the gauge sees them. These are latched by the gauge itself,
+    // so they keep coming even if our own SoC polling falls behind
+    #[derive(Default)]
+    pub struct GaugeSocFlags: u32 {
+        // SOC1, programmed to the low battery warning level
+        const LOW = 1 << 0;
+        // SOCF, programmed to the level where arming is inhibited
+        const FINAL = 1 << 1;
+    }
+}
+
+// Zero is left for "not shutting down", which is what peers read most of the time
+#[repr(u8)]
+#[derive(Clone, Copy, PartialEq, defmt::Format)]
+pub enum ShutdownReason {
+    BatteryDepleted = 1,
+    Switch = 2,
+    Host = 3,
+}
+
+bitflags! {
+    // Everyone who has something to do before we power off, see shutdown.rs
+    #[derive(Default)]
+    pub struct ShutdownAcks: u32 {
+        const SETTINGS = 1 << 0;
+        const PEERS = 1 << 1;
+        const GAUGE = 1 << 2;
+    }
+}
+
+bitflags! {
+    // Persisted settings that can be put back to defaults on their own, see settings.rs
+    #[derive(Default)]
+    pub struct SettingsGroups: u32 {
+        // Battery profile, charge mode and SoC thresholds
+        const BATTERY = 1 << 0;
+        const GAUGE_LEARNED = 1 << 1;
+        // Theme and flight light
+        const INDICATIONS = 1 << 2;
+        // PID gains, gyro offset, parameters and pilot profiles
+        const CONTROL = 1 << 3;
+        // Known controller and phones
+        const PEERS = 1 << 4;
+        const SELF_TEST = 1 << 5;
+        // Odometer, boot counters and the last fault
+        const STATISTICS = 1 << 6;
+    }
+}
+
+bitflags! {
+    // Mirrors POWER.RESETREAS
+    #[derive(Default)]
+    pub struct ResetReason: u32 {
+        const PIN = 1 << 0;
+        const WATCHDOG = 1 << 1;
+        // sys_reset(), which includes our panic handler
+        const SOFT = 1 << 2;
+        const LOCKUP = 1 << 3;
+        const WAKEUP_GPIO = 1 << 16;
+        const WAKEUP_LPCOMP = 1 << 17;
+        const WAKEUP_DEBUG = 1 << 18;
+        const WAKEUP_NFC = 1 << 19;
+    }
+}
+
+// Power-on reset and brown-out leave RESETREAS empty
+#[repr(C, packed)]
+#[derive(Default, Copy, Clone)]
+pub struct BootInfo {
+    pub reset_reason: u32,
+    pub gpregret: u32,
+}
+
+impl BootInfo {
+    pub fn reset_reason(&self) -> ResetReason {
+        ResetReason::from_bits_truncate(self.reset_reason)
+    }
+}
@@ -1,24 +1,127 @@
+use core::future::Future;
+
+use defmt::{warn, Format};
 use embassy_futures::select::select;
+use embassy_time::{Duration, Ticker};
 
 use crate::state::StateReceiver;
 
-pub async fn run_with_receiver<'a, F>(mut receiver: StateReceiver<'a, bool>, mut fun: F)
+// Each piece of the state has its own watch, so waiting on one of them is not woken
+// up by the rest. Returns right away if the value is already there
+pub async fn wait_for<'a, T, C>(receiver: &mut StateReceiver<'a, T>, cond: C) -> T
+where
+    T: Clone,
+    C: Fn(&T) -> bool,
+{
+    receiver.get_and(|value| cond(value)).await
+}
+
+// Keeps running `fun` for as long as the value satisfies `cond`, and cancels it as
+// soon as it doesn't
+pub async fn run_while<'a, T, C, F>(receiver: &mut StateReceiver<'a, T>, cond: C, mut fun: F)
 where
+    T: Clone,
+    C: Fn(&T) -> bool,
     F: AsyncFnMut(),
 {
     loop {
-        if let Some(true) = receiver.try_get() {
-            let mut wait_cancellation = async || loop {
-                let cond = receiver.changed().await;
-                if !cond {
-                    break;
+        wait_for(receiver, &cond).await;
+        select(fun(), receiver.changed_and(|value| !cond(value))).await;
+    }
+}
+
+// Paces the reads of a sensor and keeps one that fails from flooding the log
+pub struct Sampler {
+    ticker: Ticker,
+    failing: bool,
+    count: u32,
+}
+
+impl Sampler {
+    fn new(rate_hz: u64) -> Self {
+        Self {
+            ticker: Ticker::every(Duration::from_hz(rate_hz)),
+            failing: false,
+            count: 0,
+        }
+    }
+
+    // Waits for the next tick, then reads. Failures are only logged once in a row,
+    // whoever uses the samples lets go of them once they're stale
+    pub async fn next<T, E: Format>(
+        &mut self,
+        what: &str,
+        read: impl Future<Output = Result<T, E>>,
+    ) -> Option<T> {
+        self.ticker.next().await;
+
+        match read.await {
+            Ok(value) => {
+                self.failing = false;
+                self.count = self.count.wrapping_add(1);
+                Some(value)
+            }
+            Err(e) => {
+                if !self.failing {
+                    warn!("unable to read the {} - {}", what, e);
                 }
-            };
 
-            select(fun(), wait_cancellation()).await;
-            continue;
+                self.failing = true;
+                None
+            }
         }
+    }
 
-        _ = receiver.changed().await;
+    // Every that many good samples, for the reports that don't need the full rate
+    pub fn every(&self, divider: u32) -> bool {
+        self.count % divider == 0
+    }
+}
+
+// Sensors share the bus with the gauge, so it's only kept busy while the samples are
+// of use - while armed. `fun` starts over with a fresh sampler on every arming, and
+// is cancelled once disarmed
+pub async fn sample_while_armed<'a, F>(
+    armed_receiver: &mut StateReceiver<'a, bool>,
+    rate_hz: u64,
+    mut fun: F,
+) where
+    F: AsyncFnMut(&mut Sampler),
+{
+    run_while(
+        armed_receiver,
+        |armed| *armed,
+        async || fun(&mut Sampler::new(rate_hz)).await,
+    )
+    .await
+}
+
+// Plain moving average over the last N samples
+pub struct RollingAverage<const N: usize> {
+    samples: [i32; N],
+    next: usize,
+    len: usize,
+}
+
+impl<const N: usize> RollingAverage<N> {
+    pub const fn new() -> Self {
+        Self {
+            samples: [0; N],
+            next: 0,
+            len: 0,
+        }
+    }
+
+    pub fn add(&mut self, sample: i32) {
+        self.samples[self.next] = sample;
+        self.next = (self.next + 1) % N;
+        self.len = (self.len + 1).min(N);
+    }
+
+    pub fn get(&self) -> Option<i32> {
+        match self.len {
+            0 => None,
+            len => Some(self.samples[..len].iter().sum::<i32>() / len as i32),
+        }
     }
 }
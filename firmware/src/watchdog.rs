@@ -0,0 +1,156 @@
+// Watchdog and task supervision
+//
+// The hardware watchdog is only fed for as long as every supervised task keeps checking
+// in on time. A task that stops doing so, or the executor getting stuck as a whole, ends
+// with a watchdog reset, which is counted along with the rest (see BootCounters). Which
+// task it was is kept in retained RAM and reported after the reboot. Tasks are only
+// supervised while they have a deadline, the ones that are legitimately waiting for
+// something release it for that long.
+
+use core::{cell::Cell, future, mem::MaybeUninit, ptr};
+
+use defmt::{error, warn};
+use embassy_nrf::{
+    peripherals,
+    wdt::{self, Watchdog},
+    Peri,
+};
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_time::{Duration, Instant, Timer};
+
+use crate::state::{StateMutex, SystemState};
+use crate::taskstats::{self, Task};
+
+const OVERDUE_MAGIC: u32 = 0x5107_0d0e;
+
+#[repr(u8)]
+#[derive(Clone, Copy, PartialEq, defmt::Format)]
+pub enum Supervised {
+    Control = 0,
+    Power = 1,
+    Ble = 2,
+}
+
+const SUPERVISED_COUNT: usize = 3;
+
+impl Supervised {
+    const ALL: [Self; SUPERVISED_COUNT] = [Self::Control, Self::Power, Self::Ble];
+
+    fn from_u32(value: u32) -> Option<Self> {
+        Self::ALL.into_iter().find(|task| *task as u32 == value)
+    }
+}
+
+pub struct Supervisor {
+    // Latest check-in deadline of each task, None while it's not supervised
+    deadlines: Mutex<StateMutex, [Cell<Option<Instant>>; SUPERVISED_COUNT]>,
+}
+
+impl Supervisor {
+    pub const fn new() -> Self {
+        Self {
+            deadlines: Mutex::new([const { Cell::new(None) }; SUPERVISED_COUNT]),
+        }
+    }
+
+    // Next check-in is due within that long
+    pub fn check_in(&self, task: Supervised, within: Duration) {
+        let at = Instant::now() + within;
+
+        self.deadlines
+            .lock(|deadlines| deadlines[task as usize].set(Some(at)));
+    }
+
+    pub fn release(&self, task: Supervised) {
+        self.deadlines
+            .lock(|deadlines| deadlines[task as usize].set(None));
+    }
+
+    fn overdue(&self) -> Option<Supervised> {
+        let now = Instant::now();
+
+        self.deadlines.lock(|deadlines| {
+            Supervised::ALL
+                .into_iter()
+                .find(|task| deadlines[*task as usize].get().is_some_and(|at| at < now))
+        })
+    }
+}
+
+#[repr(C)]
+struct Retained {
+    magic: u32,
+    task: u32,
+}
+
+#[link_section = ".uninit.WATCHDOG"]
+static mut RETAINED: MaybeUninit<Retained> = MaybeUninit::uninit();
+
+fn retained() -> *mut Retained {
+    unsafe { ptr::addr_of_mut!(RETAINED).cast() }
+}
+
+// Hands out the task that got the previous run reset, if any. Only once, same as
+// the post-mortem reports
+pub fn take_overdue() -> Option<Supervised> {
+    unsafe {
+        let r = retained();
+
+        // Power-on leaves garbage there
+        if ptr::read_volatile(ptr::addr_of!((*r).magic)) != OVERDUE_MAGIC {
+            return None;
+        }
+
+        ptr::write_volatile(ptr::addr_of_mut!((*r).magic), 0);
+        Supervised::from_u32(ptr::read_volatile(ptr::addr_of!((*r).task)))
+    }
+}
+
+#[embassy_executor::task]
+pub async fn run(state: &'static SystemState, wdt: Peri<'static, peripherals::WDT>) {
+    taskstats::accounted(Task::Watchdog, task(state, wdt)).await
+}
+
+async fn task(state: &'static SystemState, wdt: Peri<'static, peripherals::WDT>) {
+    // Way longer than any flash operation can stall us
+    const TIMEOUT: u32 = 2 * 32768; // LFCLK ticks
+    const FEED_INTERVAL: Duration = Duration::from_millis(500);
+
+    let mut config = wdt::Config::default();
+    config.timeout_ticks = TIMEOUT;
+
+    // Can't be stopped or reconfigured once started, and it keeps running across soft
+    // resets. One started by some other firmware bites shortly, and the next boot gets
+    // to set it up
+    let (_wdt, [mut handle]) = match Watchdog::try_new(wdt, config) {
+        Ok(wdt) => wdt,
+        Err(_) => {
+            warn!("watchdog is running with a different config, expect a reset");
+            return;
+        }
+    };
+
+    loop {
+        if let Some(task) = state.supervisor.overdue() {
+            error!(
+                "{} missed its check-in, letting the watchdog reset us",
+                task
+            );
+
+            unsafe {
+                ptr::write_volatile(
+                    retained(),
+                    Retained {
+                        magic: OVERDUE_MAGIC,
+                        task: task as u32,
+                    },
+                );
+            }
+
+            return future::pending().await;
+        }
+
+        handle.pet();
+        Timer::after(FEED_INTERVAL).await;
+    }
+}
@@ -0,0 +1,226 @@
+// The checks themselves
+//
+// Each one leaves the board the way it found it, failed or not. Operator checks need
+// someone with the paired controller in hand, and a board that's safe to arm - rotor
+// blades off, or the airframe held down.
+
+use std::{io, time::Duration};
+
+use futures::StreamExt;
+use link::{Device, Result};
+use tokio::time::{self, Instant};
+
+use link::proto::{
+    self, EventKind, PeriodicUpdate, SocThresholds, FLIGHT_LIGHT_MAX, LOG_LEVEL_TRACE, LOG_MODULES,
+    RAIL_VOLTAGE_UNKNOWN, SELF_TEST_MODE_MAX, SOC_STAGE_INHIBIT_ARMING,
+};
+
+macro_rules! ensure {
+    ($cond:expr, $($arg:tt)+) => {
+        if !$cond {
+            return Err(format!($($arg)+).into());
+        }
+    };
+}
+
+// Updates come every second, or every 10s on the charger
+const TELEMETRY_WINDOW: Duration = Duration::from_secs(25);
+const TELEMETRY_MIN_UPDATES: usize = 2;
+// Gauge polls drive the low battery policy, so a new stage takes one of them
+const STAGE_TIMEOUT: Duration = Duration::from_secs(12);
+const OPERATOR_TIMEOUT: Duration = Duration::from_secs(10);
+// Link loss has to cut the motors right away, not on some later timeout
+const FAILSAFE_LATENCY_MS: u32 = 500;
+// Supervision timeout and then some
+const LINK_LOSS_TIMEOUT: Duration = Duration::from_secs(15);
+
+fn prompt(what: &str) -> Result<()> {
+    eprint!("  >> {} and press enter ", what);
+    io::stdin().read_line(&mut String::new())?;
+    Ok(())
+}
+
+pub async fn telemetry(dev: &mut Device) -> Result<()> {
+    let mut updates = Box::pin(dev.notifications(proto::PERIODIC_UPDATE).await?);
+    let deadline = Instant::now() + TELEMETRY_WINDOW;
+    let mut seen = 0;
+
+    while let Ok(Some(raw)) = time::timeout_at(deadline, updates.next()).await {
+        let u = PeriodicUpdate::parse(&raw).ok_or("periodic update has the wrong size")?;
+
+        ensure!(
+            (3000..=4400).contains(&u.voltage),
+            "battery at {} mV, is the gauge alive?",
+            u.voltage
+        );
+        ensure!(
+            (-200..=850).contains(&u.mcu_temperature),
+            "MCU at {} (0.1 C)",
+            u.mcu_temperature
+        );
+        ensure!(u.cpu_load <= 1000, "CPU load at {} (0.1 %)", u.cpu_load);
+        ensure!(
+            u.rail_voltage == RAIL_VOLTAGE_UNKNOWN,
+            "rail voltage {} mV while disarmed",
+            u.rail_voltage
+        );
+
+        seen += 1;
+    }
+
+    ensure!(
+        seen >= TELEMETRY_MIN_UPDATES,
+        "only {} updates in {:?}",
+        seen,
+        TELEMETRY_WINDOW
+    );
+
+    Ok(())
+}
+
+// Written value has to survive a reconnect, and whatever was there is put back
+async fn round_trip(dev: &mut Device, uuid: uuid::Uuid, value: &[u8], expect: &[u8]) -> Result<()> {
+    let original = dev.read(uuid).await?;
+
+    let r = async {
+        dev.write(uuid, value).await?;
+        dev.reconnect().await?;
+
+        let read = dev.read(uuid).await?;
+        ensure!(
+            read == expect,
+            "{} reads {:?} after writing {:?}",
+            uuid,
+            read,
+            value
+        );
+
+        Ok(())
+    }
+    .await;
+
+    dev.write(uuid, &original).await?;
+    r
+}
+
+// Out of range values are dropped, the previous one stays
+async fn rejected(dev: &mut Device, uuid: uuid::Uuid, value: &[u8]) -> Result<()> {
+    let original = dev.read(uuid).await?;
+
+    dev.write(uuid, value).await?;
+    dev.reconnect().await?;
+
+    let read = dev.read(uuid).await?;
+    if read != original {
+        dev.write(uuid, &original).await?;
+    }
+
+    ensure!(read == original, "{} took {:?}", uuid, value);
+    Ok(())
+}
+
+pub async fn config(dev: &mut Device) -> Result<()> {
+    let thresholds: SocThresholds = [25, 18, 12, 6, 3];
+    round_trip(dev, proto::SOC_THRESHOLDS, &thresholds, &thresholds).await?;
+
+    let light = dev.read(proto::FLIGHT_LIGHT).await?;
+    let other = [(light[0] + 1) % (FLIGHT_LIGHT_MAX + 1)];
+    round_trip(dev, proto::FLIGHT_LIGHT, &other, &other).await?;
+    rejected(dev, proto::FLIGHT_LIGHT, &[FLIGHT_LIGHT_MAX + 1]).await?;
+
+    let mode = dev.read(proto::SELF_TEST_MODE).await?;
+    let other = [(mode[0] + 1) % (SELF_TEST_MODE_MAX + 1)];
+    round_trip(dev, proto::SELF_TEST_MODE, &other, &other).await?;
+    rejected(dev, proto::SELF_TEST_MODE, &[SELF_TEST_MODE_MAX + 1]).await?;
+
+    // Not stored, but kept for as long as the board is up
+    let mut levels = [LOG_LEVEL_TRACE + 3; LOG_MODULES];
+    levels[0] = 0;
+    let mut expect = [LOG_LEVEL_TRACE; LOG_MODULES];
+    expect[0] = 0;
+    round_trip(dev, proto::LOG_LEVELS, &levels, &expect).await?;
+
+    // Everything put back has to stay put as well
+    dev.reconnect().await?;
+
+    Ok(())
+}
+
+pub async fn arm_inhibition(dev: &mut Device, operator: bool) -> Result<()> {
+    let original = dev.read(proto::SOC_THRESHOLDS).await?;
+    let mark = dev.event_mark().await?;
+
+    // Whatever the charge, it's too low to take off
+    let inhibit: SocThresholds = [100, 100, 100, 100, 0];
+    dev.write(proto::SOC_THRESHOLDS, &inhibit).await?;
+
+    let r = async {
+        let stage = dev
+            .wait_for_event(mark, STAGE_TIMEOUT, |e| {
+                e.is(EventKind::SocStage) && e.value >= SOC_STAGE_INHIBIT_ARMING
+            })
+            .await?;
+
+        ensure!(stage.is_some(), "low battery policy didn't inhibit arming");
+
+        if operator {
+            let mark = dev.event_mark().await?;
+
+            prompt("keep the throttle down, press the arm button")?;
+
+            let armed = dev
+                .wait_for_event(mark, OPERATOR_TIMEOUT, |e| e.is(EventKind::Armed))
+                .await?;
+
+            ensure!(armed.is_none(), "armed with arming inhibited");
+        }
+
+        Ok(())
+    }
+    .await;
+
+    dev.write(proto::SOC_THRESHOLDS, &original).await?;
+    r
+}
+
+pub async fn failsafe(dev: &mut Device) -> Result<()> {
+    let mark = dev.event_mark().await?;
+
+    prompt("make sure the rotors can't hurt anyone, arm with the throttle down")?;
+
+    let armed = dev
+        .wait_for_event(mark, OPERATOR_TIMEOUT, |e| e.is(EventKind::Armed))
+        .await?
+        .ok_or("never armed, is the controller connected?")?;
+
+    prompt("switch the controller off")?;
+
+    let disarmed = dev
+        .wait_for_event(armed.timestamp, LINK_LOSS_TIMEOUT, |e| {
+            e.is(EventKind::Disarmed)
+        })
+        .await?
+        .ok_or("still armed with the controller gone")?;
+
+    let lost = dev
+        .events_since(armed.timestamp)
+        .await?
+        .into_iter()
+        .find(|e| e.is(EventKind::ControllerDisconnected))
+        .ok_or("disarmed, but the controller is still connected")?;
+
+    // Idle disarm timeout got there first, nothing to measure
+    ensure!(
+        disarmed.timestamp >= lost.timestamp,
+        "disarmed before the controller was switched off, too slow?"
+    );
+
+    let latency = disarmed.timestamp - lost.timestamp;
+    ensure!(
+        latency <= FAILSAFE_LATENCY_MS,
+        "motors were cut {} ms after the link was lost",
+        latency
+    );
+
+    Ok(())
+}
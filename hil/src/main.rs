@@ -0,0 +1,119 @@
+// Hardware-in-the-loop checks
+//
+// Drives a flashed board over its GATT services and checks what only the real thing
+// can tell: telemetry coming through with sane values, settings surviving a reconnect,
+// the low battery policy keeping it on the ground, and the motors being cut when the
+// controller goes away. The last two need an operator with the paired controller,
+// they are skipped unless asked for.
+//
+//   cargo run -p hil -- [--name NAME] [--operator] [CHECK...]
+//
+// Exits with 1 if anything failed, so it can sit in a script.
+
+mod checks;
+
+use std::{env, process};
+
+use link::{Device, Result};
+
+const CHECKS: [&str; 4] = ["telemetry", "config", "arm-inhibition", "failsafe"];
+
+struct Options {
+    name: Option<String>,
+    operator: bool,
+    checks: Vec<String>,
+}
+
+fn usage() -> ! {
+    eprintln!(
+        "usage: hil [--name NAME] [--operator] [CHECK...]\nchecks: {}",
+        CHECKS.join(", ")
+    );
+    process::exit(2);
+}
+
+fn parse_options() -> Options {
+    let mut options = Options {
+        name: None,
+        operator: false,
+        checks: Vec::new(),
+    };
+
+    let mut args = env::args().skip(1);
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--name" => options.name = Some(args.next().unwrap_or_else(|| usage())),
+            "--operator" => options.operator = true,
+            check if CHECKS.contains(&check) => options.checks.push(arg),
+            _ => usage(),
+        }
+    }
+
+    if options.checks.is_empty() {
+        options.checks = CHECKS.iter().map(|c| c.to_string()).collect();
+    }
+
+    options
+}
+
+enum Outcome {
+    Pass,
+    Fail(String),
+    Skipped(&'static str),
+}
+
+async fn run(dev: &mut Device, check: &str, operator: bool) -> Outcome {
+    let r: Result<()> = match check {
+        "telemetry" => checks::telemetry(dev).await,
+        "config" => checks::config(dev).await,
+        "arm-inhibition" => checks::arm_inhibition(dev, operator).await,
+        "failsafe" if operator => checks::failsafe(dev).await,
+        "failsafe" => return Outcome::Skipped("needs --operator"),
+        _ => unreachable!(),
+    };
+
+    match r {
+        Ok(()) => Outcome::Pass,
+        Err(e) => Outcome::Fail(e.to_string()),
+    }
+}
+
+#[tokio::main]
+async fn main() {
+    let options = parse_options();
+
+    let mut dev = match Device::connect(options.name.as_deref()).await {
+        Ok(dev) => dev,
+        Err(e) => {
+            eprintln!("unable to connect - {}", e);
+            process::exit(1);
+        }
+    };
+
+    let mut failed = 0;
+
+    for check in &options.checks {
+        println!("{}...", check);
+
+        match run(&mut dev, check, options.operator).await {
+            Outcome::Pass => println!("PASS {}", check),
+            Outcome::Skipped(why) => println!("SKIP {} ({})", check, why),
+            Outcome::Fail(why) => {
+                println!("FAIL {}: {}", check, why);
+                failed += 1;
+            }
+        }
+    }
+
+    // Board is left advertising, ready for the next run
+    if let Err(e) = dev.disconnect().await {
+        eprintln!("unable to disconnect - {}", e);
+    }
+
+    println!("{} of {} checks failed", failed, options.checks.len());
+
+    if failed > 0 {
+        process::exit(1);
+    }
+}
@@ -0,0 +1,147 @@
+// Control loop, as the firmware runs it
+//
+// The pipeline itself is copter_core::control, same as in Controller::tick() in
+// firmware/src/control.rs. What's left here is arming and the battery, with the
+// airframe defaults from there. Keep the two in step, otherwise the simulator flies
+// something else than the airframe does.
+
+use copter_core::{
+    control::{ControlCore, Estimates, Gains, Shaping, Sticks},
+    mixer::Duties,
+    policy::{SocPolicy, SocStage, Thresholds},
+};
+
+pub const MAX_DUTY: u16 = 512;
+pub const LOOP_HZ: u32 = 200;
+
+// Default SocThresholds of the firmware
+const THRESHOLDS: Thresholds = Thresholds {
+    warn: 20,
+    limit_throttle: 15,
+    force_landing: 10,
+    inhibit_arming: 5,
+    shutdown: 2,
+};
+
+pub struct Flight {
+    core: ControlCore,
+    policy: SocPolicy,
+    // Default PilotProfile of the firmware
+    pub profile: Shaping,
+    pub stage: SocStage,
+    pub armed: bool,
+    pub duties: Duties,
+}
+
+impl Flight {
+    pub fn new() -> Self {
+        Self {
+            core: ControlCore::new(MAX_DUTY, LOOP_HZ),
+            policy: SocPolicy::new(),
+            profile: Shaping::default(),
+            stage: SocStage::Normal,
+            armed: false,
+            duties: Duties::default(),
+        }
+    }
+
+    // Arming is refused on a flat battery, same as on the airframe
+    pub fn toggle_arming(&mut self) {
+        self.armed = !self.armed && self.stage < SocStage::InhibitArming;
+    }
+
+    pub fn set_pid(&mut self, p: f32, i: f32, d: f32) {
+        self.core.set_gains(Gains { p, i, d });
+    }
+
+    pub fn update_soc(&mut self, soc: u8) {
+        self.stage = self.policy.update(soc, false, &THRESHOLDS);
+
+        if self.stage == SocStage::Shutdown {
+            self.armed = false;
+        }
+    }
+
+    pub fn undervoltage(&mut self) {
+        self.core.undervoltage();
+    }
+
+    pub fn locked_out(&self) -> bool {
+        self.core.locked_out()
+    }
+
+    pub fn throttle_limit(&self) -> f32 {
+        self.core.throttle_limit()
+    }
+
+    pub fn tick(&mut self, sticks: &Sticks, gyro: f32) -> Duties {
+        let step = match self.armed {
+            true => self.core.tick(
+                sticks,
+                &self.profile,
+                self.stage,
+                gyro,
+                &Estimates::default(),
+            ),
+            false => None,
+        };
+
+        self.duties = step.map_or(Duties::default(), |s| s.duties(MAX_DUTY));
+        self.duties
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::plant::Plant;
+
+    const DT: f32 = 1.0 / LOOP_HZ as f32;
+
+    fn fly(flight: &mut Flight, plant: &mut Plant, sticks: &Sticks, seconds: u32) {
+        for _ in 0..seconds * LOOP_HZ {
+            let duties = flight.tick(sticks, plant.gyro());
+            plant.step(&duties, MAX_DUTY, DT);
+        }
+    }
+
+    #[test]
+    fn holds_the_heading_in_a_hover() {
+        let (mut flight, mut plant) = (Flight::new(), Plant::new());
+        let sticks = Sticks {
+            throttle: 20000,
+            ..Default::default()
+        };
+
+        flight.toggle_arming();
+        fly(&mut flight, &mut plant, &sticks, 5);
+
+        assert!(plant.altitude > 0.0);
+        assert!(
+            plant.yaw_rate.abs() < 10.0,
+            "spinning at {}",
+            plant.yaw_rate
+        );
+    }
+
+    #[test]
+    fn forced_landing_brings_it_down() {
+        let (mut flight, mut plant) = (Flight::new(), Plant::new());
+        let sticks = Sticks {
+            throttle: 20000,
+            ..Default::default()
+        };
+
+        flight.toggle_arming();
+        fly(&mut flight, &mut plant, &sticks, 3);
+        assert!(plant.altitude > 0.0);
+
+        flight.update_soc(10);
+        assert_eq!(flight.stage, SocStage::ForceLanding);
+
+        fly(&mut flight, &mut plant, &sticks, 20);
+
+        assert_eq!(plant.altitude, 0.0);
+        assert_eq!(flight.duties, Duties::default());
+    }
+}
@@ -0,0 +1,148 @@
+// Virtual gamepad
+//
+// Terminals only tell about key presses, not releases, so the keyboard can't hold a
+// stick the way a thumb does. Throttle stays wherever it was put, the other sticks are
+// deflected for a moment with each press and then spring back. A real controller
+// (with the "gamepad" feature) maps the same way the Xbox one does on the airframe.
+
+use std::time::Duration;
+
+use crossterm::event::{self, Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers};
+
+use copter_core::control::Sticks;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Command {
+    Arm,
+    // Same as a supply rail dip on the airframe
+    Undervoltage,
+    // Knock some SoC off, to get to the low battery stages quicker
+    DrainBattery,
+    Reset,
+    Quit,
+}
+
+pub trait Input {
+    // Called once per control loop tick
+    fn poll(&mut self, sticks: &mut Sticks) -> Option<Command>;
+}
+
+const STICK_FULL: i32 = i16::MAX as i32;
+const THROTTLE_STEP: i32 = STICK_FULL / 64;
+const DEFLECTION: i32 = STICK_FULL / 2;
+// In control loop ticks, a bit longer than the keyboard repeat delay
+const SPRING_BACK: u32 = 60;
+
+#[derive(Default)]
+pub struct Keyboard {
+    yaw_held: u32,
+    pitch_held: u32,
+}
+
+impl Keyboard {
+    pub const HELP: &'static str =
+        "w/s throttle, a/d yaw, up/down pitch, space arm, u undervoltage, b drain, r reset, q quit";
+
+    fn key(&mut self, key: KeyEvent, sticks: &mut Sticks) -> Option<Command> {
+        let ctrl = key.modifiers.contains(KeyModifiers::CONTROL);
+
+        match key.code {
+            KeyCode::Char('c') if ctrl => return Some(Command::Quit),
+            KeyCode::Char('q') | KeyCode::Esc => return Some(Command::Quit),
+            KeyCode::Char(' ') => return Some(Command::Arm),
+            KeyCode::Char('u') => return Some(Command::Undervoltage),
+            KeyCode::Char('b') => return Some(Command::DrainBattery),
+            KeyCode::Char('r') => return Some(Command::Reset),
+
+            KeyCode::Char('w') => {
+                sticks.throttle = (sticks.throttle + THROTTLE_STEP).min(STICK_FULL);
+            }
+            KeyCode::Char('s') => {
+                sticks.throttle = (sticks.throttle - THROTTLE_STEP).max(0);
+            }
+            KeyCode::Char('a') => (sticks.yaw, self.yaw_held) = (-DEFLECTION, SPRING_BACK),
+            KeyCode::Char('d') => (sticks.yaw, self.yaw_held) = (DEFLECTION, SPRING_BACK),
+            KeyCode::Up => (sticks.pitch, self.pitch_held) = (DEFLECTION, SPRING_BACK),
+            KeyCode::Down => (sticks.pitch, self.pitch_held) = (-DEFLECTION, SPRING_BACK),
+            _ => {}
+        }
+
+        None
+    }
+}
+
+fn spring_back(stick: &mut i32, held: &mut u32) {
+    match *held {
+        0 => *stick = 0,
+        _ => *held -= 1,
+    }
+}
+
+impl Input for Keyboard {
+    fn poll(&mut self, sticks: &mut Sticks) -> Option<Command> {
+        spring_back(&mut sticks.yaw, &mut self.yaw_held);
+        spring_back(&mut sticks.pitch, &mut self.pitch_held);
+
+        while event::poll(Duration::ZERO).unwrap_or(false) {
+            let Ok(Event::Key(key)) = event::read() else {
+                continue;
+            };
+
+            if key.kind == KeyEventKind::Release {
+                continue;
+            }
+
+            if let Some(command) = self.key(key, sticks) {
+                return Some(command);
+            }
+        }
+
+        None
+    }
+}
+
+#[cfg(feature = "gamepad")]
+pub struct Gamepad {
+    gilrs: gilrs::Gilrs,
+    // Keys still work for everything but the sticks
+    keyboard: Keyboard,
+}
+
+#[cfg(feature = "gamepad")]
+impl Gamepad {
+    pub fn new() -> Result<Self, gilrs::Error> {
+        Ok(Self {
+            gilrs: gilrs::Gilrs::new()?,
+            keyboard: Keyboard::default(),
+        })
+    }
+}
+
+#[cfg(feature = "gamepad")]
+impl Input for Gamepad {
+    fn poll(&mut self, sticks: &mut Sticks) -> Option<Command> {
+        use gilrs::{Axis, Button, EventType};
+
+        let mut command = None;
+
+        while let Some(e) = self.gilrs.next_event() {
+            if let EventType::ButtonPressed(Button::Start, _) = e.event {
+                command = Some(Command::Arm);
+            }
+        }
+
+        // Whichever one is connected first
+        if let Some((_, pad)) = self.gilrs.gamepads().next() {
+            let raw = |axis| (pad.value(axis) * STICK_FULL as f32) as i32;
+
+            *sticks = Sticks {
+                throttle: raw(Axis::LeftStickY),
+                yaw: raw(Axis::RightStickX),
+                pitch: raw(Axis::RightStickY),
+            };
+        }
+
+        let mut unused = *sticks;
+        command.or(self.keyboard.poll(&mut unused))
+    }
+}
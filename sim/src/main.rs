@@ -0,0 +1,263 @@
+// Desktop flight simulator
+//
+// Flies the copter-core control loop against a model of the airframe, in real time, so
+// PID, mixer and failsafe changes can be tried out before they go anywhere near a real
+// one. Sticks come from the keyboard, or from a real controller with the "gamepad"
+// feature. Battery drains with the rotor load and runs through the same low battery
+// stages as on the airframe.
+//
+//   cargo run -p sim -- [--pid P,I,D] [--expo N] [--gamepad]
+
+mod flight;
+mod input;
+mod plant;
+
+use std::{
+    env,
+    io::{self, Write},
+    process, thread,
+    time::{Duration, Instant},
+};
+
+use crossterm::{
+    cursor, execute, queue,
+    style::Print,
+    terminal::{self, ClearType},
+};
+
+use copter_core::control::Sticks;
+use flight::{Flight, LOOP_HZ, MAX_DUTY};
+use input::{Command, Input, Keyboard};
+use plant::Plant;
+
+const DT: f32 = 1.0 / LOOP_HZ as f32;
+// Screen is redrawn at 20Hz
+const REDRAW_TICKS: u32 = LOOP_HZ / 20;
+
+// A full battery lasts ~6 minutes in a hover
+const HOVER_LOAD: f32 = 2.0 * 0.6 * 0.6;
+const DRAIN_PER_SECOND: f32 = 100.0 / 360.0;
+const DRAIN_STEP: f32 = 5.0;
+
+struct Options {
+    pid: Option<(f32, f32, f32)>,
+    expo: Option<u8>,
+    gamepad: bool,
+}
+
+fn usage() -> ! {
+    eprintln!("usage: sim [--pid P,I,D] [--expo N] [--gamepad]");
+    process::exit(2);
+}
+
+fn parse_options() -> Options {
+    let mut options = Options {
+        pid: None,
+        expo: None,
+        gamepad: false,
+    };
+
+    let mut args = env::args().skip(1);
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--pid" => {
+                let gains: Vec<f32> = args
+                    .next()
+                    .unwrap_or_else(|| usage())
+                    .split(',')
+                    .map(|g| g.parse().unwrap_or_else(|_| usage()))
+                    .collect();
+
+                match gains[..] {
+                    [p, i, d] => options.pid = Some((p, i, d)),
+                    _ => usage(),
+                }
+            }
+            "--expo" => {
+                options.expo = args.next().and_then(|e| e.parse().ok());
+                if options.expo.is_none() {
+                    usage();
+                }
+            }
+            "--gamepad" => options.gamepad = true,
+            _ => usage(),
+        }
+    }
+
+    options
+}
+
+fn input(gamepad: bool) -> Box<dyn Input> {
+    #[cfg(feature = "gamepad")]
+    if gamepad {
+        match input::Gamepad::new() {
+            Ok(pad) => return Box::new(pad),
+            Err(e) => {
+                eprintln!("no gamepad - {}", e);
+                process::exit(1);
+            }
+        }
+    }
+
+    #[cfg(not(feature = "gamepad"))]
+    if gamepad {
+        eprintln!("built without the \"gamepad\" feature");
+        process::exit(1);
+    }
+
+    Box::new(Keyboard::default())
+}
+
+struct Sim {
+    flight: Flight,
+    plant: Plant,
+    sticks: Sticks,
+    soc: f32,
+}
+
+impl Sim {
+    fn new(options: &Options) -> Self {
+        let mut flight = Flight::new();
+
+        if let Some((p, i, d)) = options.pid {
+            flight.set_pid(p, i, d);
+        }
+
+        if let Some(expo) = options.expo {
+            flight.profile.expo = expo;
+        }
+
+        Self {
+            flight,
+            plant: Plant::new(),
+            sticks: Sticks::default(),
+            soc: 100.0,
+        }
+    }
+
+    fn reset(&mut self) {
+        self.flight.armed = false;
+        self.plant = Plant::new();
+        self.soc = 100.0;
+        self.flight.update_soc(100);
+    }
+
+    fn tick(&mut self) {
+        let duties = self.flight.tick(&self.sticks, self.plant.gyro());
+        self.plant.step(&duties, MAX_DUTY, DT);
+
+        if self.plant.crashed {
+            self.flight.armed = false;
+        }
+
+        let load = self.plant.rotor1.powi(2) + self.plant.rotor2.powi(2);
+        self.soc = (self.soc - load / HOVER_LOAD * DRAIN_PER_SECOND * DT).max(0.0);
+        self.flight.update_soc(self.soc.ceil() as u8);
+    }
+
+    fn command(&mut self, command: Command) {
+        match command {
+            Command::Arm => self.flight.toggle_arming(),
+            Command::Undervoltage => self.flight.undervoltage(),
+            Command::DrainBattery => self.soc = (self.soc - DRAIN_STEP).max(0.0),
+            Command::Reset => self.reset(),
+            Command::Quit => {}
+        }
+    }
+
+    fn draw(&self, out: &mut impl Write) -> io::Result<()> {
+        let f = &self.flight;
+        let p = &self.plant;
+        let d = &f.duties;
+
+        let status = match (p.crashed, f.armed, f.locked_out()) {
+            (true, _, _) => "CRASHED, r to reset",
+            (_, _, true) => "undervoltage lockout",
+            (_, true, _) => "armed",
+            _ => "disarmed",
+        };
+
+        let lines = [
+            status.to_string(),
+            format!(
+                "battery {:5.1}%  stage {:?}  throttle limit {:.0}",
+                self.soc,
+                f.stage,
+                f.throttle_limit()
+            ),
+            format!(
+                "sticks  throttle {:6}  yaw {:6}  pitch {:6}",
+                self.sticks.throttle, self.sticks.yaw, self.sticks.pitch
+            ),
+            format!(
+                "duties  rotor1 {:3}  rotor2 {:3}  tail {:3}{}",
+                d.rotor1,
+                d.rotor2,
+                d.tail,
+                if d.tail_n { " (n)" } else { "" }
+            ),
+            format!(
+                "height {:6.2} m  climb {:6.2} m/s  yaw rate {:7.1} dps",
+                p.altitude, p.climb, p.yaw_rate
+            ),
+            format!(
+                "heading {:5.1}  speed {:5.2} m/s  at {:6.1}, {:6.1} m",
+                p.heading, p.speed, p.x, p.y
+            ),
+            String::new(),
+            Keyboard::HELP.to_string(),
+        ];
+
+        queue!(out, cursor::MoveTo(0, 0), terminal::Clear(ClearType::All))?;
+
+        for (row, line) in lines.iter().enumerate() {
+            queue!(out, cursor::MoveTo(0, row as u16), Print(line))?;
+        }
+
+        out.flush()
+    }
+}
+
+fn run(sim: &mut Sim, input: &mut dyn Input) -> io::Result<()> {
+    let mut out = io::stdout();
+    let period = Duration::from_secs(1) / LOOP_HZ;
+    let mut next = Instant::now();
+    let mut ticks = 0u32;
+
+    loop {
+        match input.poll(&mut sim.sticks) {
+            Some(Command::Quit) => return Ok(()),
+            Some(command) => sim.command(command),
+            None => {}
+        }
+
+        sim.tick();
+
+        if ticks.is_multiple_of(REDRAW_TICKS) {
+            sim.draw(&mut out)?;
+        }
+
+        ticks = ticks.wrapping_add(1);
+        next += period;
+        thread::sleep(next.saturating_duration_since(Instant::now()));
+    }
+}
+
+fn main() -> io::Result<()> {
+    let options = parse_options();
+    let mut input = input(options.gamepad);
+    let mut sim = Sim::new(&options);
+
+    let mut out = io::stdout();
+
+    terminal::enable_raw_mode()?;
+    execute!(out, terminal::EnterAlternateScreen, cursor::Hide)?;
+
+    let r = run(&mut sim, input.as_mut());
+
+    execute!(out, cursor::Show, terminal::LeaveAlternateScreen)?;
+    terminal::disable_raw_mode()?;
+
+    r
+}
@@ -0,0 +1,115 @@
+// Coaxial helicopter plant
+//
+// Just enough physics to tell a good control change from a bad one: rotors spin up with
+// some lag, lift goes with the square of their speed and yaw with the difference between
+// them. Lower rotor is a bit stronger, like on most airframes out there, so the loop has
+// to trim that out. Tail rotor only pushes the nose along, nothing tips over. Speeds are
+// in 0..1 of the full duty.
+
+use copter_core::mixer::Duties;
+
+const G: f32 = 9.81;
+// Both rotors at this speed just about hold it in the air
+const HOVER_SPEED: f32 = 0.6;
+const ROTOR_LAG: f32 = 0.05;
+const ROTOR_MISMATCH: f32 = 1.03;
+
+// dps/s for a unit of rotor speed squared difference, and how fast the spin dies out
+const YAW_TORQUE: f32 = 400.0;
+const YAW_DAMPING: f32 = 3.0;
+
+// m/s^2 at the full tail, and the drag that keeps the forward speed in check
+const TAIL_THRUST: f32 = 3.0;
+const DRAG: f32 = 1.5;
+
+// Coming down faster than this breaks something
+const CRASH_SPEED: f32 = 3.0;
+// Gyro noise, dps
+const GYRO_NOISE: f32 = 2.0;
+
+#[derive(Default)]
+pub struct Plant {
+    pub rotor1: f32,
+    pub rotor2: f32,
+    // dps, positive as the gyro sees it
+    pub yaw_rate: f32,
+    // Degrees, wraps around
+    pub heading: f32,
+    // m and m/s
+    pub altitude: f32,
+    pub climb: f32,
+    pub speed: f32,
+    pub x: f32,
+    pub y: f32,
+    pub crashed: bool,
+    noise: u32,
+}
+
+impl Plant {
+    pub fn new() -> Self {
+        Self {
+            noise: 0x5107,
+            ..Default::default()
+        }
+    }
+
+    pub fn step(&mut self, duties: &Duties, max_duty: u16, dt: f32) {
+        let full = max_duty as f32;
+        let spin_up = |speed: f32, duty: u16| speed + (duty as f32 / full - speed) * dt / ROTOR_LAG;
+
+        self.rotor1 = spin_up(self.rotor1, duties.rotor1);
+        self.rotor2 = spin_up(self.rotor2, duties.rotor2);
+
+        let lift1 = self.rotor1 * self.rotor1;
+        let lift2 = self.rotor2 * self.rotor2 * ROTOR_MISMATCH;
+
+        // Bolted to the ground until there's enough to lift off
+        let airborne = self.altitude > 0.0;
+
+        let accel = G * ((lift1 + lift2) / (2.0 * HOVER_SPEED * HOVER_SPEED) - 1.0);
+        if airborne || accel > 0.0 {
+            self.climb += accel * dt;
+            self.altitude += self.climb * dt;
+
+            let yaw_accel = YAW_TORQUE * (lift1 - lift2) - YAW_DAMPING * self.yaw_rate;
+            self.yaw_rate += yaw_accel * dt;
+        }
+
+        if self.altitude <= 0.0 {
+            if self.climb < -CRASH_SPEED {
+                self.crashed = true;
+            }
+
+            self.altitude = 0.0;
+            self.climb = 0.0;
+            self.yaw_rate = 0.0;
+            self.speed = 0.0;
+            return;
+        }
+
+        // See mixer for how the tail bridge is driven
+        let tail = match duties.tail_n {
+            true => (max_duty - duties.tail) as f32 / full,
+            false => -(duties.tail as f32) / full,
+        };
+
+        self.speed += (TAIL_THRUST * tail - DRAG * self.speed) * dt;
+        self.heading = (self.heading + self.yaw_rate * dt).rem_euclid(360.0);
+
+        let (sin, cos) = self.heading.to_radians().sin_cos();
+        self.x += self.speed * cos * dt;
+        self.y += self.speed * sin * dt;
+    }
+
+    // What the firmware would see, noise included
+    pub fn gyro(&mut self) -> f32 {
+        // Plain LCG, no need for anything better
+        self.noise = self
+            .noise
+            .wrapping_mul(1_664_525)
+            .wrapping_add(1_013_904_223);
+        let unit = (self.noise >> 8) as f32 / (1 << 24) as f32 * 2.0 - 1.0;
+
+        self.yaw_rate + unit * GYRO_NOISE
+    }
+}
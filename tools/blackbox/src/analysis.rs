@@ -0,0 +1,245 @@
+// What the history says about the yaw loop
+//
+// The dump is decimated to 20Hz, so this is about the shape of things rather than the
+// detail: a step that's done within a sample or two is as good as it gets here. Only
+// the samples with the rotors up to speed count, the gyro isn't read below that.
+
+use copter_core::control::MIN_CONTROL_THROTTLE;
+use link::proto::{HistorySample, HISTORY_HZ};
+
+// Duty cycle units per sample, a flick of the yaw stick rather than a nudge of the trim
+const STEP_MIN: i32 = 30;
+// How long a step is followed, unless the next one comes first
+const STEP_WINDOW: usize = HISTORY_HZ as usize;
+// Tail end of the window where it should have settled
+const SETTLED: usize = 5;
+// Of the way to the new setpoint
+const RISE: f32 = 0.9;
+
+const SAMPLE_S: f32 = 1.0 / HISTORY_HZ as f32;
+
+// PID goes after the negated yaw stick, see copter_core::control
+fn setpoint(s: &HistorySample) -> i32 {
+    -(s.yaw as i32)
+}
+
+fn output(s: &HistorySample) -> i32 {
+    (s.rotor1 as i32 - s.rotor2 as i32) / 2
+}
+
+fn flying(s: &HistorySample) -> bool {
+    s.throttle as i32 > MIN_CONTROL_THROTTLE
+}
+
+fn rms(values: impl Iterator<Item = f32>) -> Option<f32> {
+    let (sum, n) = values.fold((0.0, 0), |(sum, n), v| (sum + v * v, n + 1));
+    (n > 0).then(|| (sum / n as f32).sqrt())
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Step {
+    // Sample it started at
+    pub at: usize,
+    // dps
+    pub size: i32,
+    // s, None if it never got there
+    pub rise: Option<f32>,
+    // % of the size
+    pub overshoot: f32,
+    // dps, None if the next step came too soon to tell
+    pub error: Option<f32>,
+}
+
+#[derive(Debug)]
+pub struct Metrics {
+    pub flying: usize,
+    pub steps: Vec<Step>,
+    // dps RMS, away from the steps
+    pub tracking_noise: Option<f32>,
+    // RMS of the change from one sample to the next, a twitchy D shows there
+    pub output_noise: Option<f32>,
+    // mV, zero ones are left out
+    pub rail_min: Option<u16>,
+}
+
+impl Metrics {
+    pub fn median_rise(&self) -> Option<f32> {
+        let mut rises: Vec<f32> = self.steps.iter().filter_map(|s| s.rise).collect();
+        rises.sort_by(f32::total_cmp);
+        rises.get(rises.len() / 2).copied()
+    }
+
+    pub fn mean_overshoot(&self) -> Option<f32> {
+        let n = self.steps.len();
+        (n > 0).then(|| self.steps.iter().map(|s| s.overshoot).sum::<f32>() / n as f32)
+    }
+
+    pub fn mean_error(&self) -> Option<f32> {
+        let errors: Vec<f32> = self.steps.iter().filter_map(|s| s.error).collect();
+        let n = errors.len();
+        (n > 0).then(|| errors.iter().map(|e| e.abs()).sum::<f32>() / n as f32)
+    }
+}
+
+// A stick moved over a couple of samples is still the one step
+fn step_end(samples: &[HistorySample], at: usize, sign: i32) -> usize {
+    let mut end = at;
+
+    while let Some(next) = samples.get(end + 1) {
+        let change = setpoint(next) - setpoint(&samples[end]);
+
+        if !flying(next) || change * sign < STEP_MIN / 2 {
+            break;
+        }
+
+        end += 1;
+    }
+
+    end
+}
+
+fn step(samples: &[HistorySample], at: usize, end: usize, window: &[HistorySample]) -> Step {
+    let before = samples[at - 1].yaw_rate as f32;
+    let target = setpoint(&samples[end]) as f32;
+    let size = target - before;
+
+    let progress = |s: &HistorySample| (s.yaw_rate as f32 - before) / size;
+
+    let rise = window
+        .iter()
+        .position(|s| progress(s) >= RISE)
+        .map(|n| (n + 1) as f32 * SAMPLE_S);
+
+    let overshoot = window
+        .iter()
+        .map(|s| (progress(s) - 1.0) * 100.0)
+        .fold(0.0, f32::max);
+
+    let error = (window.len() >= 2 * SETTLED).then(|| {
+        let settled = &window[window.len() - SETTLED..];
+        settled
+            .iter()
+            .map(|s| s.yaw_rate as f32 - target)
+            .sum::<f32>()
+            / SETTLED as f32
+    });
+
+    Step {
+        at,
+        size: size as i32,
+        rise,
+        overshoot,
+        error,
+    }
+}
+
+pub fn analyze(samples: &[HistorySample]) -> Metrics {
+    let mut steps = Vec::new();
+    // Samples that belong to a step, they don't count as noise
+    let mut stepping = vec![false; samples.len()];
+
+    let mut i = 1;
+    while i < samples.len() {
+        let change = setpoint(&samples[i]) - setpoint(&samples[i - 1]);
+
+        if !flying(&samples[i - 1]) || !flying(&samples[i]) || change.abs() < STEP_MIN {
+            i += 1;
+            continue;
+        }
+
+        let end = step_end(samples, i, change.signum());
+
+        // Up to the next step, or to the landing
+        let mut last = end;
+        while last + 1 < samples.len() && last + 1 - i < STEP_WINDOW {
+            let next = &samples[last + 1];
+            let change = setpoint(next) - setpoint(&samples[last]);
+
+            if !flying(next) || change.abs() >= STEP_MIN {
+                break;
+            }
+
+            last += 1;
+        }
+
+        steps.push(step(samples, i, end, &samples[i..=last]));
+        stepping[i..=last].fill(true);
+        i = last + 1;
+    }
+
+    let held = samples
+        .iter()
+        .zip(&stepping)
+        .filter(|(s, stepping)| flying(s) && !**stepping)
+        .map(|(s, _)| s.yaw_rate as f32 - setpoint(s) as f32);
+
+    let output_changes = samples
+        .windows(2)
+        .filter(|w| flying(&w[0]) && flying(&w[1]))
+        .map(|w| (output(&w[1]) - output(&w[0])) as f32);
+
+    Metrics {
+        flying: samples.iter().filter(|s| flying(s)).count(),
+        steps,
+        tracking_noise: rms(held),
+        output_noise: rms(output_changes),
+        rail_min: samples
+            .iter()
+            .map(|s| s.rail_voltage)
+            .filter(|&v| v > 0)
+            .min(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample(yaw: i16, yaw_rate: i16) -> HistorySample {
+        HistorySample {
+            yaw_rate,
+            throttle: 300,
+            yaw,
+            rotor1: 300 + yaw_rate,
+            rotor2: 300 - yaw_rate,
+            rail_voltage: 3700,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn step_response() {
+        // Hover, then a hard left: the gyro gets halfway there, overshoots by a fifth
+        // and settles short by a bit
+        let mut samples = vec![sample(0, 0); 10];
+        samples.extend([-50, -80, -120, -110].map(|rate| sample(100, rate)));
+        samples.extend([sample(100, -98); 11]);
+
+        let m = analyze(&samples);
+
+        assert_eq!(m.flying, 25);
+        assert_eq!(m.steps.len(), 1);
+
+        let step = m.steps[0];
+        assert_eq!((step.at, step.size), (10, -100));
+        assert!((step.rise.unwrap() - 0.15).abs() < 0.001);
+        assert!((step.overshoot - 20.0).abs() < 0.01);
+        assert_eq!(step.error, Some(2.0));
+
+        // Right on it while hovering
+        assert_eq!(m.tracking_noise, Some(0.0));
+        assert_eq!(m.rail_min, Some(3700));
+    }
+
+    #[test]
+    fn nothing_on_the_ground() {
+        let samples = [HistorySample::default(); 20];
+        let m = analyze(&samples);
+
+        assert_eq!(m.flying, 0);
+        assert!(m.steps.is_empty());
+        assert_eq!(m.tracking_noise, None);
+        assert_eq!(m.output_noise, None);
+        assert_eq!(m.rail_min, None);
+    }
+}
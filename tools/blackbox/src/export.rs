@@ -0,0 +1,187 @@
+// Files the dumps are kept in
+//
+// Flash only holds the latest dump, so each one is saved under its boot and device time:
+// downloading the same one again overwrites it, a new one goes next to the older ones.
+// Analysis works off the CSV just the same, see --from. Every CSV gets a gnuplot script
+// next to it, `gnuplot -p NAME.gp` has the loop, the rotors and the rail on one screen.
+
+use std::{
+    fs::{self, File},
+    io::{self, BufWriter, Write},
+    path::{Path, PathBuf},
+};
+
+use link::proto::{self, BlackboxEntry, History, HistorySample, HISTORY_HZ};
+
+const COLUMNS: [&str; 9] = [
+    "time_s",
+    "yaw_rate_dps",
+    "throttle",
+    "yaw",
+    "pitch",
+    "rotor1",
+    "rotor2",
+    "throttle_limit",
+    "rail_voltage_mv",
+];
+
+// Column numbers are the ones above, counting from 1
+const GNUPLOT: &str = r#"set datafile separator ","
+set key top left
+set grid
+set multiplot layout 3,1
+plot CSV using 1:2 with lines title "gyro, dps", CSV using 1:(-$4) with lines title "setpoint"
+plot CSV using 1:3 with lines title "throttle", CSV using 1:(($6-$7)/2) with lines title "PID output", CSV using 1:8 with lines title "throttle limit"
+plot CSV using 1:($9 > 0 ? $9 : NaN) with linespoints title "rail, mV"
+unset multiplot
+"#;
+
+pub fn history_name(history: &History) -> String {
+    format!("history-{}-{}", history.boot, history.device_time)
+}
+
+pub fn write_history(dir: &Path, history: &History) -> io::Result<PathBuf> {
+    let csv = dir.join(history_name(history)).with_extension("csv");
+    let mut file = BufWriter::new(File::create(&csv)?);
+
+    writeln!(
+        file,
+        "# {}, boot {}, device time {} s",
+        proto::history_trigger_name(history.trigger),
+        history.boot,
+        history.device_time
+    )?;
+    writeln!(file, "{}", COLUMNS.join(","))?;
+
+    for (n, s) in history.samples.iter().enumerate() {
+        writeln!(
+            file,
+            "{:.2},{},{},{},{},{},{},{},{}",
+            n as f32 / HISTORY_HZ as f32,
+            s.yaw_rate,
+            s.throttle,
+            s.yaw,
+            s.pitch,
+            s.rotor1,
+            s.rotor2,
+            s.throttle_limit,
+            s.rail_voltage
+        )?;
+    }
+
+    file.flush()?;
+
+    let name = csv.file_name().unwrap_or_default().to_string_lossy();
+    fs::write(
+        csv.with_extension("gp"),
+        format!("CSV = \"{}\"\n{}", name, GNUPLOT),
+    )?;
+
+    Ok(csv)
+}
+
+// Samples of a CSV written above
+pub fn read_history(path: &Path) -> io::Result<Vec<HistorySample>> {
+    let invalid = |line: usize| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("line {}, not a history dump", line + 1),
+        )
+    };
+
+    let text = fs::read_to_string(path)?;
+    let mut lines = text
+        .lines()
+        .enumerate()
+        .filter(|(_, l)| !l.starts_with('#'));
+
+    match lines.next() {
+        Some((_, header)) if header == COLUMNS.join(",") => {}
+        Some((n, _)) => return Err(invalid(n)),
+        None => return Err(invalid(0)),
+    }
+
+    lines
+        .map(|(n, line)| {
+            let v: Vec<i32> = line
+                .split(',')
+                .skip(1)
+                .map(|v| v.parse().map_err(|_| invalid(n)))
+                .collect::<io::Result<_>>()?;
+
+            match v[..] {
+                [yaw_rate, throttle, yaw, pitch, rotor1, rotor2, throttle_limit, rail_voltage] => {
+                    Ok(HistorySample {
+                        yaw_rate: yaw_rate as i16,
+                        throttle: throttle as i16,
+                        yaw: yaw as i16,
+                        pitch: pitch as i16,
+                        rotor1: rotor1 as i16,
+                        rotor2: rotor2 as i16,
+                        throttle_limit: throttle_limit as i16,
+                        rail_voltage: rail_voltage as u16,
+                    })
+                }
+                _ => Err(invalid(n)),
+            }
+        })
+        .collect()
+}
+
+// Incidents are only ever a few, all of them are written every time
+pub fn write_blackbox(dir: &Path, entries: &[BlackboxEntry]) -> io::Result<PathBuf> {
+    let path = dir.join("blackbox.csv");
+    let mut file = BufWriter::new(File::create(&path)?);
+
+    writeln!(file, "boot,timestamp_ms,level,incident,name")?;
+
+    for e in entries {
+        let level = match e.event.kind {
+            proto::EVENT_WARNING => "warning",
+            proto::EVENT_ERROR => "error",
+            _ => "",
+        };
+
+        writeln!(
+            file,
+            "{},{},{},{},{}",
+            e.boot,
+            e.event.timestamp,
+            level,
+            e.event.value,
+            proto::incident_name(e.event.value)
+        )?;
+    }
+
+    file.flush()?;
+    Ok(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn history_reads_back() {
+        let history = History {
+            trigger: 1,
+            boot: 7,
+            device_time: 1234,
+            samples: (0..5)
+                .map(|n| HistorySample {
+                    yaw_rate: -n,
+                    throttle: 300,
+                    rail_voltage: 3700,
+                    ..Default::default()
+                })
+                .collect(),
+        };
+
+        let dir = std::env::temp_dir();
+        let csv = write_history(&dir, &history).unwrap();
+
+        assert_eq!(csv, dir.join("history-7-1234.csv"));
+        assert!(csv.with_extension("gp").exists());
+        assert_eq!(read_history(&csv).unwrap(), history.samples);
+    }
+}
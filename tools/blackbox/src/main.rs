@@ -0,0 +1,188 @@
+// Black box reader
+//
+// Downloads what the board keeps in flash: the incidents of the black box (see
+// firmware/src/blackbox.rs) and the pre-crash history dump (firmware/src/history.rs).
+// Both are saved to CSV, and the history is looked into for how the yaw loop did: step
+// response to the stick, tracking noise and how twitchy the PID output was. Saved
+// dumps can be looked into again later, side by side, without a board.
+//
+//   cargo run -p blackbox -- [--name NAME] [--out DIR]
+//   cargo run -p blackbox -- --from FILE...
+
+mod analysis;
+mod export;
+
+use std::{
+    env, fs,
+    path::{Path, PathBuf},
+    process,
+};
+
+use link::{
+    proto::{self, HistorySample, HISTORY_HZ},
+    Device, Result,
+};
+
+enum Options {
+    Board { name: Option<String>, out: PathBuf },
+    Files(Vec<PathBuf>),
+}
+
+fn usage() -> ! {
+    eprintln!("usage: blackbox [--name NAME] [--out DIR]");
+    eprintln!("       blackbox --from FILE...");
+    process::exit(2);
+}
+
+fn parse_options() -> Options {
+    let mut args = env::args().skip(1).peekable();
+
+    if args.peek().map(String::as_str) == Some("--from") {
+        let files: Vec<PathBuf> = args.skip(1).map(PathBuf::from).collect();
+
+        return match files.is_empty() {
+            true => usage(),
+            false => Options::Files(files),
+        };
+    }
+
+    let mut name = None;
+    let mut out = PathBuf::from(".");
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--name" => name = Some(args.next().unwrap_or_else(|| usage())),
+            "--out" => out = args.next().unwrap_or_else(|| usage()).into(),
+            _ => usage(),
+        }
+    }
+
+    Options::Board { name, out }
+}
+
+fn or_dash(v: Option<f32>, precision: usize) -> String {
+    v.map_or("-".to_string(), |v| format!("{:.*}", precision, v))
+}
+
+fn report(samples: &[HistorySample]) {
+    let m = analysis::analyze(samples);
+
+    println!("  {} samples, {} of them flying", samples.len(), m.flying);
+
+    if m.flying == 0 {
+        return;
+    }
+
+    for step in &m.steps {
+        println!(
+            "  step at {:5.2} s: {:+5} dps, rise {} s, overshoot {:.0}%, settled {} dps off",
+            step.at as f32 / HISTORY_HZ as f32,
+            step.size,
+            or_dash(step.rise, 2),
+            step.overshoot,
+            or_dash(step.error, 1),
+        );
+    }
+
+    match m.steps.len() {
+        0 => println!("  no yaw steps to go by"),
+        n => println!(
+            "  {} steps: median rise {} s, mean overshoot {}%, mean settled error {} dps",
+            n,
+            or_dash(m.median_rise(), 2),
+            or_dash(m.mean_overshoot(), 0),
+            or_dash(m.mean_error(), 1),
+        ),
+    }
+
+    println!(
+        "  tracking noise {} dps RMS, output noise {} RMS",
+        or_dash(m.tracking_noise, 1),
+        or_dash(m.output_noise, 1),
+    );
+
+    if let Some(rail) = m.rail_min {
+        println!("  rail down to {} mV", rail);
+    }
+}
+
+async fn download(dev: &Device, out: &Path) -> Result<()> {
+    let entries = dev.blackbox().await?;
+    println!("{} incidents", entries.len());
+
+    for e in &entries {
+        println!(
+            "  boot {:4} at {:10} ms: {}",
+            e.boot,
+            e.event.timestamp,
+            proto::incident_name(e.event.value)
+        );
+    }
+
+    export::write_blackbox(out, &entries)?;
+
+    let Some(history) = dev.history().await? else {
+        println!("no history dump, nothing has crashed so far");
+        return Ok(());
+    };
+
+    let path = export::write_history(out, &history)?;
+
+    println!(
+        "{} dump, boot {}, saved to {}",
+        proto::history_trigger_name(history.trigger),
+        history.boot,
+        path.display()
+    );
+
+    report(&history.samples);
+    Ok(())
+}
+
+#[tokio::main]
+async fn main() {
+    let (name, out) = match parse_options() {
+        Options::Board { name, out } => (name, out),
+
+        Options::Files(files) => {
+            for file in files {
+                match export::read_history(&file) {
+                    Ok(samples) => {
+                        println!("{}", file.display());
+                        report(&samples);
+                    }
+                    Err(e) => {
+                        eprintln!("unable to read {} - {}", file.display(), e);
+                        process::exit(1);
+                    }
+                }
+            }
+
+            return;
+        }
+    };
+
+    if let Err(e) = fs::create_dir_all(&out) {
+        eprintln!("unable to create {} - {}", out.display(), e);
+        process::exit(1);
+    }
+
+    let dev = match Device::connect(name.as_deref()).await {
+        Ok(dev) => dev,
+        Err(e) => {
+            eprintln!("unable to connect - {}", e);
+            process::exit(1);
+        }
+    };
+
+    let r = download(&dev, &out).await;
+
+    if let Err(e) = dev.disconnect().await {
+        eprintln!("unable to disconnect - {}", e);
+    }
+
+    if let Err(e) = r {
+        eprintln!("download failed - {}", e);
+        process::exit(1);
+    }
+}
@@ -0,0 +1,231 @@
+// Web Bluetooth, as much of it as the dashboard needs
+//
+// web-sys only has it behind an unstable cfg, so the few calls we make are bound by
+// hand. Every service has to be asked for up front, the browser hides the rest.
+
+use std::{cell::RefCell, collections::HashMap};
+
+use js_sys::{Array, DataView, Object, Promise, Reflect, Uint8Array};
+use link::proto;
+use uuid::Uuid;
+use wasm_bindgen::{prelude::*, JsCast};
+use wasm_bindgen_futures::JsFuture;
+use web_sys::{Event, EventTarget};
+
+#[wasm_bindgen]
+extern "C" {
+    type Bluetooth;
+
+    #[wasm_bindgen(method, js_name = requestDevice)]
+    fn request_device(this: &Bluetooth, options: &Object) -> Promise;
+
+    #[wasm_bindgen(extends = EventTarget)]
+    type BluetoothDevice;
+
+    #[wasm_bindgen(method, getter)]
+    fn name(this: &BluetoothDevice) -> Option<String>;
+
+    #[wasm_bindgen(method, getter)]
+    fn gatt(this: &BluetoothDevice) -> GattServer;
+
+    type GattServer;
+
+    #[wasm_bindgen(method)]
+    fn connect(this: &GattServer) -> Promise;
+
+    #[wasm_bindgen(method)]
+    fn disconnect(this: &GattServer);
+
+    #[wasm_bindgen(method, js_name = getPrimaryServices)]
+    fn get_primary_services(this: &GattServer) -> Promise;
+
+    type GattService;
+
+    #[wasm_bindgen(method, js_name = getCharacteristics)]
+    fn get_characteristics(this: &GattService) -> Promise;
+
+    #[wasm_bindgen(extends = EventTarget)]
+    type Characteristic;
+
+    #[wasm_bindgen(method, getter)]
+    fn uuid(this: &Characteristic) -> String;
+
+    #[wasm_bindgen(method, getter)]
+    fn value(this: &Characteristic) -> Option<DataView>;
+
+    #[wasm_bindgen(method, js_name = readValue)]
+    fn read_value(this: &Characteristic) -> Promise;
+
+    #[wasm_bindgen(method, js_name = writeValueWithResponse)]
+    fn write_value(this: &Characteristic, value: &Uint8Array) -> Promise;
+
+    #[wasm_bindgen(method, js_name = startNotifications)]
+    fn start_notifications(this: &Characteristic) -> Promise;
+}
+
+const SERVICES: [Uuid; 7] = [
+    proto::POWER_SERVICE,
+    proto::REQUESTS_SERVICE,
+    proto::CONFIG_SERVICE,
+    proto::DIAGNOSTICS_SERVICE,
+    proto::LIGHTS_SERVICE,
+    proto::BATTERY_SERVICE,
+    proto::DEVICE_INFORMATION_SERVICE,
+];
+
+fn bytes(value: &DataView) -> Vec<u8> {
+    Uint8Array::new_with_byte_offset_and_length(
+        &value.buffer(),
+        value.byte_offset() as u32,
+        value.byte_length() as u32,
+    )
+    .to_vec()
+}
+
+fn set(object: &Object, key: &str, value: &JsValue) -> Result<(), JsValue> {
+    Reflect::set(object, &key.into(), value).map(|_| ())
+}
+
+// Only boards are offered to pick from, same as the tools look for them
+fn request_options() -> Result<Object, JsValue> {
+    let filter = Object::new();
+    let services: Array = SERVICES
+        .iter()
+        .map(|s| JsValue::from(s.to_string()))
+        .collect();
+
+    set(
+        &filter,
+        "services",
+        &Array::of1(&proto::POWER_SERVICE.to_string().into()),
+    )?;
+
+    let options = Object::new();
+    set(&options, "filters", &Array::of1(&filter))?;
+    set(&options, "optionalServices", &services)?;
+    Ok(options)
+}
+
+type Listener = Closure<dyn FnMut(Event)>;
+
+pub struct Board {
+    device: BluetoothDevice,
+    characteristics: HashMap<Uuid, Characteristic>,
+    // Dropped along with the board, nothing comes in once it's gone anyway
+    listeners: RefCell<Vec<Listener>>,
+}
+
+impl Board {
+    // Browser asks which one, has to come from a click
+    pub async fn request() -> Result<Self, JsValue> {
+        let navigator = web_sys::window().ok_or("no window")?.navigator();
+        let bluetooth = Reflect::get(&navigator, &"bluetooth".into())?;
+
+        if bluetooth.is_undefined() {
+            return Err("this browser has no Web Bluetooth, try Chrome or Edge".into());
+        }
+
+        let bluetooth: Bluetooth = bluetooth.unchecked_into();
+        let device: BluetoothDevice = JsFuture::from(bluetooth.request_device(&request_options()?))
+            .await?
+            .unchecked_into();
+
+        let server: GattServer = JsFuture::from(device.gatt().connect())
+            .await?
+            .unchecked_into();
+        let services: Array = JsFuture::from(server.get_primary_services())
+            .await?
+            .unchecked_into();
+
+        let mut characteristics = HashMap::new();
+
+        for service in services.iter() {
+            let service: GattService = service.unchecked_into();
+            let found: Array = JsFuture::from(service.get_characteristics())
+                .await?
+                .unchecked_into();
+
+            for c in found.iter() {
+                let c: Characteristic = c.unchecked_into();
+
+                if let Ok(uuid) = Uuid::parse_str(&c.uuid()) {
+                    characteristics.insert(uuid, c);
+                }
+            }
+        }
+
+        Ok(Self {
+            device,
+            characteristics,
+            listeners: RefCell::new(Vec::new()),
+        })
+    }
+
+    pub fn name(&self) -> String {
+        self.device
+            .name()
+            .unwrap_or_else(|| proto::DEVICE_NAME.into())
+    }
+
+    pub fn has(&self, uuid: Uuid) -> bool {
+        self.characteristics.contains_key(&uuid)
+    }
+
+    fn characteristic(&self, uuid: Uuid) -> Result<&Characteristic, JsValue> {
+        self.characteristics
+            .get(&uuid)
+            .ok_or_else(|| format!("no characteristic {}, older firmware?", uuid).into())
+    }
+
+    pub async fn read(&self, uuid: Uuid) -> Result<Vec<u8>, JsValue> {
+        let value: DataView = JsFuture::from(self.characteristic(uuid)?.read_value())
+            .await?
+            .unchecked_into();
+
+        Ok(bytes(&value))
+    }
+
+    pub async fn write(&self, uuid: Uuid, value: &[u8]) -> Result<(), JsValue> {
+        let value = Uint8Array::from(value);
+        JsFuture::from(self.characteristic(uuid)?.write_value(&value)).await?;
+        Ok(())
+    }
+
+    fn listen(&self, target: &EventTarget, event: &str, f: Listener) {
+        // Can't fail with a closure
+        let _ = target.add_event_listener_with_callback(event, f.as_ref().unchecked_ref());
+        self.listeners.borrow_mut().push(f);
+    }
+
+    pub async fn subscribe(
+        &self,
+        uuid: Uuid,
+        mut f: impl FnMut(&[u8]) + 'static,
+    ) -> Result<(), JsValue> {
+        let c = self.characteristic(uuid)?;
+
+        let changed = Closure::<dyn FnMut(Event)>::new(move |e: Event| {
+            let value = e
+                .target()
+                .map(|t| t.unchecked_into::<Characteristic>())
+                .and_then(|c| c.value());
+
+            if let Some(value) = value {
+                f(&bytes(&value));
+            }
+        });
+
+        self.listen(c, "characteristicvaluechanged", changed);
+        JsFuture::from(c.start_notifications()).await?;
+        Ok(())
+    }
+
+    pub fn on_disconnect(&self, mut f: impl FnMut() + 'static) {
+        let gone = Closure::<dyn FnMut(Event)>::new(move |_| f());
+        self.listen(&self.device, "gattserverdisconnected", gone);
+    }
+
+    pub fn disconnect(&self) {
+        self.device.gatt().disconnect();
+    }
+}
@@ -0,0 +1,138 @@
+// Strip charts on a canvas
+//
+// Same idea as the ones in tools/tune: newest sample on the right edge, and the scale
+// follows whatever is on screen but never gets narrower than the span it's given, so a
+// board sitting on the bench doesn't fill the whole chart with noise.
+
+use std::collections::VecDeque;
+
+use web_sys::CanvasRenderingContext2d;
+
+pub struct Series {
+    pub color: &'static str,
+    samples: VecDeque<f64>,
+}
+
+impl Series {
+    pub fn new(color: &'static str) -> Self {
+        Self {
+            color,
+            samples: VecDeque::new(),
+        }
+    }
+
+    pub fn push(&mut self, v: f64, len: usize) {
+        if self.samples.len() == len {
+            self.samples.pop_front();
+        }
+
+        self.samples.push_back(v);
+    }
+}
+
+pub struct Chart {
+    pub series: Vec<Series>,
+    // Samples across
+    pub len: usize,
+    // Scale doesn't get narrower than that
+    pub min_span: f64,
+}
+
+// Low and high end of what's on screen
+fn range(series: &[Series], min_span: f64) -> (f64, f64) {
+    let (low, high) = series
+        .iter()
+        .flat_map(|s| s.samples.iter())
+        .fold((f64::MAX, f64::MIN), |(low, high), &v| {
+            (low.min(v), high.max(v))
+        });
+
+    if low > high {
+        return (0.0, min_span);
+    }
+
+    let middle = (low + high) / 2.0;
+    let half = ((high - low) / 2.0).max(min_span / 2.0);
+    (middle - half, middle + half)
+}
+
+impl Chart {
+    pub fn new(series: Vec<Series>, len: usize, min_span: f64) -> Self {
+        Self {
+            series,
+            len,
+            min_span,
+        }
+    }
+
+    pub fn push(&mut self, values: &[f64]) {
+        for (series, &v) in self.series.iter_mut().zip(values) {
+            series.push(v, self.len);
+        }
+    }
+
+    // Canvas coordinates, y grows down
+    fn points(&self, series: &Series, width: f64, height: f64) -> Vec<(f64, f64)> {
+        let (low, high) = range(&self.series, self.min_span);
+        let step = width / (self.len - 1).max(1) as f64;
+        let first = self.len - series.samples.len();
+
+        series
+            .samples
+            .iter()
+            .enumerate()
+            .map(|(n, v)| {
+                let x = (first + n) as f64 * step;
+                let y = height - (v - low) / (high - low) * height;
+                (x, y)
+            })
+            .collect()
+    }
+
+    pub fn draw(&self, ctx: &CanvasRenderingContext2d, width: f64, height: f64) {
+        ctx.clear_rect(0.0, 0.0, width, height);
+
+        for series in &self.series {
+            ctx.begin_path();
+            ctx.set_stroke_style_str(series.color);
+
+            for (n, (x, y)) in self.points(series, width, height).into_iter().enumerate() {
+                match n {
+                    0 => ctx.move_to(x, y),
+                    _ => ctx.line_to(x, y),
+                }
+            }
+
+            ctx.stroke();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fills_the_chart_from_the_right() {
+        let mut chart = Chart::new(vec![Series::new("red")], 5, 1.0);
+
+        chart.push(&[10.0]);
+        chart.push(&[14.0]);
+        chart.push(&[12.0]);
+
+        let points = chart.points(&chart.series[0], 8.0, 4.0);
+        assert_eq!(points, [(4.0, 4.0), (6.0, 0.0), (8.0, 2.0)]);
+
+        // Oldest ones go away
+        for v in 0..10 {
+            chart.push(&[v as f64]);
+        }
+        assert_eq!(chart.series[0].samples.len(), 5);
+        assert_eq!(chart.series[0].samples.front(), Some(&5.0));
+
+        // Flat line sits in the middle of the minimum span
+        let mut chart = Chart::new(vec![Series::new("red")], 5, 1.0);
+        chart.push(&[3.0]);
+        assert_eq!(range(&chart.series, 1.0), (2.5, 3.5));
+    }
+}
@@ -0,0 +1,398 @@
+// Dashboard in the browser
+//
+// Roughly what ground-station and the config tools do, without installing anything:
+// battery, link state and faults, live charts of the battery and the attitude, and the
+// pilot profile and light / charger settings to change. Talks to the board over Web
+// Bluetooth, so Chrome or Edge, and only from a secure page - localhost counts.
+//
+//   cd tools/dashboard
+//   wasm-pack build --target web
+//   python3 -m http.server
+//
+// then open http://localhost:8000.
+
+mod bluetooth;
+mod chart;
+
+use std::{cell::RefCell, rc::Rc};
+
+use bluetooth::Board;
+use chart::{Chart, Series};
+use link::proto::{self, Attitude, ChargerState, PeriodicUpdate, PilotProfile};
+use wasm_bindgen::{prelude::*, JsCast};
+use wasm_bindgen_futures::spawn_local;
+use web_sys::{
+    CanvasRenderingContext2d, HtmlCanvasElement, HtmlElement, HtmlInputElement, HtmlSelectElement,
+};
+
+// Periodic updates come once a second, attitude at 10Hz
+const POWER_SAMPLES: usize = 120;
+const ATTITUDE_SAMPLES: usize = 300;
+
+// Floors for the chart scales - V, A, °
+const VOLTAGE_SPAN: f64 = 0.2;
+const CURRENT_SPAN: f64 = 0.1;
+const ATTITUDE_SPAN: f64 = 10.0;
+
+// See ShutdownReason in firmware/src/types.rs
+fn shutdown_reason(reason: u8) -> &'static str {
+    match reason {
+        1 => "battery depleted",
+        2 => "switched off",
+        3 => "asked to",
+        _ => "unknown reason",
+    }
+}
+
+fn element<T: JsCast>(id: &str) -> T {
+    web_sys::window()
+        .and_then(|w| w.document())
+        .and_then(|d| d.get_element_by_id(id))
+        .unwrap_or_else(|| panic!("index.html has no #{}", id))
+        .unchecked_into()
+}
+
+fn show(id: &str, text: &str) {
+    element::<HtmlElement>(id).set_text_content(Some(text));
+}
+
+fn report(e: JsValue) {
+    let text = e.as_string().unwrap_or_else(|| format!("{:?}", e));
+    show("status", &text);
+}
+
+fn on(id: &str, event: &str, f: impl FnMut() + 'static) {
+    let f = Closure::<dyn FnMut()>::new(f);
+    let _ = element::<HtmlElement>(id)
+        .add_event_listener_with_callback(event, f.as_ref().unchecked_ref());
+    // Page lives as long as the dashboard does
+    f.forget();
+}
+
+fn draw(id: &str, chart: &Chart) {
+    let canvas: HtmlCanvasElement = element(id);
+    let ctx = canvas
+        .get_context("2d")
+        .ok()
+        .flatten()
+        .map(|c| c.unchecked_into::<CanvasRenderingContext2d>());
+
+    if let Some(ctx) = ctx {
+        chart.draw(&ctx, canvas.width() as f64, canvas.height() as f64);
+    }
+}
+
+#[derive(Default)]
+struct App {
+    board: RefCell<Option<Rc<Board>>>,
+}
+
+impl App {
+    fn board(&self) -> Option<Rc<Board>> {
+        self.board.borrow().clone()
+    }
+
+    // Settings changes from the page, one write each
+    fn write(self: &Rc<Self>, uuid: uuid::Uuid, value: Vec<u8>) {
+        let Some(board) = self.board() else {
+            return;
+        };
+
+        spawn_local(async move {
+            match board.write(uuid, &value).await {
+                Ok(()) => show("status", "saved"),
+                Err(e) => report(e),
+            }
+        });
+    }
+}
+
+fn pilot_from_page(mut pilot: PilotProfile) -> Option<PilotProfile> {
+    let value = |id| element::<HtmlInputElement>(id).value().parse::<i16>().ok();
+
+    pilot.yaw_trim = i8::try_from(value("yaw-trim")?).ok()?;
+    pilot.pitch_trim = i8::try_from(value("pitch-trim")?).ok()?;
+    pilot.yaw_rate = u8::try_from(value("yaw-rate")?).ok()?;
+    pilot.pitch_rate = u8::try_from(value("pitch-rate")?).ok()?;
+    pilot.expo = u8::try_from(value("expo")?).ok()?;
+    Some(pilot)
+}
+
+fn pilot_to_page(pilot: &PilotProfile) {
+    let set = |id, v: i16| element::<HtmlInputElement>(id).set_value(&v.to_string());
+
+    set("yaw-trim", pilot.yaw_trim.into());
+    set("pitch-trim", pilot.pitch_trim.into());
+    set("yaw-rate", pilot.yaw_rate.into());
+    set("pitch-rate", pilot.pitch_rate.into());
+    set("expo", pilot.expo.into());
+}
+
+async fn read_byte(board: &Board, uuid: uuid::Uuid) -> Result<Option<u8>, JsValue> {
+    Ok(board.read(uuid).await?.first().copied())
+}
+
+// Whatever the board remembers going wrong, oldest first
+async fn show_faults(board: &Board) -> Result<(), JsValue> {
+    let entries = proto::parse_blackbox(&board.read(proto::BLACKBOX).await?);
+    let faults: Vec<String> = entries
+        .iter()
+        .map(|e| {
+            let level = match e.event.kind == proto::EVENT_ERROR {
+                true => "error",
+                false => "warning",
+            };
+
+            format!(
+                "boot {}: {} - {}",
+                e.boot,
+                level,
+                proto::incident_name(e.event.value)
+            )
+        })
+        .collect();
+
+    match faults.is_empty() {
+        true => show("faults", "none"),
+        false => show("faults", &faults.join("\n")),
+    }
+
+    Ok(())
+}
+
+async fn show_settings(board: &Board) -> Result<(), JsValue> {
+    if let Some(pilot) = PilotProfile::parse(&board.read(proto::PILOT_PROFILE).await?) {
+        pilot_to_page(&pilot);
+    }
+
+    if let Some(light) = read_byte(board, proto::FLIGHT_LIGHT).await? {
+        element::<HtmlSelectElement>("flight-light").set_value(&light.to_string());
+    }
+
+    if let Some(mode) = read_byte(board, proto::CHARGE_MODE).await? {
+        let select = element::<HtmlSelectElement>("charge-mode");
+        select.set_disabled(mode == proto::CHARGE_MODE_NONE);
+        select.set_value(&mode.to_string());
+    }
+
+    if board.has(proto::AUX_LIGHTS) {
+        let on = read_byte(board, proto::AUX_LIGHTS).await? == Some(1);
+        element::<HtmlInputElement>("aux-lights").set_checked(on);
+    }
+
+    Ok(())
+}
+
+async fn subscribe(board: &Board) -> Result<(), JsValue> {
+    let voltage_chart = Rc::new(RefCell::new(Chart::new(
+        vec![Series::new("#2a6")],
+        POWER_SAMPLES,
+        VOLTAGE_SPAN,
+    )));
+    let current_chart = Rc::new(RefCell::new(Chart::new(
+        vec![Series::new("#c52")],
+        POWER_SAMPLES,
+        CURRENT_SPAN,
+    )));
+    let attitude = Rc::new(RefCell::new(Chart::new(
+        vec![Series::new("#36c"), Series::new("#c3a")],
+        ATTITUDE_SAMPLES,
+        ATTITUDE_SPAN,
+    )));
+
+    board
+        .subscribe(proto::BATTERY_LEVEL, |b| {
+            if let Some(level) = b.first() {
+                show("battery", &format!("{}%", level));
+            }
+        })
+        .await?;
+
+    board
+        .subscribe(proto::PERIODIC_UPDATE, move |b| {
+            let Some(u) = PeriodicUpdate::parse(b) else {
+                return;
+            };
+
+            let voltage = u.voltage as f64 / 1000.0;
+            let current = u.current as f64 / 1000.0;
+
+            show("voltage", &format!("{:.2} V", voltage));
+            show("current", &format!("{:.2} A", current));
+            show(
+                "temperature",
+                &format!("{:.1} °C", u.temperature as f64 / 10.0 - 273.15),
+            );
+            show(
+                "flight-time",
+                &match u.flight_time {
+                    proto::FLIGHT_TIME_UNKNOWN => "-".to_string(),
+                    minutes => format!("{} min", minutes),
+                },
+            );
+
+            let mut chart = voltage_chart.borrow_mut();
+            chart.push(&[voltage]);
+            draw("voltage-chart", &chart);
+
+            let mut chart = current_chart.borrow_mut();
+            chart.push(&[current]);
+            draw("current-chart", &chart);
+        })
+        .await?;
+
+    board
+        .subscribe(proto::CHARGER_STATE, |b| {
+            if let Some(state) = ChargerState::parse(b) {
+                show(
+                    "charger",
+                    match (state.failure, state.charging) {
+                        (true, _) => "failed",
+                        (false, true) => "charging",
+                        (false, false) => "not charging",
+                    },
+                );
+            }
+        })
+        .await?;
+
+    if board.has(proto::ATTITUDE) {
+        board
+            .subscribe(proto::ATTITUDE, move |b| {
+                let Some(a) = Attitude::parse(b) else {
+                    return;
+                };
+
+                let roll = a.roll as f64 / 10.0;
+                let pitch = a.pitch as f64 / 10.0;
+
+                show(
+                    "attitude",
+                    &format!("roll {:.1}°, pitch {:.1}°", roll, pitch),
+                );
+
+                let mut attitude = attitude.borrow_mut();
+                attitude.push(&[roll, pitch]);
+                draw("attitude-chart", &attitude);
+            })
+            .await?;
+    }
+
+    board
+        .subscribe(proto::SHUTDOWN, |b| {
+            if let Some(&reason) = b.first() {
+                show(
+                    "link",
+                    &format!("board shut down, {}", shutdown_reason(reason)),
+                );
+            }
+        })
+        .await?;
+
+    Ok(())
+}
+
+async fn connect(app: Rc<App>) -> Result<(), JsValue> {
+    show("status", "looking for boards");
+
+    let board = Rc::new(Board::request().await?);
+    show("link", &format!("connected to {}", board.name()));
+
+    let gone = Rc::downgrade(&app);
+    board.on_disconnect(move || {
+        if let Some(app) = gone.upgrade() {
+            app.board.replace(None);
+        }
+        show("link", "disconnected");
+    });
+
+    let firmware = board.read(proto::FIRMWARE_REVISION).await?;
+    show("firmware", &String::from_utf8_lossy(&firmware));
+
+    if board.has(proto::HARDWARE_REVISION) {
+        let hardware = board.read(proto::HARDWARE_REVISION).await?;
+        show("hardware", &String::from_utf8_lossy(&hardware));
+    }
+
+    if let Some(level) = read_byte(&board, proto::BATTERY_LEVEL).await? {
+        show("battery", &format!("{}%", level));
+    }
+
+    show_faults(&board).await?;
+    show_settings(&board).await?;
+    subscribe(&board).await?;
+
+    app.board.replace(Some(board));
+    show("status", "");
+    Ok(())
+}
+
+#[wasm_bindgen(start)]
+pub fn start() {
+    let app = Rc::new(App::default());
+
+    let a = app.clone();
+    on("connect", "click", move || {
+        let app = a.clone();
+        spawn_local(async move {
+            if let Err(e) = connect(app).await {
+                report(e);
+            }
+        });
+    });
+
+    let a = app.clone();
+    on("disconnect", "click", move || {
+        if let Some(board) = a.board() {
+            board.disconnect();
+        }
+    });
+
+    // Read back first, the page doesn't have the button assignments
+    let a = app.clone();
+    on("save-pilot", "click", move || {
+        let Some(board) = a.board() else {
+            return;
+        };
+
+        spawn_local(async move {
+            let r = async {
+                let pilot = PilotProfile::parse(&board.read(proto::PILOT_PROFILE).await?)
+                    .ok_or("board sent a bad pilot profile")?;
+                let pilot = pilot_from_page(pilot).ok_or("trims go -128..127, rates 0..255")?;
+
+                board.write(proto::PILOT_PROFILE, &pilot.to_bytes()).await
+            };
+
+            match r.await {
+                Ok(()) => show("status", "saved"),
+                Err(e) => report(e),
+            }
+        });
+    });
+
+    let a = app.clone();
+    on("flight-light", "change", move || {
+        if let Ok(light) = element::<HtmlSelectElement>("flight-light")
+            .value()
+            .parse::<u8>()
+        {
+            a.write(proto::FLIGHT_LIGHT, vec![light]);
+        }
+    });
+
+    let a = app.clone();
+    on("charge-mode", "change", move || {
+        if let Ok(mode) = element::<HtmlSelectElement>("charge-mode")
+            .value()
+            .parse::<u8>()
+        {
+            a.write(proto::CHARGE_MODE, vec![mode]);
+        }
+    });
+
+    on("aux-lights", "change", move || {
+        let on = element::<HtmlInputElement>("aux-lights").checked();
+        app.write(proto::AUX_LIGHTS, vec![on as u8]);
+    });
+}
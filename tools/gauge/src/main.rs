@@ -0,0 +1,241 @@
+// Fuel gauge calibration
+//
+// Takes the cell through the bq27427 learning cycle (see firmware/src/learning.rs): says
+// what to do at every phase, follows the gauge's update status and where QMAX is going,
+// and once it's over prints what the gauge learned the way firmware/src/types.rs has it.
+// The board keeps the result in its settings either way, the snippet is for making it
+// the default.
+//
+//   cargo run -p gauge -- [--name NAME] [--out FILE]
+//
+// A whole cycle takes the better part of a day, most of it resting. The board has to stay
+// powered all along, a dropped link is picked up again. Running it while a cycle is on
+// already just follows that one.
+
+mod qmax;
+mod snippet;
+
+use std::{
+    env, fs,
+    io::{self, Write},
+    path::{Path, PathBuf},
+    process,
+    time::Duration,
+};
+
+use futures::StreamExt;
+use link::{
+    proto::{self, GaugeLearned, LearningPhase, LearningReport},
+    Device, Result,
+};
+use qmax::{Qmax, Verdict};
+use tokio::time;
+
+const CONNECTION_CHECK: Duration = Duration::from_secs(30);
+const RECONNECT_TIMEOUT: Duration = Duration::from_secs(120);
+
+struct Options {
+    name: Option<String>,
+    out: PathBuf,
+}
+
+fn usage() -> ! {
+    eprintln!("usage: gauge [--name NAME] [--out FILE]");
+    process::exit(2);
+}
+
+fn parse_options() -> Options {
+    let mut name = None;
+    let mut out = PathBuf::from("gauge-learned.rs");
+    let mut args = env::args().skip(1);
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--name" => name = Some(args.next().unwrap_or_else(|| usage())),
+            "--out" => out = args.next().unwrap_or_else(|| usage()).into(),
+            _ => usage(),
+        }
+    }
+
+    Options { name, out }
+}
+
+fn instructions(phase: LearningPhase) -> &'static str {
+    match phase {
+        LearningPhase::Idle => "cycle isn't running",
+        LearningPhase::ChargeToFull => {
+            "charge: plug the charger in and leave it on until charging is done"
+        }
+        LearningPhase::RestCharged => {
+            "rest: unplug the charger and leave the board alone for 2 hours"
+        }
+        LearningPhase::Discharge => {
+            "discharge: hover, or put a load of about C/5 on it, down to the terminate voltage"
+        }
+        LearningPhase::RestDischarged => "rest: leave the board alone for 5 hours, no charging",
+        LearningPhase::Complete => "done",
+    }
+}
+
+fn update_status_name(status: u8) -> &'static str {
+    match status {
+        proto::UPDATE_STATUS_LEARNING => "learning",
+        proto::UPDATE_STATUS_QMAX_UPDATED => "QMAX updated",
+        proto::UPDATE_STATUS_RA_UPDATED => "Ra updated",
+        _ => "not learning",
+    }
+}
+
+fn wait_for_enter(prompt: &str) -> Result<()> {
+    print!("{}, Enter to go on ", prompt);
+    io::stdout().flush()?;
+    io::stdin().read_line(&mut String::new())?;
+    Ok(())
+}
+
+async fn phase(dev: &Device) -> Result<Option<LearningPhase>> {
+    let value = dev.read(proto::LEARNING_PHASE).await?;
+    Ok(value.first().copied().and_then(LearningPhase::from_u8))
+}
+
+async fn learned(dev: &Device) -> Result<GaugeLearned> {
+    let value = dev.read(proto::GAUGE_LEARNED).await?;
+    GaugeLearned::parse(&value)
+        .ok_or_else(|| format!("unexpected learned data {:02x?}", value).into())
+}
+
+// Until the cycle is over, or the link drops
+async fn follow(dev: &Device, qmax: &mut Qmax, shown: &mut Option<LearningPhase>) -> Result<bool> {
+    let mut reports = Box::pin(dev.notifications(proto::LEARNING_REPORT).await?);
+    let mut check = time::interval(CONNECTION_CHECK);
+
+    loop {
+        let value = tokio::select! {
+            value = reports.next() => match value {
+                Some(value) => value,
+                None => return Ok(false),
+            },
+
+            _ = check.tick() => {
+                if !dev.is_connected().await? {
+                    return Ok(false);
+                }
+
+                continue;
+            }
+        };
+
+        let Some(report) = LearningReport::parse(&value) else {
+            continue;
+        };
+
+        if *shown != Some(report.phase) {
+            println!("\n{}", instructions(report.phase));
+            *shown = Some(report.phase);
+        }
+
+        if let Some(previous) = qmax.add(report.qmax) {
+            println!(
+                "\nQMAX {} -> {} ({:+.1}%)",
+                previous,
+                report.qmax,
+                qmax.change()
+            );
+        }
+
+        match report.phase {
+            LearningPhase::Complete => return Ok(true),
+            LearningPhase::Idle => return Err("cycle was stopped".into()),
+            _ => {}
+        }
+
+        print!(
+            "\r{}, QMAX {}, {} mV, {} mA, rested {} min    ",
+            update_status_name(report.update_status),
+            report.qmax,
+            report.voltage,
+            report.current,
+            report.rested
+        );
+        io::stdout().flush()?;
+    }
+}
+
+async fn learn(dev: &mut Device, out: &Path) -> Result<()> {
+    let before = learned(dev).await?;
+    println!("gauge runs with QMAX {}", before.qmax);
+
+    match phase(dev).await? {
+        Some(LearningPhase::Idle | LearningPhase::Complete) | None => {
+            wait_for_enter("learning cycle starts with a charge, plug the charger in")?;
+            dev.write(proto::START_LEARNING, &[1]).await?;
+        }
+        Some(phase) => println!("learning cycle is on already, at {:?}", phase),
+    }
+
+    let mut qmax = Qmax::new(before.qmax);
+    let mut shown = None;
+
+    while !follow(dev, &mut qmax, &mut shown).await? {
+        println!("\nlink dropped, reconnecting");
+        dev.reconnect_after_reboot(RECONNECT_TIMEOUT).await?;
+
+        // Cycle isn't kept across a reboot
+        if matches!(phase(dev).await?, Some(LearningPhase::Idle) | None) {
+            return Err("board rebooted, the cycle has to be started over".into());
+        }
+    }
+
+    let after = learned(dev).await?;
+
+    match qmax.verdict() {
+        Verdict::Unchanged => {
+            println!("QMAX stayed where it was, the discharge may not have gone deep enough")
+        }
+        Verdict::Settled(change) => println!("QMAX settled, {:+.1}% over the cycle", change),
+        Verdict::Moved(change) => println!(
+            "QMAX moved by {:+.1}% over the cycle, another one shows whether it sticks",
+            change
+        ),
+    }
+
+    let profile = dev.read(proto::BATTERY_PROFILE).await?;
+    let cell = match proto::BatteryProfile::parse(&profile) {
+        Some(profile) => format!("a {} mAh cell", profile.capacity),
+        None => "this cell".to_string(),
+    };
+
+    let snippet = snippet::rust(&after, &cell);
+    fs::write(out, &snippet)?;
+
+    println!("\n{}", snippet);
+    println!(
+        "saved to {}, it goes over the one in firmware/src/types.rs",
+        out.display()
+    );
+    Ok(())
+}
+
+#[tokio::main]
+async fn main() {
+    let options = parse_options();
+
+    let mut dev = match Device::connect(options.name.as_deref()).await {
+        Ok(dev) => dev,
+        Err(e) => {
+            eprintln!("unable to connect - {}", e);
+            process::exit(1);
+        }
+    };
+
+    let r = learn(&mut dev, &options.out).await;
+
+    if let Err(e) = dev.disconnect().await {
+        eprintln!("unable to disconnect - {}", e);
+    }
+
+    if let Err(e) = r {
+        eprintln!("calibration failed - {}", e);
+        process::exit(1);
+    }
+}
@@ -0,0 +1,84 @@
+// Where QMAX is going
+//
+// Gauge only moves it at a couple of points in the cycle, once the cell has rested. A
+// cycle that lands close to where the previous one did means it has settled, a big jump
+// is worth another cycle to see whether it sticks.
+
+// % of the starting value
+const SETTLED: f32 = 2.0;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Verdict {
+    Unchanged,
+    Settled(f32),
+    Moved(f32),
+}
+
+pub struct Qmax {
+    initial: u16,
+    values: Vec<u16>,
+}
+
+impl Qmax {
+    pub fn new(initial: u16) -> Self {
+        Self {
+            initial,
+            values: vec![initial],
+        }
+    }
+
+    pub fn latest(&self) -> u16 {
+        *self.values.last().unwrap_or(&self.initial)
+    }
+
+    // Returns the previous value if this one is new
+    pub fn add(&mut self, qmax: u16) -> Option<u16> {
+        let previous = self.latest();
+
+        (qmax != previous).then(|| {
+            self.values.push(qmax);
+            previous
+        })
+    }
+
+    // %, since the start of the cycle
+    pub fn change(&self) -> f32 {
+        (self.latest() as f32 - self.initial as f32) / self.initial.max(1) as f32 * 100.0
+    }
+
+    pub fn updates(&self) -> usize {
+        self.values.len() - 1
+    }
+
+    pub fn verdict(&self) -> Verdict {
+        let change = self.change();
+
+        match self.updates() {
+            0 => Verdict::Unchanged,
+            _ if change.abs() <= SETTLED => Verdict::Settled(change),
+            _ => Verdict::Moved(change),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn follows_the_updates() {
+        let mut qmax = Qmax::new(10000);
+
+        assert_eq!(qmax.add(10000), None);
+        assert_eq!(qmax.verdict(), Verdict::Unchanged);
+
+        assert_eq!(qmax.add(10100), Some(10000));
+        assert_eq!(qmax.add(10100), None);
+        assert_eq!(qmax.updates(), 1);
+        assert_eq!(qmax.verdict(), Verdict::Settled(1.0));
+
+        assert_eq!(qmax.add(9000), Some(10100));
+        assert_eq!(qmax.latest(), 9000);
+        assert_eq!(qmax.verdict(), Verdict::Moved(-10.0));
+    }
+}
@@ -0,0 +1,181 @@
+// Ground station
+//
+// Connects to a board, subscribes to everything it reports and logs it to CSV, one file
+// per characteristic in a new directory per run. Every row starts with the host time in
+// seconds since the epoch, so runs can be lined up with a video or the sim afterwards.
+// Good for tuning and for battery analysis, without an app in the way.
+//
+//   cargo run -p ground-station -- [--name NAME] [--out DIR]
+//
+// Runs until Ctrl-C, or until the board goes away.
+
+mod telemetry;
+
+use std::{
+    env,
+    fs::{self, File},
+    io::{self, BufWriter, Write},
+    path::{Path, PathBuf},
+    process,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use futures::StreamExt;
+use link::{Device, Result, CONNECTION_CHECK};
+use tokio::time;
+
+use telemetry::{Telemetry, TELEMETRY};
+
+struct Options {
+    name: Option<String>,
+    out: PathBuf,
+}
+
+fn usage() -> ! {
+    eprintln!("usage: ground-station [--name NAME] [--out DIR]");
+    process::exit(2);
+}
+
+fn parse_options() -> Options {
+    let mut options = Options {
+        name: None,
+        out: PathBuf::from("."),
+    };
+
+    let mut args = env::args().skip(1);
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--name" => options.name = Some(args.next().unwrap_or_else(|| usage())),
+            "--out" => options.out = args.next().unwrap_or_else(|| usage()).into(),
+            _ => usage(),
+        }
+    }
+
+    options
+}
+
+fn now() -> f64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs_f64()
+}
+
+// One CSV, flushed every row, so whatever made it is there if this gets killed
+struct Log {
+    telemetry: &'static Telemetry,
+    file: BufWriter<File>,
+    rows: usize,
+}
+
+impl Log {
+    fn create(dir: &Path, telemetry: &'static Telemetry) -> io::Result<Self> {
+        let mut file = BufWriter::new(File::create(
+            dir.join(telemetry.name).with_extension("csv"),
+        )?);
+
+        writeln!(file, "time,{}", telemetry.columns.join(","))?;
+        file.flush()?;
+
+        Ok(Self {
+            telemetry,
+            file,
+            rows: 0,
+        })
+    }
+
+    fn append(&mut self, time: f64, value: &[u8]) -> io::Result<()> {
+        let Some(row) = (self.telemetry.row)(value) else {
+            eprintln!(
+                "{}: unexpected value {:02x?}, older ground station?",
+                self.telemetry.name, value
+            );
+            return Ok(());
+        };
+
+        writeln!(self.file, "{:.3},{}", time, row.join(","))?;
+        self.rows += 1;
+        self.file.flush()
+    }
+}
+
+async fn record(dev: &Device, dir: &Path) -> Result<Vec<Log>> {
+    let mut logs = Vec::new();
+
+    for telemetry in &TELEMETRY {
+        match dev.has(telemetry.uuid) {
+            true => logs.push(Log::create(dir, telemetry)?),
+            false => eprintln!("{}: not there, older firmware?", telemetry.name),
+        }
+    }
+
+    let uuids: Vec<_> = logs.iter().map(|log| log.telemetry.uuid).collect();
+    let mut values = Box::pin(dev.subscribe(&uuids).await?);
+    let mut check = time::interval(CONNECTION_CHECK);
+
+    println!("logging to {}, Ctrl-C to stop", dir.display());
+
+    loop {
+        tokio::select! {
+            value = values.next() => {
+                let Some((uuid, value)) = value else {
+                    break;
+                };
+
+                if let Some(log) = logs.iter_mut().find(|log| log.telemetry.uuid == uuid) {
+                    log.append(now(), &value)?;
+                }
+            }
+
+            _ = check.tick() => {
+                if !dev.is_connected().await? {
+                    eprintln!("board went away");
+                    break;
+                }
+            }
+
+            _ = tokio::signal::ctrl_c() => break,
+        }
+    }
+
+    Ok(logs)
+}
+
+#[tokio::main]
+async fn main() {
+    let options = parse_options();
+
+    let dir = options.out.join(format!("s107-{}", now() as u64));
+    if let Err(e) = fs::create_dir_all(&dir) {
+        eprintln!("unable to create {} - {}", dir.display(), e);
+        process::exit(1);
+    }
+
+    let dev = match Device::connect(options.name.as_deref()).await {
+        Ok(dev) => dev,
+        Err(e) => {
+            eprintln!("unable to connect - {}", e);
+            process::exit(1);
+        }
+    };
+
+    let logs = record(&dev, &dir).await;
+
+    // Board is left advertising, for whoever is next
+    if let Err(e) = dev.disconnect().await {
+        eprintln!("unable to disconnect - {}", e);
+    }
+
+    match logs {
+        Ok(logs) => {
+            for log in logs {
+                println!("{}: {} rows", log.telemetry.name, log.rows);
+            }
+        }
+        Err(e) => {
+            eprintln!("logging stopped - {}", e);
+            process::exit(1);
+        }
+    }
+}
@@ -0,0 +1,212 @@
+// What gets logged, one CSV per characteristic
+//
+// Fixed point values from the firmware come out in plain units, with the unit in the
+// column name. Values the firmware marks as unknown are left empty, so plots have a gap
+// there rather than a spike.
+
+use link::proto::{
+    self, Altitude, Attitude, ChargerState, FlightSummary, PeriodicUpdate, RailSag,
+    FLIGHT_TIME_UNKNOWN, RAIL_VOLTAGE_UNKNOWN,
+};
+use uuid::Uuid;
+
+pub struct Telemetry {
+    // File name as well
+    pub name: &'static str,
+    pub uuid: Uuid,
+    pub columns: &'static [&'static str],
+    // None if it doesn't look like what proto.rs expects, a newer firmware maybe
+    pub row: fn(&[u8]) -> Option<Vec<String>>,
+}
+
+fn tenths(v: i32) -> String {
+    format!("{:.1}", v as f32 / 10.0)
+}
+
+fn known(v: u16, unknown: u16) -> String {
+    match v == unknown {
+        true => String::new(),
+        false => v.to_string(),
+    }
+}
+
+fn charger(b: &[u8]) -> Option<Vec<String>> {
+    let s = ChargerState::parse(b)?;
+    Some(vec![
+        (s.charging as u8).to_string(),
+        (s.failure as u8).to_string(),
+    ])
+}
+
+fn periodic(b: &[u8]) -> Option<Vec<String>> {
+    let u = PeriodicUpdate::parse(b)?;
+
+    Some(vec![
+        u.voltage.to_string(),
+        u.current.to_string(),
+        tenths(u.temperature as i32 - 2731),
+        known(u.flight_time, FLIGHT_TIME_UNKNOWN),
+        tenths(u.mcu_temperature as i32),
+        known(u.rail_voltage, RAIL_VOLTAGE_UNKNOWN),
+        tenths(u.cpu_load as i32),
+        u.wakeups.to_string(),
+    ])
+}
+
+fn byte(b: &[u8]) -> Option<Vec<String>> {
+    (b.len() == 1).then(|| vec![b[0].to_string()])
+}
+
+fn flight_summary(b: &[u8]) -> Option<Vec<String>> {
+    let s = FlightSummary::parse(b)?;
+
+    Some(vec![
+        s.duration.to_string(),
+        s.voltage_min.to_string(),
+        s.voltage_avg.to_string(),
+        s.voltage_max.to_string(),
+        s.current_min.to_string(),
+        s.current_avg.to_string(),
+        s.current_max.to_string(),
+    ])
+}
+
+fn attitude(b: &[u8]) -> Option<Vec<String>> {
+    let a = Attitude::parse(b)?;
+
+    Some(vec![
+        tenths(a.roll as i32),
+        tenths(a.pitch as i32),
+        tenths(a.heading as i32),
+    ])
+}
+
+fn altitude(b: &[u8]) -> Option<Vec<String>> {
+    let a = Altitude::parse(b)?;
+    Some(vec![a.altitude.to_string(), a.climb.to_string()])
+}
+
+fn rail_sag(b: &[u8]) -> Option<Vec<String>> {
+    let s = RailSag::parse(b)?;
+
+    Some(vec![
+        s.baseline.to_string(),
+        s.min.to_string(),
+        s.droop.to_string(),
+        s.time_to_min.to_string(),
+        s.step.to_string(),
+    ])
+}
+
+pub const TELEMETRY: [Telemetry; 9] = [
+    Telemetry {
+        name: "battery",
+        uuid: proto::BATTERY_LEVEL,
+        columns: &["soc_pct"],
+        row: byte,
+    },
+    Telemetry {
+        name: "charger",
+        uuid: proto::CHARGER_STATE,
+        columns: &["charging", "failure"],
+        row: charger,
+    },
+    Telemetry {
+        name: "periodic",
+        uuid: proto::PERIODIC_UPDATE,
+        columns: &[
+            "voltage_mv",
+            "current_ma",
+            "temperature_c",
+            "flight_time_min",
+            "mcu_temperature_c",
+            "rail_voltage_mv",
+            "cpu_load_pct",
+            "wakeups_per_s",
+        ],
+        row: periodic,
+    },
+    // LearningPhase, see firmware/src/learning.rs
+    Telemetry {
+        name: "learning",
+        uuid: proto::LEARNING_PHASE,
+        columns: &["phase"],
+        row: byte,
+    },
+    Telemetry {
+        name: "flights",
+        uuid: proto::FLIGHT_SUMMARY,
+        columns: &[
+            "duration_s",
+            "voltage_min_mv",
+            "voltage_avg_mv",
+            "voltage_max_mv",
+            "current_min_ma",
+            "current_avg_ma",
+            "current_max_ma",
+        ],
+        row: flight_summary,
+    },
+    // ShutdownReason, that's the last thing the board sends
+    Telemetry {
+        name: "shutdown",
+        uuid: proto::SHUTDOWN,
+        columns: &["reason"],
+        row: byte,
+    },
+    Telemetry {
+        name: "attitude",
+        uuid: proto::ATTITUDE,
+        columns: &["roll_deg", "pitch_deg", "heading_deg"],
+        row: attitude,
+    },
+    Telemetry {
+        name: "altitude",
+        uuid: proto::ALTITUDE,
+        columns: &["altitude_cm", "climb_cm_s"],
+        row: altitude,
+    },
+    Telemetry {
+        name: "rail_sag",
+        uuid: proto::RAIL_SAG,
+        columns: &[
+            "baseline_mv",
+            "min_mv",
+            "droop_mv",
+            "time_to_min_ms",
+            "step",
+        ],
+        row: rail_sag,
+    },
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rows_match_the_columns() {
+        let periodic = [
+            0x68, 0x10, // 4200 mV
+            0x9c, 0xff, // -100 mA
+            0x0b, 0x0c, // 308.3 K
+            0xff, 0xff, // no flight time
+            0xfa, 0x00, // 25.0 °C
+            0x00, 0x00, // not armed
+            0x7b, 0x00, // 12.3 %
+            0x05, 0x00,
+        ];
+
+        let t = &TELEMETRY[2];
+        let row = (t.row)(&periodic).unwrap();
+
+        assert_eq!(row.len(), t.columns.len());
+        assert_eq!(
+            row,
+            ["4200", "-100", "35.2", "", "25.0", "", "12.3", "5"].map(String::from)
+        );
+
+        // Anything short of the whole thing is thrown away
+        assert_eq!((t.row)(&periodic[..15]), None);
+    }
+}
@@ -0,0 +1,298 @@
+// Connection to the board
+//
+// Values written over GATT only make it back to the characteristics once the firmware
+// took them, and that's only visible after a reconnect - see restore_peer_attrs() and the
+// settings. Whoever cares about what the firmware kept goes through reconnect().
+
+use std::{error::Error, time::Duration};
+
+use btleplug::{
+    api::{Central, Characteristic, Manager as _, Peripheral as _, ScanFilter, WriteType},
+    platform::{Adapter, Manager, Peripheral},
+};
+use futures::{Stream, StreamExt};
+use tokio::time::{self, Instant};
+use uuid::Uuid;
+
+use crate::proto::{self, BlackboxEntry, BootInfo, History, HistoryPage, LoggedEvent};
+
+pub type Result<T> = std::result::Result<T, Box<dyn Error + Send + Sync>>;
+
+// How often the tools that stream look for the board having gone away. Lost link takes
+// a few seconds to show anyway
+pub const CONNECTION_CHECK: Duration = Duration::from_secs(5);
+
+const SCAN_TIMEOUT: Duration = Duration::from_secs(20);
+// Advertising interval is 1s, give it a couple of them to come back
+const RECONNECT_DELAY: Duration = Duration::from_secs(2);
+const EVENT_POLL_INTERVAL: Duration = Duration::from_millis(250);
+// Page is read from flash by the settings task, that takes a moment
+const HISTORY_PAGE_TIMEOUT: Duration = Duration::from_secs(2);
+const HISTORY_PAGE_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+pub struct Device {
+    adapter: Adapter,
+    peripheral: Peripheral,
+}
+
+async fn find(adapter: &Adapter, name: Option<&str>) -> Result<Peripheral> {
+    let deadline = Instant::now() + SCAN_TIMEOUT;
+
+    adapter
+        .start_scan(ScanFilter {
+            services: vec![proto::POWER_SERVICE],
+        })
+        .await?;
+
+    while Instant::now() < deadline {
+        for p in adapter.peripherals().await? {
+            let Some(props) = p.properties().await? else {
+                continue;
+            };
+
+            let wanted = name.unwrap_or(proto::DEVICE_NAME);
+            if props.local_name.as_deref() == Some(wanted)
+                || props.services.contains(&proto::POWER_SERVICE) && name.is_none()
+            {
+                adapter.stop_scan().await?;
+                return Ok(p);
+            }
+        }
+
+        time::sleep(Duration::from_millis(500)).await;
+    }
+
+    adapter.stop_scan().await?;
+    Err("no board around, is it powered and advertising?".into())
+}
+
+impl Device {
+    pub async fn connect(name: Option<&str>) -> Result<Self> {
+        let manager = Manager::new().await?;
+        let adapter = manager
+            .adapters()
+            .await?
+            .into_iter()
+            .next()
+            .ok_or("no BLE adapter")?;
+
+        let peripheral = find(&adapter, name).await?;
+
+        peripheral.connect().await?;
+        peripheral.discover_services().await?;
+
+        Ok(Self {
+            adapter,
+            peripheral,
+        })
+    }
+
+    pub async fn reconnect(&mut self) -> Result<()> {
+        self.peripheral.disconnect().await?;
+        time::sleep(RECONNECT_DELAY).await;
+
+        // Same board, the address doesn't change
+        self.peripheral = self.adapter.peripheral(&self.peripheral.id()).await?;
+        self.peripheral.connect().await?;
+        self.peripheral.discover_services().await?;
+
+        Ok(())
+    }
+
+    // For when the board rebooted on its own, into a new image say. It's back once it
+    // takes the connection
+    pub async fn reconnect_after_reboot(&mut self, timeout: Duration) -> Result<()> {
+        let deadline = Instant::now() + timeout;
+
+        loop {
+            time::sleep(RECONNECT_DELAY).await;
+
+            self.peripheral = self.adapter.peripheral(&self.peripheral.id()).await?;
+
+            match self.peripheral.connect().await {
+                Ok(()) => break,
+                Err(e) if Instant::now() >= deadline => return Err(e.into()),
+                Err(_) => {}
+            }
+        }
+
+        self.peripheral.discover_services().await?;
+        Ok(())
+    }
+
+    pub async fn disconnect(&self) -> Result<()> {
+        Ok(self.peripheral.disconnect().await?)
+    }
+
+    pub async fn is_connected(&self) -> Result<bool> {
+        Ok(self.peripheral.is_connected().await?)
+    }
+
+    // dBm, as the adapter last saw the board. Some stacks only have it while scanning
+    pub async fn rssi(&self) -> Result<Option<i16>> {
+        Ok(self.peripheral.properties().await?.and_then(|p| p.rssi))
+    }
+
+    fn characteristic(&self, uuid: Uuid) -> Result<Characteristic> {
+        self.peripheral
+            .characteristics()
+            .into_iter()
+            .find(|c| c.uuid == uuid)
+            .ok_or_else(|| format!("no characteristic {}, older firmware?", uuid).into())
+    }
+
+    pub async fn read(&self, uuid: Uuid) -> Result<Vec<u8>> {
+        Ok(self.peripheral.read(&self.characteristic(uuid)?).await?)
+    }
+
+    pub async fn write(&self, uuid: Uuid, value: &[u8]) -> Result<()> {
+        let c = self.characteristic(uuid)?;
+        Ok(self
+            .peripheral
+            .write(&c, value, WriteType::WithResponse)
+            .await?)
+    }
+
+    // Nothing comes back, so it's only good for what's sent over and over anyway
+    pub async fn send(&self, uuid: Uuid, value: &[u8]) -> Result<()> {
+        let c = self.characteristic(uuid)?;
+        Ok(self
+            .peripheral
+            .write(&c, value, WriteType::WithoutResponse)
+            .await?)
+    }
+
+    // Values of just that characteristic, as they come
+    pub async fn notifications(&self, uuid: Uuid) -> Result<impl Stream<Item = Vec<u8>>> {
+        self.peripheral
+            .subscribe(&self.characteristic(uuid)?)
+            .await?;
+
+        let stream = self.peripheral.notifications().await?;
+        Ok(stream.filter_map(move |n| async move { (n.uuid == uuid).then_some(n.value) }))
+    }
+
+    // Older firmware doesn't have everything
+    pub fn has(&self, uuid: Uuid) -> bool {
+        self.characteristic(uuid).is_ok()
+    }
+
+    // Same as above, for a bunch of them at once. Values are tagged with where they came from
+    pub async fn subscribe(&self, uuids: &[Uuid]) -> Result<impl Stream<Item = (Uuid, Vec<u8>)>> {
+        for &uuid in uuids {
+            self.peripheral
+                .subscribe(&self.characteristic(uuid)?)
+                .await?;
+        }
+
+        let uuids = uuids.to_vec();
+        let stream = self.peripheral.notifications().await?;
+
+        Ok(stream.filter_map(move |n| {
+            let wanted = uuids.contains(&n.uuid);
+            async move { wanted.then_some((n.uuid, n.value)) }
+        }))
+    }
+
+    pub async fn firmware_revision(&self) -> Result<String> {
+        let value = self.read(proto::FIRMWARE_REVISION).await?;
+        Ok(String::from_utf8_lossy(&value).into_owned())
+    }
+
+    pub async fn boot_info(&self) -> Result<BootInfo> {
+        let value = self.read(proto::BOOT_INFO).await?;
+        BootInfo::parse(&value).ok_or_else(|| format!("unexpected boot info {:02x?}", value).into())
+    }
+
+    pub async fn events(&self) -> Result<Vec<LoggedEvent>> {
+        Ok(proto::parse_event_log(&self.read(proto::EVENT_LOG).await?))
+    }
+
+    // Device time of the latest event, newer ones are the ones that happened after
+    pub async fn event_mark(&self) -> Result<u32> {
+        Ok(self.events().await?.last().map_or(0, |e| e.timestamp))
+    }
+
+    pub async fn events_since(&self, mark: u32) -> Result<Vec<LoggedEvent>> {
+        let mut events = self.events().await?;
+        events.retain(|e| e.timestamp > mark);
+        Ok(events)
+    }
+
+    pub async fn wait_for_event(
+        &self,
+        mark: u32,
+        timeout: Duration,
+        matches: impl Fn(&LoggedEvent) -> bool,
+    ) -> Result<Option<LoggedEvent>> {
+        let deadline = Instant::now() + timeout;
+
+        loop {
+            if let Some(e) = self.events_since(mark).await?.into_iter().find(&matches) {
+                return Ok(Some(e));
+            }
+
+            if Instant::now() >= deadline {
+                return Ok(None);
+            }
+
+            time::sleep(EVENT_POLL_INTERVAL).await;
+        }
+    }
+
+    pub async fn blackbox(&self) -> Result<Vec<BlackboxEntry>> {
+        Ok(proto::parse_blackbox(&self.read(proto::BLACKBOX).await?))
+    }
+
+    // Previous page stays there until the new one is read from flash
+    async fn history_page(&self, page: u8) -> Result<HistoryPage> {
+        self.write(proto::HISTORY_PAGE, &[page]).await?;
+
+        let deadline = Instant::now() + HISTORY_PAGE_TIMEOUT;
+
+        loop {
+            let value = self.read(proto::HISTORY).await?;
+            let Some(read) = HistoryPage::parse(&value) else {
+                return Err(format!("unexpected history page {:02x?}, older tool?", value).into());
+            };
+
+            if read.page == page as u32 {
+                return Ok(read);
+            }
+
+            if Instant::now() >= deadline {
+                return Err(format!("history page {} never showed up", page).into());
+            }
+
+            time::sleep(HISTORY_PAGE_POLL_INTERVAL).await;
+        }
+    }
+
+    // Latest pre-crash dump, if there is one
+    pub async fn history(&self) -> Result<Option<History>> {
+        let mut history: Option<History> = None;
+
+        for page in 0..proto::HISTORY_PAGES {
+            let read = self.history_page(page as u8).await?;
+
+            if read.trigger == 0 {
+                return Ok(None);
+            }
+
+            let history = history.get_or_insert_with(|| History {
+                trigger: read.trigger,
+                boot: read.boot,
+                device_time: read.device_time,
+                samples: Vec::new(),
+            });
+
+            history.samples.extend(read.samples);
+
+            if history.samples.len() >= read.len as usize {
+                break;
+            }
+        }
+
+        Ok(history)
+    }
+}
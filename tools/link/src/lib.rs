@@ -0,0 +1,12 @@
+// Host side of the BLE link
+//
+// Everything that runs on a PC and talks to the board goes through here: hil, and the
+// tools next to this crate. Connecting and GATT access are in device.rs, the layout of
+// the characteristics in proto.rs. The browser dashboard only takes the latter.
+
+#[cfg(feature = "device")]
+pub mod device;
+pub mod proto;
+
+#[cfg(feature = "device")]
+pub use device::{Device, Result, CONNECTION_CHECK};
@@ -0,0 +1,861 @@
+// What the firmware exposes over GATT
+//
+// Mirrors firmware/src/ble/peripheral.rs and the packed types behind it. Everything is
+// little-endian with no padding, so it's decoded by hand rather than shared.
+
+use uuid::Uuid;
+
+// 38924a07-23d7-43fe-af5d-9c887XY89cf1, X is the service and Y the characteristic
+const fn uuid(xy: u8) -> Uuid {
+    Uuid::from_u128(0x38924a07_23d7_43fe_af5d_9c8870089cf1 | (xy as u128) << 20)
+}
+
+const fn standard(short: u16) -> Uuid {
+    Uuid::from_u128(0x00000000_0000_1000_8000_00805f9b34fb | (short as u128) << 96)
+}
+
+pub const BATTERY_SERVICE: Uuid = standard(0x180f);
+pub const BATTERY_LEVEL: Uuid = standard(0x2a19);
+
+pub const DEVICE_INFORMATION_SERVICE: Uuid = standard(0x180a);
+// git describe of the build
+pub const FIRMWARE_REVISION: Uuid = standard(0x2a26);
+// As provisioned, "unknown" otherwise
+pub const HARDWARE_REVISION: Uuid = standard(0x2a27);
+
+pub const POWER_SERVICE: Uuid = uuid(0xa0);
+pub const CHARGER_STATE: Uuid = uuid(0xa1);
+pub const PERIODIC_UPDATE: Uuid = uuid(0xa2);
+pub const RATE_LOOP: Uuid = uuid(0xa3);
+pub const LEARNING_PHASE: Uuid = uuid(0xa4);
+pub const FLIGHT_SUMMARY: Uuid = uuid(0xa5);
+pub const SHUTDOWN: Uuid = uuid(0xa6);
+pub const ATTITUDE: Uuid = uuid(0xa7);
+pub const ALTITUDE: Uuid = uuid(0xa8);
+pub const RAIL_SAG: Uuid = uuid(0xa9);
+pub const LEARNING_REPORT: Uuid = uuid(0xaa);
+
+pub const REQUESTS_SERVICE: Uuid = uuid(0xb0);
+pub const PID_UPDATE: Uuid = uuid(0xb2);
+pub const START_LEARNING: Uuid = uuid(0xb4);
+// Xbox HID report, see copter_core::xbox
+pub const DIRECT_CONTROL: Uuid = uuid(0xb9);
+
+pub const CONFIG_SERVICE: Uuid = uuid(0xc0);
+pub const BATTERY_PROFILE: Uuid = uuid(0xc1);
+pub const CHARGE_MODE: Uuid = uuid(0xc2);
+pub const SOC_THRESHOLDS: Uuid = uuid(0xc3);
+pub const INDICATION_THEME: Uuid = uuid(0xc4);
+pub const INDICATION_THEME_ENTRY: Uuid = uuid(0xc5);
+pub const FLIGHT_LIGHT: Uuid = uuid(0xc6);
+pub const SELF_TEST_MODE: Uuid = uuid(0xc7);
+pub const PARAM_UPDATE: Uuid = uuid(0xc9);
+pub const PARAMS: Uuid = uuid(0xca);
+pub const PARAM_TABLE: Uuid = uuid(0xcb);
+pub const PILOT_PROFILE: Uuid = uuid(0xcc);
+pub const LOG_LEVELS: Uuid = uuid(0xce);
+pub const GAUGE_LEARNED: Uuid = uuid(0xcf);
+
+pub const DIAGNOSTICS_SERVICE: Uuid = uuid(0xd0);
+pub const BOOT_INFO: Uuid = uuid(0xd1);
+pub const EVENT_LOG: Uuid = uuid(0xd2);
+pub const BLACKBOX: Uuid = uuid(0xd5);
+pub const HISTORY: Uuid = uuid(0xd7);
+pub const HISTORY_PAGE: Uuid = uuid(0xd8);
+// Round trips for range tests
+pub const ECHO: Uuid = uuid(0xdc);
+
+pub const LIGHTS_SERVICE: Uuid = uuid(0xe0);
+pub const AUX_OUTPUTS: Uuid = uuid(0xe1);
+pub const AUX_LIGHTS: Uuid = uuid(0xe2);
+
+// Firmware update, values are the ones of copter_core::dfu
+pub const DFU_CONTROL: Uuid = uuid(0xf1);
+pub const DFU_DATA: Uuid = uuid(0xf2);
+pub const DFU_STATUS: Uuid = uuid(0xf3);
+
+pub const DEVICE_NAME: &str = "Syma S107";
+
+fn u16_at(b: &[u8], at: usize) -> u16 {
+    u16::from_le_bytes([b[at], b[at + 1]])
+}
+
+fn i16_at(b: &[u8], at: usize) -> i16 {
+    u16_at(b, at) as i16
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct ChargerState {
+    pub charging: bool,
+    pub failure: bool,
+}
+
+impl ChargerState {
+    pub fn parse(b: &[u8]) -> Option<Self> {
+        (b.len() == 2).then(|| Self {
+            charging: b[0] != 0,
+            failure: b[1] != 0,
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct PeriodicUpdate {
+    // mV, mA
+    pub voltage: u16,
+    pub current: i16,
+    // 0.1 K, from the gauge
+    pub temperature: u16,
+    // Minutes of flight left
+    pub flight_time: u16,
+    // 0.1 °C
+    pub mcu_temperature: i16,
+    pub rail_voltage: u16,
+    // 0.1 %
+    pub cpu_load: u16,
+    // Per second
+    pub wakeups: u16,
+}
+
+// Only measured while armed
+pub const RAIL_VOLTAGE_UNKNOWN: u16 = 0;
+// Unless discharging
+pub const FLIGHT_TIME_UNKNOWN: u16 = u16::MAX;
+
+impl PeriodicUpdate {
+    pub fn parse(b: &[u8]) -> Option<Self> {
+        (b.len() == 16).then(|| Self {
+            voltage: u16_at(b, 0),
+            current: i16_at(b, 2),
+            temperature: u16_at(b, 4),
+            flight_time: u16_at(b, 6),
+            mcu_temperature: i16_at(b, 8),
+            rail_voltage: u16_at(b, 10),
+            cpu_load: u16_at(b, 12),
+            wakeups: u16_at(b, 14),
+        })
+    }
+}
+
+// Once the flight is over
+#[derive(Clone, Copy, Debug)]
+pub struct FlightSummary {
+    // s
+    pub duration: u16,
+    // mV
+    pub voltage_min: u16,
+    pub voltage_avg: u16,
+    pub voltage_max: u16,
+    // mA
+    pub current_min: i16,
+    pub current_avg: i16,
+    pub current_max: i16,
+}
+
+impl FlightSummary {
+    pub fn parse(b: &[u8]) -> Option<Self> {
+        (b.len() == 14).then(|| Self {
+            duration: u16_at(b, 0),
+            voltage_min: u16_at(b, 2),
+            voltage_avg: u16_at(b, 4),
+            voltage_max: u16_at(b, 6),
+            current_min: i16_at(b, 8),
+            current_avg: i16_at(b, 10),
+            current_max: i16_at(b, 12),
+        })
+    }
+}
+
+// 0.1 °
+#[derive(Clone, Copy, Debug)]
+pub struct Attitude {
+    pub roll: i16,
+    pub pitch: i16,
+    pub heading: u16,
+}
+
+impl Attitude {
+    pub fn parse(b: &[u8]) -> Option<Self> {
+        (b.len() == 6).then(|| Self {
+            roll: i16_at(b, 0),
+            pitch: i16_at(b, 2),
+            heading: u16_at(b, 4),
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Altitude {
+    // cm above the ground at arming, cm/s
+    pub altitude: i16,
+    pub climb: i16,
+}
+
+impl Altitude {
+    pub fn parse(b: &[u8]) -> Option<Self> {
+        (b.len() == 4).then(|| Self {
+            altitude: i16_at(b, 0),
+            climb: i16_at(b, 2),
+        })
+    }
+}
+
+// One per throttle punch
+#[derive(Clone, Copy, Debug)]
+pub struct RailSag {
+    // mV
+    pub baseline: u16,
+    pub min: u16,
+    pub droop: u16,
+    // ms
+    pub time_to_min: u16,
+    // Stick units
+    pub step: i16,
+}
+
+impl RailSag {
+    pub fn parse(b: &[u8]) -> Option<Self> {
+        (b.len() == 10).then(|| Self {
+            baseline: u16_at(b, 0),
+            min: u16_at(b, 2),
+            droop: u16_at(b, 4),
+            time_to_min: u16_at(b, 6),
+            step: i16_at(b, 8),
+        })
+    }
+}
+
+// Fixed point, 0.01 per step
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Pid {
+    pub p: u16,
+    pub i: u16,
+    pub d: u16,
+}
+
+impl Pid {
+    pub fn to_bytes(self) -> [u8; 6] {
+        let mut b = [0; 6];
+
+        b[0..2].copy_from_slice(&self.p.to_le_bytes());
+        b[2..4].copy_from_slice(&self.i.to_le_bytes());
+        b[4..6].copy_from_slice(&self.d.to_le_bytes());
+        b
+    }
+}
+
+// Yaw rate loop, at 50Hz while armed
+#[derive(Clone, Copy, Debug)]
+pub struct RateLoop {
+    // 0.1 dps
+    pub gyro: i16,
+    pub setpoint: i16,
+    // Duty cycle units
+    pub output: i16,
+    pub throttle: i16,
+    // What the loop runs with
+    pub gains: Pid,
+}
+
+impl RateLoop {
+    pub fn parse(b: &[u8]) -> Option<Self> {
+        (b.len() == 14).then(|| Self {
+            gyro: i16_at(b, 0),
+            setpoint: i16_at(b, 2),
+            output: i16_at(b, 4),
+            throttle: i16_at(b, 6),
+            gains: Pid {
+                p: u16_at(b, 8),
+                i: u16_at(b, 10),
+                d: u16_at(b, 12),
+            },
+        })
+    }
+}
+
+// See firmware/src/learning.rs
+#[repr(u8)]
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum LearningPhase {
+    Idle = 0,
+    ChargeToFull = 1,
+    RestCharged = 2,
+    Discharge = 3,
+    RestDischarged = 4,
+    Complete = 5,
+}
+
+impl LearningPhase {
+    pub fn from_u8(v: u8) -> Option<Self> {
+        Some(match v {
+            0 => Self::Idle,
+            1 => Self::ChargeToFull,
+            2 => Self::RestCharged,
+            3 => Self::Discharge,
+            4 => Self::RestDischarged,
+            5 => Self::Complete,
+            _ => return None,
+        })
+    }
+}
+
+// Gauge update status as it goes through the cycle
+pub const UPDATE_STATUS_LEARNING: u8 = 0x03;
+pub const UPDATE_STATUS_QMAX_UPDATED: u8 = 0x05;
+pub const UPDATE_STATUS_RA_UPDATED: u8 = 0x06;
+
+// Once per gauge poll while the learning cycle runs
+#[derive(Clone, Copy, Debug)]
+pub struct LearningReport {
+    pub phase: LearningPhase,
+    pub update_status: u8,
+    pub qmax: u16,
+    // mV, mA
+    pub voltage: u16,
+    pub current: i16,
+    // Minutes, zero while there's a load
+    pub rested: u16,
+}
+
+impl LearningReport {
+    pub fn parse(b: &[u8]) -> Option<Self> {
+        if b.len() != 10 {
+            return None;
+        }
+
+        Some(Self {
+            phase: LearningPhase::from_u8(b[0])?,
+            update_status: b[1],
+            qmax: u16_at(b, 2),
+            voltage: u16_at(b, 4),
+            current: i16_at(b, 6),
+            rested: u16_at(b, 8),
+        })
+    }
+}
+
+pub const RA_TABLE_LEN: usize = 15;
+
+// What a learning cycle comes up with
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct GaugeLearned {
+    pub qmax: u16,
+    pub ra_table: [u16; RA_TABLE_LEN],
+}
+
+impl GaugeLearned {
+    pub fn parse(b: &[u8]) -> Option<Self> {
+        (b.len() == 2 + 2 * RA_TABLE_LEN).then(|| Self {
+            qmax: u16_at(b, 0),
+            ra_table: std::array::from_fn(|n| u16_at(b, 2 + 2 * n)),
+        })
+    }
+
+    pub fn to_bytes(self) -> Vec<u8> {
+        let mut b = self.qmax.to_le_bytes().to_vec();
+
+        for ra in self.ra_table {
+            b.extend(ra.to_le_bytes());
+        }
+
+        b
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct BatteryProfile {
+    // mAh, mWh, mV
+    pub capacity: u16,
+    pub energy: u16,
+    pub terminate_voltage: u16,
+    pub taper_rate: u16,
+    // %
+    pub soc_delta: u8,
+    pub chemistry: u8,
+}
+
+impl BatteryProfile {
+    pub fn parse(b: &[u8]) -> Option<Self> {
+        (b.len() == 10).then(|| Self {
+            capacity: u16_at(b, 0),
+            energy: u16_at(b, 2),
+            terminate_voltage: u16_at(b, 4),
+            taper_rate: u16_at(b, 6),
+            soc_delta: b[8],
+            chemistry: b[9],
+        })
+    }
+
+    pub fn to_bytes(self) -> [u8; 10] {
+        let mut b = [0; 10];
+
+        b[0..2].copy_from_slice(&self.capacity.to_le_bytes());
+        b[2..4].copy_from_slice(&self.energy.to_le_bytes());
+        b[4..6].copy_from_slice(&self.terminate_voltage.to_le_bytes());
+        b[6..8].copy_from_slice(&self.taper_rate.to_le_bytes());
+        b[8] = self.soc_delta;
+        b[9] = self.chemistry;
+        b
+    }
+}
+
+// ChargeMode and BatteryChemistry values past these are rejected
+pub const CHARGE_MODE_MAX: u8 = 1;
+// Read back from boards that can't switch the charge mode, their writes are ignored
+pub const CHARGE_MODE_NONE: u8 = 0xff;
+pub const CHEMISTRY_MAX: u8 = 2;
+
+// Same order as the fields of SocThresholds
+pub type SocThresholds = [u8; 5];
+
+// Stages as the event log has them, see copter_core::policy::SocStage
+pub const SOC_STAGE_INHIBIT_ARMING: u16 = 4;
+
+// FlightLight and SelfTestMode values past these are rejected
+pub const FLIGHT_LIGHT_MAX: u8 = 3;
+pub const SELF_TEST_MODE_MAX: u8 = 2;
+
+// Parameter registry, see firmware/src/params.rs
+pub const PARAM_SLOTS: usize = 8;
+
+// By id, the board only says what the values are and how far they go
+pub fn param_name(id: usize) -> Option<&'static str> {
+    Some(match id {
+        0 => "idle_disarm_timeout",
+        1 => "crash_yaw_rate",
+        2 => "pairing_timeout",
+        3 => "motor_current_limit",
+        _ => return None,
+    })
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct ParamInfo {
+    pub kind: u8,
+    pub min: i32,
+    pub max: i32,
+    pub default: i32,
+}
+
+impl ParamInfo {
+    // Unused slots have nothing between min and max
+    pub fn used(&self) -> bool {
+        self.max > self.min
+    }
+
+    pub fn contains(&self, value: i32) -> bool {
+        (self.min..=self.max).contains(&value)
+    }
+}
+
+pub fn parse_param_table(b: &[u8]) -> Option<Vec<ParamInfo>> {
+    (b.len() == 16 * PARAM_SLOTS).then(|| {
+        b.chunks_exact(16)
+            .map(|e| ParamInfo {
+                kind: e[0],
+                min: i32_at(e, 4),
+                max: i32_at(e, 8),
+                default: i32_at(e, 12),
+            })
+            .collect()
+    })
+}
+
+pub fn parse_params(b: &[u8]) -> Option<Vec<i32>> {
+    (b.len() == 4 * PARAM_SLOTS).then(|| b.chunks_exact(4).map(|v| i32_at(v, 0)).collect())
+}
+
+pub fn param_update(id: u8, value: i32) -> [u8; 8] {
+    let mut b = [0; 8];
+
+    b[0] = id;
+    b[4..8].copy_from_slice(&value.to_le_bytes());
+    b
+}
+
+// Of the controller the board knows
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct PilotProfile {
+    pub yaw_trim: i8,
+    pub pitch_trim: i8,
+    // %
+    pub yaw_rate: u8,
+    pub pitch_rate: u8,
+    pub expo: u8,
+    // Bit numbers in ButtonFlags, plus one for the last two
+    pub arm_button: u8,
+    pub hold_button: u8,
+    pub aux_button: u8,
+}
+
+impl PilotProfile {
+    pub fn parse(b: &[u8]) -> Option<Self> {
+        (b.len() == 8).then(|| Self {
+            yaw_trim: b[0] as i8,
+            pitch_trim: b[1] as i8,
+            yaw_rate: b[2],
+            pitch_rate: b[3],
+            expo: b[4],
+            arm_button: b[5],
+            hold_button: b[6],
+            aux_button: b[7],
+        })
+    }
+
+    pub fn to_bytes(self) -> [u8; 8] {
+        [
+            self.yaw_trim as u8,
+            self.pitch_trim as u8,
+            self.yaw_rate,
+            self.pitch_rate,
+            self.expo,
+            self.arm_button,
+            self.hold_button,
+            self.aux_button,
+        ]
+    }
+}
+
+// In IndicationStyle order, that's how the theme has them
+pub const INDICATION_STYLES: [&str; 14] = [
+    "searching",
+    "pairing_mode",
+    "connected_idle",
+    "charge_complete",
+    "low_battery",
+    "lost_model",
+    "blink_slow",
+    "blink_fast",
+    "charging",
+    "charge_inhibited",
+    "gyro_calibration",
+    "fault",
+    "factory_reset",
+    "disabled",
+];
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct ThemeEntry {
+    // ms
+    pub on: u16,
+    pub off: u16,
+    pub pause: u16,
+    pub count: u8,
+    // %
+    pub brightness: u8,
+    // 100 Hz
+    pub tone: u8,
+    // %
+    pub volume: u8,
+}
+
+impl ThemeEntry {
+    const SIZE: usize = 10;
+
+    fn parse(b: &[u8]) -> Self {
+        Self {
+            on: u16_at(b, 0),
+            off: u16_at(b, 2),
+            pause: u16_at(b, 4),
+            count: b[6],
+            brightness: b[7],
+            tone: b[8],
+            volume: b[9],
+        }
+    }
+
+    // What the theme entry characteristic takes, the theme is too large for one write
+    pub fn update(self, style: u8) -> [u8; 1 + Self::SIZE] {
+        let mut b = [0; 1 + Self::SIZE];
+
+        b[0] = style;
+        b[1..3].copy_from_slice(&self.on.to_le_bytes());
+        b[3..5].copy_from_slice(&self.off.to_le_bytes());
+        b[5..7].copy_from_slice(&self.pause.to_le_bytes());
+        b[7] = self.count;
+        b[8] = self.brightness;
+        b[9] = self.tone;
+        b[10] = self.volume;
+        b
+    }
+}
+
+pub fn parse_theme(b: &[u8]) -> Option<Vec<ThemeEntry>> {
+    (b.len() == ThemeEntry::SIZE * INDICATION_STYLES.len()).then(|| {
+        b.chunks_exact(ThemeEntry::SIZE)
+            .map(ThemeEntry::parse)
+            .collect()
+    })
+}
+
+// Canopy lights, see firmware/src/aux.rs
+pub const AUX_OUTPUT_COUNT: usize = 2;
+// copter_core::lights::Pattern ones past this are turned away
+pub const AUX_PATTERN_MAX: u8 = 4;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct AuxOutput {
+    pub pattern: u8,
+    // %
+    pub brightness: u8,
+}
+
+pub fn parse_aux_outputs(b: &[u8]) -> Option<Vec<AuxOutput>> {
+    (b.len() == 2 * AUX_OUTPUT_COUNT).then(|| {
+        b.chunks_exact(2)
+            .map(|o| AuxOutput {
+                pattern: o[0],
+                brightness: o[1],
+            })
+            .collect()
+    })
+}
+
+pub fn aux_outputs_bytes(outputs: &[AuxOutput]) -> Vec<u8> {
+    outputs
+        .iter()
+        .flat_map(|o| [o.pattern, o.brightness])
+        .collect()
+}
+
+// One per logfilter::Module. Anything past Trace is taken as Trace
+pub const LOG_MODULES: usize = 3;
+pub const LOG_LEVEL_TRACE: u8 = 5;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum EventKind {
+    ControllerDisconnected = 3,
+    Armed = 4,
+    Disarmed = 5,
+    SocStage = 7,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct LoggedEvent {
+    // Device time in ms
+    pub timestamp: u32,
+    pub kind: u8,
+    pub value: u16,
+}
+
+impl LoggedEvent {
+    pub fn is(&self, kind: EventKind) -> bool {
+        self.kind == kind as u8
+    }
+}
+
+fn u32_at(b: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes([b[at], b[at + 1], b[at + 2], b[at + 3]])
+}
+
+fn i32_at(b: &[u8], at: usize) -> i32 {
+    u32_at(b, at) as i32
+}
+
+fn parse_event(e: &[u8]) -> LoggedEvent {
+    LoggedEvent {
+        timestamp: u32_at(e, 0),
+        kind: e[4],
+        value: u16_at(e, 6),
+    }
+}
+
+// Oldest first, empty slots are left out
+pub fn parse_event_log(b: &[u8]) -> Vec<LoggedEvent> {
+    b.chunks_exact(8)
+        .map(parse_event)
+        .filter(|e| e.kind != 0)
+        .collect()
+}
+
+// Black box entries are Warning / Error events, with the Incident as the value
+pub const EVENT_WARNING: u8 = 10;
+pub const EVENT_ERROR: u8 = 11;
+
+// See firmware/src/blackbox.rs
+pub fn incident_name(incident: u16) -> &'static str {
+    match incident {
+        1 => "panic",
+        2 => "gauge communication",
+        3 => "settings read",
+        4 => "settings store",
+        6 => "advertise",
+        7 => "notification dispatcher",
+        8 => "controller gatt",
+        9 => "controller search",
+        11 => "hard fault",
+        12 => "watchdog",
+        13 => "image rolled back",
+        14 => "unrecoverable",
+        15 => "assertion",
+        16 => "motor current",
+        _ => "unknown",
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct BlackboxEntry {
+    // Boots since the counters were reset, that's what keeps entries in order
+    pub boot: u32,
+    pub event: LoggedEvent,
+}
+
+// Oldest first. Erased slots are all ones, they're left out
+pub fn parse_blackbox(b: &[u8]) -> Vec<BlackboxEntry> {
+    b.chunks_exact(16)
+        .filter(|e| u32_at(e, 0) != u32::MAX)
+        .map(|e| BlackboxEntry {
+            boot: u32_at(e, 4),
+            event: parse_event(&e[8..]),
+        })
+        .collect()
+}
+
+// Pre-crash history, see firmware/src/history.rs. The control loop is decimated to
+// 20Hz, ~10s of it in the dump
+pub const HISTORY_HZ: u32 = 20;
+pub const HISTORY_LEN: usize = 200;
+pub const HISTORY_PAGE_LEN: usize = 20;
+pub const HISTORY_PAGES: usize = HISTORY_LEN / HISTORY_PAGE_LEN;
+
+#[derive(Clone, Copy, Default, PartialEq, Debug)]
+pub struct HistorySample {
+    // dps, the PID's units. Zero while the gyro is not read
+    pub yaw_rate: i16,
+    pub throttle: i16,
+    // Sticks after the pilot profile, duty cycle units
+    pub yaw: i16,
+    pub pitch: i16,
+    // As asked for, before clamping
+    pub rotor1: i16,
+    pub rotor2: i16,
+    pub throttle_limit: i16,
+    // mV, once a second
+    pub rail_voltage: u16,
+}
+
+impl HistorySample {
+    const SIZE: usize = 16;
+
+    fn parse(b: &[u8]) -> Self {
+        Self {
+            yaw_rate: i16_at(b, 0),
+            throttle: i16_at(b, 2),
+            yaw: i16_at(b, 4),
+            pitch: i16_at(b, 6),
+            rotor1: i16_at(b, 8),
+            rotor2: i16_at(b, 10),
+            throttle_limit: i16_at(b, 12),
+            rail_voltage: u16_at(b, 14),
+        }
+    }
+}
+
+// Zero for no dump at all
+pub fn history_trigger_name(trigger: u8) -> &'static str {
+    match trigger {
+        1 => "crash",
+        2 => "panic",
+        3 => "hard fault",
+        _ => "unknown",
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct HistoryPage {
+    pub trigger: u8,
+    // Same as with the black box, tells dumps apart across power cycles
+    pub boot: u32,
+    // s, when the dump was made
+    pub device_time: u32,
+    // Samples in the whole dump
+    pub len: u32,
+    pub page: u32,
+    // Only the ones that are there, oldest first
+    pub samples: Vec<HistorySample>,
+}
+
+impl HistoryPage {
+    // Page number included
+    const HEADER_SIZE: usize = 24;
+
+    pub fn parse(b: &[u8]) -> Option<Self> {
+        if b.len() != Self::HEADER_SIZE + HistorySample::SIZE * HISTORY_PAGE_LEN {
+            return None;
+        }
+
+        let len = u32_at(b, 16);
+        let page = u32_at(b, 20);
+        let count = (len as usize)
+            .min(HISTORY_LEN)
+            .saturating_sub(page as usize * HISTORY_PAGE_LEN)
+            .min(HISTORY_PAGE_LEN);
+
+        let samples = b[Self::HEADER_SIZE..]
+            .chunks_exact(HistorySample::SIZE)
+            .take(count)
+            .map(HistorySample::parse)
+            .collect();
+
+        Some(Self {
+            trigger: b[4],
+            boot: u32_at(b, 8),
+            device_time: u32_at(b, 12),
+            len,
+            page,
+            samples,
+        })
+    }
+}
+
+// All of the pages of a dump put together
+#[derive(Clone, Debug)]
+pub struct History {
+    pub trigger: u8,
+    pub boot: u32,
+    pub device_time: u32,
+    pub samples: Vec<HistorySample>,
+}
+
+// Where the bootloader leaves its note, see copter_core::boot
+#[derive(Clone, Copy, Debug)]
+pub struct BootInfo {
+    pub reset_reason: u32,
+    pub gpregret: u32,
+}
+
+impl BootInfo {
+    pub fn parse(b: &[u8]) -> Option<Self> {
+        (b.len() == 8).then(|| Self {
+            reset_reason: u32_at(b, 0),
+            gpregret: u32_at(b, 4),
+        })
+    }
+}
+
+// Echo packets fill a default MTU. Sequence number first, the board puts the RSSI it
+// sees after it
+pub const ECHO_LEN: usize = 20;
+const ECHO_RSSI_UNKNOWN: i8 = 127;
+
+pub fn echo_packet(seq: u32) -> [u8; ECHO_LEN] {
+    let mut b = [0; ECHO_LEN];
+    b[0..4].copy_from_slice(&seq.to_le_bytes());
+    b
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Echo {
+    pub seq: u32,
+    // dBm, None until the board has a reading
+    pub rssi: Option<i8>,
+}
+
+impl Echo {
+    pub fn parse(b: &[u8]) -> Option<Self> {
+        (b.len() == ECHO_LEN).then(|| Self {
+            seq: u32_at(b, 0),
+            rssi: Some(b[4] as i8).filter(|&r| r != ECHO_RSSI_UNKNOWN),
+        })
+    }
+}
@@ -0,0 +1,101 @@
+// Image to send
+//
+// Raw binary of a firmware built with the "bootloader" feature, the way objcopy makes it.
+// Anything else is caught here rather than by the bootloader rolling it back. The image
+// is padded to whole words with erased flash and signed with the update key (see key.rs),
+// the signature goes after it and the CRC is taken over all of it.
+
+use std::{fs, path::Path};
+
+use copter_core::dfu::{self, ImageError, IMAGE_ALIGN, IMAGE_MAX_LEN, SIGNATURE_LEN};
+use ed25519_compact::KeyPair;
+use link::Result;
+
+pub struct Image {
+    // Signature included
+    pub data: Vec<u8>,
+    pub crc: u32,
+}
+
+impl Image {
+    pub fn load(path: &Path, keys: &KeyPair) -> Result<Self> {
+        Self::from_bytes(fs::read(path)?, keys)
+    }
+
+    pub fn from_bytes(mut data: Vec<u8>, keys: &KeyPair) -> Result<Self> {
+        if data.starts_with(b"\x7fELF") {
+            return Err("that's an ELF file, make a binary out of it first: \
+                `cargo objcopy --release --features bootloader -- -O binary ble-copter.bin`"
+                .into());
+        }
+
+        while !data.len().is_multiple_of(IMAGE_ALIGN as usize) {
+            data.push(0xff);
+        }
+
+        dfu::check_image(&data).map_err(|e| match e {
+            ImageError::Length => format!(
+                "{} bytes, only up to {} fit",
+                data.len(),
+                IMAGE_MAX_LEN - SIGNATURE_LEN
+            ),
+            ImageError::StackPointer => {
+                "no vector table at the start, not a firmware image?".into()
+            }
+            ImageError::ResetVector => {
+                "built for another flash layout, is the bootloader feature on?".into()
+            }
+        })?;
+
+        let signature = keys.sk.sign(&data, None);
+        data.extend_from_slice(signature.as_ref());
+
+        let crc = dfu::crc32(&data);
+        Ok(Self { data, crc })
+    }
+
+    // Version string is in there, so the board running this image reports one of its own
+    pub fn has_revision(&self, revision: &str) -> bool {
+        let revision = revision.as_bytes();
+
+        !revision.is_empty()
+            && self
+                .data
+                .windows(revision.len())
+                .any(|window| window == revision)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ed25519_compact::Seed;
+
+    #[test]
+    fn images_are_padded_and_checked() {
+        let mut data = 0x2001_0000_u32.to_le_bytes().to_vec();
+        data.extend((dfu::ACTIVE_START + 0x101).to_le_bytes());
+        data.extend(vec![0; 0x200]);
+        data.extend(b"v1.2-3-gabcdef0");
+
+        let keys = KeyPair::from_seed(Seed::new([7; 32]));
+        let image = Image::from_bytes(data, &keys).unwrap();
+
+        let len = dfu::image_len(image.data.len() as u32).unwrap() as usize;
+        let (signed, signature) = image.data.split_at(len);
+        assert!(len.is_multiple_of(IMAGE_ALIGN as usize));
+        assert_eq!(signed.last(), Some(&0xff));
+        assert_eq!(image.crc, dfu::crc32(&image.data));
+
+        let mut check = dfu::SignatureCheck::new(&keys.pk, signature.try_into().unwrap()).unwrap();
+        check.update(signed);
+        assert!(check.finish());
+
+        assert!(image.has_revision("v1.2-3-gabcdef0"));
+        assert!(!image.has_revision("v1.2-4-g1234567"));
+        assert!(!image.has_revision(""));
+
+        assert!(Image::from_bytes(b"\x7fELF and the rest".to_vec(), &keys).is_err());
+        assert!(Image::from_bytes(vec![0; 64], &keys).is_err());
+    }
+}
@@ -0,0 +1,59 @@
+// Update key
+//
+// Seed of an ed25519 key pair, 64 hex digits in a file of its own. The firmware is built
+// with the public half in DFU_PUBLIC_KEY and only takes images signed with it. Keep the
+// file out of the repo, whoever has it can update any board built against it.
+
+use std::{fs, io::Write, path::Path};
+
+use copter_core::dfu;
+use ed25519_compact::{KeyPair, Seed};
+use link::Result;
+
+fn hex(b: &[u8]) -> String {
+    b.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+pub fn load(path: &Path) -> Result<KeyPair> {
+    let seed = dfu::parse_key(fs::read_to_string(path)?.trim())
+        .ok_or("not a key, should be 64 hex digits")?;
+
+    Ok(KeyPair::from_seed(Seed::new(seed)))
+}
+
+// Never over an existing one, boards built against it would be stuck with what they run.
+// Returns the public half, as DFU_PUBLIC_KEY wants it
+pub fn generate(path: &Path) -> Result<String> {
+    let keys = KeyPair::generate();
+
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+
+    writeln!(options.open(path)?, "{}", hex(keys.sk.seed().as_ref()))?;
+    Ok(hex(keys.pk.as_ref()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keys_survive_the_file() {
+        let path = std::env::temp_dir().join(format!("ota-key-{}", std::process::id()));
+        let _ = fs::remove_file(&path);
+
+        let public = generate(&path).unwrap();
+        let keys = load(&path).unwrap();
+        assert_eq!(public, hex(keys.pk.as_ref()));
+
+        assert!(generate(&path).is_err());
+
+        fs::write(&path, "not a key").unwrap();
+        assert!(load(&path).is_err());
+
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,338 @@
+// Firmware update over BLE
+//
+// Sends a new image to a board running with the bootloader (see bootloader/ and
+// copter_core::dfu for how it goes on the wire). Chunks go out without waiting for each
+// one to be acknowledged, a window at a time, and the status says how far the board
+// actually got - whatever went missing is sent again from there. Once the board has
+// checked the CRC the image is activated, and the board reboots into it. It's only
+// done when the board comes back running the version that's in the image.
+//
+// Images are signed with the update key, the board takes nothing else. Make one once, and
+// build the firmware with the public half it prints:
+//
+//   cargo run -p ota -- --keygen ~/.ble-copter.key
+//   DFU_PUBLIC_KEY=... cargo build --release --features bootloader
+//
+//   cargo run -p ota -- [--name NAME] --key ~/.ble-copter.key IMAGE.bin
+//
+// Boards without the bootloader or with firmware from before the update service have to
+// be flashed over SWD once.
+
+mod image;
+mod key;
+
+use std::{
+    env,
+    io::{self, Write},
+    path::{Path, PathBuf},
+    process,
+    time::Duration,
+};
+
+use copter_core::{
+    boot::ImageState,
+    dfu::{Chunk, Command, Failure, Phase, Status, CHUNK_LEN, CHUNK_WRITE_LEN},
+};
+use image::Image;
+use link::{proto, Device, Result};
+use tokio::time::{self, Instant};
+
+// Chunks sent before looking at how far the board got
+const WINDOW: usize = 32;
+// Windows in a row that didn't get anywhere
+const MAX_RETRIES: u32 = 5;
+const RETRY_DELAY: Duration = Duration::from_millis(500);
+
+const STATUS_POLL_INTERVAL: Duration = Duration::from_millis(100);
+// Whole partition is erased first, page by page in between the radio events
+const ERASE_TIMEOUT: Duration = Duration::from_secs(15);
+const CHECK_TIMEOUT: Duration = Duration::from_secs(5);
+const REBOOT_TIMEOUT: Duration = Duration::from_secs(10);
+// Swapping the image in takes the bootloader a while
+const COMEBACK_TIMEOUT: Duration = Duration::from_secs(60);
+
+enum Options {
+    Update {
+        name: Option<String>,
+        key: PathBuf,
+        image: PathBuf,
+    },
+    Keygen(PathBuf),
+}
+
+fn usage() -> ! {
+    eprintln!("usage: ota [--name NAME] --key KEY IMAGE.bin");
+    eprintln!("       ota --keygen KEY");
+    process::exit(2);
+}
+
+fn parse_options() -> Options {
+    let mut name = None;
+    let mut key = None;
+    let mut keygen = None;
+    let mut image = None;
+    let mut args = env::args().skip(1);
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--name" => name = Some(args.next().unwrap_or_else(|| usage())),
+            "--key" => key = Some(PathBuf::from(args.next().unwrap_or_else(|| usage()))),
+            "--keygen" => keygen = Some(PathBuf::from(args.next().unwrap_or_else(|| usage()))),
+            _ if arg.starts_with('-') || image.is_some() => usage(),
+            _ => image = Some(PathBuf::from(arg)),
+        }
+    }
+
+    match (keygen, name, key, image) {
+        (Some(path), None, None, None) => Options::Keygen(path),
+        (None, name, Some(key), Some(image)) => Options::Update { name, key, image },
+        _ => usage(),
+    }
+}
+
+async fn read_status(dev: &Device) -> Result<Status> {
+    let value = dev.read(proto::DFU_STATUS).await?;
+    Status::parse(&value).ok_or_else(|| format!("unexpected update status {:02x?}", value).into())
+}
+
+fn check(status: Status) -> Result<Status> {
+    let reason = match status.failure {
+        _ if status.phase != Phase::Failed => return Ok(status),
+        Failure::Armed => "board is armed, land and disarm first",
+        Failure::Image => "board didn't take the image",
+        Failure::Crc => "image came through damaged, CRC doesn't match",
+        Failure::Flash => "unable to write the flash",
+        Failure::Sequence => "board was in the middle of something else",
+        Failure::Unsupported => {
+            "firmware runs without the bootloader or the update key, flash it over SWD"
+        }
+        Failure::Signature => "image isn't signed with the key the firmware was built with",
+        Failure::None => "board gave up",
+    };
+
+    Err(reason.into())
+}
+
+async fn wait_while(
+    dev: &Device,
+    timeout: Duration,
+    busy: impl Fn(&Status) -> bool,
+) -> Result<Status> {
+    let deadline = Instant::now() + timeout;
+
+    loop {
+        let status = check(read_status(dev).await?)?;
+
+        if !busy(&status) {
+            return Ok(status);
+        }
+
+        if Instant::now() >= deadline {
+            return Err(format!("board is stuck at {:?}", status.phase).into());
+        }
+
+        time::sleep(STATUS_POLL_INTERVAL).await;
+    }
+}
+
+fn progress(received: u32, len: u32) {
+    print!(
+        "\r{:3}% {:6} / {} bytes",
+        received as u64 * 100 / len as u64,
+        received,
+        len
+    );
+    let _ = io::stdout().flush();
+}
+
+async fn send_window(dev: &Device, image: &Image, from: u32) -> Result<()> {
+    let mut b = [0; CHUNK_WRITE_LEN];
+    let rest = &image.data[from as usize..];
+
+    for (n, data) in rest.chunks(CHUNK_LEN).take(WINDOW).enumerate() {
+        let chunk = Chunk {
+            offset: from + (n * CHUNK_LEN) as u32,
+            data,
+        };
+
+        let len = chunk.write_to(&mut b);
+        dev.send(proto::DFU_DATA, &b[..len]).await?;
+    }
+
+    Ok(())
+}
+
+async fn transfer(dev: &Device, image: &Image) -> Result<()> {
+    let len = image.data.len() as u32;
+    let start = Command::Start {
+        len,
+        crc: image.crc,
+    };
+
+    dev.write(proto::DFU_CONTROL, &start.to_bytes()).await?;
+
+    println!("erasing");
+    let mut status = wait_while(dev, ERASE_TIMEOUT, |s| {
+        matches!(s.phase, Phase::Idle | Phase::Erasing)
+    })
+    .await?;
+
+    if status.phase != Phase::Receiving || status.len != len {
+        return Err(format!("board isn't ready for the image, {:?}", status.phase).into());
+    }
+
+    let mut retries = 0;
+
+    while status.received < len {
+        let from = status.received;
+
+        if let Err(e) = send_window(dev, image, from).await {
+            eprintln!("\nunable to send - {}", e);
+            time::sleep(RETRY_DELAY).await;
+        }
+
+        status = check(read_status(dev).await?)?;
+        progress(status.received, len);
+
+        retries = match status.received > from {
+            true => 0,
+            false => retries + 1,
+        };
+
+        if retries > MAX_RETRIES {
+            println!();
+            return Err(format!("transfer stalled at {} bytes", status.received).into());
+        }
+    }
+
+    println!();
+    println!("checking");
+
+    let status = wait_while(dev, CHECK_TIMEOUT, |s| s.phase == Phase::Receiving).await?;
+
+    match status.phase {
+        Phase::Ready => Ok(()),
+        phase => Err(format!("board isn't done with the image, {:?}", phase).into()),
+    }
+}
+
+async fn activate(dev: &mut Device, image: &Image) -> Result<()> {
+    println!("activating");
+    dev.write(proto::DFU_CONTROL, &Command::Activate.to_bytes())
+        .await?;
+
+    // Write is acknowledged before the board reboots, otherwise we'd be talking to the
+    // old image still
+    let deadline = Instant::now() + REBOOT_TIMEOUT;
+    while dev.is_connected().await? {
+        if Instant::now() >= deadline {
+            return Err("board didn't reboot".into());
+        }
+
+        time::sleep(STATUS_POLL_INTERVAL).await;
+    }
+
+    println!("waiting for the board to come back");
+    dev.reconnect_after_reboot(COMEBACK_TIMEOUT).await?;
+
+    let revision = dev.firmware_revision().await?;
+    if !image.has_revision(&revision) {
+        return Err(format!(
+            "board came back with {}, not the new image - rolled back?",
+            revision
+        )
+        .into());
+    }
+
+    println!("running {}", revision);
+
+    if ImageState::from_gpregret(dev.boot_info().await?.gpregret) == ImageState::Trial {
+        println!("on trial: it stays once it's been up for a minute, otherwise it's rolled back");
+    }
+
+    Ok(())
+}
+
+async fn update(dev: &mut Device, image: &Image) -> Result<()> {
+    if !dev.has(proto::DFU_CONTROL) {
+        return Err("firmware has no update service, it has to be flashed over SWD once".into());
+    }
+
+    if dev.has(proto::FIRMWARE_REVISION) {
+        println!("board runs {}", dev.firmware_revision().await?);
+    }
+
+    if let Err(e) = transfer(dev, image).await {
+        // Doesn't get in the way of the next try then. The board may be gone already
+        let _ = dev
+            .write(proto::DFU_CONTROL, &Command::Abort.to_bytes())
+            .await;
+        return Err(e);
+    }
+
+    activate(dev, image).await
+}
+
+fn keygen(path: &Path) {
+    match key::generate(path) {
+        Ok(public) => {
+            println!("update key is in {}, keep it safe", path.display());
+            println!("build the firmware with DFU_PUBLIC_KEY={}", public);
+        }
+        Err(e) => {
+            eprintln!("unable to make {} - {}", path.display(), e);
+            process::exit(1);
+        }
+    }
+}
+
+#[tokio::main]
+async fn main() {
+    let (name, key, path) = match parse_options() {
+        Options::Update { name, key, image } => (name, key, image),
+        Options::Keygen(path) => return keygen(&path),
+    };
+
+    let keys = match key::load(&key) {
+        Ok(keys) => keys,
+        Err(e) => {
+            eprintln!("unable to use the key in {} - {}", key.display(), e);
+            process::exit(1);
+        }
+    };
+
+    // Checked before anything is connected to
+    let image = match Image::load(&path, &keys) {
+        Ok(image) => image,
+        Err(e) => {
+            eprintln!("unable to use {} - {}", path.display(), e);
+            process::exit(1);
+        }
+    };
+
+    println!(
+        "{}: {} bytes signed, CRC {:08x}",
+        path.display(),
+        image.data.len(),
+        image.crc
+    );
+
+    let mut dev = match Device::connect(name.as_deref()).await {
+        Ok(dev) => dev,
+        Err(e) => {
+            eprintln!("unable to connect - {}", e);
+            process::exit(1);
+        }
+    };
+
+    let r = update(&mut dev, &image).await;
+
+    if let Err(e) = dev.disconnect().await {
+        eprintln!("unable to disconnect - {}", e);
+    }
+
+    if let Err(e) = r {
+        eprintln!("update failed - {}", e);
+        process::exit(1);
+    }
+}
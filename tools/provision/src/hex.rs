@@ -0,0 +1,72 @@
+// Intel HEX, the one format every flashing tool takes with the address in it
+//
+// Only what a single page needs: data records, with an extended linear address one in
+// front since the page is past the first 64K.
+
+use std::fmt::Write;
+
+const DATA: u8 = 0x00;
+const END: u8 = 0x01;
+const EXTENDED_LINEAR_ADDRESS: u8 = 0x04;
+
+const RECORD_LEN: usize = 16;
+
+fn record(s: &mut String, kind: u8, address: u16, data: &[u8]) {
+    let mut bytes = vec![data.len() as u8];
+    bytes.extend(address.to_be_bytes());
+    bytes.push(kind);
+    bytes.extend(data);
+
+    let sum = bytes.iter().fold(0u8, |sum, b| sum.wrapping_add(*b));
+    bytes.push(sum.wrapping_neg());
+
+    s.push(':');
+    for b in bytes {
+        // Writing to a String doesn't fail
+        let _ = write!(s, "{:02X}", b);
+    }
+    s.push('\n');
+}
+
+pub fn encode(address: u32, data: &[u8]) -> String {
+    let mut s = String::new();
+    let mut upper = None;
+
+    for (n, chunk) in data.chunks(RECORD_LEN).enumerate() {
+        let at = address + (n * RECORD_LEN) as u32;
+
+        if upper != Some(at >> 16) {
+            upper = Some(at >> 16);
+            record(
+                &mut s,
+                EXTENDED_LINEAR_ADDRESS,
+                0,
+                &((at >> 16) as u16).to_be_bytes(),
+            );
+        }
+
+        record(&mut s, DATA, at as u16, chunk);
+    }
+
+    record(&mut s, END, 0, &[]);
+    s
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn records_add_up() {
+        let data: Vec<u8> = (0..20).collect();
+
+        assert_eq!(
+            encode(0x3_a000, &data),
+            ":020000040003F7
+:10A00000000102030405060708090A0B0C0D0E0FD8
+:04A010001011121306
+:00000001FF
+"
+        );
+    }
+}
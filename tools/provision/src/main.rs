@@ -0,0 +1,167 @@
+// Factory provisioning
+//
+// Writes what makes a board this particular unit (board revision, the cell it comes
+// with, its name and address, see copter_core::factory) to the page the firmware reads
+// it from at boot. Same firmware image for a whole batch then, and reflashing it leaves
+// the page alone. Goes over SWD with probe-rs, same as flashing the firmware does.
+//
+//   cargo run -p provision -- [--bootloader] [--chip CHIP] [--name NAME]
+//       [--address ADDRESS] [--out FILE.hex] UNIT.toml
+//
+// --bootloader for boards that run with the bootloader, the page is elsewhere there.
+// --name and --address go over the ones in the file, so one file does for a batch.
+// --out only writes the page to a file, for whatever else does the flashing. Settings
+// the board already has stay, so a new battery profile only shows after restoring the
+// battery defaults.
+
+mod hex;
+mod unit;
+
+use std::{
+    env, fs,
+    path::{Path, PathBuf},
+    process::{self, Command},
+};
+
+use copter_core::factory::{self, Factory};
+use unit::Unit;
+
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
+
+// Same ones firmware/.cargo/config.toml and bootloader/.cargo/config.toml run with
+const CHIP: &str = "nRF52832_xxAB";
+const CHIP_BOOTLOADER: &str = "nRF52832_xxAA";
+
+struct Options {
+    bootloader: bool,
+    chip: Option<String>,
+    name: Option<String>,
+    address: Option<String>,
+    out: Option<PathBuf>,
+    unit: PathBuf,
+}
+
+fn usage() -> ! {
+    eprintln!(
+        "usage: provision [--bootloader] [--chip CHIP] [--name NAME] [--address ADDRESS] \
+        [--out FILE.hex] UNIT.toml"
+    );
+    process::exit(2);
+}
+
+fn parse_options() -> Options {
+    let mut options = Options {
+        bootloader: false,
+        chip: None,
+        name: None,
+        address: None,
+        out: None,
+        unit: PathBuf::new(),
+    };
+    let mut unit = None;
+    let mut args = env::args().skip(1);
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--bootloader" => options.bootloader = true,
+            "--chip" => options.chip = Some(args.next().unwrap_or_else(|| usage())),
+            "--name" => options.name = Some(args.next().unwrap_or_else(|| usage())),
+            "--address" => options.address = Some(args.next().unwrap_or_else(|| usage())),
+            "--out" => options.out = Some(args.next().unwrap_or_else(|| usage()).into()),
+            _ if arg.starts_with('-') || unit.is_some() => usage(),
+            _ => unit = Some(PathBuf::from(arg)),
+        }
+    }
+
+    options.unit = unit.unwrap_or_else(|| usage());
+    options
+}
+
+fn load(options: &Options) -> Result<Factory> {
+    let mut unit: Unit = toml::from_str(&fs::read_to_string(&options.unit)?)?;
+
+    if options.name.is_some() {
+        unit.name = options.name.clone();
+    }
+    if options.address.is_some() {
+        unit.address = options.address.clone();
+    }
+
+    Ok(unit.factory()?)
+}
+
+fn describe(unit: &Factory) {
+    println!("board revision {}", unit.board_revision);
+    println!("name {}", unit.name().unwrap_or("built-in"));
+
+    match unit.address {
+        Some(address) => {
+            let bytes: Vec<String> = address.iter().rev().map(|b| format!("{:02x}", b)).collect();
+            println!("address {}", bytes.join(":"));
+        }
+        None => println!("address from the chip"),
+    }
+
+    match unit.battery {
+        Some(battery) => println!(
+            "{} mAh cell, terminates at {} mV",
+            battery.capacity, battery.terminate_voltage
+        ),
+        None => println!("built-in battery profile"),
+    }
+}
+
+fn flash(chip: &str, hex: &Path) -> Result<()> {
+    let status = Command::new("probe-rs")
+        .args(["download", "--chip", chip, "--binary-format", "hex"])
+        .arg(hex)
+        .status()
+        .map_err(|e| format!("unable to run probe-rs - {}", e))?;
+
+    match status.success() {
+        true => Ok(()),
+        false => Err(format!("probe-rs failed, {}", status).into()),
+    }
+}
+
+fn provision(options: &Options) -> Result<()> {
+    let unit = load(options)?;
+    describe(&unit);
+
+    let page = match options.bootloader {
+        true => factory::PAGE_BOOTLOADER,
+        false => factory::PAGE,
+    };
+    let hex = hex::encode(page, &unit.to_bytes());
+
+    if let Some(out) = &options.out {
+        fs::write(out, hex)?;
+        println!("saved to {}", out.display());
+        return Ok(());
+    }
+
+    let chip = match (&options.chip, options.bootloader) {
+        (Some(chip), _) => chip.as_str(),
+        (None, true) => CHIP_BOOTLOADER,
+        (None, false) => CHIP,
+    };
+
+    let path = env::temp_dir().join(format!("provision-{}.hex", process::id()));
+    fs::write(&path, hex)?;
+
+    let r = flash(chip, &path);
+    let _ = fs::remove_file(&path);
+    r?;
+
+    println!("done, the board takes it on the next boot");
+    Ok(())
+}
+
+fn main() {
+    let options = parse_options();
+
+    if let Err(e) = provision(&options) {
+        eprintln!("{} - {}", options.unit.display(), e);
+        process::exit(1);
+    }
+}
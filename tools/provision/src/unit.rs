@@ -0,0 +1,128 @@
+// What a unit file says
+//
+//   board_revision = 2
+//   name = "S107 #3"                  # advertised instead of "Syma S107"
+//   address = "c0:ff:ee:00:00:03"     # static random, same order as tools show it
+//
+//   [battery]                         # default battery profile, same as the settings one
+//   capacity = 150
+//   energy = 555
+//   terminate_voltage = 3000
+//   taper_rate = 56
+//   soc_delta = 1
+//   chemistry = 0
+//
+// Everything but the revision can be left out, the firmware goes with its own then. One
+// file does for a whole batch, with the name and address given per unit on the command
+// line.
+
+use copter_core::factory::{self, Factory, NAME_MAX};
+use serde::Deserialize;
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct Unit {
+    pub board_revision: u8,
+    pub name: Option<String>,
+    pub address: Option<String>,
+    pub battery: Option<Battery>,
+}
+
+#[derive(Deserialize, Clone, Copy, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct Battery {
+    pub capacity: u16,
+    pub energy: u16,
+    pub terminate_voltage: u16,
+    pub taper_rate: u16,
+    pub soc_delta: u8,
+    pub chemistry: u8,
+}
+
+// BatteryChemistry values past this one are rejected
+const CHEMISTRY_MAX: u8 = 2;
+
+// Most significant byte first in the string, least significant first on the board
+pub fn parse_address(s: &str) -> Option<[u8; 6]> {
+    let bytes: Vec<u8> = s
+        .split(':')
+        .map(
+            |b| match b.len() == 2 && b.bytes().all(|c| c.is_ascii_hexdigit()) {
+                true => u8::from_str_radix(b, 16).ok(),
+                false => None,
+            },
+        )
+        .collect::<Option<_>>()?;
+
+    let mut address: [u8; 6] = bytes.try_into().ok()?;
+    address.reverse();
+    Some(address)
+}
+
+impl Unit {
+    pub fn factory(&self) -> Result<Factory, String> {
+        let address = match &self.address {
+            Some(s) => Some(parse_address(s).ok_or(format!("{} isn't an address", s))?),
+            None => None,
+        };
+
+        if self.battery.is_some_and(|b| b.chemistry > CHEMISTRY_MAX) {
+            return Err("unknown battery chemistry".into());
+        }
+
+        let battery = self.battery.map(|b| factory::Battery {
+            capacity: b.capacity,
+            energy: b.energy,
+            terminate_voltage: b.terminate_voltage,
+            taper_rate: b.taper_rate,
+            soc_delta: b.soc_delta,
+            chemistry: b.chemistry,
+        });
+
+        Factory::new(self.board_revision, battery, address, self.name.as_deref()).map_err(|e| {
+            match e {
+                factory::Error::Name => format!("name is longer than {} bytes", NAME_MAX),
+                factory::Error::Address => {
+                    "address has to be a static random one, c0:... to ff:...".into()
+                }
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unit_files_make_records() {
+        let unit: Unit = toml::from_str(
+            r#"
+            board_revision = 2
+            name = "S107 #3"
+            address = "c0:ff:ee:00:00:03"
+
+            [battery]
+            capacity = 150
+            energy = 555
+            terminate_voltage = 3000
+            taper_rate = 56
+            soc_delta = 1
+            chemistry = 0
+            "#,
+        )
+        .unwrap();
+
+        let factory = unit.factory().unwrap();
+        assert_eq!(factory.address, Some([0x03, 0x00, 0x00, 0xee, 0xff, 0xc0]));
+        assert_eq!(factory.name(), Some("S107 #3"));
+        assert_eq!(factory.battery.map(|b| b.capacity), Some(150));
+
+        let mut public = unit.clone();
+        public.address = Some("00:11:22:33:44:55".into());
+        assert!(public.factory().is_err());
+
+        assert_eq!(parse_address("c0:ff:ee:00:00"), None);
+        assert_eq!(parse_address("c0:ff:ee:00:00:3"), None);
+    }
+}
@@ -0,0 +1,238 @@
+// Range and latency tests
+//
+// Sends echo packets to the board at a steady rate and times how long they take to come
+// back, counts the ones that never do and keeps the RSSI at both ends. Meant to be walked:
+// one run per spot, each one a row in the same CSV, so changes to the connection
+// parameters, the PHY or the advertising can be told apart on numbers rather than on how
+// the last flight felt. Firmware revision goes into every row for that.
+//
+//   cargo run -p range -- [--name NAME] [--count N] [--interval MS] [--out FILE.csv] LABEL
+//
+// LABEL is where the run was made, "10m" or "behind the shed". Rows are added to
+// range.csv unless --out says otherwise. Packets go without a response, the way the relay
+// sends its frames, so the ones the link loses stay lost. Ctrl-C ends the run early, with
+// what was sent so far.
+
+mod stats;
+
+use std::{
+    collections::HashMap,
+    env,
+    fs::OpenOptions,
+    io::{self, Write},
+    path::PathBuf,
+    process,
+    time::Duration,
+};
+
+use futures::StreamExt;
+use link::{
+    proto::{self, Echo},
+    Device, Result,
+};
+use stats::{Run, Summary};
+use tokio::time::{self, Instant, MissedTickBehavior};
+
+const COUNT: u32 = 500;
+const INTERVAL: Duration = Duration::from_millis(50);
+// Anything later than that is as good as lost
+const STRAGGLERS: Duration = Duration::from_secs(2);
+const PROGRESS: Duration = Duration::from_secs(1);
+
+struct Options {
+    name: Option<String>,
+    count: u32,
+    interval: Duration,
+    out: PathBuf,
+    label: String,
+}
+
+fn usage() -> ! {
+    eprintln!("usage: range [--name NAME] [--count N] [--interval MS] [--out FILE.csv] LABEL");
+    process::exit(2);
+}
+
+fn number(arg: Option<String>) -> u32 {
+    arg.and_then(|a| a.parse().ok())
+        .filter(|&n| n > 0)
+        .unwrap_or_else(|| usage())
+}
+
+fn parse_options() -> Options {
+    let mut options = Options {
+        name: None,
+        count: COUNT,
+        interval: INTERVAL,
+        out: PathBuf::from("range.csv"),
+        label: String::new(),
+    };
+    let mut label = None;
+    let mut args = env::args().skip(1);
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--name" => options.name = Some(args.next().unwrap_or_else(|| usage())),
+            "--count" => options.count = number(args.next()),
+            "--interval" => options.interval = Duration::from_millis(number(args.next()).into()),
+            "--out" => options.out = args.next().unwrap_or_else(|| usage()).into(),
+            _ if arg.starts_with('-') || label.is_some() => usage(),
+            _ => label = Some(arg),
+        }
+    }
+
+    options.label = label.unwrap_or_else(|| usage());
+    options
+}
+
+fn show_progress(run: &Run) -> io::Result<()> {
+    let s = run.summary();
+    let rssi = |r: Option<f64>| r.map_or("-".to_string(), |r| format!("{:.0} dBm", r));
+
+    print!(
+        "\rsent {}, echoed {}, median {}, board RSSI {}    ",
+        s.sent,
+        s.echoed,
+        s.latency
+            .map_or("-".to_string(), |l| format!("{:.1} ms", l.median)),
+        rssi(s.board_rssi)
+    );
+    io::stdout().flush()
+}
+
+async fn measure(dev: &Device, options: &Options) -> Result<Run> {
+    if !dev.has(proto::ECHO) {
+        return Err("board has no echo characteristic, older firmware?".into());
+    }
+
+    let mut echoes = Box::pin(dev.notifications(proto::ECHO).await?);
+    let mut tick = time::interval(options.interval);
+    // Bunching up the late ones would only measure the queue
+    tick.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    let mut progress = time::interval(PROGRESS);
+
+    let mut run = Run::default();
+    let mut pending: HashMap<u32, Instant> = HashMap::new();
+    let mut seq = 0;
+    let mut last = None;
+
+    loop {
+        tokio::select! {
+            _ = tick.tick(), if seq < options.count => {
+                pending.insert(seq, Instant::now());
+                dev.send(proto::ECHO, &proto::echo_packet(seq)).await?;
+
+                run.sent += 1;
+                seq += 1;
+
+                if seq == options.count {
+                    last = Some(Instant::now());
+                }
+            }
+
+            value = echoes.next() => {
+                let Some(value) = value else {
+                    return Err("board went away".into());
+                };
+
+                let Some(echo) = Echo::parse(&value) else {
+                    continue;
+                };
+
+                if let Some(sent) = pending.remove(&echo.seq) {
+                    run.echoed(sent.elapsed(), echo.rssi);
+                }
+
+                if last.is_some() && pending.is_empty() {
+                    break;
+                }
+            }
+
+            _ = progress.tick() => {
+                if let Some(rssi) = dev.rssi().await? {
+                    run.host_rssi(rssi);
+                }
+
+                show_progress(&run)?;
+            }
+
+            _ = time::sleep_until(last.unwrap_or_else(Instant::now) + STRAGGLERS),
+                if last.is_some() => break,
+
+            _ = tokio::signal::ctrl_c() => break,
+        }
+    }
+
+    show_progress(&run)?;
+    println!();
+    Ok(run)
+}
+
+fn describe(s: &Summary) {
+    println!("{} of {} came back, {:.1}% lost", s.echoed, s.sent, s.loss);
+
+    if let Some(l) = s.latency {
+        println!(
+            "round trip {:.1} / {:.1} / {:.1} / {:.1} ms (min / median / 95% / max)",
+            l.min, l.median, l.p95, l.max
+        );
+    }
+}
+
+fn save(options: &Options, firmware: &str, s: &Summary) -> io::Result<()> {
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&options.out)?;
+
+    // New file, header first
+    if file.metadata()?.len() == 0 {
+        writeln!(file, "{}", stats::COLUMNS.join(","))?;
+    }
+
+    writeln!(
+        file,
+        "{}",
+        stats::row(&options.label, firmware, options.interval, s)
+    )
+}
+
+async fn range(dev: &Device, options: &Options) -> Result<()> {
+    let firmware = dev.firmware_revision().await?;
+    println!(
+        "firmware {}, {} packets every {} ms",
+        firmware,
+        options.count,
+        options.interval.as_millis()
+    );
+
+    let summary = measure(dev, options).await?.summary();
+    describe(&summary);
+
+    save(options, &firmware, &summary)?;
+    println!("added to {}", options.out.display());
+    Ok(())
+}
+
+#[tokio::main(flavor = "current_thread")]
+async fn main() {
+    let options = parse_options();
+
+    let dev = match Device::connect(options.name.as_deref()).await {
+        Ok(dev) => dev,
+        Err(e) => {
+            eprintln!("unable to connect - {}", e);
+            process::exit(1);
+        }
+    };
+
+    let r = range(&dev, &options).await;
+
+    if let Err(e) = dev.disconnect().await {
+        eprintln!("unable to disconnect - {}", e);
+    }
+
+    if let Err(e) = r {
+        eprintln!("\nrun failed - {}", e);
+        process::exit(1);
+    }
+}
@@ -0,0 +1,180 @@
+// What a run comes down to, and the row it leaves in the CSV
+
+use std::time::Duration;
+
+pub const COLUMNS: [&str; 12] = [
+    "label",
+    "firmware",
+    "interval_ms",
+    "sent",
+    "echoed",
+    "loss_pct",
+    "latency_min_ms",
+    "latency_median_ms",
+    "latency_p95_ms",
+    "latency_max_ms",
+    "board_rssi_dbm",
+    "host_rssi_dbm",
+];
+
+#[derive(Default)]
+pub struct Run {
+    pub sent: usize,
+    latencies: Vec<Duration>,
+    board_rssi: Vec<i8>,
+    host_rssi: Vec<i16>,
+}
+
+// ms
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Latency {
+    pub min: f64,
+    pub median: f64,
+    pub p95: f64,
+    pub max: f64,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Summary {
+    pub sent: usize,
+    pub echoed: usize,
+    // %
+    pub loss: f64,
+    // None if nothing came back
+    pub latency: Option<Latency>,
+    // Averages in dBm, None without readings
+    pub board_rssi: Option<f64>,
+    pub host_rssi: Option<f64>,
+}
+
+// Nearest rank, of a sorted non-empty slice
+fn percentile(sorted: &[f64], p: usize) -> f64 {
+    let rank = (sorted.len() * p).div_ceil(100).max(1);
+    sorted[rank - 1]
+}
+
+fn average(v: impl ExactSizeIterator<Item = f64>) -> Option<f64> {
+    let n = v.len();
+    (n > 0).then(|| v.sum::<f64>() / n as f64)
+}
+
+impl Run {
+    pub fn echoed(&mut self, latency: Duration, board_rssi: Option<i8>) {
+        self.latencies.push(latency);
+        self.board_rssi.extend(board_rssi);
+    }
+
+    pub fn host_rssi(&mut self, rssi: i16) {
+        self.host_rssi.push(rssi);
+    }
+
+    pub fn summary(&self) -> Summary {
+        let mut ms: Vec<f64> = self
+            .latencies
+            .iter()
+            .map(|l| l.as_secs_f64() * 1000.0)
+            .collect();
+        ms.sort_by(f64::total_cmp);
+
+        let latency = (!ms.is_empty()).then(|| Latency {
+            min: ms[0],
+            median: percentile(&ms, 50),
+            p95: percentile(&ms, 95),
+            max: ms[ms.len() - 1],
+        });
+
+        let lost = self.sent.saturating_sub(ms.len());
+
+        Summary {
+            sent: self.sent,
+            echoed: ms.len(),
+            loss: match self.sent {
+                0 => 0.0,
+                sent => lost as f64 * 100.0 / sent as f64,
+            },
+            latency,
+            board_rssi: average(self.board_rssi.iter().map(|&r| r as f64)),
+            host_rssi: average(self.host_rssi.iter().map(|&r| r as f64)),
+        }
+    }
+}
+
+fn optional(v: Option<f64>) -> String {
+    v.map_or(String::new(), |v| format!("{:.1}", v))
+}
+
+// Commas in the label would shift the columns
+pub fn row(label: &str, firmware: &str, interval: Duration, s: &Summary) -> String {
+    let latency = [
+        s.latency.map(|l| l.min),
+        s.latency.map(|l| l.median),
+        s.latency.map(|l| l.p95),
+        s.latency.map(|l| l.max),
+    ];
+
+    let mut fields = vec![
+        label.replace(',', ";"),
+        firmware.replace(',', ";"),
+        interval.as_millis().to_string(),
+        s.sent.to_string(),
+        s.echoed.to_string(),
+        format!("{:.1}", s.loss),
+    ];
+
+    fields.extend(latency.into_iter().map(optional));
+    fields.push(optional(s.board_rssi));
+    fields.push(optional(s.host_rssi));
+    fields.join(",")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn runs_sum_up() {
+        let mut run = Run {
+            sent: 20,
+            ..Run::default()
+        };
+
+        for ms in 1..=16 {
+            run.echoed(
+                Duration::from_millis(ms * 10),
+                Some(-60 - (ms % 2) as i8 * 2),
+            );
+        }
+        run.host_rssi(-70);
+        run.host_rssi(-72);
+
+        let s = run.summary();
+        assert_eq!((s.sent, s.echoed, s.loss), (20, 16, 20.0));
+        assert_eq!(
+            s.latency,
+            Some(Latency {
+                min: 10.0,
+                median: 80.0,
+                p95: 160.0,
+                max: 160.0
+            })
+        );
+        assert_eq!((s.board_rssi, s.host_rssi), (Some(-61.0), Some(-71.0)));
+
+        assert_eq!(
+            row("10m, door", "v1.2", Duration::from_millis(50), &s),
+            "10m; door,v1.2,50,20,16,20.0,10.0,80.0,160.0,160.0,-61.0,-71.0"
+        );
+
+        // Nothing made it back
+        let s = Run {
+            sent: 5,
+            ..Run::default()
+        }
+        .summary();
+        assert_eq!((s.loss, s.latency, s.board_rssi), (100.0, None, None));
+        assert_eq!(
+            row("far", "v1.2", Duration::from_millis(50), &s),
+            "far,v1.2,50,5,0,100.0,,,,,,"
+        );
+    }
+}
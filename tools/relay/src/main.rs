@@ -0,0 +1,132 @@
+// Gamepad relay
+//
+// Flies the board with a pad it can't pair with itself: a wired one, or anything that
+// isn't an Xbox controller. The pad is read here and sent on to the direct control
+// characteristic at 50Hz, as the Xbox HID report the firmware already knows. Buttons
+// are where they are on an Xbox pad then, Start arms unless the pilot profile says
+// otherwise. See firmware/src/relay.rs for when the board listens to it.
+//
+//   cargo run -p relay -- [--name NAME]
+//
+// Runs until Ctrl-C, or until the board goes away. Nothing is sent while there's no pad,
+// so pulling the plug is a lost link as far as the board is concerned. The board only
+// lets the frames fly once its switch is pressed, once per connection.
+
+mod pad;
+
+use std::{env, process, time::Duration};
+
+use copter_core::xbox;
+use gilrs::{GamepadId, Gilrs};
+use link::{proto, Device, Result, CONNECTION_CHECK};
+use tokio::time::{self, MissedTickBehavior};
+
+const PERIOD: Duration = Duration::from_millis(20);
+
+fn usage() -> ! {
+    eprintln!("usage: relay [--name NAME]");
+    process::exit(2);
+}
+
+fn parse_options() -> Option<String> {
+    let mut name = None;
+    let mut args = env::args().skip(1);
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--name" => name = Some(args.next().unwrap_or_else(|| usage())),
+            _ => usage(),
+        }
+    }
+
+    name
+}
+
+async fn relay(dev: &Device, gilrs: &mut Gilrs) -> Result<()> {
+    let mut tick = time::interval(PERIOD);
+    // Frames that are late are stale, there's no catching up on them
+    tick.set_missed_tick_behavior(MissedTickBehavior::Skip);
+
+    let mut check = time::interval(CONNECTION_CHECK);
+    let mut relayed: Option<GamepadId> = None;
+    // Board only takes frames over an encrypted link. Refused write is what gets the
+    // OS to pair, the ones without a response would be dropped without a word
+    let mut paired = false;
+
+    println!("relaying, press the switch on the board to let it fly. Ctrl-C to stop");
+
+    loop {
+        tokio::select! {
+            _ = tick.tick() => {
+                // Pad state is only brought up to date by going through the events
+                while gilrs.next_event().is_some() {}
+
+                // Whichever one is connected first
+                let pad = gilrs.gamepads().next();
+                let id = pad.as_ref().map(|(id, _)| *id);
+
+                if id != relayed {
+                    match &pad {
+                        Some((_, pad)) => println!("pad: {}", pad.name()),
+                        None => println!("no pad, nothing is sent"),
+                    }
+
+                    relayed = id;
+                }
+
+                if let Some((_, pad)) = pad {
+                    let report = xbox::encode_hid_report(&pad::gamepad_report(&pad));
+
+                    match paired {
+                        true => dev.send(proto::DIRECT_CONTROL, &report).await?,
+                        false => {
+                            dev.write(proto::DIRECT_CONTROL, &report).await?;
+                            paired = true;
+                        }
+                    }
+                }
+            }
+
+            _ = check.tick() => {
+                if !dev.is_connected().await? {
+                    return Err("board went away".into());
+                }
+            }
+
+            _ = tokio::signal::ctrl_c() => return Ok(()),
+        }
+    }
+}
+
+#[tokio::main(flavor = "current_thread")]
+async fn main() {
+    let name = parse_options();
+
+    // Pads are looked for first, no point in connecting without any way to read them
+    let mut gilrs = match Gilrs::new() {
+        Ok(gilrs) => gilrs,
+        Err(e) => {
+            eprintln!("unable to read gamepads - {}", e);
+            process::exit(1);
+        }
+    };
+
+    let dev = match Device::connect(name.as_deref()).await {
+        Ok(dev) => dev,
+        Err(e) => {
+            eprintln!("unable to connect - {}", e);
+            process::exit(1);
+        }
+    };
+
+    let r = relay(&dev, &mut gilrs).await;
+
+    if let Err(e) = dev.disconnect().await {
+        eprintln!("unable to disconnect - {}", e);
+    }
+
+    if let Err(e) = r {
+        eprintln!("relay stopped - {}", e);
+        process::exit(1);
+    }
+}
@@ -0,0 +1,81 @@
+// Whatever pad is attached, as the Xbox controller the firmware knows
+//
+// Sticks and buttons go where they are on an Xbox pad, gilrs already names them by
+// position rather than by label. D-pad and the rest of what some pads have is left out,
+// the firmware has no use for it.
+
+use copter_core::xbox::HidReport;
+use gilrs::{Axis, Button, Gamepad};
+
+// Sticks are ±32767 in the report
+const STICK_FULL: f32 = 32767.0;
+// 10 bits
+const TRIGGER_FULL: f32 = 1023.0;
+
+// Bits of the report, see ButtonFlags in the firmware
+const BUTTONS: [(Button, u32); 11] = [
+    (Button::South, 0),
+    (Button::East, 1),
+    (Button::West, 3),
+    (Button::North, 4),
+    (Button::LeftTrigger, 6),
+    (Button::RightTrigger, 7),
+    (Button::Select, 10),
+    (Button::Start, 11),
+    (Button::Mode, 12),
+    (Button::LeftThumb, 13),
+    (Button::RightThumb, 14),
+];
+
+// Axes are -1..1 with up and right positive, buttons 0..1 so the analog triggers fit
+pub fn report(axis: impl Fn(Axis) -> f32, button: impl Fn(Button) -> f32) -> HidReport {
+    let stick = |a| (axis(a).clamp(-1.0, 1.0) * STICK_FULL) as i32;
+    let trigger = |b| (button(b).clamp(0.0, 1.0) * TRIGGER_FULL) as u16;
+
+    HidReport {
+        j1: (stick(Axis::LeftStickX), stick(Axis::LeftStickY)),
+        j2: (stick(Axis::RightStickX), stick(Axis::RightStickY)),
+        t1: trigger(Button::LeftTrigger2),
+        t2: trigger(Button::RightTrigger2),
+        buttons: BUTTONS
+            .iter()
+            .filter(|(b, _)| button(*b) > 0.5)
+            .fold(0, |bits, (_, bit)| bits | 1 << bit),
+    }
+}
+
+pub fn gamepad_report(pad: &Gamepad) -> HidReport {
+    report(
+        |a| pad.value(a),
+        |b| pad.button_data(b).map_or(0.0, |d| d.value()),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn laid_out_as_an_xbox_pad() {
+        let axis = |a| match a {
+            Axis::LeftStickY => 1.0,
+            Axis::RightStickX => -0.5,
+            _ => 0.0,
+        };
+
+        let button = |b| match b {
+            Button::Start => 1.0,
+            Button::RightTrigger2 => 1.0,
+            // Half pressed isn't pressed
+            Button::South => 0.3,
+            _ => 0.0,
+        };
+
+        let r = report(axis, button);
+
+        assert_eq!(r.j1, (0, 32767));
+        assert_eq!(r.j2, (-16383, 0));
+        assert_eq!((r.t1, r.t2), (0, 1023));
+        assert_eq!(r.buttons, 1 << 11);
+    }
+}
@@ -0,0 +1,466 @@
+// What goes in the file
+//
+// Same values as the characteristics have them, only with names instead of positions
+// where the board goes by index: parameters by their registry names, theme entries by
+// style. Every section is optional, whatever is left out of a file isn't touched on
+// restore - taking the gauge section out is what moving settings to a board with
+// another cell looks like.
+
+use std::{collections::BTreeMap, path::Path};
+
+use link::{
+    proto::{self, AuxOutput, BatteryProfile, GaugeLearned, PilotProfile, ThemeEntry},
+    Result,
+};
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize, Clone, Default, PartialEq, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct Backup {
+    // What the board ran when it was saved, nothing to restore
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub firmware: Option<String>,
+    // Plain values go before the tables, TOML wants it that way
+    pub charge_mode: Option<u8>,
+    pub soc_thresholds: Option<[u8; 5]>,
+    pub flight_light: Option<u8>,
+    pub self_test_mode: Option<u8>,
+    pub battery: Option<Battery>,
+    pub gauge: Option<Gauge>,
+    pub params: Option<BTreeMap<String, i32>>,
+    pub pilot: Option<Pilot>,
+    pub theme: Option<BTreeMap<String, Theme>>,
+    pub aux_outputs: Option<Vec<Aux>>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct Battery {
+    pub capacity: u16,
+    pub energy: u16,
+    pub terminate_voltage: u16,
+    pub taper_rate: u16,
+    pub soc_delta: u8,
+    pub chemistry: u8,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct Gauge {
+    pub qmax: u16,
+    pub ra_table: [u16; proto::RA_TABLE_LEN],
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct Pilot {
+    pub yaw_trim: i8,
+    pub pitch_trim: i8,
+    pub yaw_rate: u8,
+    pub pitch_rate: u8,
+    pub expo: u8,
+    pub arm_button: u8,
+    pub hold_button: u8,
+    pub aux_button: u8,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct Theme {
+    pub on: u16,
+    pub off: u16,
+    pub pause: u16,
+    pub count: u8,
+    pub brightness: u8,
+    pub tone: u8,
+    pub volume: u8,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct Aux {
+    pub pattern: u8,
+    pub brightness: u8,
+}
+
+impl From<BatteryProfile> for Battery {
+    fn from(p: BatteryProfile) -> Self {
+        Self {
+            capacity: p.capacity,
+            energy: p.energy,
+            terminate_voltage: p.terminate_voltage,
+            taper_rate: p.taper_rate,
+            soc_delta: p.soc_delta,
+            chemistry: p.chemistry,
+        }
+    }
+}
+
+impl From<Battery> for BatteryProfile {
+    fn from(b: Battery) -> Self {
+        Self {
+            capacity: b.capacity,
+            energy: b.energy,
+            terminate_voltage: b.terminate_voltage,
+            taper_rate: b.taper_rate,
+            soc_delta: b.soc_delta,
+            chemistry: b.chemistry,
+        }
+    }
+}
+
+impl From<GaugeLearned> for Gauge {
+    fn from(l: GaugeLearned) -> Self {
+        Self {
+            qmax: l.qmax,
+            ra_table: l.ra_table,
+        }
+    }
+}
+
+impl From<Gauge> for GaugeLearned {
+    fn from(g: Gauge) -> Self {
+        Self {
+            qmax: g.qmax,
+            ra_table: g.ra_table,
+        }
+    }
+}
+
+impl From<PilotProfile> for Pilot {
+    fn from(p: PilotProfile) -> Self {
+        Self {
+            yaw_trim: p.yaw_trim,
+            pitch_trim: p.pitch_trim,
+            yaw_rate: p.yaw_rate,
+            pitch_rate: p.pitch_rate,
+            expo: p.expo,
+            arm_button: p.arm_button,
+            hold_button: p.hold_button,
+            aux_button: p.aux_button,
+        }
+    }
+}
+
+impl From<Pilot> for PilotProfile {
+    fn from(p: Pilot) -> Self {
+        Self {
+            yaw_trim: p.yaw_trim,
+            pitch_trim: p.pitch_trim,
+            yaw_rate: p.yaw_rate,
+            pitch_rate: p.pitch_rate,
+            expo: p.expo,
+            arm_button: p.arm_button,
+            hold_button: p.hold_button,
+            aux_button: p.aux_button,
+        }
+    }
+}
+
+impl From<ThemeEntry> for Theme {
+    fn from(e: ThemeEntry) -> Self {
+        Self {
+            on: e.on,
+            off: e.off,
+            pause: e.pause,
+            count: e.count,
+            brightness: e.brightness,
+            tone: e.tone,
+            volume: e.volume,
+        }
+    }
+}
+
+impl From<Theme> for ThemeEntry {
+    fn from(t: Theme) -> Self {
+        Self {
+            on: t.on,
+            off: t.off,
+            pause: t.pause,
+            count: t.count,
+            brightness: t.brightness,
+            tone: t.tone,
+            volume: t.volume,
+        }
+    }
+}
+
+impl From<AuxOutput> for Aux {
+    fn from(o: AuxOutput) -> Self {
+        Self {
+            pattern: o.pattern,
+            brightness: o.brightness,
+        }
+    }
+}
+
+impl From<Aux> for AuxOutput {
+    fn from(a: Aux) -> Self {
+        Self {
+            pattern: a.pattern,
+            brightness: a.brightness,
+        }
+    }
+}
+
+pub fn param_id(name: &str) -> Option<u8> {
+    (0..proto::PARAM_SLOTS)
+        .find(|&id| proto::param_name(id) == Some(name))
+        .map(|id| id as u8)
+}
+
+pub fn style_id(name: &str) -> Option<u8> {
+    proto::INDICATION_STYLES
+        .iter()
+        .position(|&style| style == name)
+        .map(|id| id as u8)
+}
+
+fn json(path: &Path) -> bool {
+    path.extension()
+        .is_some_and(|e| e.eq_ignore_ascii_case("json"))
+}
+
+fn compare<T: PartialEq>(d: &mut Vec<String>, name: &str, wanted: &Option<T>, got: &Option<T>) {
+    if wanted.is_some() && wanted != got {
+        d.push(name.to_string());
+    }
+}
+
+impl Backup {
+    // JSON for .json files, TOML for anything else
+    pub fn to_string(&self, path: &Path) -> Result<String> {
+        Ok(match json(path) {
+            true => serde_json::to_string_pretty(self)? + "\n",
+            false => toml::to_string(self)?,
+        })
+    }
+
+    pub fn from_str(s: &str, path: &Path) -> Result<Self> {
+        Ok(match json(path) {
+            true => serde_json::from_str(s)?,
+            false => toml::from_str(s)?,
+        })
+    }
+
+    // What the board would turn away without saying so. Parameter ranges are only known
+    // to the board, those are checked against its table on restore
+    pub fn check(&self) -> Result<()> {
+        let mut problems = Vec::new();
+
+        if self.charge_mode.is_some_and(|m| m > proto::CHARGE_MODE_MAX) {
+            problems.push("unknown charge_mode".to_string());
+        }
+
+        if self
+            .flight_light
+            .is_some_and(|l| l > proto::FLIGHT_LIGHT_MAX)
+        {
+            problems.push("unknown flight_light".to_string());
+        }
+
+        if self
+            .self_test_mode
+            .is_some_and(|m| m > proto::SELF_TEST_MODE_MAX)
+        {
+            problems.push("unknown self_test_mode".to_string());
+        }
+
+        if let Some(battery) = self.battery {
+            if battery.chemistry > proto::CHEMISTRY_MAX {
+                problems.push("unknown battery.chemistry".to_string());
+            }
+        }
+
+        if self.gauge.is_some_and(|g| g.qmax == 0) {
+            problems.push("gauge.qmax can't be zero".to_string());
+        }
+
+        for name in self.params.iter().flat_map(|p| p.keys()) {
+            if param_id(name).is_none() {
+                problems.push(format!("unknown parameter {}", name));
+            }
+        }
+
+        for name in self.theme.iter().flat_map(|t| t.keys()) {
+            if style_id(name).is_none() {
+                problems.push(format!("unknown theme style {}", name));
+            }
+        }
+
+        if let Some(outputs) = &self.aux_outputs {
+            if outputs.len() != proto::AUX_OUTPUT_COUNT {
+                problems.push(format!(
+                    "{} aux outputs, the board has {}",
+                    outputs.len(),
+                    proto::AUX_OUTPUT_COUNT
+                ));
+            }
+
+            if outputs.iter().any(|o| o.pattern > proto::AUX_PATTERN_MAX) {
+                problems.push("unknown aux output pattern".to_string());
+            }
+        }
+
+        match problems.is_empty() {
+            true => Ok(()),
+            false => Err(problems.join(", ").into()),
+        }
+    }
+
+    // Sections of this one the board doesn't have the same way. Only the parameters and
+    // theme entries that are in here are compared
+    pub fn differences(&self, board: &Backup) -> Vec<String> {
+        let mut differences = Vec::new();
+        let d = &mut differences;
+        compare(d, "charge_mode", &self.charge_mode, &board.charge_mode);
+        compare(
+            d,
+            "soc_thresholds",
+            &self.soc_thresholds,
+            &board.soc_thresholds,
+        );
+        compare(d, "flight_light", &self.flight_light, &board.flight_light);
+        compare(
+            d,
+            "self_test_mode",
+            &self.self_test_mode,
+            &board.self_test_mode,
+        );
+        compare(d, "battery", &self.battery, &board.battery);
+        compare(d, "gauge", &self.gauge, &board.gauge);
+        compare(d, "pilot", &self.pilot, &board.pilot);
+        compare(d, "aux_outputs", &self.aux_outputs, &board.aux_outputs);
+
+        for (name, value) in self.params.iter().flatten() {
+            if board.params.as_ref().and_then(|p| p.get(name)) != Some(value) {
+                d.push(format!("params.{}", name));
+            }
+        }
+
+        for (name, entry) in self.theme.iter().flatten() {
+            if board.theme.as_ref().and_then(|t| t.get(name)) != Some(entry) {
+                d.push(format!("theme.{}", name));
+            }
+        }
+
+        differences
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn backup() -> Backup {
+        let theme = Theme {
+            on: 100,
+            off: 200,
+            pause: 1000,
+            count: 2,
+            brightness: 50,
+            tone: 40,
+            volume: 30,
+        };
+
+        Backup {
+            firmware: Some("v1.2-3-gabcdef0".to_string()),
+            charge_mode: Some(1),
+            soc_thresholds: Some([30, 20, 15, 10, 5]),
+            flight_light: Some(2),
+            self_test_mode: Some(0),
+            battery: Some(Battery {
+                capacity: 150,
+                energy: 555,
+                terminate_voltage: 3000,
+                taper_rate: 100,
+                soc_delta: 1,
+                chemistry: 0,
+            }),
+            gauge: Some(Gauge {
+                qmax: 17449,
+                ra_table: [50, 30, 34, 46, 38, 32, 37, 31, 32, 35, 39, 39, 61, 115, 200],
+            }),
+            params: Some(BTreeMap::from([
+                ("crash_yaw_rate".to_string(), 250),
+                ("pairing_timeout".to_string(), 60),
+            ])),
+            pilot: Some(Pilot {
+                yaw_trim: -3,
+                pitch_trim: 5,
+                yaw_rate: 80,
+                pitch_rate: 100,
+                expo: 20,
+                arm_button: 4,
+                hold_button: 0,
+                aux_button: 2,
+            }),
+            theme: Some(BTreeMap::from([("low_battery".to_string(), theme)])),
+            aux_outputs: Some(vec![
+                Aux {
+                    pattern: 3,
+                    brightness: 100,
+                },
+                Aux {
+                    pattern: 0,
+                    brightness: 0,
+                },
+            ]),
+        }
+    }
+
+    #[test]
+    fn goes_through_both_formats() {
+        let backup = backup();
+
+        for file in ["s107.toml", "s107.json"] {
+            let path = Path::new(file);
+            let s = backup.to_string(path).unwrap();
+
+            assert_eq!(Backup::from_str(&s, path).unwrap(), backup);
+        }
+
+        assert!(backup.check().is_ok());
+
+        // Sections left out aren't restored, unknown ones are a mistake
+        let partial = Backup::from_str("flight_light = 1\n", Path::new("s107.toml")).unwrap();
+        assert_eq!(partial.flight_light, Some(1));
+        assert_eq!(partial.battery, None);
+        assert!(Backup::from_str("flight_lights = 1\n", Path::new("s107.toml")).is_err());
+    }
+
+    #[test]
+    fn finds_what_didnt_stick() {
+        let wanted = backup();
+        let mut board = wanted.clone();
+
+        board.firmware = None;
+        board
+            .params
+            .as_mut()
+            .unwrap()
+            .insert("idle_disarm_timeout".to_string(), 10);
+        assert!(wanted.differences(&board).is_empty());
+
+        board.pilot.as_mut().unwrap().yaw_trim = 0;
+        board
+            .params
+            .as_mut()
+            .unwrap()
+            .insert("pairing_timeout".to_string(), 30);
+        board.theme = None;
+
+        assert_eq!(
+            wanted.differences(&board),
+            ["pilot", "params.pairing_timeout", "theme.low_battery"]
+        );
+
+        let mut bad = wanted.clone();
+        bad.flight_light = Some(9);
+        bad.params
+            .as_mut()
+            .unwrap()
+            .insert("rotor_speed".to_string(), 1);
+        assert!(bad.check().is_err());
+    }
+}
@@ -0,0 +1,326 @@
+// Settings backup and restore
+//
+// Saves what the board keeps in its settings to a TOML or JSON file (by extension):
+// battery profile, charge mode and thresholds, what the gauge learned, the parameters,
+// the pilot profile with its trims, the indication theme and the aux outputs. Restoring
+// writes them back through the same characteristics the other tools use, to the same
+// board or another one, then reconnects and reads everything back to see what stuck.
+//
+//   cargo run -p settings -- [--name NAME] backup FILE
+//   cargo run -p settings -- [--name NAME] restore FILE
+//
+// Pilot profile is kept per controller, the board only takes it once it knows one.
+
+mod backup;
+
+use std::{collections::BTreeMap, env, fs, path::PathBuf, process, time::Duration};
+
+use backup::{Backup, Battery, Gauge, Pilot};
+use link::{
+    proto::{self, AuxOutput, BatteryProfile, GaugeLearned, PilotProfile, ThemeEntry},
+    Device, Result,
+};
+use tokio::time;
+use uuid::Uuid;
+
+// Every write is a request for the settings and a flash write. The board only queues a
+// few of those, so they're spaced out
+const WRITE_INTERVAL: Duration = Duration::from_millis(200);
+
+enum Action {
+    Backup,
+    Restore,
+}
+
+struct Options {
+    name: Option<String>,
+    action: Action,
+    file: PathBuf,
+}
+
+fn usage() -> ! {
+    eprintln!("usage: settings [--name NAME] backup|restore FILE");
+    process::exit(2);
+}
+
+fn parse_options() -> Options {
+    let mut name = None;
+    let mut positional = Vec::new();
+    let mut args = env::args().skip(1);
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--name" => name = Some(args.next().unwrap_or_else(|| usage())),
+            _ if arg.starts_with('-') => usage(),
+            _ => positional.push(arg),
+        }
+    }
+
+    let [action, file] = <[String; 2]>::try_from(positional).unwrap_or_else(|_| usage());
+    let action = match action.as_str() {
+        "backup" => Action::Backup,
+        "restore" => Action::Restore,
+        _ => usage(),
+    };
+
+    Options {
+        name,
+        action,
+        file: file.into(),
+    }
+}
+
+// None if the firmware doesn't have it
+async fn read_with<T>(
+    dev: &Device,
+    uuid: Uuid,
+    parse: impl FnOnce(&[u8]) -> Option<T>,
+) -> Result<Option<T>> {
+    if !dev.has(uuid) {
+        return Ok(None);
+    }
+
+    let value = dev.read(uuid).await?;
+    match parse(&value) {
+        Some(v) => Ok(Some(v)),
+        None => Err(format!("unexpected value of {}: {:02x?}", uuid, value).into()),
+    }
+}
+
+async fn read_params(dev: &Device) -> Result<Option<BTreeMap<String, i32>>> {
+    let Some(table) = read_with(dev, proto::PARAM_TABLE, proto::parse_param_table).await? else {
+        return Ok(None);
+    };
+    let Some(values) = read_with(dev, proto::PARAMS, proto::parse_params).await? else {
+        return Ok(None);
+    };
+
+    let params = table
+        .iter()
+        .zip(values)
+        .enumerate()
+        .filter(|(_, (info, _))| info.used())
+        .filter_map(|(id, (_, value))| Some((proto::param_name(id)?.to_string(), value)))
+        .collect();
+
+    Ok(Some(params))
+}
+
+async fn read(dev: &Device) -> Result<Backup> {
+    let firmware = match dev.has(proto::FIRMWARE_REVISION) {
+        true => Some(dev.firmware_revision().await?),
+        false => None,
+    };
+
+    let byte = |b: &[u8]| (b.len() == 1).then(|| b[0]);
+    let theme = read_with(dev, proto::INDICATION_THEME, proto::parse_theme).await?;
+    let aux_outputs = read_with(dev, proto::AUX_OUTPUTS, proto::parse_aux_outputs).await?;
+
+    Ok(Backup {
+        firmware,
+        charge_mode: read_with(dev, proto::CHARGE_MODE, byte)
+            .await?
+            .filter(|&mode| mode != proto::CHARGE_MODE_NONE),
+        soc_thresholds: read_with(dev, proto::SOC_THRESHOLDS, |b| b.try_into().ok()).await?,
+        flight_light: read_with(dev, proto::FLIGHT_LIGHT, byte).await?,
+        self_test_mode: read_with(dev, proto::SELF_TEST_MODE, byte).await?,
+        battery: read_with(dev, proto::BATTERY_PROFILE, BatteryProfile::parse)
+            .await?
+            .map(Battery::from),
+        gauge: read_with(dev, proto::GAUGE_LEARNED, GaugeLearned::parse)
+            .await?
+            .map(Gauge::from),
+        params: read_params(dev).await?,
+        pilot: read_with(dev, proto::PILOT_PROFILE, PilotProfile::parse)
+            .await?
+            .map(Pilot::from),
+        theme: theme.map(|theme| {
+            proto::INDICATION_STYLES
+                .iter()
+                .zip(theme)
+                .map(|(style, entry)| (style.to_string(), entry.into()))
+                .collect()
+        }),
+        aux_outputs: aux_outputs.map(|outputs| outputs.into_iter().map(Into::into).collect()),
+    })
+}
+
+async fn backup(dev: &Device, options: &Options) -> Result<()> {
+    let backup = read(dev).await?;
+
+    fs::write(&options.file, backup.to_string(&options.file)?)?;
+    println!("saved to {}", options.file.display());
+    Ok(())
+}
+
+// Parameters by id, checked against what the board says they can be
+async fn param_updates(dev: &Device, wanted: &Backup) -> Result<Vec<[u8; 8]>> {
+    let Some(params) = &wanted.params else {
+        return Ok(Vec::new());
+    };
+
+    let table = read_with(dev, proto::PARAM_TABLE, proto::parse_param_table)
+        .await?
+        .ok_or("firmware has no parameters")?;
+
+    let mut updates = Vec::new();
+
+    for (name, &value) in params {
+        // Checked already
+        let id = backup::param_id(name).ok_or("unknown parameter")?;
+        let info = table[id as usize];
+
+        if !info.used() {
+            println!("board has no {}, left out", name);
+        } else if !info.contains(value) {
+            return Err(format!(
+                "{} is {}, has to be {}..={}",
+                name, value, info.min, info.max
+            )
+            .into());
+        } else {
+            updates.push(proto::param_update(id, value));
+        }
+    }
+
+    Ok(updates)
+}
+
+async fn write_all(dev: &Device, writes: &[(Uuid, Vec<u8>)]) -> Result<()> {
+    for (uuid, value) in writes {
+        if !dev.has(*uuid) {
+            println!("firmware has no {}, left out", uuid);
+            continue;
+        }
+
+        dev.write(*uuid, value).await?;
+        time::sleep(WRITE_INTERVAL).await;
+    }
+
+    Ok(())
+}
+
+fn load(options: &Options) -> Result<Backup> {
+    let backup = Backup::from_str(&fs::read_to_string(&options.file)?, &options.file)?;
+    backup.check()?;
+    Ok(backup)
+}
+
+async fn restore(dev: &mut Device, wanted: &Backup, options: &Options) -> Result<()> {
+    if let (Some(saved), true) = (&wanted.firmware, dev.has(proto::FIRMWARE_REVISION)) {
+        let running = dev.firmware_revision().await?;
+
+        if *saved != running {
+            println!("saved from {}, board runs {}", saved, running);
+        }
+    }
+
+    let mut writes = Vec::new();
+    let mut push = |uuid, value: Vec<u8>| writes.push((uuid, value));
+
+    // Battery first, the rest of the power settings go by it
+    if let Some(battery) = wanted.battery {
+        push(
+            proto::BATTERY_PROFILE,
+            BatteryProfile::from(battery).to_bytes().to_vec(),
+        );
+    }
+    if let Some(gauge) = wanted.gauge {
+        push(proto::GAUGE_LEARNED, GaugeLearned::from(gauge).to_bytes());
+    }
+    if let Some(mode) = wanted.charge_mode {
+        push(proto::CHARGE_MODE, vec![mode]);
+    }
+    if let Some(thresholds) = wanted.soc_thresholds {
+        push(proto::SOC_THRESHOLDS, thresholds.to_vec());
+    }
+    if let Some(light) = wanted.flight_light {
+        push(proto::FLIGHT_LIGHT, vec![light]);
+    }
+    if let Some(mode) = wanted.self_test_mode {
+        push(proto::SELF_TEST_MODE, vec![mode]);
+    }
+    if let Some(pilot) = wanted.pilot {
+        push(
+            proto::PILOT_PROFILE,
+            PilotProfile::from(pilot).to_bytes().to_vec(),
+        );
+    }
+    for (name, &entry) in wanted.theme.iter().flatten() {
+        // Checked already
+        let style = backup::style_id(name).ok_or("unknown style")?;
+        push(
+            proto::INDICATION_THEME_ENTRY,
+            ThemeEntry::from(entry).update(style).to_vec(),
+        );
+    }
+    if let Some(outputs) = &wanted.aux_outputs {
+        let outputs: Vec<AuxOutput> = outputs.iter().copied().map(Into::into).collect();
+        push(proto::AUX_OUTPUTS, proto::aux_outputs_bytes(&outputs));
+    }
+
+    // Nothing is written unless all of the parameters fit
+    for update in param_updates(dev, wanted).await? {
+        writes.push((proto::PARAM_UPDATE, update.to_vec()));
+    }
+
+    println!("writing {} values", writes.len());
+    write_all(dev, &writes).await?;
+
+    dev.reconnect().await?;
+    let differences = wanted.differences(&read(dev).await?);
+
+    if differences.is_empty() {
+        println!("restored from {}", options.file.display());
+        return Ok(());
+    }
+
+    if differences.iter().any(|d| d == "pilot") {
+        println!("pilot profile is only kept once the board knows a controller, pair one first");
+    }
+
+    if differences.iter().any(|d| d == "charge_mode") {
+        println!("charge mode is only there on boards built with charger control");
+    }
+
+    Err(format!("board didn't take {}", differences.join(", ")).into())
+}
+
+#[tokio::main]
+async fn main() {
+    let options = parse_options();
+
+    // Checked before anything is connected to
+    let wanted = match options.action {
+        Action::Backup => None,
+        Action::Restore => match load(&options) {
+            Ok(backup) => Some(backup),
+            Err(e) => {
+                eprintln!("unable to use {} - {}", options.file.display(), e);
+                process::exit(1);
+            }
+        },
+    };
+
+    let mut dev = match Device::connect(options.name.as_deref()).await {
+        Ok(dev) => dev,
+        Err(e) => {
+            eprintln!("unable to connect - {}", e);
+            process::exit(1);
+        }
+    };
+
+    let r = match &wanted {
+        None => backup(&dev, &options).await,
+        Some(wanted) => restore(&mut dev, wanted, &options).await,
+    };
+
+    if let Err(e) = dev.disconnect().await {
+        eprintln!("unable to disconnect - {}", e);
+    }
+
+    if let Err(e) = r {
+        eprintln!("{} - {}", options.file.display(), e);
+        process::exit(1);
+    }
+}
@@ -0,0 +1,293 @@
+// Live PID tuning
+//
+// Connects to a board and plots the yaw rate loop as the firmware runs it: gyro against
+// the setpoint, and what the PID makes of the difference. Gains are nudged with the
+// keyboard and go straight to the board, so the effect of a change shows on the next
+// punch of the stick rather than after a reflash.
+//
+//   cargo run -p tune -- [--name NAME]
+//
+// Nothing comes through until the board is armed. Gains the board is given only last
+// until it's powered off, the ones it ended up with are printed on the way out, ready
+// for the sim's --pid.
+
+mod plot;
+
+use std::{
+    env,
+    io::{self, Write},
+    process,
+    time::{Duration, Instant},
+};
+
+use crossterm::{
+    cursor,
+    event::{Event, EventStream, KeyCode, KeyEventKind, KeyModifiers},
+    execute, queue,
+    style::Print,
+    terminal::{self, ClearType},
+};
+use futures::StreamExt;
+use link::{
+    proto::{self, Pid, RateLoop},
+    Device, Result, CONNECTION_CHECK,
+};
+use tokio::time;
+
+use plot::Trace;
+
+// 5s worth of the loop, at 50Hz
+const HISTORY: usize = 250;
+const REDRAW: Duration = Duration::from_millis(50);
+// Reports stop when the board is disarmed
+const ARMED_TIMEOUT: Duration = Duration::from_millis(500);
+
+const RATE_ROWS: usize = 15;
+const OUTPUT_ROWS: usize = 9;
+// Hands off the sticks, it shouldn't look like anything is going on
+const RATE_FLOOR: f32 = 20.0;
+const OUTPUT_FLOOR: f32 = 50.0;
+
+const HELP: &str = "p/P, i/I, d/D lower/raise a gain by 5%, q quit";
+
+fn usage() -> ! {
+    eprintln!("usage: tune [--name NAME]");
+    process::exit(2);
+}
+
+fn parse_options() -> Option<String> {
+    let mut name = None;
+    let mut args = env::args().skip(1);
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--name" => name = Some(args.next().unwrap_or_else(|| usage())),
+            _ => usage(),
+        }
+    }
+
+    name
+}
+
+// 5%, but always at least a step of the fixed point
+fn nudge(gain: u16, up: bool) -> u16 {
+    let step = (gain / 20).max(1);
+
+    match up {
+        true => gain.saturating_add(step),
+        false => gain.saturating_sub(step),
+    }
+}
+
+fn gain(v: u16) -> f32 {
+    v as f32 / 100.0
+}
+
+#[derive(Default)]
+struct Tuner {
+    reports: Vec<RateLoop>,
+    last_report_at: Option<Instant>,
+    // Taken from the board once it's armed, the keyboard has the say after that
+    gains: Option<Pid>,
+    // Whatever went wrong with the last write
+    status: String,
+}
+
+impl Tuner {
+    fn report(&mut self, report: RateLoop) {
+        if self.reports.len() == HISTORY {
+            self.reports.remove(0);
+        }
+
+        self.reports.push(report);
+        self.last_report_at = Some(Instant::now());
+        self.gains.get_or_insert(report.gains);
+    }
+
+    fn armed(&self) -> bool {
+        self.last_report_at
+            .is_some_and(|at| at.elapsed() < ARMED_TIMEOUT)
+    }
+
+    // New gains to send, if the key was for one of them
+    fn key(&mut self, key: char) -> Option<Pid> {
+        let gains = self.gains.as_mut()?;
+        let up = key.is_ascii_uppercase();
+
+        match key.to_ascii_lowercase() {
+            'p' => gains.p = nudge(gains.p, up),
+            'i' => gains.i = nudge(gains.i, up),
+            'd' => gains.d = nudge(gains.d, up),
+            _ => return None,
+        }
+
+        Some(*gains)
+    }
+
+    fn draw(&self, out: &mut impl Write) -> io::Result<()> {
+        let (columns, _) = terminal::size()?;
+        let width = (columns as usize).min(HISTORY);
+
+        let series = |f: fn(&RateLoop) -> i16, unit: f32| -> Vec<f32> {
+            self.reports.iter().map(|r| f(r) as f32 / unit).collect()
+        };
+
+        let gyro = series(|r| r.gyro, 10.0);
+        let setpoint = series(|r| r.setpoint, 10.0);
+        let output = series(|r| r.output, 1.0);
+
+        let (rate_rows, rate_scale) = plot::render(
+            &[
+                Trace {
+                    samples: &setpoint,
+                    mark: '+',
+                },
+                Trace {
+                    samples: &gyro,
+                    mark: '*',
+                },
+            ],
+            width,
+            RATE_ROWS,
+            RATE_FLOOR,
+        );
+
+        let (output_rows, output_scale) = plot::render(
+            &[Trace {
+                samples: &output,
+                mark: '*',
+            }],
+            width,
+            OUTPUT_ROWS,
+            OUTPUT_FLOOR,
+        );
+
+        let status = match (self.armed(), self.reports.last()) {
+            (true, Some(r)) => format!("armed, throttle {}", r.throttle),
+            _ => "disarmed, arm the board to start".to_string(),
+        };
+
+        let gains = match self.gains {
+            Some(g) => format!("p {:.2}  i {:.2}  d {:.2}", gain(g.p), gain(g.i), gain(g.d)),
+            None => "gains not known yet".to_string(),
+        };
+
+        let mut lines = vec![
+            status,
+            gains,
+            self.status.clone(),
+            format!("yaw rate, * gyro + setpoint, ±{:.0} dps", rate_scale),
+        ];
+
+        lines.extend(rate_rows);
+        lines.push(format!("PID output, ±{:.0}", output_scale));
+        lines.extend(output_rows);
+        lines.push(String::new());
+        lines.push(HELP.to_string());
+
+        queue!(out, cursor::MoveTo(0, 0), terminal::Clear(ClearType::All))?;
+
+        for (row, line) in lines.iter().enumerate() {
+            queue!(out, cursor::MoveTo(0, row as u16), Print(line))?;
+        }
+
+        out.flush()
+    }
+}
+
+async fn run(dev: &Device, tuner: &mut Tuner) -> Result<()> {
+    let mut out = io::stdout();
+    let mut keys = EventStream::new();
+    let mut reports = Box::pin(dev.notifications(proto::RATE_LOOP).await?);
+    let mut redraw = time::interval(REDRAW);
+    let mut check = time::interval(CONNECTION_CHECK);
+
+    loop {
+        tokio::select! {
+            event = keys.next() => {
+                let Some(Event::Key(key)) = event.transpose()? else {
+                    continue;
+                };
+
+                let (KeyCode::Char(c), KeyEventKind::Press) = (key.code, key.kind) else {
+                    continue;
+                };
+
+                let ctrl = key.modifiers.contains(KeyModifiers::CONTROL);
+                if c == 'q' || ctrl && c == 'c' {
+                    return Ok(());
+                }
+
+                if let Some(gains) = tuner.key(c) {
+                    tuner.status = match dev.write(proto::PID_UPDATE, &gains.to_bytes()).await {
+                        Ok(()) => String::new(),
+                        Err(e) => format!("unable to send the gains - {}", e),
+                    };
+                }
+            }
+
+            report = reports.next() => {
+                let Some(report) = report else {
+                    return Err("board went away".into());
+                };
+
+                if let Some(report) = RateLoop::parse(&report) {
+                    tuner.report(report);
+                }
+            }
+
+            _ = redraw.tick() => tuner.draw(&mut out)?,
+
+            _ = check.tick() => {
+                if !dev.is_connected().await? {
+                    return Err("board went away".into());
+                }
+            }
+        }
+    }
+}
+
+#[tokio::main]
+async fn main() {
+    let name = parse_options();
+
+    let dev = match Device::connect(name.as_deref()).await {
+        Ok(dev) => dev,
+        Err(e) => {
+            eprintln!("unable to connect - {}", e);
+            process::exit(1);
+        }
+    };
+
+    let mut tuner = Tuner::default();
+    let mut out = io::stdout();
+
+    let r = match terminal::enable_raw_mode() {
+        Ok(()) => {
+            let _ = execute!(out, terminal::EnterAlternateScreen, cursor::Hide);
+            let r = run(&dev, &mut tuner).await;
+            let _ = execute!(out, cursor::Show, terminal::LeaveAlternateScreen);
+            let _ = terminal::disable_raw_mode();
+            r
+        }
+        Err(e) => Err(e.into()),
+    };
+
+    if let Err(e) = dev.disconnect().await {
+        eprintln!("unable to disconnect - {}", e);
+    }
+
+    if let Some(g) = tuner.gains {
+        println!(
+            "gains: --pid {:.2},{:.2},{:.2}",
+            gain(g.p),
+            gain(g.i),
+            gain(g.d)
+        );
+    }
+
+    if let Err(e) = r {
+        eprintln!("tuning stopped - {}", e);
+        process::exit(1);
+    }
+}
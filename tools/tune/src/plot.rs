@@ -0,0 +1,77 @@
+// Strip charts, in plain characters so any terminal will do
+//
+// Newest sample on the right edge. Scale is symmetric around zero and follows whatever
+// is on screen, but never goes below the floor it's given - otherwise the noise of a
+// board sitting on the bench fills the whole chart.
+
+pub struct Trace<'a> {
+    pub samples: &'a [f32],
+    pub mark: char,
+}
+
+// Only as much as fits
+fn visible<'a>(trace: &Trace<'a>, width: usize) -> &'a [f32] {
+    &trace.samples[trace.samples.len().saturating_sub(width)..]
+}
+
+// One string per row, top one first, and the scale it came out at. Later traces are
+// drawn over the earlier ones
+pub fn render(traces: &[Trace], width: usize, height: usize, floor: f32) -> (Vec<String>, f32) {
+    let scale = traces
+        .iter()
+        .flat_map(|t| visible(t, width))
+        .fold(floor, |scale, v| scale.max(v.abs()));
+
+    let middle = height / 2;
+    let mut rows = vec![vec![' '; width]; height];
+    rows[middle].fill('-');
+
+    for trace in traces {
+        let samples = visible(trace, width);
+        let first = width - samples.len();
+
+        for (column, v) in samples.iter().enumerate() {
+            let offset = (v / scale * middle as f32).round() as isize;
+            let row = (middle as isize - offset).clamp(0, height as isize - 1);
+
+            rows[row as usize][first + column] = trace.mark;
+        }
+    }
+
+    let rows = rows.into_iter().map(String::from_iter).collect();
+    (rows, scale)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ramp_goes_corner_to_corner() {
+        let ramp: Vec<f32> = (-2..=2).map(|v| v as f32).collect();
+        let trace = Trace {
+            samples: &ramp,
+            mark: '*',
+        };
+
+        let (rows, scale) = render(&[trace], 8, 5, 1.0);
+
+        assert_eq!(scale, 2.0);
+        assert_eq!(
+            rows,
+            ["       *", "      * ", "-----*--", "    *   ", "   *    "]
+        );
+
+        // Quiet ones stay small
+        let flat = [0.1; 3];
+        let trace = Trace {
+            samples: &flat,
+            mark: '*',
+        };
+
+        let (rows, scale) = render(&[trace], 3, 3, 1.0);
+
+        assert_eq!(scale, 1.0);
+        assert_eq!(rows, ["   ", "***", "   "]);
+    }
+}